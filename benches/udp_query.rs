@@ -0,0 +1,60 @@
+//! Round trips of UDP queries answered by an in-process server, hosting `example.com.`
+//! with a single A record, on a loopback port picked by the OS.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use hickory_proto::op::{Message, MessageType, OpCode, Query};
+use hickory_proto::rr::{Name, RecordType};
+use hickory_proto::serialize::binary::BinEncodable;
+use rdns::testing::TestServer;
+use std::str::FromStr;
+use tokio::net::UdpSocket;
+use tokio::runtime::Runtime;
+
+/// Starts the server, returning once it answers queries.
+async fn start_server() -> anyhow::Result<TestServer> {
+    let server = TestServer::start(&["example.com."]).await?;
+    server
+        .state
+        .write()
+        .await
+        .add_record("www.example.com.".into(), "A".into(), "192.0.2.1".into(), 300)
+        .await?;
+    Ok(server)
+}
+
+fn encode_query(name: &str, record_type: RecordType) -> Vec<u8> {
+    let mut message = Message::new();
+    message
+        .set_id(1)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .add_query(Query::query(Name::from_str(name).unwrap(), record_type));
+    message.to_bytes().unwrap()
+}
+
+async fn round_trip(socket: &UdpSocket, query: &[u8]) -> usize {
+    let mut buffer = [0u8; 512];
+    socket.send(query).await.unwrap();
+    socket.recv(&mut buffer).await.unwrap()
+}
+
+fn udp_query(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let server = runtime.block_on(start_server()).unwrap();
+    let socket = runtime.block_on(async {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server.dns_addr).await.unwrap();
+        socket
+    });
+
+    let mut group = c.benchmark_group("udp_query");
+    for (label, name) in [("answer", "www.example.com."), ("nxdomain", "missing.example.com.")] {
+        let query = encode_query(name, RecordType::A);
+        group.bench_function(label, |b| b.iter(|| runtime.block_on(round_trip(&socket, &query))));
+    }
+    group.finish();
+    runtime.block_on(server.shutdown()).unwrap();
+}
+
+criterion_group!(benches, udp_query);
+criterion_main!(benches);
@@ -1,9 +1,35 @@
 use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 fn main() {
     let out_dir = env::var("OUT_DIR").expect("OUT_DIR not set");
     println!("cargo:warning=OUT_DIR = {}", out_dir);
     
-    tonic_build::compile_protos("proto/control.proto")
+    // The descriptor set is served by the gRPC reflection service
+    tonic_build::configure()
+        .file_descriptor_set_path(PathBuf::from(&out_dir).join("control_descriptor.bin"))
+        .compile(&["proto/rdns/control/v1/control.proto", "proto/rdns/control/v2/records.proto"], &["proto"])
         .unwrap_or_else(|e| panic!("Failed to compile protos: {:?}", e));
+    tonic_build::compile_protos("proto/dnstap.proto")
+        .unwrap_or_else(|e| panic!("Failed to compile protos: {:?}", e));
+    // Only the client is needed, to export spans to a collector
+    tonic_build::configure()
+        .build_server(false)
+        .compile(&["proto/otlp.proto"], &["proto"])
+        .unwrap_or_else(|e| panic!("Failed to compile protos: {:?}", e));
+
+    // The commit the binary is built from, reported by GetServerInfo; RDNS_COMMIT gives it
+    // for builds outside a git checkout
+    let commit = env::var("RDNS_COMMIT").ok().or_else(|| {
+        let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+        output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    });
+    println!("cargo:rustc-env=RDNS_COMMIT={}", commit.unwrap_or_else(|| "unknown".into()));
+    println!("cargo:rerun-if-env-changed=RDNS_COMMIT");
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,13 @@
+//! Arbitrary bytes read as the PROXY header opening a connection.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio::runtime::{Builder, Runtime};
+
+fuzz_target!(|data: &[u8]| {
+    static RUNTIME: std::sync::OnceLock<Runtime> = std::sync::OnceLock::new();
+    let runtime = RUNTIME.get_or_init(|| Builder::new_current_thread().build().unwrap());
+    let mut stream = data;
+    let _ = runtime.block_on(rdns::proxy::read_header(&mut stream));
+});
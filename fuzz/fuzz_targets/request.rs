@@ -0,0 +1,50 @@
+//! Arbitrary bytes sent as a query over UDP and TCP to the handler the listeners use,
+//! hosting `example.com.` with a single A record. Any panic is a crash a client could
+//! trigger remotely.
+
+#![no_main]
+
+use hickory_server::server::Protocol;
+use libfuzzer_sys::fuzz_target;
+use rdns::dns::{DnsOptions, DnsState, HandlerOptions, SharedCatalog};
+use rdns::hardening;
+use rdns::metrics::Metrics;
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use tokio::runtime::Runtime;
+use tokio::sync::{watch, RwLock};
+
+/// The runtime and handler shared by every input.
+fn handler() -> &'static (Runtime, SharedCatalog) {
+    static HANDLER: OnceLock<(Runtime, SharedCatalog)> = OnceLock::new();
+    HANDLER.get_or_init(|| {
+        let runtime = Runtime::new().unwrap();
+        let handler = runtime.block_on(start()).unwrap();
+        (runtime, handler)
+    })
+}
+
+async fn start() -> anyhow::Result<SharedCatalog> {
+    // Never bound, the handler is called directly
+    let listen_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let options = DnsOptions::builder().listen_addr(listen_addr).zone("example.com.").build()?;
+    let metrics = Arc::new(Metrics::new()?);
+    let state = Arc::new(RwLock::new(DnsState::new(&options, None, metrics).await?));
+    state
+        .write()
+        .await
+        .add_record("www.example.com.".into(), "A".into(), "192.0.2.1".into(), 300)
+        .await?;
+    let (_, handler_options) = watch::channel(Arc::new(HandlerOptions::default()));
+    Ok(SharedCatalog::new(state, handler_options, &options).await)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (runtime, handler) = handler();
+    let src = SocketAddr::from(([192, 0, 2, 53], 53000));
+    runtime.block_on(async {
+        for protocol in [Protocol::Udp, Protocol::Tcp] {
+            hardening::answer(handler, data, src, protocol).await;
+        }
+    });
+});
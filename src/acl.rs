@@ -0,0 +1,95 @@
+//! Client access control for query handling.
+//!
+//! Each access list allows and denies client networks: a client is permitted when it
+//! is in no denied network and, if any networks are allowed, in one of them. Requests
+//! must pass the global list; queries must also pass the list of the most specific zone
+//! list covering the queried name, and queries forwarded to upstream resolvers the
+//! recursion list. Refused clients get REFUSED.
+
+use hickory_proto::rr::{LowerName, Name};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use crate::settings::{AccessListSettings, AclSettings, ZoneAclSettings};
+use crate::views::ClientMatch;
+
+/// Networks allowed and denied by one list.
+#[derive(Clone)]
+pub struct AccessList {
+    allow: ClientMatch,
+    /// Whether any networks are allowed; everyone not denied is when none are.
+    restricted: bool,
+    deny: ClientMatch,
+}
+
+impl AccessList {
+    fn parse(allow: &[String], deny: &[String]) -> anyhow::Result<Self> {
+        Ok(AccessList {
+            allow: ClientMatch::parse(allow)?,
+            restricted: !allow.is_empty(),
+            deny: ClientMatch::parse(deny)?,
+        })
+    }
+
+    pub fn permits(&self, client: IpAddr) -> bool {
+        !self.deny.contains(client) && (!self.restricted || self.allow.contains(client))
+    }
+}
+
+impl TryFrom<AccessListSettings> for AccessList {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: AccessListSettings) -> anyhow::Result<Self> {
+        AccessList::parse(&cfg.allow, &cfg.deny)
+    }
+}
+
+/// Config options for client access control
+#[derive(Clone)]
+pub struct AclOptions {
+    /// Applies to every request.
+    pub global: AccessList,
+    /// Applies to queries forwarded upstream, which are open to every permitted client
+    /// when unset.
+    pub recursion: Option<AccessList>,
+    /// Per-zone lists, most specific zone first.
+    pub zones: Vec<(LowerName, AccessList)>,
+}
+
+impl TryFrom<AclSettings> for AclOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: AclSettings) -> anyhow::Result<Self> {
+        let mut zones = cfg
+            .zones
+            .into_iter()
+            .map(|ZoneAclSettings { zone, allow, deny }| {
+                let mut origin = Name::from_str(&zone)?;
+                origin.set_fqdn(true);
+                Ok((LowerName::new(&origin), AccessList::parse(&allow, &deny)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        zones.sort_by_key(|(origin, _)| std::cmp::Reverse(origin.num_labels()));
+        Ok(AclOptions {
+            global: AccessList::parse(&cfg.allow, &cfg.deny)?,
+            recursion: cfg.recursion.map(AccessList::try_from).transpose()?,
+            zones,
+        })
+    }
+}
+
+impl AclOptions {
+    /// Whether `client` may send requests at all.
+    pub fn permits_client(&self, client: IpAddr) -> bool {
+        self.global.permits(client)
+    }
+
+    /// Whether `client` may query `name`, which is answered by forwarding when `forwarded`.
+    pub fn permits_query(&self, client: IpAddr, name: &LowerName, forwarded: bool) -> bool {
+        let zone = self.zones.iter().find(|(origin, _)| origin.zone_of(name));
+        if zone.is_some_and(|(_, list)| !list.permits(client)) {
+            return false;
+        }
+        !forwarded || self.recursion.as_ref().is_none_or(|list| list.permits(client))
+    }
+}
@@ -0,0 +1,304 @@
+//! ACME DNS-01 challenge records.
+//!
+//! `SetAcmeChallenge` publishes the key authorization digest of a DNS-01 challenge as a
+//! TXT record at `_acme-challenge.<domain>`, with the short TTL of `[acme]`, and
+//! `ClearAcmeChallenge` removes it once the certificate authority has checked it, so
+//! that certbot or lego hooks can call rdns directly. A domain may hold several
+//! challenges at once, as validating a name and its wildcard takes two. Challenge
+//! records are leased: those never cleared, e.g. by a client that crashed, are removed
+//! after `cleanup_secs` all the same.
+//!
+//! With `[acme.acme_dns]`, the same is served over the HTTP API of acme-dns, which
+//! clients such as certbot-dns-acmedns and lego's `acme-dns` provider speak:
+//!
+//! - `POST /update` sets a challenge of the `subdomain` in its body, the domain being
+//!   validated, to its `txt`, authenticated by the `X-Api-User` and `X-Api-Key` headers;
+//!   bodies over the 1 MiB of the REST gateway are rejected with 413
+//! - `GET /health` answers once the API is up
+//!
+//! Accounts are configured rather than registered through `/register`, each limited to
+//! the domains listed for it, and clients are given them as their stored acme-dns
+//! account, with the domain as its `subdomain`. Challenges are written straight to the
+//! hosted zone, so no `_acme-challenge` CNAME is needed. Changes made through the API
+//! are recorded in the audit log with the account's username as token.
+
+use hickory_proto::rr::{LowerName, Name};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+
+use crate::audit::{self, Actor, AuditLog, Change};
+use crate::auth;
+use crate::dns::DnsState;
+use crate::error::RdnsError;
+use crate::rest;
+use crate::settings::{AcmeDnsSettings, AcmeSettings};
+use crate::shutdown::Shutdown;
+
+/// Label the challenge records of a domain are published under.
+const CHALLENGE_LABEL: &str = "_acme-challenge.";
+/// Length of a key authorization digest: a SHA-256 hash in unpadded base64url.
+const DIGEST_LEN: usize = 43;
+
+/// Config options for challenge records
+#[derive(Clone, Debug)]
+pub struct AcmeOptions {
+    pub ttl: u32,
+    /// How long a challenge record is kept unless cleared before.
+    pub cleanup: Duration,
+}
+
+impl From<AcmeSettings> for AcmeOptions {
+    fn from(cfg: AcmeSettings) -> Self {
+        AcmeOptions {
+            ttl: cfg.ttl,
+            cleanup: Duration::from_secs(cfg.cleanup_secs.max(1)),
+        }
+    }
+}
+
+/// Config options for the acme-dns compatible API
+pub struct AcmeDnsOptions {
+    pub listen_addr: String,
+    pub accounts: Vec<AcmeAccount>,
+}
+
+/// An account of the acme-dns compatible API.
+pub struct AcmeAccount {
+    pub username: String,
+    pub password: String,
+    /// Domains the account may set challenges of, their subdomains included.
+    pub domains: Vec<LowerName>,
+}
+
+impl TryFrom<AcmeDnsSettings> for AcmeDnsOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: AcmeDnsSettings) -> anyhow::Result<Self> {
+        let accounts = cfg
+            .accounts
+            .into_iter()
+            .map(|account| {
+                if account.username.is_empty() || account.password.is_empty() {
+                    anyhow::bail!("acme_dns accounts need a username and a password");
+                }
+                let domains = account
+                    .domains
+                    .iter()
+                    .map(|domain| Ok(LowerName::new(&DnsState::parse_name(domain)?)))
+                    .collect::<Result<_, RdnsError>>()?;
+                Ok(AcmeAccount {
+                    username: account.username,
+                    password: account.password,
+                    domains,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(AcmeDnsOptions {
+            listen_addr: cfg.listen_addr,
+            accounts,
+        })
+    }
+}
+
+/// The name the challenge records of `domain` are published at. `domain` may be a
+/// wildcard, whose challenges are those of its parent, or that name already.
+pub fn challenge_name(domain: &str) -> String {
+    let domain = domain.strip_prefix("*.").unwrap_or(domain);
+    if domain.len() >= CHALLENGE_LABEL.len() && domain[..CHALLENGE_LABEL.len()].eq_ignore_ascii_case(CHALLENGE_LABEL) {
+        domain.to_string()
+    } else {
+        format!("{}{}", CHALLENGE_LABEL, domain)
+    }
+}
+
+/// Fails unless `digest` is a key authorization digest, so that challenge records can't
+/// be used to publish anything else.
+fn validate_digest(digest: &str) -> Result<(), RdnsError> {
+    if digest.len() != DIGEST_LEN || !digest.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_') {
+        return Err(RdnsError::InvalidArgument(format!(
+            "{:?} is not an ACME key authorization digest, expected {} base64url characters",
+            digest, DIGEST_LEN
+        )));
+    }
+    Ok(())
+}
+
+impl AcmeOptions {
+    /// Publishes `digest` as a challenge record at `name`, next to the challenges already
+    /// there, with `ttl` or else the configured TTL. The record is removed after the
+    /// cleanup delay unless cleared before. Returns the TTL it was clamped to, like
+    /// `DnsState::add_record`.
+    pub async fn set_challenge(&self, state: &mut DnsState, name: &str, digest: &str, ttl: u32) -> Result<Option<u32>, RdnsError> {
+        validate_digest(digest)?;
+        let ttl = if ttl > 0 { ttl } else { self.ttl };
+        let expires_at = SystemTime::now() + self.cleanup;
+        state
+            .add_leased_record(name.to_string(), "TXT".to_string(), digest.to_string(), ttl, Some(expires_at), None)
+            .await
+            .map(|added| added.clamped)
+    }
+}
+
+/// Removes the challenge record at `name` holding `digest`, or every challenge record of
+/// `name` when `digest` is empty. Succeeds when there is none, e.g. as it was cleaned up
+/// already.
+pub async fn clear_challenge(state: &DnsState, name: &str, digest: &str) -> Result<(), RdnsError> {
+    let result = if digest.is_empty() {
+        state.delete_record(name.to_string(), "TXT".to_string()).await
+    } else {
+        state.delete_record_value(name.to_string(), "TXT".to_string(), digest.to_string()).await
+    };
+    match result {
+        Err(RdnsError::RecordNotFound(_)) => Ok(()),
+        result => result,
+    }
+}
+
+/// Body of an acme-dns `/update` request.
+#[derive(Deserialize)]
+struct UpdateBody {
+    subdomain: String,
+    txt: String,
+}
+
+#[derive(Serialize)]
+struct UpdateResponse {
+    txt: String,
+}
+
+/// An acme-dns error, e.g. `forbidden` or `bad_txt`.
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Builds a JSON response with the given status.
+fn json<T: Serialize>(code: StatusCode, body: &T) -> hyper::Response<Body> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    let mut response = hyper::Response::new(Body::from(body));
+    *response.status_mut() = code;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    response
+}
+
+fn error(code: StatusCode, message: impl Into<String>) -> hyper::Response<Body> {
+    json(code, &ErrorBody { error: message.into() })
+}
+
+/// The value of header `name` of `request`, empty when missing.
+fn header_value<'a>(request: &'a hyper::Request<Body>, name: &str) -> &'a str {
+    request.headers().get(name).and_then(|value| value.to_str().ok()).unwrap_or_default()
+}
+
+/// Answers a single acme-dns API request.
+async fn serve(
+    state: Arc<RwLock<DnsState>>,
+    audit: Option<Arc<AuditLog>>,
+    challenges: Arc<AcmeOptions>,
+    options: Arc<AcmeDnsOptions>,
+    peer: SocketAddr,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let response = match (method, request.uri().path()) {
+        (Method::GET, "/health") => hyper::Response::new(Body::empty()),
+        (Method::POST, "/register") => error(StatusCode::FORBIDDEN, "registration_disabled"),
+        (Method::POST, "/update") => {
+            let username = header_value(&request, "x-api-user");
+            let password = header_value(&request, "x-api-key");
+            let Some(account) = options
+                .accounts
+                .iter()
+                .find(|account| account.username == username && auth::constant_time_eq(account.password.as_bytes(), password.as_bytes()))
+            else {
+                return Ok(error(StatusCode::UNAUTHORIZED, "forbidden"));
+            };
+            let actor = Actor {
+                token: Some(account.username.clone()),
+                peer: Some(peer.to_string()),
+            };
+            match rest::read_json::<UpdateBody>(request).await {
+                Ok(body) => update(&state, audit.as_deref(), &challenges, account, actor, body).await,
+                Err(rest::BodyError::TooLarge) => error(StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large"),
+                Err(_) => error(StatusCode::BAD_REQUEST, "malformed_json_payload"),
+            }
+        }
+        _ => error(StatusCode::NOT_FOUND, "not_found"),
+    };
+    Ok(response)
+}
+
+/// Sets the challenge of an `/update` request made by `account`.
+async fn update(
+    state: &RwLock<DnsState>,
+    audit: Option<&AuditLog>,
+    challenges: &AcmeOptions,
+    account: &AcmeAccount,
+    actor: Actor,
+    body: UpdateBody,
+) -> hyper::Response<Body> {
+    let name = challenge_name(&body.subdomain);
+    let allowed = Name::from_str(&name)
+        .is_ok_and(|parsed| account.domains.iter().any(|domain| domain.zone_of(&LowerName::new(&parsed))));
+    if !allowed {
+        return error(StatusCode::BAD_REQUEST, "bad_subdomain");
+    }
+    if validate_digest(&body.txt).is_err() {
+        return error(StatusCode::BAD_REQUEST, "bad_txt");
+    }
+    let mut state = state.write().await;
+    let change = Change::records(audit, &state, actor, "SetAcmeChallenge", &name, "TXT").await;
+    let result = challenges.set_challenge(&mut state, &name, &body.txt, 0).await;
+    audit::finish(change, &state, &result).await;
+    match result {
+        Ok(_) => json(StatusCode::OK, &UpdateResponse { txt: body.txt }),
+        Err(e) => error(rest::status_code(&e), e.to_string()),
+    }
+}
+
+/// Starts the acme-dns compatible API, setting the challenges of its accounts in `state`
+/// as `challenges` says until `shutdown` is requested, and recording its changes in
+/// `audit` when it is set.
+///
+/// # Errors
+///
+/// Returns an error if the listen address is invalid or the server fails.
+pub async fn run_acme_dns_server(
+    state: Arc<RwLock<DnsState>>,
+    audit: Option<Arc<AuditLog>>,
+    challenges: AcmeOptions,
+    options: AcmeDnsOptions,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let addr: SocketAddr = options.listen_addr.parse()?;
+    let challenges = Arc::new(challenges);
+    let options = Arc::new(options);
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let audit = audit.clone();
+        let challenges = challenges.clone();
+        let options = options.clone();
+        let peer = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                serve(state.clone(), audit.clone(), challenges.clone(), options.clone(), peer, request)
+            }))
+        }
+    });
+    println!("acme-dns API listening on {}", addr);
+    hyper::Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.requested())
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,616 @@
+//! Certificates of the DoT and DoH listeners obtained through ACME.
+//!
+//! With `[acme.certificate]`, rdns obtains the certificate of its DNS-over-TLS and
+//! DNS-over-HTTPS listeners itself from an ACME certificate authority, Let's Encrypt by
+//! default, validating the names through DNS-01 against its own zones: the challenge
+//! records are published in the hosted zone of each name, as `SetAcmeChallenge` does, so
+//! the names must be in zones rdns serves authoritatively to the internet. The records
+//! are removed once the authority has checked them.
+//!
+//! The account key, the certificate chain and its key are stored under `dir`, so that a
+//! restart serves the certificate obtained before rather than ordering another one. It
+//! is renewed `renew_before_days` before it expires, or when the configured names
+//! change, and the listeners serve the new one from their next handshake on, without a
+//! restart. Until a first certificate is obtained, TLS handshakes fail.
+//!
+//! Failed orders are retried every hour. On a cluster follower, the challenge records
+//! can't be written and no certificate is obtained.
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hickory_proto::rustls::tls_server;
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, HeaderMap, Method, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock as StdRwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+use crate::acme::{self, AcmeOptions};
+use crate::dns::DnsState;
+use crate::settings::AcmeCertificateSettings;
+use crate::shutdown::Shutdown;
+
+/// Interval between checks of the status of an authorization or order.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Checks of the status of an authorization or order before it is given up on.
+const POLL_ATTEMPTS: u32 = 60;
+/// Delay before a failed order is retried.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+/// Files stored under the certificate directory.
+const ACCOUNT_KEY_FILE: &str = "account.key";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// Config options for the certificate of the DoT and DoH listeners
+#[derive(Clone, Debug)]
+pub struct CertificateOptions {
+    /// Names the certificate is for, each in a hosted zone.
+    pub domains: Vec<String>,
+    pub directory_url: Uri,
+    /// Contact URLs of the account.
+    pub contact: Vec<String>,
+    /// Directory the account key, certificate and its key are stored in.
+    pub dir: PathBuf,
+    /// How long before it expires the certificate is renewed.
+    pub renew_before: Duration,
+    /// Interval between checks of whether the certificate is due for renewal.
+    pub check_interval: Duration,
+    /// Delay between publishing the challenge records and having them checked.
+    pub propagation: Duration,
+}
+
+impl TryFrom<AcmeCertificateSettings> for CertificateOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: AcmeCertificateSettings) -> anyhow::Result<Self> {
+        if cfg.domains.is_empty() {
+            anyhow::bail!("acme.certificate.domains must list the names the certificate is for");
+        }
+        let domains = cfg
+            .domains
+            .iter()
+            .map(|domain| domain.trim_end_matches('.').to_ascii_lowercase())
+            .collect();
+        let directory_url: Uri = cfg
+            .directory_url
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid acme.certificate.directory_url {}: {}", cfg.directory_url, e))?;
+        if directory_url.scheme_str() != Some("https") && directory_url.scheme_str() != Some("http") {
+            anyhow::bail!("acme.certificate.directory_url must be an http(s) URL, got {}", cfg.directory_url);
+        }
+        Ok(CertificateOptions {
+            domains,
+            directory_url,
+            contact: cfg.contact,
+            dir: PathBuf::from(cfg.dir),
+            renew_before: Duration::from_secs(cfg.renew_before_days * 86400),
+            check_interval: Duration::from_secs(cfg.check_interval_secs.max(60)),
+            propagation: Duration::from_secs(cfg.propagation_secs),
+        })
+    }
+}
+
+/// The certificate the TLS listeners serve, swapped on renewal.
+#[derive(Default)]
+pub struct CertificateResolver {
+    current: StdRwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl CertificateResolver {
+    /// Serves the certificate chain at `cert_path` with the key at `key_path` from the
+    /// next handshake on.
+    fn install(&self, cert_path: &Path, key_path: &Path) -> anyhow::Result<()> {
+        let certs = tls_server::read_cert(cert_path).map_err(|e| anyhow::anyhow!("{}", e))?;
+        let key = tls_server::read_key_from_pem(key_path).map_err(|e| anyhow::anyhow!("{}: {}", key_path.display(), e))?;
+        let key = rustls::sign::any_supported_type(&key).map_err(|e| anyhow::anyhow!("{}: {}", key_path.display(), e))?;
+        *self.current.write().unwrap() = Some(Arc::new(CertifiedKey::new(certs, key)));
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// TLS config of a listener: serving the certificate of `resolver` when there is one,
+/// and otherwise the certificate chain at `cert_path` with the key at `key_path`.
+///
+/// # Errors
+///
+/// Returns an error if the certificate or key can't be loaded.
+pub fn server_config(resolver: Option<&Arc<CertificateResolver>>, cert_path: &Path, key_path: &Path) -> anyhow::Result<ServerConfig> {
+    if let Some(resolver) = resolver {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        return Ok(config);
+    }
+    if cert_path.as_os_str().is_empty() || key_path.as_os_str().is_empty() {
+        anyhow::bail!("cert_path and key_path must be set, unless [acme.certificate] provides the certificate");
+    }
+    let certs = tls_server::read_cert(cert_path)?;
+    let key = tls_server::read_key_from_pem(key_path)?;
+    Ok(tls_server::new_acceptor(certs, key)?)
+}
+
+/// Obtains and renews the certificate of the TLS listeners.
+pub struct CertificateManager {
+    options: CertificateOptions,
+    acme: AcmeOptions,
+    resolver: Arc<CertificateResolver>,
+    http: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl CertificateManager {
+    /// Sets up the certificate directory and serves the certificate stored there, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the directory can't be created, or the certificate stored
+    /// there can't be loaded.
+    pub fn new(options: CertificateOptions, acme: AcmeOptions) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&options.dir).map_err(|e| anyhow::anyhow!("can't create {}: {}", options.dir.display(), e))?;
+        let resolver = Arc::new(CertificateResolver::default());
+        let cert_path = options.dir.join(CERT_FILE);
+        if cert_path.exists() {
+            resolver.install(&cert_path, &options.dir.join(KEY_FILE))?;
+        }
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(CertificateManager {
+            options,
+            acme,
+            resolver,
+            http: Client::builder().build(connector),
+        })
+    }
+
+    /// The certificate served by the TLS listeners.
+    pub fn resolver(&self) -> Arc<CertificateResolver> {
+        self.resolver.clone()
+    }
+
+    /// Orders a certificate whenever the one stored is due for renewal, until shutdown.
+    /// The DNS server must be serving the zones of the names by then.
+    pub async fn run(self, state: Arc<RwLock<DnsState>>, shutdown: Shutdown) {
+        loop {
+            let delay = match self.due() {
+                None => self.options.check_interval,
+                Some(reason) => {
+                    println!("Ordering a certificate for {}: {}", self.options.domains.join(", "), reason);
+                    match self.renew(&state).await {
+                        Ok(expires) => {
+                            println!("Obtained a certificate for {}, valid for {} days", self.options.domains.join(", "), expires.as_secs() / 86400);
+                            self.options.check_interval
+                        }
+                        Err(e) => {
+                            eprintln!("Certificate order failed, retrying in an hour: {:#}", e);
+                            RETRY_INTERVAL
+                        }
+                    }
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = shutdown.clone().requested() => return,
+            }
+        }
+    }
+
+    /// Why the stored certificate needs renewing, or `None` if it doesn't yet.
+    fn due(&self) -> Option<String> {
+        let Ok(pem) = std::fs::read(self.options.dir.join(CERT_FILE)) else {
+            return Some("there is none yet".into());
+        };
+        let parsed = x509_parser::pem::parse_x509_pem(&pem).map_err(|e| e.to_string()).and_then(|(_, pem)| {
+            let cert = pem.parse_x509().map_err(|e| e.to_string())?;
+            let not_after = UNIX_EPOCH + Duration::from_secs(cert.validity().not_after.timestamp().max(0) as u64);
+            let names: Vec<String> = match cert.subject_alternative_name() {
+                Ok(Some(san)) => san
+                    .value
+                    .general_names
+                    .iter()
+                    .filter_map(|name| match name {
+                        x509_parser::extensions::GeneralName::DNSName(name) => Some(name.to_ascii_lowercase()),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            Ok((not_after, names))
+        });
+        let (not_after, names) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => return Some(format!("the stored certificate can't be read: {}", e)),
+        };
+        if let Some(missing) = self.options.domains.iter().find(|domain| !names.contains(domain)) {
+            return Some(format!("the stored certificate doesn't cover {}", missing));
+        }
+        match not_after.duration_since(SystemTime::now() + self.options.renew_before) {
+            Ok(_) => None,
+            Err(_) => Some("the stored certificate expires soon".into()),
+        }
+    }
+
+    /// Orders a certificate for the configured names and serves it, returning how long
+    /// it is valid for.
+    async fn renew(&self, state: &RwLock<DnsState>) -> anyhow::Result<Duration> {
+        let mut client = AcmeClient::connect(&self.http, &self.options).await?;
+        let (cert_pem, key_pem) = client.order(state, &self.acme, &self.options).await?;
+        let cert_path = self.options.dir.join(CERT_FILE);
+        let key_path = self.options.dir.join(KEY_FILE);
+        write_private(&key_path, key_pem.as_bytes())?;
+        write_private(&cert_path, cert_pem.as_bytes())?;
+        self.resolver.install(&cert_path, &key_path)?;
+        let (_, pem) = x509_parser::pem::parse_x509_pem(cert_pem.as_bytes()).map_err(|e| anyhow::anyhow!("invalid certificate: {}", e))?;
+        let cert = pem.parse_x509().map_err(|e| anyhow::anyhow!("invalid certificate: {}", e))?;
+        let not_after = UNIX_EPOCH + Duration::from_secs(cert.validity().not_after.timestamp().max(0) as u64);
+        Ok(not_after.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+}
+
+/// Writes `content` to `path` readable by the owner only, replacing it atomically. On
+/// Windows, the file takes the permissions of its directory instead.
+fn write_private(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).write(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(&tmp)
+        .map_err(|e| anyhow::anyhow!("can't write {}: {}", tmp.display(), e))?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// PEM encoding of `der` with the given label.
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {}-----\n", label);
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {}-----\n", label));
+    pem
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+/// An RFC 7807 problem document, as ACME errors are reported.
+#[derive(Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    identifier: Identifier,
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+/// A session with the certificate authority, under the stored account.
+struct AcmeClient<'a> {
+    http: &'a Client<HttpsConnector<HttpConnector>>,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    /// Public account key, in the JWK form requests are signed with until it is registered.
+    jwk: serde_json::Value,
+    /// Account URL, which requests are signed with once it is registered.
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl<'a> AcmeClient<'a> {
+    /// Fetches the directory and registers the stored account key, created first if there
+    /// is none, or finds the account it is registered to already.
+    async fn connect(http: &'a Client<HttpsConnector<HttpConnector>>, options: &CertificateOptions) -> anyhow::Result<AcmeClient<'a>> {
+        let rng = SystemRandom::new();
+        let key = load_account_key(&options.dir.join(ACCOUNT_KEY_FILE), &rng)?;
+        let public = key.public_key().as_ref();
+        // An uncompressed P-256 point: 0x04, then both coordinates
+        let jwk = json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&public[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&public[33..65]),
+        });
+        let response = http.get(options.directory_url.clone()).await?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        if !status.is_success() {
+            anyhow::bail!("ACME directory {} answered {}", options.directory_url, status);
+        }
+        let directory: Directory = serde_json::from_slice(&body).map_err(|e| anyhow::anyhow!("invalid ACME directory: {}", e))?;
+        let mut client = AcmeClient {
+            http,
+            directory,
+            key,
+            rng,
+            jwk,
+            kid: None,
+            nonce: None,
+        };
+        let url = client.directory.new_account.clone();
+        let payload = json!({ "termsOfServiceAgreed": true, "contact": options.contact });
+        let (_, headers, _) = client.post(&url, Some(&payload)).await?;
+        let kid = location(&headers).ok_or_else(|| anyhow::anyhow!("the ACME account has no URL"))?;
+        client.kid = Some(kid);
+        Ok(client)
+    }
+
+    /// Orders a certificate for the configured names, validating them through challenge
+    /// records in `state`. Returns the certificate chain and its key, in PEM.
+    async fn order(&mut self, state: &RwLock<DnsState>, acme: &AcmeOptions, options: &CertificateOptions) -> anyhow::Result<(String, String)> {
+        let identifiers: Vec<_> = options.domains.iter().map(|domain| json!({ "type": "dns", "value": domain })).collect();
+        let url = self.directory.new_order.clone();
+        let (_, headers, body) = self.post(&url, Some(&json!({ "identifiers": identifiers }))).await?;
+        let order_url = location(&headers).ok_or_else(|| anyhow::anyhow!("the ACME order has no URL"))?;
+        let order: Order = serde_json::from_slice(&body)?;
+
+        // Publish the challenge records of every name, then have them all checked
+        let mut published = Vec::new();
+        let result = self.authorize(state, acme, options, &order.authorizations, &mut published).await;
+        for (name, digest) in &published {
+            let state = state.read().await;
+            if let Err(e) = acme::clear_challenge(&state, name, digest).await {
+                eprintln!("Failed to clear the ACME challenge at {}: {}", name, e);
+            }
+        }
+        result?;
+
+        // Request the certificate for a fresh key, then fetch it once it is issued
+        let mut params = rcgen::CertificateParams::new(options.domains.clone());
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        params.distinguished_name.push(rcgen::DnType::CommonName, options.domains[0].clone());
+        let cert = rcgen::Certificate::from_params(params)?;
+        let csr = cert.serialize_request_der()?;
+        self.post(&order.finalize, Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) }))).await?;
+        let mut attempts = 0;
+        let certificate_url = loop {
+            let (_, _, body) = self.post(&order_url, None).await?;
+            let order: Order = serde_json::from_slice(&body)?;
+            match (order.status.as_str(), order.certificate) {
+                ("valid", Some(url)) => break url,
+                ("invalid", _) => anyhow::bail!("the ACME order was refused: {}", problem_detail(order.error.as_ref())),
+                _ if attempts >= POLL_ATTEMPTS => anyhow::bail!("the ACME order is still {} after {} checks", order.status, attempts),
+                _ => attempts += 1,
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        };
+        let (_, _, chain) = self.post(&certificate_url, None).await?;
+        let chain = String::from_utf8(chain.to_vec()).map_err(|_| anyhow::anyhow!("the certificate chain isn't PEM"))?;
+        Ok((chain, pem("PRIVATE KEY", &cert.serialize_private_key_der())))
+    }
+
+    /// Publishes the DNS-01 challenge records of the pending `authorizations`, recording
+    /// them in `published` to be cleared, and waits until the authority has checked them.
+    async fn authorize(
+        &mut self,
+        state: &RwLock<DnsState>,
+        acme: &AcmeOptions,
+        options: &CertificateOptions,
+        authorizations: &[String],
+        published: &mut Vec<(String, String)>,
+    ) -> anyhow::Result<()> {
+        let mut pending = Vec::new();
+        for url in authorizations {
+            let (_, _, body) = self.post(url, None).await?;
+            let authorization: Authorization = serde_json::from_slice(&body)?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let domain = authorization.identifier.value;
+            let challenge = authorization
+                .challenges
+                .into_iter()
+                .find(|challenge| challenge.kind == "dns-01")
+                .ok_or_else(|| anyhow::anyhow!("the ACME authority offers no DNS-01 challenge for {}", domain))?;
+            let key_authorization = format!("{}.{}", challenge.token, self.thumbprint());
+            let digest = URL_SAFE_NO_PAD.encode(ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes()));
+            let name = acme::challenge_name(&domain);
+            acme.set_challenge(&mut *state.write().await, &name, &digest, 0)
+                .await
+                .map_err(|e| anyhow::anyhow!("can't publish the ACME challenge of {}: {}", domain, e))?;
+            published.push((name, digest));
+            pending.push((url.clone(), challenge.url));
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+        tokio::time::sleep(options.propagation).await;
+        for (_, challenge_url) in &pending {
+            self.post(challenge_url, Some(&json!({}))).await?;
+        }
+        for (url, _) in &pending {
+            let mut attempts = 0;
+            loop {
+                let (_, _, body) = self.post(url, None).await?;
+                let authorization: Authorization = serde_json::from_slice(&body)?;
+                match authorization.status.as_str() {
+                    "valid" => break,
+                    "pending" | "processing" if attempts < POLL_ATTEMPTS => attempts += 1,
+                    status => {
+                        let error = authorization.challenges.iter().find(|challenge| challenge.kind == "dns-01").and_then(|challenge| challenge.error.as_ref());
+                        anyhow::bail!("the ACME challenge of {} is {}: {}", authorization.identifier.value, status, problem_detail(error));
+                    }
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// RFC 7638 thumbprint of the account key.
+    fn thumbprint(&self) -> String {
+        // The required members in lexicographic order, without whitespace
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#, self.jwk["x"], self.jwk["y"]);
+        URL_SAFE_NO_PAD.encode(ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes()))
+    }
+
+    /// POSTs `payload` signed with the account key to `url`, or an empty payload to fetch
+    /// a resource when there is none. A request refused for its nonce is sent once more.
+    async fn post(&mut self, url: &str, payload: Option<&serde_json::Value>) -> anyhow::Result<(StatusCode, HeaderMap, Bytes)> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let payload = payload.map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string())).unwrap_or_default();
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|_| anyhow::anyhow!("failed to sign the ACME request"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+            });
+            let request = hyper::Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .header(header::USER_AGENT, concat!("rdns/", env!("CARGO_PKG_VERSION")))
+                .body(Body::from(body.to_string()))?;
+            let response = self.http.request(request).await?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            if let Some(nonce) = headers.get("replay-nonce").and_then(|nonce| nonce.to_str().ok()) {
+                self.nonce = Some(nonce.to_string());
+            }
+            let body = read_body(response.into_body()).await?;
+            if status.is_success() {
+                return Ok((status, headers, body));
+            }
+            let problem: Option<Problem> = serde_json::from_slice(&body).ok();
+            if !retried && problem.as_ref().is_some_and(|problem| problem.kind.ends_with(":badNonce")) {
+                retried = true;
+                continue;
+            }
+            anyhow::bail!("ACME request to {} failed with {}: {}", url, status, problem_detail(problem.as_ref()));
+        }
+    }
+
+    async fn new_nonce(&self) -> anyhow::Result<String> {
+        let request = hyper::Request::builder()
+            .method(Method::HEAD)
+            .uri(&self.directory.new_nonce)
+            .body(Body::empty())?;
+        let response = self.http.request(request).await?;
+        response
+            .headers()
+            .get("replay-nonce")
+            .and_then(|nonce| nonce.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow::anyhow!("the ACME authority gave no nonce"))
+    }
+}
+
+/// Reads the account key at `path`, creating one first if there is none.
+fn load_account_key(path: &Path, rng: &SystemRandom) -> anyhow::Result<EcdsaKeyPair> {
+    let pkcs8 = match std::fs::read_to_string(path) {
+        Ok(pem) => {
+            let encoded: String = pem.lines().filter(|line| !line.starts_with("-----")).collect();
+            STANDARD.decode(encoded).map_err(|e| anyhow::anyhow!("invalid ACME account key {}: {}", path.display(), e))?
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+                .map_err(|_| anyhow::anyhow!("failed to generate an ACME account key"))?;
+            write_private(path, pem("PRIVATE KEY", pkcs8.as_ref()).as_bytes())?;
+            pkcs8.as_ref().to_vec()
+        }
+        Err(e) => return Err(anyhow::anyhow!("can't read {}: {}", path.display(), e)),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, rng)
+        .map_err(|e| anyhow::anyhow!("invalid ACME account key {}: {}", path.display(), e))
+}
+
+fn location(headers: &HeaderMap) -> Option<String> {
+    headers.get(header::LOCATION).and_then(|location| location.to_str().ok()).map(str::to_string)
+}
+
+fn problem_detail(problem: Option<&Problem>) -> String {
+    match problem {
+        Some(problem) if !problem.detail.is_empty() => problem.detail.clone(),
+        Some(problem) => problem.kind.clone(),
+        None => "no details given".into(),
+    }
+}
+
+/// Reads a response body of at most a megabyte, more than a certificate chain takes.
+async fn read_body(mut body: Body) -> anyhow::Result<Bytes> {
+    let mut read = Vec::new();
+    while let Some(chunk) = body.data().await {
+        read.extend_from_slice(&chunk?);
+        if read.len() > 1 << 20 {
+            anyhow::bail!("the ACME response is too large");
+        }
+    }
+    Ok(read.into())
+}
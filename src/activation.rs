@@ -0,0 +1,78 @@
+//! Systemd socket activation.
+//!
+//! When systemd starts rdns from a socket unit, it passes the sockets it bound, e.g. on
+//! port 53, as file descriptors from 3 on, announced through `LISTEN_FDS` and
+//! `LISTEN_PID`. The DNS server serves each listen address from the activated UDP and TCP
+//! sockets bound to it, and only binds those it wasn't given. Activated sockets matching
+//! no listen address are closed with a warning. There is no socket activation outside
+//! of unix, where every listen address is bound.
+
+use socket2::{Socket, Type};
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, RawFd};
+
+/// First file descriptor passed by systemd.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The sockets passed by systemd, taken as they are matched with the listen addresses.
+#[derive(Default)]
+pub struct ActivatedSockets {
+    sockets: Vec<(SocketAddr, Type, Socket)>,
+}
+
+impl ActivatedSockets {
+    /// Takes the sockets passed to this process, if it was socket activated. The
+    /// environment variables announcing them are removed, so that they aren't taken twice
+    /// nor passed on to child processes.
+    #[cfg(unix)]
+    pub fn from_env() -> std::io::Result<Self> {
+        let pid = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+        let count = std::env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok());
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+        let (Some(pid), Some(count)) = (pid, count) else {
+            return Ok(Self::default());
+        };
+        if pid != std::process::id() {
+            return Ok(Self::default());
+        }
+
+        let mut sockets = Vec::new();
+        for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+            // Safety: systemd hands the descriptors over to this process, which owns them
+            // from here on and takes each of them only once
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+            socket.set_nonblocking(true)?;
+            let Some(addr) = socket.local_addr()?.as_socket() else {
+                eprintln!("Ignoring activated socket {}, which is not an IP socket", fd);
+                continue;
+            };
+            sockets.push((addr, socket.r#type()?, socket));
+        }
+        Ok(Self { sockets })
+    }
+
+    /// No sockets, as only systemd activates them.
+    #[cfg(not(unix))]
+    pub fn from_env() -> std::io::Result<Self> {
+        Ok(Self::default())
+    }
+
+    /// Takes the activated socket of `kind` bound to `addr`, if there is one.
+    pub fn take(&mut self, addr: SocketAddr, kind: Type) -> Option<Socket> {
+        let index = self.sockets.iter().position(|(bound, bound_kind, _)| *bound == addr && *bound_kind == kind)?;
+        Some(self.sockets.swap_remove(index).2)
+    }
+
+    /// Closes the sockets no listen address took, warning about each of them.
+    pub fn close_unused(self) {
+        for (addr, kind, _) in self.sockets {
+            let protocol = if kind == Type::DGRAM { "UDP" } else { "TCP" };
+            eprintln!("Closing activated socket {} ({}), which is not a listen address", addr, protocol);
+        }
+    }
+}
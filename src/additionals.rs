@@ -0,0 +1,135 @@
+//! Additional-section processing of MX, SRV and NS answers.
+//!
+//! The zones answer MX, SRV and NS queries with the addresses of the targets in the
+//! zone of the queried name in the additional section, but not with those of targets in
+//! the other hosted zones, e.g. an MX of `example.com` pointing at `mx.example.net`. With
+//! `dns.additional_records` set, the A and AAAA records of every target in a hosted zone
+//! are added, so that clients don't have to query them in turn (RFC 1034 section 3.6.2,
+//! RFC 2782). Targets the additional section already has addresses of are left as they
+//! are.
+//!
+//! Additional records are only there to spare round trips, so over UDP they are added
+//! while the response still fits the client's buffer, and those that wouldn't are left
+//! out rather than truncating the response. Like CNAME chains (see `cnamechain`), signed
+//! answers, to queries with the DO bit, are left as the zone gives them.
+
+use hickory_proto::rr::rdata::NS;
+use hickory_proto::rr::{LowerName, RData, Record, RecordType};
+use hickory_proto::serialize::binary::{BinDecodable, BinEncodable, BinEncoder};
+use hickory_server::authority::{MessageRequest, MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use std::sync::Arc;
+use tonic::async_trait;
+
+use crate::catalogsnapshot::CatalogSnapshot;
+
+/// Room left in responses for the options added to their OPT record after this handler,
+/// like a server cookie.
+const RESERVED: usize = 40;
+
+/// Response handler that adds the addresses of the MX, SRV and NS targets of answers from
+/// the hosted zones of `snapshot` before sending them on.
+#[derive(Clone)]
+pub struct AdditionalResponseHandler<R> {
+    inner: R,
+    snapshot: Arc<CatalogSnapshot>,
+    /// Size the response must fit in, over UDP.
+    limit: Option<u16>,
+}
+
+impl<R> AdditionalResponseHandler<R> {
+    pub fn new(inner: R, snapshot: Arc<CatalogSnapshot>, limit: Option<u16>) -> Self {
+        Self { inner, snapshot, limit }
+    }
+}
+
+/// Whether the additional section of the answers to `request` is completed.
+pub fn applies(request: &Request, enabled: bool) -> bool {
+    let record_type = request.query().query_type();
+    let signed = request.edns().is_some_and(|edns| edns.dnssec_ok());
+    enabled && !signed && matches!(record_type, RecordType::MX | RecordType::SRV | RecordType::NS)
+}
+
+/// The name whose addresses complete `record`, if it is an MX, SRV or NS record.
+fn target(record: &Record) -> Option<LowerName> {
+    let target = match record.data()? {
+        RData::MX(mx) => mx.exchange(),
+        RData::SRV(srv) => srv.target(),
+        RData::NS(NS(target)) => target,
+        _ => return None,
+    };
+    // An SRV target of "." means the service isn't available at the name
+    (!target.is_root()).then(|| LowerName::new(target))
+}
+
+impl<R> AdditionalResponseHandler<R> {
+    /// Adds the addresses of the targets of `records` the additional section has none of,
+    /// while the response, of `size` bytes so far, fits the limit.
+    async fn complete(&self, records: &[Record], additionals: &mut Vec<Record>, mut size: usize) -> std::io::Result<()> {
+        let limit = self.limit.map_or(usize::MAX, |limit| usize::from(limit).saturating_sub(RESERVED));
+        let mut targets: Vec<LowerName> = Vec::new();
+        for target in records.iter().filter_map(target) {
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+        for target in targets {
+            let known = additionals.iter().any(|record| {
+                matches!(record.record_type(), RecordType::A | RecordType::AAAA) && LowerName::new(record.name()) == target
+            });
+            if known {
+                continue;
+            }
+            for record_type in [RecordType::A, RecordType::AAAA] {
+                for record in self.snapshot.lookup_records(&target, record_type).await {
+                    // Names are compressed in the response, so this overestimates the room taken
+                    size += record.to_bytes().map_err(std::io::Error::other)?.len();
+                    if size > limit {
+                        return Ok(());
+                    }
+                    additionals.push(record);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for AdditionalResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        // The sections can't be inspected in place, so the response is encoded and re-read,
+        // keeping the size it takes, then rebuilt with the addresses added
+        let mut buffer = Vec::new();
+        response
+            .destructive_emit(&mut BinEncoder::new(&mut buffer))
+            .map_err(std::io::Error::other)?;
+        let message = MessageRequest::from_bytes(&buffer).map_err(std::io::Error::other)?;
+        let mut additionals = message.additionals().to_vec();
+        let records: Vec<Record> = message.answers().iter().chain(message.name_servers()).cloned().collect();
+        self.complete(&records, &mut additionals, buffer.len()).await?;
+
+        let mut builder = MessageResponseBuilder::from_message_request(&message);
+        if let Some(edns) = message.edns() {
+            builder.edns(edns.clone());
+        }
+        let response = builder.build(
+            *message.header(),
+            message.answers(),
+            message.name_servers(),
+            [],
+            additionals.iter().chain(message.sig0()),
+        );
+        self.inner.send_response(response).await
+    }
+}
@@ -0,0 +1,88 @@
+//! ALIAS records.
+//!
+//! A CNAME can't share its name with other records, so it can't be placed at a zone apex
+//! next to the SOA and NS records. An alias makes A and AAAA queries for a name answered
+//! with the addresses of its target instead, looked up as the queries come in: from the
+//! zone when the target is hosted, otherwise through the forwarder, whose cache keeps
+//! them for their TTL, so the alias follows the target as its addresses change. The
+//! answers carry the alias name as if the addresses were its own, with the TTL of the
+//! alias at most.
+//!
+//! Queries of other types are answered from the zone's own records, as are address
+//! queries when the target can't be resolved. Targets are not followed through further
+//! aliases.
+
+use hickory_proto::rr::{LowerName, Name, Record, RecordType};
+use hickory_server::authority::{Catalog, LookupOptions};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::dns::DnsState;
+use crate::error::RdnsError;
+
+/// An alias of a name, as managed through the control API and persisted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AliasEntry {
+    pub name: String,
+    /// Name whose addresses are answered for `name`
+    pub target: String,
+    /// Largest TTL of the answers
+    pub ttl: u32,
+}
+
+/// An alias with its names parsed.
+pub struct Alias {
+    pub entry: AliasEntry,
+    name: LowerName,
+    target: LowerName,
+}
+
+/// Aliases by the name they answer for.
+pub type AliasTable = RwLock<HashMap<LowerName, Arc<Alias>>>;
+
+impl Alias {
+    /// Parses `entry`, bringing its names into canonical form: lower-cased and fully
+    /// qualified.
+    pub fn new(mut entry: AliasEntry) -> Result<Self, RdnsError> {
+        let name = DnsState::parse_name(&entry.name)?.to_lowercase();
+        let target = DnsState::parse_name(&entry.target)?.to_lowercase();
+        if name == target {
+            return Err(RdnsError::InvalidArgument(format!("{} can't be an alias of itself", name)));
+        }
+        entry.name = name.to_string();
+        entry.target = target.to_string();
+        Ok(Alias {
+            entry,
+            name: LowerName::new(&name),
+            target: LowerName::new(&target),
+        })
+    }
+
+    /// The name the alias answers for.
+    pub fn key(&self) -> &LowerName {
+        &self.name
+    }
+
+    /// The `record_type` records of the target, renamed to `name`; none when the target
+    /// can't be resolved or has no such records.
+    pub async fn resolve(&self, catalog: &Catalog, name: &Name, record_type: RecordType) -> Vec<Record> {
+        let Some(authority) = catalog.find(&self.target) else {
+            return Vec::new();
+        };
+        let Ok(lookup) = authority.lookup(&self.target, record_type, LookupOptions::default()).await else {
+            return Vec::new();
+        };
+        // A CNAME chain leading to the addresses is flattened away
+        lookup
+            .iter()
+            .filter(|record| record.record_type() == record_type)
+            .map(|record| {
+                let mut record = record.clone();
+                record.set_name(name.clone());
+                record.set_ttl(record.ttl().min(self.entry.ttl));
+                record
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,76 @@
+//! Minimal answers to ANY queries (RFC 8482).
+//!
+//! An ANY query for a hosted name is answered with a single synthesized HINFO record,
+//! `"RFC8482" ""`, instead of every record of the name, so that small queries can't be
+//! turned into large responses against a spoofed source. With `any_response = "subset"`
+//! it is answered with one of the name's RRsets instead, its addresses if it has any,
+//! and with `"full"` with every record as before. Names without records and forwarded
+//! names are left to the catalog, so they still get NXDOMAIN or the upstream's answer.
+
+use hickory_proto::rr::rdata::HINFO;
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use std::fmt;
+use std::str::FromStr;
+
+/// TTL of the synthesized HINFO record, unless the name's records have a shorter one.
+const HINFO_TTL: u32 = 3600;
+/// Types the RRset of a subset answer is picked from, in order of preference.
+const SUBSET_PREFERENCE: [RecordType; 5] = [RecordType::A, RecordType::AAAA, RecordType::CNAME, RecordType::MX, RecordType::TXT];
+
+/// How ANY queries for hosted names are answered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnyResponse {
+    /// A single synthesized HINFO record
+    #[default]
+    Hinfo,
+    /// One RRset of the name
+    Subset,
+    /// Every record of the name
+    Full,
+}
+
+impl FromStr for AnyResponse {
+    type Err = anyhow::Error;
+
+    fn from_str(mode: &str) -> anyhow::Result<Self> {
+        match mode.to_ascii_lowercase().as_str() {
+            "hinfo" => Ok(AnyResponse::Hinfo),
+            "subset" => Ok(AnyResponse::Subset),
+            "full" => Ok(AnyResponse::Full),
+            _ => anyhow::bail!("unknown dns.any_response {}, expected hinfo, subset or full", mode),
+        }
+    }
+}
+
+impl fmt::Display for AnyResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AnyResponse::Hinfo => "hinfo",
+            AnyResponse::Subset => "subset",
+            AnyResponse::Full => "full",
+        })
+    }
+}
+
+/// The answer to an ANY query for `name`, given its `records`; none when the name has no
+/// records or every one is to be answered.
+pub fn minimal_answer(mode: AnyResponse, name: &Name, records: Vec<Record>) -> Option<Vec<Record>> {
+    if records.is_empty() {
+        return None;
+    }
+    match mode {
+        AnyResponse::Full => None,
+        AnyResponse::Hinfo => {
+            let ttl = records.iter().map(Record::ttl).min().unwrap_or(HINFO_TTL).min(HINFO_TTL);
+            let hinfo = HINFO::new("RFC8482".into(), String::new());
+            Some(vec![Record::from_rdata(name.clone(), ttl, RData::HINFO(hinfo))])
+        }
+        AnyResponse::Subset => {
+            let record_type = SUBSET_PREFERENCE
+                .into_iter()
+                .find(|preferred| records.iter().any(|record| record.record_type() == *preferred))
+                .unwrap_or_else(|| records[0].record_type());
+            Some(records.into_iter().filter(|record| record.record_type() == record_type).collect())
+        }
+    }
+}
@@ -0,0 +1,205 @@
+//! Rate and concurrency limits of the control API.
+//!
+//! A runaway client, such as a script stuck in a retry loop, can call the control API
+//! faster than its mutations are applied, and as each of them takes the state's write
+//! lock, hold up the DNS queries answered from the state meanwhile. With
+//! `[grpc.limits]`, calls beyond `requests_per_second` from all callers together, or
+//! beyond `peer_requests_per_second` from one client address, are refused with
+//! RESOURCE_EXHAUSTED for the client to back off; either rate may be exceeded in bursts of
+//! up to a second's worth of calls. Calls over a Unix domain socket have no client address,
+//! and only count towards the global rate. Mutating calls beyond `max_mutations_in_flight`
+//! wait for one of those in flight to finish, while the calls that only read the state
+//! aren't held up. The health and reflection services are never limited.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tonic::body::BoxBody;
+use tonic::codegen::http;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::Status;
+
+use crate::metrics::Metrics;
+use crate::settings::GrpcLimitSettings;
+
+/// Client addresses tracked before idle ones are forgotten.
+const MAX_TRACKED: usize = 65_536;
+
+/// Prefixes of the names of the methods that only read the state.
+const READ_METHODS: [&str; 10] = ["Get", "List", "Query", "Verify", "Export", "Check", "Watch", "ClusterStatus", "BackupState", "Replicate"];
+
+/// Config options for the control API limits
+#[derive(Debug, Clone, Default)]
+pub struct ApiLimitOptions {
+    /// Calls per second from all callers together; unlimited when unset.
+    pub requests_per_second: Option<u32>,
+    /// Calls per second from each client address; unlimited when unset.
+    pub peer_requests_per_second: Option<u32>,
+    /// Mutating calls handled at once; unlimited when unset.
+    pub max_mutations_in_flight: Option<usize>,
+}
+
+impl TryFrom<GrpcLimitSettings> for ApiLimitOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: GrpcLimitSettings) -> anyhow::Result<Self> {
+        if cfg.requests_per_second == Some(0) {
+            anyhow::bail!("grpc.limits.requests_per_second must be at least 1");
+        }
+        if cfg.peer_requests_per_second == Some(0) {
+            anyhow::bail!("grpc.limits.peer_requests_per_second must be at least 1");
+        }
+        if cfg.max_mutations_in_flight == Some(0) {
+            anyhow::bail!("grpc.limits.max_mutations_in_flight must be at least 1");
+        }
+        Ok(ApiLimitOptions {
+            requests_per_second: cfg.requests_per_second,
+            peer_requests_per_second: cfg.peer_requests_per_second,
+            max_mutations_in_flight: cfg.max_mutations_in_flight,
+        })
+    }
+}
+
+/// Credit of the calls of one client, or of all of them.
+struct Bucket {
+    credit: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Bucket { credit: rate, updated: now }
+    }
+
+    /// Charges a call at `now`, returning whether `rate` allows it.
+    fn take(&mut self, rate: f64, now: Instant) -> bool {
+        let earned = now.duration_since(self.updated).as_secs_f64() * rate;
+        self.credit = (self.credit + earned).min(rate);
+        self.updated = now;
+        if self.credit < 1.0 {
+            return false;
+        }
+        self.credit -= 1.0;
+        true
+    }
+}
+
+/// The limits in effect, shared by the services of every connection.
+struct Limiter {
+    options: ApiLimitOptions,
+    global: Mutex<Bucket>,
+    peers: Mutex<HashMap<IpAddr, Bucket>>,
+    mutations: Option<Arc<Semaphore>>,
+    metrics: Arc<Metrics>,
+}
+
+impl Limiter {
+    /// Charges a call from `peer`, failing with the status it is refused with if one of
+    /// the rates doesn't allow it.
+    #[allow(clippy::result_large_err)] // returns `Status` like the RPCs it guards
+    fn admit(&self, peer: Option<IpAddr>) -> Result<(), Status> {
+        let now = Instant::now();
+        if let (Some(rate), Some(peer)) = (self.options.peer_requests_per_second, peer) {
+            let rate = f64::from(rate);
+            let mut peers = self.peers.lock().unwrap();
+            if peers.len() >= MAX_TRACKED && !peers.contains_key(&peer) {
+                // Buckets left alone for a second are full again, as new ones are
+                peers.retain(|_, bucket| now.duration_since(bucket.updated) < Duration::from_secs(1));
+            }
+            if !peers.entry(peer).or_insert_with(|| Bucket::new(rate, now)).take(rate, now) {
+                self.metrics.observe_grpc_limited("peer");
+                return Err(Status::resource_exhausted(format!("too many calls from {}, retry later", peer)));
+            }
+        }
+        if let Some(rate) = self.options.requests_per_second {
+            if !self.global.lock().unwrap().take(f64::from(rate), now) {
+                self.metrics.observe_grpc_limited("global");
+                return Err(Status::resource_exhausted("too many calls, retry later"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tower layer applying the control API limits to every call, when they are configured.
+#[derive(Clone)]
+pub struct LimitLayer(Option<Arc<Limiter>>);
+
+impl LimitLayer {
+    pub fn new(options: Option<ApiLimitOptions>, metrics: Arc<Metrics>) -> Self {
+        LimitLayer(options.map(|options| {
+            let rate = f64::from(options.requests_per_second.unwrap_or_default());
+            Arc::new(Limiter {
+                global: Mutex::new(Bucket::new(rate, Instant::now())),
+                peers: Mutex::new(HashMap::new()),
+                mutations: options.max_mutations_in_flight.map(|max| Arc::new(Semaphore::new(max))),
+                metrics,
+                options,
+            })
+        }))
+    }
+}
+
+impl<S> tower::Layer<S> for LimitLayer {
+    type Service = LimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LimitService {
+            inner,
+            limiter: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LimitService<S> {
+    inner: S,
+    limiter: Option<Arc<Limiter>>,
+}
+
+impl<S, B> tower::Service<http::Request<B>> for LimitService<S>
+where
+    S: tower::Service<http::Request<B>, Response = http::Response<BoxBody>> + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let path = request.uri().path().trim_start_matches('/');
+        let Some(limiter) = self.limiter.clone().filter(|_| !path.starts_with("grpc.")) else {
+            return Box::pin(self.inner.call(request));
+        };
+        let method = path.rsplit('/').next().unwrap_or_default();
+        let mutation = !READ_METHODS.iter().any(|prefix| method.starts_with(prefix));
+        // IPv4 clients of dual-stack sockets arrive as mapped IPv6 addresses
+        let peer = request
+            .extensions()
+            .get::<TcpConnectInfo>()
+            .and_then(TcpConnectInfo::remote_addr)
+            .map(|addr| addr.ip().to_canonical());
+        if let Err(status) = limiter.admit(peer) {
+            return Box::pin(async move { Ok(status.to_http()) });
+        }
+        let mutations = limiter.mutations.clone().filter(|_| mutation);
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let _permit = match mutations {
+                Some(mutations) => mutations.acquire_owned().await.ok(),
+                None => None,
+            };
+            future.await
+        })
+    }
+}
@@ -0,0 +1,64 @@
+//! Versions of the control API.
+//!
+//! The control services are defined in versioned protobuf packages: `rdns.control.v1`
+//! holds the complete API, with record values as zone file text, and `rdns.control.v2`
+//! the `Records` service, whose values are typed. Both are served by the same server
+//! and share its state, tokens and audit log, so clients can move to v2 one call at a
+//! time.
+//!
+//! Clients built before the packages were versioned call the v1 services under their
+//! old `control` package, e.g. `/control.DnsControl/AddRecord`; those calls are routed
+//! to the v1 services as they are.
+
+use std::task::{Context, Poll};
+use tonic::codegen::http;
+
+/// Path prefix of the calls of clients built before the packages were versioned.
+const LEGACY_PREFIX: &str = "/control.";
+/// Path prefix of the v1 services the legacy calls are routed to.
+const V1_PREFIX: &str = "/rdns.control.v1.";
+
+/// Layer routing the calls of the unversioned `control` package to the v1 services.
+#[derive(Clone, Copy, Default)]
+pub struct LegacyPackageLayer;
+
+impl<S> tower::Layer<S> for LegacyPackageLayer {
+    type Service = LegacyPackageService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        LegacyPackageService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct LegacyPackageService<S> {
+    inner: S,
+}
+
+impl<S, B> tower::Service<http::Request<B>> for LegacyPackageService<S>
+where
+    S: tower::Service<http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<B>) -> Self::Future {
+        if let Some(uri) = v1_uri(request.uri()) {
+            *request.uri_mut() = uri;
+        }
+        self.inner.call(request)
+    }
+}
+
+/// The URI of the v1 method a legacy call of `uri` is for; none for other calls.
+fn v1_uri(uri: &http::Uri) -> Option<http::Uri> {
+    let method = uri.path().strip_prefix(LEGACY_PREFIX)?;
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(format!("{}{}", V1_PREFIX, method).parse().ok()?);
+    http::Uri::from_parts(parts).ok()
+}
@@ -0,0 +1,84 @@
+//! Record changes awaiting approval.
+//!
+//! Regulated environments need a second person to sign off on each change to DNS. Tokens
+//! with the `propose` role may call `AddRecord`, `DeleteRecord` and `ApplyChangeset`,
+//! but their calls change no record: each is checked as the call would be, then queued
+//! as a change request under an id. An admin lists the requests with
+//! `ListPendingApprovals`, and either applies one with `ApproveChange`, as a single
+//! changeset, or drops it with `RejectChange`. A request is applied to the records as
+//! they are when it is approved, so one that no longer applies, e.g. as its zone was
+//! deleted meanwhile, fails to be approved and stays pending until it is rejected. Tokens
+//! scoped to a tenant only see and decide on the requests for the tenant's zones.
+//!
+//! Change requests are persisted and replicated, and only the leader takes them.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::dns::ChangeOperation;
+
+/// A proposed change, as persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeRequest {
+    pub id: u64,
+    /// The changeset applied on approval
+    pub operations: Vec<ChangeOperation>,
+    /// Call that proposed the change, e.g. `AddRecord`
+    pub method: String,
+    /// Name of the token that proposed the change, unset when authentication is disabled
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_by: Option<String>,
+    pub requested_at: SystemTime,
+}
+
+impl ChangeRequest {
+    /// The names the operations of the request change.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.operations.iter().map(|operation| operation.target().0)
+    }
+}
+
+/// The pending change requests, by id.
+#[derive(Default)]
+pub struct Approvals {
+    requests: Mutex<BTreeMap<u64, ChangeRequest>>,
+    /// Id of the last request taken, so that ids aren't reused once requests are gone.
+    last_id: AtomicU64,
+}
+
+impl Approvals {
+    /// Queues `request`, giving it the next id, which is returned.
+    pub fn add(&self, mut request: ChangeRequest) -> u64 {
+        request.id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let id = request.id;
+        self.requests.lock().unwrap().insert(id, request);
+        id
+    }
+
+    /// Queues `request` under the id it has, e.g. as loaded from a snapshot.
+    pub fn insert(&self, request: ChangeRequest) {
+        self.last_id.fetch_max(request.id, Ordering::Relaxed);
+        self.requests.lock().unwrap().insert(request.id, request);
+    }
+
+    pub fn get(&self, id: u64) -> Option<ChangeRequest> {
+        self.requests.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Takes the request `id` out of the queue.
+    pub fn remove(&self, id: u64) -> Option<ChangeRequest> {
+        self.requests.lock().unwrap().remove(&id)
+    }
+
+    pub fn clear(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+
+    /// Every pending request, oldest first.
+    pub fn entries(&self) -> Vec<ChangeRequest> {
+        self.requests.lock().unwrap().values().cloned().collect()
+    }
+}
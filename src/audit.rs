@@ -0,0 +1,212 @@
+//! Audit log of control-plane mutations.
+//!
+//! With `[audit]` configured, every record and zone change made through the gRPC API
+//! (`AddRecord`, `UpdateRecord`, `DeleteRecord`, `DeleteRecordValue`, `CreateZone`,
+//! `DeleteZone`, `ImportZone`, `RollbackZone`), the REST gateway or the external-dns
+//! webhook is appended to a file, one JSON object per line, holding when the change was
+//! made, the name of the API token and the peer address of the caller, the records it
+//! affected as they were before and after it, and, when a zone history is kept, the
+//! change id of the zone version it produced. The file is only ever appended to;
+//! `GetAuditLog` reads the entries of a time range back for compliance reviews.
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::dns::{DnsState, RecordEntry};
+use crate::settings::AuditSettings;
+
+/// Config options for the audit log
+pub struct AuditOptions {
+    pub path: PathBuf,
+}
+
+impl From<AuditSettings> for AuditOptions {
+    fn from(cfg: AuditSettings) -> Self {
+        AuditOptions {
+            path: PathBuf::from(cfg.path),
+        }
+    }
+}
+
+/// Who made a change.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Actor {
+    /// Name of the API token presented, unset when authentication is disabled.
+    pub token: Option<String>,
+    /// Address the call came from, when known.
+    pub peer: Option<String>,
+}
+
+/// One change, as it is written to the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: SystemTime,
+    #[serde(flatten)]
+    pub actor: Actor,
+    /// The call that made the change, e.g. `AddRecord`.
+    pub action: String,
+    /// The record name or zone origin the change applies to.
+    pub target: String,
+    pub before: Vec<RecordEntry>,
+    pub after: Vec<RecordEntry>,
+    /// Change id of the zone version the change produced, when a zone history is kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_id: Option<u64>,
+}
+
+/// The append-only log file.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Opens the log at the path `options` names, creating it if needed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be opened for appending.
+    pub async fn open(options: AuditOptions) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&options.path)
+            .await
+            .map_err(|e| anyhow::anyhow!("can't open audit log {}: {}", options.path.display(), e))?;
+        Ok(Self {
+            path: options.path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends `entry` to the log. A failure is logged rather than returned, as the
+    /// change the entry records has already been made.
+    pub async fn append(&self, entry: &AuditEntry) {
+        let result = async {
+            let mut line = serde_json::to_vec(entry)?;
+            line.push(b'\n');
+            let mut file = self.file.lock().await;
+            file.write_all(&line).await?;
+            file.sync_data().await?;
+            anyhow::Ok(())
+        }
+        .await;
+        if let Err(e) = result {
+            eprintln!("Failed to write {} of {} to audit log: {}", entry.action, entry.target, e);
+        }
+    }
+
+    /// Reads the entries made in `since..until`, oldest first; either bound may be open.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read.
+    pub async fn read(&self, since: Option<SystemTime>, until: Option<SystemTime>) -> anyhow::Result<Vec<AuditEntry>> {
+        // Holding the lock keeps a half-written line from being read
+        let _file = self.file.lock().await;
+        let content = tokio::fs::read_to_string(&self.path).await?;
+        Ok(content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+            .filter(|entry| since.is_none_or(|since| entry.timestamp >= since))
+            .filter(|entry| until.is_none_or(|until| entry.timestamp < until))
+            .collect())
+    }
+}
+
+/// What a change applies to.
+enum Scope {
+    Records { name: String, record_type: String },
+    Zone(String),
+}
+
+impl Scope {
+    async fn records(&self, state: &DnsState) -> Vec<RecordEntry> {
+        let records = match self {
+            Scope::Records { name, record_type } => state.get_record(name.clone(), record_type.clone()).await,
+            Scope::Zone(origin) => state.get_zone_records(origin).await,
+        };
+        records.unwrap_or_default()
+    }
+}
+
+/// A change about to be made, holding the records it affects as they were beforehand.
+pub struct Change<'a> {
+    log: &'a AuditLog,
+    actor: Actor,
+    action: &'static str,
+    scope: Scope,
+    before: Vec<RecordEntry>,
+}
+
+impl<'a> Change<'a> {
+    /// Starts recording a change to the records of `name` and `record_type`, if an
+    /// audit log is kept.
+    pub async fn records(
+        log: Option<&'a AuditLog>,
+        state: &DnsState,
+        actor: Actor,
+        action: &'static str,
+        name: &str,
+        record_type: &str,
+    ) -> Option<Change<'a>> {
+        let scope = Scope::Records {
+            name: name.to_string(),
+            record_type: record_type.to_string(),
+        };
+        Change::start(log?, state, actor, action, scope).await
+    }
+
+    /// Starts recording a change to the zone at `origin`, if an audit log is kept.
+    pub async fn zone(
+        log: Option<&'a AuditLog>,
+        state: &DnsState,
+        actor: Actor,
+        action: &'static str,
+        origin: &str,
+    ) -> Option<Change<'a>> {
+        Change::start(log?, state, actor, action, Scope::Zone(origin.to_string())).await
+    }
+
+    async fn start(log: &'a AuditLog, state: &DnsState, actor: Actor, action: &'static str, scope: Scope) -> Option<Change<'a>> {
+        let before = scope.records(state).await;
+        Some(Change {
+            log,
+            actor,
+            action,
+            scope,
+            before,
+        })
+    }
+
+    /// Appends the change to the log, with the records it affects as they are now.
+    pub async fn finish(self, state: &DnsState) {
+        let after = self.scope.records(state).await;
+        let target = match self.scope {
+            Scope::Records { name, .. } => name,
+            Scope::Zone(origin) => origin,
+        };
+        let change_id = state.latest_change_id(&target);
+        let entry = AuditEntry {
+            timestamp: SystemTime::now(),
+            actor: self.actor,
+            action: self.action.to_string(),
+            target,
+            before: self.before,
+            after,
+            change_id,
+        };
+        self.log.append(&entry).await;
+    }
+}
+
+/// Appends `change` to its log if it was made, i.e. `result` is a success.
+pub async fn finish<T, E>(change: Option<Change<'_>>, state: &DnsState, result: &Result<T, E>) {
+    if let (Some(change), Ok(_)) = (change, result) {
+        change.finish(state).await;
+    }
+}
@@ -0,0 +1,294 @@
+//! API-token authentication for the gRPC control interface.
+//!
+//! Clients authenticate with an `authorization: Bearer <token>` header. Each token maps
+//! to a role: `read` tokens may only call the read RPCs, while `admin` tokens may call
+//! every RPC, including the mutations and `RotateToken`. `propose` tokens may call the
+//! read RPCs too, and propose record changes for an admin to approve; see `approval`.
+//! The interceptor attaches the caller's token name and role to the request and each RPC
+//! checks it with `require`.
+//!
+//! Tokens scoped to a tenant may only call the record and zone RPCs, which check them
+//! with `require_tenant` and then only act on the tenant's zones; see `tenant`.
+//!
+//! Tokens come from the config file or from a separate JSON tokens file; tokens from
+//! the tokens file are written back to it when rotated, while rotated config tokens
+//! only last until the server restarts.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+use crate::settings::{ApiTokenSettings, AuthSettings};
+
+/// What a token is allowed to do. Roles are ordered, so `Admin` can do anything `Read` can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Read,
+    /// Record changes are queued for approval rather than applied
+    Propose,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(role: &str) -> anyhow::Result<Self> {
+        match role {
+            "read" => Ok(Role::Read),
+            "propose" => Ok(Role::Propose),
+            "admin" => Ok(Role::Admin),
+            _ => anyhow::bail!("unknown role {}, expected read, propose or admin", role),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Read => write!(f, "read"),
+            Role::Propose => write!(f, "propose"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// The caller of a request, as authenticated by its bearer token.
+#[derive(Debug, Clone)]
+pub struct Caller {
+    /// Name of the token presented, unset when authentication is disabled.
+    pub token: Option<String>,
+    pub role: Role,
+    /// Tenant the token is scoped to, unset for tokens managing every zone.
+    pub tenant: Option<String>,
+}
+
+/// A named API token, as configured or stored in the tokens file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub name: String,
+    pub token: String,
+    pub role: Role,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+}
+
+impl TryFrom<ApiTokenSettings> for ApiToken {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ApiTokenSettings) -> anyhow::Result<Self> {
+        Ok(ApiToken {
+            name: cfg.name,
+            token: cfg.token,
+            role: cfg.role.parse()?,
+            tenant: cfg.tenant,
+        })
+    }
+}
+
+/// Config options for control API authentication
+pub struct AuthOptions {
+    pub tokens: Vec<ApiToken>,
+    /// JSON file holding a list of further tokens.
+    pub tokens_file: Option<PathBuf>,
+}
+
+impl TryFrom<AuthSettings> for AuthOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: AuthSettings) -> anyhow::Result<Self> {
+        Ok(AuthOptions {
+            tokens: cfg
+                .tokens
+                .into_iter()
+                .map(ApiToken::try_from)
+                .collect::<anyhow::Result<_>>()?,
+            tokens_file: cfg.tokens_file.map(PathBuf::from),
+        })
+    }
+}
+
+struct Entry {
+    token: ApiToken,
+    /// Whether the token was loaded from, and is saved back to, the tokens file.
+    from_file: bool,
+}
+
+/// The known tokens and the file some of them are saved to.
+struct Tokens {
+    entries: Vec<Entry>,
+    file: Option<PathBuf>,
+}
+
+impl Tokens {
+    /// Reads the configured tokens and the tokens file.
+    async fn read(options: AuthOptions) -> anyhow::Result<Self> {
+        let mut entries: Vec<Entry> = options
+            .tokens
+            .into_iter()
+            .map(|token| Entry { token, from_file: false })
+            .collect();
+        if let Some(path) = &options.tokens_file {
+            let tokens: Vec<ApiToken> = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+            entries.extend(tokens.into_iter().map(|token| Entry { token, from_file: true }));
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.token.token.is_empty() {
+                anyhow::bail!("token {} is empty", entry.token.name);
+            }
+            if entries[..i].iter().any(|other| other.token.name == entry.token.name) {
+                anyhow::bail!("token name {} is used more than once", entry.token.name);
+            }
+        }
+        Ok(Self {
+            entries,
+            file: options.tokens_file,
+        })
+    }
+}
+
+/// Validates bearer tokens and assigns callers their role.
+pub struct Authenticator {
+    /// Known tokens; authentication is disabled and every caller is an admin when unset.
+    tokens: Option<RwLock<Tokens>>,
+}
+
+impl Authenticator {
+    /// Loads the configured tokens and the tokens file. With no options, authentication
+    /// is disabled.
+    pub async fn load(options: Option<AuthOptions>) -> anyhow::Result<Self> {
+        let tokens = match options {
+            Some(options) => Some(RwLock::new(Tokens::read(options).await?)),
+            None => None,
+        };
+        Ok(Self { tokens })
+    }
+
+    /// Replaces every token with those of `options`, re-reading the tokens file. Tokens
+    /// rotated since they were loaded from the config return to their configured value.
+    pub async fn reload(&self, options: AuthOptions) -> anyhow::Result<()> {
+        let Some(tokens) = &self.tokens else {
+            anyhow::bail!("authentication is not enabled");
+        };
+        let loaded = Tokens::read(options).await?;
+        *tokens.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    /// Returns the caller presenting the `authorization` header value, or why it was
+    /// rejected.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Result<Caller, &'static str> {
+        let Some(tokens) = &self.tokens else {
+            return Ok(Caller {
+                token: None,
+                role: Role::Admin,
+                tenant: None,
+            });
+        };
+        let token = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or("missing bearer token")?;
+        tokens
+            .read()
+            .unwrap()
+            .entries
+            .iter()
+            .find(|entry| constant_time_eq(entry.token.token.as_bytes(), token.as_bytes()))
+            .map(|entry| Caller {
+                token: Some(entry.token.name.clone()),
+                role: entry.token.role,
+                tenant: entry.token.tenant.clone(),
+            })
+            .ok_or("invalid token")
+    }
+
+    /// Replaces the token called `name` with a newly generated one, which is returned.
+    /// The old token stops working immediately.
+    pub async fn rotate(&self, name: &str) -> anyhow::Result<String> {
+        let Some(tokens) = &self.tokens else {
+            anyhow::bail!("authentication is not enabled");
+        };
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+
+        let file_tokens = {
+            let mut tokens = tokens.write().unwrap();
+            let Some(entry) = tokens.entries.iter_mut().find(|entry| entry.token.name == name) else {
+                anyhow::bail!("no token named {}", name);
+            };
+            entry.token.token = token.clone();
+            match (entry.from_file, &tokens.file) {
+                (true, Some(path)) => {
+                    let saved: Vec<_> = tokens
+                        .entries
+                        .iter()
+                        .filter(|entry| entry.from_file)
+                        .map(|entry| entry.token.clone())
+                        .collect();
+                    Some((saved, path.clone()))
+                }
+                _ => None,
+            }
+        };
+        if let Some((tokens, path)) = file_tokens {
+            let tmp = path.with_extension("tmp");
+            tokio::fs::write(&tmp, serde_json::to_vec_pretty(&tokens)?).await?;
+            tokio::fs::rename(&tmp, &path).await?;
+        }
+        Ok(token)
+    }
+}
+
+/// Interceptor attaching the caller to each request, rejecting requests without a known
+/// bearer token.
+#[derive(Clone)]
+pub struct AuthInterceptor(pub Arc<Authenticator>);
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let authorization = request.metadata().get("authorization").and_then(|value| value.to_str().ok());
+        let caller = self.0.authenticate(authorization).map_err(Status::unauthenticated)?;
+        request.extensions_mut().insert(caller);
+        Ok(request)
+    }
+}
+
+/// Fails unless the caller of `request` was assigned at least `role` by the interceptor,
+/// with a token that isn't scoped to a tenant.
+#[allow(clippy::result_large_err)] // returns `Status` like the RPCs it guards
+pub fn require<T>(request: &Request<T>, role: Role) -> Result<(), Status> {
+    match require_tenant(request, role)? {
+        None => Ok(()),
+        Some(tenant) => Err(Status::permission_denied(format!("this call isn't available to the tokens of tenant {}", tenant))),
+    }
+}
+
+/// Fails unless the caller of `request` was assigned at least `role` by the interceptor,
+/// returning the tenant its token is scoped to, if any.
+#[allow(clippy::result_large_err)]
+pub fn require_tenant<T>(request: &Request<T>, role: Role) -> Result<Option<String>, Status> {
+    match request.extensions().get::<Caller>() {
+        Some(caller) if caller.role >= role => Ok(caller.tenant.clone()),
+        Some(_) => Err(Status::permission_denied(format!("this call requires the {} role", role))),
+        None => Err(Status::unauthenticated("request was not authenticated")),
+    }
+}
+
+/// Whether the record changes of the caller of `request` are only proposed, for an admin
+/// to approve.
+pub fn proposes<T>(request: &Request<T>) -> bool {
+    request.extensions().get::<Caller>().is_some_and(|caller| caller.role < Role::Admin)
+}
+
+/// Compares two byte strings in time independent of where they differ.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
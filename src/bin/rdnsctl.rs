@@ -0,0 +1,2081 @@
+//! Command-line client for the rdns gRPC control API.
+//!
+//! The server address and credentials are read from `~/.config/rdnsctl/config.toml`
+//! (or the file given with `--config`), then from `RDNSCTL_*` environment variables,
+//! and finally from command-line flags, each overriding the previous:
+//!
+//! ```toml
+//! server = "https://ns1.example.com:50051"    # or "unix:/run/rdns/control.sock"
+//! token = "..."
+//! ca_cert = "certs/ca.pem"
+//! client_cert = "certs/client.pem"
+//! client_key = "certs/client.key"
+//! ```
+
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use tonic::metadata::AsciiMetadataValue;
+use tonic::service::interceptor::InterceptedService;
+use tonic::service::Interceptor;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+#[cfg(unix)]
+use tonic::transport::Uri;
+#[cfg(unix)]
+use tower::service_fn;
+use tonic::{Request, Status};
+
+mod control {
+    #![allow(dead_code)]
+    tonic::include_proto!("rdns.control.v1");
+}
+
+use control::admin_client::AdminClient;
+use control::dns_control_client::DnsControlClient;
+use control::forwarding_rules_client::ForwardingRulesClient;
+use control::query_rules_client::QueryRulesClient;
+use control::rewrite_rules_client::RewriteRulesClient;
+use control::stats_client::StatsClient;
+use control::*;
+
+#[derive(Parser)]
+#[command(name = "rdnsctl", about = "Manage an rdns server through its control API")]
+struct Cli {
+    /// Config file to read instead of ~/.config/rdnsctl/config.toml
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    connection: ConnectionArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Connection settings, each overriding the config file and environment.
+#[derive(Args, Default, Deserialize)]
+#[serde(default)]
+struct ConnectionArgs {
+    /// Control API address, e.g. http://127.0.0.1:50051 or unix:/run/rdns/control.sock
+    #[arg(long, global = true)]
+    server: Option<String>,
+    /// Bearer token to authenticate with
+    #[arg(long, global = true)]
+    token: Option<String>,
+    /// CA certificate to verify the server with; implies TLS
+    #[arg(long, global = true)]
+    ca_cert: Option<PathBuf>,
+    /// Client certificate for mutual TLS
+    #[arg(long, global = true)]
+    client_cert: Option<PathBuf>,
+    /// Private key of the client certificate
+    #[arg(long, global = true)]
+    client_key: Option<PathBuf>,
+}
+
+impl ConnectionArgs {
+    /// Fills settings not given on the command line from `other`.
+    fn or(self, other: ConnectionArgs) -> Self {
+        ConnectionArgs {
+            server: self.server.or(other.server),
+            token: self.token.or(other.token),
+            ca_cert: self.ca_cert.or(other.ca_cert),
+            client_cert: self.client_cert.or(other.client_cert),
+            client_key: self.client_key.or(other.client_key),
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage records
+    #[command(subcommand)]
+    Record(RecordCommand),
+    /// Review the record changes proposed by propose tokens
+    #[command(subcommand)]
+    Approval(ApprovalCommand),
+    /// Manage zones
+    #[command(subcommand)]
+    Zone(ZoneCommand),
+    /// Manage weighted and failover record pools
+    #[command(subcommand)]
+    Pool(PoolCommand),
+    /// Manage health checks of record values
+    #[command(subcommand)]
+    Health(HealthCommand),
+    /// Manage records answered per client region
+    #[command(subcommand)]
+    Geo(GeoCommand),
+    /// Manage rollouts of new record values to a share of the clients
+    #[command(subcommand)]
+    Rollout(RolloutCommand),
+    /// Manage name patterns answered with records synthesized from the query name
+    #[command(subcommand)]
+    Pattern(PatternCommand),
+    /// Manage forwarded names answered locally instead
+    #[command(subcommand)]
+    Override(OverrideCommand),
+    /// Manage names answered with the addresses of another, e.g. at a zone apex
+    #[command(subcommand)]
+    Alias(AliasCommand),
+    /// Manage service registrations and their SRV and TXT records
+    #[command(subcommand)]
+    Service(ServiceCommand),
+    /// Manage the record overlays of split-horizon views
+    #[command(subcommand)]
+    View(ViewCommand),
+    /// Manage blocked and allowed domains
+    #[command(subcommand)]
+    Blocklist(BlocklistCommand),
+    /// Manage the TSIG keys zone transfers and dynamic updates are signed with
+    #[command(subcommand)]
+    Tsig(TsigCommand),
+    /// Show the DS records of signed zones and roll their keys over
+    #[command(subcommand)]
+    Dnssec(DnssecCommand),
+    /// Manage the tenants owning zones, whose tokens only manage their own zones
+    #[command(subcommand)]
+    Tenant(TenantCommand),
+    /// Manage the TXT records of ACME DNS-01 challenges, e.g. from certbot hooks
+    #[command(subcommand)]
+    Acme(AcmeCommand),
+    /// Manage the rules forwarding the names under a domain to upstreams of their own
+    #[command(subcommand)]
+    Forward(ForwardCommand),
+    /// Manage the rules answering some names with the records of others
+    #[command(subcommand)]
+    Rewrite(RewriteCommand),
+    /// Manage the rules answering matching names with NXDOMAIN, static records or
+    /// upstreams of their own
+    #[command(subcommand)]
+    Rules(RulesCommand),
+    /// Empty the cache of forwarded answers, or remove those for a single name
+    FlushCache {
+        /// Name whose answers, of every type, are removed, rather than the whole cache
+        name: Option<String>,
+    },
+    /// Re-read the server's Config.toml
+    ReloadConfig,
+    /// Check a Config.toml on the server without applying it, the server's own when no
+    /// file is given
+    CheckConfig {
+        file: Option<PathBuf>,
+    },
+    /// Print record changes as they happen
+    Watch,
+    /// Query the server's own DNS listener, to check that a record resolves end to end
+    Dig {
+        name: String,
+        /// A when not given
+        record_type: Option<String>,
+        /// Query over TCP rather than UDP
+        #[arg(long, conflicts_with_all = ["tls", "https"])]
+        tcp: bool,
+        /// Query over DNS over TLS rather than UDP
+        #[arg(long, conflicts_with = "https")]
+        tls: bool,
+        /// Query over DNS over HTTPS rather than UDP
+        #[arg(long)]
+        https: bool,
+        /// Fail unless an answer holds this value, rather than unless there is any answer
+        #[arg(long)]
+        expect: Option<String>,
+    },
+    /// Print the most queried names, or the least recently queried records of a zone
+    Stats {
+        /// Only names at or under this zone
+        #[arg(long)]
+        zone: Option<String>,
+        /// Only this name
+        #[arg(long)]
+        name: Option<String>,
+        /// Only this record type
+        #[arg(long = "type")]
+        record_type: Option<String>,
+        /// Only count the queries of the last this many seconds
+        #[arg(long)]
+        window: Option<u64>,
+        /// Entries printed at most
+        #[arg(long, default_value_t = 10)]
+        top: u32,
+        /// Print the records of --zone not queried within the window instead
+        #[arg(long, requires = "zone")]
+        stale: bool,
+    },
+    /// Print the clients that sent the most queries
+    TopClients {
+        /// Only count the queries of the last this many seconds
+        #[arg(long)]
+        window: Option<u64>,
+        /// Entries printed at most
+        #[arg(long, default_value_t = 10)]
+        top: u32,
+    },
+    /// Print query spikes and NXDOMAIN floods as they are detected
+    WatchAnomalies,
+    /// Print the audit log of record and zone changes
+    Audit {
+        /// Only changes made at or after this Unix time
+        #[arg(long)]
+        since: Option<i64>,
+        /// Only changes made before this Unix time
+        #[arg(long)]
+        until: Option<i64>,
+    },
+    /// Save a snapshot of every zone and the runtime state to a file
+    Backup {
+        file: PathBuf,
+    },
+    /// Replace every zone and the runtime state with a snapshot saved by `backup`
+    Restore {
+        file: PathBuf,
+    },
+    /// Show the server's role in its cluster and how far replication has got
+    Cluster,
+    /// Show the records and estimated memory of each zone, the cache size and uptime
+    ServerStats,
+    /// Show the version, build commit, enabled features, listeners and zone count
+    ServerInfo,
+    /// Shut down, reload or drain the server, or adjust its logging
+    #[command(subcommand)]
+    Admin(AdminCommand),
+}
+
+#[derive(Subcommand)]
+enum RecordCommand {
+    /// Add a record
+    Add {
+        name: String,
+        record_type: String,
+        /// Record data in zone file syntax
+        value: String,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+        /// Remove the record again after this many seconds
+        #[arg(long)]
+        lease: Option<u32>,
+        /// Remove the record again at this Unix time
+        #[arg(long, conflicts_with = "lease")]
+        expires_at: Option<i64>,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+        /// Only show what would change
+        #[arg(long)]
+        dry_run: bool,
+        /// Add the record at this Unix time rather than now
+        #[arg(long, conflicts_with = "dry_run")]
+        at: Option<i64>,
+        /// Answer the value instead of the zone's records while this schedule is active,
+        /// e.g. "mon-fri 22:00-06:00 +02:00" or "cron 0 2 * * 0 3h"
+        #[arg(long, conflicts_with_all = ["lease", "expires_at", "dry_run", "at"])]
+        schedule: Option<String>,
+    },
+    /// Add an HTTPS or SVCB record from its parameters
+    AddBinding {
+        name: String,
+        /// 0 for AliasMode, otherwise the endpoint's preference, lowest first
+        priority: u32,
+        /// Endpoint name, `.` for the owner itself
+        #[arg(default_value = ".")]
+        target: String,
+        #[arg(long = "type", default_value = "HTTPS")]
+        record_type: String,
+        /// Protocol the endpoint supports, e.g. h2; repeat for several
+        #[arg(long)]
+        alpn: Vec<String>,
+        /// Don't assume the scheme's default protocol is supported
+        #[arg(long)]
+        no_default_alpn: bool,
+        #[arg(long)]
+        port: Option<u32>,
+        /// IPv4 address hint; repeat for several
+        #[arg(long = "ipv4hint")]
+        ipv4_hints: Vec<String>,
+        /// IPv6 address hint; repeat for several
+        #[arg(long = "ipv6hint")]
+        ipv6_hints: Vec<String>,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+    },
+    /// Replace the records of a name and type
+    Update {
+        name: String,
+        record_type: String,
+        value: String,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+        /// Only replace the record currently holding this value
+        #[arg(long)]
+        expect: Option<String>,
+        #[command(flatten)]
+        metadata: MetadataArgs,
+    },
+    /// Delete the records of a name and type
+    Delete {
+        name: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+        /// Only delete the record holding this value
+        #[arg(long)]
+        value: Option<String>,
+        /// Only show what would change
+        #[arg(long, conflicts_with = "value")]
+        dry_run: bool,
+        /// Delete the records at this Unix time rather than now
+        #[arg(long, conflicts_with_all = ["value", "dry_run"])]
+        at: Option<i64>,
+        /// Delete the values answered on this schedule instead
+        #[arg(long, conflicts_with_all = ["value", "dry_run", "at"])]
+        schedule: Option<String>,
+    },
+    /// List the record changes scheduled with --at that are still to be applied
+    Pending,
+    /// List the values answered on a schedule, added with --schedule
+    Timed,
+    /// Cancel a scheduled record change
+    Cancel { id: u64 },
+    /// Import the records of a zone exported from Route53 or Cloudflare
+    Import {
+        file: PathBuf,
+        /// Provider the export comes from: route53 (list-resource-record-sets JSON),
+        /// cloudflare (BIND export) or json (rdnsctl record export)
+        #[arg(long)]
+        from: String,
+        /// Zone origin, when the export names none
+        #[arg(long)]
+        origin: Option<String>,
+    },
+    /// Print the records of a zone, or of one name in it, as JSON for version control
+    Export {
+        origin: String,
+        /// Only the records of this name
+        #[arg(long)]
+        name: Option<String>,
+        /// Only the records of this type
+        #[arg(long = "type")]
+        record_type: Option<String>,
+    },
+    /// Show the records of a name and type
+    Get {
+        name: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+        /// Print names in Unicode rather than punycode
+        #[arg(long)]
+        unicode: bool,
+    },
+    /// List records, optionally filtered
+    List {
+        #[arg(long)]
+        prefix: Option<String>,
+        /// Only names in this domain, e.g. a zone origin
+        #[arg(long)]
+        suffix: Option<String>,
+        #[arg(long = "type")]
+        record_type: Option<String>,
+        /// Only records with this owner
+        #[arg(long)]
+        owner: Option<String>,
+        /// Only records with this tag, as KEY=VALUE or KEY for any value; repeat for several
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Print names in Unicode rather than punycode
+        #[arg(long)]
+        unicode: bool,
+        /// Print only the names and types, without fetching the values
+        #[arg(long)]
+        names_only: bool,
+    },
+}
+
+/// Metadata of a record to add or update. The record keeps the metadata it has when
+/// none of these are given; any given replace all of it.
+#[derive(Args)]
+struct MetadataArgs {
+    /// What the record is for
+    #[arg(long)]
+    comment: Option<String>,
+    /// Team or person responsible for the record
+    #[arg(long)]
+    owner: Option<String>,
+    /// Tag as KEY=VALUE; repeat for several
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+}
+
+impl MetadataArgs {
+    fn into_metadata(self) -> anyhow::Result<Option<RecordMetadata>> {
+        if self.comment.is_none() && self.owner.is_none() && self.tags.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(RecordMetadata {
+            comment: self.comment.unwrap_or_default(),
+            owner: self.owner.unwrap_or_default(),
+            tags: parse_key_values(&self.tags)?,
+        }))
+    }
+}
+
+#[derive(Subcommand)]
+enum ZoneCommand {
+    /// List the hosted zones
+    List,
+    /// Create a zone, populated from the server's default zone template if it has one
+    Create {
+        origin: String,
+        /// Add and delete PTR records along with the zone's A and AAAA records
+        #[arg(long)]
+        auto_reverse: bool,
+        /// Zone template to populate the zone with instead
+        #[arg(long)]
+        template: Option<String>,
+    },
+    /// Delete a zone and its records
+    Delete { origin: String },
+    /// Replace a zone's records with those of a BIND zone file
+    Import {
+        file: PathBuf,
+        /// Zone origin, when the file has no $ORIGIN
+        #[arg(long)]
+        origin: Option<String>,
+    },
+    /// Make a zone hold exactly the records of a BIND zone file, SOA and DNSSEC aside
+    Apply {
+        origin: String,
+        file: PathBuf,
+        /// Only show what would change
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print a zone as a BIND zone file
+    Export { origin: String },
+    /// Check a zone for common problems, failing if any is an error
+    Check { origin: String },
+    /// List the kept versions of a zone
+    Versions { origin: String },
+    /// Put the records of a kept version of a zone back
+    Rollback {
+        origin: String,
+        /// Version produced by this change
+        #[arg(long, required_unless_present = "serial", conflicts_with = "serial")]
+        change_id: Option<u64>,
+        /// Version with this SOA serial
+        #[arg(long)]
+        serial: Option<u32>,
+    },
+    /// Reject changes to a zone, which is still answered, until it is thawed
+    Freeze { origin: String },
+    /// Accept changes to a frozen zone again
+    Thaw { origin: String },
+}
+
+#[derive(Subcommand)]
+enum PoolCommand {
+    /// List the pools
+    List,
+    /// Create or replace the pool of a name and type
+    Set {
+        name: String,
+        record_type: String,
+        /// Primary members, as VALUE or VALUE=WEIGHT
+        #[arg(required = true)]
+        members: Vec<String>,
+        /// Backup member, as VALUE or VALUE=WEIGHT; may be repeated
+        #[arg(long = "backup")]
+        backups: Vec<String>,
+        /// Member value to add disabled; may be repeated
+        #[arg(long = "disable")]
+        disabled: Vec<String>,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+    },
+    /// Delete the pool of a name and type, keeping its records
+    Delete {
+        name: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum HealthCommand {
+    /// Show whether each value of a name and type is served
+    Get {
+        name: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+    },
+    /// Start checking one value, replacing any check of it
+    Set {
+        name: String,
+        record_type: String,
+        value: String,
+        /// http, tcp or icmp
+        #[arg(long, default_value = "tcp")]
+        kind: String,
+        #[arg(long)]
+        port: Option<u32>,
+        /// Path requested by http checks
+        #[arg(long)]
+        path: Option<String>,
+        /// Seconds between probes, the server default when unset
+        #[arg(long)]
+        interval: Option<u32>,
+        /// Seconds a probe may take, the server default when unset
+        #[arg(long)]
+        timeout: Option<u32>,
+    },
+    /// Stop checking one value
+    Delete {
+        name: String,
+        record_type: String,
+        value: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum GeoCommand {
+    /// List the geo records
+    List,
+    /// Create or replace the geo record of a name and type
+    Set {
+        name: String,
+        record_type: String,
+        /// Values as REGION=VALUE; repeat a region for several values
+        #[arg(required = true)]
+        values: Vec<String>,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+    },
+    /// Delete the geo record of a name and type
+    Delete {
+        name: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum RolloutCommand {
+    /// List the rollouts
+    List,
+    /// Create or replace the rollout of a name and type
+    Set {
+        name: String,
+        record_type: String,
+        /// New values answered to the clients rolled out to
+        #[arg(required = true)]
+        values: Vec<String>,
+        /// Share of the clients answered with the new values, from 0 to 100
+        #[arg(long)]
+        percent: u32,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+    },
+    /// Delete the rollout of a name and type, leaving every client on the zone's records
+    Delete {
+        name: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+    },
+    /// Replace the zone's records of a name and type with the rollout's new values
+    Promote {
+        name: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum PatternCommand {
+    /// List the name patterns
+    List,
+    /// Create or replace the values of a name pattern and type
+    Set {
+        /// Name whose labels may be placeholders, e.g. {branch}.preview.example.com
+        pattern: String,
+        record_type: String,
+        /// Values, which may hold the placeholders of the pattern
+        #[arg(required = true)]
+        values: Vec<String>,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+    },
+    /// Delete the values of a name pattern and type
+    Delete {
+        pattern: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ApprovalCommand {
+    /// List the change requests awaiting approval
+    List,
+    /// Apply a change request
+    Approve { id: u64 },
+    /// Drop a change request without applying it
+    Reject { id: u64 },
+}
+
+#[derive(Subcommand)]
+enum OverrideCommand {
+    /// List the overrides
+    List,
+    /// Answer a forwarded name and type locally with values
+    Set {
+        name: String,
+        record_type: String,
+        #[arg(required = true)]
+        values: Vec<String>,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+    },
+    /// Forward a name and type again
+    Delete {
+        name: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum AliasCommand {
+    /// List the aliases
+    List,
+    /// Create or replace the alias of a name
+    Set {
+        name: String,
+        /// Name whose addresses are answered
+        target: String,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+    },
+    /// Delete the alias of a name
+    Delete { name: String },
+}
+
+#[derive(Subcommand)]
+enum TsigCommand {
+    /// List the TSIG keys, without their secrets
+    List,
+    /// Generate a TSIG key and print its secret
+    Create {
+        /// Key name, e.g. xfr.example.com.
+        name: String,
+        /// hmac-sha256, hmac-sha384 or hmac-sha512
+        #[arg(long, default_value = "hmac-sha256")]
+        algorithm: String,
+    },
+    /// Delete a TSIG key created with `tsig create`
+    Delete { name: String },
+}
+
+#[derive(Subcommand)]
+enum DnssecCommand {
+    /// Print the DS records to publish at the registrar for a signed zone
+    Ds { origin: String },
+    /// Show where the rollovers of a signed zone's keys stand
+    Status { origin: String },
+    /// Start rolling over a signed zone's ZSK, or its KSK, printing the new DS to publish
+    Rollover {
+        origin: String,
+        /// Roll over the KSK rather than the ZSK
+        #[arg(long)]
+        ksk: bool,
+    },
+    /// Retire the old KSK once the registrar publishes the DS of the new one
+    Confirm { origin: String },
+    /// Roll back a rollover, dropping the new key
+    Cancel {
+        origin: String,
+        /// Roll back the KSK rollover rather than the ZSK one
+        #[arg(long)]
+        ksk: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum TenantCommand {
+    /// List the tenants with the zones they own and the records those hold
+    List,
+    /// Create a tenant, optionally owning existing zones
+    Create {
+        name: String,
+        /// Zone the tenant owns; may be repeated
+        #[arg(long = "zone")]
+        zones: Vec<String>,
+        /// Most zones the tenant may own; unlimited when unset
+        #[arg(long, default_value_t = 0)]
+        max_zones: u32,
+        /// Most records the tenant's zones may hold together; unlimited when unset
+        #[arg(long, default_value_t = 0)]
+        max_records: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum AcmeCommand {
+    /// Publish a challenge at _acme-challenge.<domain>, removed after [acme] cleanup_secs
+    /// unless cleared before
+    Set {
+        /// Domain being validated, e.g. www.example.com or *.example.com
+        domain: String,
+        /// Key authorization digest, e.g. $CERTBOT_VALIDATION
+        value: String,
+        /// TTL of the record; that of [acme] when unset
+        #[arg(long, default_value_t = 0)]
+        ttl: u32,
+    },
+    /// Remove a challenge, or every challenge of the domain when no value is given
+    Clear { domain: String, value: Option<String> },
+}
+
+#[derive(Subcommand)]
+enum ForwardCommand {
+    /// List the forwarding rules in the order they are tried
+    List,
+    /// Forward the names under a domain to the given upstreams, tried after the rules
+    /// added before
+    Add {
+        /// Domain, e.g. corp.internal
+        domain: String,
+        /// Upstream, as ip[:port], tls://host[:port][#name] or https://host[:port]/dns-query
+        #[arg(required = true)]
+        upstreams: Vec<String>,
+    },
+    /// Delete a forwarding rule added with `forward add`
+    Delete { domain: String },
+}
+
+#[derive(Subcommand)]
+enum RewriteCommand {
+    /// List the rewrite rules in the order they are tried
+    List,
+    /// Answer the names under a domain with the records of the same names under another,
+    /// tried after the rules added before
+    Add {
+        /// Domain whose names are rewritten, e.g. staging.example.com, or a pattern of
+        /// the names with --regex
+        from: String,
+        /// Domain the names are moved under, or the name they become with --regex
+        to: String,
+        /// Match names against the regular expression `from`, expanding $1-style group
+        /// references in `to`
+        #[arg(long)]
+        regex: bool,
+    },
+    /// Delete a rewrite rule added with `rewrite add`
+    Delete { from: String },
+}
+
+#[derive(Subcommand)]
+enum RulesCommand {
+    /// List the query rules in the order they are tried
+    List,
+    /// Answer the names a pattern matches with a fixed action, tried after the rules
+    /// added before
+    Add {
+        /// Name, domain or regular expression matched, as --match says
+        pattern: String,
+        /// How names are matched: exact, suffix (the domain and the names under it) or
+        /// regex (the lower-cased, fully qualified names)
+        #[arg(long = "match", default_value = "suffix")]
+        match_type: String,
+        /// What the names are answered with: nxdomain, answer or forward
+        #[arg(long, default_value = "nxdomain")]
+        action: String,
+        /// Records to answer with, as type and value like "A 0.0.0.0", or upstreams to
+        /// forward to, addresses with an optional port
+        values: Vec<String>,
+        /// TTL of the records answered with, 300 when unset
+        #[arg(long, default_value_t = 0)]
+        ttl: u32,
+    },
+    /// Delete a query rule added with `rules add` or `rules import`
+    Delete {
+        pattern: String,
+        /// How the rule matches names: exact, suffix or regex
+        #[arg(long = "match", default_value = "suffix")]
+        match_type: String,
+    },
+    /// Add the rules of a rule file, a rule per line like
+    /// `suffix telemetry.example.com nxdomain`
+    Import {
+        file: PathBuf,
+        /// Replace every rule added through the control API rather than adding to them
+        #[arg(long)]
+        replace: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum AdminCommand {
+    /// Stop the server gracefully, as SIGTERM does
+    Shutdown,
+    /// Re-read the server's Config.toml and zone files
+    Reload,
+    /// Drain the server ahead of maintenance, reporting it unhealthy
+    Drain {
+        /// How queries are answered meanwhile: truncate (UDP with TC set), refuse, or
+        /// unhealthy (as usual)
+        #[arg(default_value = "unhealthy")]
+        mode: String,
+    },
+    /// End draining
+    Undrain,
+    /// Show the log level, or set it: info, or debug to log every query as well
+    LogLevel {
+        level: Option<String>,
+        /// Return to the current level after this many seconds
+        #[arg(long, requires = "level")]
+        duration: Option<u64>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ServiceCommand {
+    /// List the registered service instances
+    List,
+    /// Register an instance of a service, or replace its registration
+    Register {
+        /// Instance name, e.g. web1
+        instance: String,
+        /// Service name, e.g. http
+        service: String,
+        /// Domain the service is published under
+        domain: String,
+        /// Host the instance runs on
+        host: String,
+        port: u32,
+        #[arg(long, default_value = "tcp")]
+        protocol: String,
+        /// Address of the host to publish; repeat for several
+        #[arg(long = "address")]
+        addresses: Vec<String>,
+        /// Metadata as KEY=VALUE; repeat for several
+        #[arg(long = "meta")]
+        metadata: Vec<String>,
+        #[arg(long, default_value_t = 0)]
+        priority: u32,
+        #[arg(long, default_value_t = 0)]
+        weight: u32,
+        #[arg(long, default_value_t = 60)]
+        ttl: u32,
+    },
+    /// Deregister an instance of a service
+    Deregister {
+        instance: String,
+        service: String,
+        domain: String,
+        #[arg(long, default_value = "tcp")]
+        protocol: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ViewCommand {
+    /// List the views, their client networks and records
+    List,
+    /// Add a record to a view
+    Add {
+        view: String,
+        name: String,
+        record_type: String,
+        value: String,
+        #[arg(long, default_value_t = 300)]
+        ttl: u32,
+    },
+    /// Delete the records of a name and type from a view
+    Delete {
+        view: String,
+        name: String,
+        #[arg(default_value = "A")]
+        record_type: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BlocklistCommand {
+    /// List the blocked and allowed domains and the state of each blocklist
+    List,
+    /// Block a domain and its subdomains
+    Add {
+        domain: String,
+        /// Allow the domain instead, even if a blocklist has it
+        #[arg(long)]
+        allow: bool,
+    },
+    /// Delete a blocked domain
+    Delete {
+        domain: String,
+        /// Delete an allowed domain instead
+        #[arg(long)]
+        allow: bool,
+    },
+}
+
+/// Bytes of a snapshot sent per message by `backup` and `restore`.
+const SNAPSHOT_CHUNK_SIZE: u32 = 1024 * 1024;
+
+/// The blocklist entry of `domain`, allowed or blocked.
+fn blocklist_entry(domain: String, allow: bool) -> BlocklistEntry {
+    let action = if allow { blocklist_entry::Action::Allow } else { blocklist_entry::Action::Block };
+    BlocklistEntry {
+        domain,
+        action: action as i32,
+    }
+}
+
+/// Parses `KEY=VALUE` arguments.
+fn parse_key_values(args: &[String]) -> anyhow::Result<HashMap<String, String>> {
+    args.iter()
+        .map(|arg| match arg.split_once('=') {
+            Some((key, value)) => Ok((key.to_string(), value.to_string())),
+            None => anyhow::bail!("expected KEY=VALUE, got {}", arg),
+        })
+        .collect()
+}
+
+/// Parses a `VALUE[=WEIGHT]` pool member argument.
+fn parse_member(arg: &str, backup: bool, disabled: &[String]) -> anyhow::Result<PoolMember> {
+    let (value, weight) = match arg.split_once('=') {
+        Some((value, weight)) => (value, weight.parse()?),
+        None => (arg, 1),
+    };
+    Ok(PoolMember {
+        value: value.into(),
+        weight,
+        backup,
+        disabled: disabled.iter().any(|d| d == value),
+    })
+}
+
+/// Attaches the bearer token, if any, to every request.
+#[derive(Clone)]
+struct TokenInterceptor(Option<AsciiMetadataValue>);
+
+impl Interceptor for TokenInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(authorization) = &self.0 {
+            request.metadata_mut().insert("authorization", authorization.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// Channel to the server, shared by the clients of its services.
+type Connection = InterceptedService<Channel, TokenInterceptor>;
+
+/// Reads the connection settings from the config file and environment, below `flags`.
+fn load_connection(path: Option<PathBuf>, flags: ConnectionArgs) -> anyhow::Result<ConnectionArgs> {
+    let path = path.or_else(|| {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/rdnsctl/config.toml"))
+    });
+    let mut builder = config::Config::builder();
+    if let Some(path) = path {
+        builder = builder.add_source(config::File::from(path).required(false));
+    }
+    let settings: ConnectionArgs = builder
+        .add_source(config::Environment::with_prefix("RDNSCTL"))
+        .build()?
+        .try_deserialize()?;
+    Ok(flags.or(settings))
+}
+
+async fn connect(settings: ConnectionArgs) -> anyhow::Result<Connection> {
+    let tls = settings.ca_cert.is_some() || settings.client_cert.is_some();
+    let default_server = if tls { "https://127.0.0.1:50051" } else { "http://127.0.0.1:50051" };
+    let server = settings.server.unwrap_or_else(|| default_server.into());
+    // Unix domain sockets are connected to directly, the URI only naming the authority
+    let socket = server.strip_prefix("unix:").map(PathBuf::from);
+    let mut endpoint = match socket {
+        Some(_) if tls => Endpoint::from_static("https://localhost"),
+        Some(_) => Endpoint::from_static("http://localhost"),
+        None => Endpoint::from_shared(server)?,
+    };
+    if tls {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &settings.ca_cert {
+            config = config.ca_certificate(Certificate::from_pem(std::fs::read(ca)?));
+        }
+        match (&settings.client_cert, &settings.client_key) {
+            (Some(cert), Some(key)) => {
+                config = config.identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+            }
+            (None, None) => {}
+            _ => anyhow::bail!("client_cert and client_key must be given together"),
+        }
+        endpoint = endpoint.tls_config(config)?;
+    }
+    let channel = match socket {
+        #[cfg(unix)]
+        Some(path) => {
+            endpoint
+                .connect_with_connector(service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+                .await?
+        }
+        #[cfg(not(unix))]
+        Some(path) => anyhow::bail!("{} is a unix socket, which is only supported on unix", path.display()),
+        None => endpoint.connect().await?,
+    };
+
+    let authorization: Option<AsciiMetadataValue> = settings
+        .token
+        .map(|token| format!("Bearer {}", token).parse())
+        .transpose()?;
+    Ok(InterceptedService::new(channel, TokenInterceptor(authorization)))
+}
+
+/// Prints a record in zone file syntax, its metadata after it as a comment.
+fn print_record(record: &DnsRecord) {
+    let mut line = format!("{}\t{}\tIN\t{}\t{}", record.name, record.ttl, record.record_type, record.value);
+    if let Some(metadata) = &record.metadata {
+        let mut notes = Vec::new();
+        if !metadata.owner.is_empty() {
+            notes.push(format!("owner={}", metadata.owner));
+        }
+        let mut tags: Vec<_> = metadata.tags.iter().collect();
+        tags.sort();
+        notes.extend(tags.into_iter().map(|(key, value)| format!("{}={}", key, value)));
+        if !metadata.comment.is_empty() {
+            notes.push(metadata.comment.clone());
+        }
+        line.push_str(&format!("\t; {}", notes.join(" ")));
+    }
+    println!("{}", line);
+}
+
+/// The key `dnssec rollover` and `dnssec cancel` apply to.
+fn key_role(ksk: bool) -> KeyRole {
+    if ksk {
+        KeyRole::KeySigning
+    } else {
+        KeyRole::ZoneSigning
+    }
+}
+
+/// Prints the outcome of a mutation, failing if it was unsuccessful.
+fn report(response: ControlResponse) -> anyhow::Result<()> {
+    if !response.success {
+        let message = response.message.strip_prefix("Error: ").unwrap_or(&response.message);
+        anyhow::bail!("{}", message);
+    }
+    if let Some(dry_run) = &response.dry_run {
+        print_plan(dry_run);
+    }
+    println!("{}", response.message);
+    Ok(())
+}
+
+/// Prints planned changes like `watch` does, followed by the serial bumps.
+fn print_plan(dry_run: &DryRun) {
+    for event in &dry_run.changes {
+        let change = record_event::Change::try_from(event.change).map_or("UNKNOWN", |change| change.as_str_name());
+        if let Some(record) = &event.record {
+            print!("{}\t", change);
+            print_record(record);
+        }
+    }
+    for zone in &dry_run.serials {
+        match (zone.serial, zone.next_serial) {
+            (_, 0) => println!("SERIAL\t{}\tno SOA", zone.origin),
+            (0, next) => println!("SERIAL\t{}\tnew SOA with serial {}", zone.origin, next),
+            (serial, next) => println!("SERIAL\t{}\t{} -> {}", zone.origin, serial, next),
+        }
+    }
+}
+
+async fn run(connection: Connection, command: Command) -> anyhow::Result<()> {
+    let mut client = DnsControlClient::new(connection.clone());
+    match command {
+        Command::Record(RecordCommand::Add { name, record_type, value, ttl, lease, expires_at, metadata, dry_run, at, schedule }) => {
+            let request = AddRecordRequest {
+                name,
+                value,
+                ttl,
+                record_type,
+                lease_seconds: lease.unwrap_or_default(),
+                expires_at: expires_at.map(|seconds| prost_types::Timestamp { seconds, nanos: 0 }),
+                metadata: metadata.into_metadata()?,
+                dry_run,
+                effective_at: at.map(|seconds| prost_types::Timestamp { seconds, nanos: 0 }),
+                schedule: schedule.unwrap_or_default(),
+            };
+            report(client.add_record(request).await?.into_inner())
+        }
+        Command::Record(RecordCommand::AddBinding {
+            name,
+            priority,
+            target,
+            record_type,
+            alpn,
+            no_default_alpn,
+            port,
+            ipv4_hints,
+            ipv6_hints,
+            ttl,
+        }) => {
+            let request = AddServiceBindingRequest {
+                name,
+                record_type,
+                priority,
+                target,
+                alpn,
+                no_default_alpn,
+                port: port.unwrap_or_default(),
+                ipv4_hints,
+                ipv6_hints,
+                ttl,
+            };
+            report(client.add_service_binding(request).await?.into_inner())
+        }
+        Command::Record(RecordCommand::Update { name, record_type, value, ttl, expect, metadata }) => {
+            let request = UpdateRecordRequest {
+                name,
+                value,
+                ttl,
+                record_type,
+                expected_value: expect.unwrap_or_default(),
+                metadata: metadata.into_metadata()?,
+            };
+            report(client.update_record(request).await?.into_inner())
+        }
+        Command::Record(RecordCommand::Delete { name, record_type, value: None, dry_run, at, schedule }) => {
+            let effective_at = at.map(|seconds| prost_types::Timestamp { seconds, nanos: 0 });
+            let schedule = schedule.unwrap_or_default();
+            let request = DeleteRecordRequest { name, record_type, dry_run, effective_at, schedule };
+            report(client.delete_record(request).await?.into_inner())
+        }
+        Command::Record(RecordCommand::Delete { name, record_type, value: Some(value), .. }) => {
+            let request = DeleteRecordValueRequest { name, record_type, value };
+            report(client.delete_record_value(request).await?.into_inner())
+        }
+        Command::Record(RecordCommand::Timed) => {
+            for record in client.list_timed_records(Empty {}).await?.into_inner().records {
+                let state = if record.active { "active" } else { "inactive" };
+                for value in &record.values {
+                    println!("{}\t{}\tIN\t{}\t{}\t{}\t{}", record.name, record.ttl, record.record_type, value, record.schedule, state);
+                }
+            }
+            Ok(())
+        }
+        Command::Record(RecordCommand::Pending) => {
+            for change in client.list_pending_changes(Empty {}).await?.into_inner().changes {
+                let at = change.effective_at.as_ref().map_or(0, |timestamp| timestamp.seconds);
+                match change.kind() {
+                    pending_change::Kind::Add => println!(
+                        "{}\tat={}\tadd\t{}\t{}\tIN\t{}\t{}",
+                        change.id, at, change.name, change.ttl, change.record_type, change.value
+                    ),
+                    pending_change::Kind::Delete => {
+                        println!("{}\tat={}\tdelete\t{}\t{}", change.id, at, change.name, change.record_type)
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Record(RecordCommand::Cancel { id }) => {
+            report(client.cancel_pending_change(CancelPendingChangeRequest { id }).await?.into_inner())
+        }
+        Command::Approval(ApprovalCommand::List) => {
+            for approval in client.list_pending_approvals(Empty {}).await?.into_inner().approvals {
+                let at = approval.requested_at.as_ref().map_or(0, |timestamp| timestamp.seconds);
+                println!("{}\tat={}\tby={}\t{}", approval.id, at, approval.requested_by, approval.method);
+                for operation in approval.operations {
+                    match operation.operation {
+                        Some(changeset_operation::Operation::Add(add)) => {
+                            println!("\tadd\t{}\t{}\tIN\t{}\t{}", add.name, add.ttl, add.record_type, add.value)
+                        }
+                        Some(changeset_operation::Operation::Delete(delete)) => {
+                            println!("\tdelete\t{}\t{}", delete.name, delete.record_type)
+                        }
+                        None => {}
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Approval(ApprovalCommand::Approve { id }) => {
+            report(client.approve_change(ApprovalDecision { id }).await?.into_inner())
+        }
+        Command::Approval(ApprovalCommand::Reject { id }) => {
+            report(client.reject_change(ApprovalDecision { id }).await?.into_inner())
+        }
+        Command::Record(RecordCommand::Import { file, from, origin }) => {
+            let format = match from.to_ascii_lowercase().as_str() {
+                "route53" => import_provider_records_request::Format::Route53,
+                "cloudflare" => import_provider_records_request::Format::Cloudflare,
+                "json" => import_provider_records_request::Format::Json,
+                _ => anyhow::bail!("unknown provider {}, expected route53, cloudflare or json", from),
+            };
+            let request = ImportProviderRecordsRequest {
+                format: format as i32,
+                origin: origin.unwrap_or_default(),
+                content: std::fs::read_to_string(&file)?,
+            };
+            let response = client.import_provider_records(request).await?.into_inner();
+            println!("Imported {} records, skipped {}", response.inserted, response.skipped);
+            for failure in &response.failed {
+                eprintln!("entry {}: {}", failure.index, failure.reason);
+            }
+            if !response.failed.is_empty() {
+                anyhow::bail!("{} entries could not be imported", response.failed.len());
+            }
+            Ok(())
+        }
+        Command::Record(RecordCommand::Export { origin, name, record_type }) => {
+            let request = ExportRecordsRequest {
+                origin,
+                name: name.unwrap_or_default(),
+                record_type: record_type.unwrap_or_default(),
+            };
+            println!("{}", client.export_records(request).await?.into_inner().content);
+            Ok(())
+        }
+        Command::Record(RecordCommand::Get { name, record_type, unicode }) => {
+            let response = client
+                .get_record(GetRecordRequest { name, record_type, unicode })
+                .await?
+                .into_inner();
+            response.records.iter().for_each(print_record);
+            Ok(())
+        }
+        Command::Record(RecordCommand::List { prefix, suffix, record_type, owner, tags, unicode, names_only }) => {
+            let tags: HashMap<_, _> = tags
+                .iter()
+                .map(|tag| match tag.split_once('=') {
+                    Some((key, value)) => (key.to_string(), value.to_string()),
+                    None => (tag.clone(), String::new()),
+                })
+                .collect();
+            let mut page_token = String::new();
+            let mut last = None;
+            loop {
+                let request = QueryRecordsRequest {
+                    name_prefix: prefix.clone().unwrap_or_default(),
+                    name_suffix: suffix.clone().unwrap_or_default(),
+                    record_type: record_type.clone().unwrap_or_default(),
+                    page_size: 0,
+                    page_token,
+                    unicode,
+                    owner: owner.clone().unwrap_or_default(),
+                    tags: tags.clone(),
+                    read_mask: names_only.then(|| prost_types::FieldMask {
+                        paths: vec!["name".into(), "record_type".into()],
+                    }),
+                };
+                let response = client.query_records(request).await?.into_inner();
+                if names_only {
+                    // Records come sorted by name and type, one per value
+                    for record in &response.records {
+                        let key = (record.name.clone(), record.record_type.clone());
+                        if last.as_ref() != Some(&key) {
+                            println!("{}\t{}", key.0, key.1);
+                            last = Some(key);
+                        }
+                    }
+                } else {
+                    response.records.iter().for_each(print_record);
+                }
+                if response.next_page_token.is_empty() {
+                    return Ok(());
+                }
+                page_token = response.next_page_token;
+            }
+        }
+        Command::Zone(ZoneCommand::List) => {
+            for zone in client.list_zones(Empty {}).await?.into_inner().zones {
+                let auto_reverse = if zone.auto_reverse { "\tauto-reverse" } else { "" };
+                let frozen = if zone.frozen { "\tfrozen" } else { "" };
+                println!("{}\t{} records{}{}", zone.origin, zone.record_count, auto_reverse, frozen);
+            }
+            Ok(())
+        }
+        Command::Zone(ZoneCommand::Create { origin, auto_reverse, template: None }) => {
+            let request = CreateZoneRequest { origin, auto_reverse };
+            report(client.create_zone(request).await?.into_inner())
+        }
+        Command::Zone(ZoneCommand::Create { origin, auto_reverse, template: Some(template) }) => {
+            let request = CreateZoneFromTemplateRequest { origin, template, auto_reverse };
+            report(client.create_zone_from_template(request).await?.into_inner())
+        }
+        Command::Zone(ZoneCommand::Delete { origin }) => {
+            report(client.delete_zone(DeleteZoneRequest { origin }).await?.into_inner())
+        }
+        Command::Zone(ZoneCommand::Import { file, origin }) => {
+            let content = std::fs::read_to_string(&file)?;
+            let request = ImportZoneRequest {
+                origin: origin.unwrap_or_default(),
+                content,
+            };
+            report(client.import_zone(request).await?.into_inner())
+        }
+        Command::Zone(ZoneCommand::Apply { origin, file, dry_run }) => {
+            let request = ApplyZoneSpecRequest {
+                origin,
+                records: Vec::new(),
+                zone_file: std::fs::read_to_string(&file)?,
+                dry_run,
+            };
+            let response = client.apply_zone_spec(request).await?.into_inner();
+            if response.changes.is_empty() {
+                println!("Zone matches the spec");
+                return Ok(());
+            }
+            print_plan(&DryRun {
+                changes: response.changes,
+                serials: response.serials,
+            });
+            match response.applied {
+                true => println!("Zone spec applied"),
+                false => println!("Dry run, nothing changed"),
+            }
+            Ok(())
+        }
+        Command::Zone(ZoneCommand::Export { origin }) => {
+            let response = client.export_zone(ExportZoneRequest { origin }).await?.into_inner();
+            print!("{}", response.content);
+            Ok(())
+        }
+        Command::Zone(ZoneCommand::Check { origin }) => {
+            let findings = client.check_zone(CheckZoneRequest { origin }).await?.into_inner().findings;
+            let mut errors = 0;
+            for finding in &findings {
+                let severity = zone_finding::Severity::try_from(finding.severity).unwrap_or(zone_finding::Severity::Error);
+                if severity == zone_finding::Severity::Error {
+                    errors += 1;
+                }
+                println!("{}\t{}\t{}\t{}", severity.as_str_name(), finding.check, finding.name, finding.message);
+            }
+            if errors > 0 {
+                anyhow::bail!("{} of {} problems found are errors", errors, findings.len());
+            }
+            if findings.is_empty() {
+                println!("No problems found");
+            }
+            Ok(())
+        }
+        Command::Zone(ZoneCommand::Versions { origin }) => {
+            let request = ListZoneVersionsRequest { origin };
+            for version in client.list_zone_versions(request).await?.into_inner().versions {
+                let time = version.timestamp.map_or(0, |timestamp| timestamp.seconds);
+                println!(
+                    "{}\t{}\tserial={}\t{} records",
+                    version.change_id, time, version.serial, version.record_count
+                );
+            }
+            Ok(())
+        }
+        Command::Zone(ZoneCommand::Rollback { origin, change_id, serial }) => {
+            let version = match (change_id, serial) {
+                (Some(change_id), _) => rollback_zone_request::Version::ChangeId(change_id),
+                (None, Some(serial)) => rollback_zone_request::Version::Serial(serial),
+                (None, None) => anyhow::bail!("--change-id or --serial is required"),
+            };
+            let request = RollbackZoneRequest {
+                origin,
+                version: Some(version),
+            };
+            report(client.rollback_zone(request).await?.into_inner())
+        }
+        Command::Zone(ZoneCommand::Freeze { origin }) => {
+            report(client.freeze_zone(FreezeZoneRequest { origin }).await?.into_inner())
+        }
+        Command::Zone(ZoneCommand::Thaw { origin }) => {
+            report(client.thaw_zone(ThawZoneRequest { origin }).await?.into_inner())
+        }
+        Command::Pool(PoolCommand::List) => {
+            for pool in client.list_pools(Empty {}).await?.into_inner().pools {
+                for member in &pool.members {
+                    let role = if member.backup { "backup" } else { "primary" };
+                    let state = if member.disabled { "\tdisabled" } else { "" };
+                    println!(
+                        "{}\t{}\tIN\t{}\t{}\t{} weight={}{}",
+                        pool.name, pool.ttl, pool.record_type, member.value, role, member.weight, state
+                    );
+                }
+            }
+            Ok(())
+        }
+        Command::Pool(PoolCommand::Set { name, record_type, members, backups, disabled, ttl }) => {
+            let primaries = members.iter().map(|arg| parse_member(arg, false, &disabled));
+            let backups = backups.iter().map(|arg| parse_member(arg, true, &disabled));
+            let request = SetPoolRequest {
+                name,
+                record_type,
+                ttl,
+                members: primaries.chain(backups).collect::<anyhow::Result<_>>()?,
+            };
+            report(client.set_pool(request).await?.into_inner())
+        }
+        Command::Pool(PoolCommand::Delete { name, record_type }) => {
+            report(client.delete_pool(DeletePoolRequest { name, record_type }).await?.into_inner())
+        }
+        Command::Health(HealthCommand::Get { name, record_type }) => {
+            let response = client.get_record_health(GetRecordHealthRequest { name, record_type }).await?.into_inner();
+            for value in response.values {
+                let Some(check) = &value.check else {
+                    println!("{}\tunchecked", value.value);
+                    continue;
+                };
+                let state = if value.healthy { "healthy" } else { "unhealthy" };
+                let port = if check.port == 0 { String::new() } else { format!(":{}", check.port) };
+                print!("{}\t{}\t{}{}{}", value.value, state, check.kind, port, check.path);
+                if !value.last_error.is_empty() {
+                    print!("\t{} failures: {}", value.consecutive_failures, value.last_error);
+                }
+                println!();
+            }
+            Ok(())
+        }
+        Command::Health(HealthCommand::Set { name, record_type, value, kind, port, path, interval, timeout }) => {
+            let check = HealthCheck {
+                kind,
+                port: port.unwrap_or_default(),
+                path: path.unwrap_or_default(),
+                interval_secs: interval.unwrap_or_default(),
+                timeout_secs: timeout.unwrap_or_default(),
+            };
+            let request = SetHealthCheckRequest {
+                name,
+                record_type,
+                value,
+                check: Some(check),
+            };
+            report(client.set_health_check(request).await?.into_inner())
+        }
+        Command::Health(HealthCommand::Delete { name, record_type, value }) => {
+            let request = DeleteHealthCheckRequest { name, record_type, value };
+            report(client.delete_health_check(request).await?.into_inner())
+        }
+        Command::Geo(GeoCommand::List) => {
+            for record in client.list_geo_records(Empty {}).await?.into_inner().records {
+                for region in &record.regions {
+                    for value in &region.values {
+                        println!("{}\t{}\tIN\t{}\t{}\t{}", record.name, record.ttl, record.record_type, value, region.region);
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Geo(GeoCommand::Set { name, record_type, values, ttl }) => {
+            let mut regions: Vec<RegionValues> = Vec::new();
+            for arg in values {
+                let Some((region, value)) = arg.split_once('=') else {
+                    anyhow::bail!("expected REGION=VALUE, got {}", arg);
+                };
+                match regions.iter_mut().find(|existing| existing.region == region) {
+                    Some(existing) => existing.values.push(value.into()),
+                    None => regions.push(RegionValues {
+                        region: region.into(),
+                        values: vec![value.into()],
+                    }),
+                }
+            }
+            let record = GeoRecord { name, record_type, ttl, regions };
+            report(client.set_geo_record(record).await?.into_inner())
+        }
+        Command::Geo(GeoCommand::Delete { name, record_type }) => {
+            report(client.delete_geo_record(DeleteGeoRecordRequest { name, record_type }).await?.into_inner())
+        }
+        Command::Rollout(RolloutCommand::List) => {
+            for rollout in client.list_rollouts(Empty {}).await?.into_inner().rollouts {
+                for value in &rollout.values {
+                    println!("{}	{}	IN	{}	{}	{}%", rollout.name, rollout.ttl, rollout.record_type, value, rollout.percent);
+                }
+            }
+            Ok(())
+        }
+        Command::Rollout(RolloutCommand::Set { name, record_type, values, percent, ttl }) => {
+            let rollout = Rollout { name, record_type, ttl, values, percent };
+            report(client.set_rollout(rollout).await?.into_inner())
+        }
+        Command::Rollout(RolloutCommand::Delete { name, record_type }) => {
+            report(client.delete_rollout(DeleteRolloutRequest { name, record_type }).await?.into_inner())
+        }
+        Command::Rollout(RolloutCommand::Promote { name, record_type }) => {
+            report(client.promote_rollout(PromoteRolloutRequest { name, record_type }).await?.into_inner())
+        }
+        Command::Pattern(PatternCommand::List) => {
+            for pattern in client.list_name_patterns(Empty {}).await?.into_inner().patterns {
+                for value in &pattern.values {
+                    println!("{}\t{}\tIN\t{}\t{}", pattern.pattern, pattern.ttl, pattern.record_type, value);
+                }
+            }
+            Ok(())
+        }
+        Command::Pattern(PatternCommand::Set { pattern, record_type, values, ttl }) => {
+            let pattern = NamePattern { pattern, record_type, ttl, values };
+            report(client.set_name_pattern(pattern).await?.into_inner())
+        }
+        Command::Pattern(PatternCommand::Delete { pattern, record_type }) => {
+            report(client.delete_name_pattern(DeleteNamePatternRequest { pattern, record_type }).await?.into_inner())
+        }
+        Command::Override(OverrideCommand::List) => {
+            for local in client.list_overrides(Empty {}).await?.into_inner().overrides {
+                for value in &local.values {
+                    println!("{}\t{}\tIN\t{}\t{}", local.name, local.ttl, local.record_type, value);
+                }
+            }
+            Ok(())
+        }
+        Command::Override(OverrideCommand::Set { name, record_type, values, ttl }) => {
+            report(client.set_override(Override { name, record_type, ttl, values }).await?.into_inner())
+        }
+        Command::Override(OverrideCommand::Delete { name, record_type }) => {
+            report(client.delete_override(DeleteOverrideRequest { name, record_type }).await?.into_inner())
+        }
+        Command::Alias(AliasCommand::List) => {
+            for alias in client.list_aliases(Empty {}).await?.into_inner().aliases {
+                println!("{}\t{}\tALIAS\t{}", alias.name, alias.ttl, alias.target);
+            }
+            Ok(())
+        }
+        Command::Alias(AliasCommand::Set { name, target, ttl }) => {
+            report(client.set_alias(Alias { name, target, ttl }).await?.into_inner())
+        }
+        Command::Alias(AliasCommand::Delete { name }) => {
+            report(client.delete_alias(DeleteAliasRequest { name }).await?.into_inner())
+        }
+        Command::Dnssec(DnssecCommand::Ds { origin }) => {
+            for ds in client.get_zone_ds(GetZoneDsRequest { origin }).await?.into_inner().ds_records {
+                println!("{}", ds);
+            }
+            Ok(())
+        }
+        Command::Dnssec(DnssecCommand::Status { origin }) => {
+            let rollovers = client.get_key_rollovers(GetKeyRolloversRequest { origin }).await?.into_inner();
+            if let Some(since) = rollovers.zsk_signing_since {
+                println!("zsk-signing-since\t{}", since.seconds);
+            }
+            for rollover in &rollovers.rollovers {
+                let since = rollover.since.as_ref().map_or(0, |since| since.seconds);
+                println!("rollover\t{}\t{}\tsince {}", rollover.role().as_str_name(), rollover.phase, since);
+            }
+            if !rollovers.next_step.is_empty() {
+                let at = rollovers.next_step_at.map_or(0, |at| at.seconds);
+                println!("next-step\t{}\tat {}", rollovers.next_step, at);
+            }
+            if !rollovers.next_ds.is_empty() {
+                println!("new-ds\t{}", rollovers.next_ds);
+            }
+            Ok(())
+        }
+        Command::Dnssec(DnssecCommand::Rollover { origin, ksk }) => {
+            let request = KeyRolloverRequest { origin, role: key_role(ksk).into() };
+            report(client.start_key_rollover(request).await?.into_inner())
+        }
+        Command::Dnssec(DnssecCommand::Confirm { origin }) => {
+            report(client.confirm_ksk_rollover(ConfirmKskRolloverRequest { origin }).await?.into_inner())
+        }
+        Command::Dnssec(DnssecCommand::Cancel { origin, ksk }) => {
+            let request = KeyRolloverRequest { origin, role: key_role(ksk).into() };
+            report(client.cancel_key_rollover(request).await?.into_inner())
+        }
+        Command::Tsig(TsigCommand::List) => {
+            for key in client.list_tsig_keys(Empty {}).await?.into_inner().keys {
+                let source = if key.configured { "config" } else { "created" };
+                println!("{}\t{}\t{}", key.name, key.algorithm, source);
+            }
+            Ok(())
+        }
+        Command::Tsig(TsigCommand::Create { name, algorithm }) => {
+            let key = client.create_tsig_key(CreateTsigKeyRequest { name, algorithm }).await?.into_inner();
+            println!("{}\t{}\t{}", key.name, key.algorithm, key.secret);
+            Ok(())
+        }
+        Command::Tsig(TsigCommand::Delete { name }) => {
+            report(client.delete_tsig_key(DeleteTsigKeyRequest { name }).await?.into_inner())
+        }
+        Command::Tenant(TenantCommand::List) => {
+            let limit = |limit: u32| if limit == 0 { "-".to_string() } else { limit.to_string() };
+            for tenant in client.list_tenants(Empty {}).await?.into_inner().tenants {
+                let source = if tenant.configured { "config" } else { "created" };
+                println!(
+                    "{}	{}/{} zones	{}/{} records	{}	{}",
+                    tenant.name,
+                    tenant.zones.len(),
+                    limit(tenant.max_zones),
+                    tenant.records,
+                    limit(tenant.max_records),
+                    tenant.zones.join(","),
+                    source
+                );
+            }
+            Ok(())
+        }
+        Command::Tenant(TenantCommand::Create { name, zones, max_zones, max_records }) => {
+            let request = CreateTenantRequest { name, zones, max_zones, max_records };
+            report(client.create_tenant(request).await?.into_inner())
+        }
+        Command::Acme(AcmeCommand::Set { domain, value, ttl }) => {
+            report(client.set_acme_challenge(SetAcmeChallengeRequest { domain, value, ttl }).await?.into_inner())
+        }
+        Command::Acme(AcmeCommand::Clear { domain, value }) => {
+            let request = ClearAcmeChallengeRequest { domain, value: value.unwrap_or_default() };
+            report(client.clear_acme_challenge(request).await?.into_inner())
+        }
+        Command::Forward(ForwardCommand::List) => {
+            let mut client = ForwardingRulesClient::new(connection);
+            for rule in client.list_forwarding_rules(Empty {}).await?.into_inner().rules {
+                let source = if rule.configured { "config" } else { "created" };
+                println!("{}\t{}\t{}", rule.domain, rule.upstreams.join(","), source);
+            }
+            Ok(())
+        }
+        Command::Forward(ForwardCommand::Add { domain, upstreams }) => {
+            let mut client = ForwardingRulesClient::new(connection);
+            let rule = ForwardingRule { domain, upstreams, configured: false };
+            report(client.add_forwarding_rule(rule).await?.into_inner())
+        }
+        Command::Forward(ForwardCommand::Delete { domain }) => {
+            let mut client = ForwardingRulesClient::new(connection);
+            report(client.delete_forwarding_rule(DeleteForwardingRuleRequest { domain }).await?.into_inner())
+        }
+        Command::Rewrite(RewriteCommand::List) => {
+            let mut client = RewriteRulesClient::new(connection);
+            for rule in client.list_rewrite_rules(Empty {}).await?.into_inner().rules {
+                let kind = if rule.regex { "regex" } else { "suffix" };
+                let source = if rule.configured { "config" } else { "created" };
+                println!("{}\t{}\t{}\t{}", rule.from, rule.to, kind, source);
+            }
+            Ok(())
+        }
+        Command::Rewrite(RewriteCommand::Add { from, to, regex }) => {
+            let mut client = RewriteRulesClient::new(connection);
+            let rule = RewriteRule { from, to, regex, configured: false };
+            report(client.add_rewrite_rule(rule).await?.into_inner())
+        }
+        Command::Rewrite(RewriteCommand::Delete { from }) => {
+            let mut client = RewriteRulesClient::new(connection);
+            report(client.delete_rewrite_rule(DeleteRewriteRuleRequest { from }).await?.into_inner())
+        }
+        Command::Rules(RulesCommand::List) => {
+            let mut client = QueryRulesClient::new(connection);
+            for rule in client.list_query_rules(Empty {}).await?.into_inner().rules {
+                let source = if rule.configured { "config" } else { "created" };
+                println!("{}\t{}\t{}\t{}\t{}", rule.match_type, rule.pattern, rule.action, rule.values.join(", "), source);
+            }
+            Ok(())
+        }
+        Command::Rules(RulesCommand::Add { pattern, match_type, action, values, ttl }) => {
+            let mut client = QueryRulesClient::new(connection);
+            let rule = QueryRule { pattern, match_type, action, values, ttl, configured: false };
+            report(client.add_query_rule(rule).await?.into_inner())
+        }
+        Command::Rules(RulesCommand::Delete { pattern, match_type }) => {
+            let mut client = QueryRulesClient::new(connection);
+            report(client.delete_query_rule(DeleteQueryRuleRequest { match_type, pattern }).await?.into_inner())
+        }
+        Command::Rules(RulesCommand::Import { file, replace }) => {
+            let mut client = QueryRulesClient::new(connection);
+            let request = ImportQueryRulesRequest {
+                content: std::fs::read_to_string(&file)?,
+                replace,
+            };
+            report(client.import_query_rules(request).await?.into_inner())
+        }
+        Command::Service(ServiceCommand::List) => {
+            for service in client.list_services(Empty {}).await?.into_inner().services {
+                let metadata: Vec<String> = service.metadata.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+                println!(
+                    "{}._{}._{}.{}\t{}:{}\t{}",
+                    service.instance,
+                    service.service,
+                    service.protocol,
+                    service.domain,
+                    service.host,
+                    service.port,
+                    metadata.join(" ")
+                );
+            }
+            Ok(())
+        }
+        Command::Service(ServiceCommand::Register {
+            instance,
+            service,
+            domain,
+            host,
+            port,
+            protocol,
+            addresses,
+            metadata,
+            priority,
+            weight,
+            ttl,
+        }) => {
+            let entries = parse_key_values(&metadata)?;
+            let registration = ServiceRegistration {
+                instance,
+                service,
+                protocol,
+                domain,
+                host,
+                addresses,
+                port,
+                priority,
+                weight,
+                metadata: entries,
+                ttl,
+            };
+            report(client.register_service(registration).await?.into_inner())
+        }
+        Command::Service(ServiceCommand::Deregister { instance, service, domain, protocol }) => {
+            let request = DeregisterServiceRequest {
+                instance,
+                service,
+                protocol,
+                domain,
+            };
+            report(client.deregister_service(request).await?.into_inner())
+        }
+        Command::View(ViewCommand::List) => {
+            for view in client.list_views(Empty {}).await?.into_inner().views {
+                println!("view {}\t{}", view.name, view.match_clients.join(", "));
+                view.records.iter().for_each(print_record);
+            }
+            Ok(())
+        }
+        Command::View(ViewCommand::Add { view, name, record_type, value, ttl }) => {
+            let record = DnsRecord { name, value, ttl, record_type, metadata: None };
+            let request = AddViewRecordRequest { view, record: Some(record) };
+            report(client.add_view_record(request).await?.into_inner())
+        }
+        Command::View(ViewCommand::Delete { view, name, record_type }) => {
+            let request = DeleteViewRecordRequest { view, name, record_type };
+            report(client.delete_view_record(request).await?.into_inner())
+        }
+        Command::Blocklist(BlocklistCommand::List) => {
+            let response = client.list_blocklist(Empty {}).await?.into_inner();
+            for source in &response.sources {
+                let state = match (&source.loaded, source.error.as_str()) {
+                    (_, error) if !error.is_empty() => format!("failed: {}", error),
+                    (None, _) => "not loaded yet".into(),
+                    (Some(_), _) => "loaded".into(),
+                };
+                println!("list\t{}\t{} entries\t{}", source.source, source.entries, state);
+            }
+            for entry in &response.entries {
+                println!("{}\t{}", entry.action().as_str_name().to_ascii_lowercase(), entry.domain);
+            }
+            Ok(())
+        }
+        Command::Blocklist(BlocklistCommand::Add { domain, allow }) => {
+            report(client.add_blocklist_entry(blocklist_entry(domain, allow)).await?.into_inner())
+        }
+        Command::Blocklist(BlocklistCommand::Delete { domain, allow }) => {
+            report(client.delete_blocklist_entry(blocklist_entry(domain, allow)).await?.into_inner())
+        }
+        Command::FlushCache { name } => {
+            let request = FlushCacheRequest { name: name.unwrap_or_default() };
+            report(client.flush_cache(request).await?.into_inner())
+        }
+        Command::ReloadConfig => {
+            let response = client.reload_config(Empty {}).await?.into_inner();
+            report(ControlResponse {
+                success: response.success,
+                message: response.message,
+                dry_run: None,
+            })
+        }
+        Command::CheckConfig { file } => {
+            let content = match &file {
+                Some(file) => std::fs::read_to_string(file)?,
+                None => String::new(),
+            };
+            let response = client.check_config(CheckConfigRequest { content }).await?.into_inner();
+            let name = file.as_ref().map_or("Config.toml".to_string(), |file| file.display().to_string());
+            for problem in &response.problems {
+                let position = match problem.line {
+                    0 => String::new(),
+                    line => format!("{}:{}: ", line, problem.column),
+                };
+                let key = if problem.key.is_empty() { String::new() } else { format!("{}: ", problem.key) };
+                println!("{}:{}{}{}", name, position, key, problem.message);
+                if problem.line > 0 && !problem.context.is_empty() {
+                    println!("{:>5} | {}", problem.line, problem.context);
+                }
+            }
+            if !response.valid {
+                anyhow::bail!("{} problem(s) found in {}", response.problems.len(), name);
+            }
+            println!("{} is valid", name);
+            Ok(())
+        }
+        Command::Watch => {
+            let mut events = client.watch_records(Empty {}).await?.into_inner();
+            while let Some(event) = events.message().await? {
+                let change = record_event::Change::try_from(event.change).map_or("UNKNOWN", |change| change.as_str_name());
+                if let Some(record) = &event.record {
+                    print!("{}\t", change);
+                    print_record(record);
+                }
+            }
+            Ok(())
+        }
+        Command::Dig { name, record_type, tcp, tls, https, expect } => {
+            let protocol = match (tcp, tls, https) {
+                (_, _, true) => verify_record_request::Protocol::Https,
+                (_, true, _) => verify_record_request::Protocol::Tls,
+                (true, _, _) => verify_record_request::Protocol::Tcp,
+                _ => verify_record_request::Protocol::Udp,
+            };
+            let record_type = record_type.unwrap_or_else(|| "A".to_string()).to_ascii_uppercase();
+            let request = VerifyRecordRequest {
+                name: name.clone(),
+                record_type: record_type.clone(),
+                protocol: protocol.into(),
+                value: expect.clone().unwrap_or_default(),
+            };
+            let response = client.verify_record(request).await?.into_inner();
+            let flags = if response.authoritative { ", authoritative" } else { "" };
+            let truncated = if response.truncated { ", truncated" } else { "" };
+            println!(";; {}{}{} in {} ms over {}", response.rcode, flags, truncated, response.rtt_ms, protocol.as_str_name());
+            for record in &response.answers {
+                print_record(record);
+            }
+            if !response.found {
+                match expect {
+                    Some(value) => anyhow::bail!("no {} record of {} holds {}", record_type, name, value),
+                    None => anyhow::bail!("{} has no {} records", name, record_type),
+                }
+            }
+            Ok(())
+        }
+        Command::Stats { zone, name, record_type, window, top, stale } => {
+            let mut client = StatsClient::new(connection);
+            let request = GetQueryStatsRequest {
+                zone: zone.unwrap_or_default(),
+                name: name.unwrap_or_default(),
+                record_type: record_type.unwrap_or_default(),
+                window_secs: window.unwrap_or_default(),
+                top,
+                stale,
+            };
+            for entry in client.get_query_stats(request).await?.into_inner().entries {
+                let last = entry.last_queried.map_or("never".to_string(), |timestamp| timestamp.seconds.to_string());
+                let capped = if entry.clients_capped { "+" } else { "" };
+                println!(
+                    "{}\t{}\t{} queries\t{} total\t{}{} clients\tlast={}",
+                    entry.name, entry.record_type, entry.queries, entry.total, entry.unique_clients, capped, last
+                );
+            }
+            Ok(())
+        }
+        Command::TopClients { window, top } => {
+            let mut client = StatsClient::new(connection);
+            let request = GetTopClientsRequest {
+                window_secs: window.unwrap_or_default(),
+                top,
+            };
+            for entry in client.get_top_clients(request).await?.into_inner().entries {
+                println!(
+                    "{}\t{} queries\t{} nxdomain\t{} total\tlast={}",
+                    entry.client,
+                    entry.queries,
+                    entry.nxdomain,
+                    entry.total,
+                    entry.last_queried.map_or(0, |t| t.seconds)
+                );
+            }
+            Ok(())
+        }
+        Command::WatchAnomalies => {
+            let mut client = StatsClient::new(connection);
+            let mut anomalies = client.watch_anomalies(Empty {}).await?.into_inner();
+            while let Some(anomaly) = anomalies.message().await? {
+                let kind = anomaly_event::Kind::try_from(anomaly.kind).map_or("UNKNOWN", |kind| kind.as_str_name());
+                println!(
+                    "{}\t{}\t{}\t{}/{}",
+                    anomaly.timestamp.map_or(0, |t| t.seconds),
+                    kind,
+                    anomaly.client,
+                    anomaly.count,
+                    anomaly.threshold
+                );
+            }
+            Ok(())
+        }
+        Command::Audit { since, until } => {
+            let timestamp = |seconds| prost_types::Timestamp { seconds, nanos: 0 };
+            let request = GetAuditLogRequest {
+                since: since.map(timestamp),
+                until: until.map(timestamp),
+            };
+            for entry in client.get_audit_log(request).await?.into_inner().entries {
+                let time = entry.timestamp.map_or(0, |timestamp| timestamp.seconds);
+                let token = if entry.token.is_empty() { "-" } else { &entry.token };
+                let peer = if entry.peer.is_empty() { "-" } else { &entry.peer };
+                let change = match entry.change_id {
+                    0 => String::new(),
+                    change_id => format!("\tchange={}", change_id),
+                };
+                println!("{}\t{}\t{}\ttoken={}\tpeer={}{}", time, entry.action, entry.target, token, peer, change);
+                for record in &entry.before {
+                    print!("-\t");
+                    print_record(record);
+                }
+                for record in &entry.after {
+                    print!("+\t");
+                    print_record(record);
+                }
+            }
+            Ok(())
+        }
+        Command::Backup { file } => {
+            let request = BackupStateRequest { chunk_size: SNAPSHOT_CHUNK_SIZE };
+            let mut stream = client.backup_state(request).await?.into_inner();
+            let mut data = Vec::new();
+            while let Some(chunk) = stream.message().await? {
+                data.extend_from_slice(&chunk.data);
+            }
+            std::fs::write(&file, &data)?;
+            println!("Saved {} bytes to {}", data.len(), file.display());
+            Ok(())
+        }
+        Command::Restore { file } => {
+            let data = std::fs::read(&file)?;
+            let chunks: Vec<SnapshotChunk> = data
+                .chunks(SNAPSHOT_CHUNK_SIZE as usize)
+                .map(|chunk| SnapshotChunk { data: chunk.to_vec() })
+                .collect();
+            report(client.restore_state(futures_util::stream::iter(chunks)).await?.into_inner())
+        }
+        Command::Cluster => {
+            let status = client.cluster_status(Empty {}).await?.into_inner();
+            println!("role	{}
+sequence	{}", status.role, status.sequence);
+            for follower in &status.followers {
+                let since = follower.connected_since.as_ref().map_or(0, |timestamp| timestamp.seconds);
+                println!("follower	{}	since={}	sent={}", follower.peer, since, follower.sent_sequence);
+            }
+            if status.role == "follower" {
+                let state = if status.connected { "connected" } else { "disconnected" };
+                println!("leader	{}	{}	sequence={}", status.leader, state, status.leader_sequence);
+                println!("lag	{}ms", status.lag_ms);
+                if let Some(contact) = &status.last_contact {
+                    println!("last contact	{}", contact.seconds);
+                }
+                if !status.error.is_empty() {
+                    println!("error	{}", status.error);
+                }
+            }
+            Ok(())
+        }
+        Command::Admin(AdminCommand::Shutdown) => {
+            let mut client = AdminClient::new(connection);
+            report(client.shutdown(Empty {}).await?.into_inner())
+        }
+        Command::Admin(AdminCommand::Reload) => {
+            let mut client = AdminClient::new(connection);
+            let response = client.reload(Empty {}).await?.into_inner();
+            for zone_file in &response.zone_files {
+                match zone_file.error.as_str() {
+                    "" => println!("{}\t{} records", zone_file.path, zone_file.records),
+                    error => println!("{}\tfailed: {}", zone_file.path, error),
+                }
+            }
+            println!("{}", response.message);
+            Ok(())
+        }
+        Command::Admin(AdminCommand::Drain { mode }) => {
+            let mut client = AdminClient::new(connection);
+            let mode = match drain_mode_request::Mode::from_str_name(&mode.to_uppercase()) {
+                Some(drain_mode_request::Mode::Off) | None => {
+                    anyhow::bail!("unknown drain mode {}, expected truncate, refuse or unhealthy", mode)
+                }
+                Some(mode) => mode,
+            };
+            let request = DrainModeRequest { mode: mode.into() };
+            report(client.drain_mode(request).await?.into_inner())
+        }
+        Command::Admin(AdminCommand::Undrain) => {
+            let mut client = AdminClient::new(connection);
+            let request = DrainModeRequest { mode: drain_mode_request::Mode::Off.into() };
+            report(client.drain_mode(request).await?.into_inner())
+        }
+        Command::Admin(AdminCommand::LogLevel { level: Some(level), duration }) => {
+            let mut client = AdminClient::new(connection);
+            let request = SetLogLevelRequest {
+                level,
+                duration_secs: duration.unwrap_or_default(),
+            };
+            report(client.set_log_level(request).await?.into_inner())
+        }
+        Command::Admin(AdminCommand::LogLevel { level: None, .. }) => {
+            let mut client = AdminClient::new(connection);
+            let response = client.get_log_level(Empty {}).await?.into_inner();
+            match response.reverts_at {
+                Some(reverts_at) => println!("{}\tuntil {}", response.level, reverts_at.seconds),
+                None => println!("{}", response.level),
+            }
+            Ok(())
+        }
+        Command::ServerStats => {
+            let stats = client.get_server_stats(Empty {}).await?.into_inner();
+            let limit = |limit: u64| if limit == 0 { "unlimited".to_string() } else { limit.to_string() };
+            for zone in &stats.zones {
+                println!("{}	{} records	{} bytes", zone.origin, zone.records, zone.estimated_bytes);
+            }
+            println!("records	{} of {}", stats.records, limit(stats.max_records));
+            println!("memory	{} of {} bytes", stats.estimated_bytes, limit(stats.max_estimated_bytes));
+            println!(
+                "cache	{} entries	{} negative	{} bytes",
+                stats.cache_entries, stats.cache_negative_entries, stats.cache_estimated_bytes
+            );
+            for upstream in &stats.upstreams {
+                println!(
+                    "upstream	{}	{:.1}ms	{:.1}% failed	{} answered	{} failed{}",
+                    upstream.upstream,
+                    upstream.srtt_ms,
+                    upstream.failure_rate * 100.0,
+                    upstream.answered,
+                    upstream.failed,
+                    if upstream.down { "	down" } else { "" }
+                );
+            }
+            println!("uptime	{}s", stats.uptime_secs);
+            Ok(())
+        }
+        Command::ServerInfo => {
+            let info = client.get_server_info(Empty {}).await?.into_inner();
+            println!("version\t{}", info.version);
+            println!("commit\t{}", info.commit);
+            for listener in &info.listeners {
+                println!("listener\t{}\t{}", listener.protocol, listener.address);
+            }
+            println!("features\t{}", info.features.join(","));
+            println!("zones\t{}", info.zones);
+            println!("started\t{}", info.started_at.map_or(0, |at| at.seconds));
+            if let Some(reloaded_at) = info.reloaded_at {
+                println!("reloaded\t{}", reloaded_at.seconds);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = async {
+        let settings = load_connection(cli.config, cli.connection)?;
+        let connection = connect(settings).await?;
+        run(connection, cli.command).await
+    }
+    .await;
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            match e.downcast_ref::<Status>() {
+                Some(status) => eprintln!("Error: {}", status.message()),
+                None => eprintln!("Error: {}", e),
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Load generator for measuring the UDP query throughput of an rdns server.
+//!
+//! Every worker sends one query at a time over its own socket and waits for the answer,
+//! so the load scales with `--concurrency`. The query is encoded once and only its ID is
+//! rewritten before each send, and answers are read into a buffer reused throughout, so
+//! the generator itself allocates nothing per query:
+//!
+//! ```sh
+//! rdnsload --server 127.0.0.1:8053 --name www.example.com. --concurrency 32 --duration-secs 10
+//! ```
+
+use clap::Parser;
+use hickory_proto::op::{Message, MessageType, OpCode, Query};
+use hickory_proto::rr::{Name, RecordType};
+use hickory_proto::serialize::binary::BinEncodable;
+use std::net::SocketAddr;
+use std::process::ExitCode;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+#[derive(Parser)]
+#[command(name = "rdnsload", about = "Measure the UDP query throughput of a DNS server")]
+struct Cli {
+    /// Server to query
+    #[arg(long, default_value = "127.0.0.1:8053")]
+    server: SocketAddr,
+    /// Name to query
+    #[arg(long, default_value = "www.example.com.")]
+    name: String,
+    /// Type to query
+    #[arg(long = "type", default_value = "A")]
+    record_type: String,
+    /// Queries in flight at once, each from its own socket
+    #[arg(long, default_value_t = 16)]
+    concurrency: usize,
+    /// How long to send queries for
+    #[arg(long, default_value_t = 10)]
+    duration_secs: u64,
+    /// How long to wait for each answer before counting the query as lost
+    #[arg(long, default_value_t = 1000)]
+    timeout_ms: u64,
+}
+
+/// What a worker measured.
+#[derive(Default)]
+struct Tally {
+    /// Round-trip times of the answered queries, in microseconds
+    latencies: Vec<u32>,
+    lost: usize,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    let query = encode_query(&cli.name, &cli.record_type)?;
+    let duration = Duration::from_secs(cli.duration_secs);
+    let timeout = Duration::from_millis(cli.timeout_ms);
+    let deadline = Instant::now() + duration;
+
+    let mut workers = Vec::with_capacity(cli.concurrency);
+    for _ in 0..cli.concurrency {
+        let socket = UdpSocket::bind(match cli.server {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        })
+        .await?;
+        socket.connect(cli.server).await?;
+        workers.push(tokio::spawn(worker(socket, query.clone(), deadline, timeout)));
+    }
+    let mut latencies = Vec::new();
+    let mut lost = 0;
+    for worker in workers {
+        let tally = worker.await??;
+        latencies.extend(tally.latencies);
+        lost += tally.lost;
+    }
+
+    latencies.sort_unstable();
+    let answered = latencies.len();
+    println!("{} queries answered, {} lost in {:?}", answered, lost, duration);
+    println!("{:.0} queries/s", answered as f64 / duration.as_secs_f64());
+    if answered > 0 {
+        let percentile = |p: usize| latencies[(answered * p / 100).min(answered - 1)];
+        println!("latency p50 {}µs, p99 {}µs, max {}µs", percentile(50), percentile(99), latencies[answered - 1]);
+    }
+    Ok(())
+}
+
+/// Sends queries one after the other until `deadline`, matching answers by ID.
+async fn worker(socket: UdpSocket, mut query: Vec<u8>, deadline: Instant, timeout: Duration) -> std::io::Result<Tally> {
+    let mut tally = Tally::default();
+    let mut buffer = [0u8; 4096];
+    let mut id: u16 = rand::random();
+    while Instant::now() < deadline {
+        id = id.wrapping_add(1);
+        query[..2].copy_from_slice(&id.to_be_bytes());
+        let sent = Instant::now();
+        socket.send(&query).await?;
+        let answered = tokio::time::timeout(timeout, async {
+            loop {
+                let len = socket.recv(&mut buffer).await?;
+                // Late answers to queries already counted as lost are skipped
+                if len >= 2 && buffer[..2] == id.to_be_bytes() {
+                    return Ok::<_, std::io::Error>(());
+                }
+            }
+        })
+        .await;
+        match answered {
+            Ok(result) => {
+                result?;
+                tally.latencies.push(sent.elapsed().as_micros().min(u32::MAX as u128) as u32);
+            }
+            Err(_) => tally.lost += 1,
+        }
+    }
+    Ok(tally)
+}
+
+fn encode_query(name: &str, record_type: &str) -> anyhow::Result<Vec<u8>> {
+    let name = Name::from_str(name)?;
+    let record_type = RecordType::from_str(&record_type.to_uppercase())?;
+    let mut message = Message::new();
+    message
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, record_type));
+    Ok(message.to_bytes()?)
+}
@@ -0,0 +1,350 @@
+//! Blocking of unwanted domains, Pi-hole style.
+//!
+//! Blocklists are downloaded from HTTP(S) URLs, or read from files, at startup and then
+//! on every refresh. Three formats are understood, and may be mixed in one list:
+//!
+//! - hosts files, `0.0.0.0 ads.example.com`, blocking the names listed;
+//! - adblock filters, `||ads.example.com^`, blocking the domain and its subdomains, with
+//!   `@@||cdn.example.com^` exceptions unblocking them again;
+//! - plain domain lists, one name per line, blocking the names listed.
+//!
+//! Domains can also be blocked or allowed through the control API, along with their
+//! subdomains; those entries are persisted and take precedence over the lists, allowed
+//! ones over blocked ones. Queries for a blocked name are answered with the configured
+//! sinkhole addresses, or NXDOMAIN when there are none.
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{LowerName, RData, Record, RecordType};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use hyper::client::HttpConnector;
+use hyper::{Client, Uri};
+use hyper_rustls::HttpsConnector;
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::dns;
+use crate::settings::BlocklistSettings;
+
+/// How long downloading a single list may take.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Config options for blocking
+#[derive(Clone)]
+pub struct BlocklistOptions {
+    /// URLs or paths of the lists.
+    pub lists: Vec<String>,
+    pub refresh: Duration,
+    /// Addresses blocked names resolve to; NXDOMAIN is answered when empty.
+    pub sinkhole: Vec<IpAddr>,
+    pub ttl: u32,
+}
+
+impl TryFrom<BlocklistSettings> for BlocklistOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: BlocklistSettings) -> anyhow::Result<Self> {
+        if cfg.refresh_secs == 0 {
+            anyhow::bail!("blocklist.refresh_secs must be at least 1");
+        }
+        let sinkhole = cfg
+            .sinkhole
+            .iter()
+            .map(|address| address.parse().map_err(|e| anyhow::anyhow!("invalid sinkhole address {}: {}", address, e)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(BlocklistOptions {
+            lists: cfg.lists,
+            refresh: Duration::from_secs(cfg.refresh_secs),
+            sinkhole,
+            ttl: cfg.ttl,
+        })
+    }
+}
+
+/// Which runtime entries a domain is added to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Block,
+    Allow,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Block => f.write_str("block"),
+            Action::Allow => f.write_str("allow"),
+        }
+    }
+}
+
+/// The domains of one list, without trailing dots.
+#[derive(Default)]
+struct ListEntries {
+    /// Blocked names.
+    exact: HashSet<String>,
+    /// Blocked domains, along with their subdomains.
+    domains: HashSet<String>,
+    /// Domains unblocked, along with their subdomains.
+    exceptions: HashSet<String>,
+}
+
+impl ListEntries {
+    /// Parses a list in any mix of the hosts, adblock and plain domain formats, skipping
+    /// lines it doesn't understand.
+    fn parse(text: &str) -> Self {
+        let mut entries = Self::default();
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+                continue;
+            }
+            if let Some(filter) = line.strip_prefix("@@||") {
+                entries.exceptions.extend(adblock_domain(filter));
+            } else if let Some(filter) = line.strip_prefix("||") {
+                entries.domains.extend(adblock_domain(filter));
+            } else {
+                let mut tokens = line.split_whitespace();
+                let first = tokens.next().unwrap_or_default();
+                if first.parse::<IpAddr>().is_ok() {
+                    entries.exact.extend(tokens.filter_map(normalize));
+                } else if tokens.next().is_none() {
+                    entries.exact.extend(normalize(first));
+                }
+            }
+        }
+        entries
+    }
+
+    fn len(&self) -> usize {
+        self.exact.len() + self.domains.len()
+    }
+}
+
+/// The domain of an adblock filter following its `||`, e.g. `ads.example.com^`, if the
+/// filter applies to the whole domain.
+fn adblock_domain(filter: &str) -> Option<String> {
+    let (domain, options) = filter.split_once('^')?;
+    if !options.is_empty() && options != "$important" {
+        return None;
+    }
+    normalize(domain)
+}
+
+/// Lower-cases `domain` and drops its trailing dot, if it is a valid multi-label name.
+fn normalize(domain: &str) -> Option<String> {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let valid = domain.contains('.')
+        && domain.split('.').all(|label| !label.is_empty())
+        && domain.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'.');
+    (valid && domain != "localhost.localdomain").then_some(domain)
+}
+
+/// `name` and each domain above it, e.g. `a.b.c`, `b.c` and `c`.
+fn domains_of(name: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(name), |name| name.split_once('.').map(|(_, parent)| parent))
+}
+
+/// The state of one list.
+struct List {
+    source: String,
+    entries: ListEntries,
+    loaded: Option<SystemTime>,
+    error: Option<String>,
+}
+
+/// How a list last loaded, as reported through the control API.
+pub struct ListStatus {
+    pub source: String,
+    pub entries: usize,
+    pub loaded: Option<SystemTime>,
+    pub error: Option<String>,
+}
+
+/// The loaded lists and runtime entries, shared with the request handler.
+pub struct Blocklist {
+    options: BlocklistOptions,
+    lists: RwLock<Vec<List>>,
+    allowed: RwLock<BTreeSet<String>>,
+    blocked: RwLock<BTreeSet<String>>,
+    http: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Blocklist {
+    pub fn new(options: BlocklistOptions) -> Self {
+        let lists = options
+            .lists
+            .iter()
+            .map(|source| List {
+                source: source.clone(),
+                entries: ListEntries::default(),
+                loaded: None,
+                error: None,
+            })
+            .collect();
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Self {
+            options,
+            lists: RwLock::new(lists),
+            allowed: RwLock::new(BTreeSet::new()),
+            blocked: RwLock::new(BTreeSet::new()),
+            http: Client::builder().build(connector),
+        }
+    }
+
+    /// Whether queries for `name` are blocked.
+    pub fn blocks(&self, name: &LowerName) -> bool {
+        let name = name.to_string();
+        let name = name.trim_end_matches('.');
+        if domains_of(name).any(|domain| self.allowed.read().unwrap().contains(domain)) {
+            return false;
+        }
+        if domains_of(name).any(|domain| self.blocked.read().unwrap().contains(domain)) {
+            return true;
+        }
+        let lists = self.lists.read().unwrap();
+        if lists.iter().any(|list| domains_of(name).any(|domain| list.entries.exceptions.contains(domain))) {
+            return false;
+        }
+        lists.iter().any(|list| {
+            list.entries.exact.contains(name) || domains_of(name).any(|domain| list.entries.domains.contains(domain))
+        })
+    }
+
+    /// Adds `domain` to the runtime entries of `action`, taking it off the other's.
+    pub fn add(&self, domain: &str, action: Action) -> anyhow::Result<()> {
+        let domain = normalize(domain).ok_or_else(|| anyhow::anyhow!("invalid domain {}", domain))?;
+        let (to, from) = match action {
+            Action::Block => (&self.blocked, &self.allowed),
+            Action::Allow => (&self.allowed, &self.blocked),
+        };
+        from.write().unwrap().remove(&domain);
+        to.write().unwrap().insert(domain);
+        Ok(())
+    }
+
+    /// Removes `domain` from the runtime entries of `action`, returning whether it was one.
+    pub fn remove(&self, domain: &str, action: Action) -> anyhow::Result<bool> {
+        let domain = normalize(domain).ok_or_else(|| anyhow::anyhow!("invalid domain {}", domain))?;
+        let entries = match action {
+            Action::Block => &self.blocked,
+            Action::Allow => &self.allowed,
+        };
+        Ok(entries.write().unwrap().remove(&domain))
+    }
+
+    /// Removes every runtime entry.
+    pub fn clear(&self) {
+        self.blocked.write().unwrap().clear();
+        self.allowed.write().unwrap().clear();
+    }
+
+    /// The runtime entries of `action`, sorted.
+    pub fn entries(&self, action: Action) -> Vec<String> {
+        let entries = match action {
+            Action::Block => &self.blocked,
+            Action::Allow => &self.allowed,
+        };
+        entries.read().unwrap().iter().cloned().collect()
+    }
+
+    /// How each list last loaded, in the configured order.
+    pub fn sources(&self) -> Vec<ListStatus> {
+        self.lists
+            .read()
+            .unwrap()
+            .iter()
+            .map(|list| ListStatus {
+                source: list.source.clone(),
+                entries: list.entries.len(),
+                loaded: list.loaded,
+                error: list.error.clone(),
+            })
+            .collect()
+    }
+
+    /// Downloads every list again; a list that fails keeps its previous entries.
+    async fn refresh(&self) {
+        for source in &self.options.lists {
+            let result = self.fetch(source).await.map(|text| ListEntries::parse(&text));
+            let mut lists = self.lists.write().unwrap();
+            let Some(list) = lists.iter_mut().find(|list| &list.source == source) else {
+                continue;
+            };
+            match result {
+                Ok(entries) => {
+                    println!("Loaded {} blocklist entries from {}", entries.len(), source);
+                    list.entries = entries;
+                    list.loaded = Some(SystemTime::now());
+                    list.error = None;
+                }
+                Err(e) => {
+                    eprintln!("Failed to load blocklist {}: {}", source, e);
+                    list.error = Some(e.to_string());
+                }
+            }
+        }
+    }
+
+    /// Reads the list at `source`, a URL or a file path.
+    async fn fetch(&self, source: &str) -> anyhow::Result<String> {
+        if !source.starts_with("http://") && !source.starts_with("https://") {
+            let path = source.strip_prefix("file://").unwrap_or(source);
+            return Ok(tokio::fs::read_to_string(path).await?);
+        }
+        let uri = Uri::from_str(source)?;
+        let download = async {
+            let response = self.http.get(uri).await?;
+            if !response.status().is_success() {
+                anyhow::bail!("HTTP {}", response.status());
+            }
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            Ok(String::from_utf8_lossy(&body).into_owned())
+        };
+        tokio::time::timeout(FETCH_TIMEOUT, download)
+            .await
+            .map_err(|_| anyhow::anyhow!("timed out after {}s", FETCH_TIMEOUT.as_secs()))?
+    }
+
+    /// Answers `request` for a blocked name: with the sinkhole addresses of the queried
+    /// type, an empty answer when it has none of that type, or NXDOMAIN without any.
+    pub async fn send_blocked<R: ResponseHandler>(&self, request: &Request, response_handle: R) -> ResponseInfo {
+        if self.options.sinkhole.is_empty() {
+            return dns::send_error(request, ResponseCode::NXDomain, response_handle).await;
+        }
+        let name = request.query().original().name().clone();
+        let records: Vec<Record> = self
+            .options
+            .sinkhole
+            .iter()
+            .filter_map(|address| match (address, request.query().query_type()) {
+                (IpAddr::V4(v4), RecordType::A) => Some(RData::A(A(*v4))),
+                (IpAddr::V6(v6), RecordType::AAAA) => Some(RData::AAAA(AAAA(*v6))),
+                _ => None,
+            })
+            .map(|rdata| Record::from_rdata(name.clone(), self.options.ttl, rdata))
+            .collect();
+        dns::send_records(request, &records, None, response_handle).await
+    }
+}
+
+/// Loads the lists of `blocklist` now and then on every refresh, for as long as the
+/// server runs.
+pub async fn run_refresh(blocklist: Arc<Blocklist>) {
+    if blocklist.options.lists.is_empty() {
+        return;
+    }
+    let mut ticks = tokio::time::interval(blocklist.options.refresh);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        blocklist.refresh().await;
+    }
+}
@@ -0,0 +1,363 @@
+//! Response cache for forwarded queries.
+//!
+//! Upstream answers are kept until their TTL runs out and served with the remaining
+//! TTL. The cache is bounded both by entry count and by an estimate of the memory the
+//! cached records take up; when either bound is exceeded the least recently used
+//! entries are evicted first. Answers are cached along with whether they were validated
+//! as secure, so that they are served with the AD bit from the cache too.
+//!
+//! With `stale_window_secs` set, answers are kept that long past their TTL, and served
+//! stale (RFC 8767) with a TTL of `stale_ttl` when no upstream can be reached to
+//! resolve them again, rather than failing with SERVFAIL while upstreams are out.
+//!
+//! With `prefetch_min_hits` set, an answer served that many times is claimed for
+//! prefetching once less than `prefetch_percent` of its TTL remains, so that the
+//! forwarder refreshes it in the background before it expires. Each answer is claimed
+//! once; the refreshed answer counts its hits anew.
+//!
+//! Negative answers, NXDOMAIN and NODATA, are cached too (RFC 2308), for the TTL of the
+//! SOA record upstreams send along with them, capped at its minimum field and at
+//! `max_negative_ttl`. Those without an SOA record aren't cached, as the RFC has it.
+
+use hickory_proto::op::{Message, Query, ResponseCode};
+use hickory_proto::rr::{LowerName, RData, Record, RecordType};
+use hickory_proto::serialize::binary::BinEncodable;
+use hickory_server::authority::LookupError;
+use hickory_server::resolver::lookup::Lookup;
+use lru_cache::LruCache;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::settings::CacheSettings;
+
+/// Rough per-entry overhead of the key and bookkeeping, on top of the records.
+const ENTRY_OVERHEAD: usize = 128;
+
+/// Config options for the response cache
+pub struct CacheOptions {
+    pub max_entries: usize,
+    /// Upper bound on the estimated size of all cached records, in bytes.
+    pub max_bytes: usize,
+    /// How long past their TTL answers may be served stale; never when zero.
+    pub stale_window: Duration,
+    /// TTL of the answers served stale.
+    pub stale_ttl: u32,
+    /// Hits after which answers are prefetched; never when zero.
+    pub prefetch_min_hits: u32,
+    /// Share of their TTL, in percent, left when answers are prefetched.
+    pub prefetch_percent: u32,
+    /// Longest TTL negative answers are cached for; never when zero.
+    pub max_negative_ttl: u32,
+}
+
+impl From<CacheSettings> for CacheOptions {
+    fn from(cfg: CacheSettings) -> Self {
+        CacheOptions {
+            max_entries: cfg.max_entries,
+            max_bytes: cfg.max_memory_mb * 1024 * 1024,
+            stale_window: Duration::from_secs(cfg.stale_window_secs),
+            stale_ttl: cfg.stale_ttl,
+            prefetch_min_hits: cfg.prefetch_min_hits,
+            prefetch_percent: cfg.prefetch_percent.min(100),
+            max_negative_ttl: cfg.max_negative_ttl,
+        }
+    }
+}
+
+/// A negative answer (RFC 2308).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Negative {
+    /// The name doesn't exist.
+    NxDomain,
+    /// The name exists, without records of the type asked for.
+    NoData,
+}
+
+impl Negative {
+    /// The error lookups answered negatively fail with, as the catalog expects it.
+    pub fn error(self) -> LookupError {
+        match self {
+            Negative::NxDomain => LookupError::from(ResponseCode::NXDomain),
+            Negative::NoData => LookupError::NameExists,
+        }
+    }
+}
+
+/// An upstream's answer to a query, as it is cached.
+#[derive(Clone)]
+pub enum Answer {
+    Records(Lookup),
+    /// A negative answer, with the TTL it may be cached for if its SOA record gave one.
+    Negative(Negative, Option<u32>),
+}
+
+impl Answer {
+    /// The answer of `response` to `query`, or the error it is if it is neither records
+    /// nor a negative answer.
+    pub fn of(query: Query, response: &Message) -> Result<Self, LookupError> {
+        Answer::new(query, response.response_code(), response.answers().to_vec(), negative_ttl(response))
+    }
+
+    /// The answer to `query` of a response with `response_code` and `answers`, whose
+    /// negative TTL is `negative_ttl`, or the error it is.
+    pub fn new(query: Query, response_code: ResponseCode, answers: Vec<Record>, negative_ttl: Option<u32>) -> Result<Self, LookupError> {
+        let negative = match response_code {
+            ResponseCode::NoError if answers.is_empty() => Negative::NoData,
+            ResponseCode::NoError => return Ok(Answer::Records(Lookup::new_with_max_ttl(query, answers.into()))),
+            ResponseCode::NXDomain => Negative::NxDomain,
+            code => return Err(LookupError::from(code)),
+        };
+        Ok(Answer::Negative(negative, negative_ttl))
+    }
+
+    /// The lookup answering the query, or the error a negative answer fails it with.
+    pub fn lookup(self) -> Result<Lookup, LookupError> {
+        match self {
+            Answer::Records(lookup) => Ok(lookup),
+            Answer::Negative(negative, _) => Err(negative.error()),
+        }
+    }
+}
+
+/// How long the negative answer `response` may be cached for: the TTL of the SOA record
+/// of its authority section, or its minimum field if lower (RFC 2308 section 5).
+pub fn negative_ttl(response: &Message) -> Option<u32> {
+    response.name_servers().iter().find_map(|record| match record.data() {
+        Some(RData::SOA(soa)) => Some(record.ttl().min(soa.minimum())),
+        _ => None,
+    })
+}
+
+struct Entry {
+    lookup: Lookup,
+    /// The negative answer the entry holds, if it does; its lookup has no records then.
+    negative: Option<Negative>,
+    size: usize,
+    secure: bool,
+    inserted: Instant,
+    hits: u32,
+    /// Whether the entry has been claimed for prefetching
+    prefetching: bool,
+}
+
+struct Inner {
+    entries: LruCache<(LowerName, RecordType), Entry>,
+    bytes: usize,
+    /// How many of the entries hold negative answers
+    negative: usize,
+}
+
+impl Inner {
+    /// Removes the entry for `key`, if there is one.
+    fn remove(&mut self, key: &(LowerName, RecordType)) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.forget(&entry);
+        Some(entry)
+    }
+
+    /// Accounts for `entry` being gone.
+    fn forget(&mut self, entry: &Entry) {
+        self.bytes -= entry.size;
+        if entry.negative.is_some() {
+            self.negative -= 1;
+        }
+    }
+}
+
+/// How much the cache holds.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheUsage {
+    pub entries: usize,
+    /// Entries holding negative answers, counted in `entries` too
+    pub negative: usize,
+    /// Estimated size of the entries
+    pub bytes: usize,
+}
+
+/// A TTL-respecting LRU cache of upstream lookups.
+pub struct ResponseCache {
+    inner: Mutex<Inner>,
+    max_entries: usize,
+    max_bytes: usize,
+    stale_window: Duration,
+    stale_ttl: u32,
+    prefetch_min_hits: u32,
+    prefetch_percent: u32,
+    max_negative_ttl: u32,
+}
+
+impl ResponseCache {
+    pub fn new(options: &CacheOptions) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                // Both bounds are enforced by `insert`, so the LRU itself is unbounded
+                entries: LruCache::new(usize::MAX),
+                bytes: 0,
+                negative: 0,
+            }),
+            max_entries: options.max_entries,
+            max_bytes: options.max_bytes,
+            stale_window: options.stale_window,
+            stale_ttl: options.stale_ttl,
+            prefetch_min_hits: options.prefetch_min_hits,
+            prefetch_percent: options.prefetch_percent,
+            max_negative_ttl: options.max_negative_ttl,
+        }
+    }
+
+    /// Returns the cached answer for `name` and `record_type`, with TTLs reduced to the
+    /// time remaining, and whether it was validated, or `None` if it is missing or
+    /// expired.
+    pub fn get(&self, name: &LowerName, record_type: RecordType) -> Option<(Answer, bool)> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let entry = self.entry(&mut inner, name, record_type, now)?;
+        let valid_until = entry.lookup.valid_until();
+        if valid_until <= now {
+            return None;
+        }
+        entry.hits = entry.hits.saturating_add(1);
+        let remaining = (valid_until - now).as_secs() as u32;
+        Some((answer(entry, remaining, valid_until), entry.secure))
+    }
+
+    /// Returns the cached answer for `name` and `record_type` even if it has expired, as
+    /// long as it is within the stale window, with TTLs of at most `stale_ttl`, and
+    /// whether it was validated.
+    pub fn get_stale(&self, name: &LowerName, record_type: RecordType) -> Option<(Answer, bool)> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let entry = self.entry(&mut inner, name, record_type, now)?;
+        Some((answer(entry, self.stale_ttl, now + Duration::from_secs(self.stale_ttl.into())), entry.secure))
+    }
+
+    /// Claims the entry for `name` and `record_type` for prefetching if it has been hit
+    /// often enough and is about to expire, returning whether it was claimed. An entry
+    /// is claimed at most once.
+    pub fn claim_prefetch(&self, name: &LowerName, record_type: RecordType) -> bool {
+        if self.prefetch_min_hits == 0 {
+            return false;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let Some(entry) = self.entry(&mut inner, name, record_type, now) else {
+            return false;
+        };
+        let valid_until = entry.lookup.valid_until();
+        if entry.prefetching || entry.hits < self.prefetch_min_hits || valid_until <= now {
+            return false;
+        }
+        let lifetime = valid_until.saturating_duration_since(entry.inserted);
+        if valid_until - now > lifetime * self.prefetch_percent / 100 {
+            return false;
+        }
+        entry.prefetching = true;
+        true
+    }
+
+    /// The entry for `name` and `record_type`, removed instead once it is past the stale
+    /// window.
+    fn entry<'a>(&self, inner: &'a mut Inner, name: &LowerName, record_type: RecordType, now: Instant) -> Option<&'a mut Entry> {
+        let key = (name.clone(), record_type);
+        let entry = inner.entries.get_mut(&key)?;
+        if entry.lookup.valid_until() + self.stale_window <= now {
+            inner.remove(&key);
+            return None;
+        }
+        inner.entries.get_mut(&key)
+    }
+
+    /// Caches `answer` for `name` and `record_type`, `secure` if it was validated,
+    /// evicting older entries as needed. Negative answers without a TTL aren't cached.
+    pub fn insert(&self, name: &LowerName, record_type: RecordType, answer: Answer, secure: bool) {
+        let (lookup, negative) = match answer {
+            Answer::Records(lookup) => (lookup, None),
+            Answer::Negative(negative, Some(ttl)) if ttl > 0 && self.max_negative_ttl > 0 => {
+                let ttl = ttl.min(self.max_negative_ttl);
+                let query = Query::query(name.into(), record_type);
+                let lookup = Lookup::new_with_deadline(query, Arc::new([]), Instant::now() + Duration::from_secs(ttl.into()));
+                (lookup, Some(negative))
+            }
+            Answer::Negative(..) => return,
+        };
+        let size = ENTRY_OVERHEAD
+            + name.len()
+            + lookup
+                .records()
+                .iter()
+                .map(|record| record.to_bytes().map_or(0, |bytes| bytes.len()))
+                .sum::<usize>();
+        if size > self.max_bytes || self.max_entries == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let entry = Entry { lookup, negative, size, secure, inserted: Instant::now(), hits: 0, prefetching: false };
+        if let Some(old) = inner.entries.insert((name.clone(), record_type), entry) {
+            inner.forget(&old);
+        }
+        inner.bytes += size;
+        if negative.is_some() {
+            inner.negative += 1;
+        }
+        while inner.entries.len() > self.max_entries || inner.bytes > self.max_bytes {
+            let Some((_, evicted)) = inner.entries.remove_lru() else {
+                break;
+            };
+            inner.forget(&evicted);
+        }
+    }
+
+    /// How many entries are cached, negative ones among them, and the estimated bytes
+    /// they take up.
+    pub fn usage(&self) -> CacheUsage {
+        let inner = self.inner.lock().unwrap();
+        CacheUsage {
+            entries: inner.entries.len(),
+            negative: inner.negative,
+            bytes: inner.bytes,
+        }
+    }
+
+    /// Removes every entry, returning how many were cached.
+    pub fn flush(&self) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let count = inner.entries.len();
+        inner.entries.clear();
+        inner.bytes = 0;
+        inner.negative = 0;
+        count
+    }
+
+    /// Removes the entries for `name`, whatever their type, returning how many there were.
+    pub fn purge(&self, name: &LowerName) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let keys: Vec<(LowerName, RecordType)> = inner.entries.iter().map(|(key, _)| key).filter(|(owner, _)| owner == name).cloned().collect();
+        for key in &keys {
+            inner.remove(key);
+        }
+        keys.len()
+    }
+}
+
+/// The answer `entry` holds, with TTLs of at most `ttl`, valid until `valid_until`.
+fn answer(entry: &Entry, ttl: u32, valid_until: Instant) -> Answer {
+    match entry.negative {
+        Some(negative) => Answer::Negative(negative, Some(ttl)),
+        None => Answer::Records(with_ttl(&entry.lookup, ttl, valid_until)),
+    }
+}
+
+/// `lookup` with the TTLs of its records reduced to `ttl`, valid until `valid_until`.
+fn with_ttl(lookup: &Lookup, ttl: u32, valid_until: Instant) -> Lookup {
+    let records: Arc<[Record]> = lookup
+        .records()
+        .iter()
+        .map(|record| {
+            let mut record = record.clone();
+            record.set_ttl(record.ttl().min(ttl));
+            record
+        })
+        .collect();
+    Lookup::new_with_deadline(lookup.query().clone(), records, valid_until)
+}
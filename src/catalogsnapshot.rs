@@ -0,0 +1,203 @@
+//! Snapshots of the catalog that queries are answered from.
+//!
+//! `DnsState` registers the hosted zones, the catalog zone and the forwarder with
+//! `CatalogSnapshots`, which publishes an immutable `CatalogSnapshot` of them through a
+//! watch channel whenever they change. The request handler only clones the `Arc` of the
+//! latest snapshot to answer a query, so queries never wait on the `DnsState` lock that
+//! zone changes and record mutations hold.
+//!
+//! Record mutations change the records of a zone's `InMemoryAuthority` in place, and
+//! re-signing a DNSSEC zone holds its lock for as long as signing the whole zone takes,
+//! so the snapshots don't share the authorities of the hosted zones. They hold copies of
+//! their records instead, taken when `DnsState` reports a zone changed, which no write
+//! ever locks; a query sees a zone as of its latest change. The copies don't hold the
+//! zones' signing keys, which hickory only uses to sign ANAME answers on the fly.
+
+use hickory_proto::rr::rdata::NS;
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType, RrKey};
+use hickory_server::authority::{Authority, AuthorityObject, Catalog};
+use hickory_server::store::in_memory::InMemoryAuthority;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{watch, Mutex};
+
+use crate::delegation::Referral;
+
+/// The authorities serving queries at one point in time.
+pub struct CatalogSnapshot {
+    catalog: Catalog,
+    /// Copies of the hosted zones, looked into directly for referrals and minimal ANY
+    /// answers.
+    zones: HashMap<LowerName, Arc<InMemoryAuthority>>,
+}
+
+impl CatalogSnapshot {
+    /// The catalog queries are handed to.
+    pub fn catalog(&self) -> &Catalog {
+        &self.catalog
+    }
+
+    /// The hosted zone containing `name`, if any.
+    fn find_zone(&self, name: &LowerName) -> Option<&Arc<InMemoryAuthority>> {
+        let mut candidate = name.clone();
+        loop {
+            if let Some(authority) = self.zones.get(&candidate) {
+                return Some(authority);
+            }
+            if candidate.is_root() {
+                return None;
+            }
+            candidate = candidate.base_name();
+        }
+    }
+
+    /// The referral answering queries of `record_type` for `name`, when the name is at or
+    /// below a delegation of the zone containing it; see `delegation`.
+    pub async fn referral(&self, name: &LowerName, record_type: RecordType) -> Option<Referral> {
+        let authority = self.find_zone(name)?;
+        let origin = authority.origin();
+        let records = authority.records().await;
+        // The delegation closest to the apex takes everything below it
+        let cut = (origin.num_labels() + 1..=name.num_labels())
+            .map(|labels| LowerName::new(&Name::from(name).trim_to(labels as usize)))
+            .find(|candidate| records.contains_key(&RrKey::new(candidate.clone(), RecordType::NS)))?;
+        if cut == *name && record_type == RecordType::DS {
+            return None;
+        }
+        let delegation = records.get(&RrKey::new(cut.clone(), RecordType::NS))?;
+        let mut referral = Referral {
+            name_servers: delegation.records_without_rrsigs().cloned().collect(),
+            glue: Vec::new(),
+        };
+        if let Some(ds) = records.get(&RrKey::new(cut.clone(), RecordType::DS)) {
+            referral.name_servers.extend(ds.records_without_rrsigs().cloned());
+        }
+        for record in delegation.records_without_rrsigs() {
+            let Some(RData::NS(NS(target))) = record.data() else {
+                continue;
+            };
+            let target = LowerName::new(target);
+            if !origin.zone_of(&target) {
+                continue;
+            }
+            for record_type in [RecordType::A, RecordType::AAAA] {
+                if let Some(set) = records.get(&RrKey::new(target.clone(), record_type)) {
+                    referral.glue.extend(set.records_without_rrsigs().cloned());
+                }
+            }
+        }
+        Some(referral)
+    }
+
+    /// The records of a name and type, or of every type for ANY, from the zone containing
+    /// them; none when no zone holds the name.
+    pub async fn lookup_records(&self, name: &LowerName, record_type: RecordType) -> Vec<Record> {
+        let Some(authority) = self.find_zone(name) else {
+            return Vec::new();
+        };
+        let records = authority.records().await;
+        if record_type != RecordType::ANY {
+            let key = RrKey::new(name.clone(), record_type);
+            return records.get(&key).map(|set| set.records_without_rrsigs().cloned().collect()).unwrap_or_default();
+        }
+        records
+            .iter()
+            .filter(|(key, _)| key.name == *name)
+            .flat_map(|(_, set)| set.records_without_rrsigs().cloned())
+            .collect()
+    }
+}
+
+/// The authorities registered for serving, published as a new snapshot on every change.
+pub struct CatalogSnapshots {
+    /// Authorities other than the hosted zones, i.e. the forwarder.
+    authorities: HashMap<LowerName, Box<dyn AuthorityObject>>,
+    /// Copies of the hosted zones as of their latest change, locked while one is replaced
+    /// so that copies taken one after the other are published in that order.
+    zones: Mutex<HashMap<LowerName, Arc<InMemoryAuthority>>>,
+    current: watch::Sender<Arc<CatalogSnapshot>>,
+}
+
+impl Default for CatalogSnapshots {
+    fn default() -> Self {
+        let empty = CatalogSnapshot {
+            catalog: Catalog::new(),
+            zones: HashMap::new(),
+        };
+        CatalogSnapshots {
+            authorities: HashMap::new(),
+            zones: Mutex::new(HashMap::new()),
+            current: watch::channel(Arc::new(empty)).0,
+        }
+    }
+}
+
+impl CatalogSnapshots {
+    /// Registers `authority` for `origin`, replacing whatever served it before.
+    pub async fn upsert(&mut self, origin: LowerName, authority: Box<dyn AuthorityObject>) {
+        let mut zones = self.zones.lock().await;
+        zones.remove(&origin);
+        self.authorities.insert(origin, authority);
+        self.publish(&zones);
+    }
+
+    /// Registers a copy of the hosted zone `authority` for `origin`, replacing whatever
+    /// served it before.
+    pub async fn upsert_zone(&mut self, origin: LowerName, authority: &InMemoryAuthority) {
+        let mut zones = self.zones.lock().await;
+        zones.insert(origin.clone(), freeze(authority).await);
+        self.authorities.remove(&origin);
+        self.publish(&zones);
+    }
+
+    /// Serves a new copy of the hosted zone `authority` after its records changed, unless
+    /// it is no longer registered.
+    pub async fn refresh(&self, authority: &InMemoryAuthority) {
+        let mut zones = self.zones.lock().await;
+        if !zones.contains_key(authority.origin()) {
+            return;
+        }
+        zones.insert(authority.origin().clone(), freeze(authority).await);
+        self.publish(&zones);
+    }
+
+    /// Stops serving the authorities of `origins`.
+    pub async fn remove<'a>(&mut self, origins: impl IntoIterator<Item = &'a LowerName>) {
+        let mut zones = self.zones.lock().await;
+        for origin in origins {
+            self.authorities.remove(origin);
+            zones.remove(origin);
+        }
+        self.publish(&zones);
+    }
+
+    /// The latest snapshot.
+    pub fn current(&self) -> Arc<CatalogSnapshot> {
+        self.current.borrow().clone()
+    }
+
+    /// Receives every snapshot published from now on, the latest one first.
+    pub fn subscribe(&self) -> watch::Receiver<Arc<CatalogSnapshot>> {
+        self.current.subscribe()
+    }
+
+    fn publish(&self, zones: &HashMap<LowerName, Arc<InMemoryAuthority>>) {
+        let mut catalog = Catalog::new();
+        for (origin, authority) in &self.authorities {
+            catalog.upsert(origin.clone(), authority.box_clone());
+        }
+        for (origin, authority) in zones {
+            catalog.upsert(origin.clone(), Box::new(authority.clone()));
+        }
+        let zones = zones.clone();
+        self.current.send_replace(Arc::new(CatalogSnapshot { catalog, zones }));
+    }
+}
+
+/// A copy of the records `authority` holds, which queries can read while the zone is
+/// written.
+async fn freeze(authority: &InMemoryAuthority) -> Arc<InMemoryAuthority> {
+    let mut frozen = InMemoryAuthority::empty(authority.origin().into(), authority.zone_type(), authority.is_axfr_allowed());
+    *frozen.records_get_mut() = authority.records().await;
+    Arc::new(frozen)
+}
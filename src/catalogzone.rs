@@ -0,0 +1,132 @@
+//! Catalog zones (RFC 9432).
+//!
+//! With `[dns.catalog_zone]` configured, rdns serves a zone at its `name` listing every
+//! primary zone it hosts, so that secondaries such as BIND or Knot, set up to consume it,
+//! provision a zone as soon as it is created through the APIs and drop it once deleted.
+//! Each member zone is listed as `<id>.zones.<catalog> PTR <zone>`, under an id derived
+//! from the zone's name that stays the same across restarts and instances. The serial of
+//! the catalog zone is incremented and its secondaries are notified whenever the member
+//! zones change. It is transferred like the hosted zones, to the configured secondaries,
+//! but isn't persisted or changed through the APIs itself. Secondary zones aren't listed,
+//! as they are provisioned from their own primary.
+
+use hickory_proto::rr::rdata::{NS, PTR, SOA, TXT};
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordSet, RrKey};
+use hickory_server::authority::{Authority, ZoneType};
+use hickory_server::store::in_memory::InMemoryAuthority;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::settings::CatalogZoneSettings;
+use crate::soa;
+
+/// Schema version of the catalog zone, published in its `version` TXT record.
+const SCHEMA_VERSION: &str = "2";
+
+/// Config options for the catalog zone
+#[derive(Clone)]
+pub struct CatalogZoneOptions {
+    pub origin: Name,
+}
+
+impl TryFrom<CatalogZoneSettings> for CatalogZoneOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: CatalogZoneSettings) -> anyhow::Result<Self> {
+        let mut origin = Name::from_str(&cfg.name).map_err(|e| anyhow::anyhow!("invalid dns.catalog_zone.name {}: {}", cfg.name, e))?;
+        origin.set_fqdn(true);
+        if origin.is_root() {
+            anyhow::bail!("dns.catalog_zone.name can't be the root zone");
+        }
+        Ok(CatalogZoneOptions { origin })
+    }
+}
+
+/// The catalog zone and the member zones it lists.
+pub struct CatalogZone {
+    authority: Arc<InMemoryAuthority>,
+    members: BTreeSet<LowerName>,
+    serial: u32,
+}
+
+impl CatalogZone {
+    /// Creates the catalog zone without members; it is transferable when `allow_axfr`.
+    pub async fn new(options: &CatalogZoneOptions, allow_axfr: bool) -> Self {
+        let zone = CatalogZone {
+            authority: Arc::new(InMemoryAuthority::empty(options.origin.clone(), ZoneType::Primary, allow_axfr)),
+            members: BTreeSet::new(),
+            serial: soa::initial_serial(),
+        };
+        zone.write_records().await;
+        zone
+    }
+
+    /// The authority serving the catalog zone.
+    pub fn authority(&self) -> &Arc<InMemoryAuthority> {
+        &self.authority
+    }
+
+    pub fn origin(&self) -> &LowerName {
+        self.authority.origin()
+    }
+
+    /// Replaces the member zones, incrementing the serial when they changed. Returns
+    /// whether they did.
+    pub async fn set_members(&mut self, members: BTreeSet<LowerName>) -> bool {
+        if members == self.members {
+            return false;
+        }
+        self.members = members;
+        self.serial = self.serial.wrapping_add(1);
+        self.write_records().await;
+        true
+    }
+
+    /// Replaces the records of the zone with those listing the current members.
+    async fn write_records(&self) {
+        let origin = Name::from(self.origin());
+        let invalid = Name::from_ascii("invalid.").expect("invalid. is a valid name");
+        let soa = SOA::new(invalid.clone(), invalid.clone(), self.serial, 3600, 600, 2_419_200, 0);
+        let mut generated = vec![
+            Record::from_rdata(origin.clone(), 0, RData::SOA(soa)),
+            Record::from_rdata(origin.clone(), 0, RData::NS(NS(invalid))),
+            Record::from_rdata(child(&origin, "version"), 0, RData::TXT(TXT::new(vec![SCHEMA_VERSION.into()]))),
+        ];
+        let zones = child(&origin, "zones");
+        for member in &self.members {
+            let name = child(&zones, &member_id(member));
+            generated.push(Record::from_rdata(name, 0, RData::PTR(PTR(Name::from(member)))));
+        }
+
+        let mut records = self.authority.records_mut().await;
+        records.clear();
+        for record in generated {
+            let set = records
+                .entry(RrKey::new(LowerName::new(record.name()), record.record_type()))
+                .or_insert_with(|| Arc::new(RecordSet::new(record.name(), record.record_type(), 0)));
+            Arc::make_mut(set).insert(record, 0);
+        }
+    }
+}
+
+/// `label` prepended to `name`.
+fn child(name: &Name, label: &str) -> Name {
+    Name::from_ascii(label)
+        .and_then(|label| label.append_domain(name))
+        .expect("catalog zone labels are valid")
+}
+
+/// The id of the member zone at `origin`: the 64-bit FNV-1a hash of its name in wire
+/// format, in hex, which doesn't change between releases as that of the standard
+/// library's hasher may.
+fn member_id(origin: &LowerName) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for label in Name::from(origin).iter() {
+        for &byte in std::iter::once(&(label.len() as u8)).chain(label) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
@@ -0,0 +1,292 @@
+//! Identification of client devices, for per-device policies on LAN networks.
+//!
+//! On home and small office networks, addresses come from DHCP and change, so policies
+//! bound to them, like views, drift from the devices they were meant for. Under
+//! `[dns.clients]`, devices are named and recognized by what stays the same instead:
+//!
+//! - the client ID of an EDNS option, `id_option`, 65074 by default as dnsmasq sends with
+//!   `--add-cpe-id`
+//! - the MAC address of an EDNS option, `mac_option`, 65001 by default as dnsmasq sends
+//!   with `--add-mac`, in binary, text or base64 form
+//! - with `neighbor_table` set, the MAC address the ARP or NDP neighbor table of the host
+//!   has for the client's address, for clients on a network the host is attached to.
+//!   The table is read again every `neighbor_refresh_secs`, in the background, so a
+//!   device that just joined may not be recognized by its first queries
+//!
+//! Any client can put options in its queries, so EDNS options are only taken from the
+//! `edns_from` networks, e.g. the router forwarding with dnsmasq, when set. The first
+//! device with the client ID or MAC address a query carries, in the order they are
+//! configured, is the client's; the client ID is checked first. A device may then:
+//!
+//! - be answered from a view of its own, whatever its address, e.g. one with safe search
+//!   names for a child's tablet
+//! - have query rules of its own, in the line format of rule files (see `queryrules`),
+//!   tried before the others by the `rules` stage, e.g. `suffix videos.example.com`
+//! - opt out of query logging with `log = false`: its queries are left out of dnstap
+//!   output, the debug log, `WatchQueries`, query statistics and traces, though still
+//!   counted in the metrics
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use hickory_proto::rr::LowerName;
+use hickory_server::server::Request;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::queryrules::{self, QueryRule};
+use crate::settings::{ClientSettings, DeviceSettings, ViewSettings};
+use crate::views::ClientMatch;
+
+/// A MAC address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Mac([u8; 6]);
+
+impl FromStr for Mac {
+    type Err = anyhow::Error;
+
+    /// Parses six hex octets separated by colons or dashes, e.g. `aa:bb:cc:dd:ee:ff`.
+    fn from_str(mac: &str) -> anyhow::Result<Self> {
+        let octets: Vec<&str> = mac.split([':', '-']).collect();
+        let mut parsed = [0; 6];
+        if octets.len() != parsed.len() {
+            anyhow::bail!("invalid MAC address {}, expected six octets like aa:bb:cc:dd:ee:ff", mac);
+        }
+        for (octet, text) in parsed.iter_mut().zip(octets) {
+            *octet = u8::from_str_radix(text, 16).map_err(|_| anyhow::anyhow!("invalid MAC address {}", mac))?;
+        }
+        Ok(Mac(parsed))
+    }
+}
+
+impl fmt::Display for Mac {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+impl Mac {
+    /// Reads the MAC address of an EDNS option, as dnsmasq sends it in binary, text or
+    /// base64 form.
+    fn from_option(data: &[u8]) -> Option<Self> {
+        if let Ok(octets) = <[u8; 6]>::try_from(data) {
+            return Some(Mac(octets));
+        }
+        let text = std::str::from_utf8(data).ok()?.trim();
+        if let Ok(mac) = text.parse() {
+            return Some(mac);
+        }
+        <[u8; 6]>::try_from(STANDARD.decode(text).ok()?).ok().map(Mac)
+    }
+}
+
+/// A named client device and its policies.
+#[derive(Debug)]
+pub struct Device {
+    pub name: String,
+    /// MAC addresses the device is recognized by.
+    macs: Vec<Mac>,
+    /// Client IDs the device is recognized by.
+    ids: Vec<String>,
+    /// View the device is answered from, rather than that of its address.
+    pub view: Option<String>,
+    /// Query rules of the device, tried before the others.
+    rules: Vec<QueryRule>,
+    /// Whether the device's queries are logged.
+    pub log: bool,
+}
+
+impl TryFrom<DeviceSettings> for Device {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: DeviceSettings) -> anyhow::Result<Self> {
+        if cfg.macs.is_empty() && cfg.ids.is_empty() {
+            anyhow::bail!("device {} has neither MAC addresses nor client IDs to be recognized by", cfg.name);
+        }
+        let macs = cfg.macs.iter().map(|mac| mac.parse()).collect::<anyhow::Result<_>>()?;
+        let source = format!("dns.clients.devices {} rules", cfg.name);
+        let rules = queryrules::parse_file(&cfg.rules.join("\n"), &source)?
+            .into_iter()
+            .map(QueryRule::new)
+            .collect::<Result<_, _>>()?;
+        Ok(Device {
+            name: cfg.name,
+            macs,
+            ids: cfg.ids,
+            view: cfg.view.filter(|view| !view.is_empty()),
+            rules,
+            log: cfg.log,
+        })
+    }
+}
+
+impl Device {
+    /// The first of the device's query rules matching `name`.
+    pub fn rule_for(&self, name: &LowerName) -> Option<&QueryRule> {
+        self.rules.iter().find(|rule| rule.matches(name))
+    }
+}
+
+/// Config options for identifying client devices
+#[derive(Debug)]
+pub struct ClientOptions {
+    /// EDNS option carrying the client's MAC address.
+    pub mac_option: u16,
+    /// EDNS option carrying the client's ID.
+    pub id_option: u16,
+    /// Clients whose EDNS options are taken; every client's are when empty.
+    pub edns_from: ClientMatch,
+    /// MAC addresses of the clients on attached networks, if looked up.
+    pub neighbors: Option<NeighborTable>,
+    /// Devices, in the order they are matched.
+    pub devices: Vec<Device>,
+}
+
+impl TryFrom<ClientSettings> for ClientOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ClientSettings) -> anyhow::Result<Self> {
+        let mut names = std::collections::HashSet::new();
+        if let Some(device) = cfg.devices.iter().find(|device| !names.insert(device.name.as_str())) {
+            anyhow::bail!("dns.clients lists device {} more than once", device.name);
+        }
+        if cfg.neighbor_table && cfg.neighbor_refresh_secs == 0 {
+            anyhow::bail!("dns.clients.neighbor_refresh_secs must be at least 1");
+        }
+        Ok(ClientOptions {
+            mac_option: cfg.mac_option,
+            id_option: cfg.id_option,
+            edns_from: ClientMatch::parse(&cfg.edns_from)?,
+            neighbors: cfg
+                .neighbor_table
+                .then(|| NeighborTable::new(Duration::from_secs(cfg.neighbor_refresh_secs))),
+            devices: cfg.devices.into_iter().map(Device::try_from).collect::<anyhow::Result<_>>()?,
+        })
+    }
+}
+
+impl ClientOptions {
+    /// The device the client of `request` is, if it is recognized.
+    pub fn identify(&self, request: &Request) -> Option<&Device> {
+        let client = request.src().ip();
+        let edns = match self.edns_from.is_empty() || self.edns_from.contains(client) {
+            true => request.edns(),
+            false => None,
+        };
+        let option = |code: u16| match edns?.options().get(EdnsCode::from(code)) {
+            Some(EdnsOption::Unknown(_, data)) => Some(data.as_slice()),
+            _ => None,
+        };
+        if let Some(id) = option(self.id_option).and_then(|data| std::str::from_utf8(data).ok()) {
+            let id = id.trim();
+            if let Some(device) = self.devices.iter().find(|device| device.ids.iter().any(|known| known == id)) {
+                return Some(device);
+            }
+        }
+        let mac = option(self.mac_option)
+            .and_then(Mac::from_option)
+            .or_else(|| self.neighbors.as_ref()?.lookup(client))?;
+        self.devices.iter().find(|device| device.macs.contains(&mac))
+    }
+}
+
+/// Checks that the devices of `clients` are answered from views that exist.
+pub fn check_views(clients: &ClientSettings, views: &[ViewSettings]) -> anyhow::Result<()> {
+    for device in &clients.devices {
+        if let Some(view) = device.view.as_deref().filter(|view| !view.is_empty()) {
+            if !views.iter().any(|known| known.name == view) {
+                anyhow::bail!("device {} is answered from view {}, which isn't configured", device.name, view);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The MAC addresses the neighbor table of the host has for client addresses, read again
+/// in the background once they are older than the refresh interval.
+#[derive(Debug)]
+pub struct NeighborTable {
+    refresh: Duration,
+    entries: Arc<RwLock<HashMap<IpAddr, Mac>>>,
+    read_at: Arc<Mutex<Option<Instant>>>,
+    reading: Arc<AtomicBool>,
+}
+
+impl NeighborTable {
+    fn new(refresh: Duration) -> Self {
+        NeighborTable {
+            refresh,
+            entries: Arc::default(),
+            read_at: Arc::default(),
+            reading: Arc::default(),
+        }
+    }
+
+    /// The MAC address of the client at `address`, as of the last reading of the table.
+    fn lookup(&self, address: IpAddr) -> Option<Mac> {
+        // IPv4 clients of dual-stack sockets arrive as mapped IPv6 addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            v4 => v4,
+        };
+        let stale = self.read_at.lock().unwrap().is_none_or(|read_at| read_at.elapsed() >= self.refresh);
+        if stale && !self.reading.swap(true, Ordering::AcqRel) {
+            let (entries, read_at, reading) = (self.entries.clone(), self.read_at.clone(), self.reading.clone());
+            tokio::task::spawn_blocking(move || {
+                match read_neighbors() {
+                    Ok(neighbors) => *entries.write().unwrap() = neighbors,
+                    Err(e) => eprintln!("Failed to read the neighbor table: {}", e),
+                }
+                *read_at.lock().unwrap() = Some(Instant::now());
+                reading.store(false, Ordering::Release);
+            });
+        }
+        self.entries.read().unwrap().get(&address).copied()
+    }
+}
+
+/// Reads the neighbor table of both families with `ip neigh`, or the IPv4 ARP table of
+/// `/proc/net/arp` where `ip` isn't installed.
+fn read_neighbors() -> anyhow::Result<HashMap<IpAddr, Mac>> {
+    let output = match std::process::Command::new("ip").args(["neigh", "show"]).output() {
+        Ok(output) if output.status.success() => output,
+        _ => return read_arp_table(),
+    };
+    // e.g. "192.0.2.10 dev eth0 lladdr aa:bb:cc:dd:ee:ff REACHABLE"; unresolved entries
+    // have no lladdr
+    let neighbors = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let address = fields.first()?.parse().ok()?;
+            let mac = fields.iter().position(|field| *field == "lladdr").and_then(|i| fields.get(i + 1))?;
+            Some((address, mac.parse().ok()?))
+        })
+        .collect();
+    Ok(neighbors)
+}
+
+/// Reads the IPv4 ARP table of `/proc/net/arp`.
+fn read_arp_table() -> anyhow::Result<HashMap<IpAddr, Mac>> {
+    let table = std::fs::read_to_string("/proc/net/arp")?;
+    // Columns: IP address, HW type, flags, HW address, mask, device; a flag of 0x0
+    // marks an unresolved entry
+    let neighbors = table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(2) == Some(&"0x0") {
+                return None;
+            }
+            Some((fields.first()?.parse().ok()?, fields.get(3)?.parse().ok()?))
+        })
+        .collect();
+    Ok(neighbors)
+}
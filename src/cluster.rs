@@ -0,0 +1,376 @@
+//! Leader/follower replication between instances.
+//!
+//! With `[cluster]` configured, one instance is the leader, which takes every change, and
+//! the others follow it over the control API: a follower calls `Replicate` on the leader
+//! and gets a full snapshot of its zones and runtime state, then one event per change the
+//! leader makes, carrying the zone (or the blocklist) the change touched as it is
+//! afterwards, and a heartbeat in between. Followers answer queries from what they have
+//! replicated and reject changes of their own with `NOT_LEADER`; one that falls too far
+//! behind is sent a full snapshot again. A follower that loses the leader keeps serving
+//! its last state and reconnects; failing over to a follower is a manual config change.
+//!
+//! There is no consensus between the instances: a change is acknowledged once the leader
+//! has applied and persisted it, before any follower has it, so a change made just before
+//! the leader is lost may be missing from the follower promoted in its place. Committing
+//! changes to a replicated log first (e.g. with Raft) would need a consensus
+//! implementation, membership management and a log store, none of which rdns has yet.
+
+use hickory_proto::rr::{LowerName, Name};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, mpsc, RwLock};
+use tonic::metadata::AsciiMetadataValue;
+use tonic::service::Interceptor;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint};
+use tonic::Status;
+
+use crate::control::dns_control_client::DnsControlClient;
+use crate::control::replication_event::Scope;
+use crate::control::{Empty, ReplicationEvent};
+use crate::dns::{ChangeScope, DnsState};
+use crate::persist::Snapshot;
+use crate::settings::ClusterSettings;
+use crate::shutdown::Shutdown;
+
+/// Delay before a follower reconnects to the leader.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Heartbeats a follower may miss before it reconnects.
+const MISSED_HEARTBEATS: u32 = 3;
+/// Events buffered per follower ahead of its connection.
+const STREAM_BUFFER: usize = 16;
+
+/// The part an instance plays in the cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClusterRole {
+    Leader,
+    /// Follows the leader at the control API URL.
+    Follower { leader: String },
+}
+
+/// Config options for replication
+#[derive(Debug, Clone)]
+pub struct ClusterOptions {
+    pub role: ClusterRole,
+    /// Admin token a follower presents to the leader.
+    pub token: Option<String>,
+    /// CA the leader's certificate is signed by.
+    pub ca_path: Option<PathBuf>,
+    pub heartbeat: Duration,
+}
+
+impl TryFrom<ClusterSettings> for ClusterOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ClusterSettings) -> anyhow::Result<Self> {
+        let role = match (cfg.role.to_ascii_lowercase().as_str(), cfg.leader) {
+            ("leader", None) => ClusterRole::Leader,
+            ("leader", Some(_)) => anyhow::bail!("cluster.leader can only be set on a follower"),
+            ("follower", Some(leader)) => ClusterRole::Follower { leader },
+            ("follower", None) => anyhow::bail!("cluster.leader must be set on a follower"),
+            (role, _) => anyhow::bail!("unknown cluster role {}, expected leader or follower", role),
+        };
+        if cfg.heartbeat_secs == 0 {
+            anyhow::bail!("cluster.heartbeat_secs must be at least 1");
+        }
+        Ok(ClusterOptions {
+            role,
+            token: cfg.token,
+            ca_path: cfg.ca_path.map(PathBuf::from),
+            heartbeat: Duration::from_secs(cfg.heartbeat_secs),
+        })
+    }
+}
+
+/// A follower streaming changes from this leader.
+pub struct FollowerLink {
+    pub peer: String,
+    pub connected_since: SystemTime,
+    /// Sequence number of the latest change sent to it.
+    pub sent_sequence: u64,
+}
+
+/// How far a follower has caught up with its leader.
+#[derive(Debug, Clone, Default)]
+pub struct SyncStatus {
+    pub connected: bool,
+    /// Latest sequence number the leader reported.
+    pub leader_sequence: u64,
+    /// Sequence number of the latest change applied.
+    pub applied_sequence: u64,
+    /// When the leader was last heard from.
+    pub last_contact: Option<SystemTime>,
+    /// When the follower last had every change the leader reported.
+    pub synced_at: Option<SystemTime>,
+    /// Why the last connection to the leader failed.
+    pub error: Option<String>,
+}
+
+impl SyncStatus {
+    /// How long the follower has gone without all of the leader's changes, zero while it
+    /// is connected and has them.
+    pub fn lag(&self) -> Duration {
+        if self.connected && self.applied_sequence >= self.leader_sequence {
+            return Duration::ZERO;
+        }
+        self.synced_at
+            .and_then(|synced_at| synced_at.elapsed().ok())
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Replication state of this instance, reported by `ClusterStatus`.
+pub struct Cluster {
+    pub options: ClusterOptions,
+    next_link: AtomicU64,
+    /// Followers connected to a leader, by link id.
+    followers: Mutex<HashMap<u64, FollowerLink>>,
+    /// A follower's link to its leader.
+    sync: Mutex<SyncStatus>,
+}
+
+impl Cluster {
+    pub fn new(options: ClusterOptions) -> Self {
+        Self {
+            options,
+            next_link: AtomicU64::new(1),
+            followers: Mutex::new(HashMap::new()),
+            sync: Mutex::new(SyncStatus::default()),
+        }
+    }
+
+    /// The leader a follower replicates from, or `None` on the leader.
+    pub fn leader(&self) -> Option<&str> {
+        match &self.options.role {
+            ClusterRole::Leader => None,
+            ClusterRole::Follower { leader } => Some(leader),
+        }
+    }
+
+    /// The followers connected to this leader, longest connected first.
+    pub fn followers(&self) -> Vec<FollowerLink> {
+        let followers = self.followers.lock().unwrap();
+        let mut links: Vec<FollowerLink> = followers
+            .values()
+            .map(|link| FollowerLink {
+                peer: link.peer.clone(),
+                connected_since: link.connected_since,
+                sent_sequence: link.sent_sequence,
+            })
+            .collect();
+        links.sort_by_key(|link| link.connected_since);
+        links
+    }
+
+    /// How far this follower has caught up with its leader.
+    pub fn sync_status(&self) -> SyncStatus {
+        self.sync.lock().unwrap().clone()
+    }
+
+    fn update_sync(&self, update: impl FnOnce(&mut SyncStatus)) {
+        let mut sync = self.sync.lock().unwrap();
+        update(&mut sync);
+        if sync.connected {
+            sync.last_contact = Some(SystemTime::now());
+            if sync.applied_sequence >= sync.leader_sequence {
+                sync.synced_at = sync.last_contact;
+            }
+        }
+    }
+}
+
+/// Streams the changes of `state` to a follower at `peer`, starting with a full snapshot,
+/// until the follower disconnects or the server shuts down.
+pub fn stream_changes(
+    state: Arc<RwLock<DnsState>>,
+    cluster: Arc<Cluster>,
+    peer: String,
+    shutdown: Shutdown,
+) -> mpsc::Receiver<Result<ReplicationEvent, Status>> {
+    let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+    tokio::spawn(async move {
+        let id = cluster.next_link.fetch_add(1, Ordering::Relaxed);
+        cluster.followers.lock().unwrap().insert(
+            id,
+            FollowerLink {
+                peer: peer.clone(),
+                connected_since: SystemTime::now(),
+                sent_sequence: 0,
+            },
+        );
+        println!("Follower {} connected", peer);
+
+        let (mut event, mut changes) = {
+            let state = state.read().await;
+            // Changes published after subscribing are sent again even if the snapshot has them
+            let (sequence, changes) = state.subscribe_changes();
+            (full_sync(&state, sequence).await, changes)
+        };
+        let mut heartbeat = tokio::time::interval(cluster.options.heartbeat);
+        heartbeat.tick().await;
+        let mut stopped = pin!(shutdown.requested());
+        loop {
+            let sequence = event.as_ref().map_or(0, |event| event.sequence);
+            if sender.send(event).await.is_err() {
+                break;
+            }
+            if let Some(link) = cluster.followers.lock().unwrap().get_mut(&id) {
+                link.sent_sequence = sequence;
+            }
+            event = tokio::select! {
+                change = changes.recv() => {
+                    let state = state.read().await;
+                    match change {
+                        Ok(change) => replication_event(&state, change.sequence, change.timestamp, &change.scope).await,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => {
+                            eprintln!("Follower {} fell {} changes behind, sending a full snapshot", peer, missed);
+                            full_sync(&state, state.sequence()).await
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+                _ = heartbeat.tick() => Ok(ReplicationEvent {
+                    sequence: state.read().await.sequence(),
+                    timestamp: Some(SystemTime::now().into()),
+                    scope: Scope::Heartbeat.into(),
+                    ..Default::default()
+                }),
+                _ = &mut stopped => break,
+                _ = sender.closed() => break,
+            };
+        }
+
+        cluster.followers.lock().unwrap().remove(&id);
+        println!("Follower {} disconnected", peer);
+    });
+    receiver
+}
+
+/// A snapshot of the whole state, as the event that starts a follower off.
+async fn full_sync(state: &DnsState, sequence: u64) -> Result<ReplicationEvent, Status> {
+    replication_event(state, sequence, SystemTime::now(), &ChangeScope::All).await
+}
+
+/// The event replicating the change to `scope` with `sequence`.
+async fn replication_event(
+    state: &DnsState,
+    sequence: u64,
+    timestamp: SystemTime,
+    scope: &ChangeScope,
+) -> Result<ReplicationEvent, Status> {
+    let snapshot = state.replication_snapshot(scope).await;
+    let (scope, origin) = match scope {
+        ChangeScope::All => (Scope::Full, String::new()),
+        ChangeScope::Zone(origin) => (Scope::Zone, origin.to_string()),
+        ChangeScope::ZoneDeleted(origin) => (Scope::ZoneDeleted, origin.to_string()),
+        ChangeScope::Blocklist => (Scope::Blocklist, String::new()),
+    };
+    Ok(ReplicationEvent {
+        sequence,
+        timestamp: Some(timestamp.into()),
+        scope: scope.into(),
+        origin,
+        snapshot: snapshot.encode().map_err(|e| Status::internal(e.to_string()))?,
+    })
+}
+
+/// Keeps a follower in sync with its leader, reconnecting whenever the stream of changes
+/// breaks. Runs until the process exits.
+pub async fn run_follower(state: Arc<RwLock<DnsState>>, cluster: Arc<Cluster>) {
+    let Some(leader) = cluster.leader().map(str::to_string) else {
+        return;
+    };
+    loop {
+        let error = match follow(&state, &cluster, &leader).await {
+            Ok(()) => "the leader closed the stream".to_string(),
+            Err(e) => e.to_string(),
+        };
+        eprintln!("Lost replication from {}: {}", leader, error);
+        cluster.update_sync(|sync| {
+            sync.connected = false;
+            sync.error = Some(error);
+        });
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connects to the leader and applies the changes it streams, until the stream ends.
+async fn follow(state: &RwLock<DnsState>, cluster: &Cluster, leader: &str) -> anyhow::Result<()> {
+    let mut endpoint = Endpoint::from_shared(leader.to_string())?;
+    if leader.starts_with("https://") || cluster.options.ca_path.is_some() {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &cluster.options.ca_path {
+            config = config.ca_certificate(Certificate::from_pem(tokio::fs::read(ca).await?));
+        }
+        endpoint = endpoint.tls_config(config)?;
+    }
+    let channel = endpoint.connect().await?;
+    let authorization: Option<AsciiMetadataValue> = cluster
+        .options
+        .token
+        .as_ref()
+        .map(|token| format!("Bearer {}", token).parse())
+        .transpose()?;
+    let mut client = DnsControlClient::with_interceptor(channel, TokenInterceptor(authorization))
+        // A full snapshot holds every zone
+    .max_decoding_message_size(usize::MAX);
+
+    let mut stream = client.replicate(Empty {}).await?.into_inner();
+    println!("Replicating from {}", leader);
+    // The leader's sequence numbers start over when it restarts without a journal
+    cluster.update_sync(|sync| {
+        sync.connected = true;
+        sync.leader_sequence = 0;
+        sync.applied_sequence = 0;
+        sync.error = None;
+    });
+    let timeout = cluster.options.heartbeat * MISSED_HEARTBEATS;
+    loop {
+        let Some(event) = tokio::time::timeout(timeout, stream.message())
+            .await
+            .map_err(|_| anyhow::anyhow!("no heartbeat for {}s", timeout.as_secs()))??
+        else {
+            return Ok(());
+        };
+        let sequence = event.sequence;
+        if event.scope() != Scope::Heartbeat {
+            apply(state, event).await?;
+            cluster.update_sync(|sync| {
+                sync.applied_sequence = sequence;
+                sync.leader_sequence = sync.leader_sequence.max(sequence);
+            });
+        } else {
+            cluster.update_sync(|sync| sync.leader_sequence = sequence);
+        }
+    }
+}
+
+/// Attaches the follower's bearer token, if any, to its requests.
+struct TokenInterceptor(Option<AsciiMetadataValue>);
+
+impl Interceptor for TokenInterceptor {
+    fn call(&mut self, mut request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
+        if let Some(authorization) = &self.0 {
+            request.metadata_mut().insert("authorization", authorization.clone());
+        }
+        Ok(request)
+    }
+}
+
+/// Applies a change streamed from the leader to the state.
+async fn apply(state: &RwLock<DnsState>, event: ReplicationEvent) -> anyhow::Result<()> {
+    let origin = || -> anyhow::Result<LowerName> { Ok(LowerName::new(&Name::from_ascii(&event.origin)?)) };
+    let scope = match event.scope() {
+        Scope::Full => ChangeScope::All,
+        Scope::Zone => ChangeScope::Zone(origin()?),
+        Scope::ZoneDeleted => ChangeScope::ZoneDeleted(origin()?),
+        Scope::Blocklist => ChangeScope::Blocklist,
+        Scope::Heartbeat => return Ok(()),
+    };
+    let snapshot = Snapshot::decode(&event.snapshot)?;
+    state.write().await.apply_replicated(&scope, snapshot).await?;
+    Ok(())
+}
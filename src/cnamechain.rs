@@ -0,0 +1,165 @@
+//! CNAME chains in authoritative answers.
+//!
+//! A query for a name with a CNAME is answered with the CNAME and, when its target is
+//! hosted too, with the target's records of the queried type, so that clients don't have
+//! to query the target themselves. The chain is followed through every hosted zone, not
+//! only the one of the queried name: through the CNAMEs of the targets in turn, until a
+//! target has records of the queried type, isn't hosted, is delegated, or the chain holds
+//! `dns.cname_chain_depth` CNAMEs. A chain that comes back to a name already in it stops
+//! there. The CNAMEs and the records they lead to are all placed in the answer section,
+//! in chain order (RFC 1034 section 4.3.2).
+//!
+//! Targets are answered with the records of their zone as they are, without the stages
+//! of the pipeline, and signed answers, to queries with the DO bit, are left as the zone
+//! gives them, since the signatures of records from other zones aren't looked up.
+
+use hickory_proto::rr::rdata::CNAME;
+use hickory_proto::rr::{LowerName, RData, Record, RecordType};
+use hickory_server::authority::{MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tonic::async_trait;
+
+use crate::catalogsnapshot::CatalogSnapshot;
+use crate::roundrobin::reread;
+
+/// Response handler that completes the CNAME chains of authoritative answers from the
+/// hosted zones of `snapshot` before sending them on.
+#[derive(Clone)]
+pub struct ChainResponseHandler<R> {
+    inner: R,
+    snapshot: Arc<CatalogSnapshot>,
+    name: LowerName,
+    record_type: RecordType,
+    depth: usize,
+}
+
+impl<R> ChainResponseHandler<R> {
+    pub fn new(inner: R, snapshot: Arc<CatalogSnapshot>, request: &Request, depth: usize) -> Self {
+        Self {
+            inner,
+            snapshot,
+            name: request.query().name().clone(),
+            record_type: request.query().query_type(),
+            depth,
+        }
+    }
+}
+
+/// Whether the chains of the answers to `request` are completed, given at most `depth`
+/// CNAMEs.
+pub fn applies(request: &Request, depth: usize) -> bool {
+    let record_type = request.query().query_type();
+    let signed = request.edns().is_some_and(|edns| edns.dnssec_ok());
+    depth > 0 && !signed && !matches!(record_type, RecordType::CNAME | RecordType::ANY | RecordType::RRSIG)
+}
+
+/// The target of `record`, if it is a CNAME.
+fn cname_target(record: &Record) -> Option<LowerName> {
+    match record.data() {
+        Some(RData::CNAME(CNAME(target))) => Some(LowerName::new(target)),
+        _ => None,
+    }
+}
+
+impl<R> ChainResponseHandler<R> {
+    /// Completes the chain starting at the queried name, taking the records of each link
+    /// from the additional section when the zone put them there, or from the hosted
+    /// zones otherwise.
+    async fn complete(&self, answers: &mut Vec<Record>, additionals: &mut Vec<Record>) {
+        let mut visited = HashSet::from([self.name.clone()]);
+        let mut current = self.name.clone();
+        let mut links = 0;
+        loop {
+            let answered = |records: &[Record], record_type: RecordType| {
+                records
+                    .iter()
+                    .filter(|record| record.record_type() == record_type && LowerName::new(record.name()) == current)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+            if !answered(answers, self.record_type).is_empty() {
+                return;
+            }
+            let cname = answered(answers, RecordType::CNAME);
+            if cname.is_empty() {
+                let mut records = [answered(additionals, self.record_type), answered(additionals, RecordType::CNAME)].concat();
+                let moved = !records.is_empty();
+                if !moved {
+                    records = self.lookup(&current).await;
+                }
+                // A chain holding as many CNAMEs as allowed only takes the records of the
+                // queried type of its last target
+                if links >= self.depth {
+                    records.retain(|record| record.record_type() != RecordType::CNAME);
+                }
+                if records.is_empty() {
+                    return;
+                }
+                if moved {
+                    additionals.retain(|record| !records.contains(record));
+                }
+                answers.extend(records);
+                continue;
+            }
+            let Some(target) = cname.first().and_then(cname_target) else {
+                return;
+            };
+            links += 1;
+            if links > self.depth || !visited.insert(target.clone()) {
+                return;
+            }
+            current = target;
+        }
+    }
+
+    /// The records of the queried type `name` has in a hosted zone, or its CNAME.
+    async fn lookup(&self, name: &LowerName) -> Vec<Record> {
+        if self.snapshot.referral(name, self.record_type).await.is_some() {
+            return Vec::new();
+        }
+        let records = self.snapshot.lookup_records(name, self.record_type).await;
+        match records.is_empty() {
+            true => self.snapshot.lookup_records(name, RecordType::CNAME).await,
+            false => records,
+        }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for ChainResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        // The answer iterators can't be inspected in place, so the response is encoded and
+        // re-read, then rebuilt with the completed chain
+        let message = reread(response)?;
+        let mut answers = message.answers().to_vec();
+        let mut additionals = message.additionals().to_vec();
+        if answers.iter().any(|record| record.record_type() == RecordType::CNAME) {
+            self.complete(&mut answers, &mut additionals).await;
+        }
+
+        let mut builder = MessageResponseBuilder::from_message_request(&message);
+        if let Some(edns) = message.edns() {
+            builder.edns(edns.clone());
+        }
+        let response = builder.build(
+            *message.header(),
+            answers.iter(),
+            message.name_servers(),
+            [],
+            additionals.iter().chain(message.sig0()),
+        );
+        self.inner.send_response(response).await
+    }
+}
@@ -0,0 +1,311 @@
+//! Configuration checks.
+//!
+//! `rdns check-config [PATH]` and the `CheckConfig` call check a Config.toml without
+//! starting anything, for a bad config to be caught in CI before it is deployed rather
+//! than when the server fails to start with it. The file is parsed along with the
+//! `APP__*` environment overrides, as at startup, and every section is turned into the
+//! options the servers are started with, which checks the listen addresses, upstreams
+//! and the rest of the settings' syntax. The TLS certificates and keys are read and the
+//! configured zone files parsed as well, but nothing is bound or connected to.
+//!
+//! Each problem names the setting it is with and, when the file has it, the line it is
+//! on. Parsing stops at the first syntax error, the other checks report every problem.
+
+use hickory_proto::rr::Name;
+use hickory_proto::rustls::tls_server;
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+use toml::de::{DeTable, DeValue};
+use toml::Spanned;
+
+use crate::acme::AcmeDnsOptions;
+use crate::acmecert::CertificateOptions;
+use crate::clientid::ClientOptions;
+use crate::cluster::ClusterOptions;
+use crate::control::GrpcOptions;
+use crate::dns::DnsOptions;
+use crate::dnstap::DnstapOptions;
+use crate::events::EventOptions;
+use crate::forward::ForwardOptions;
+use crate::logging::LogLevel;
+use crate::notifications::NotificationOptions;
+use crate::persist::StorageOptions;
+use crate::probe::ProbeOptions;
+use crate::queryrules;
+use crate::script::Script;
+use crate::settings::Settings;
+use crate::telemetry::TelemetryOptions;
+use crate::verify::Listeners;
+use crate::zonefile;
+
+/// A problem found with a setting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigProblem {
+    /// The setting, e.g. `dns.forward.upstreams`, or empty for the file as a whole
+    pub key: String,
+    /// Line and column of the setting in the file, from 1, when it is there
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+    /// The line of the file the setting is on
+    pub context: Option<String>,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((line, column)) = self.position {
+            write!(f, "{}:{}: ", line, column)?;
+        }
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        write!(f, "{}", self.message)?;
+        if let (Some((line, _)), Some(context)) = (self.position, &self.context) {
+            write!(f, "\n{:>5} | {}", line, context)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks Config.toml `content`, returning the problems found, none if it is valid.
+pub fn check(content: &str) -> Vec<ConfigProblem> {
+    let document = Document::new(content);
+    let settings = match Settings::parse(content) {
+        Ok(settings) => settings,
+        Err(e) => {
+            // The file alone tells where the problem is, unless it is with the overrides
+            let (message, span) = match toml::from_str::<Settings>(content) {
+                Err(located) => (located.message().to_string(), located.span()),
+                Ok(_) => (e.to_string(), None),
+            };
+            return vec![document.problem(String::new(), span, message)];
+        }
+    };
+    let mut checks = Checks {
+        document,
+        problems: Vec::new(),
+    };
+
+    // Subsections first, for their problems to be reported with them rather than `dns`
+    if let Some(forward) = settings.dns.forward.clone() {
+        checks.report("dns.forward", ForwardOptions::try_from(forward).map(drop));
+    }
+    for (i, zone_file) in settings.dns.zone_files.iter().enumerate() {
+        let parsed = std::fs::read_to_string(&zone_file.path).map_err(anyhow::Error::from).and_then(|content| {
+            let origin = zone_file.origin.as_deref().map(Name::from_str).transpose()?;
+            zonefile::parse(&content, origin, Some(zone_file.path.clone().into())).map(drop)
+        });
+        checks.report(&format!("dns.zone_files[{}].path", i), parsed.map_err(|e| e.context(zone_file.path.clone())));
+    }
+    if let Some(script) = settings.dns.script.clone() {
+        checks.report("dns.script", Script::try_from(script).map(drop));
+    }
+    if let Some(clients) = settings.dns.clients.clone() {
+        checks.report("dns.clients", ClientOptions::try_from(clients).map(drop));
+    }
+    for (i, rule) in settings.dns.query_rules.iter().enumerate() {
+        checks.report(&format!("dns.query_rules[{}]", i), queryrules::load_configured(std::slice::from_ref(rule), &[]).map(drop));
+    }
+    for (i, file) in settings.dns.query_rule_files.iter().enumerate() {
+        checks.report(&format!("dns.query_rule_files[{}]", i), queryrules::load_configured(&[], std::slice::from_ref(file)).map(drop));
+    }
+    // The listeners serve the certificate obtained through ACME rather than their files
+    if let Some(certificate) = settings.acme.certificate.clone() {
+        checks.report("acme.certificate", CertificateOptions::try_from(certificate).map(drop));
+    } else {
+        if let Some(tls) = &settings.dns.tls {
+            checks.report("dns.tls", check_identity(&tls.cert_path, &tls.key_path));
+        }
+        if let Some(https) = &settings.dns.https {
+            checks.report("dns.https", check_identity(&https.cert_path, &https.key_path));
+        }
+    }
+    match DnsOptions::try_from(settings.dns.clone()) {
+        Ok(options) => checks.report("dns", Listeners::try_from(&options).map(drop)),
+        Err(e) => checks.report("dns", Err(e)),
+    }
+
+    checks.report("grpc", GrpcOptions::try_from(settings.grpc.clone()).map(drop));
+    if !settings.grpc.listen_addr.starts_with("unix:") {
+        checks.report("grpc.listen_addr", parse_addr(&settings.grpc.listen_addr));
+    }
+    if let Some(tls) = &settings.grpc.tls {
+        checks.report("grpc.tls", check_identity(&tls.cert_path, &tls.key_path));
+        if let Some(ca_path) = &tls.client_ca_path {
+            checks.report("grpc.tls.client_ca_path", read_certs(ca_path).map(drop));
+        }
+    }
+    checks.report("storage", StorageOptions::try_from(settings.storage.clone()).map(drop));
+    checks.report("logging.level", settings.logging.level.parse::<LogLevel>().map(drop));
+    if let Some(dnstap) = settings.logging.dnstap.clone() {
+        checks.report("logging.dnstap", DnstapOptions::try_from(dnstap).map(drop));
+    }
+    if let Some(telemetry) = settings.telemetry.clone() {
+        checks.report("telemetry", TelemetryOptions::try_from(telemetry).map(drop));
+    }
+    if let Some(cluster) = settings.cluster.clone() {
+        checks.report("cluster", ClusterOptions::try_from(cluster).map(drop));
+    }
+    for (i, notification) in settings.notifications.iter().enumerate() {
+        checks.report(&format!("notifications[{}]", i), NotificationOptions::try_from(notification.clone()).map(drop));
+    }
+    if let Some(events) = settings.events.clone() {
+        checks.report("events", EventOptions::try_from(events).map(drop));
+    }
+    if let Some(acme_dns) = settings.acme.acme_dns.clone() {
+        checks.report("acme.acme_dns", AcmeDnsOptions::try_from(acme_dns).map(drop));
+    }
+    if let Some(metrics) = &settings.metrics {
+        checks.report("metrics.listen_addr", parse_addr(&metrics.listen_addr));
+    }
+    if let Some(probe) = settings.probe.clone() {
+        checks.report("probe", ProbeOptions::try_from(probe).map(drop));
+    }
+    if let Some(rest) = &settings.rest {
+        checks.report("rest.listen_addr", parse_addr(&rest.listen_addr));
+    }
+    if let Some(webhook) = &settings.webhook {
+        checks.report("webhook.listen_addr", parse_addr(&webhook.listen_addr));
+    }
+    checks.problems
+}
+
+/// Checks Config.toml at `path`, or an empty config if there is none, as at startup.
+pub fn check_file(path: &Path) -> Vec<ConfigProblem> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => check(&content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => check(""),
+        Err(e) => vec![ConfigProblem {
+            key: String::new(),
+            position: None,
+            message: format!("can't read {}: {}", path.display(), e),
+            context: None,
+        }],
+    }
+}
+
+struct Checks<'a> {
+    document: Document<'a>,
+    problems: Vec<ConfigProblem>,
+}
+
+impl Checks<'_> {
+    /// Records the failure of a check of the settings under `key`, attributed to the
+    /// setting it names if it names one, as most option errors do.
+    fn report(&mut self, key: &str, result: anyhow::Result<()>) {
+        let Err(e) = result else {
+            return;
+        };
+        let message = format!("{:#}", e);
+        let key = named_key(&message, key).unwrap_or_else(|| key.to_string());
+        // An error of a subsection is reported again by the check of the options holding it
+        if self.problems.iter().any(|problem| problem.message == message) {
+            return;
+        }
+        let span = self.document.locate(&key);
+        self.problems.push(self.document.problem(key, span, message));
+    }
+}
+
+/// The longest setting under `section` that `message` names, e.g. `grpc.limits.requests_per_second`.
+fn named_key(message: &str, section: &str) -> Option<String> {
+    let section = section.split('[').next().unwrap_or(section);
+    message
+        .split(|c: char| !(c.is_ascii_alphanumeric() || "._[]".contains(c)))
+        .map(|word| word.trim_end_matches('.'))
+        .filter(|word| word.starts_with(section) && word.len() > section.len() && word[section.len()..].starts_with(['.', '[']))
+        .max_by_key(|word| word.len())
+        .map(str::to_string)
+}
+
+/// The parsed file, for the lines of the settings.
+struct Document<'a> {
+    content: &'a str,
+    root: Option<Spanned<DeTable<'a>>>,
+}
+
+impl<'a> Document<'a> {
+    fn new(content: &'a str) -> Self {
+        Document {
+            content,
+            root: DeTable::parse(content).ok(),
+        }
+    }
+
+    /// Span of `key` in the file, or of the closest table holding it that is there.
+    fn locate(&self, key: &str) -> Option<Range<usize>> {
+        let root = self.root.as_ref()?;
+        let mut table = root.get_ref();
+        let mut span = None;
+        for segment in key.split('.') {
+            let (name, index) = match segment.split_once('[') {
+                Some((name, index)) => (name, index.trim_end_matches(']').parse::<usize>().ok()),
+                None => (segment, None),
+            };
+            let Some((found, value)) = table.get_key_value(name) else {
+                break;
+            };
+            span = Some(found.span());
+            let value = match (value.get_ref(), index) {
+                (DeValue::Array(array), Some(index)) => match array.get(index) {
+                    Some(item) => {
+                        span = Some(item.span());
+                        item.get_ref()
+                    }
+                    None => break,
+                },
+                (value, _) => value,
+            };
+            match value {
+                DeValue::Table(inner) => table = inner,
+                _ => break,
+            }
+        }
+        span
+    }
+
+    fn problem(&self, key: String, span: Option<Range<usize>>, message: String) -> ConfigProblem {
+        let position = span.map(|span| {
+            let before = &self.content[..span.start.min(self.content.len())];
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+            (line, column)
+        });
+        let context = position.and_then(|(line, _)| self.content.lines().nth(line - 1)).map(|line| line.trim_end().to_string());
+        ConfigProblem {
+            key,
+            position,
+            message,
+            context,
+        }
+    }
+}
+
+fn parse_addr(addr: &str) -> anyhow::Result<()> {
+    addr.parse::<SocketAddr>()
+        .map(drop)
+        .map_err(|e| anyhow::anyhow!("invalid address {}: {}", addr, e))
+}
+
+/// Reads the certificates at `path`, failing if it holds none.
+fn read_certs(path: &str) -> anyhow::Result<Vec<rustls::Certificate>> {
+    let certs = tls_server::read_cert(Path::new(path)).map_err(|e| anyhow::anyhow!("{}", e))?;
+    if certs.is_empty() {
+        anyhow::bail!("{} holds no certificate", path);
+    }
+    Ok(certs)
+}
+
+/// Reads the certificate chain at `cert_path` and the private key at `key_path`, and
+/// checks that a TLS server can be set up with them.
+fn check_identity(cert_path: &str, key_path: &str) -> anyhow::Result<()> {
+    if cert_path.is_empty() || key_path.is_empty() {
+        anyhow::bail!("cert_path and key_path must be set, unless [acme.certificate] provides the certificate");
+    }
+    let certs = read_certs(cert_path)?;
+    let key = tls_server::read_key_from_pem(Path::new(key_path)).map_err(|e| anyhow::anyhow!("{}: {}", key_path, e))?;
+    tls_server::new_acceptor(certs, key).map_err(|e| anyhow::anyhow!("{}: {}", cert_path, e))?;
+    Ok(())
+}
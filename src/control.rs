@@ -3,117 +3,3724 @@
 //! Provides a `DnsControl` gRPC server implementation that allows adding and
 //! deleting DNS records in a shared `DnsState` through protobuf requests.
 
-use tonic::{transport::Server, Request, Response, Status};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::sync::RwLock;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use futures_util::Stream;
+use hickory_proto::rr::rdata::{CNAME, MX, NS, PTR, SRV, TXT};
+use hickory_proto::rr::{LowerName, Name, RData, Record};
+use tonic::transport::server::TcpIncoming;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
+use tonic::{Code, Request, Response, Status, Streaming};
+use tonic_types::{ErrorDetails, StatusExt};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::{collections::HashMap, net::Ipv4Addr, net::Ipv6Addr, net::SocketAddr, path::Path, path::PathBuf, pin::Pin, sync::Arc, time::Duration, time::SystemTime};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::sync::{broadcast, watch, RwLock};
+use tonic_health::ServingStatus;
 
-use crate::{control::dns_control_server::DnsControl, dns::DnsState, settings::GrpcSettings};
+use crate::acme::{self, AcmeOptions};
+use crate::alias::AliasEntry;
+use crate::approval::ChangeRequest;
+use crate::apilimit::{ApiLimitOptions, LimitLayer};
+use crate::apiversion::LegacyPackageLayer;
+use crate::audit::{self, Actor, AuditLog, Change};
+use crate::auth::{self, AuthInterceptor, AuthOptions, Authenticator, Caller, Role};
+use crate::blocklist::{self, Action};
+use crate::cluster::{self, Cluster, ClusterRole};
+use crate::configcheck;
+use crate::dnssec;
+use crate::error::RdnsError;
+use crate::forward::{ForwardRuleEntry, ForwardRuleInfo};
+use crate::geo::GeoEntry;
+use crate::health::{self, HealthCheckEntry};
+use crate::history::{self, VersionSelector};
+use crate::logging::{self, LogLevel};
+use crate::metadata;
+use crate::migrate::{self, ExportFormat};
+use crate::overrides::OverrideEntry;
+use crate::pattern::{self, PatternEntry};
+use crate::persist::Snapshot;
+use crate::pool::{PoolEntry, PoolMemberEntry};
+use crate::reload::Reloader;
+use crate::queryrules::{QueryRuleEntry, QueryRuleInfo};
+use crate::rewrite::{RewriteRuleEntry, RewriteRuleInfo};
+use crate::rollout::RolloutEntry;
+use crate::schedule::{ScheduledChange, ScheduledKind};
+use crate::serverinfo;
+use crate::service::ServiceEntry;
+use crate::shutdown::{DrainMode, Shutdown, ShutdownTrigger};
+use crate::stats::{self, AnomalyKind, ClientStats, NameStats, StatsQuery};
+use crate::svcb::ServiceBinding;
+use crate::telemetry::{self, TraceLayer, Tracer};
+use crate::tenant::TenantEntry;
+use crate::tsig::TsigKeyInfo;
+use crate::verify::{self, Listeners};
+use crate::zonecheck::{self, Finding};
+use crate::zonefile;
+use crate::{control::admin_server::Admin, control::dns_control_server::DnsControl, control::forwarding_rules_server::ForwardingRules, control::query_rules_server::QueryRules, control::rewrite_rules_server::RewriteRules, control::stats_server::Stats, dns::{self, DnsState}, metrics::Metrics, settings::{AcmeSettings, GrpcSettings, GrpcTlsSettings}};
 
-// Generated protobuf code for the `control` service.
+// Generated protobuf code for the v1 control services, see `apiversion`.
 // Includes the request/response types and the DnsControl trait.
-tonic::include_proto!("control");
+tonic::include_proto!("rdns.control.v1");
+
+/// Generated protobuf code for the v2 `Records` service, whose record values are typed.
+pub mod v2 {
+    tonic::include_proto!("rdns.control.v2");
+}
+
+/// Most records an `ImportRecords` stream may send, and most bytes they may encode to,
+/// as the batch is held in memory until it is applied.
+const MAX_IMPORT_RECORDS: usize = 100_000;
+const MAX_IMPORT_BYTES: usize = 64 * 1024 * 1024;
+
+/// Encoded descriptors of the control protos, served by the reflection service.
+const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("control_descriptor");
 
 /// The gRPC control server that exposes methods for managing DNS records.
+#[derive(Clone)]
 pub struct ControlServer {
     /// Shared mutable access to the DNS state.
     state: Arc<RwLock<DnsState>>,
+    /// Registry mutation counts are recorded in.
+    metrics: Arc<Metrics>,
+    /// Bearer-token validation, shared with the request interceptor.
+    auth: Arc<Authenticator>,
+    /// Applies config changes for `ReloadConfig`.
+    reloader: Arc<Reloader>,
+    /// Stops the server and ends open `WatchRecords` streams.
+    shutdown: Shutdown,
+    /// Whether failed mutations are answered with `success = false` instead of an error.
+    legacy_errors: bool,
+    /// Log record and zone changes are appended to, none are logged when unset.
+    audit: Option<Arc<AuditLog>>,
+    /// Replication with the other instances of a cluster, if this is one of them.
+    cluster: Option<Arc<Cluster>>,
+    /// Tracer calls are recorded with, if tracing is configured.
+    tracer: Option<Tracer>,
+    /// When the server was started, for its uptime.
+    started: SystemTime,
+    /// Raises the shutdown signal for `Shutdown`, which is refused when unset.
+    shutdown_trigger: Option<ShutdownTrigger>,
+    /// TTL and cleanup delay of the records of `SetAcmeChallenge`.
+    acme: AcmeOptions,
+    /// DNS listeners `VerifyRecord` queries, which is refused when unset.
+    listeners: Option<Listeners>,
+    /// Whether the probes of the DNS listeners succeed, if they are probed.
+    probe: Option<watch::Receiver<bool>>,
 }
 
 impl ControlServer {
-    /// Constructs a new `ControlServer` with shared DNS state
-    pub fn new(state: Arc<RwLock<DnsState>>) -> Self {
-        Self { state }
+    /// Constructs a new `ControlServer` with shared DNS state, metrics, authenticator,
+    /// reloader and audit log
+    pub fn new(
+        state: Arc<RwLock<DnsState>>,
+        metrics: Arc<Metrics>,
+        auth: Arc<Authenticator>,
+        reloader: Arc<Reloader>,
+        shutdown: Shutdown,
+        legacy_errors: bool,
+        audit: Option<Arc<AuditLog>>,
+    ) -> Self {
+        Self {
+            state,
+            metrics,
+            auth,
+            reloader,
+            shutdown,
+            legacy_errors,
+            audit,
+            cluster: None,
+            tracer: None,
+            started: SystemTime::now(),
+            shutdown_trigger: None,
+            acme: AcmeOptions::from(AcmeSettings::default()),
+            listeners: None,
+            probe: None,
+        }
+    }
+
+    /// Serves `Replicate` and `ClusterStatus` for this instance's part in `cluster`.
+    pub fn with_cluster(mut self, cluster: Arc<Cluster>) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Lets `Shutdown` stop the server by raising the shutdown signal with `trigger`.
+    pub fn with_shutdown_trigger(mut self, trigger: ShutdownTrigger) -> Self {
+        self.shutdown_trigger = Some(trigger);
+        self
+    }
+
+    /// Publishes the challenges of `SetAcmeChallenge` as `acme` says, rather than with
+    /// the default TTL and cleanup delay.
+    pub fn with_acme(mut self, acme: AcmeOptions) -> Self {
+        self.acme = acme;
+        self
+    }
+
+    /// Answers `VerifyRecord` with queries of the DNS server's `listeners`.
+    pub fn with_listeners(mut self, listeners: Listeners) -> Self {
+        self.listeners = Some(listeners);
+        self
+    }
+
+    /// Reports the server as not serving while `probe` is false, see `probe`.
+    pub fn with_probe(mut self, probe: watch::Receiver<bool>) -> Self {
+        self.probe = Some(probe);
+        self
+    }
+
+    /// Records a span for every call with `tracer`, and the latest mutation for the DNS
+    /// requests that follow it to link to.
+    pub fn with_tracer(mut self, tracer: Tracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+
+    /// Answers the mutation `rpc` with its outcome, recording it in the metrics. A failure
+    /// is an error status, or a `success = false` response for legacy clients.
+    #[allow(clippy::result_large_err)] // returns `Status` like the RPCs it answers
+    fn mutation(&self, rpc: &str, result: Result<String, RdnsError>) -> Result<Response<ControlResponse>, Status> {
+        self.metrics.observe_mutation(rpc, result.is_ok());
+        if result.is_ok() {
+            telemetry::record_mutation();
+        }
+        match result {
+            Ok(message) => Ok(Response::new(ControlResponse {
+                success: true,
+                message,
+                dry_run: None,
+            })),
+            Err(e) => self.failure(e),
+        }
+    }
+
+    /// Answers a dry run of a mutation with what it would change. As nothing changed,
+    /// it is left out of the mutation metrics and the audit log.
+    #[allow(clippy::result_large_err)] // returns `Status` like the RPCs it answers
+    fn dry_run(&self, result: Result<dns::ChangePlan, RdnsError>) -> Result<Response<ControlResponse>, Status> {
+        match result {
+            Ok(plan) => Ok(Response::new(ControlResponse {
+                success: true,
+                message: "Dry run, nothing changed".to_string(),
+                dry_run: Some(plan.into()),
+            })),
+            Err(e) => self.failure(e),
+        }
+    }
+
+    /// Answers a failed mutation with an error status, or a `success = false` response
+    /// for legacy clients.
+    #[allow(clippy::result_large_err)] // returns `Status` like the RPCs it answers
+    fn failure(&self, e: RdnsError) -> Result<Response<ControlResponse>, Status> {
+        match self.legacy_errors {
+            true => Ok(Response::new(ControlResponse {
+                success: false,
+                message: format!("Error: {}", e),
+                dry_run: None,
+            })),
+            false => Err(error_status(&e)),
+        }
+    }
+
+    /// Queues the `operations` of a `method` call by a propose token for an admin's
+    /// approval, rather than applying them.
+    async fn propose(
+        &self,
+        state: &DnsState,
+        actor: Actor,
+        method: &str,
+        operations: Vec<dns::ChangeOperation>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let request = ChangeRequest {
+            id: 0,
+            operations,
+            method: method.to_string(),
+            requested_by: actor.token,
+            requested_at: SystemTime::now(),
+        };
+        let result = state.propose_changes(request).await;
+        self.mutation(method, result.map(|id| format!("Change proposed as request {}, awaiting approval", id)))
+    }
+
+    /// The change request `id`, if it is pending and every name it changes is in the zones
+    /// of `tenant`.
+    fn pending_approval(&self, state: &DnsState, tenant: Option<&str>, id: u64) -> Result<ChangeRequest, RdnsError> {
+        let pending = state
+            .pending_approval(id)
+            .ok_or_else(|| RdnsError::RecordNotFound(format!("no change request {}", id)))?;
+        for name in pending.names() {
+            state.check_tenant(tenant, name)?;
+        }
+        Ok(pending)
+    }
+}
+
+/// Page size of `QueryRecords` when the request doesn't set one.
+const DEFAULT_PAGE_SIZE: usize = 100;
+/// Largest page `QueryRecords` and `GetAllRecords` return.
+const MAX_PAGE_SIZE: usize = 1000;
+/// The `DnsRecord` fields a read mask may name.
+const RECORD_FIELDS: [&str; 5] = ["name", "value", "ttl", "record_type", "metadata"];
+/// Entries `GetQueryStats` and `GetTopClients` list when the request doesn't set how
+/// many.
+const DEFAULT_STATS_TOP: usize = 10;
+
+/// The message of a successful record mutation, noting the TTL the record was clamped
+/// to by the TTL policy, if it was.
+pub fn with_clamped_ttl(message: &str, clamped: Option<u32>) -> String {
+    match clamped {
+        Some(ttl) => format!("{} (TTL clamped to {})", message, ttl),
+        None => message.to_string(),
+    }
+}
+
+/// The message of a successful record addition, noting a record of the same value that
+/// existed already and the TTL the record was clamped to, if it was.
+pub fn record_added(added: dns::Added) -> String {
+    let message = match added.duplicate {
+        true => "Record already existed, its TTL and lease were refreshed",
+        false => "Record added",
+    };
+    with_clamped_ttl(message, added.clamped)
+}
+
+/// The message of a successful addition of a value on a schedule, like `record_added`.
+fn timed_record_added(added: dns::Added) -> String {
+    let message = match added.duplicate {
+        true => "Value already on the schedule, the TTL of its values was updated",
+        false => "Value added on the schedule",
+    };
+    with_clamped_ttl(message, added.clamped)
+}
+
+/// The message of a successful zone creation, noting the number of records added from
+/// a zone template, if any were.
+pub fn zone_created(records: usize) -> String {
+    match records {
+        0 => "Zone created".to_string(),
+        records => format!("Zone created with {} template records", records),
+    }
+}
+
+/// Domain of the `google.rpc.ErrorInfo` details of error statuses.
+const ERROR_DOMAIN: &str = "rdns";
+
+/// The status of a failed call: the code its category calls for, with a
+/// `google.rpc.ErrorInfo` whose reason names the category, e.g. `ZONE_NOT_FOUND`.
+fn error_status(e: &RdnsError) -> Status {
+    let code = match e {
+        RdnsError::InvalidName(_) | RdnsError::InvalidRdata(_) | RdnsError::InvalidArgument(_) => Code::InvalidArgument,
+        RdnsError::ZoneNotFound(_) | RdnsError::RecordNotFound(_) | RdnsError::VersionNotFound(_) => Code::NotFound,
+        RdnsError::ZoneExists(_) | RdnsError::Conflict(_) => Code::AlreadyExists,
+        RdnsError::ReadOnlyZone(_)
+        | RdnsError::ZoneFrozen(_)
+        | RdnsError::Occluded(_)
+        | RdnsError::ValueMismatch(_)
+        | RdnsError::NotLeader(_)
+        | RdnsError::NotEnabled(_) => Code::FailedPrecondition,
+        RdnsError::QuotaExceeded(_) => Code::ResourceExhausted,
+        RdnsError::PermissionDenied(_) => Code::PermissionDenied,
+        RdnsError::StorageError(_) => Code::Internal,
+        RdnsError::Other(_) => Code::Unknown,
+    };
+    let details = ErrorDetails::with_error_info(e.reason(), ERROR_DOMAIN, HashMap::new());
+    Status::with_error_details(code, e.to_string(), details)
+}
+
+/// The caller of `request`, as the audit log records it.
+fn actor<T>(request: &Request<T>) -> Actor {
+    Actor {
+        token: request.extensions().get::<Caller>().and_then(|caller| caller.token.clone()),
+        peer: request.remote_addr().map(|addr| addr.to_string()),
+    }
+}
+
+/// Parses a timestamp of a request, such as a bound of a `GetAuditLog` time range.
+#[allow(clippy::result_large_err)] // returns `Status` like the RPC it parses for
+fn time_bound(bound: Option<prost_types::Timestamp>, field: &str) -> Result<Option<SystemTime>, Status> {
+    bound
+        .map(SystemTime::try_from)
+        .transpose()
+        .map_err(|e| Status::invalid_argument(format!("invalid {}: {}", field, e)))
+}
+
+/// Checks that a port, priority or weight of a `ServiceRegistration` or
+/// `AddServiceBindingRequest` fits in 16 bits.
+#[allow(clippy::result_large_err)] // returns `Status` like the RPC it parses for
+fn service_field(value: u32, field: &str) -> Result<u16, Status> {
+    u16::try_from(value).map_err(|_| Status::invalid_argument(format!("{} {} is out of range", field, value)))
+}
+
+/// Parses the address hints of an `AddServiceBindingRequest`.
+#[allow(clippy::result_large_err)] // returns `Status` like the RPC it parses for
+fn address_hints<T: std::str::FromStr>(hints: &[String]) -> Result<Vec<T>, Status> {
+    let mut parsed = Vec::with_capacity(hints.len());
+    for hint in hints {
+        parsed.push(hint.parse().map_err(|_| Status::invalid_argument(format!("invalid address hint {}", hint)))?);
+    }
+    Ok(parsed)
+}
+
+/// Encodes the key of the last record of a `QueryRecords` page as an opaque token.
+fn encode_page_token(record: &dns::RecordEntry) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}\0{}\0{}", record.name, record.record_type, record.value))
+}
+
+/// Decodes a token produced by `encode_page_token`.
+fn decode_page_token(token: &str) -> Option<(String, String, String)> {
+    let token = String::from_utf8(URL_SAFE_NO_PAD.decode(token).ok()?).ok()?;
+    let mut parts = token.splitn(3, '\0');
+    Some((parts.next()?.into(), parts.next()?.into(), parts.next()?.into()))
+}
+
+/// The key records of the page after `token` sort after; none for the first page.
+#[allow(clippy::result_large_err)] // returns `Status` like the RPC it parses for
+fn page_after(token: &str) -> Result<Option<(String, String, String)>, Status> {
+    match token {
+        "" => Ok(None),
+        token => Ok(Some(decode_page_token(token).ok_or_else(|| Status::invalid_argument("invalid page token"))?)),
+    }
+}
+
+/// The token of the page after `page`, empty when it is the last.
+fn next_page_token(page: &dns::RecordPage) -> String {
+    match page.records.last() {
+        Some(last) if page.more => encode_page_token(last),
+        _ => String::new(),
+    }
+}
+
+/// The `DnsRecord` fields `mask` names, after checking that it names only those; none
+/// when every field is to be filled in.
+#[allow(clippy::result_large_err)] // returns `Status` like the RPC it parses for
+fn record_fields(mask: Option<prost_types::FieldMask>) -> Result<Option<Vec<String>>, Status> {
+    let Some(mask) = mask.filter(|mask| !mask.paths.is_empty()) else {
+        return Ok(None);
+    };
+    if let Some(path) = mask.paths.iter().find(|path| !RECORD_FIELDS.contains(&path.as_str())) {
+        return Err(Status::invalid_argument(format!("unknown read_mask field {}, expected {}", path, RECORD_FIELDS.join(", "))));
+    }
+    Ok(Some(mask.paths))
+}
+
+/// `record` with only the `fields` of a read mask filled in, or all of them without one.
+fn mask_record(record: DnsRecord, fields: Option<&[String]>) -> DnsRecord {
+    let Some(fields) = fields else {
+        return record;
+    };
+    let has = |field: &str| fields.iter().any(|name| name == field);
+    DnsRecord {
+        name: if has("name") { record.name } else { String::new() },
+        value: if has("value") { record.value } else { String::new() },
+        ttl: if has("ttl") { record.ttl } else { 0 },
+        record_type: if has("record_type") { record.record_type } else { String::new() },
+        metadata: record.metadata.filter(|_| has("metadata")),
+    }
+}
+
+impl From<audit::AuditEntry> for AuditEntry {
+    fn from(entry: audit::AuditEntry) -> Self {
+        AuditEntry {
+            timestamp: Some(entry.timestamp.into()),
+            token: entry.actor.token.unwrap_or_default(),
+            peer: entry.actor.peer.unwrap_or_default(),
+            action: entry.action,
+            target: entry.target,
+            before: entry.before.into_iter().map(DnsRecord::from).collect(),
+            after: entry.after.into_iter().map(DnsRecord::from).collect(),
+            change_id: entry.change_id.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<history::VersionInfo> for ZoneVersion {
+    fn from(version: history::VersionInfo) -> Self {
+        ZoneVersion {
+            change_id: version.change_id,
+            serial: version.serial.unwrap_or_default(),
+            timestamp: Some(version.timestamp.into()),
+            record_count: version.record_count as u32,
+        }
+    }
+}
+
+impl From<dns::KeyRollovers> for KeyRollovers {
+    fn from(rollovers: dns::KeyRollovers) -> Self {
+        let rollover = |role: KeyRole, rollover: Option<dnssec::Rollover>| {
+            rollover.map(|rollover| KeyRollover {
+                role: role.into(),
+                phase: rollover.phase.to_string(),
+                since: Some(rollover.since.into()),
+            })
+        };
+        KeyRollovers {
+            rollovers: [rollover(KeyRole::ZoneSigning, rollovers.zsk), rollover(KeyRole::KeySigning, rollovers.ksk)]
+                .into_iter()
+                .flatten()
+                .collect(),
+            zsk_signing_since: rollovers.zsk_signing_since.map(Into::into),
+            next_step: rollovers.next_step.map(|(step, _)| step.to_string()).unwrap_or_default(),
+            next_step_at: rollovers.next_step.map(|(_, at)| at.into()),
+            next_ds: rollovers.next_ds.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<KeyRole> for dnssec::KeyRole {
+    fn from(role: KeyRole) -> Self {
+        match role {
+            KeyRole::ZoneSigning => dnssec::KeyRole::ZoneSigning,
+            KeyRole::KeySigning => dnssec::KeyRole::KeySigning,
+        }
+    }
+}
+
+impl From<ScheduledChange> for PendingChange {
+    fn from(change: ScheduledChange) -> Self {
+        let kind = match change.kind {
+            ScheduledKind::Add => pending_change::Kind::Add,
+            ScheduledKind::Delete => pending_change::Kind::Delete,
+        };
+        PendingChange {
+            id: change.id,
+            kind: kind.into(),
+            name: change.name,
+            record_type: change.record_type,
+            value: change.value,
+            ttl: change.ttl,
+            effective_at: Some(change.effective_at.into()),
+            expires_at: change.expires_at.map(Into::into),
+        }
+    }
+}
+
+impl From<dns::ChangeOperation> for ChangesetOperation {
+    fn from(operation: dns::ChangeOperation) -> Self {
+        let operation = match operation {
+            dns::ChangeOperation::Add(entry) => changeset_operation::Operation::Add(AddRecordRequest {
+                name: entry.name,
+                value: entry.value,
+                ttl: entry.ttl,
+                record_type: entry.record_type,
+                metadata: (!entry.metadata.is_empty()).then(|| entry.metadata.into()),
+                ..Default::default()
+            }),
+            dns::ChangeOperation::Delete { name, record_type } => changeset_operation::Operation::Delete(DeleteRecordRequest {
+                name,
+                record_type,
+                ..Default::default()
+            }),
+        };
+        ChangesetOperation { operation: Some(operation) }
+    }
+}
+
+impl From<ChangeRequest> for PendingApproval {
+    fn from(request: ChangeRequest) -> Self {
+        PendingApproval {
+            id: request.id,
+            operations: request.operations.into_iter().map(Into::into).collect(),
+            method: request.method,
+            requested_by: request.requested_by.unwrap_or_default(),
+            requested_at: Some(request.requested_at.into()),
+        }
+    }
+}
+
+impl From<cluster::FollowerLink> for ClusterFollower {
+    fn from(link: cluster::FollowerLink) -> Self {
+        ClusterFollower {
+            peer: link.peer,
+            connected_since: Some(link.connected_since.into()),
+            sent_sequence: link.sent_sequence,
+        }
+    }
+}
+
+impl From<dns::RecordEntry> for DnsRecord {
+    fn from(record: dns::RecordEntry) -> Self {
+        DnsRecord {
+            name: record.name,
+            value: record.value,
+            ttl: record.ttl,
+            record_type: record.record_type,
+            metadata: (!record.metadata.is_empty()).then(|| record.metadata.into()),
+        }
+    }
+}
+
+impl From<DnsRecord> for dns::RecordEntry {
+    fn from(record: DnsRecord) -> Self {
+        dns::RecordEntry {
+            name: record.name,
+            record_type: record.record_type,
+            value: record.value,
+            ttl: record.ttl,
+            metadata: record.metadata.map(Into::into).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<metadata::RecordMetadata> for RecordMetadata {
+    fn from(metadata: metadata::RecordMetadata) -> Self {
+        RecordMetadata {
+            comment: metadata.comment,
+            owner: metadata.owner,
+            tags: metadata.tags.into_iter().collect(),
+        }
+    }
+}
+
+impl From<metadata::RecordMetadata> for v2::Metadata {
+    fn from(metadata: metadata::RecordMetadata) -> Self {
+        v2::Metadata {
+            comment: metadata.comment,
+            owner: metadata.owner,
+            tags: metadata.tags.into_iter().collect(),
+        }
+    }
+}
+
+impl From<v2::Metadata> for RecordMetadata {
+    fn from(metadata: v2::Metadata) -> Self {
+        RecordMetadata {
+            comment: metadata.comment,
+            owner: metadata.owner,
+            tags: metadata.tags,
+        }
+    }
+}
+
+/// The record type and zone file value of the typed data of a v2 record.
+#[allow(clippy::result_large_err)] // returns `Status` like the RPCs it parses for
+fn zone_file_value(data: Option<v2::record::Data>) -> Result<(String, String), Status> {
+    use v2::record::Data;
+    let name = |name: &str| DnsState::parse_name(name).map_err(|e| error_status(&e));
+    let number = |value: u32, field: &str| u16::try_from(value).map_err(|_| Status::invalid_argument(format!("{} {} is above 65535", field, value)));
+    let rdata = match data.ok_or_else(|| Status::invalid_argument("the record has no data"))? {
+        Data::A(a) => RData::A(a.address.parse::<Ipv4Addr>().map_err(|_| Status::invalid_argument(format!("invalid IPv4 address {}", a.address)))?.into()),
+        Data::Aaaa(aaaa) => RData::AAAA(aaaa.address.parse::<Ipv6Addr>().map_err(|_| Status::invalid_argument(format!("invalid IPv6 address {}", aaaa.address)))?.into()),
+        Data::Cname(cname) => RData::CNAME(CNAME(name(&cname.target)?)),
+        Data::Mx(mx) => RData::MX(MX::new(number(mx.preference, "preference")?, name(&mx.exchange)?)),
+        Data::Txt(txt) => RData::TXT(TXT::new(txt.strings)),
+        Data::Srv(srv) => RData::SRV(SRV::new(number(srv.priority, "priority")?, number(srv.weight, "weight")?, number(srv.port, "port")?, name(&srv.target)?)),
+        Data::Ns(ns) => RData::NS(NS(name(&ns.host)?)),
+        Data::Ptr(ptr) => RData::PTR(PTR(name(&ptr.target)?)),
+        // Left to the zone file parser, which checks the tag and value
+        Data::Caa(caa) => {
+            let value = format!("{} {} \"{}\"", if caa.critical { 128 } else { 0 }, caa.tag, caa.value.replace('\\', "\\\\").replace('"', "\\\""));
+            return Ok(("CAA".into(), value));
+        }
+        Data::Other(other) => return Ok((other.record_type, other.value)),
+    };
+    Ok((rdata.record_type().to_string(), zonefile::format_rdata(&rdata)))
+}
+
+/// The v2 form of `record`, with its value typed if its type has a typed form.
+fn typed_record(record: dns::RecordEntry) -> v2::Record {
+    use v2::record::Data;
+    let parsed = DnsState::build_record(record.name.clone(), record.record_type.clone(), record.value.clone(), record.ttl);
+    let data = match parsed.as_ref().ok().and_then(Record::data) {
+        Some(RData::A(a)) => Data::A(v2::A { address: a.0.to_string() }),
+        Some(RData::AAAA(aaaa)) => Data::Aaaa(v2::Aaaa { address: aaaa.0.to_string() }),
+        Some(RData::CNAME(cname)) => Data::Cname(v2::Cname { target: cname.0.to_ascii() }),
+        Some(RData::MX(mx)) => Data::Mx(v2::Mx {
+            preference: mx.preference().into(),
+            exchange: mx.exchange().to_ascii(),
+        }),
+        Some(RData::TXT(txt)) => Data::Txt(v2::Txt {
+            strings: txt.iter().map(|data| String::from_utf8_lossy(data).into_owned()).collect(),
+        }),
+        Some(RData::SRV(srv)) => Data::Srv(v2::Srv {
+            priority: srv.priority().into(),
+            weight: srv.weight().into(),
+            port: srv.port().into(),
+            target: srv.target().to_ascii(),
+        }),
+        Some(RData::NS(ns)) => Data::Ns(v2::Ns { host: ns.0.to_ascii() }),
+        Some(RData::PTR(ptr)) => Data::Ptr(v2::Ptr { target: ptr.0.to_ascii() }),
+        // The value is read back from the zone file form, `flags tag "value"`
+        Some(RData::CAA(caa)) => match record.value.splitn(3, ' ').nth(2) {
+            Some(value) => Data::Caa(v2::Caa {
+                critical: caa.issuer_critical(),
+                tag: caa.tag().as_str().to_string(),
+                value: value.trim_matches('"').replace("\\\"", "\"").replace("\\\\", "\\"),
+            }),
+            None => Data::Other(v2::Other { record_type: record.record_type, value: record.value }),
+        },
+        _ => Data::Other(v2::Other { record_type: record.record_type, value: record.value }),
+    };
+    v2::Record {
+        name: record.name,
+        ttl: record.ttl,
+        data: Some(data),
+        metadata: (!record.metadata.is_empty()).then(|| record.metadata.into()),
+    }
+}
+
+/// The message of the v1 `response` a v2 call was answered with, or the failure it
+/// reports to legacy clients as an error.
+#[allow(clippy::result_large_err)] // returns `Status` like the RPCs it answers
+fn v1_message(response: Response<ControlResponse>) -> Result<String, Status> {
+    let response = response.into_inner();
+    match response.success {
+        true => Ok(response.message),
+        false => Err(Status::unknown(response.message)),
+    }
+}
+
+impl From<RecordMetadata> for metadata::RecordMetadata {
+    fn from(metadata: RecordMetadata) -> Self {
+        metadata::RecordMetadata {
+            comment: metadata.comment,
+            owner: metadata.owner,
+            tags: metadata.tags.into_iter().collect(),
+        }
+    }
+}
+
+/// The record as listed, with its name in Unicode when `unicode` is set.
+fn display_record(record: dns::RecordEntry, unicode: bool) -> DnsRecord {
+    DnsRecord::from(if unicode { record.into_unicode() } else { record })
+}
+
+/// Config options for the Grpc Control Server
+pub struct GrpcOptions {
+    /// TCP address, or `unix:` followed by the path of a Unix domain socket.
+    pub listen_addr: String,
+    /// Permissions the Unix domain socket is created with.
+    pub unix_socket_mode: u32,
+    /// TLS for the control API, plaintext when unset.
+    pub tls: Option<GrpcTlsOptions>,
+    /// Bearer-token authentication, disabled when unset.
+    pub auth: Option<AuthOptions>,
+    /// Whether failed mutations are answered with `success = false` instead of an error.
+    pub legacy_error_responses: bool,
+    /// Rate and concurrency limits of the calls, unlimited when unset.
+    pub limits: Option<ApiLimitOptions>,
+}
+impl TryFrom<GrpcSettings> for GrpcOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: GrpcSettings) -> anyhow::Result<Self> {
+        Ok(GrpcOptions {
+            listen_addr: cfg.listen_addr,
+            unix_socket_mode: cfg.unix_socket_mode,
+            tls: cfg.tls.map(GrpcTlsOptions::from),
+            auth: cfg.auth.map(AuthOptions::try_from).transpose()?,
+            legacy_error_responses: cfg.legacy_error_responses,
+            limits: cfg.limits.map(ApiLimitOptions::try_from).transpose()?,
+        })
+    }
+}
+
+impl GrpcOptions {
+    /// Options built in code rather than read from the config, for embedding the control
+    /// API: plaintext and unauthenticated until told otherwise.
+    pub fn builder() -> GrpcOptionsBuilder {
+        GrpcOptionsBuilder {
+            options: GrpcOptions {
+                listen_addr: String::new(),
+                unix_socket_mode: crate::settings::default_unix_socket_mode(),
+                tls: None,
+                auth: None,
+                legacy_error_responses: false,
+                limits: None,
+            },
+        }
+    }
+}
+
+/// Builds `GrpcOptions` step by step, see `GrpcOptions::builder`.
+pub struct GrpcOptionsBuilder {
+    options: GrpcOptions,
+}
+
+impl GrpcOptionsBuilder {
+    /// The address the control API is served on, e.g. `127.0.0.1:50051`, or
+    /// `unix:/run/rdns/control.sock` for a Unix domain socket.
+    pub fn listen_addr(mut self, addr: impl Into<String>) -> Self {
+        self.options.listen_addr = addr.into();
+        self
+    }
+
+    /// Permissions of the Unix domain socket, `0o660` by default.
+    pub fn unix_socket_mode(mut self, mode: u32) -> Self {
+        self.options.unix_socket_mode = mode;
+        self
+    }
+
+    pub fn tls(mut self, tls: GrpcTlsOptions) -> Self {
+        self.options.tls = Some(tls);
+        self
+    }
+
+    pub fn auth(mut self, auth: AuthOptions) -> Self {
+        self.options.auth = Some(auth);
+        self
+    }
+
+    pub fn legacy_error_responses(mut self, legacy: bool) -> Self {
+        self.options.legacy_error_responses = legacy;
+        self
+    }
+
+    pub fn limits(mut self, limits: ApiLimitOptions) -> Self {
+        self.options.limits = Some(limits);
+        self
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if no listen address was set.
+    pub fn build(self) -> anyhow::Result<GrpcOptions> {
+        if self.options.listen_addr.is_empty() {
+            anyhow::bail!("a gRPC listen address is required");
+        }
+        Ok(self.options)
+    }
+}
+
+/// Config options for TLS on the control API
+pub struct GrpcTlsOptions {
+    /// PEM-encoded certificate chain presented to clients.
+    pub cert_path: PathBuf,
+    /// PEM-encoded private key for the certificate.
+    pub key_path: PathBuf,
+    /// PEM-encoded CA certificates that client certificates are verified against.
+    /// When set, connections without a valid client certificate are rejected.
+    pub client_ca_path: Option<PathBuf>,
+}
+
+impl From<GrpcTlsSettings> for GrpcTlsOptions {
+    fn from(cfg: GrpcTlsSettings) -> Self {
+        GrpcTlsOptions {
+            cert_path: PathBuf::from(cfg.cert_path),
+            key_path: PathBuf::from(cfg.key_path),
+            client_ca_path: cfg.client_ca_path.map(PathBuf::from),
+        }
+    }
+}
+
+impl GrpcTlsOptions {
+    /// Loads the certificates and key into a tonic TLS configuration.
+    async fn server_config(&self) -> anyhow::Result<ServerTlsConfig> {
+        let cert = tokio::fs::read(&self.cert_path).await?;
+        let key = tokio::fs::read(&self.key_path).await?;
+        let mut config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+        if let Some(ca_path) = &self.client_ca_path {
+            let ca = tokio::fs::read(ca_path).await?;
+            config = config.client_ca_root(Certificate::from_pem(ca));
+        }
+        Ok(config)
+    }
+}
+
+impl From<configcheck::ConfigProblem> for ConfigProblem {
+    fn from(problem: configcheck::ConfigProblem) -> Self {
+        let (line, column) = problem.position.unwrap_or_default();
+        ConfigProblem {
+            key: problem.key,
+            line: line as u32,
+            column: column as u32,
+            message: problem.message,
+            context: problem.context.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<PoolMember> for PoolMemberEntry {
+    fn from(member: PoolMember) -> Self {
+        PoolMemberEntry {
+            value: member.value,
+            weight: member.weight.max(1),
+            backup: member.backup,
+            enabled: !member.disabled,
+        }
+    }
+}
+
+impl From<PoolEntry> for RecordPool {
+    fn from(entry: PoolEntry) -> Self {
+        let members = entry
+            .members
+            .into_iter()
+            .map(|member| PoolMember {
+                value: member.value,
+                weight: member.weight,
+                backup: member.backup,
+                disabled: !member.enabled,
+            })
+            .collect();
+        RecordPool {
+            name: entry.name,
+            record_type: entry.record_type,
+            ttl: entry.ttl,
+            members,
+        }
+    }
+}
+
+impl From<Alias> for AliasEntry {
+    fn from(alias: Alias) -> Self {
+        AliasEntry {
+            name: alias.name,
+            target: alias.target,
+            ttl: alias.ttl,
+        }
+    }
+}
+
+impl From<AliasEntry> for Alias {
+    fn from(entry: AliasEntry) -> Self {
+        Alias {
+            name: entry.name,
+            target: entry.target,
+            ttl: entry.ttl,
+        }
+    }
+}
+
+impl From<ForwardRuleInfo> for ForwardingRule {
+    fn from(rule: ForwardRuleInfo) -> Self {
+        ForwardingRule {
+            domain: rule.entry.domain,
+            upstreams: rule.entry.upstreams,
+            configured: rule.configured,
+        }
+    }
+}
+
+impl From<RewriteRuleInfo> for RewriteRule {
+    fn from(rule: RewriteRuleInfo) -> Self {
+        RewriteRule {
+            from: rule.entry.from,
+            to: rule.entry.to,
+            regex: rule.entry.regex,
+            configured: rule.configured,
+        }
+    }
+}
+
+impl From<QueryRuleInfo> for QueryRule {
+    fn from(rule: QueryRuleInfo) -> Self {
+        QueryRule {
+            pattern: rule.entry.pattern,
+            match_type: rule.entry.match_type,
+            action: rule.entry.action,
+            values: rule.entry.values,
+            ttl: rule.entry.ttl,
+            configured: rule.configured,
+        }
+    }
+}
+
+impl From<NameStats> for QueryStatsEntry {
+    fn from(stats: NameStats) -> Self {
+        QueryStatsEntry {
+            name: stats.name.to_string(),
+            record_type: stats.record_type.to_string(),
+            queries: stats.queries,
+            total: stats.total,
+            unique_clients: stats.clients as u64,
+            clients_capped: stats.clients_capped,
+            last_queried: stats.last_queried.map(Into::into),
+        }
+    }
+}
+
+impl From<TsigKeyInfo> for TsigKey {
+    fn from(key: TsigKeyInfo) -> Self {
+        TsigKey {
+            name: key.name,
+            algorithm: key.algorithm,
+            configured: key.configured,
+        }
+    }
+}
+
+impl From<GeoRecord> for GeoEntry {
+    fn from(record: GeoRecord) -> Self {
+        GeoEntry {
+            name: record.name,
+            record_type: record.record_type,
+            ttl: record.ttl,
+            regions: record.regions.into_iter().map(|region| (region.region, region.values)).collect(),
+        }
+    }
+}
+
+impl From<GeoEntry> for GeoRecord {
+    fn from(entry: GeoEntry) -> Self {
+        GeoRecord {
+            name: entry.name,
+            record_type: entry.record_type,
+            ttl: entry.ttl,
+            regions: entry
+                .regions
+                .into_iter()
+                .map(|(region, values)| RegionValues { region, values })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<Rollout> for RolloutEntry {
+    type Error = Status;
+
+    fn try_from(rollout: Rollout) -> Result<Self, Status> {
+        let percent = u8::try_from(rollout.percent)
+            .map_err(|_| Status::invalid_argument(format!("a rollout's percent must be from 0 to 100, not {}", rollout.percent)))?;
+        Ok(RolloutEntry {
+            name: rollout.name,
+            record_type: rollout.record_type,
+            ttl: rollout.ttl,
+            values: rollout.values,
+            percent,
+        })
+    }
+}
+
+impl From<RolloutEntry> for Rollout {
+    fn from(entry: RolloutEntry) -> Self {
+        Rollout {
+            name: entry.name,
+            record_type: entry.record_type,
+            ttl: entry.ttl,
+            values: entry.values,
+            percent: u32::from(entry.percent),
+        }
+    }
+}
+
+impl From<NamePattern> for PatternEntry {
+    fn from(pattern: NamePattern) -> Self {
+        PatternEntry {
+            pattern: pattern.pattern,
+            record_type: pattern.record_type,
+            ttl: pattern.ttl,
+            values: pattern.values,
+        }
+    }
+}
+
+impl From<PatternEntry> for NamePattern {
+    fn from(entry: PatternEntry) -> Self {
+        NamePattern {
+            pattern: entry.pattern,
+            record_type: entry.record_type,
+            ttl: entry.ttl,
+            values: entry.values,
+        }
+    }
+}
+
+impl From<Override> for OverrideEntry {
+    fn from(local: Override) -> Self {
+        OverrideEntry {
+            name: local.name,
+            record_type: local.record_type,
+            ttl: local.ttl,
+            values: local.values,
+        }
+    }
+}
+
+impl From<OverrideEntry> for Override {
+    fn from(entry: OverrideEntry) -> Self {
+        Override {
+            name: entry.name,
+            record_type: entry.record_type,
+            ttl: entry.ttl,
+            values: entry.values,
+        }
+    }
+}
+
+impl TryFrom<ServiceRegistration> for ServiceEntry {
+    type Error = Status;
+
+    fn try_from(registration: ServiceRegistration) -> Result<Self, Status> {
+        Ok(ServiceEntry {
+            instance: registration.instance,
+            service: registration.service,
+            protocol: registration.protocol,
+            domain: registration.domain,
+            host: registration.host,
+            addresses: registration.addresses,
+            port: service_field(registration.port, "port")?,
+            priority: service_field(registration.priority, "priority")?,
+            weight: service_field(registration.weight, "weight")?,
+            metadata: registration.metadata.into_iter().collect(),
+            ttl: registration.ttl,
+        })
+    }
+}
+
+impl TryFrom<&AddServiceBindingRequest> for ServiceBinding {
+    type Error = Status;
+
+    fn try_from(request: &AddServiceBindingRequest) -> Result<Self, Status> {
+        Ok(ServiceBinding {
+            priority: service_field(request.priority, "priority")?,
+            target: request.target.clone(),
+            alpn: request.alpn.clone(),
+            no_default_alpn: request.no_default_alpn,
+            port: Some(service_field(request.port, "port")?).filter(|&port| port != 0),
+            ipv4_hints: address_hints(&request.ipv4_hints)?,
+            ipv6_hints: address_hints(&request.ipv6_hints)?,
+        })
+    }
+}
+
+impl From<ServiceEntry> for ServiceRegistration {
+    fn from(entry: ServiceEntry) -> Self {
+        ServiceRegistration {
+            instance: entry.instance,
+            service: entry.service,
+            protocol: entry.protocol,
+            domain: entry.domain,
+            host: entry.host,
+            addresses: entry.addresses,
+            port: entry.port.into(),
+            priority: entry.priority.into(),
+            weight: entry.weight.into(),
+            metadata: entry.metadata.into_iter().collect(),
+            ttl: entry.ttl,
+        }
+    }
+}
+
+impl From<&HealthCheckEntry> for HealthCheck {
+    fn from(entry: &HealthCheckEntry) -> Self {
+        HealthCheck {
+            kind: entry.kind.to_string(),
+            port: entry.port.map(u32::from).unwrap_or_default(),
+            path: entry.path.clone().unwrap_or_default(),
+            interval_secs: entry.interval_secs.unwrap_or_default() as u32,
+            timeout_secs: entry.timeout_secs.unwrap_or_default() as u32,
+        }
+    }
+}
+
+impl From<health::ValueHealth> for ValueHealth {
+    fn from(health: health::ValueHealth) -> Self {
+        match health.check {
+            Some((entry, status)) => ValueHealth {
+                value: health.value,
+                check: Some(HealthCheck::from(&entry)),
+                healthy: status.healthy,
+                consecutive_failures: status.failures,
+                last_checked: status.last_checked.map(Into::into),
+                last_error: status.last_error.unwrap_or_default(),
+            },
+            None => ValueHealth {
+                value: health.value,
+                healthy: true,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl From<blocklist_entry::Action> for Action {
+    fn from(action: blocklist_entry::Action) -> Self {
+        match action {
+            blocklist_entry::Action::Block => Action::Block,
+            blocklist_entry::Action::Allow => Action::Allow,
+        }
+    }
+}
+
+impl From<drain_mode_request::Mode> for DrainMode {
+    fn from(mode: drain_mode_request::Mode) -> Self {
+        match mode {
+            drain_mode_request::Mode::Off => DrainMode::Off,
+            drain_mode_request::Mode::Truncate => DrainMode::Truncate,
+            drain_mode_request::Mode::Refuse => DrainMode::Refuse,
+            drain_mode_request::Mode::Unhealthy => DrainMode::Unhealthy,
+        }
+    }
+}
+
+impl From<blocklist::ListStatus> for BlocklistSource {
+    fn from(status: blocklist::ListStatus) -> Self {
+        BlocklistSource {
+            source: status.source,
+            entries: status.entries as u64,
+            loaded: status.loaded.map(Into::into),
+            error: status.error.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<ClientStats> for ClientStatsEntry {
+    fn from(stats: ClientStats) -> Self {
+        ClientStatsEntry {
+            client: stats.client.to_string(),
+            queries: stats.queries,
+            nxdomain: stats.nxdomain,
+            total: stats.total,
+            last_queried: Some(stats.last_queried.into()),
+        }
+    }
+}
+
+impl From<stats::Anomaly> for AnomalyEvent {
+    fn from(anomaly: stats::Anomaly) -> Self {
+        let kind = match anomaly.kind {
+            AnomalyKind::QuerySpike => anomaly_event::Kind::QuerySpike,
+            AnomalyKind::NxdomainFlood => anomaly_event::Kind::NxdomainFlood,
+        };
+        AnomalyEvent {
+            kind: kind as i32,
+            client: anomaly.client.map(|client| client.to_string()).unwrap_or_default(),
+            count: anomaly.count,
+            threshold: anomaly.threshold,
+            timestamp: Some(anomaly.timestamp.into()),
+        }
+    }
+}
+
+impl From<dns::ChangePlan> for DryRun {
+    fn from(plan: dns::ChangePlan) -> Self {
+        DryRun {
+            changes: plan.changes.into_iter().map(RecordEvent::from).collect(),
+            serials: plan
+                .serials
+                .into_iter()
+                .map(|serial| ZoneSerial {
+                    origin: serial.origin.to_string(),
+                    serial: serial.serial.unwrap_or_default(),
+                    next_serial: serial.next.unwrap_or_default(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<Finding> for ZoneFinding {
+    fn from(finding: Finding) -> Self {
+        let severity = match finding.severity {
+            zonecheck::Severity::Error => zone_finding::Severity::Error,
+            zonecheck::Severity::Warning => zone_finding::Severity::Warning,
+        };
+        ZoneFinding {
+            severity: severity as i32,
+            check: finding.check.to_string(),
+            name: finding.name,
+            message: finding.message,
+        }
+    }
+}
+
+impl From<dns::RecordEvent> for RecordEvent {
+    fn from(event: dns::RecordEvent) -> Self {
+        let change = match event.change {
+            dns::RecordChange::Added => record_event::Change::Added,
+            dns::RecordChange::Updated => record_event::Change::Updated,
+            dns::RecordChange::Deleted => record_event::Change::Deleted,
+        };
+        RecordEvent {
+            change: change as i32,
+            record: Some(DnsRecord::from(event.record)),
+            timestamp: Some(event.timestamp.into()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl DnsControl for ControlServer {
+    type WatchRecordsStream = Pin<Box<dyn Stream<Item = Result<RecordEvent, Status>> + Send>>;
+    type BackupStateStream = Pin<Box<dyn Stream<Item = Result<SnapshotChunk, Status>> + Send>>;
+    type ReplicateStream = Pin<Box<dyn Stream<Item = Result<ReplicationEvent, Status>> + Send>>;
+
+    /// Adds a new record (A unless another type is given) to the DNS authority.
+    ///
+    /// This method is invoked via gRPC with a `AddRecordRequest` and returns
+    /// a `ControlResponse` on success and an error status otherwise.
+    async fn add_record(
+        &self,
+        request: Request<AddRecordRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Propose)?;
+        let proposes = auth::proposes(&request);
+        let actor = actor(&request);
+        // Extract request data
+        let req = request.into_inner();
+        // Obtain write lock on DNS state to allow mutation
+        let effective_at = time_bound(req.effective_at, "effective_at")?;
+        let lease = (req.lease_seconds > 0)
+            .then(|| effective_at.unwrap_or_else(SystemTime::now) + Duration::from_secs(req.lease_seconds.into()));
+        let expires_at = time_bound(req.expires_at, "expires_at")?.or(lease);
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &req.name) {
+            return self.mutation("AddRecord", Err(e));
+        }
+        if !req.schedule.is_empty() {
+            if proposes || effective_at.is_some() || expires_at.is_some() || req.dry_run {
+                let message = "values on a schedule can't be proposed, scheduled, leased or dry run";
+                return self.mutation("AddRecord", Err(RdnsError::InvalidArgument(message.into())));
+            }
+            let change = Change::records(self.audit.as_deref(), &state, actor, "AddTimedRecord", &req.name, &req.record_type).await;
+            let result = state.add_timed_record(req.name, req.record_type, req.value, req.ttl, req.schedule).await;
+            audit::finish(change, &state, &result).await;
+            return self.mutation("AddRecord", result.map(timed_record_added));
+        }
+        if proposes && !req.dry_run {
+            if effective_at.is_some() || expires_at.is_some() {
+                return self.mutation("AddRecord", Err(RdnsError::InvalidArgument("proposed changes can't be scheduled or leased".into())));
+            }
+            let entry = dns::RecordEntry {
+                name: req.name,
+                record_type: req.record_type,
+                value: req.value,
+                ttl: req.ttl,
+                metadata: req.metadata.map(Into::into).unwrap_or_default(),
+            };
+            return self.propose(&state, actor, "AddRecord", vec![dns::ChangeOperation::Add(entry)]).await;
+        }
+        if let Some(effective_at) = effective_at {
+            if req.dry_run {
+                return self.mutation("AddRecord", Err(RdnsError::InvalidArgument("dry_run can't be scheduled".into())));
+            }
+            let change = Change::records(self.audit.as_deref(), &state, actor, "ScheduleAddRecord", &req.name, &req.record_type).await;
+            let scheduled = ScheduledChange {
+                id: 0,
+                kind: ScheduledKind::Add,
+                name: req.name,
+                record_type: req.record_type,
+                value: req.value,
+                ttl: req.ttl,
+                expires_at,
+                metadata: req.metadata.map(Into::into),
+                effective_at,
+            };
+            let result = state.schedule_change(scheduled).await;
+            audit::finish(change, &state, &result).await;
+            return self.mutation("AddRecord", result.map(|id| format!("Record addition scheduled as change {}", id)));
+        }
+        if req.dry_run {
+            let entry = dns::RecordEntry {
+                name: req.name,
+                record_type: req.record_type,
+                value: req.value,
+                ttl: req.ttl,
+                metadata: req.metadata.map(Into::into).unwrap_or_default(),
+            };
+            return self.dry_run(state.plan_add_record(entry, expires_at).await);
+        }
+        let change = Change::records(self.audit.as_deref(), &state, actor, "AddRecord", &req.name, &req.record_type).await;
+        let result = state
+            .add_leased_record(req.name, req.record_type, req.value, req.ttl, expires_at, req.metadata.map(Into::into))
+            .await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("AddRecord", result.map(record_added))
+    }
+
+    /// Adds an SVCB or HTTPS record built from its parameters.
+    async fn add_service_binding(
+        &self,
+        request: Request<AddServiceBindingRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let value = ServiceBinding::try_from(&req)?.value();
+        let record_type = if req.record_type.is_empty() { "HTTPS".to_string() } else { req.record_type.to_ascii_uppercase() };
+        if record_type != "HTTPS" && record_type != "SVCB" {
+            return Err(Status::invalid_argument(format!("record_type must be HTTPS or SVCB, not {}", record_type)));
+        }
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &req.name) {
+            return self.mutation("AddServiceBinding", Err(e));
+        }
+        let change = Change::records(self.audit.as_deref(), &state, actor, "AddServiceBinding", &req.name, &record_type).await;
+        let result = state.add_record(req.name, record_type, value, req.ttl).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("AddServiceBinding", result.map(record_added))
+    }
+
+    /// Replaces the records of a name and type, optionally only if the current value
+    /// matches `expected_value`.
+    async fn update_record(
+        &self,
+        request: Request<UpdateRecordRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let expected = Some(req.expected_value).filter(|value| !value.is_empty());
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &req.name) {
+            return self.mutation("UpdateRecord", Err(e));
+        }
+        let change = Change::records(self.audit.as_deref(), &state, actor, "UpdateRecord", &req.name, &req.record_type).await;
+        let result = state
+            .update_record(req.name, req.record_type, req.value, req.ttl, expected, req.metadata.map(Into::into))
+            .await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("UpdateRecord", result.map(|clamped| with_clamped_ttl("Record updated", clamped)))
+    }
+
+    /// Deletes the records of a type (A unless another is given) from the DNS authority.
+    ///
+    /// This method is invoked via gRPC with a `DeleteRecordRequest` and returns
+    /// a `ControlResponse` on success and an error status otherwise.
+    async fn delete_record(
+        &self,
+        request: Request<DeleteRecordRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Propose)?;
+        let proposes = auth::proposes(&request);
+        let actor = actor(&request);
+        // Extract request data
+        let req = request.into_inner();
+        // Obtain write lock on DNS state to allow mutation
+        let effective_at = time_bound(req.effective_at, "effective_at")?;
+        let state = self.state.write().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &req.name) {
+            return self.mutation("DeleteRecord", Err(e));
+        }
+        if !req.schedule.is_empty() {
+            if proposes || effective_at.is_some() || req.dry_run {
+                let message = "values on a schedule can't be proposed, scheduled or dry run";
+                return self.mutation("DeleteRecord", Err(RdnsError::InvalidArgument(message.into())));
+            }
+            let change = Change::records(self.audit.as_deref(), &state, actor, "DeleteTimedRecords", &req.name, &req.record_type).await;
+            let result = state.delete_timed_records(req.name, req.record_type, req.schedule).await;
+            audit::finish(change, &state, &result).await;
+            return self.mutation("DeleteRecord", result.map(|_| "Values on the schedule deleted".to_string()));
+        }
+        if proposes && !req.dry_run {
+            if effective_at.is_some() {
+                return self.mutation("DeleteRecord", Err(RdnsError::InvalidArgument("proposed changes can't be scheduled".into())));
+            }
+            let operation = dns::ChangeOperation::Delete {
+                name: req.name,
+                record_type: req.record_type,
+            };
+            return self.propose(&state, actor, "DeleteRecord", vec![operation]).await;
+        }
+        if let Some(effective_at) = effective_at {
+            if req.dry_run {
+                return self.mutation("DeleteRecord", Err(RdnsError::InvalidArgument("dry_run can't be scheduled".into())));
+            }
+            let change = Change::records(self.audit.as_deref(), &state, actor, "ScheduleDeleteRecord", &req.name, &req.record_type).await;
+            let scheduled = ScheduledChange {
+                id: 0,
+                kind: ScheduledKind::Delete,
+                name: req.name,
+                record_type: req.record_type,
+                value: String::new(),
+                ttl: 0,
+                expires_at: None,
+                metadata: None,
+                effective_at,
+            };
+            let result = state.schedule_change(scheduled).await;
+            audit::finish(change, &state, &result).await;
+            return self.mutation("DeleteRecord", result.map(|id| format!("Record deletion scheduled as change {}", id)));
+        }
+        if req.dry_run {
+            return self.dry_run(state.plan_delete_record(req.name, req.record_type).await);
+        }
+        let change = Change::records(self.audit.as_deref(), &state, actor, "DeleteRecord", &req.name, &req.record_type).await;
+        let result = state.delete_record(req.name, req.record_type).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("DeleteRecord", result.map(|_| "Record deleted".to_string()))
+    }
+
+    /// Deletes a single value of a multi-value RRset, leaving the others in place.
+    async fn delete_record_value(
+        &self,
+        request: Request<DeleteRecordValueRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let state = self.state.write().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &req.name) {
+            return self.mutation("DeleteRecordValue", Err(e));
+        }
+        let change = Change::records(self.audit.as_deref(), &state, actor, "DeleteRecordValue", &req.name, &req.record_type).await;
+        let result = state.delete_record_value(req.name, req.record_type, req.value).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("DeleteRecordValue", result.map(|_| "Record value deleted".to_string()))
+    }
+
+    /// Lists the record changes queued until they are due, in the order they are.
+    async fn list_pending_changes(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListPendingChangesResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let changes = state
+            .pending_changes()
+            .into_iter()
+            .filter(|change| state.check_tenant(tenant.as_deref(), &change.name).is_ok())
+            .map(PendingChange::from)
+            .collect();
+
+        Ok(Response::new(ListPendingChangesResponse { changes }))
+    }
+
+    /// Drops a queued record change before it is applied.
+    async fn cancel_pending_change(
+        &self,
+        request: Request<CancelPendingChangeRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let state = self.state.write().await;
+        let Some(pending) = state.pending_change(req.id) else {
+            return self.mutation("CancelPendingChange", Err(RdnsError::RecordNotFound(format!("no pending change {}", req.id))));
+        };
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &pending.name) {
+            return self.mutation("CancelPendingChange", Err(e));
+        }
+        let change = Change::records(self.audit.as_deref(), &state, actor, "CancelPendingChange", &pending.name, &pending.record_type).await;
+        let result = state.cancel_change(req.id).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("CancelPendingChange", result.map(|cancelled| format!("Cancelled change {}: {}", cancelled.id, cancelled)))
+    }
+
+    /// Lists the record changes proposed for approval, oldest first.
+    async fn list_pending_approvals(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListPendingApprovalsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let approvals = state
+            .pending_approvals()
+            .into_iter()
+            .filter(|pending| pending.names().all(|name| state.check_tenant(tenant.as_deref(), name).is_ok()))
+            .map(PendingApproval::from)
+            .collect();
+
+        Ok(Response::new(ListPendingApprovalsResponse { approvals }))
+    }
+
+    /// Applies a proposed record change.
+    async fn approve_change(
+        &self,
+        request: Request<ApprovalDecision>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let state = self.state.write().await;
+        let pending = match self.pending_approval(&state, tenant.as_deref(), req.id) {
+            Ok(pending) => pending,
+            Err(e) => return self.mutation("ApproveChange", Err(e)),
+        };
+        let mut changes = Vec::new();
+        for operation in &pending.operations {
+            let (name, record_type) = operation.target();
+            changes.push(Change::records(self.audit.as_deref(), &state, actor.clone(), "ApproveChange", name, record_type).await);
+        }
+        let result = state.approve_change(req.id).await;
+        for change in changes {
+            audit::finish(change, &state, &result).await;
+        }
+        self.mutation(
+            "ApproveChange",
+            result.map(|approved| format!("Approved change request {}, applied {} operations", approved.id, approved.operations.len())),
+        )
+    }
+
+    /// Drops a proposed record change without applying it.
+    async fn reject_change(
+        &self,
+        request: Request<ApprovalDecision>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.write().await;
+        if let Err(e) = self.pending_approval(&state, tenant.as_deref(), req.id) {
+            return self.mutation("RejectChange", Err(e));
+        }
+        let result = state.reject_change(req.id).await;
+        self.mutation("RejectChange", result.map(|rejected| format!("Rejected change request {}", rejected.id)))
+    }
+
+    /// Returns every record, or a page of them.
+    async fn get_all_records(
+        &self,
+        request: Request<GetAllRecordsRequest>,
+    ) -> Result<Response<GetAllRecordsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let fields = record_fields(req.read_mask)?;
+        let query = dns::RecordQuery {
+            tenant,
+            after: page_after(&req.page_token)?,
+            limit: match req.page_size as usize {
+                0 => usize::MAX,
+                size => size.min(MAX_PAGE_SIZE),
+            },
+            ..Default::default()
+        };
+
+        let state = self.state.read().await;
+        let page = state.query_records(&query).await.map_err(|e| error_status(&e))?;
+        let next_page_token = next_page_token(&page);
+
+        Ok(Response::new(GetAllRecordsResponse {
+            total_size: page.total as u32,
+            records: page.records.into_iter().map(|record| mask_record(record.into(), fields.as_deref())).collect(),
+            next_page_token,
+        }))
+    }
+
+    /// Returns the records of a single name and type (A unless another is given).
+    async fn get_record(
+        &self,
+        request: Request<GetRecordRequest>,
+    ) -> Result<Response<GetRecordResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        state.check_tenant(tenant.as_deref(), &req.name).map_err(|e| error_status(&e))?;
+        let records = state
+            .get_record(req.name, req.record_type)
+            .await
+            .map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(GetRecordResponse {
+            records: records.into_iter().map(|record| display_record(record, req.unicode)).collect(),
+        }))
+    }
+
+    /// Returns a page of the records matching the request's name and type filters.
+    async fn query_records(
+        &self,
+        request: Request<QueryRecordsRequest>,
+    ) -> Result<Response<QueryRecordsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let after = page_after(&req.page_token)?;
+        let fields = record_fields(req.read_mask)?;
+        let limit = match req.page_size as usize {
+            0 => DEFAULT_PAGE_SIZE,
+            size => size.min(MAX_PAGE_SIZE),
+        };
+        let query = dns::RecordQuery {
+            name_prefix: req.name_prefix,
+            name_suffix: req.name_suffix,
+            record_type: req.record_type,
+            owner: req.owner,
+            tags: req.tags.into_iter().collect(),
+            tenant,
+            after,
+            limit,
+        };
+
+        let state = self.state.read().await;
+        let page = state
+            .query_records(&query)
+            .await
+            .map_err(|e| error_status(&e))?;
+        let next_page_token = next_page_token(&page);
+
+        Ok(Response::new(QueryRecordsResponse {
+            total_size: page.total as u32,
+            records: page
+                .records
+                .into_iter()
+                .map(|record| mask_record(display_record(record, req.unicode), fields.as_deref()))
+                .collect(),
+            next_page_token,
+        }))
+    }
+
+    /// Queries the server's own DNS listener for a record, to check that it resolves.
+    async fn verify_record(
+        &self,
+        request: Request<VerifyRecordRequest>,
+    ) -> Result<Response<VerifyRecordResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let Some(listeners) = &self.listeners else {
+            return Err(Status::failed_precondition("queries of the DNS listener are not available on this server"));
+        };
+        let protocol = match req.protocol() {
+            verify_record_request::Protocol::Udp => verify::Protocol::Udp,
+            verify_record_request::Protocol::Tcp => verify::Protocol::Tcp,
+            verify_record_request::Protocol::Tls => verify::Protocol::Tls,
+            verify_record_request::Protocol::Https => verify::Protocol::Https,
+        };
+        self.state
+            .read()
+            .await
+            .check_tenant(tenant.as_deref(), &req.name)
+            .map_err(|e| error_status(&e))?;
+        let name = DnsState::parse_name(&req.name).map_err(|e| error_status(&e))?;
+        let record_type = DnsState::parse_record_type(&req.record_type).map_err(|e| error_status(&e))?;
+        let expected = match req.value.as_str() {
+            "" => None,
+            _ => DnsState::build_record(req.name, req.record_type, req.value, 0)
+                .map_err(|e| error_status(&e))?
+                .into_data(),
+        };
+
+        let answer = listeners
+            .query(&name, record_type, protocol)
+            .await
+            .map_err(|e| error_status(&e))?;
+        Ok(Response::new(VerifyRecordResponse {
+            rcode: answer.response.response_code().to_string(),
+            authoritative: answer.response.authoritative(),
+            truncated: answer.response.truncated(),
+            answers: answer
+                .response
+                .answers()
+                .iter()
+                .map(|record| DnsRecord::from(dns::RecordEntry::from(record)))
+                .collect(),
+            rtt_ms: u32::try_from(answer.rtt.as_millis()).unwrap_or(u32::MAX),
+            found: answer.holds(record_type, expected.as_ref()),
+        }))
+    }
+
+    /// Creates a new, empty zone served by its own authority.
+    async fn create_zone(
+        &self,
+        request: Request<CreateZoneRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let mut state = self.state.write().await;
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "CreateZone", &req.origin).await;
+        let result = match &tenant {
+            Some(tenant) => state.create_tenant_zone(tenant, &req.origin, None, req.auto_reverse).await,
+            None => state.create_zone_from_template(&req.origin, None, req.auto_reverse).await,
+        };
+        audit::finish(change, &state, &result).await;
+        self.mutation("CreateZone", result.map(zone_created))
+    }
+
+    /// Creates a zone populated with the records of a configured zone template.
+    async fn create_zone_from_template(
+        &self,
+        request: Request<CreateZoneFromTemplateRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let template = Some(req.template.as_str()).filter(|template| !template.is_empty());
+        let mut state = self.state.write().await;
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "CreateZoneFromTemplate", &req.origin).await;
+        let result = match &tenant {
+            Some(tenant) => state.create_tenant_zone(tenant, &req.origin, template, req.auto_reverse).await,
+            None => state.create_zone_from_template(&req.origin, template, req.auto_reverse).await,
+        };
+        audit::finish(change, &state, &result).await;
+        self.mutation("CreateZoneFromTemplate", result.map(zone_created))
+    }
+
+    /// Deletes a zone along with all of its records.
+    async fn delete_zone(
+        &self,
+        request: Request<DeleteZoneRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant_zone(tenant.as_deref(), &req.origin) {
+            return self.mutation("DeleteZone", Err(e));
+        }
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "DeleteZone", &req.origin).await;
+        let result = state.delete_zone(&req.origin).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("DeleteZone", result.map(|_| "Zone deleted".to_string()))
+    }
+
+    async fn list_zones(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListZonesResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let zones = state
+            .list_zones(tenant.as_deref())
+            .await
+            .into_iter()
+            .map(|(origin, record_count, auto_reverse, frozen)| Zone {
+                origin,
+                record_count,
+                auto_reverse,
+                frozen,
+            })
+            .collect();
+
+        Ok(Response::new(ListZonesResponse { zones }))
+    }
+
+    /// Replaces a zone's records with those parsed from a BIND-format zone file.
+    async fn import_zone(
+        &self,
+        request: Request<ImportZoneRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant_zone(tenant.as_deref(), &req.origin) {
+            return self.mutation("ImportZone", Err(e));
+        }
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "ImportZone", &req.origin).await;
+        let result = state.import_zone(&req.origin, &req.content, None).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("ImportZone", result.map(|count| format!("Imported {} records", count)))
+    }
+
+    /// Lists the kept versions of a zone, oldest first.
+    async fn list_zone_versions(
+        &self,
+        request: Request<ListZoneVersionsRequest>,
+    ) -> Result<Response<ListZoneVersionsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        state.check_tenant_zone(tenant.as_deref(), &req.origin).map_err(|e| error_status(&e))?;
+        let versions = state
+            .zone_versions(&req.origin)
+            .map_err(|e| error_status(&e))?
+            .into_iter()
+            .map(ZoneVersion::from)
+            .collect();
+
+        Ok(Response::new(ListZoneVersionsResponse { versions }))
+    }
+
+    /// Puts the records of a kept version of a zone back.
+    async fn rollback_zone(
+        &self,
+        request: Request<RollbackZoneRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let selector = match req.version {
+            Some(rollback_zone_request::Version::ChangeId(change_id)) => VersionSelector::ChangeId(change_id),
+            Some(rollback_zone_request::Version::Serial(serial)) => VersionSelector::Serial(serial),
+            None => {
+                let e = RdnsError::InvalidArgument("a change id or serial to roll back to is required".into());
+                return self.mutation("RollbackZone", Err(e));
+            }
+        };
+        let state = self.state.write().await;
+        if let Err(e) = state.check_tenant_zone(tenant.as_deref(), &req.origin) {
+            return self.mutation("RollbackZone", Err(e));
+        }
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "RollbackZone", &req.origin).await;
+        let result = state.rollback_zone(&req.origin, selector).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("RollbackZone", result.map(|change_id| format!("Rolled back to change {}", change_id)))
+    }
+
+    /// Rejects changes to a zone until it is thawed, while it is still answered.
+    async fn freeze_zone(
+        &self,
+        request: Request<FreezeZoneRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant_zone(tenant.as_deref(), &req.origin) {
+            return self.mutation("FreezeZone", Err(e));
+        }
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "FreezeZone", &req.origin).await;
+        let result = state.set_zone_frozen(&req.origin, true).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation(
+            "FreezeZone",
+            result.map(|was_frozen| if was_frozen { "Zone already frozen" } else { "Zone frozen" }.to_string()),
+        )
+    }
+
+    /// Accepts changes to a frozen zone again.
+    async fn thaw_zone(
+        &self,
+        request: Request<ThawZoneRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant_zone(tenant.as_deref(), &req.origin) {
+            return self.mutation("ThawZone", Err(e));
+        }
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "ThawZone", &req.origin).await;
+        let result = state.set_zone_frozen(&req.origin, false).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation(
+            "ThawZone",
+            result.map(|was_frozen| if was_frozen { "Zone thawed" } else { "Zone was not frozen" }.to_string()),
+        )
+    }
+
+    /// Serializes a zone's records as a BIND-format zone file.
+    async fn export_zone(
+        &self,
+        request: Request<ExportZoneRequest>,
+    ) -> Result<Response<ExportZoneResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        state.check_tenant_zone(tenant.as_deref(), &req.origin).map_err(|e| error_status(&e))?;
+        let content = state
+            .export_zone(&req.origin)
+            .await
+            .map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(ExportZoneResponse { content }))
+    }
+
+    /// Exports the records of a zone, or of one name in it, as a JSON document.
+    async fn export_records(
+        &self,
+        request: Request<ExportRecordsRequest>,
+    ) -> Result<Response<ExportRecordsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        state.check_tenant_zone(tenant.as_deref(), &req.origin).map_err(|e| error_status(&e))?;
+        let name = Some(req.name.as_str()).filter(|name| !name.is_empty());
+        let record_type = Some(req.record_type.as_str()).filter(|record_type| !record_type.is_empty());
+        let export = state
+            .export_records(&req.origin, name, record_type)
+            .await
+            .map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(ExportRecordsResponse { content: export.to_json() }))
+    }
+
+    /// Checks a zone for common problems without changing it.
+    async fn check_zone(
+        &self,
+        request: Request<CheckZoneRequest>,
+    ) -> Result<Response<CheckZoneResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        state.check_tenant_zone(tenant.as_deref(), &req.origin).map_err(|e| error_status(&e))?;
+        let findings = state
+            .check_zone(&req.origin)
+            .await
+            .map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(CheckZoneResponse {
+            findings: findings.into_iter().map(ZoneFinding::from).collect(),
+        }))
+    }
+
+    /// Returns the DS records to publish at the registrar for a signed zone.
+    async fn get_zone_ds(
+        &self,
+        request: Request<GetZoneDsRequest>,
+    ) -> Result<Response<GetZoneDsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        state.check_tenant_zone(tenant.as_deref(), &req.origin).map_err(|e| error_status(&e))?;
+        let ds_records = state
+            .get_zone_ds(&req.origin)
+            .await
+            .map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(GetZoneDsResponse { ds_records }))
+    }
+
+    /// Shows where the rollovers of a signed zone's keys stand.
+    async fn get_key_rollovers(
+        &self,
+        request: Request<GetKeyRolloversRequest>,
+    ) -> Result<Response<KeyRollovers>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        state.check_tenant_zone(tenant.as_deref(), &req.origin).map_err(|e| error_status(&e))?;
+        let rollovers = state
+            .key_rollovers(&req.origin)
+            .await
+            .map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(rollovers.into()))
+    }
+
+    /// Starts rolling over a signed zone's ZSK or KSK.
+    async fn start_key_rollover(
+        &self,
+        request: Request<KeyRolloverRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant_zone(tenant.as_deref(), &req.origin) {
+            return self.mutation("StartKeyRollover", Err(e));
+        }
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "StartKeyRollover", &req.origin).await;
+        let result = state.start_key_rollover(&req.origin, req.role().into()).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("StartKeyRollover", result)
+    }
+
+    /// Retires the old KSK of a signed zone once the parent zone publishes the new DS.
+    async fn confirm_ksk_rollover(
+        &self,
+        request: Request<ConfirmKskRolloverRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant_zone(tenant.as_deref(), &req.origin) {
+            return self.mutation("ConfirmKskRollover", Err(e));
+        }
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "ConfirmKskRollover", &req.origin).await;
+        let result = state.confirm_ksk_rollover(&req.origin).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("ConfirmKskRollover", result)
+    }
+
+    /// Rolls back the rollover of a signed zone's ZSK or KSK, dropping the new key.
+    async fn cancel_key_rollover(
+        &self,
+        request: Request<KeyRolloverRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant_zone(tenant.as_deref(), &req.origin) {
+            return self.mutation("CancelKeyRollover", Err(e));
+        }
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "CancelKeyRollover", &req.origin).await;
+        let result = state.cancel_key_rollover(&req.origin, req.role().into()).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("CancelKeyRollover", result)
+    }
+
+    /// Purges the cached answers for forwarded queries, of the name requested or all.
+    async fn flush_cache(
+        &self,
+        request: Request<FlushCacheRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let name = Some(req.name.as_str()).filter(|name| !name.is_empty());
+        let state = self.state.read().await;
+        let result = state.flush_cache(name);
+        let message = |count| match name {
+            Some(name) => format!("Flushed {} cache entries for {}", count, name),
+            None => format!("Flushed {} cache entries", count),
+        };
+        self.mutation("FlushCache", result.map(message))
+    }
+
+    /// Adds every record sent on the request stream under a single write lock, reporting
+    /// how many were inserted, skipped as duplicates, or rejected.
+    async fn import_records(
+        &self,
+        request: Request<Streaming<DnsRecord>>,
+    ) -> Result<Response<ImportRecordsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        // Receive the whole batch first so the lock isn't held while waiting on the client
+        let mut stream = request.into_inner();
+        let mut records = Vec::new();
+        let mut bytes = 0;
+        while let Some(record) = stream.message().await? {
+            bytes += prost::Message::encoded_len(&record);
+            if records.len() >= MAX_IMPORT_RECORDS || bytes > MAX_IMPORT_BYTES {
+                self.metrics.observe_mutation("ImportRecords", false);
+                return Err(Status::resource_exhausted(format!(
+                    "an import may hold at most {} records of {} MiB in total, split it into smaller batches",
+                    MAX_IMPORT_RECORDS,
+                    MAX_IMPORT_BYTES / (1024 * 1024)
+                )));
+            }
+            records.push(dns::RecordEntry::from(record));
+        }
+
+        let state = self.state.write().await;
+        // Records outside the tenant's zones are rejected like invalid ones, the others
+        // imported and their failures reported at their position in the whole batch
+        let mut denied = Vec::new();
+        let mut positions = Vec::with_capacity(records.len());
+        let mut allowed = Vec::with_capacity(records.len());
+        for (index, record) in records.into_iter().enumerate() {
+            match state.check_tenant(tenant.as_deref(), &record.name) {
+                Ok(()) => {
+                    positions.push(index);
+                    allowed.push(record);
+                }
+                Err(e) => denied.push((index, e.to_string())),
+            }
+        }
+        let result = state.import_records(allowed).await;
+        self.metrics.observe_mutation("ImportRecords", result.is_ok());
+        let summary = result.map_err(|e| error_status(&e))?;
+        let mut failed: Vec<(usize, String)> = summary
+            .failed
+            .into_iter()
+            .map(|(index, reason)| (positions[index], reason))
+            .chain(denied)
+            .collect();
+        failed.sort_by_key(|(index, _)| *index);
+        Ok(Response::new(ImportRecordsResponse {
+            inserted: summary.inserted,
+            skipped: summary.skipped,
+            failed: failed
+                .into_iter()
+                .map(|(index, reason)| ImportFailure { index: index as u32, reason })
+                .collect(),
+        }))
+    }
+
+    /// Imports a zone exported from another DNS provider.
+    async fn import_provider_records(
+        &self,
+        request: Request<ImportProviderRecordsRequest>,
+    ) -> Result<Response<ImportRecordsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let format = match import_provider_records_request::Format::try_from(req.format) {
+            Ok(import_provider_records_request::Format::Route53) => ExportFormat::Route53,
+            Ok(import_provider_records_request::Format::Cloudflare) => ExportFormat::Cloudflare,
+            Ok(import_provider_records_request::Format::Json) => ExportFormat::Json,
+            Err(_) => return Err(Status::invalid_argument(format!("unknown export format {}", req.format))),
+        };
+        let result = migrate::convert(format, &req.content, &req.origin);
+        let mut state = self.state.write().await;
+        let converted = result.and_then(|converted| {
+            state.check_tenant_zone(tenant.as_deref(), &converted.origin)?;
+            Ok(converted)
+        });
+        let converted = match converted {
+            Ok(converted) => converted,
+            Err(e) => {
+                self.metrics.observe_mutation("ImportProviderRecords", false);
+                return Err(error_status(&e));
+            }
+        };
+        let change = Change::zone(self.audit.as_deref(), &state, actor, "ImportProviderRecords", &converted.origin).await;
+        let result = state.import_converted(converted).await;
+        audit::finish(change, &state, &result).await;
+        self.metrics.observe_mutation("ImportProviderRecords", result.is_ok());
+        let summary = result.map_err(|e| error_status(&e))?;
+        Ok(Response::new(ImportRecordsResponse {
+            inserted: summary.inserted,
+            skipped: summary.skipped,
+            failed: summary
+                .failed
+                .into_iter()
+                .map(|(index, reason)| ImportFailure { index: index as u32, reason })
+                .collect(),
+        }))
+    }
+
+    /// Applies a list of record additions and deletions as a single transaction.
+    async fn apply_changeset(
+        &self,
+        request: Request<ApplyChangesetRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Propose)?;
+        let proposes = auth::proposes(&request);
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let operations = req
+            .operations
+            .into_iter()
+            .map(|operation| match operation.operation {
+                Some(changeset_operation::Operation::Add(add)) => Ok(dns::ChangeOperation::Add(dns::RecordEntry {
+                    name: add.name,
+                    record_type: add.record_type,
+                    value: add.value,
+                    ttl: add.ttl,
+                    metadata: add.metadata.map(Into::into).unwrap_or_default(),
+                })),
+                Some(changeset_operation::Operation::Delete(delete)) => Ok(dns::ChangeOperation::Delete {
+                    name: delete.name,
+                    record_type: delete.record_type,
+                }),
+                None => Err(RdnsError::InvalidArgument("operation not set".into())),
+            })
+            .collect::<Result<Vec<_>, _>>();
+
+        let state = self.state.write().await;
+        let operations = operations.and_then(|operations| {
+            for (index, operation) in operations.iter().enumerate() {
+                state
+                    .check_tenant(tenant.as_deref(), operation.target().0)
+                    .map_err(|e| e.context(format_args!("operation {}", index)))?;
+            }
+            Ok(operations)
+        });
+        if req.dry_run {
+            return self.dry_run(match operations {
+                Ok(operations) => state.plan_changeset(operations).await,
+                Err(e) => Err(e),
+            });
+        }
+        if proposes {
+            return match operations {
+                Ok(operations) => self.propose(&state, actor, "ApplyChangeset", operations).await,
+                Err(e) => self.mutation("ApplyChangeset", Err(e)),
+            };
+        }
+        let result = match operations {
+            Ok(operations) => state.apply_changeset(operations).await,
+            Err(e) => Err(e),
+        };
+        self.mutation("ApplyChangeset", result.map(|count| format!("Applied {} operations", count)))
+    }
+
+    /// Brings a zone in line with a declarative spec of its records.
+    async fn apply_zone_spec(
+        &self,
+        request: Request<ApplyZoneSpecRequest>,
+    ) -> Result<Response<ApplyZoneSpecResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let spec = match (req.zone_file.is_empty(), req.records.is_empty()) {
+            (true, _) => Ok(req.records.into_iter().map(dns::RecordEntry::from).collect()),
+            (false, true) => DnsState::parse_name(&req.origin).and_then(|origin| {
+                let (_, records) = zonefile::parse(&req.zone_file, Some(origin), None)
+                    .map_err(|e| RdnsError::InvalidArgument(format!("invalid zone file: {}", e)))?;
+                Ok(records.iter().map(dns::RecordEntry::from).collect())
+            }),
+            (false, false) => Err(RdnsError::InvalidArgument("give either records or a zone_file".into())),
+        };
+        let state = self.state.write().await;
+        let spec = spec.and_then(|spec| {
+            state.check_tenant_zone(tenant.as_deref(), &req.origin)?;
+            Ok(spec)
+        });
+        let change = match (&spec, req.dry_run) {
+            (Ok(_), false) => Change::zone(self.audit.as_deref(), &state, actor, "ApplyZoneSpec", &req.origin).await,
+            _ => None,
+        };
+        let result = match spec {
+            Ok(spec) => state.apply_zone_spec(&req.origin, spec, req.dry_run).await,
+            Err(e) => Err(e),
+        };
+        audit::finish(change, &state, &result).await;
+        if !req.dry_run {
+            self.metrics.observe_mutation("ApplyZoneSpec", result.is_ok());
+        }
+        let DryRun { changes, serials } = result.map_err(|e| error_status(&e))?.into();
+
+        Ok(Response::new(ApplyZoneSpecResponse {
+            applied: !req.dry_run && !changes.is_empty(),
+            changes,
+            serials,
+        }))
+    }
+
+    /// Replaces an API token with a newly generated one and returns it.
+    async fn rotate_token(
+        &self,
+        request: Request<RotateTokenRequest>,
+    ) -> Result<Response<RotateTokenResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let result = self.auth.rotate(&req.name).await;
+        self.metrics.observe_mutation("RotateToken", result.is_ok());
+        let token = result.map_err(|e| Status::failed_precondition(e.to_string()))?;
+
+        Ok(Response::new(RotateTokenResponse { token }))
+    }
+
+    /// Generates a TSIG key and returns it with its secret.
+    async fn create_tsig_key(
+        &self,
+        request: Request<CreateTsigKeyRequest>,
+    ) -> Result<Response<CreateTsigKeyResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.create_tsig_key(&req.name, &req.algorithm).await;
+        self.metrics.observe_mutation("CreateTsigKey", result.is_ok());
+        let key = result.map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(CreateTsigKeyResponse {
+            name: key.name,
+            algorithm: key.algorithm,
+            secret: key.secret,
+        }))
+    }
+
+    async fn delete_tsig_key(
+        &self,
+        request: Request<DeleteTsigKeyRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.delete_tsig_key(&req.name).await;
+        self.mutation("DeleteTsigKey", result.map(|_| "TSIG key deleted".to_string()))
+    }
+
+    async fn list_tsig_keys(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListTsigKeysResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let keys = state.list_tsig_keys().into_iter().map(TsigKey::from).collect();
+
+        Ok(Response::new(ListTsigKeysResponse { keys }))
+    }
+
+    /// Creates or replaces the weighted/failover pool of a name and type.
+    async fn set_pool(
+        &self,
+        request: Request<SetPoolRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let entry = PoolEntry {
+            name: req.name,
+            record_type: req.record_type,
+            ttl: req.ttl,
+            members: req.members.into_iter().map(PoolMemberEntry::from).collect(),
+        };
+        let state = self.state.write().await;
+        let result = state.set_pool(entry).await;
+        self.mutation("SetPool", result.map(|_| "Pool set".to_string()))
+    }
+
+    async fn delete_pool(
+        &self,
+        request: Request<DeletePoolRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.write().await;
+        let result = state.delete_pool(req.name, req.record_type).await;
+        self.mutation("DeletePool", result.map(|_| "Pool deleted".to_string()))
+    }
+
+    async fn list_pools(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListPoolsResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let pools = state.list_pools().into_iter().map(RecordPool::from).collect();
+
+        Ok(Response::new(ListPoolsResponse { pools }))
+    }
+
+    /// Starts health-checking one value of an A or AAAA RRset, replacing any check of it.
+    async fn set_health_check(
+        &self,
+        request: Request<SetHealthCheckRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let check = req.check.unwrap_or_default();
+        let result = async {
+            let entry = HealthCheckEntry {
+                name: req.name,
+                record_type: req.record_type,
+                value: req.value,
+                kind: check.kind.parse().map_err(|e: anyhow::Error| RdnsError::InvalidArgument(e.to_string()))?,
+                port: (check.port != 0)
+                    .then(|| u16::try_from(check.port))
+                    .transpose()
+                    .map_err(|_| RdnsError::InvalidArgument(format!("port {} is out of range", check.port)))?,
+                path: (!check.path.is_empty()).then_some(check.path),
+                interval_secs: (check.interval_secs != 0).then_some(u64::from(check.interval_secs)),
+                timeout_secs: (check.timeout_secs != 0).then_some(u64::from(check.timeout_secs)),
+            };
+            self.state.read().await.set_health_check(entry).await
+        }
+        .await;
+        self.mutation("SetHealthCheck", result.map(|_| "Health check set".to_string()))
+    }
+
+    async fn delete_health_check(
+        &self,
+        request: Request<DeleteHealthCheckRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.delete_health_check(req.name, req.record_type, req.value).await;
+        self.mutation("DeleteHealthCheck", result.map(|_| "Health check deleted".to_string()))
+    }
+
+    /// Reports whether each value of a name's records is served, with its latest check result.
+    async fn get_record_health(
+        &self,
+        request: Request<GetRecordHealthRequest>,
+    ) -> Result<Response<GetRecordHealthResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let values = state
+            .record_health(req.name, req.record_type)
+            .await
+            .map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(GetRecordHealthResponse {
+            values: values.into_iter().map(ValueHealth::from).collect(),
+        }))
+    }
+
+    /// Adds a record to the overlay of a split-horizon view.
+    async fn add_view_record(
+        &self,
+        request: Request<AddViewRecordRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let record = req.record.unwrap_or_default();
+        let state = self.state.read().await;
+        let result = state
+            .add_view_record(&req.view, record.name, record.record_type, record.value, record.ttl)
+            .await;
+        self.mutation("AddViewRecord", result.map(|_| "View record added".to_string()))
+    }
+
+    async fn delete_view_record(
+        &self,
+        request: Request<DeleteViewRecordRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.delete_view_record(&req.view, req.name, req.record_type).await;
+        self.mutation("DeleteViewRecord", result.map(|_| "View records deleted".to_string()))
+    }
+
+    async fn list_views(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListViewsResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let views = state
+            .views()
+            .iter()
+            .map(|view| View {
+                name: view.name.clone(),
+                match_clients: view.clients.networks(),
+                records: view.records().iter().map(|record| DnsRecord::from(dns::RecordEntry::from(record))).collect(),
+            })
+            .collect();
+
+        Ok(Response::new(ListViewsResponse { views }))
+    }
+
+    /// Blocks or allows a domain and its subdomains, whatever the blocklists say.
+    async fn add_blocklist_entry(
+        &self,
+        request: Request<BlocklistEntry>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let entry = request.into_inner();
+        let action = Action::from(entry.action());
+        let state = self.state.read().await;
+        let result = state.add_blocklist_entry(&entry.domain, action).await;
+        self.mutation("AddBlocklistEntry", result.map(|_| format!("Blocklist {} entry added", action)))
+    }
+
+    async fn delete_blocklist_entry(
+        &self,
+        request: Request<BlocklistEntry>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let entry = request.into_inner();
+        let action = Action::from(entry.action());
+        let state = self.state.read().await;
+        let result = state.delete_blocklist_entry(&entry.domain, action).await;
+        self.mutation("DeleteBlocklistEntry", result.map(|_| format!("Blocklist {} entry deleted", action)))
+    }
+
+    /// Lists the runtime entries, blocked then allowed, and the state of each blocklist.
+    async fn list_blocklist(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListBlocklistResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let blocklist = state.blocklist().map_err(|e| error_status(&e))?;
+        let mut entries = Vec::new();
+        for (action, proto_action) in [
+            (Action::Block, blocklist_entry::Action::Block),
+            (Action::Allow, blocklist_entry::Action::Allow),
+        ] {
+            entries.extend(blocklist.entries(action).into_iter().map(|domain| BlocklistEntry {
+                domain,
+                action: proto_action as i32,
+            }));
+        }
+        let sources = blocklist.sources().into_iter().map(BlocklistSource::from).collect();
+
+        Ok(Response::new(ListBlocklistResponse { entries, sources }))
+    }
+
+    /// Creates or replaces the geo record of a name and type.
+    async fn set_geo_record(
+        &self,
+        request: Request<GeoRecord>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let entry = GeoEntry::from(request.into_inner());
+        let state = self.state.read().await;
+        let result = state.set_geo_record(entry).await;
+        self.mutation("SetGeoRecord", result.map(|_| "Geo record set".to_string()))
+    }
+
+    async fn delete_geo_record(
+        &self,
+        request: Request<DeleteGeoRecordRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.delete_geo_record(req.name, req.record_type).await;
+        self.mutation("DeleteGeoRecord", result.map(|_| "Geo record deleted".to_string()))
+    }
+
+    async fn list_geo_records(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListGeoRecordsResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let records = state.list_geo_records().into_iter().map(GeoRecord::from).collect();
+
+        Ok(Response::new(ListGeoRecordsResponse { records }))
+    }
+
+    /// Creates or replaces the rollout of a name and type.
+    async fn set_rollout(
+        &self,
+        request: Request<Rollout>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let entry = RolloutEntry::try_from(request.into_inner())?;
+        let state = self.state.read().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &entry.name) {
+            return self.mutation("SetRollout", Err(e));
+        }
+        let result = state.set_rollout(entry).await;
+        self.mutation("SetRollout", result.map(|_| "Rollout set".to_string()))
+    }
+
+    async fn delete_rollout(
+        &self,
+        request: Request<DeleteRolloutRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &req.name) {
+            return self.mutation("DeleteRollout", Err(e));
+        }
+        let result = state.delete_rollout(req.name, req.record_type).await;
+        self.mutation("DeleteRollout", result.map(|_| "Rollout deleted".to_string()))
+    }
+
+    async fn list_rollouts(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListRolloutsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let rollouts = state
+            .list_rollouts()
+            .into_iter()
+            .filter(|entry| state.check_tenant(tenant.as_deref(), &entry.name).is_ok())
+            .map(Rollout::from)
+            .collect();
+
+        Ok(Response::new(ListRolloutsResponse { rollouts }))
+    }
+
+    /// Lists the values answered on a schedule, with whether their schedule is active.
+    async fn list_timed_records(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListTimedRecordsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let records = state
+            .list_timed_records()
+            .into_iter()
+            .filter(|(entry, _)| state.check_tenant(tenant.as_deref(), &entry.name).is_ok())
+            .map(|(entry, active)| TimedRecord {
+                name: entry.name,
+                record_type: entry.record_type,
+                ttl: entry.ttl,
+                schedule: entry.schedule,
+                values: entry.values,
+                active,
+            })
+            .collect();
+
+        Ok(Response::new(ListTimedRecordsResponse { records }))
+    }
+
+    /// Makes the new values of a rollout the zone's records, for every client.
+    async fn promote_rollout(
+        &self,
+        request: Request<PromoteRolloutRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let state = self.state.write().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &req.name) {
+            return self.mutation("PromoteRollout", Err(e));
+        }
+        let change = Change::records(self.audit.as_deref(), &state, actor, "PromoteRollout", &req.name, &req.record_type).await;
+        let result = state.promote_rollout(req.name, req.record_type).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("PromoteRollout", result.map(|_| "Rollout promoted".to_string()))
+    }
+
+    /// Creates or replaces the values of a name pattern and type.
+    async fn set_name_pattern(
+        &self,
+        request: Request<NamePattern>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let entry = PatternEntry::from(request.into_inner());
+        let state = self.state.read().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), entry.suffix()) {
+            return self.mutation("SetNamePattern", Err(e));
+        }
+        let result = state.set_pattern(entry).await;
+        self.mutation("SetNamePattern", result.map(|_| "Name pattern set".to_string()))
+    }
+
+    async fn delete_name_pattern(
+        &self,
+        request: Request<DeleteNamePatternRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), pattern::suffix(&req.pattern)) {
+            return self.mutation("DeleteNamePattern", Err(e));
+        }
+        let result = state.delete_pattern(req.pattern, req.record_type).await;
+        self.mutation("DeleteNamePattern", result.map(|_| "Name pattern deleted".to_string()))
+    }
+
+    async fn list_name_patterns(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListNamePatternsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let patterns = state
+            .list_patterns()
+            .into_iter()
+            .filter(|entry| state.check_tenant(tenant.as_deref(), entry.suffix()).is_ok())
+            .map(NamePattern::from)
+            .collect();
+
+        Ok(Response::new(ListNamePatternsResponse { patterns }))
+    }
+
+    /// Answers a forwarded name and type locally.
+    async fn set_override(
+        &self,
+        request: Request<Override>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let entry = OverrideEntry::from(request.into_inner());
+        let state = self.state.read().await;
+        let result = state.set_override(entry).await;
+        self.mutation("SetOverride", result.map(|_| "Override set".to_string()))
+    }
+
+    async fn delete_override(
+        &self,
+        request: Request<DeleteOverrideRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.delete_override(req.name, req.record_type).await;
+        self.mutation("DeleteOverride", result.map(|_| "Override deleted".to_string()))
+    }
+
+    async fn list_overrides(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListOverridesResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let overrides = state.list_overrides().into_iter().map(Override::from).collect();
+
+        Ok(Response::new(ListOverridesResponse { overrides }))
+    }
+
+    async fn set_alias(
+        &self,
+        request: Request<Alias>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let entry = AliasEntry::from(request.into_inner());
+        let state = self.state.read().await;
+        let result = state.set_alias(entry).await;
+        self.mutation("SetAlias", result.map(|_| "Alias set".to_string()))
+    }
+
+    async fn delete_alias(
+        &self,
+        request: Request<DeleteAliasRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.delete_alias(req.name).await;
+        self.mutation("DeleteAlias", result.map(|_| "Alias deleted".to_string()))
+    }
+
+    async fn list_aliases(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListAliasesResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let aliases = state.list_aliases().into_iter().map(Alias::from).collect();
+
+        Ok(Response::new(ListAliasesResponse { aliases }))
     }
-}
 
-/// Config options for the Grpc Control Server
-pub struct GrpcOptions {
-    pub listen_addr: String,
-}
-impl From<GrpcSettings> for GrpcOptions {
-    fn from(cfg: GrpcSettings) -> Self {
-        GrpcOptions {
-            listen_addr: cfg.listen_addr,
-        }
+    /// Registers an instance of a service, publishing its SRV, TXT and address records.
+    async fn register_service(
+        &self,
+        request: Request<ServiceRegistration>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let entry = ServiceEntry::try_from(request.into_inner())?;
+        let state = self.state.read().await;
+        let result = state.register_service(entry).await;
+        self.mutation("RegisterService", result.map(|_| "Service registered".to_string()))
     }
-}
 
-#[tonic::async_trait]
-impl DnsControl for ControlServer {
-    /// Adds a new A record to the DNS authority.
-    ///
-    /// This method is invoked via gRPC with a `AddRecordRequest` and returns
-    /// a `ControlResponse` indicating success or failure.
-    async fn add_record(
+    async fn deregister_service(
         &self,
-        request: Request<AddRecordRequest>,
+        request: Request<DeregisterServiceRequest>,
     ) -> Result<Response<ControlResponse>, Status> {
-        // Extract request data
+        auth::require(&request, Role::Admin)?;
         let req = request.into_inner();
-        // Obtain write lock on DNS state to allow mutation
-        let state = self.state.write().await;
-        // Attempt to add the record
-        match state.add_record(req.name, req.value, req.ttl).await {
-            Ok(_) => Ok(Response::new(ControlResponse {
+        let state = self.state.read().await;
+        let result = state
+            .deregister_service(&req.instance, &req.service, &req.protocol, &req.domain)
+            .await;
+        self.mutation("DeregisterService", result.map(|_| "Service deregistered".to_string()))
+    }
+
+    async fn list_services(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListServicesResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let services = state.list_services().into_iter().map(ServiceRegistration::from).collect();
+
+        Ok(Response::new(ListServicesResponse { services }))
+    }
+
+    /// Re-reads Config.toml, applying the changes that can be applied without a restart.
+    async fn reload_config(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ReloadConfigResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let result = self.reloader.reload().await;
+        self.metrics.observe_mutation("ReloadConfig", result.is_ok());
+        match result {
+            Ok(report) => Ok(Response::new(ReloadConfigResponse {
                 success: true,
-                message: "Record added".into(),
+                message: report.summary(),
+                applied: report.applied,
+                restart_required: report.restart_required,
             })),
-            Err(e) => Ok(Response::new(ControlResponse {
+            Err(e) if self.legacy_errors => Ok(Response::new(ReloadConfigResponse {
                 success: false,
                 message: format!("Error: {}", e),
+                ..Default::default()
             })),
+            Err(e) => Err(error_status(&RdnsError::from(e))),
         }
     }
 
-    /// Deletes an A record from the DNS authority.
+    /// Checks the given Config.toml content, or the server's own Config.toml, as
+    /// `rdns check-config` does, without applying it. Admin only, as problems quote the
+    /// lines of the file, which may hold secrets.
+    async fn check_config(&self, request: Request<CheckConfigRequest>) -> Result<Response<CheckConfigResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let content = request.into_inner().content;
+        // Reads certificates and zone files
+        let problems = tokio::task::spawn_blocking(move || match content.is_empty() {
+            true => configcheck::check_file(Path::new("Config.toml")),
+            false => configcheck::check(&content),
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?;
+        Ok(Response::new(CheckConfigResponse {
+            valid: problems.is_empty(),
+            problems: problems.into_iter().map(ConfigProblem::from).collect(),
+        }))
+    }
+
+    /// Streams record changes made through the control API until the client disconnects
+    /// or the server shuts down.
     ///
-    /// This method is invoked via gRPC with a `DeleteRecordRequest` and returns
-    /// a `ControlResponse` indicating success or failure.
-    async fn delete_record(
+    /// A subscriber that falls too far behind receives a `DATA_LOSS` error and should
+    /// re-read the records before watching again.
+    async fn watch_records(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<Self::WatchRecordsStream>, Status> {
+        auth::require(&request, Role::Read)?;
+        let receiver = self.state.read().await.subscribe();
+        let shutdown = self.shutdown.clone();
+        let stream = futures_util::stream::unfold(Some(receiver), move |receiver| {
+            let shutdown = shutdown.clone();
+            async move {
+                let mut receiver = receiver?;
+                let event = tokio::select! {
+                    event = receiver.recv() => event,
+                    _ = shutdown.requested() => return None,
+                };
+                match event {
+                    Ok(event) => Some((Ok(RecordEvent::from(event)), Some(receiver))),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => Some((
+                        Err(Status::data_loss(format!("watcher fell behind, {} events dropped", missed))),
+                        None,
+                    )),
+                    Err(broadcast::error::RecvError::Closed) => None,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    /// Reads the audit log entries made in a time range, oldest first.
+    async fn get_audit_log(
+        &self,
+        request: Request<GetAuditLogRequest>,
+    ) -> Result<Response<GetAuditLogResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let Some(audit) = &self.audit else {
+            return Err(error_status(&RdnsError::NotEnabled("the audit log is not enabled".into())));
+        };
+        let req = request.into_inner();
+        let since = time_bound(req.since, "since")?;
+        let until = time_bound(req.until, "until")?;
+        let entries = audit.read(since, until).await.map_err(|e| Status::internal(e.to_string()))?;
+
+        Ok(Response::new(GetAuditLogResponse {
+            entries: entries.into_iter().map(AuditEntry::from).collect(),
+        }))
+    }
+
+    /// Streams a snapshot of the whole state, taken under a single read lock, in chunks
+    /// of at most `chunk_size` bytes.
+    async fn backup_state(
+        &self,
+        request: Request<BackupStateRequest>,
+    ) -> Result<Response<Self::BackupStateStream>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let snapshot = self.state.read().await.snapshot().await;
+        let data = snapshot.encode().map_err(|e| Status::internal(e.to_string()))?;
+        let chunk_size = match req.chunk_size {
+            0 => data.len().max(1),
+            size => size as usize,
+        };
+        let chunks: Vec<SnapshotChunk> = data
+            .chunks(chunk_size)
+            .map(|chunk| SnapshotChunk { data: chunk.to_vec() })
+            .collect();
+        Ok(Response::new(Box::pin(futures_util::stream::iter(chunks.into_iter().map(Ok)))))
+    }
+
+    /// Replaces the primary zones and the runtime state with a snapshot sent as chunks,
+    /// as streamed by `BackupState`.
+    async fn restore_state(
         &self,
-        request: Request<DeleteRecordRequest>, 
+        request: Request<Streaming<SnapshotChunk>>,
     ) -> Result<Response<ControlResponse>, Status> {
-        // Extract request data
+        auth::require(&request, Role::Admin)?;
+        // Receive the whole snapshot first so the lock isn't held while waiting on the client
+        let mut stream = request.into_inner();
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.message().await? {
+            data.extend_from_slice(&chunk.data);
+        }
+
+        let result = match Snapshot::decode(&data) {
+            Ok(snapshot) => {
+                let zones = snapshot.zones.len();
+                let mut state = self.state.write().await;
+                state.restore(snapshot).await.map(|_| format!("Restored {} zones", zones))
+            }
+            Err(e) => Err(RdnsError::InvalidArgument(e.to_string())),
+        };
+        self.mutation("RestoreState", result)
+    }
+
+    /// Streams the changes made on this leader to a follower, starting with a full
+    /// snapshot of the state.
+    async fn replicate(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<Self::ReplicateStream>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let Some(cluster) = self.cluster.clone().filter(|cluster| cluster.options.role == ClusterRole::Leader) else {
+            return Err(error_status(&RdnsError::NotEnabled("this instance is not a cluster leader".into())));
+        };
+        let peer = request.remote_addr().map_or_else(|| "unknown".to_string(), |addr| addr.to_string());
+        let receiver = cluster::stream_changes(self.state.clone(), cluster, peer, self.shutdown.clone());
+        let stream = futures_util::stream::unfold(receiver, |mut receiver| async move {
+            let event = receiver.recv().await?;
+            Some((event, receiver))
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    /// Reports the role of this instance in the cluster and how far replication has got.
+    async fn cluster_status(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ClusterStatusResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let sequence = self.state.read().await.sequence();
+        let Some(cluster) = &self.cluster else {
+            return Ok(Response::new(ClusterStatusResponse {
+                role: "standalone".into(),
+                sequence,
+                ..Default::default()
+            }));
+        };
+        let response = match cluster.leader() {
+            None => ClusterStatusResponse {
+                role: "leader".into(),
+                sequence,
+                followers: cluster.followers().into_iter().map(ClusterFollower::from).collect(),
+                ..Default::default()
+            },
+            Some(leader) => {
+                let sync = cluster.sync_status();
+                ClusterStatusResponse {
+                    role: "follower".into(),
+                    sequence: sync.applied_sequence,
+                    followers: Vec::new(),
+                    leader: leader.to_string(),
+                    connected: sync.connected,
+                    leader_sequence: sync.leader_sequence,
+                    last_contact: sync.last_contact.map(Into::into),
+                    lag_ms: sync.lag().as_millis() as u64,
+                    error: sync.error.clone().unwrap_or_default(),
+                }
+            }
+        };
+        Ok(Response::new(response))
+    }
+
+    async fn get_server_stats(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<GetServerStatsResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let usage = state.record_usage();
+        let mut zones: Vec<ZoneUsage> = usage
+            .zones()
+            .map(|(origin, zone)| ZoneUsage {
+                origin: Name::from(origin.clone()).to_ascii(),
+                records: zone.records as u64,
+                estimated_bytes: zone.bytes as u64,
+            })
+            .collect();
+        zones.sort_by(|a, b| a.origin.cmp(&b.origin));
+        let cache = state.cache_usage().unwrap_or_default();
+        let quotas = state.quotas();
+        Ok(Response::new(GetServerStatsResponse {
+            zones,
+            records: usage.total().records as u64,
+            estimated_bytes: usage.total().bytes as u64,
+            max_records: quotas.and_then(|quotas| quotas.max_records).unwrap_or_default() as u64,
+            max_estimated_bytes: quotas.and_then(|quotas| quotas.max_bytes).unwrap_or_default() as u64,
+            cache_entries: cache.entries as u64,
+            cache_estimated_bytes: cache.bytes as u64,
+            started_at: Some(self.started.into()),
+            uptime_secs: self.started.elapsed().unwrap_or_default().as_secs(),
+            cache_negative_entries: cache.negative as u64,
+            upstreams: state
+                .upstream_stats()
+                .into_iter()
+                .map(|stats| UpstreamStats {
+                    upstream: stats.upstream,
+                    srtt_ms: stats.srtt.map_or(0.0, |srtt| srtt.as_secs_f64() * 1000.0),
+                    failure_rate: stats.failure_rate,
+                    answered: stats.answered,
+                    failed: stats.failed,
+                    down: stats.down,
+                })
+                .collect(),
+        }))
+    }
+
+    /// Reports the version, build and enabled features of the server, where it listens
+    /// and how many zones it serves.
+    async fn get_server_info(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ServerInfo>, Status> {
+        auth::require(&request, Role::Read)?;
+        let settings = self.reloader.settings().await;
+        let listeners = serverinfo::listeners(&settings)
+            .into_iter()
+            .map(|listener| ServerListener {
+                protocol: listener.protocol.to_string(),
+                address: listener.address,
+            })
+            .collect();
+        let zones = self.state.read().await.list_zones(None).await.len();
+        Ok(Response::new(ServerInfo {
+            version: serverinfo::VERSION.to_string(),
+            commit: serverinfo::COMMIT.to_string(),
+            features: serverinfo::features(&settings),
+            listeners,
+            zones: zones as u64,
+            started_at: Some(self.started.into()),
+            reloaded_at: self.reloader.reloaded_at().map(Into::into),
+        }))
+    }
+
+    /// Creates a tenant, optionally owning existing zones, whose tokens only manage the
+    /// zones it owns.
+    async fn create_tenant(
+        &self,
+        request: Request<CreateTenantRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
         let req = request.into_inner();
-        // Obtain write lock on DNS state to allow mutation
+        let entry = TenantEntry {
+            name: req.name,
+            max_zones: Some(req.max_zones as usize).filter(|&zones| zones > 0),
+            max_records: Some(req.max_records as usize).filter(|&records| records > 0),
+        };
+        let mut state = self.state.write().await;
+        let result = state.create_tenant(entry, &req.zones).await;
+        self.mutation("CreateTenant", result.map(|_| "Tenant created".to_string()))
+    }
+
+    /// Lists the tenants with the zones they own and the records those hold.
+    async fn list_tenants(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListTenantsResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let tenants = state
+            .list_tenants()
+            .await
+            .into_iter()
+            .map(|(info, zones, records)| Tenant {
+                name: info.entry.name,
+                zones,
+                max_zones: info.entry.max_zones.unwrap_or_default() as u32,
+                max_records: info.entry.max_records.unwrap_or_default() as u32,
+                records: records as u64,
+                configured: info.configured,
+            })
+            .collect();
+
+        Ok(Response::new(ListTenantsResponse { tenants }))
+    }
+
+    /// Publishes the digest of an ACME DNS-01 challenge at `_acme-challenge.<domain>`,
+    /// until it is cleared or its cleanup delay lapses.
+    async fn set_acme_challenge(
+        &self,
+        request: Request<SetAcmeChallengeRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let name = acme::challenge_name(&req.domain);
+        let mut state = self.state.write().await;
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &name) {
+            return self.mutation("SetAcmeChallenge", Err(e));
+        }
+        let change = Change::records(self.audit.as_deref(), &state, actor, "SetAcmeChallenge", &name, "TXT").await;
+        let result = self.acme.set_challenge(&mut state, &name, &req.value, req.ttl).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("SetAcmeChallenge", result.map(|clamped| with_clamped_ttl("Challenge set", clamped)))
+    }
+
+    /// Removes a challenge set with `SetAcmeChallenge`, or every challenge of a domain.
+    async fn clear_acme_challenge(
+        &self,
+        request: Request<ClearAcmeChallengeRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Admin)?;
+        let actor = actor(&request);
+        let req = request.into_inner();
+        let name = acme::challenge_name(&req.domain);
         let state = self.state.write().await;
-        // Attempt to delete the record
-        match state.delete_record(req.name).await {
-            Ok(_) => Ok(Response::new(ControlResponse {
-                success: true,
-                message: "Record deleted".into(),
-            })),
-            Err(e) => Ok(Response::new(ControlResponse {
-                success: false,
-                message: format!("Error: {}", e),
-            })),
+        if let Err(e) = state.check_tenant(tenant.as_deref(), &name) {
+            return self.mutation("ClearAcmeChallenge", Err(e));
         }
+        let change = Change::records(self.audit.as_deref(), &state, actor, "ClearAcmeChallenge", &name, "TXT").await;
+        let result = acme::clear_challenge(&state, &name, &req.value).await;
+        audit::finish(change, &state, &result).await;
+        self.mutation("ClearAcmeChallenge", result.map(|_| "Challenge cleared".to_string()))
     }
+}
 
-    async fn get_all_records(
+#[tonic::async_trait]
+impl ForwardingRules for ControlServer {
+    async fn add_forwarding_rule(
         &self,
-        _request: Request<Empty>,
-    ) -> Result<Response<GetAllRecordsResponse>, Status> {
+        request: Request<ForwardingRule>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let entry = ForwardRuleEntry {
+            domain: req.domain,
+            upstreams: req.upstreams,
+        };
+        let state = self.state.read().await;
+        let result = state.add_forward_rule(entry).await;
+        self.mutation("AddForwardingRule", result.map(|_| "Forwarding rule added".to_string()))
+    }
+
+    async fn delete_forwarding_rule(
+        &self,
+        request: Request<DeleteForwardingRuleRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.delete_forward_rule(&req.domain).await;
+        self.mutation("DeleteForwardingRule", result.map(|_| "Forwarding rule deleted".to_string()))
+    }
+
+    async fn list_forwarding_rules(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListForwardingRulesResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let rules = state.list_forward_rules().into_iter().map(ForwardingRule::from).collect();
+
+        Ok(Response::new(ListForwardingRulesResponse { rules }))
+    }
+}
+
+#[tonic::async_trait]
+impl RewriteRules for ControlServer {
+    async fn add_rewrite_rule(
+        &self,
+        request: Request<RewriteRule>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let entry = RewriteRuleEntry {
+            from: req.from,
+            to: req.to,
+            regex: req.regex,
+        };
+        let state = self.state.read().await;
+        let result = state.add_rewrite_rule(entry).await;
+        self.mutation("AddRewriteRule", result.map(|_| "Rewrite rule added".to_string()))
+    }
+
+    async fn delete_rewrite_rule(
+        &self,
+        request: Request<DeleteRewriteRuleRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
         let state = self.state.read().await;
-        let records = state.get_all_records().await;
+        let result = state.delete_rewrite_rule(&req.from).await;
+        self.mutation("DeleteRewriteRule", result.map(|_| "Rewrite rule deleted".to_string()))
+    }
+
+    async fn list_rewrite_rules(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListRewriteRulesResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let rules = state.list_rewrite_rules().into_iter().map(RewriteRule::from).collect();
+
+        Ok(Response::new(ListRewriteRulesResponse { rules }))
+    }
+}
+
+#[tonic::async_trait]
+impl QueryRules for ControlServer {
+    async fn add_query_rule(
+        &self,
+        request: Request<QueryRule>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let entry = QueryRuleEntry {
+            pattern: req.pattern,
+            match_type: req.match_type,
+            action: req.action,
+            values: req.values,
+            ttl: req.ttl,
+        };
+        let state = self.state.read().await;
+        let result = state.add_query_rule(entry).await;
+        self.mutation("AddQueryRule", result.map(|_| "Query rule added".to_string()))
+    }
+
+    async fn delete_query_rule(
+        &self,
+        request: Request<DeleteQueryRuleRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.delete_query_rule(&req.match_type, &req.pattern).await;
+        self.mutation("DeleteQueryRule", result.map(|_| "Query rule deleted".to_string()))
+    }
+
+    async fn list_query_rules(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ListQueryRulesResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let state = self.state.read().await;
+        let rules = state.list_query_rules().into_iter().map(QueryRule::from).collect();
+
+        Ok(Response::new(ListQueryRulesResponse { rules }))
+    }
+
+    async fn import_query_rules(
+        &self,
+        request: Request<ImportQueryRulesRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let state = self.state.read().await;
+        let result = state.import_query_rules(&req.content, req.replace).await;
+        self.mutation("ImportQueryRules", result.map(|count| format!("{} query rules imported", count)))
+    }
+}
 
-        let proto_records = records
+/// Entries `GetQueryStats` and `GetTopClients` list for the `top` of a request.
+pub fn stats_top(top: u32) -> usize {
+    match top {
+        0 => DEFAULT_STATS_TOP,
+        top => top as usize,
+    }
+}
+
+#[tonic::async_trait]
+impl Stats for ControlServer {
+    type WatchAnomaliesStream = Pin<Box<dyn Stream<Item = Result<AnomalyEvent, Status>> + Send>>;
+
+    async fn get_query_stats(
+        &self,
+        request: Request<GetQueryStatsRequest>,
+    ) -> Result<Response<GetQueryStatsResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let req = request.into_inner();
+        let name = |name: &str| match name {
+            "" => Ok(None),
+            name => DnsState::parse_name(name).map(|name| Some(LowerName::new(&name))),
+        };
+        let query = StatsQuery {
+            zone: name(&req.zone).map_err(|e| error_status(&e))?,
+            name: name(&req.name).map_err(|e| error_status(&e))?,
+            record_type: match req.record_type.as_str() {
+                "" => None,
+                record_type => Some(DnsState::parse_record_type(record_type).map_err(|e| error_status(&e))?),
+            },
+            window: (req.window_secs > 0).then(|| Duration::from_secs(req.window_secs)),
+            top: stats_top(req.top),
+        };
+        let state = self.state.read().await;
+        let (stats, since) = state.query_stats(&query, req.stale).await.map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(GetQueryStatsResponse {
+            entries: stats.into_iter().map(QueryStatsEntry::from).collect(),
+            since: Some(since.into()),
+        }))
+    }
+
+    async fn get_top_clients(
+        &self,
+        request: Request<GetTopClientsRequest>,
+    ) -> Result<Response<GetTopClientsResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        let req = request.into_inner();
+        let window = (req.window_secs > 0).then(|| Duration::from_secs(req.window_secs));
+        let state = self.state.read().await;
+        let (stats, since) = state.top_clients(window, stats_top(req.top)).map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(GetTopClientsResponse {
+            entries: stats.into_iter().map(ClientStatsEntry::from).collect(),
+            since: Some(since.into()),
+        }))
+    }
+
+    /// Streams the anomalies reported until the client disconnects or the server shuts
+    /// down. A subscriber that falls too far behind receives a `DATA_LOSS` error.
+    async fn watch_anomalies(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<Self::WatchAnomaliesStream>, Status> {
+        auth::require(&request, Role::Read)?;
+        let receiver = self.state.read().await.subscribe_anomalies().map_err(|e| error_status(&e))?;
+        let shutdown = self.shutdown.clone();
+        let stream = futures_util::stream::unfold(Some(receiver), move |receiver| {
+            let shutdown = shutdown.clone();
+            async move {
+                let mut receiver = receiver?;
+                let anomaly = tokio::select! {
+                    anomaly = receiver.recv() => anomaly,
+                    _ = shutdown.requested() => return None,
+                };
+                match anomaly {
+                    Ok(anomaly) => Some((Ok(AnomalyEvent::from(anomaly)), Some(receiver))),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => Some((
+                        Err(Status::data_loss(format!("watcher fell behind, {} anomalies dropped", missed))),
+                        None,
+                    )),
+                    Err(broadcast::error::RecvError::Closed) => None,
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for ControlServer {
+    /// Stops the server gracefully, as SIGTERM does. The call is answered before the
+    /// servers stop accepting work.
+    async fn shutdown(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let Some(trigger) = &self.shutdown_trigger else {
+            return Err(Status::failed_precondition("shutdown through the API is not available on this server"));
+        };
+        trigger.trigger();
+        self.metrics.observe_mutation("Shutdown", true);
+        Ok(Response::new(ControlResponse {
+            success: true,
+            message: "Shutting down".to_string(),
+            dry_run: None,
+        }))
+    }
+
+    /// Re-reads Config.toml as `ReloadConfig` does, then the configured zone files. A zone
+    /// file that fails to load keeps its zone as it was and is reported in the response.
+    async fn reload(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<ReloadResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let report = self.reloader.reload().await;
+        self.metrics.observe_mutation("Reload", report.is_ok());
+        let report = report.map_err(|e| error_status(&RdnsError::from(e)))?;
+        let mut message = report.summary();
+        let zone_files: Vec<ZoneFileReload> = self
+            .reloader
+            .reload_zone_files()
+            .await
             .into_iter()
-            .map(|(name, value, ttl)| DnsRecord { name, value, ttl })
+            .map(|(zone_file, result)| ZoneFileReload {
+                path: zone_file.path.display().to_string(),
+                records: *result.as_ref().unwrap_or(&0) as u64,
+                error: result.err().map(|e| e.to_string()).unwrap_or_default(),
+            })
             .collect();
+        if !zone_files.is_empty() {
+            let failed = zone_files.iter().filter(|zone_file| !zone_file.error.is_empty()).count();
+            message = format!("{}; reloaded {} of {} zone files", message, zone_files.len() - failed, zone_files.len());
+        }
+        Ok(Response::new(ReloadResponse {
+            message,
+            applied: report.applied,
+            restart_required: report.restart_required,
+            zone_files,
+        }))
+    }
 
-        Ok(Response::new(GetAllRecordsResponse {
-            records: proto_records,
+    /// Drains the instance in the mode requested, or ends draining.
+    async fn drain_mode(
+        &self,
+        request: Request<DrainModeRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let mode = DrainMode::from(request.get_ref().mode());
+        self.state.read().await.set_drain_mode(mode);
+        self.metrics.observe_mutation("DrainMode", true);
+        let message = match mode {
+            DrainMode::Off => "Draining ended".to_string(),
+            mode => format!("Draining in {} mode", mode),
+        };
+        println!("{}", message);
+        Ok(Response::new(ControlResponse {
+            success: true,
+            message,
+            dry_run: None,
+        }))
+    }
+
+    /// Sets the log level, for `duration_secs` if set.
+    async fn set_log_level(
+        &self,
+        request: Request<SetLogLevelRequest>,
+    ) -> Result<Response<ControlResponse>, Status> {
+        auth::require(&request, Role::Admin)?;
+        let req = request.into_inner();
+        let level: LogLevel = req.level.parse().map_err(|e: anyhow::Error| Status::invalid_argument(e.to_string()))?;
+        let message = match req.duration_secs {
+            0 => {
+                logging::set_level(level);
+                format!("Log level set to {}", level)
+            }
+            secs => {
+                logging::set_level_for(level, Duration::from_secs(secs));
+                format!("Log level set to {} for {}s", level, secs)
+            }
+        };
+        self.metrics.observe_mutation("SetLogLevel", true);
+        println!("{}", message);
+        Ok(Response::new(ControlResponse {
+            success: true,
+            message,
+            dry_run: None,
+        }))
+    }
+
+    async fn get_log_level(
+        &self,
+        request: Request<Empty>,
+    ) -> Result<Response<GetLogLevelResponse>, Status> {
+        auth::require(&request, Role::Read)?;
+        Ok(Response::new(GetLogLevelResponse {
+            level: logging::level().to_string(),
+            reverts_at: logging::reverts_at().map(Into::into),
+        }))
+    }
+}
+
+/// The v2 record API, answered from the same state as v1; changes go through the v1
+/// calls, so they are proposed, audited and counted alike.
+#[tonic::async_trait]
+impl v2::records_server::Records for ControlServer {
+    /// Returns a page of the records of a zone, or of every zone.
+    async fn list_records(
+        &self,
+        request: Request<v2::ListRecordsRequest>,
+    ) -> Result<Response<v2::ListRecordsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let query = dns::RecordQuery {
+            name_suffix: req.zone,
+            record_type: req.record_type,
+            tenant,
+            after: page_after(&req.page_token)?,
+            limit: match req.page_size as usize {
+                0 => DEFAULT_PAGE_SIZE,
+                size => size.min(MAX_PAGE_SIZE),
+            },
+            ..Default::default()
+        };
+
+        let state = self.state.read().await;
+        let page = state.query_records(&query).await.map_err(|e| error_status(&e))?;
+        let next_page_token = next_page_token(&page);
+
+        Ok(Response::new(v2::ListRecordsResponse {
+            total_size: page.total as u32,
+            records: page.records.into_iter().map(typed_record).collect(),
+            next_page_token,
+        }))
+    }
+
+    /// Returns the records of a name and type, or of every type.
+    async fn get_records(
+        &self,
+        request: Request<v2::GetRecordsRequest>,
+    ) -> Result<Response<v2::GetRecordsResponse>, Status> {
+        let tenant = auth::require_tenant(&request, Role::Read)?;
+        let req = request.into_inner();
+        let name = DnsState::parse_name(&req.name).map_err(|e| error_status(&e))?.to_ascii();
+        let query = dns::RecordQuery {
+            name_suffix: name.clone(),
+            record_type: req.record_type,
+            tenant,
+            limit: usize::MAX,
+            ..Default::default()
+        };
+
+        let state = self.state.read().await;
+        let page = state.query_records(&query).await.map_err(|e| error_status(&e))?;
+
+        Ok(Response::new(v2::GetRecordsResponse {
+            records: page
+                .records
+                .into_iter()
+                .filter(|record| record.name.eq_ignore_ascii_case(&name))
+                .map(typed_record)
+                .collect(),
         }))
     }
+
+    /// Adds a record as `AddRecord` of v1 does.
+    async fn add_record(
+        &self,
+        request: Request<v2::AddRecordRequest>,
+    ) -> Result<Response<v2::AddRecordResponse>, Status> {
+        let (metadata, extensions, req) = request.into_parts();
+        let record = req.record.ok_or_else(|| Status::invalid_argument("record is required"))?;
+        let (record_type, value) = zone_file_value(record.data)?;
+        let add = AddRecordRequest {
+            name: record.name,
+            value,
+            ttl: record.ttl,
+            record_type,
+            metadata: record.metadata.map(Into::into),
+            ..Default::default()
+        };
+        let response = DnsControl::add_record(self, Request::from_parts(metadata, extensions, add)).await?;
+        Ok(Response::new(v2::AddRecordResponse { message: v1_message(response)? }))
+    }
+
+    /// Deletes one record as `DeleteRecordValue` of v1 does.
+    async fn delete_record(
+        &self,
+        request: Request<v2::DeleteRecordRequest>,
+    ) -> Result<Response<v2::DeleteRecordResponse>, Status> {
+        let (metadata, extensions, req) = request.into_parts();
+        let record = req.record.ok_or_else(|| Status::invalid_argument("record is required"))?;
+        let (record_type, value) = zone_file_value(record.data)?;
+        let delete = DeleteRecordValueRequest {
+            name: record.name,
+            record_type,
+            value,
+        };
+        let response = DnsControl::delete_record_value(self, Request::from_parts(metadata, extensions, delete)).await?;
+        Ok(Response::new(v2::DeleteRecordResponse { message: v1_message(response)? }))
+    }
 }
 
-pub async fn run_grpc_server(service: ControlServer, options: GrpcOptions) -> anyhow::Result<()> {
-    let addr: SocketAddr = options.listen_addr.parse()?;
-    println!("gRPC server listening on {}", addr);
-    Server::builder()
-        .add_service(dns_control_server::DnsControlServer::new(service))
-        .serve(addr)
-        .await?;
+/// Starts the gRPC control server, over TLS when it is configured, along with the
+/// reflection and health services.
+///
+/// A `unix:` listen address serves the API on a Unix domain socket at that path instead
+/// of TCP, created with `unix_socket_mode` as its permissions and removed on shutdown. A
+/// socket left behind by a previous run is replaced.
+///
+/// The health service reports the control services as serving, and the server as a whole
+/// (the empty service name) as serving only while `dns_ready` is true, i.e. while the DNS
+/// listeners are bound, the instance isn't drained, and its own queries of the listeners
+/// are answered when they are probed. The server stops accepting calls once shutdown is
+/// requested and returns when the calls in flight have completed. Calls are limited as
+/// `limits` says, see `apilimit`.
+pub async fn run_grpc_server(
+    service: ControlServer,
+    options: GrpcOptions,
+    dns_ready: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let listener = GrpcListener::bind(&options).await?;
+    serve_grpc(service, options, listener, dns_ready).await
+}
+
+/// Runs the gRPC control server as `run_grpc_server` does, on `listener` bound beforehand.
+///
+/// # Errors
+///
+/// Returns an error if the TLS config can't be loaded or the server fails.
+pub async fn serve_grpc(
+    service: ControlServer,
+    options: GrpcOptions,
+    listener: GrpcListener,
+    mut dns_ready: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let addr = match &listener {
+        GrpcListener::Tcp(listener) => listener.local_addr()?.to_string(),
+        #[cfg(unix)]
+        GrpcListener::Unix(..) => options.listen_addr.clone(),
+    };
+    let mut builder = Server::builder();
+    match &options.tls {
+        Some(tls) => {
+            builder = builder.tls_config(tls.server_config().await?)?;
+            let auth = if tls.client_ca_path.is_some() { "mutual TLS" } else { "TLS" };
+            println!("gRPC server listening on {} ({})", addr, auth);
+        }
+        None => println!("gRPC server listening on {}", addr),
+    }
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()?;
+    let (mut reporter, health) = tonic_health::server::health_reporter();
+    reporter
+        .set_serving::<dns_control_server::DnsControlServer<ControlServer>>()
+        .await;
+    reporter
+        .set_serving::<forwarding_rules_server::ForwardingRulesServer<ControlServer>>()
+        .await;
+    reporter
+        .set_serving::<rewrite_rules_server::RewriteRulesServer<ControlServer>>()
+        .await;
+    reporter
+        .set_serving::<query_rules_server::QueryRulesServer<ControlServer>>()
+        .await;
+    reporter
+        .set_serving::<stats_server::StatsServer<ControlServer>>()
+        .await;
+    reporter
+        .set_serving::<admin_server::AdminServer<ControlServer>>()
+        .await;
+    reporter
+        .set_serving::<v2::records_server::RecordsServer<ControlServer>>()
+        .await;
+    let mut drain = service.state.read().await.drain_mode();
+    let mut probe = service.probe.clone();
+    tokio::spawn(async move {
+        loop {
+            let answered = probe.as_mut().is_none_or(|probe| *probe.borrow_and_update());
+            let serving = *dns_ready.borrow_and_update() && drain.borrow_and_update().healthy() && answered;
+            let status = match serving {
+                true => ServingStatus::Serving,
+                false => ServingStatus::NotServing,
+            };
+            reporter.set_service_status("", status).await;
+            let changed = tokio::select! {
+                changed = dns_ready.changed() => changed,
+                changed = drain.changed() => changed,
+                changed = async {
+                    match probe.as_mut() {
+                        Some(probe) => probe.changed().await,
+                        None => std::future::pending().await,
+                    }
+                } => changed,
+            };
+            if changed.is_err() {
+                break;
+            }
+        }
+    });
+
+    let interceptor = AuthInterceptor(service.auth.clone());
+    let shutdown = service.shutdown.clone();
+    let router = builder
+        .layer(LegacyPackageLayer)
+        .layer(TraceLayer(service.tracer.clone()))
+        .layer(LimitLayer::new(options.limits.clone(), service.metrics.clone()))
+        .add_service(reflection)
+        .add_service(health)
+        .add_service(forwarding_rules_server::ForwardingRulesServer::with_interceptor(service.clone(), interceptor.clone()))
+        .add_service(rewrite_rules_server::RewriteRulesServer::with_interceptor(service.clone(), interceptor.clone()))
+        .add_service(query_rules_server::QueryRulesServer::with_interceptor(service.clone(), interceptor.clone()))
+        .add_service(stats_server::StatsServer::with_interceptor(service.clone(), interceptor.clone()))
+        .add_service(admin_server::AdminServer::with_interceptor(service.clone(), interceptor.clone()))
+        .add_service(v2::records_server::RecordsServer::with_interceptor(service.clone(), interceptor.clone()))
+        .add_service(dns_control_server::DnsControlServer::with_interceptor(service, interceptor));
+    match listener {
+        GrpcListener::Tcp(listener) => {
+            let incoming = TcpIncoming::from_listener(listener, false, None).map_err(|e| anyhow::anyhow!(e))?;
+            router.serve_with_incoming_shutdown(incoming, shutdown.requested()).await?
+        }
+        #[cfg(unix)]
+        GrpcListener::Unix(path, listener) => {
+            let incoming = futures_util::stream::unfold(listener, |listener| async move {
+                let stream = listener.accept().await.map(|(stream, _)| stream);
+                Some((stream, listener))
+            });
+            let result = router.serve_with_incoming_shutdown(incoming, shutdown.requested()).await;
+            let _ = std::fs::remove_file(&path);
+            result?;
+        }
+    }
     Ok(())
 }
+
+/// Where the control API is served, bound before the server is started so that a port
+/// given as 0 can be told first.
+pub enum GrpcListener {
+    Tcp(TcpListener),
+    /// A Unix domain socket at the path, removed on shutdown
+    #[cfg(unix)]
+    Unix(PathBuf, UnixListener),
+}
+
+impl GrpcListener {
+    /// Binds the listen address of `options`, see `run_grpc_server`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the address is invalid or can't be bound.
+    pub async fn bind(options: &GrpcOptions) -> anyhow::Result<Self> {
+        match options.listen_addr.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Ok(GrpcListener::Unix(PathBuf::from(path), bind_unix(Path::new(path), options.unix_socket_mode)?)),
+            #[cfg(not(unix))]
+            Some(_) => anyhow::bail!("grpc.listen_addr can only be a unix: socket on unix"),
+            None => {
+                let addr: SocketAddr = options.listen_addr.parse()?;
+                Ok(GrpcListener::Tcp(TcpListener::bind(addr).await?))
+            }
+        }
+    }
+
+    /// The address the API is served on over TCP, with the port that was bound if given
+    /// as 0; `None` for a Unix domain socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            GrpcListener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            GrpcListener::Unix(..) => None,
+        }
+    }
+}
+
+/// Binds a Unix domain socket at `path` with the permissions in `mode`, replacing a
+/// socket left there by a previous run. Any other file at `path` is left alone.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: u32) -> anyhow::Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| anyhow::anyhow!("failed to bind {}: {}", path.display(), e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
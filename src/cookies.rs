@@ -0,0 +1,377 @@
+//! DNS cookies (RFC 7873).
+//!
+//! With `[dns.cookies]` configured, queries carrying a COOKIE option get a server cookie
+//! back, which the client returns with its later queries. Only the address a cookie was
+//! sent to can return it, so queries with a valid one aren't spoofed: they are exempt
+//! from rate limiting, and with `require_above_bytes` the other clients' UDP answers
+//! longer than that are truncated for them to retry over TCP, so that spoofed queries
+//! can't be turned into large answers aimed at their victims.
+//!
+//! Server cookies take the layout of RFC 9018: a version, a timestamp, and a hash of the
+//! client cookie, the timestamp and the client address under the server secret. The hash
+//! is HMAC-SHA256 truncated to 8 bytes rather than SipHash, so only rdns instances sharing
+//! `secret` accept each other's cookies. A cookie is valid for an hour, and a fresh one
+//! is sent with every response. The secret is replaced every `rotate_secs`, cookies of the
+//! previous one staying valid; secrets are derived from `secret` when one is set, so that
+//! the instances sharing it rotate together, and random otherwise.
+//!
+//! COOKIE options of the wrong length are answered with FORMERR (RFC 7873 section 5.2.2).
+
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use hickory_proto::rr::Record;
+use hickory_server::authority::MessageResponse;
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use ring::hmac;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::async_trait;
+
+use crate::auth;
+use crate::settings::CookieSettings;
+
+const CLIENT_COOKIE_LEN: usize = 8;
+/// Length of the server cookies sent; those of other servers may be 8 to 32 bytes.
+const SERVER_COOKIE_LEN: usize = 16;
+const VERSION: u8 = 1;
+/// Seconds a server cookie stays valid (RFC 9018 section 4.3).
+const LIFETIME: u64 = 3600;
+/// Seconds a cookie's timestamp may be ahead, for instances whose clocks differ.
+const CLOCK_SKEW: u64 = 300;
+
+/// Config options for DNS cookies
+#[derive(Clone)]
+pub struct CookieOptions {
+    /// How long each server secret is used for.
+    pub rotation: Duration,
+    /// Secret server secrets are derived from; they are random when unset.
+    pub secret: Option<Vec<u8>>,
+    /// Size UDP answers to clients without a valid server cookie are truncated to, if any.
+    pub require_above: Option<u16>,
+}
+
+impl TryFrom<CookieSettings> for CookieOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: CookieSettings) -> anyhow::Result<Self> {
+        if cfg.rotate_secs < LIFETIME {
+            anyhow::bail!("dns.cookies.rotate_secs must be at least {}, got {}", LIFETIME, cfg.rotate_secs);
+        }
+        if cfg.secret.as_ref().is_some_and(String::is_empty) {
+            anyhow::bail!("dns.cookies.secret must not be empty");
+        }
+        if let Some(bytes) = cfg.require_above_bytes.filter(|&bytes| bytes < 512) {
+            anyhow::bail!("dns.cookies.require_above_bytes must be at least 512, got {}", bytes);
+        }
+        Ok(CookieOptions {
+            rotation: Duration::from_secs(cfg.rotate_secs),
+            secret: cfg.secret.map(String::into_bytes),
+            require_above: cfg.require_above_bytes,
+        })
+    }
+}
+
+/// The COOKIE option of a query, as checked.
+pub enum Verdict {
+    /// The query has none.
+    Missing,
+    /// It has the wrong length, see the module docs.
+    Malformed,
+    /// It holds a client cookie alone, and the server cookie to answer with.
+    ClientOnly(EdnsOption),
+    /// Its server cookie isn't valid, or no longer is.
+    Invalid(EdnsOption),
+    /// Its server cookie is valid, so the query comes from the address it claims.
+    Valid(EdnsOption),
+}
+
+impl Verdict {
+    pub fn verified(&self) -> bool {
+        matches!(self, Verdict::Valid(_))
+    }
+
+    /// The COOKIE option to put in the response, if any.
+    pub fn reply(&self) -> Option<EdnsOption> {
+        match self {
+            Verdict::ClientOnly(reply) | Verdict::Invalid(reply) | Verdict::Valid(reply) => Some(reply.clone()),
+            Verdict::Missing | Verdict::Malformed => None,
+        }
+    }
+
+    /// Label the verdict is counted under, if it is.
+    pub fn label(&self) -> Option<&'static str> {
+        match self {
+            Verdict::Missing => None,
+            Verdict::Malformed => Some("malformed"),
+            Verdict::ClientOnly(_) => Some("client_only"),
+            Verdict::Invalid(_) => Some("invalid"),
+            Verdict::Valid(_) => Some("valid"),
+        }
+    }
+}
+
+/// Mints and validates server cookies.
+pub struct Cookies {
+    options: CookieOptions,
+    /// Secrets of the recent rotation periods, by period number.
+    secrets: Mutex<HashMap<u64, hmac::Key>>,
+}
+
+impl Cookies {
+    pub fn new(options: CookieOptions) -> Self {
+        Self {
+            options,
+            secrets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks the COOKIE option of `request`.
+    pub fn check(&self, request: &Request) -> Verdict {
+        let Some(option) = request.edns().and_then(|edns| edns.options().get(EdnsCode::Cookie)) else {
+            return Verdict::Missing;
+        };
+        // hickory-proto keeps the option as it was received
+        let EdnsOption::Unknown(_, cookie) = option else {
+            return Verdict::Malformed;
+        };
+        let (client, server) = match cookie.len() {
+            CLIENT_COOKIE_LEN => (&cookie[..], None),
+            16..=40 => (&cookie[..CLIENT_COOKIE_LEN], Some(&cookie[CLIENT_COOKIE_LEN..])),
+            _ => return Verdict::Malformed,
+        };
+        let ip = request.src().ip();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let reply = self.mint(client, ip, now);
+        match server {
+            None => Verdict::ClientOnly(reply),
+            Some(server) if self.is_valid(client, server, ip, now) => Verdict::Valid(reply),
+            Some(_) => Verdict::Invalid(reply),
+        }
+    }
+
+    /// The size UDP answers for a query with `verdict` must fit in, given the `limit` of
+    /// its transport, see `hardening::udp_limit`.
+    pub fn udp_limit(&self, verdict: &Verdict, limit: Option<u16>) -> Option<u16> {
+        match (limit, self.options.require_above) {
+            (Some(limit), Some(required)) if !verdict.verified() => Some(limit.min(required)),
+            _ => limit,
+        }
+    }
+
+    /// The COOKIE option holding `client` and a fresh server cookie for `ip`.
+    fn mint(&self, client: &[u8], ip: IpAddr, now: u64) -> EdnsOption {
+        // 32-bit timestamps last until 2106
+        let timestamp = now as u32;
+        let secret = self.secret(self.period(now), self.period(now));
+        let mut cookie = Vec::with_capacity(CLIENT_COOKIE_LEN + SERVER_COOKIE_LEN);
+        cookie.extend_from_slice(client);
+        cookie.extend_from_slice(&[VERSION, 0, 0, 0]);
+        cookie.extend_from_slice(&timestamp.to_be_bytes());
+        cookie.extend_from_slice(&hash(&secret, client, timestamp, ip));
+        EdnsOption::Unknown(u16::from(EdnsCode::Cookie), cookie)
+    }
+
+    /// Whether `server` is a cookie this server, or one sharing its secret, sent to `ip`
+    /// with `client` within the last hour.
+    fn is_valid(&self, client: &[u8], server: &[u8], ip: IpAddr, now: u64) -> bool {
+        if server.len() != SERVER_COOKIE_LEN || server[0] != VERSION {
+            return false;
+        }
+        let timestamp = u32::from_be_bytes([server[4], server[5], server[6], server[7]]);
+        let issued = u64::from(timestamp);
+        if issued > now + CLOCK_SKEW || now.saturating_sub(issued) > LIFETIME {
+            return false;
+        }
+        let secret = self.secret(self.period(issued), self.period(now));
+        auth::constant_time_eq(&hash(&secret, client, timestamp, ip), &server[8..])
+    }
+
+    /// The rotation period `secs` since the epoch lie in.
+    fn period(&self, secs: u64) -> u64 {
+        secs / self.options.rotation.as_secs().max(1)
+    }
+
+    /// The secret of rotation `period`, `current` being the period now. A random secret
+    /// drawn for a past period, such as one before a restart, validates no cookie.
+    fn secret(&self, period: u64, current: u64) -> hmac::Key {
+        let mut secrets = self.secrets.lock().unwrap();
+        secrets.retain(|&known, _| known + 1 >= current);
+        if let Some(secret) = secrets.get(&period) {
+            return secret.clone();
+        }
+        let seed = match &self.options.secret {
+            Some(shared) => hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, shared), &period.to_be_bytes())
+                .as_ref()
+                .to_vec(),
+            None => rand::random::<[u8; 32]>().to_vec(),
+        };
+        let secret = hmac::Key::new(hmac::HMAC_SHA256, &seed);
+        secrets.insert(period, secret.clone());
+        secret
+    }
+}
+
+/// The hash of a server cookie: `client`, the version, `timestamp` and `ip` under `secret`.
+fn hash(secret: &hmac::Key, client: &[u8], timestamp: u32, ip: IpAddr) -> [u8; 8] {
+    let mut context = hmac::Context::with_key(secret);
+    context.update(client);
+    context.update(&[VERSION, 0, 0, 0]);
+    context.update(&timestamp.to_be_bytes());
+    // IPv4 clients of dual-stack sockets arrive as mapped IPv6 addresses
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => context.update(&v4.octets()),
+        IpAddr::V6(v6) => context.update(&v6.octets()),
+    }
+    let mut hash = [0; 8];
+    hash.copy_from_slice(&context.sign().as_ref()[..8]);
+    hash
+}
+
+/// Response handler that adds the server cookie to the response's EDNS section.
+#[derive(Clone)]
+pub struct CookieResponseHandler<R> {
+    inner: R,
+    /// The option to add; responses are passed on as they are when unset.
+    cookie: Option<EdnsOption>,
+}
+
+impl<R> CookieResponseHandler<R> {
+    pub fn new(inner: R, cookie: Option<EdnsOption>) -> Self {
+        Self { inner, cookie }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for CookieResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        mut response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        if let (Some(cookie), Some(edns)) = (self.cookie.clone(), response.get_edns()) {
+            let mut edns = edns.clone();
+            edns.options_mut().insert(cookie);
+            response.set_edns(edns);
+        }
+        self.inner.send_response(response).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::op::{Edns, Message, Query};
+    use hickory_proto::rr::{Name, RecordType};
+    use hickory_proto::serialize::binary::BinDecodable;
+    use hickory_server::authority::MessageRequest;
+    use hickory_server::server::Protocol;
+    use std::net::SocketAddr;
+
+    const CLIENT: [u8; 8] = *b"clientck";
+    const NOW: u64 = 1_700_000_000;
+
+    fn cookies(secret: Option<&str>) -> Cookies {
+        Cookies::new(CookieOptions {
+            rotation: Duration::from_secs(86400),
+            secret: secret.map(|secret| secret.as_bytes().to_vec()),
+            require_above: Some(1232),
+        })
+    }
+
+    /// The server cookie `cookies` mints for `ip` at `now`.
+    fn server_cookie(cookies: &Cookies, ip: &str, now: u64) -> Vec<u8> {
+        match cookies.mint(&CLIENT, ip.parse().unwrap(), now) {
+            EdnsOption::Unknown(_, cookie) => cookie[CLIENT_COOKIE_LEN..].to_vec(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// A query from `src` with a COOKIE option holding `cookie`, if any.
+    fn query(cookie: Option<Vec<u8>>, src: &str) -> Request {
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_ascii("example.com.").unwrap(), RecordType::A));
+        let mut edns = Edns::new();
+        if let Some(cookie) = cookie {
+            edns.options_mut().insert(EdnsOption::Unknown(u16::from(EdnsCode::Cookie), cookie));
+        }
+        message.set_edns(edns);
+        let message = MessageRequest::from_bytes(&message.to_vec().unwrap()).unwrap();
+        Request::new(message, src.parse::<SocketAddr>().unwrap(), Protocol::Udp)
+    }
+
+    #[test]
+    fn validates_cookies_for_their_client_and_address_for_an_hour() {
+        let cookies = cookies(Some("shared"));
+        let server = server_cookie(&cookies, "192.0.2.1", NOW);
+        assert_eq!(server.len(), SERVER_COOKIE_LEN);
+        assert_eq!(server[..4], [VERSION, 0, 0, 0]);
+        let ip = "192.0.2.1".parse().unwrap();
+        assert!(cookies.is_valid(&CLIENT, &server, ip, NOW));
+        assert!(cookies.is_valid(&CLIENT, &server, ip, NOW + LIFETIME));
+        assert!(!cookies.is_valid(&CLIENT, &server, ip, NOW + LIFETIME + 1));
+        assert!(!cookies.is_valid(&CLIENT, &server, ip, NOW - CLOCK_SKEW - 1));
+        assert!(cookies.is_valid(&CLIENT, &server, "::ffff:192.0.2.1".parse().unwrap(), NOW));
+        assert!(!cookies.is_valid(&CLIENT, &server, "192.0.2.2".parse().unwrap(), NOW));
+        assert!(!cookies.is_valid(b"otherclt", &server, ip, NOW));
+        let mut tampered = server.clone();
+        tampered[15] ^= 1;
+        assert!(!cookies.is_valid(&CLIENT, &tampered, ip, NOW));
+    }
+
+    #[test]
+    fn shares_cookies_between_instances_with_the_same_secret() {
+        let ip = "2001:db8::1".parse().unwrap();
+        let minted = server_cookie(&cookies(Some("shared")), "2001:db8::1", NOW);
+        assert!(cookies(Some("shared")).is_valid(&CLIENT, &minted, ip, NOW + 60));
+        assert!(!cookies(Some("other")).is_valid(&CLIENT, &minted, ip, NOW + 60));
+        assert!(!cookies(None).is_valid(&CLIENT, &minted, ip, NOW + 60));
+
+        // Cookies minted just before a rotation stay valid after it
+        let rotated = 86400 * (NOW / 86400 + 1);
+        let minted = server_cookie(&cookies(Some("shared")), "2001:db8::1", rotated - 1);
+        assert!(cookies(Some("shared")).is_valid(&CLIENT, &minted, ip, rotated + 60));
+    }
+
+    #[test]
+    fn checks_the_cookie_option_of_queries() {
+        let cookies = cookies(None);
+        assert!(matches!(cookies.check(&query(None, "192.0.2.1:53000")), Verdict::Missing));
+        assert!(matches!(cookies.check(&query(Some(vec![1; 5]), "192.0.2.1:53000")), Verdict::Malformed));
+        assert!(matches!(cookies.check(&query(Some(vec![1; 41]), "192.0.2.1:53000")), Verdict::Malformed));
+
+        let Verdict::ClientOnly(reply) = cookies.check(&query(Some(CLIENT.to_vec()), "192.0.2.1:53000")) else {
+            panic!("a client cookie alone is answered with a server cookie");
+        };
+        let EdnsOption::Unknown(_, returned) = reply else {
+            unreachable!()
+        };
+        assert_eq!(returned[..CLIENT_COOKIE_LEN], CLIENT);
+        let valid = cookies.check(&query(Some(returned.clone()), "192.0.2.1:53001"));
+        assert!(valid.verified());
+        assert_eq!(cookies.udp_limit(&valid, Some(4096)), Some(4096));
+        let spoofed = cookies.check(&query(Some(returned), "192.0.2.2:53000"));
+        assert!(matches!(spoofed, Verdict::Invalid(_)));
+        assert_eq!(cookies.udp_limit(&spoofed, Some(4096)), Some(1232));
+        assert_eq!(cookies.udp_limit(&spoofed, None), None);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let settings = |rotate_secs, secret: Option<&str>, require_above_bytes| CookieSettings {
+            rotate_secs,
+            secret: secret.map(str::to_string),
+            require_above_bytes,
+        };
+        assert!(CookieOptions::try_from(settings(86400, Some("secret"), Some(1232))).is_ok());
+        assert!(CookieOptions::try_from(settings(LIFETIME - 1, None, None)).is_err());
+        assert!(CookieOptions::try_from(settings(86400, Some(""), None)).is_err());
+        assert!(CookieOptions::try_from(settings(86400, None, Some(511))).is_err());
+    }
+}
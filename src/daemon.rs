@@ -0,0 +1,392 @@
+//! Running as a service outside of containers.
+//!
+//! Under `[daemon]`, rdns can detach from the terminal for init systems without service
+//! supervision, e.g. SysV init scripts or `Type=forking` units: with `detach`, the
+//! process forks into the background in a session of its own before anything is started,
+//! and the command only exits once the servers are up, with 0, or with 1 and the error if
+//! they fail to start. The output of the detached process goes to `log_file`, appended
+//! to, and is discarded when that is unset. The working directory is kept, for the
+//! relative paths of Config.toml to hold.
+//!
+//! `pid_file` is written with the ID of the process serving, whether detached or not, and
+//! removed when it exits; a start is refused while the process it names still runs. Once
+//! privileges are dropped, the file is only removed if the directory holding it is
+//! writable by the unprivileged user.
+//!
+//! Under systemd `Type=notify` units, started without `detach`, the servers report
+//! `READY=1` to `NOTIFY_SOCKET` once they serve, and `STOPPING=1` when they shut down.
+//!
+//! On Windows, `detach` is replaced by running as a service: `rdns service` is the entry
+//! point the Service Control Manager starts, registered e.g. with
+//! `sc.exe create rdns binPath= "C:\rdns\rdns.exe service" start= auto`. As services
+//! start in the system directory, the working directory is set to the executable's, for
+//! the relative paths of Config.toml to hold. The service reports itself running once
+//! the servers are up, and a Stop control, or the machine shutting down, raises the
+//! shutdown signal as SIGTERM does elsewhere. Services have no console, so the output
+//! of rdns is discarded there.
+
+use std::fs::File;
+#[cfg(unix)]
+use std::fs::OpenOptions;
+#[cfg(unix)]
+use std::io::Read;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, FromRawFd};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::settings::DaemonSettings;
+
+/// Written by the detached process to the one waiting for it once it serves.
+const READY: &str = "ready";
+
+/// Config options for running as a service
+#[derive(Debug, Clone, Default)]
+pub struct DaemonOptions {
+    /// Whether to fork into the background.
+    pub detach: bool,
+    /// File the process ID is written to while it runs.
+    pub pid_file: Option<PathBuf>,
+    /// File the output of the detached process is appended to; discarded when unset.
+    pub log_file: Option<PathBuf>,
+}
+
+impl From<DaemonSettings> for DaemonOptions {
+    fn from(cfg: DaemonSettings) -> Self {
+        DaemonOptions {
+            detach: cfg.detach,
+            pid_file: cfg.pid_file.filter(|path| !path.is_empty()).map(PathBuf::from),
+            log_file: cfg.log_file.filter(|path| !path.is_empty()).map(PathBuf::from),
+        }
+    }
+}
+
+/// The service the process runs as, reporting on its start and stop.
+pub struct Daemon {
+    /// Pipe to the process waiting for a detached one to start, until it is told
+    starting: Mutex<Option<File>>,
+    pid_file: Option<PathBuf>,
+}
+
+impl Daemon {
+    /// Detaches and writes the PID file as configured. Must be called before any thread
+    /// is started, as only the calling thread carries on in the detached process; the
+    /// process started from the command line exits once the detached one reports.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the PID file names a process still running, or it can't be written.
+    pub fn start(options: DaemonOptions) -> anyhow::Result<Self> {
+        if let Some(pid) = options.pid_file.as_deref().and_then(running_pid) {
+            anyhow::bail!("rdns is already running as process {}", pid);
+        }
+        let starting = match options.detach {
+            true => Some(detach(options.log_file.as_deref())?),
+            false => None,
+        };
+        let daemon = Daemon {
+            starting: Mutex::new(starting),
+            pid_file: options.pid_file,
+        };
+        if let Some(path) = &daemon.pid_file {
+            let written = write_pid_file(path);
+            if let Err(e) = written {
+                let e = anyhow::anyhow!("can't write the PID file {}: {}", path.display(), e);
+                daemon.failed(&e);
+                return Err(e);
+            }
+        }
+        Ok(daemon)
+    }
+
+    /// Reports the servers up: to the process waiting for a detached one, and to systemd
+    /// or the Windows Service Control Manager.
+    pub fn ready(&self) {
+        if let Some(mut starting) = self.starting.lock().unwrap().take() {
+            let _ = starting.write_all(READY.as_bytes());
+        }
+        #[cfg(unix)]
+        notify_systemd("READY=1\nSTATUS=Serving");
+        #[cfg(windows)]
+        service::report(windows_service::service::ServiceState::Running);
+    }
+
+    /// Reports that the servers failed to start, to the process waiting for a detached one.
+    pub fn failed(&self, error: &anyhow::Error) {
+        if let Some(mut starting) = self.starting.lock().unwrap().take() {
+            let _ = write!(starting, "{:#}", error);
+        }
+    }
+
+    /// Reports the servers shutting down, to systemd or the Windows Service Control
+    /// Manager.
+    pub fn stopping(&self) {
+        #[cfg(unix)]
+        notify_systemd("STOPPING=1\nSTATUS=Shutting down");
+        #[cfg(windows)]
+        service::report(windows_service::service::ServiceState::StopPending);
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        if let Some(path) = &self.pid_file {
+            if let Err(e) = std::fs::remove_file(path) {
+                eprintln!("Failed to remove the PID file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Forks into the background, in a session of its own with its output sent to
+/// `log_file`. Only returns in the detached process, with the pipe to the waiting one;
+/// the waiting process exits once the detached one reports how it started.
+#[cfg(unix)]
+fn detach(log_file: Option<&Path>) -> anyhow::Result<File> {
+    // Opened ahead of the fork for failures to be reported from the terminal
+    let output = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("can't open the log file {}: {}", path.display(), e))?,
+        None => OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let input = File::open("/dev/null")?;
+    let mut fds = [0; 2];
+    // Safety: `fds` has room for the two descriptors `pipe` writes, which are owned by
+    // the files made of them from then on
+    let (mut waiting, starting) = unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1]))
+    };
+    // Safety: no other thread runs yet, as `start` requires
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error().into()),
+        0 => {
+            drop(waiting);
+            // Safety: these calls only take descriptors owned by the files above
+            unsafe {
+                if libc::setsid() == -1 {
+                    return Err(std::io::Error::last_os_error().into());
+                }
+                libc::dup2(input.as_raw_fd(), libc::STDIN_FILENO);
+                libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO);
+                libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO);
+            }
+            Ok(starting)
+        }
+        pid => {
+            drop(starting);
+            let mut report = String::new();
+            let _ = waiting.read_to_string(&mut report);
+            match report.as_str() {
+                READY => {
+                    println!("rdns started as process {}", pid);
+                    std::process::exit(0)
+                }
+                "" => eprintln!("rdns exited before it started"),
+                error => eprintln!("rdns failed to start: {}", error),
+            }
+            std::process::exit(1)
+        }
+    }
+}
+
+#[cfg(windows)]
+fn detach(_log_file: Option<&Path>) -> anyhow::Result<File> {
+    anyhow::bail!("daemon.detach is only supported on unix; on Windows, run rdns as a service with `rdns service`")
+}
+
+/// ID of the process the PID file at `path` names, if it still runs.
+#[cfg(unix)]
+fn running_pid(path: &Path) -> Option<i32> {
+    let pid: i32 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    // Safety: signal 0 only checks that the process exists
+    let exists = unsafe { libc::kill(pid, 0) == 0 } || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+    (pid > 0 && exists).then_some(pid)
+}
+
+/// ID of the process the PID file at `path` names, if it still runs.
+#[cfg(windows)]
+fn running_pid(path: &Path) -> Option<u32> {
+    use windows_sys::Win32::Foundation::{CloseHandle, STILL_ACTIVE};
+    use windows_sys::Win32::System::Threading::{GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION};
+
+    let pid: u32 = std::fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    // Safety: the handle is only used to read the exit code, and closed right after
+    let running = unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if process == 0 {
+            return None;
+        }
+        let mut code = 0;
+        let read = GetExitCodeProcess(process, &mut code);
+        CloseHandle(process);
+        read != 0 && code == STILL_ACTIVE as u32
+    };
+    (pid > 0 && running).then_some(pid)
+}
+
+/// Writes the ID of this process to the PID file at `path`, replacing it atomically.
+fn write_pid_file(path: &Path) -> std::io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, format!("{}\n", std::process::id()))?;
+    std::fs::rename(&tmp, path)
+}
+
+/// Sends `state` to the systemd notification socket, if the service manager set one.
+#[cfg(unix)]
+fn notify_systemd(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let Ok(socket) = UnixDatagram::unbound() else {
+        return;
+    };
+    let sent = match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            std::os::unix::net::SocketAddr::from_abstract_name(name).and_then(|addr| socket.send_to_addr(state.as_bytes(), &addr))
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return,
+        None => socket.send_to(state.as_bytes(), &path),
+    };
+    if let Err(e) = sent {
+        eprintln!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Runs `serve` as a Windows service; see the module documentation.
+///
+/// # Errors
+///
+/// Returns an error if the process wasn't started by the Service Control Manager.
+#[cfg(windows)]
+pub fn run_service(serve: fn() -> anyhow::Result<()>) -> anyhow::Result<()> {
+    service::run(serve)
+}
+
+/// Resolves once the Windows Service Control Manager asks the service to stop, with the
+/// name of the control received.
+#[cfg(windows)]
+pub async fn service_stop() -> &'static str {
+    service::stop().await
+}
+
+#[cfg(windows)]
+mod service {
+    use std::ffi::OsString;
+    use std::sync::{Mutex, OnceLock};
+    use std::time::Duration;
+    use tokio::sync::Notify;
+    use windows_service::service::{ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus, ServiceType};
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult, ServiceStatusHandle};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    /// Name the service is registered under.
+    const SERVICE_NAME: &str = "rdns";
+
+    /// Runs the servers, as the binary does when started from the command line.
+    static SERVE: OnceLock<fn() -> anyhow::Result<()>> = OnceLock::new();
+    /// Reports the state of the service, once it is registered.
+    static STATUS: Mutex<Option<ServiceStatusHandle>> = Mutex::new(None);
+    /// The stop control received, waking `stop`.
+    static STOP: Mutex<Option<&'static str>> = Mutex::new(None);
+    static STOPPED: Notify = Notify::const_new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    pub(super) fn run(serve: fn() -> anyhow::Result<()>) -> anyhow::Result<()> {
+        let _ = SERVE.set(serve);
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .map_err(|e| anyhow::anyhow!("can't run as a Windows service, `rdns service` must be started by the Service Control Manager: {}", e))
+    }
+
+    pub(super) async fn stop() -> &'static str {
+        loop {
+            let notified = STOPPED.notified();
+            if let Some(control) = *STOP.lock().unwrap() {
+                return control;
+            }
+            notified.await;
+        }
+    }
+
+    /// Reports the service in `state` to the Service Control Manager, if it runs as one.
+    pub(super) fn report(state: ServiceState) {
+        report_exit(state, ServiceExitCode::Win32(0));
+    }
+
+    fn report_exit(state: ServiceState, exit_code: ServiceExitCode) {
+        let Some(status) = *STATUS.lock().unwrap() else {
+            return;
+        };
+        let controls_accepted = match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        let result = status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code,
+            checkpoint: 0,
+            // Starting and stopping are given as long as they take, the drain included
+            wait_hint: Duration::from_secs(60),
+            process_id: None,
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to report the service status: {}", e);
+        }
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let handler = |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let name = match control {
+                    ServiceControl::Stop => "SERVICE_CONTROL_STOP",
+                    _ => "SERVICE_CONTROL_SHUTDOWN",
+                };
+                *STOP.lock().unwrap() = Some(name);
+                STOPPED.notify_waiters();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        match service_control_handler::register(SERVICE_NAME, handler) {
+            Ok(status) => *STATUS.lock().unwrap() = Some(status),
+            Err(e) => {
+                eprintln!("Failed to register the service control handler: {}", e);
+                return;
+            }
+        }
+        report(ServiceState::StartPending);
+        // Services start in the system directory, and the paths of Config.toml are
+        // relative to the executable's
+        if let Some(dir) = std::env::current_exe().ok().as_deref().and_then(std::path::Path::parent) {
+            if let Err(e) = std::env::set_current_dir(dir) {
+                eprintln!("Failed to change to {}: {}", dir.display(), e);
+            }
+        }
+        let result = SERVE.get().map_or(Ok(()), |serve| serve());
+        let exit_code = match &result {
+            Ok(()) => ServiceExitCode::Win32(0),
+            Err(e) => {
+                eprintln!("rdns failed: {:#}", e);
+                ServiceExitCode::ServiceSpecific(1)
+            }
+        };
+        report_exit(ServiceState::Stopped, exit_code);
+    }
+}
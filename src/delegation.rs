@@ -0,0 +1,52 @@
+//! Delegations of child zones.
+//!
+//! NS records at a name below a zone's apex delegate that name, and everything under it,
+//! to the listed nameservers. Queries for delegated names are answered with a referral
+//! rather than from the zone: a non-authoritative response with no answers, the NS (and
+//! any DS) records of the delegation in the authority section and the addresses of the
+//! nameservers inside the zone, its glue, in the additional section. DS queries for the
+//! delegated name itself are still answered by the parent, which holds those records.
+//!
+//! Glue records are added like any others, e.g. an A record for `ns1.child.example.com`
+//! next to `child.example.com NS ns1.child.example.com.`; queries for them are referred
+//! too, as the child zone is authoritative for them.
+
+use hickory_proto::op::{Edns, Header, MessageType, ResponseCode};
+use hickory_proto::rr::Record;
+use hickory_server::authority::MessageResponseBuilder;
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+
+/// A referral to the nameservers of a delegated child zone.
+#[derive(Debug, Clone, Default)]
+pub struct Referral {
+    /// NS records at the delegation point, followed by its DS records if it has any
+    pub name_servers: Vec<Record>,
+    /// Addresses of the nameservers that lie inside the parent zone
+    pub glue: Vec<Record>,
+}
+
+/// Answers `request` with `referral`.
+pub async fn send_referral<R: ResponseHandler>(request: &Request, referral: &Referral, mut response_handle: R) -> ResponseInfo {
+    let mut header = Header::response_from_request(request.header());
+    header.set_message_type(MessageType::Response);
+    header.set_authoritative(false);
+    header.set_response_code(ResponseCode::NoError);
+
+    let mut builder = MessageResponseBuilder::from_message_request(request);
+    if let Some(request_edns) = request.edns() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(request_edns.max_payload().max(512));
+        edns.set_dnssec_ok(request_edns.dnssec_ok());
+        builder.edns(edns);
+    }
+    let response = builder.build(header, [], referral.name_servers.iter(), [], referral.glue.iter());
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("Failed to send response: {}", e);
+            let mut header = Header::new();
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        }
+    }
+}
@@ -0,0 +1,517 @@
+//! Authenticated denial of existence in signed zones.
+//!
+//! How a signed zone proves that a name or type doesn't exist is chosen with
+//! `dns.dnssec.denial`, for all signed zones or per zone in `[[dns.dnssec.zone_denial]]`:
+//!
+//! - `nsec` (the default) chains the zone's names with NSEC records, signed along with the
+//!   zone. It is the cheapest, but lets anyone list the zone by walking the chain.
+//! - `nsec3` (RFC 5155) chains the hashes of the names instead, salted with `nsec3_salt`
+//!   and hashed `nsec3_iterations` extra times, so that the names have to be guessed. With
+//!   `nsec3_opt_out` unsigned delegations are left out of the chain, which keeps it small
+//!   in zones with many of them. RFC 9276 recommends no salt and no extra iterations, as
+//!   they cost resolvers more than they cost zone walkers.
+//! - `white-lies` (RFC 4470) keeps no chain at all: each negative answer carries NSEC
+//!   records covering just the name queried and the wildcard that would have matched it,
+//!   signed when the answer is sent. Nothing can be walked, at the cost of signing on
+//!   every negative answer to a DNSSEC query.
+//!
+//! The NSEC3 chain is rebuilt whenever the zone is signed again; the proofs of both
+//! `nsec3` and `white-lies` are added to NXDOMAIN and NODATA answers to queries with the
+//! DO bit as they are sent.
+
+use data_encoding::BASE32HEX_NOPAD;
+use hickory_proto::op::{OpCode, ResponseCode};
+use hickory_proto::rr::dnssec::rdata::{DNSSECRData, NSEC, NSEC3, NSEC3PARAM, RRSIG};
+use hickory_proto::rr::dnssec::{tbs, Nsec3HashAlgorithm, SigSigner, SupportedAlgorithms};
+use hickory_proto::rr::{DNSClass, LowerName, Name, RData, Record, RecordSet, RecordType, RrKey};
+use hickory_server::authority::{Authority, DnssecAuthority, MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use hickory_server::store::in_memory::InMemoryAuthority;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tonic::async_trait;
+
+use crate::dnssec;
+use crate::roundrobin;
+use crate::settings::{DnssecSettings, ZoneDenialSettings};
+
+/// Extra NSEC3 hash iterations allowed at most; validators treat zones with more as
+/// unsigned (RFC 9276).
+const MAX_ITERATIONS: u16 = 100;
+
+/// How far back online signatures are dated, for validators whose clocks lag.
+const INCEPTION_SKEW: Duration = Duration::from_secs(60 * 60);
+
+/// NSEC3 chain parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nsec3Params {
+    pub salt: Vec<u8>,
+    pub iterations: u16,
+    /// Whether unsigned delegations are left out of the chain
+    pub opt_out: bool,
+}
+
+/// How a signed zone denies the existence of names and types.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Denial {
+    Nsec,
+    Nsec3(Nsec3Params),
+    WhiteLies,
+}
+
+impl Denial {
+    fn parse(
+        mode: &str,
+        salt: &str,
+        iterations: u16,
+        opt_out: bool,
+    ) -> anyhow::Result<Self> {
+        match mode.to_ascii_lowercase().as_str() {
+            "nsec" => Ok(Denial::Nsec),
+            "nsec3" => {
+                let salt = data_encoding::HEXLOWER_PERMISSIVE
+                    .decode(salt.as_bytes())
+                    .map_err(|e| anyhow::anyhow!("invalid dns.dnssec nsec3_salt {}: {}", salt, e))?;
+                if salt.len() > 255 {
+                    anyhow::bail!("dns.dnssec nsec3_salt is longer than 255 bytes");
+                }
+                if iterations > MAX_ITERATIONS {
+                    anyhow::bail!("dns.dnssec nsec3_iterations {} is above {}, which validators treat as unsigned", iterations, MAX_ITERATIONS);
+                }
+                Ok(Denial::Nsec3(Nsec3Params { salt, iterations, opt_out }))
+            }
+            "white-lies" => Ok(Denial::WhiteLies),
+            _ => anyhow::bail!("unknown dns.dnssec denial {}, expected nsec, nsec3 or white-lies", mode),
+        }
+    }
+}
+
+/// The denial of every signed zone and the zones whose own differs.
+pub fn parse(cfg: &DnssecSettings) -> anyhow::Result<(Denial, HashMap<LowerName, Denial>)> {
+    let default = Denial::parse(&cfg.denial, &cfg.nsec3_salt, cfg.nsec3_iterations, cfg.nsec3_opt_out)?;
+    let mut zones = HashMap::new();
+    for ZoneDenialSettings { zone, denial, nsec3_salt, nsec3_iterations, nsec3_opt_out } in &cfg.zone_denial {
+        let mut name = Name::from_ascii(zone).map_err(|e| anyhow::anyhow!("invalid dns.dnssec.zone_denial zone {}: {}", zone, e))?;
+        name.set_fqdn(true);
+        let denial = Denial::parse(
+            denial.as_deref().unwrap_or(&cfg.denial),
+            nsec3_salt.as_deref().unwrap_or(&cfg.nsec3_salt),
+            nsec3_iterations.unwrap_or(cfg.nsec3_iterations),
+            nsec3_opt_out.unwrap_or(cfg.nsec3_opt_out),
+        )?;
+        zones.insert(LowerName::new(&name), denial);
+    }
+    Ok((default, zones))
+}
+
+/// What the negative answers of a signed zone are proven with.
+pub struct ZoneDenial {
+    origin: Name,
+    denial: Denial,
+    /// The types at each authoritative name, empty for empty non-terminals
+    names: HashMap<LowerName, Vec<RecordType>>,
+    /// The signed NSEC3 records of the chain, by hashed owner name
+    chain: BTreeMap<Vec<u8>, Arc<RecordSet>>,
+    /// TTL of the denial records, the zone's negative caching TTL
+    ttl: u32,
+    /// Keys white lies are signed with
+    signers: Arc<Vec<SigSigner>>,
+}
+
+/// The denial of the signed zones that don't use NSEC, shared with the request handler.
+#[derive(Default)]
+pub struct Denials {
+    zones: RwLock<HashMap<LowerName, Arc<ZoneDenial>>>,
+    /// Keys white lies of each zone are signed with, registered when its keys are loaded
+    signers: Mutex<HashMap<LowerName, Arc<Vec<SigSigner>>>>,
+}
+
+impl Denials {
+    /// Registers the keys white lies of the zone at `origin` are signed with.
+    pub fn set_signers(&self, origin: &LowerName, signers: Vec<SigSigner>) {
+        self.signers.lock().unwrap().insert(origin.clone(), Arc::new(signers));
+    }
+
+    /// Drops the denial of a zone that was deleted or is no longer signed.
+    pub fn remove(&self, origin: &LowerName) {
+        self.zones.write().unwrap().remove(origin);
+        self.signers.lock().unwrap().remove(origin);
+    }
+
+    /// The denial of the zone holding the name `request` queries for, if the request
+    /// asks for DNSSEC records and the zone doesn't use NSEC.
+    pub fn for_request(&self, request: &Request) -> Option<Arc<ZoneDenial>> {
+        if request.op_code() != OpCode::Query || !request.edns().is_some_and(|edns| edns.dnssec_ok()) {
+            return None;
+        }
+        let zones = self.zones.read().unwrap();
+        if zones.is_empty() {
+            return None;
+        }
+        let mut name = request.query().name().clone();
+        loop {
+            if let Some(zone) = zones.get(&name) {
+                return Some(zone.clone());
+            }
+            if name.is_root() {
+                return None;
+            }
+            name = name.base_name();
+        }
+    }
+
+    /// Signs the zone of `authority`, with NSEC3 records in place of NSEC ones, or none
+    /// at all for white lies, as `denial` says.
+    pub async fn secure(&self, authority: &InMemoryAuthority, denial: &Denial) -> anyhow::Result<()> {
+        let origin = authority.origin().clone();
+        if *denial == Denial::Nsec {
+            authority.secure_zone().await?;
+            self.zones.write().unwrap().remove(&origin);
+            return Ok(());
+        }
+        let serial = authority.serial().await;
+        let (names, ttl) = {
+            let mut records = authority.records_mut().await;
+            records.retain(|key, _| !matches!(key.record_type, RecordType::NSEC3 | RecordType::NSEC3PARAM));
+            let names = names(&origin, &records);
+            let ttl = negative_ttl(&origin, &records);
+            if let Denial::Nsec3(params) = denial {
+                let rdata = NSEC3PARAM::new(Nsec3HashAlgorithm::SHA1, false, params.iterations, params.salt.clone());
+                let record = Record::from_rdata(origin.clone().into(), ttl, RData::DNSSEC(DNSSECRData::NSEC3PARAM(rdata)));
+                let mut rrset = RecordSet::new(&origin.clone().into(), RecordType::NSEC3PARAM, serial);
+                rrset.insert(record, serial);
+                records.insert(RrKey::new(origin.clone(), RecordType::NSEC3PARAM), Arc::new(rrset));
+                for (key, rrset) in nsec3_chain(&origin.clone().into(), &names, params, ttl, serial)? {
+                    records.insert(key, Arc::new(rrset));
+                }
+            }
+            (names, ttl)
+        };
+        // The zone is signed with its NSEC chain, which is then dropped
+        authority.secure_zone().await?;
+        let mut chain = BTreeMap::new();
+        {
+            let mut records = authority.records_mut().await;
+            records.retain(|key, _| key.record_type != RecordType::NSEC);
+            for (key, rrset) in records.iter().filter(|(key, _)| key.record_type == RecordType::NSEC3) {
+                if let Some(hash) = hash_of(&Name::from(&key.name)) {
+                    chain.insert(hash, rrset.clone());
+                }
+            }
+        }
+        let signers = self.signers.lock().unwrap().get(&origin).cloned().unwrap_or_default();
+        let zone = ZoneDenial {
+            origin: origin.clone().into(),
+            denial: denial.clone(),
+            names,
+            chain,
+            ttl,
+            signers,
+        };
+        self.zones.write().unwrap().insert(origin, Arc::new(zone));
+        Ok(())
+    }
+}
+
+/// The authoritative names of a zone with the types at each, adding the empty
+/// non-terminals and leaving out the names below delegations and the denial records and
+/// signatures.
+fn names(origin: &LowerName, records: &BTreeMap<RrKey, Arc<RecordSet>>) -> HashMap<LowerName, Vec<RecordType>> {
+    let cuts: Vec<&LowerName> = records
+        .keys()
+        .filter(|key| key.record_type == RecordType::NS && key.name != *origin)
+        .map(|key| &key.name)
+        .collect();
+    let mut names: HashMap<LowerName, Vec<RecordType>> = HashMap::new();
+    for key in records.keys().filter(|key| key.record_type == RecordType::DNSKEY || !dnssec::is_generated(key.record_type)) {
+        if cuts.iter().any(|cut| **cut != key.name && cut.zone_of(&key.name)) {
+            continue;
+        }
+        names.entry(key.name.clone()).or_default().push(key.record_type);
+        let mut ancestor = key.name.clone();
+        while ancestor != *origin && !ancestor.is_root() {
+            ancestor = ancestor.base_name();
+            names.entry(ancestor.clone()).or_default();
+        }
+    }
+    names
+}
+
+/// The zone's negative caching TTL, the lower of its SOA's TTL and minimum.
+fn negative_ttl(origin: &LowerName, records: &BTreeMap<RrKey, Arc<RecordSet>>) -> u32 {
+    records
+        .get(&RrKey::new(origin.clone(), RecordType::SOA))
+        .and_then(|rrset| {
+            let soa = rrset.records_without_rrsigs().next()?;
+            let minimum = soa.data()?.as_soa()?.minimum();
+            Some(soa.ttl().min(minimum))
+        })
+        .unwrap_or(0)
+}
+
+/// The hash of `name` with the chain's parameters.
+fn hash(name: &Name, params: &Nsec3Params) -> anyhow::Result<Vec<u8>> {
+    Ok(Nsec3HashAlgorithm::SHA1.hash(&params.salt, name, params.iterations)?.as_ref().to_vec())
+}
+
+/// The hash an NSEC3 owner name stands for.
+fn hash_of(owner: &Name) -> Option<Vec<u8>> {
+    let label = owner.iter().next()?;
+    BASE32HEX_NOPAD.decode(&label.to_ascii_uppercase()).ok()
+}
+
+/// The NSEC3 owner name of `hash` in the zone at `origin`.
+fn owner_of(hash: &[u8], origin: &Name) -> anyhow::Result<Name> {
+    let label = BASE32HEX_NOPAD.encode(hash).to_ascii_lowercase();
+    Ok(Name::from_ascii(label)?.append_domain(origin)?)
+}
+
+/// The unsigned NSEC3 records chaining the hashes of `names`.
+fn nsec3_chain(
+    origin: &Name,
+    names: &HashMap<LowerName, Vec<RecordType>>,
+    params: &Nsec3Params,
+    ttl: u32,
+    serial: u32,
+) -> anyhow::Result<Vec<(RrKey, RecordSet)>> {
+    let mut hashes = BTreeMap::new();
+    for (name, types) in names {
+        // Opt-out leaves out the delegations that have no DS record
+        let delegation = types.contains(&RecordType::NS) && Name::from(name) != *origin;
+        if params.opt_out && delegation && !types.contains(&RecordType::DS) {
+            continue;
+        }
+        let mut types = types.clone();
+        if !types.is_empty() && (!delegation || types.contains(&RecordType::DS)) {
+            types.push(RecordType::RRSIG);
+        }
+        if Name::from(name) == *origin {
+            types.push(RecordType::NSEC3PARAM);
+        }
+        hashes.insert(hash(&name.into(), params)?, types);
+    }
+    let Some(first) = hashes.keys().next().cloned() else {
+        return Ok(Vec::new());
+    };
+    let nexts = hashes.keys().skip(1).cloned().chain([first]).collect::<Vec<_>>();
+    let mut chain = Vec::with_capacity(hashes.len());
+    for ((hash, types), next) in hashes.into_iter().zip(nexts) {
+        let owner = owner_of(&hash, origin)?;
+        let rdata = NSEC3::new(Nsec3HashAlgorithm::SHA1, params.opt_out, params.iterations, params.salt.clone(), next, types);
+        let record = Record::from_rdata(owner.clone(), ttl, RData::DNSSEC(DNSSECRData::NSEC3(rdata)));
+        let mut rrset = RecordSet::new(&owner, RecordType::NSEC3, serial);
+        rrset.insert(record, serial);
+        chain.push((RrKey::new(LowerName::new(&owner), RecordType::NSEC3), rrset));
+    }
+    Ok(chain)
+}
+
+impl ZoneDenial {
+    /// The records, with their signatures, proving that `name` doesn't exist, or that it
+    /// has no records of the type queried when `nodata`.
+    fn proof(&self, name: &Name, nodata: bool) -> anyhow::Result<Vec<Record>> {
+        let name = name.to_lowercase();
+        let mut proof = Vec::new();
+        match &self.denial {
+            Denial::Nsec => {}
+            Denial::Nsec3(params) => {
+                if nodata {
+                    if let Some(rrset) = self.chain.get(&hash(&name, params)?) {
+                        return Ok(with_signatures(rrset));
+                    }
+                }
+                // The closest encloser, the NSEC3 covering the next closer name, and
+                // for a name that doesn't exist the NSEC3 covering the wildcard
+                let (encloser, next_closer) = self.closest_encloser(&name);
+                let mut proven = Vec::new();
+                let mut prove = |rrset: Option<&Arc<RecordSet>>| {
+                    if let Some(rrset) = rrset.filter(|rrset| !proven.contains(rrset.name())) {
+                        proven.push(rrset.name().clone());
+                        proof.extend(with_signatures(rrset));
+                    }
+                };
+                prove(self.chain.get(&hash(&encloser, params)?));
+                prove(self.covering(&hash(&next_closer, params)?));
+                if !nodata {
+                    prove(self.covering(&hash(&Name::from_ascii("*")?.append_domain(&encloser)?, params)?));
+                }
+            }
+            Denial::WhiteLies => {
+                let (encloser, _) = self.closest_encloser(&name);
+                match self.names.get(&LowerName::new(&name)).filter(|_| nodata) {
+                    Some(types) => {
+                        let mut types = types.clone();
+                        types.extend([RecordType::RRSIG, RecordType::NSEC]);
+                        proof.extend(self.signed(NSEC::new(successor(&name)?, types), name.clone())?);
+                    }
+                    None => {
+                        let wildcard = Name::from_ascii("*")?.append_domain(&encloser)?;
+                        let types = vec![RecordType::RRSIG, RecordType::NSEC];
+                        proof.extend(self.signed(NSEC::new(successor(&name)?, types.clone()), predecessor(&name)?)?);
+                        if !nodata && wildcard != name {
+                            proof.extend(self.signed(NSEC::new(successor(&wildcard)?, types), predecessor(&wildcard)?)?);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(proof)
+    }
+
+    /// The closest ancestor of `name` that exists in the zone, and the name one label
+    /// below it on the way to `name`.
+    fn closest_encloser(&self, name: &Name) -> (Name, Name) {
+        let mut next_closer = name.clone();
+        let mut encloser = name.base_name();
+        while !self.names.contains_key(&LowerName::new(&encloser)) && encloser.num_labels() > self.origin.num_labels() {
+            next_closer = encloser.clone();
+            encloser = encloser.base_name();
+        }
+        (encloser, next_closer)
+    }
+
+    /// The NSEC3 record whose hash interval covers `hash`.
+    fn covering(&self, hash: &[u8]) -> Option<&Arc<RecordSet>> {
+        self.chain
+            .range(..hash.to_vec())
+            .next_back()
+            .or_else(|| self.chain.iter().next_back())
+            .map(|(_, rrset)| rrset)
+    }
+
+    /// An NSEC record owned by `owner`, with its signatures by the zone's keys.
+    fn signed(&self, nsec: NSEC, owner: Name) -> anyhow::Result<Vec<Record>> {
+        let record = Record::from_rdata(owner.clone(), self.ttl, RData::DNSSEC(DNSSECRData::NSEC(nsec)));
+        let inception = SystemTime::now() - INCEPTION_SKEW;
+        let mut records = vec![record.clone()];
+        for signer in self.signers.iter() {
+            let expiration = inception + INCEPTION_SKEW + signer.sig_duration();
+            let (inception, expiration) = (unix_secs(inception), unix_secs(expiration));
+            let key_tag = signer.calculate_key_tag()?;
+            let tbs = tbs::rrset_tbs(
+                &owner,
+                DNSClass::IN,
+                owner.num_labels(),
+                RecordType::NSEC,
+                signer.algorithm(),
+                self.ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer.signer_name(),
+                std::slice::from_ref(&record),
+            )?;
+            let signature = signer.sign(&tbs)?;
+            let rrsig = RRSIG::new(
+                RecordType::NSEC,
+                signer.algorithm(),
+                owner.num_labels(),
+                self.ttl,
+                expiration,
+                inception,
+                key_tag,
+                signer.signer_name().clone(),
+                signature,
+            );
+            records.push(Record::from_rdata(owner.clone(), self.ttl, RData::DNSSEC(DNSSECRData::RRSIG(rrsig))));
+        }
+        Ok(records)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u32 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32
+}
+
+/// The records of `rrset` followed by their signatures.
+fn with_signatures(rrset: &RecordSet) -> Vec<Record> {
+    rrset.records(true, SupportedAlgorithms::all()).cloned().collect()
+}
+
+/// The name right after `name` in canonical order: its first child, `\000.name`.
+fn successor(name: &Name) -> anyhow::Result<Name> {
+    Ok(Name::from_labels([&[0u8][..]])?.append_domain(name)?)
+}
+
+/// A name before `name` in canonical order, with no name of the zone in between: its
+/// first label lowered by one, then padded with `\255`, or its parent when the label
+/// can't be lowered.
+fn predecessor(name: &Name) -> anyhow::Result<Name> {
+    let Some(first) = name.iter().next() else {
+        return Ok(name.clone());
+    };
+    let mut label = first.to_ascii_lowercase();
+    match label.pop() {
+        Some(0) if !label.is_empty() => {}
+        Some(0) | None => return Ok(name.base_name()),
+        Some(last) => label.push(last - 1),
+    }
+    let room = 255usize.saturating_sub(name.base_name().len() + 2);
+    label.resize(label.len().max(room.min(63)), 0xff);
+    Ok(Name::from_labels([label.as_slice()])?.append_domain(&name.base_name())?)
+}
+
+/// Response handler that adds the NSEC3 records, or the white lies, proving negative
+/// answers of its zone.
+#[derive(Clone)]
+pub struct DenialResponseHandler<R> {
+    inner: R,
+    /// The zone's denial; responses are passed on as they are when unset.
+    zone: Option<Arc<ZoneDenial>>,
+}
+
+impl<R> DenialResponseHandler<R> {
+    pub fn new(inner: R, zone: Option<Arc<ZoneDenial>>) -> Self {
+        Self { inner, zone }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for DenialResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let response_code = response.header().response_code();
+        let Some(zone) = self.zone.as_ref().filter(|_| matches!(response_code, ResponseCode::NXDomain | ResponseCode::NoError)) else {
+            return self.inner.send_response(response).await;
+        };
+        let message = roundrobin::reread(response)?;
+
+        // NODATA answers have the zone's SOA in the authority section and no answers
+        let negative = message.answers().is_empty()
+            && message
+                .name_servers()
+                .iter()
+                .any(|record| record.record_type() == RecordType::SOA && *record.name() == zone.origin);
+        let proof = match negative {
+            true => zone
+                .proof(message.query().original().name(), response_code == ResponseCode::NoError)
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to prove the negative answer for {}: {}", message.query().original().name(), e);
+                    Vec::new()
+                }),
+            false => Vec::new(),
+        };
+        let mut builder = MessageResponseBuilder::from_message_request(&message);
+        if let Some(edns) = message.edns() {
+            builder.edns(edns.clone());
+        }
+        let response = builder.build(
+            *message.header(),
+            message.answers().iter(),
+            message.name_servers().iter().chain(&proof),
+            [],
+            message.additionals().iter().chain(message.sig0()),
+        );
+        self.inner.send_response(response).await
+    }
+}
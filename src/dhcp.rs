@@ -0,0 +1,216 @@
+//! Records for the hostnames of DHCP leases.
+//!
+//! With `[dns.dhcp]` configured, the lease file of a dnsmasq or ISC dhcpd server is read
+//! every `poll_secs` seconds, and every lease that has a hostname and hasn't expired gets
+//! an A or AAAA record `<hostname>.<zone>` for its address. Leases that expire or are
+//! released drop out of the file or are marked as such, so their records go away on the
+//! next read. When a hostname holds several leases, the one that runs longest wins.
+//!
+//! The zone belongs to the integration: its A and AAAA records are replaced by those of
+//! the leases, while records of other types are left alone. Only the IPv4 leases of ISC
+//! dhcpd files are read; dnsmasq files hold both.
+
+use hickory_proto::rr::{Name, RecordType};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+use crate::dns::{DnsState, RecordEntry};
+use crate::settings::DhcpSettings;
+
+/// Format of a lease file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LeaseFormat {
+    /// dnsmasq's `dnsmasq.leases`, one lease per line.
+    Dnsmasq,
+    /// ISC dhcpd's `dhcpd.leases`, one `lease` block per grant.
+    Isc,
+}
+
+/// Config options for DHCP lease records
+#[derive(Clone)]
+pub struct DhcpOptions {
+    pub path: PathBuf,
+    pub format: LeaseFormat,
+    pub zone: Name,
+    pub ttl: u32,
+    pub poll_interval: Duration,
+}
+
+impl TryFrom<DhcpSettings> for DhcpOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: DhcpSettings) -> anyhow::Result<Self> {
+        let format = match cfg.format.as_str() {
+            "dnsmasq" => LeaseFormat::Dnsmasq,
+            "isc" => LeaseFormat::Isc,
+            other => anyhow::bail!("dns.dhcp.format must be dnsmasq or isc, not {}", other),
+        };
+        let mut zone = Name::from_ascii(&cfg.zone)?;
+        zone.set_fqdn(true);
+        Ok(DhcpOptions {
+            path: PathBuf::from(cfg.path),
+            format,
+            zone,
+            ttl: cfg.ttl,
+            poll_interval: Duration::from_secs(cfg.poll_secs.max(1)),
+        })
+    }
+}
+
+/// A lease with a hostname.
+struct Lease {
+    hostname: String,
+    address: IpAddr,
+    /// Seconds since the epoch the lease ends at, unset when it never does.
+    expires: Option<u64>,
+}
+
+/// The hostname of a lease as a record label, if it makes a valid one.
+fn label(hostname: &str) -> Option<String> {
+    // Some clients send their fully qualified name
+    let label = hostname.split('.').next()?.to_ascii_lowercase();
+    let valid = !label.is_empty()
+        && label.len() <= 63
+        && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-');
+    valid.then_some(label)
+}
+
+/// Reads the leases of a dnsmasq lease file, whose lines are
+/// `<expiry> <mac or iaid> <address> <hostname> <client id>`, with `*` for an unknown
+/// hostname and an expiry of 0 for a lease that never ends.
+fn parse_dnsmasq(content: &str) -> Vec<Lease> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [expiry, _, address, hostname, ..] = fields.as_slice() else {
+                return None;
+            };
+            let expiry: u64 = expiry.parse().ok()?;
+            Some(Lease {
+                hostname: label(hostname)?,
+                address: address.parse().ok()?,
+                expires: (expiry != 0).then_some(expiry),
+            })
+        })
+        .collect()
+}
+
+/// Seconds since the epoch of a `YYYY/MM/DD HH:MM:SS` UTC time.
+fn unix_time(date: &str, time: &str) -> Option<u64> {
+    let mut date = date.split('/').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days since the epoch of the civil date, counting years from March
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    u64::try_from(days * 86400 + hour * 3600 + minute * 60 + second).ok()
+}
+
+/// Reads the active leases of an ISC dhcpd lease file. The file is a journal, so a later
+/// block for an address replaces the earlier ones.
+fn parse_isc(content: &str) -> Vec<Lease> {
+    let mut leases: HashMap<IpAddr, Lease> = HashMap::new();
+    let mut block: Option<(IpAddr, bool, Option<String>, Option<u64>)> = None;
+    for line in content.lines() {
+        let line = line.trim().trim_end_matches(';');
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match (words.as_slice(), &mut block) {
+            (["lease", address, "{"], None) => {
+                block = address.parse().ok().map(|address| (address, false, None, None));
+            }
+            (["}"], Some(_)) => {
+                if let Some((address, active, hostname, expires)) = block.take() {
+                    match hostname.filter(|_| active) {
+                        Some(hostname) => leases.insert(address, Lease { hostname, address, expires }),
+                        None => leases.remove(&address),
+                    };
+                }
+            }
+            (["binding", "state", state], Some((_, active, ..))) => *active = *state == "active",
+            (["client-hostname", hostname], Some((_, _, name, _))) => *name = label(hostname.trim_matches('"')),
+            (["ends", "never"], Some((.., expires))) => *expires = None,
+            (["ends", "epoch", secs, ..], Some((.., expires))) => *expires = secs.parse().ok(),
+            (["ends", _, date, time], Some((.., expires))) => *expires = unix_time(date, time),
+            _ => {}
+        }
+    }
+    leases.into_values().collect()
+}
+
+/// The records for the unexpired leases in the lease file.
+async fn read_leases(options: &DhcpOptions) -> anyhow::Result<Vec<RecordEntry>> {
+    let content = tokio::fs::read_to_string(&options.path)
+        .await
+        .map_err(|e| anyhow::anyhow!("can't read {}: {}", options.path.display(), e))?;
+    let leases = match options.format {
+        LeaseFormat::Dnsmasq => parse_dnsmasq(&content),
+        LeaseFormat::Isc => parse_isc(&content),
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    // The longest running lease of each hostname and address family
+    let mut latest: HashMap<(String, bool), Lease> = HashMap::new();
+    for lease in leases.into_iter().filter(|lease| lease.expires.is_none_or(|expires| expires > now)) {
+        let key = (lease.hostname.clone(), lease.address.is_ipv4());
+        let runs_longer = |other: &Lease| other.expires.is_some_and(|other| lease.expires.is_none_or(|expires| expires > other));
+        if latest.get(&key).is_none_or(runs_longer) {
+            latest.insert(key, lease);
+        }
+    }
+    Ok(latest
+        .into_values()
+        .map(|lease| RecordEntry {
+            name: format!("{}.{}", lease.hostname, options.zone),
+            record_type: if lease.address.is_ipv4() { "A" } else { "AAAA" }.to_string(),
+            value: lease.address.to_string(),
+            ttl: options.ttl,
+            ..Default::default()
+        })
+        .collect())
+}
+
+/// Brings the records of the zone in line with the lease file.
+async fn sync(state: &RwLock<DnsState>, options: &DhcpOptions) -> anyhow::Result<()> {
+    let records = read_leases(options).await?;
+    let origin = options.zone.to_string();
+    let changed = state
+        .read()
+        .await
+        .sync_records(&origin, &[RecordType::A, RecordType::AAAA], records)
+        .await?;
+    if changed > 0 {
+        println!("Updated {} DHCP lease record sets in {}", changed, origin);
+    }
+    Ok(())
+}
+
+/// Keeps the records of the zone in line with the lease file, reading it again every
+/// poll interval. Runs until the process exits.
+pub async fn run_lease_sync(state: Arc<RwLock<DnsState>>, options: DhcpOptions) {
+    if let Err(e) = state.write().await.ensure_zones(&[options.zone.to_string()]).await {
+        eprintln!("Failed to create DHCP zone {}: {}", options.zone, e);
+        return;
+    }
+    let mut interval = tokio::time::interval(options.poll_interval);
+    loop {
+        interval.tick().await;
+        if let Err(e) = sync(&state, &options).await {
+            eprintln!("DHCP lease sync from {} failed: {}", options.path.display(), e);
+        }
+    }
+}
@@ -3,26 +3,223 @@
 //! This module sets up an in-memory authoritative DNS server using the `hickory-server` crate.
 //! It defines `DnsState` for managing DNS records, provides an async implementation of the
 //! `RequestHandler` trait to handle DNS requests, and exposes functions to add/delete records
-//! via the `InMemoryAuthority`. It also provides a `run_dns_server` function to start the UDP server.
+//! and zones, each zone being served by its own `InMemoryAuthority`. It also provides a
+//! `run_dns_server` function to start the UDP server.
 
-use hickory_proto::rr::{LowerName, RrKey};
-use hickory_server::authority::{Catalog, ZoneType};
+use hickory_proto::op::{Edns, Header, MessageType, OpCode, ResponseCode};
+use hickory_proto::rr::rdata::opt::EdnsOption;
+use hickory_proto::rr::dnssec::rdata::DNSSECRData;
+use hickory_proto::rr::rdata::{NS, PTR};
+use hickory_proto::rr::{LowerName, RecordSet, RrKey};
+use hickory_server::authority::{Authority, DnssecAuthority, MessageResponseBuilder, ZoneType};
 use hickory_server::store::in_memory::InMemoryAuthority;
-use hickory_server::server::{Request, RequestHandler, ResponseHandler, ResponseInfo, ServerFuture};
-use hickory_proto::rr::{Name, RData, Record, RecordType};
+use hickory_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo, ServerFuture};
+use hickory_proto::rr::{DNSClass, Name, RData, Record, RecordType};
+use hickory_proto::serialize::txt::RDataParser;
+use serde::{Deserialize, Serialize};
 use tonic::async_trait;
-use std::net::UdpSocket;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use socket2::{Domain, Socket, Type};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, watch, RwLock};
+use tokio_rustls::TlsAcceptor;
 
-use crate::settings::DnsSettings;
+use crate::acl::AclOptions;
+use crate::acmecert::{self, CertificateResolver};
+use crate::activation::ActivatedSockets;
+use crate::additionals::{self, AdditionalResponseHandler};
+use crate::alias::{Alias, AliasEntry, AliasTable};
+use crate::any::{self, AnyResponse};
+use crate::approval::{Approvals, ChangeRequest};
+use crate::blocklist::{self, Action, Blocklist, BlocklistOptions};
+use crate::cache::{CacheUsage, ResponseCache};
+use crate::catalogsnapshot::{CatalogSnapshot, CatalogSnapshots};
+use crate::catalogzone::{CatalogZone, CatalogZoneOptions};
+use crate::clientid::{self, ClientOptions, Device};
+use crate::cnamechain::{self, ChainResponseHandler};
+use crate::cookies::{CookieOptions, CookieResponseHandler, Cookies, Verdict as CookieVerdict};
+use crate::edns::{EdnsOptions, EdnsResponseHandler};
+use crate::delegation;
+use crate::denial::{Denial, DenialResponseHandler, Denials};
+use crate::dns64::Dns64Options;
+use crate::dnssec::{self, DnssecOptions, KeyRole, Rollover, RolloverPhase, RolloverStep, Rollovers};
+use crate::dhcp::DhcpOptions;
+use crate::dnstap::{Dnstap, TapResponseHandler};
+use crate::docker::DockerOptions;
+use crate::ecs::{self, EcsOptions, Subnet};
+use crate::doh::{self, DohOptions};
+use crate::error::RdnsError;
+use crate::family::{self, AddressFamily, FamilyResponseHandler};
+use crate::forward::{ForwardOptions, ForwardRule, ForwardRuleEntry, ForwardRuleInfo, ForwardRules};
+use crate::metrics::Metrics;
+use crate::geo::{self, GeoEntry, GeoLocator, GeoOptions, GeoRecord, GeoTable};
+use crate::hardening::{self, PayloadResponseHandler};
+use crate::health::{HealthCheckEntry, HealthMonitor, HealthOptions, HealthResponseHandler, ValueHealth};
+use crate::history::{History, HistoryOptions, VersionInfo, VersionSelector};
+use crate::hosts::HostsOptions;
+use crate::identity::{self, IdentityOptions, NsidResponseHandler};
+use crate::ixfr::{self, Journal};
+use crate::lease::Leases;
+use crate::mailauth;
+use crate::metadata::{MetadataTable, RecordMetadata};
+use crate::overrides::{self, Override, OverrideEntry, OverrideTable};
+use crate::pattern::{self, Pattern, PatternEntry, PatternTable};
+use crate::migrate::Converted;
+use crate::mdns::MdnsOptions;
+use crate::negative::{self, NegativePolicy, NegativeResponseHandler};
+use crate::pipeline::{Flow, Pipeline, Stage};
+use crate::persist::{BlocklistSnapshot, JournalEntry, Snapshot, Store, ViewSnapshot, ZoneSnapshot, JOURNAL_SIZE};
+use crate::privacy::{self, PrivacyOptions};
+use crate::proxy::{self, ProxyProtocolOptions};
+use crate::quota::{QuotaOptions, RecordUsage, Usage};
+use crate::queryrules::{self, MatchType, QueryRule, QueryRuleEntry, QueryRuleInfo, QueryRules};
+use crate::pool::{Pool, PoolEntry, PoolResponseHandler, PoolTable};
+use crate::ratelimit::{RateLimitOptions, RateLimiter, Verdict};
+use crate::recordjson::RecordExport;
+use crate::roundrobin::{AnswerOrder, AnswerOrderOptions, OrderResponseHandler, OrderRule, Rotations};
+use crate::rewrite::{self, RewriteRule, RewriteRuleEntry, RewriteRuleInfo, RewriteRules};
+use crate::rollout::{self, Rollout, RolloutEntry, RolloutTable};
+use crate::schedule::{Schedule, ScheduledChange, ScheduledKind};
+use crate::rpz::{self, PolicyZone, RpzOptions};
+use crate::script::{self, Script, ScriptQuery, ScriptResponseHandler};
+use crate::secondary::{self, SecondaryZone};
+use crate::service::{self, ServiceEntry, ServiceTable};
+use crate::soa::{self, SoaOptions};
+use crate::sockopt::SocketOptions;
+use crate::stats::{self, Anomaly, ClientStats, NameStats, QueryStats, StatsOptions, StatsQuery};
+use crate::template::{self, ZoneTemplate};
+use crate::timed::{self, TimedEntry, TimedRecords, TimedTable};
+use crate::tenant::{Tenant, TenantEntry, TenantInfo, Tenants};
+use crate::svcb;
+use crate::settings::{self, DnsSettings, HealthSettings, TlsSettings, TtlSettings};
+use crate::shutdown::{DrainMode, Shutdown};
+use crate::telemetry::Tracer;
+use crate::transfer::TransferOptions;
+use crate::tsig::{self, TsigKey, TsigKeyEntry, TsigKeyInfo, TsigKeyring, TsigResponseHandler};
+use crate::update::{self, UpdateOptions};
+use crate::upstream::UpstreamStats;
+use crate::validate;
+use crate::validator::{self, ValidatingResponseHandler};
+use crate::views::{View, ViewOptions, Views};
+use crate::zonedir::ZoneDirOptions;
+use crate::zonecheck::{self, Finding};
+use crate::zonefile::{self, ZoneFile};
 
-/// Wrapper around a shared, asynchronously accessible DNS catalog.
+/// Wrapper around the published snapshots of the DNS catalog.
 #[derive(Clone)]
-pub struct SharedCatalog(pub Arc<RwLock<Catalog>>);
+pub struct SharedCatalog {
+    /// Latest snapshot of the catalog, taken anew for every request.
+    snapshots: watch::Receiver<Arc<CatalogSnapshot>>,
+    /// Zones dynamic updates are applied to, bypassing the catalog.
+    state: Arc<RwLock<DnsState>>,
+    /// Current request handling policy, replaced when the config is reloaded.
+    options: watch::Receiver<Arc<HandlerOptions>>,
+    /// Registry every answered request is recorded in.
+    metrics: Arc<Metrics>,
+    /// How far round-robin answers have been rotated.
+    rotations: Arc<Rotations>,
+    /// Pools answers for their names are picked from.
+    pools: Arc<PoolTable>,
+    /// Health checks deciding which values are withheld from answers.
+    health: Arc<HealthMonitor>,
+    /// Locates clients for geo records; they aren't applied when unset.
+    geo: Option<Arc<GeoLocator>>,
+    /// Geo records answered for instead of the catalog.
+    geo_records: Arc<GeoTable>,
+    /// Rollouts answered for instead of the catalog, for the clients rolled out to.
+    rollouts: Arc<RolloutTable>,
+    /// Values on schedules answered for instead of the catalog while one is active.
+    timed: Arc<TimedTable>,
+    /// NSEC3 chains and white lies proving the negative answers of signed zones.
+    denials: Arc<Denials>,
+    /// Name patterns answered for instead of the catalog.
+    patterns: Arc<PatternTable>,
+    /// Forwarded names answered locally instead.
+    overrides: Arc<OverrideTable>,
+    /// Aliases whose address queries are answered with their target's addresses.
+    aliases: Arc<AliasTable>,
+    /// Split-horizon views whose overlays take precedence for their clients.
+    views: Arc<Views>,
+    /// Limits the UDP responses sent to each client network, if rate limiting is enabled.
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Mints and checks DNS cookies, if they are enabled.
+    cookies: Option<Arc<Cookies>>,
+    /// Largest UDP response to queries with EDNS, see `hardening`.
+    max_udp_payload: u16,
+    /// The EDNS section of responses and the buffer sizes they are kept within.
+    edns: Arc<EdnsOptions>,
+    /// Domains answered with the sinkhole instead, if blocking is enabled.
+    blocklist: Option<Arc<Blocklist>>,
+    /// Forwarding rules, deciding which forwarded names are refused instead.
+    forward_rules: Arc<ForwardRules>,
+    /// Rules answering some names with the records of others.
+    rewrite_rules: Arc<RewriteRules>,
+    /// Rules answering matching names with a fixed action.
+    query_rules: Arc<QueryRules>,
+    /// Counts of the queries by name and type, if statistics are enabled.
+    stats: Option<Arc<QueryStats>>,
+    /// Whether queries are turned away while the instance is drained.
+    drain: watch::Receiver<DrainMode>,
+    /// Answered queries, sent only while someone subscribes to them.
+    queries: broadcast::Sender<QueryEvent>,
+}
+
+/// Request handling policy that can be replaced while the server runs.
+#[derive(Default)]
+pub struct HandlerOptions {
+    /// Dynamic update policy; UPDATE messages are refused when unset.
+    pub update: Option<UpdateOptions>,
+    /// Who may transfer zones, and how; AXFR is refused when unset.
+    pub transfer: Option<TransferOptions>,
+    /// Sink queries and responses are logged to, if dnstap output is enabled.
+    pub dnstap: Option<Dnstap>,
+    /// Tracer requests are recorded with, if tracing is configured.
+    pub tracer: Option<Tracer>,
+    /// Anonymization of the client addresses in traces.
+    pub privacy: Option<PrivacyOptions>,
+    /// How the records of multi-value answers are ordered.
+    pub answer_order: AnswerOrderOptions,
+    /// CNAMEs followed at most to complete the chains of authoritative answers; chains
+    /// aren't completed when 0.
+    pub cname_chain_depth: usize,
+    /// Whether the addresses of MX, SRV and NS targets in every hosted zone are added to
+    /// answers.
+    pub additional_records: bool,
+    /// How ANY queries for hosted names are answered.
+    pub any_response: AnyResponse,
+    /// How the addresses of each family are answered to clients in no view with a
+    /// policy of its own.
+    pub address_family: AddressFamily,
+    /// Stages queries pass through before the catalog.
+    pub pipeline: Pipeline,
+    /// Clients permitted to query; every client is when unset.
+    pub acl: Option<AclOptions>,
+    /// Response policy zones, in the order they are checked.
+    pub rpz: Vec<PolicyZone>,
+    /// How unknown names of the zones listed are answered.
+    pub negative: Vec<NegativePolicy>,
+    /// How the Client Subnet option of queries is used and passed on.
+    pub ecs: EcsOptions,
+    /// Answers identifying the instance; CHAOS queries are refused when unset.
+    pub identity: Option<IdentityOptions>,
+    /// Synthesis of AAAA answers from A records; AAAA queries are answered as they are
+    /// when unset.
+    pub dns64: Option<Dns64Options>,
+    /// Script with hooks run before and after lookup; none run when unset.
+    pub script: Option<Arc<Script>>,
+    /// Identification of client devices; clients are told apart by address only when
+    /// unset.
+    pub clients: Option<ClientOptions>,
+}
 
-/// Implements DNS request handling by delegating to the inner shared catalog.
+/// Implements DNS request handling by delegating queries to the inner shared catalog
+/// and dynamic updates to the `update` module.
 #[async_trait]
 impl RequestHandler for SharedCatalog {
     async fn handle_request<R>(
@@ -33,126 +230,5843 @@ impl RequestHandler for SharedCatalog {
     where
         R: ResponseHandler + Send,
     {
-        let catalog = self.0.read().await;
-        catalog.handle_request(request, response_handle).await
+        let start = Instant::now();
+        let cookie = match &self.cookies {
+            Some(cookies) => cookies.check(request),
+            None => CookieVerdict::Missing,
+        };
+        if let Some(label) = cookie.label() {
+            self.metrics.observe_cookie(label);
+        }
+        let limit = hardening::udp_limit(request, self.max_udp_payload, &self.edns);
+        let limit = match &self.cookies {
+            Some(cookies) => cookies.udp_limit(&cookie, limit),
+            None => limit,
+        };
+        // Responses to signed requests are signed after everything else is done to them
+        let verified = match request.sig0().is_empty() {
+            true => Ok(None),
+            false => self.state.read().await.tsig_keys().verify(request),
+        };
+        let rejected = verified.as_ref().err().cloned();
+        let response_handle = TsigResponseHandler::new(response_handle, verified);
+        let reserved = response_handle.reserved();
+        let response_handle = PayloadResponseHandler::new(response_handle, limit, self.edns.advertised_payload, reserved);
+        let response_handle = EdnsResponseHandler::new(response_handle, self.edns.clone());
+        if let CookieVerdict::Malformed = cookie {
+            return send_error(request, ResponseCode::FormErr, response_handle).await;
+        }
+        let response_handle = CookieResponseHandler::new(response_handle, cookie.reply());
+        let drain = *self.drain.borrow();
+        match (drain, request.protocol()) {
+            (DrainMode::Truncate, Protocol::Udp) => return send_truncated(request, response_handle).await,
+            (DrainMode::Refuse, _) => return send_error(request, ResponseCode::Refused, response_handle).await,
+            _ => {}
+        }
+        // Clients returning a valid server cookie aren't spoofed, so they aren't limited
+        if let (Some(limiter), Protocol::Udp, false) = (&self.rate_limiter, request.protocol(), cookie.verified()) {
+            match limiter.check(request.src().ip()) {
+                Verdict::Send => {}
+                Verdict::Slip => {
+                    self.metrics.observe_rate_limited(true);
+                    return send_truncated(request, response_handle).await;
+                }
+                Verdict::Drop => {
+                    self.metrics.observe_rate_limited(false);
+                    return drop_response(request);
+                }
+            }
+        }
+        // Updates, transfers and NOTIFY check their signature themselves, refusing them
+        // in their own way
+        let checks_signature = request.op_code() != OpCode::Query || matches!(request.query().query_type(), RecordType::AXFR | RecordType::IXFR);
+        if let (Some(e), false) = (rejected, checks_signature) {
+            eprintln!("Rejected query from {}: {}", request.src(), e);
+            return send_error(request, ResponseCode::NotAuth, response_handle).await;
+        }
+        let options = self.options.borrow().clone();
+        let device = options.clients.as_ref().and_then(|clients| clients.identify(request));
+        // Devices opted out of logging are left out of every per-query record
+        let logged = device.is_none_or(|device| device.log);
+        let mut span = match logged {
+            true => options.tracer.as_ref().map(|tracer| tracer.start_request(request, options.privacy.as_ref())),
+            false => None,
+        };
+        let nsid = options.identity.as_ref().and_then(|identity| identity.nsid_for(request));
+        let response_handle = NsidResponseHandler::new(response_handle, nsid);
+        let response_handle = DenialResponseHandler::new(response_handle, self.denials.for_request(request));
+        let response_handle = NegativeResponseHandler::new(response_handle, negative::policy_for(&options.negative, request));
+        let info = match (&options.dnstap, logged) {
+            (Some(dnstap), true) => {
+                let query_time = SystemTime::now();
+                dnstap.log_query(request, query_time);
+                let response_handle = TapResponseHandler::new(response_handle, dnstap.clone(), request, query_time);
+                self.reorder(request, &options, device, response_handle).await
+            }
+            _ => self.reorder(request, &options, device, response_handle).await,
+        };
+        self.metrics
+            .observe_query(request.query().query_type(), info.response_code(), start.elapsed());
+        if !logged {
+            return info;
+        }
+        crate::debug!(
+            "Query {} {} from {} over {}: {} in {:?}",
+            request.query().name(),
+            request.query().query_type(),
+            privacy::anonymize(options.privacy.as_ref(), request.src().ip()),
+            request.protocol(),
+            info.response_code(),
+            start.elapsed()
+        );
+        if let Some(span) = &mut span {
+            span.set_attribute("dns.response_code", format!("{:?}", info.response_code()));
+            if info.response_code() == ResponseCode::ServFail {
+                span.set_error("SERVFAIL");
+            }
+        }
+        if let (Some(stats), OpCode::Query) = (&self.stats, request.op_code()) {
+            stats.observe(request.query().name(), request.query().query_type(), request.src().ip(), info.response_code());
+        }
+        if self.queries.receiver_count() > 0 {
+            let _ = self.queries.send(QueryEvent {
+                name: request.query().name().to_string(),
+                query_type: request.query().query_type().to_string(),
+                client: privacy::anonymize(options.privacy.as_ref(), request.src().ip()),
+                protocol: request.protocol().to_string(),
+                response_code: info.response_code().to_string(),
+                duration: start.elapsed(),
+                timestamp: SystemTime::now(),
+            });
+        }
+        info
+    }
+}
+
+impl SharedCatalog {
+    /// The handler answering queries from `state` with the current `handler_options`, as
+    /// the listeners of `options` do.
+    pub async fn new(state: Arc<RwLock<DnsState>>, handler_options: watch::Receiver<Arc<HandlerOptions>>, options: &DnsOptions) -> Self {
+        let (snapshots, metrics, pools, health, geo, geo_records, rollouts, timed, denials, patterns, overrides, aliases, views, blocklist, forward_rules, rewrite_rules, query_rules, stats, drain, queries) = {
+            let state = state.read().await;
+            (
+                state.snapshots(),
+                state.metrics(),
+                state.pools.clone(),
+                state.health.clone(),
+                state.geo.clone(),
+                state.geo_records.clone(),
+                state.rollouts.clone(),
+                state.timed.clone(),
+                state.denials.clone(),
+                state.patterns.clone(),
+                state.overrides.clone(),
+                state.aliases.clone(),
+                state.views.clone(),
+                state.blocklist.clone(),
+                state.forward_rules.clone(),
+                state.rewrite_rules.clone(),
+                state.query_rules.clone(),
+                state.stats.clone(),
+                state.drain_mode(),
+                state.queries.clone(),
+            )
+        };
+
+        SharedCatalog {
+            snapshots,
+            state,
+            options: handler_options,
+            metrics,
+            rotations: Arc::new(Rotations::default()),
+            pools,
+            health,
+            geo,
+            geo_records,
+            rollouts,
+            timed,
+            denials,
+            patterns,
+            overrides,
+            aliases,
+            views,
+            rate_limiter: options.rate_limit.clone().map(|rate_limit| Arc::new(RateLimiter::new(rate_limit))),
+            cookies: options.cookies.clone().map(|cookies| Arc::new(Cookies::new(cookies))),
+            max_udp_payload: options.max_udp_payload,
+            edns: Arc::new(options.edns.clone()),
+            blocklist,
+            forward_rules,
+            rewrite_rules,
+            query_rules,
+            stats,
+            drain,
+            queries,
+        }
+    }
+
+    /// Routes a request through the stages of the pipeline, then to the catalog: pooled
+    /// names are answered with the member their pool selects, and unhealthy values are
+    /// withheld from other answers, which are ordered by their answer order policy. The
+    /// client's address family policy applies to the answers of every stage.
+    async fn reorder<R: ResponseHandler>(&self, request: &Request, options: &HandlerOptions, device: Option<&Device>, response_handle: R) -> ResponseInfo {
+        let key = RrKey::new(request.query().name().clone(), request.query().query_type());
+        let client = Client {
+            subnet: Subnet::of_request(request),
+            device,
+        };
+        let subnet = client.subnet.as_ref();
+        let family = self.address_family(request, options, &client);
+        let mut response_handle = FamilyResponseHandler::new(response_handle, family);
+        for &stage in options.pipeline.stages() {
+            response_handle = match self.run_stage(stage, request, options, &key, &client, response_handle).await {
+                Flow::Answered(info) => return info,
+                Flow::Next(response_handle) => response_handle,
+            };
+        }
+        let hook = options.script.clone().filter(|script| {
+            script.hooks_answers()
+                && options.pipeline.stages().contains(&Stage::Script)
+                && request.op_code() == OpCode::Query
+                && !matches!(key.record_type, RecordType::AXFR | RecordType::IXFR)
+        });
+        let response_handle = ScriptResponseHandler::new(response_handle, hook, request);
+        let pool = self.pools.read().unwrap().get(&key).cloned();
+        if let Some(pool) = pool {
+            let response_handle = PoolResponseHandler::new(response_handle, pool, self.health.clone());
+            return self.route(request, options, subnet, response_handle).await;
+        }
+        let checked = !self.health.is_empty();
+        if !options.answer_order.is_fixed() {
+            let address = subnet.map_or(request.src().ip(), |subnet| subnet.address);
+            let response_handle = OrderResponseHandler::new(response_handle, options.answer_order.clone(), self.rotations.clone(), address);
+            if checked {
+                let response_handle = HealthResponseHandler::new(response_handle, self.health.clone());
+                return self.route(request, options, subnet, response_handle).await;
+            }
+            return self.route(request, options, subnet, response_handle).await;
+        }
+        if checked {
+            let response_handle = HealthResponseHandler::new(response_handle, self.health.clone());
+            return self.route(request, options, subnet, response_handle).await;
+        }
+        self.route(request, options, subnet, response_handle).await
+    }
+
+    /// The view of the client of `request`: that of its device if it has one, or the
+    /// first matching its address.
+    fn view_for(&self, request: &Request, options: &HandlerOptions, client: &Client) -> Option<&View> {
+        match client.device.and_then(|device| device.view.as_deref()) {
+            Some(view) => self.views.get(view).ok(),
+            None => self.views.for_client(view_client(request, options, client.subnet.as_ref())),
+        }
+    }
+
+    /// The address family policy of the client of `request`: that of its view, or the
+    /// global one.
+    fn address_family(&self, request: &Request, options: &HandlerOptions, client: &Client) -> AddressFamily {
+        self.view_for(request, options, client)
+            .and_then(|view| view.address_family)
+            .unwrap_or(options.address_family)
+    }
+
+    /// Offers a request to a stage of the pipeline, which answers it or passes it on.
+    async fn run_stage<R: ResponseHandler>(
+        &self,
+        stage: Stage,
+        request: &Request,
+        options: &HandlerOptions,
+        key: &RrKey,
+        client: &Client<'_>,
+        response_handle: R,
+    ) -> Flow<R> {
+        let query = request.op_code() == OpCode::Query;
+        let subnet = client.subnet.as_ref();
+        match stage {
+            Stage::Acl => {
+                if let Some(acl) = &options.acl {
+                    if !self.permitted(request, acl).await {
+                        return Flow::Answered(send_error(request, ResponseCode::Refused, response_handle).await);
+                    }
+                }
+            }
+            Stage::Chaos => {
+                if query && request.query().query_class() == DNSClass::CH {
+                    return Flow::Answered(identity::answer_chaos(request, options.identity.as_ref(), response_handle).await);
+                }
+            }
+            Stage::Family => {
+                if query && key.record_type == RecordType::AAAA {
+                    let family = self.address_family(request, options, client);
+                    let snapshot = self.snapshot();
+                    if family::filters_aaaa(family, snapshot.catalog(), &key.name).await {
+                        return Flow::Answered(send_records(request, &[], None, response_handle).await);
+                    }
+                }
+            }
+            Stage::Override => {
+                if query {
+                    if let Some(records) = overrides::answer(&self.overrides, &key.name, key.record_type) {
+                        return Flow::Answered(send_records(request, &records, None, response_handle).await);
+                    }
+                }
+            }
+            Stage::Rpz => {
+                if query {
+                    if let Some(rule) = rpz::rewrite(&options.rpz, request.query().name()) {
+                        return Flow::Answered(rpz::send_rewritten(request, rule, response_handle).await);
+                    }
+                }
+            }
+            Stage::Blocklist => {
+                if let (Some(blocklist), OpCode::Query) = (&self.blocklist, request.op_code()) {
+                    if blocklist.blocks(request.query().name()) {
+                        return Flow::Answered(blocklist.send_blocked(request, response_handle).await);
+                    }
+                }
+            }
+            Stage::Script => {
+                if let (Some(script), OpCode::Query) = (&options.script, request.op_code()) {
+                    if let Some(reply) = script.clone().spawn_query(ScriptQuery::of_request(request)).await {
+                        return Flow::Answered(script::send_reply(request, reply, response_handle).await);
+                    }
+                }
+            }
+            Stage::Rules => {
+                if query && request.query().query_class() == DNSClass::IN {
+                    if let Some(rule) = client.device.and_then(|device| device.rule_for(&key.name)) {
+                        return Flow::Answered(queryrules::send_answer(request, rule, response_handle).await);
+                    }
+                    if let Some(rule) = self.query_rules.find(&key.name) {
+                        return Flow::Answered(queryrules::send_answer(request, &rule, response_handle).await);
+                    }
+                }
+            }
+            Stage::Delegation => {
+                if query && request.query().query_class() == DNSClass::IN {
+                    let referral = self.snapshot().referral(&key.name, key.record_type).await;
+                    if let Some(referral) = referral {
+                        return Flow::Answered(delegation::send_referral(request, &referral, response_handle).await);
+                    }
+                }
+            }
+            Stage::Any => {
+                if query && key.record_type == RecordType::ANY && options.any_response != AnyResponse::Full {
+                    let records = self.snapshot().lookup_records(&key.name, RecordType::ANY).await;
+                    if let Some(records) = any::minimal_answer(options.any_response, request.query().original().name(), records) {
+                        return Flow::Answered(send_records(request, &records, None, response_handle).await);
+                    }
+                }
+            }
+            Stage::Views => {
+                if query {
+                    let overlay = self.view_for(request, options, client).and_then(|view| view.lookup(key));
+                    if let Some(records) = overlay {
+                        return Flow::Answered(send_records(request, &records, None, response_handle).await);
+                    }
+                }
+            }
+            Stage::Timed => {
+                if query {
+                    if let Some(timed) = timed::select(&self.timed, key, SystemTime::now()) {
+                        return Flow::Answered(timed::send_answer(request, &timed, response_handle).await);
+                    }
+                }
+            }
+            Stage::Geo => {
+                if let (Some(locator), OpCode::Query) = (&self.geo, request.op_code()) {
+                    let record = self.geo_records.read().unwrap().get(key).cloned();
+                    if let Some(answer) = record.as_deref().and_then(|record| locator.select(request, subnet, record)) {
+                        return Flow::Answered(geo::send_answer(request, answer, response_handle).await);
+                    }
+                }
+            }
+            Stage::Rollout => {
+                if query {
+                    let rollout = self.rollouts.read().unwrap().get(key).cloned();
+                    if let Some(answer) = rollout.as_deref().and_then(|rollout| rollout.select(request, subnet)) {
+                        return Flow::Answered(rollout::send_answer(request, answer, response_handle).await);
+                    }
+                }
+            }
+            Stage::Pattern => {
+                if query {
+                    if let Some(records) = pattern::answer(&self.patterns, request.query().original().name(), key.record_type) {
+                        return Flow::Answered(send_records(request, &records, None, response_handle).await);
+                    }
+                }
+            }
+            Stage::Alias => {
+                if query && matches!(key.record_type, RecordType::A | RecordType::AAAA) {
+                    let alias = self.aliases.read().unwrap().get(&key.name).cloned();
+                    if let Some(alias) = alias {
+                        let snapshot = self.snapshot();
+                        let records = alias.resolve(snapshot.catalog(), request.query().original().name(), key.record_type).await;
+                        if !records.is_empty() {
+                            return Flow::Answered(send_records(request, &records, None, response_handle).await);
+                        }
+                    }
+                }
+            }
+            Stage::Dns64 => {
+                if let (Some(dns64), OpCode::Query) = (&options.dns64, request.op_code()) {
+                    if dns64.applies(request) {
+                        let snapshot = self.snapshot();
+                        let records = dns64.synthesize(snapshot.catalog(), &key.name).await;
+                        if let Some(records) = records {
+                            return Flow::Answered(send_records(request, &records, None, response_handle).await);
+                        }
+                    }
+                }
+            }
+            Stage::Rewrite => {
+                if query && request.query().query_class() == DNSClass::IN {
+                    if let Some((rule, rewritten)) = self.rewrite_rules.find(&key.name) {
+                        return Flow::Answered(self.send_rewritten(request, &rule, &rewritten, response_handle).await);
+                    }
+                }
+            }
+        }
+        Flow::Next(response_handle)
+    }
+
+    /// Answers a request whose name `rule` rewrites to `rewritten` with the records of
+    /// that name, validating them like other forwarded answers if it is forwarded.
+    async fn send_rewritten<R: ResponseHandler>(&self, request: &Request, rule: &RewriteRule, rewritten: &LowerName, response_handle: R) -> ResponseInfo {
+        let snapshot = self.snapshot();
+        let Some(authority) = snapshot.catalog().find(rewritten) else {
+            return send_error(request, ResponseCode::Refused, response_handle).await;
+        };
+        if authority.zone_type() == ZoneType::Forward {
+            if !self.forward_rules.forwards(rewritten) {
+                return send_error(request, ResponseCode::Refused, response_handle).await;
+            }
+            let response_handle = ValidatingResponseHandler::new(response_handle, request);
+            return validator::validating(rewrite::send_answer(request, authority, rule, rewritten, response_handle)).await;
+        }
+        rewrite::send_answer(request, authority, rule, rewritten, response_handle).await
+    }
+
+    /// Checks the client of `request` against the access lists.
+    async fn permitted(&self, request: &Request, acl: &AclOptions) -> bool {
+        let client = request.src().ip();
+        if !acl.permits_client(client) {
+            return false;
+        }
+        if request.op_code() != OpCode::Query {
+            return true;
+        }
+        let name = request.query().name();
+        let forwarded = self
+            .snapshot()
+            .catalog()
+            .find(name)
+            .is_some_and(|authority| authority.zone_type() == ZoneType::Forward);
+        acl.permits_query(client, name, forwarded)
+    }
+
+    /// Dispatches a request to the dynamic update or NOTIFY handler or the catalog, passing
+    /// the client's `subnet` on to the forwarder if enabled.
+    async fn route<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        options: &HandlerOptions,
+        subnet: Option<&Subnet>,
+        response_handle: R,
+    ) -> ResponseInfo {
+        if request.op_code() == OpCode::Update {
+            return update::handle_update(&self.state, options.update.as_ref(), request, response_handle).await;
+        }
+        if request.op_code() == OpCode::Notify {
+            return secondary::handle_notify(&self.state, request, response_handle).await;
+        }
+        if matches!(request.query().query_type(), RecordType::AXFR | RecordType::IXFR) {
+            let Some(transfer) = &options.transfer else {
+                return self.refuse_transfer(request, options, "disabled", ResponseCode::Refused, response_handle).await;
+            };
+            let verified = self.state.read().await.tsig_keys().verify(request);
+            let signature = match verified {
+                Ok(signature) => signature,
+                Err(e) => {
+                    eprintln!("Rejected zone transfer from unverified client: {}", e);
+                    return self.refuse_transfer(request, options, "bad_signature", ResponseCode::NotAuth, response_handle).await;
+                }
+            };
+            let key = signature.as_ref().map(|signature| signature.key_name());
+            if !transfer.allows(request.query().name(), request.src().ip(), key) {
+                return self.refuse_transfer(request, options, "not_allowed", ResponseCode::Refused, response_handle).await;
+            }
+            return match signature {
+                None if transfer.require_tsig => self.refuse_transfer(request, options, "unsigned", ResponseCode::Refused, response_handle).await,
+                _ => self.transfer(request, response_handle).await,
+            };
+        }
+        let forwarded = subnet
+            .filter(|subnet| options.ecs.forward && subnet.source_prefix > 0)
+            .map(|subnet| subnet.truncated(&options.ecs));
+        let snapshot = self.snapshot();
+        let catalog = snapshot.catalog();
+        let name = request.query().name();
+        if catalog.find(name).is_some_and(|authority| authority.zone_type() == ZoneType::Forward) {
+            // The catalog answers failed forwarded lookups with an empty NOERROR
+            if !self.forward_rules.forwards(name) {
+                return send_error(request, ResponseCode::Refused, response_handle).await;
+            }
+            let response_handle = ValidatingResponseHandler::new(response_handle, request);
+            return ecs::forwarding(forwarded, validator::validating(catalog.handle_request(request, response_handle))).await;
+        }
+        if additionals::applies(request, options.additional_records) {
+            let limit = hardening::udp_limit(request, self.max_udp_payload, &self.edns);
+            let response_handle = AdditionalResponseHandler::new(response_handle, snapshot.clone(), limit);
+            return ecs::forwarding(forwarded, answer_hosted(request, options, &snapshot, response_handle)).await;
+        }
+        ecs::forwarding(forwarded, answer_hosted(request, options, &snapshot, response_handle)).await
+    }
+
+    /// Answers a zone transfer request with `code`, logging and counting the refusal for
+    /// `reason`.
+    async fn refuse_transfer<R: ResponseHandler>(
+        &self,
+        request: &Request,
+        options: &HandlerOptions,
+        reason: &str,
+        code: ResponseCode,
+        response_handle: R,
+    ) -> ResponseInfo {
+        eprintln!(
+            "Refused {} of {} to {}: {}",
+            request.query().query_type(),
+            request.query().name(),
+            privacy::anonymize(options.privacy.as_ref(), request.src().ip()),
+            reason.replace('_', " ")
+        );
+        self.metrics.observe_transfer_refused(reason);
+        send_error(request, code, response_handle).await
+    }
+
+    /// Answers a zone transfer request: IXFR for a hosted zone from its journal, AXFR and
+    /// IXFR for other zones from the catalog. Over UDP, IXFR is answered with the current
+    /// SOA alone.
+    async fn transfer<R: ResponseHandler>(&self, request: &Request, response_handle: R) -> ResponseInfo {
+        if request.query().query_type() == RecordType::IXFR {
+            let serial = request.name_servers().iter().find_map(|record| match record.data() {
+                Some(RData::SOA(soa)) => Some(soa.serial()),
+                _ => None,
+            });
+            let answer = self.state.read().await.ixfr(request.query().name(), serial).await;
+            if let Some(mut answer) = answer {
+                if matches!(request.protocol(), Protocol::Udp) {
+                    answer.truncate(1);
+                }
+                return send_records(request, &answer, None, response_handle).await;
+            }
+        }
+        self.snapshot().catalog().handle_request(request, response_handle).await
+    }
+
+    /// The latest snapshot of the catalog.
+    fn snapshot(&self) -> Arc<CatalogSnapshot> {
+        self.snapshots.borrow().clone()
+    }
+}
+
+/// Answers `request` from the hosted zones of `snapshot`, completing the CNAME chains of
+/// the answers first, see `cnamechain`.
+async fn answer_hosted<R: ResponseHandler>(request: &Request, options: &HandlerOptions, snapshot: &Arc<CatalogSnapshot>, response_handle: R) -> ResponseInfo {
+    if cnamechain::applies(request, options.cname_chain_depth) {
+        let response_handle = ChainResponseHandler::new(response_handle, snapshot.clone(), request, options.cname_chain_depth);
+        return snapshot.catalog().handle_request(request, response_handle).await;
+    }
+    snapshot.catalog().handle_request(request, response_handle).await
+}
+
+/// Answers `request` with an empty response carrying `code`.
+pub async fn send_error<R: ResponseHandler>(request: &Request, code: ResponseCode, mut response_handle: R) -> ResponseInfo {
+    let response = MessageResponseBuilder::from_message_request(request).error_msg(request.header(), code);
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("Failed to send response: {}", e);
+            let mut header = Header::new();
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        }
+    }
+}
+
+/// Sends no response to `request`, returning the info the response would have had.
+pub fn drop_response(request: &Request) -> ResponseInfo {
+    Header::response_from_request(request.header()).into()
+}
+
+/// Answers `request` with an empty response with the TC bit set, asking the client to
+/// retry over TCP.
+async fn send_truncated<R: ResponseHandler>(request: &Request, mut response_handle: R) -> ResponseInfo {
+    let mut header = Header::response_from_request(request.header());
+    header.set_truncated(true);
+    let response = MessageResponseBuilder::from_message_request(request).build_no_records(header);
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("Failed to send response: {}", e);
+            let mut header = Header::new();
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        }
+    }
+}
+
+/// What is known of the client of a request beyond its address.
+struct Client<'a> {
+    /// The Client Subnet option of the request.
+    subnet: Option<Subnet>,
+    /// The device the client was identified as.
+    device: Option<&'a Device>,
+}
+
+/// The address the client of `request` is matched against the views by.
+fn view_client(request: &Request, options: &HandlerOptions, subnet: Option<&Subnet>) -> IpAddr {
+    match options.ecs.views {
+        true => ecs::client_address(request, subnet),
+        false => request.src().ip(),
+    }
+}
+
+/// Answers `request` authoritatively with `records`, adding `option` to the EDNS
+/// section if the request has one.
+pub async fn send_records<R: ResponseHandler>(
+    request: &Request,
+    records: &[Record],
+    option: Option<EdnsOption>,
+    mut response_handle: R,
+) -> ResponseInfo {
+    let mut header = Header::response_from_request(request.header());
+    header.set_message_type(MessageType::Response);
+    header.set_authoritative(true);
+    header.set_response_code(ResponseCode::NoError);
+
+    let mut builder = MessageResponseBuilder::from_message_request(request);
+    if let Some(request_edns) = request.edns() {
+        let mut edns = Edns::new();
+        edns.set_max_payload(request_edns.max_payload().max(512));
+        edns.set_dnssec_ok(request_edns.dnssec_ok());
+        if let Some(option) = option {
+            edns.options_mut().insert(option);
+        }
+        builder.edns(edns);
+    }
+    let response = builder.build(header, records.iter(), [], [], []);
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("Failed to send response: {}", e);
+            let mut header = Header::new();
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        }
     }
 }
 
 /// Encapsulates DNS server configuration options
 pub struct DnsOptions {
+    /// Addresses UDP and TCP queries are served on.
+    pub listen_addrs: Vec<SocketAddr>,
+    /// How long an idle TCP connection is kept open.
+    pub tcp_timeout: Duration,
+    /// UDP sockets bound on each listen address, sharing it through SO_REUSEPORT.
+    pub udp_sockets: usize,
+    /// Buffer sizes, DSCP marking and IP_FREEBIND of the listener sockets, see `sockopt`.
+    pub socket: SocketOptions,
+    /// Largest UDP response to queries with EDNS, see `hardening`.
+    pub max_udp_payload: u16,
+    /// How the records of multi-value answers are ordered, see `roundrobin`.
+    pub answer_order: AnswerOrderOptions,
+    /// CNAMEs followed at most to complete the chains of answers, see `cnamechain`.
+    pub cname_chain_depth: usize,
+    /// Whether answers get the addresses of targets in every hosted zone, see `additionals`.
+    pub additional_records: bool,
+    /// How ANY queries for hosted names are answered.
+    pub any_response: AnyResponse,
+    /// How the addresses of each family are answered, see `family`.
+    pub address_family: AddressFamily,
+    /// Stages queries pass through before the catalog.
+    pub pipeline: Pipeline,
+    /// Zone origins created at startup.
+    pub zones: Vec<String>,
+    /// Zone files imported at startup.
+    pub zone_files: Vec<ZoneFile>,
+    /// Directory of zone files loaded at startup and reloaded when they change.
+    pub zone_dir: Option<ZoneDirOptions>,
+    /// Zones replicated from a primary and served read-only.
+    pub secondary_zones: Vec<SecondaryZone>,
+    /// DNS-over-TLS listener, disabled when unset.
+    pub tls: Option<TlsOptions>,
+    /// DNS-over-HTTPS listener, disabled when unset.
+    pub https: Option<DohOptions>,
+    /// Certificate obtained through ACME, served by the DoT and DoH listeners instead of
+    /// their certificate files when set.
+    pub certificates: Option<Arc<CertificateResolver>>,
+    /// DNSSEC signing, disabled when unset.
+    pub dnssec: Option<DnssecOptions>,
+    /// RFC 2136 dynamic updates, refused when unset.
+    pub update: Option<UpdateOptions>,
+    /// Outbound zone transfers, refused when unset.
+    pub transfer: Option<TransferOptions>,
+    /// Catalog zone listing the primary zones, none is served when unset.
+    pub catalog_zone: Option<CatalogZoneOptions>,
+    /// TSIG keys zone transfers and dynamic updates may be signed with.
+    pub tsig_keys: Vec<TsigKey>,
+    /// Automatic SOA and NS records, none are generated when unset.
+    pub soa: Option<SoaOptions>,
+    /// Bounds on the TTLs of records added through the APIs.
+    pub ttl: TtlPolicy,
+    /// Templates new zones are populated with.
+    pub templates: Vec<ZoneTemplate>,
+    /// History of zone versions, zones can't be rolled back when unset.
+    pub history: Option<HistoryOptions>,
+    /// Forwarding for names outside the hosted zones, refused when unset.
+    pub forward: Option<ForwardOptions>,
+    /// Use and forwarding of the Client Subnet option of queries.
+    pub ecs: EcsOptions,
+    /// Defaults for health checks.
+    pub health: HealthOptions,
+    /// GeoDNS answers, not applied when unset.
+    pub geo: Option<GeoOptions>,
+    /// Split-horizon views, in matching order.
+    pub views: Vec<ViewOptions>,
+    /// Client access control, every client may query when unset.
+    pub acl: Option<AclOptions>,
+    /// Response rate limiting of UDP clients, unlimited when unset.
+    pub rate_limit: Option<RateLimitOptions>,
+    /// DNS cookies, the COOKIE option of queries is ignored when unset.
+    pub cookies: Option<CookieOptions>,
+    /// The EDNS section of responses, see `edns`.
+    pub edns: EdnsOptions,
+    /// Blocking of listed domains, nothing is blocked when unset.
+    pub blocklist: Option<BlocklistOptions>,
+    /// Response policy zones, in the order they are checked.
+    pub rpz: Vec<RpzOptions>,
+    /// How unknown names of the zones listed are answered; others get NXDOMAIN.
+    pub negative: Vec<NegativePolicy>,
+    /// Records of the running Docker containers, none are kept when unset.
+    pub docker: Option<DockerOptions>,
+    /// Records of the hostnames of DHCP leases, none are kept when unset.
+    pub dhcp: Option<DhcpOptions>,
+    /// Records of the entries of hosts files, none are kept when unset.
+    pub hosts: Option<HostsOptions>,
+    /// Multicast DNS responder, not started when unset.
+    pub mdns: Option<MdnsOptions>,
+    /// Answers identifying the instance, CHAOS queries are refused when unset.
+    pub identity: Option<IdentityOptions>,
+    /// Synthesis of AAAA answers from A records, AAAA queries are answered as they are
+    /// when unset.
+    pub dns64: Option<Dns64Options>,
+    /// Script with hooks run before and after lookup; none run when unset.
+    pub script: Option<Arc<Script>>,
+    /// Rules answering some names with the records of others, in the order they are tried.
+    pub rewrite: Vec<RewriteRule>,
+    /// Rules answering matching names with a fixed action, in the order they are tried.
+    pub query_rules: Vec<QueryRule>,
+    /// Identification of client devices, clients are told apart by address only when
+    /// unset.
+    pub clients: Option<ClientOptions>,
+    /// Query statistics, none are kept when unset.
+    pub stats: Option<StatsOptions>,
+    /// Anonymization of client addresses, which are recorded as they are when unset.
+    pub privacy: Option<PrivacyOptions>,
+    /// PROXY protocol on the TCP and DoT listeners, which take no headers when unset.
+    pub proxy_protocol: Option<ProxyProtocolOptions>,
+    /// Limits on the records added through the APIs, unlimited when unset.
+    pub quotas: Option<QuotaOptions>,
+    /// Teams whose tokens only manage the zones they own.
+    pub tenants: Vec<Tenant>,
+}
+
+/// Default and bounds of the TTLs of records added through the APIs, so that automation
+/// can't add a record that is never cached or lingers in caches for weeks by mistake
+#[derive(Clone, Copy)]
+pub struct TtlPolicy {
+    /// TTL of records added with a TTL of 0.
+    pub default: u32,
+    pub min: u32,
+    pub max: u32,
+}
+
+impl TtlPolicy {
+    /// The TTL of a record added with `ttl`, and whether `ttl` was clamped to the bounds.
+    pub fn apply(&self, ttl: u32) -> (u32, bool) {
+        if ttl == 0 {
+            return (self.default, false);
+        }
+        let clamped = ttl.clamp(self.min, self.max);
+        (clamped, clamped != ttl)
+    }
+}
+
+impl TryFrom<TtlSettings> for TtlPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: TtlSettings) -> anyhow::Result<Self> {
+        if cfg.min_ttl > cfg.max_ttl {
+            anyhow::bail!("ttl.min_ttl must not exceed ttl.max_ttl");
+        }
+        if !(cfg.min_ttl..=cfg.max_ttl).contains(&cfg.default_ttl) {
+            anyhow::bail!("ttl.default_ttl must be between ttl.min_ttl and ttl.max_ttl");
+        }
+        Ok(TtlPolicy {
+            default: cfg.default_ttl,
+            min: cfg.min_ttl,
+            max: cfg.max_ttl,
+        })
+    }
+}
+
+impl Default for TtlPolicy {
+    fn default() -> Self {
+        let cfg = TtlSettings::default();
+        TtlPolicy {
+            default: cfg.default_ttl,
+            min: cfg.min_ttl,
+            max: cfg.max_ttl,
+        }
+    }
+}
+
+/// Encapsulates DNS-over-TLS listener options
+pub struct TlsOptions {
     pub listen_addr: String,
+    /// PEM-encoded certificate chain presented to clients.
+    pub cert_path: PathBuf,
+    /// PEM-encoded private key for the certificate.
+    pub key_path: PathBuf,
 }
 
-impl From<DnsSettings> for DnsOptions {
-    fn from(cfg: DnsSettings) -> Self {
-        DnsOptions {
+impl From<TlsSettings> for TlsOptions {
+    fn from(cfg: TlsSettings) -> Self {
+        TlsOptions {
             listen_addr: cfg.listen_addr,
+            cert_path: PathBuf::from(cfg.cert_path),
+            key_path: PathBuf::from(cfg.key_path),
         }
     }
 }
 
-/// Holds the state of the DNS server, including the authoritative data and catalog.
-pub struct DnsState {
-    catalog: Arc<RwLock<Catalog>>,
-    authority: Arc<InMemoryAuthority>,
-    // origin: LowerName,
+impl TryFrom<DnsSettings> for DnsOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: DnsSettings) -> anyhow::Result<Self> {
+        let listen_addrs = cfg
+            .listen_addrs
+            .iter()
+            .map(|addr| addr.parse().map_err(|e| anyhow::anyhow!("invalid listen address {}: {}", addr, e)))
+            .collect::<anyhow::Result<Vec<SocketAddr>>>()?;
+        if listen_addrs.is_empty() {
+            anyhow::bail!("at least one DNS listen address is required");
+        }
+        if !(512..=4096).contains(&cfg.max_udp_payload) {
+            anyhow::bail!("dns.max_udp_payload must be from 512 to 4096 bytes, not {}", cfg.max_udp_payload);
+        }
+        let tsig_keys = tsig::configured_keys(&cfg)?;
+        if let Some(clients) = &cfg.clients {
+            clientid::check_views(clients, &cfg.views)?;
+        }
+        Ok(DnsOptions {
+            listen_addrs,
+            tcp_timeout: Duration::from_secs(cfg.tcp_timeout_secs),
+            udp_sockets: match cfg.udp_sockets {
+                0 => std::thread::available_parallelism().map_or(1, usize::from),
+                sockets => sockets,
+            },
+            socket: SocketOptions::try_from(cfg.socket)?,
+            max_udp_payload: cfg.max_udp_payload,
+            answer_order: AnswerOrderOptions::parse(cfg.round_robin, &cfg.answer_order)?,
+            cname_chain_depth: cfg.cname_chain_depth,
+            additional_records: cfg.additional_records,
+            any_response: cfg.any_response.parse()?,
+            address_family: cfg.address_family.parse()?,
+            pipeline: Pipeline::parse(&cfg.pipeline)?,
+            zones: cfg.zones,
+            zone_files: cfg.zone_files.into_iter().map(ZoneFile::from).collect(),
+            zone_dir: cfg.zone_dir.map(ZoneDirOptions::from),
+            secondary_zones: cfg
+                .secondary_zones
+                .into_iter()
+                .map(SecondaryZone::try_from)
+                .collect::<anyhow::Result<_>>()?,
+            tls: cfg.tls.map(TlsOptions::from),
+            https: cfg.https.map(DohOptions::from),
+            certificates: None,
+            dnssec: cfg.dnssec.map(DnssecOptions::try_from).transpose()?,
+            update: cfg.update.map(UpdateOptions::from),
+            transfer: cfg.transfer.map(TransferOptions::try_from).transpose()?,
+            catalog_zone: cfg.catalog_zone.map(CatalogZoneOptions::try_from).transpose()?,
+            tsig_keys,
+            soa: cfg.soa.map(SoaOptions::try_from).transpose()?,
+            ttl: TtlPolicy::try_from(cfg.ttl)?,
+            templates: template::parse_all(&cfg.templates)?,
+            history: cfg.history.map(HistoryOptions::try_from).transpose()?,
+            forward: cfg.forward.map(ForwardOptions::try_from).transpose()?,
+            ecs: EcsOptions::try_from(cfg.ecs)?,
+            health: HealthOptions::from(cfg.health),
+            geo: cfg.geo.map(GeoOptions::try_from).transpose()?,
+            views: cfg.views.into_iter().map(ViewOptions::try_from).collect::<anyhow::Result<_>>()?,
+            acl: cfg.acl.map(AclOptions::try_from).transpose()?,
+            rate_limit: cfg.rate_limit.map(RateLimitOptions::try_from).transpose()?,
+            cookies: cfg.cookies.map(CookieOptions::try_from).transpose()?,
+            edns: EdnsOptions::try_from(cfg.edns)?,
+            blocklist: cfg.blocklist.map(BlocklistOptions::try_from).transpose()?,
+            rpz: cfg.rpz.into_iter().map(RpzOptions::from).collect(),
+            negative: cfg.negative.into_iter().map(NegativePolicy::try_from).collect::<anyhow::Result<_>>()?,
+            docker: cfg.docker.map(DockerOptions::from),
+            dhcp: cfg.dhcp.map(DhcpOptions::try_from).transpose()?,
+            hosts: cfg.hosts.map(HostsOptions::try_from).transpose()?,
+            mdns: cfg.mdns.map(MdnsOptions::try_from).transpose()?,
+            identity: cfg.identity.map(IdentityOptions::from),
+            dns64: cfg.dns64.map(Dns64Options::try_from).transpose()?,
+            script: cfg.script.map(Script::try_from).transpose()?.map(Arc::new),
+            rewrite: cfg
+                .rewrite
+                .into_iter()
+                .map(|rule| RewriteRule::new(RewriteRuleEntry::from(rule)))
+                .collect::<Result<_, RdnsError>>()?,
+            query_rules: queryrules::load_configured(&cfg.query_rules, &cfg.query_rule_files)?,
+            clients: cfg.clients.map(ClientOptions::try_from).transpose()?,
+            stats: cfg.stats.map(StatsOptions::try_from).transpose()?,
+            privacy: cfg.privacy.map(PrivacyOptions::try_from).transpose()?,
+            proxy_protocol: cfg.proxy_protocol.map(ProxyProtocolOptions::try_from).transpose()?,
+            quotas: cfg.quotas.map(QuotaOptions::try_from).transpose()?,
+            tenants: cfg.tenants.into_iter().map(Tenant::try_from).collect::<anyhow::Result<_>>()?,
+        })
+    }
 }
 
-impl DnsState {
-    /// Constructs a new `DnsState` with an empty authoritative zone for `example.com.
-    pub fn new() -> anyhow::Result<Self> {
-        let origin = LowerName::new(&Name::from_ascii("example.com.")?);
-        let authority = Arc::new(InMemoryAuthority::empty(origin.clone().into(), ZoneType::Primary, false));
+impl DnsOptions {
+    /// Options built in code rather than read from the config, for embedding the server:
+    /// listening nowhere and hosting no zones until told to, with every optional feature
+    /// disabled and the other settings at their config defaults.
+    pub fn builder() -> DnsOptionsBuilder {
+        DnsOptionsBuilder {
+            options: DnsOptions {
+                listen_addrs: Vec::new(),
+                tcp_timeout: Duration::from_secs(settings::default_tcp_timeout_secs()),
+                udp_sockets: settings::default_udp_sockets(),
+                socket: SocketOptions::default(),
+                max_udp_payload: settings::default_max_udp_payload(),
+                answer_order: AnswerOrderOptions::default(),
+                cname_chain_depth: 8,
+                additional_records: true,
+                any_response: AnyResponse::default(),
+                address_family: AddressFamily::default(),
+                pipeline: Pipeline::default(),
+                zones: Vec::new(),
+                zone_files: Vec::new(),
+                zone_dir: None,
+                secondary_zones: Vec::new(),
+                tls: None,
+                https: None,
+                certificates: None,
+                dnssec: None,
+                update: None,
+                transfer: None,
+                catalog_zone: None,
+                tsig_keys: Vec::new(),
+                soa: None,
+                ttl: TtlPolicy::default(),
+                templates: Vec::new(),
+                history: None,
+                forward: None,
+                ecs: EcsOptions::default(),
+                health: HealthOptions::from(HealthSettings::default()),
+                geo: None,
+                views: Vec::new(),
+                acl: None,
+                rate_limit: None,
+                cookies: None,
+                edns: EdnsOptions::default(),
+                blocklist: None,
+                rpz: Vec::new(),
+                negative: Vec::new(),
+                docker: None,
+                dhcp: None,
+                hosts: None,
+                mdns: None,
+                identity: None,
+                dns64: None,
+                script: None,
+                rewrite: Vec::new(),
+                query_rules: Vec::new(),
+                clients: None,
+                stats: None,
+                privacy: None,
+                proxy_protocol: None,
+                quotas: None,
+                tenants: Vec::new(),
+            },
+        }
+    }
+}
 
-        let mut catalog = Catalog::new();
-        catalog.upsert(origin.clone(), Box::new(authority.clone()));
+/// Builds `DnsOptions` step by step, see `DnsOptions::builder`.
+pub struct DnsOptionsBuilder {
+    options: DnsOptions,
+}
 
-        Ok(Self {
-            catalog: Arc::new(RwLock::new(catalog)),
-            authority,
-        })
+impl DnsOptionsBuilder {
+    /// Adds an address UDP and TCP queries are served on.
+    pub fn listen_addr(mut self, addr: SocketAddr) -> Self {
+        self.options.listen_addrs.push(addr);
+        self
     }
 
-    /// Helper function to construct an A record from input fields.
-    fn build_a_record(name: String, value: String, ttl: u32) -> anyhow::Result<Record> {
-        let fqdn = Name::from_ascii(&name)?;
-        let ip = value.parse()?;
-        let record = Record::from_rdata(fqdn, ttl, RData::A(ip));
-        Ok(record)
+    pub fn tcp_timeout(mut self, timeout: Duration) -> Self {
+        self.options.tcp_timeout = timeout;
+        self
     }
 
-    /// Helper function to construct an RrKey for name record mutation
-    fn build_a_record_key(name: String) -> anyhow::Result<RrKey,anyhow::Error> {
-        let name = LowerName::from_str(&name)?;
-        let rr_key = RrKey::new(name, RecordType::A);
-        Ok(rr_key)
+    /// Binds `sockets` UDP sockets on each listen address with SO_REUSEPORT, so that the
+    /// kernel spreads incoming queries across them, and the workers serving them.
+    pub fn udp_sockets(mut self, sockets: usize) -> Self {
+        self.options.udp_sockets = sockets.max(1);
+        self
     }
 
-    /// Adds an A record to the in-memory DNS zone.
-    pub async fn add_record(&self, name: String, value: String, ttl: u32) -> anyhow::Result<()> {
-        let record = DnsState::build_a_record(name, value, ttl)?;
-        self.authority.upsert(record, 0).await;
-        Ok(())
+    /// Sets the buffer sizes, DSCP marking and IP_FREEBIND of the listener sockets.
+    pub fn socket_options(mut self, socket: SocketOptions) -> Self {
+        self.options.socket = socket;
+        self
     }
 
-    /// Deletes an A record (by key) from the in-memory DNS zone.
-    pub async fn delete_record(&self, name: String) -> anyhow::Result<()> {
-        let key = DnsState::build_a_record_key(name)?;
-        let mut records = self.authority.records_mut().await;
-        records.remove(&key);
-        Ok(())
+    /// Caps UDP responses at `bytes`, kept from 512 to 4096, whatever EDNS buffer size
+    /// clients advertise; 1232 unless set otherwise.
+    pub fn max_udp_payload(mut self, bytes: u16) -> Self {
+        self.options.max_udp_payload = bytes.clamp(512, 4096);
+        self
     }
 
-    /// Gets all A records from the in-memory DNS zone (exludes RRSIGS)
-    pub async fn get_all_records(&self) -> Vec<(String, String, u32)> {
-        let records = self.authority.records_mut().await;
+    pub fn round_robin(mut self, round_robin: bool) -> Self {
+        self.options.answer_order.default = if round_robin { AnswerOrder::RoundRobin } else { AnswerOrder::Fixed };
+        self
+    }
 
-        let mut result = Vec::new();
-        for (_key, record_set) in records.iter() {
-            for record in record_set.records_without_rrsigs() {
-                if let Some(RData::A(ip)) = record.data() {
-                    result.push((
-                        record.name().to_string(),
-                        ip.to_string(),
-                        record.ttl(),
-                    ));
-                }
-            }
-        }
+    /// Sets how the answers of a zone or a name are ordered.
+    pub fn answer_order(mut self, rule: OrderRule) -> Self {
+        Arc::make_mut(&mut self.options.answer_order.rules).push(rule);
+        self
+    }
 
-        result
+    /// Follows at most `depth` CNAMEs to complete the chains of authoritative answers.
+    pub fn cname_chain_depth(mut self, depth: usize) -> Self {
+        self.options.cname_chain_depth = depth;
+        self
     }
 
-    /// Returns a clone of the internal DNS catalog reference.
-    pub fn catalog(&self) -> Arc<RwLock<Catalog>> {
-        self.catalog.clone()
+    /// Sets whether answers get the addresses of MX, SRV and NS targets in every hosted
+    /// zone, as they do unless set otherwise.
+    pub fn additional_records(mut self, additional_records: bool) -> Self {
+        self.options.additional_records = additional_records;
+        self
     }
-}
 
-/// Starts the DNS server on the configured UDP port using the provided `DnsState`.
-///
-/// Binds a UDP socket, wraps it in a `tokio::net::UdpSocket`, and launches
-/// the `ServerFuture` from the hickory-server crate to handle requests.
-///
-/// # Errors
-///
-/// Returns an error if the socket binding, conversion, or server execution fails.
-pub async fn run_dns_server(state: Arc<RwLock<DnsState>>, options: DnsOptions) -> anyhow::Result<()> {
-    let addr = options.listen_addr.clone();
-    let std_socket = UdpSocket::bind(&addr)?;
-    std_socket.set_nonblocking(true)?;
-    let tokio_socket = tokio::net::UdpSocket::from_std(std_socket)?;
-
-    let catalog = {
-        let state = state.read().await;
-        state.catalog() // Arc<RwLock<Catalog>>
-    };
+    /// Sets how ANY queries for hosted names are answered, a single HINFO record unless
+    /// set otherwise.
+    pub fn any_response(mut self, any_response: AnyResponse) -> Self {
+        self.options.any_response = any_response;
+        self
+    }
 
-    let handler = SharedCatalog(catalog);
-    let mut server = ServerFuture::new(handler);
-    server.register_socket(tokio_socket);
+    /// Sets how the addresses of each family are answered, as the records are unless set
+    /// otherwise.
+    pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+        self.options.address_family = address_family;
+        self
+    }
 
-    println!("DNS server listening on {} (UDP)",&addr);
-    server.block_until_done().await?;
-    Ok(())
+    /// Sets the stages queries pass through before the catalog, every stage in the
+    /// default order unless set otherwise.
+    pub fn pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.options.pipeline = pipeline;
+        self
+    }
+
+    /// Adds a zone created at startup, e.g. `example.com.`.
+    pub fn zone(mut self, origin: impl Into<String>) -> Self {
+        self.options.zones.push(origin.into());
+        self
+    }
+
+    /// Adds a zone file imported at startup.
+    pub fn zone_file(mut self, zone_file: ZoneFile) -> Self {
+        self.options.zone_files.push(zone_file);
+        self
+    }
+
+    /// Loads the zone files of a directory at startup, reloading them when they change.
+    pub fn zone_dir(mut self, zone_dir: ZoneDirOptions) -> Self {
+        self.options.zone_dir = Some(zone_dir);
+        self
+    }
+
+    /// Adds a zone replicated from a primary.
+    pub fn secondary_zone(mut self, zone: SecondaryZone) -> Self {
+        self.options.secondary_zones.push(zone);
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.options.tls = Some(tls);
+        self
+    }
+
+    pub fn https(mut self, https: DohOptions) -> Self {
+        self.options.https = Some(https);
+        self
+    }
+
+    /// Serves the certificate of `certificates` on the DoT and DoH listeners.
+    pub fn certificates(mut self, certificates: Arc<CertificateResolver>) -> Self {
+        self.options.certificates = Some(certificates);
+        self
+    }
+
+    pub fn dnssec(mut self, dnssec: DnssecOptions) -> Self {
+        self.options.dnssec = Some(dnssec);
+        self
+    }
+
+    pub fn update(mut self, update: UpdateOptions) -> Self {
+        self.options.update = Some(update);
+        self
+    }
+
+    pub fn transfer(mut self, transfer: TransferOptions) -> Self {
+        self.options.transfer = Some(transfer);
+        self
+    }
+
+    /// Serves a catalog zone listing the primary zones.
+    pub fn catalog_zone(mut self, catalog_zone: CatalogZoneOptions) -> Self {
+        self.options.catalog_zone = Some(catalog_zone);
+        self
+    }
+
+    /// Adds a key zone transfers and dynamic updates may be signed with.
+    pub fn tsig_key(mut self, key: TsigKey) -> Self {
+        self.options.tsig_keys.push(key);
+        self
+    }
+
+    pub fn soa(mut self, soa: SoaOptions) -> Self {
+        self.options.soa = Some(soa);
+        self
+    }
+
+    pub fn ttl(mut self, ttl: TtlPolicy) -> Self {
+        self.options.ttl = ttl;
+        self
+    }
+
+    /// Adds a template new zones can be populated with.
+    pub fn template(mut self, template: ZoneTemplate) -> Self {
+        self.options.templates.push(template);
+        self
+    }
+
+    pub fn history(mut self, history: HistoryOptions) -> Self {
+        self.options.history = Some(history);
+        self
+    }
+
+    pub fn forward(mut self, forward: ForwardOptions) -> Self {
+        self.options.forward = Some(forward);
+        self
+    }
+
+    /// Sets how the Client Subnet option of queries is used and passed on upstream.
+    pub fn ecs(mut self, ecs: EcsOptions) -> Self {
+        self.options.ecs = ecs;
+        self
+    }
+
+    pub fn health(mut self, health: HealthOptions) -> Self {
+        self.options.health = health;
+        self
+    }
+
+    pub fn geo(mut self, geo: GeoOptions) -> Self {
+        self.options.geo = Some(geo);
+        self
+    }
+
+    /// Adds a split-horizon view, matched after the views added before it.
+    pub fn view(mut self, view: ViewOptions) -> Self {
+        self.options.views.push(view);
+        self
+    }
+
+    pub fn acl(mut self, acl: AclOptions) -> Self {
+        self.options.acl = Some(acl);
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: RateLimitOptions) -> Self {
+        self.options.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn cookies(mut self, cookies: CookieOptions) -> Self {
+        self.options.cookies = Some(cookies);
+        self
+    }
+
+    pub fn edns(mut self, edns: EdnsOptions) -> Self {
+        self.options.edns = edns;
+        self
+    }
+
+    pub fn blocklist(mut self, blocklist: BlocklistOptions) -> Self {
+        self.options.blocklist = Some(blocklist);
+        self
+    }
+
+    /// Adds a response policy zone, checked after the zones added before it.
+    pub fn rpz(mut self, rpz: RpzOptions) -> Self {
+        self.options.rpz.push(rpz);
+        self
+    }
+
+    /// Sets how the unknown names of a zone are answered.
+    pub fn negative(mut self, policy: NegativePolicy) -> Self {
+        self.options.negative.push(policy);
+        self
+    }
+
+    /// Keeps records for the running Docker containers in the zone `docker` names.
+    pub fn docker(mut self, docker: DockerOptions) -> Self {
+        self.options.docker = Some(docker);
+        self
+    }
+
+    /// Keeps records for the hostnames of the leases in the lease file `dhcp` names.
+    pub fn dhcp(mut self, dhcp: DhcpOptions) -> Self {
+        self.options.dhcp = Some(dhcp);
+        self
+    }
+
+    /// Keeps records for the entries of the hosts files `hosts` names.
+    pub fn hosts(mut self, hosts: HostsOptions) -> Self {
+        self.options.hosts = Some(hosts);
+        self
+    }
+
+    /// Answers multicast DNS queries for the zone `mdns` names and advertises its services.
+    pub fn mdns(mut self, mdns: MdnsOptions) -> Self {
+        self.options.mdns = Some(mdns);
+        self
+    }
+
+    /// Answers CHAOS identity queries and the NSID option as `identity` says.
+    pub fn identity(mut self, identity: IdentityOptions) -> Self {
+        self.options.identity = Some(identity);
+        self
+    }
+
+    /// Synthesizes AAAA answers from A records with the NAT64 prefix of `dns64`.
+    pub fn dns64(mut self, dns64: Dns64Options) -> Self {
+        self.options.dns64 = Some(dns64);
+        self
+    }
+
+    /// Runs the hooks of `script` on queries and answers.
+    pub fn script(mut self, script: Script) -> Self {
+        self.options.script = Some(Arc::new(script));
+        self
+    }
+
+    /// Adds a rewrite rule, tried after the rules added before it.
+    pub fn rewrite(mut self, rule: RewriteRule) -> Self {
+        self.options.rewrite.push(rule);
+        self
+    }
+
+    /// Adds a query rule, tried after the rules added before it.
+    pub fn query_rule(mut self, rule: QueryRule) -> Self {
+        self.options.query_rules.push(rule);
+        self
+    }
+
+    /// Identifies client devices as `clients` says.
+    pub fn clients(mut self, clients: ClientOptions) -> Self {
+        self.options.clients = Some(clients);
+        self
+    }
+
+    /// Counts the queries by name and type as `stats` says.
+    pub fn stats(mut self, stats: StatsOptions) -> Self {
+        self.options.stats = Some(stats);
+        self
+    }
+
+    /// Anonymizes the client addresses recorded as `privacy` says.
+    pub fn privacy(mut self, privacy: PrivacyOptions) -> Self {
+        self.options.privacy = Some(privacy);
+        self
+    }
+
+    /// Reads a PROXY header at the start of the TCP and DoT connections from `proxy_protocol`'s
+    /// trusted peers, handling their queries as coming from the client it announces.
+    pub fn proxy_protocol(mut self, proxy_protocol: ProxyProtocolOptions) -> Self {
+        self.options.proxy_protocol = Some(proxy_protocol);
+        self
+    }
+
+    /// Refuses records added through the APIs beyond the quotas of `quotas`.
+    pub fn quotas(mut self, quotas: QuotaOptions) -> Self {
+        self.options.quotas = Some(quotas);
+        self
+    }
+
+    /// Adds a tenant, whose tokens only manage the zones it owns.
+    pub fn tenant(mut self, tenant: Tenant) -> Self {
+        self.options.tenants.push(tenant);
+        self
+    }
+
+    /// # Errors
+    ///
+    /// Returns an error if no listen address was added.
+    pub fn build(self) -> anyhow::Result<DnsOptions> {
+        if self.options.listen_addrs.is_empty() {
+            anyhow::bail!("at least one DNS listen address is required");
+        }
+        Ok(self.options)
+    }
+}
+
+/// A single resource record in its textual, zone file form.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecordEntry {
+    pub name: String,
+    /// Record type mnemonic, e.g. `A` or `MX`
+    #[serde(default = "default_record_type")]
+    pub record_type: String,
+    /// Record data in master-file syntax
+    pub value: String,
+    pub ttl: u32,
+    /// Comment, owner and tags; empty for records without any
+    #[serde(default, skip_serializing_if = "RecordMetadata::is_empty")]
+    pub metadata: RecordMetadata,
+}
+
+fn default_record_type() -> String {
+    RecordType::A.to_string()
+}
+
+impl RecordEntry {
+    /// The entry with its name in Unicode rather than the punycode it is held in.
+    pub fn into_unicode(mut self) -> Self {
+        self.name = unicode_name(&self.name);
+        self
+    }
+}
+
+/// The Unicode form of `name`, whose punycode labels are decoded; other names are
+/// returned as they are.
+pub fn unicode_name(name: &str) -> String {
+    Name::from_ascii(name).map_or_else(|_| name.to_string(), |name| name.to_utf8())
+}
+
+impl From<&Record> for RecordEntry {
+    fn from(record: &Record) -> Self {
+        RecordEntry {
+            name: record.name().to_ascii(),
+            record_type: record.record_type().to_string(),
+            value: record.data().map(zonefile::format_rdata).unwrap_or_default(),
+            ttl: record.ttl(),
+            metadata: RecordMetadata::default(),
+        }
+    }
+}
+
+/// The kind of change a `RecordEvent` reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordChange {
+    Added,
+    /// An existing record was re-added, e.g. with a new TTL, or replaced by `update_record`.
+    Updated,
+    Deleted,
+}
+
+/// What adding a record did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Added {
+    /// TTL the record's was clamped to, if it was out of bounds.
+    pub clamped: Option<u32>,
+    /// Whether a record of the same value existed already, which only had its TTL, lease
+    /// and metadata replaced.
+    pub duplicate: bool,
+}
+
+/// A change to a hosted record, published to `DnsState::subscribe` receivers.
+#[derive(Debug, Clone)]
+pub struct RecordEvent {
+    pub change: RecordChange,
+    pub record: RecordEntry,
+    pub timestamp: SystemTime,
+}
+
+/// A query answered, published to `DnsState::subscribe_queries` receivers.
+#[derive(Debug, Clone)]
+pub struct QueryEvent {
+    pub name: String,
+    pub query_type: String,
+    /// Address of the client, anonymized as `[dns.privacy]` says.
+    pub client: IpAddr,
+    pub protocol: String,
+    pub response_code: String,
+    /// How long answering the query took.
+    pub duration: Duration,
+    pub timestamp: SystemTime,
+}
+
+/// Whether a zone was created or deleted, or how the sync of a secondary zone fared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneChange {
+    Created,
+    Deleted,
+    /// A secondary zone went unrefreshed past its refresh and retry intervals.
+    Stale,
+    /// A secondary zone went unrefreshed past its expire interval, and is no longer served.
+    Expired,
+    /// A stale or expired secondary zone was refreshed again.
+    Recovered,
+}
+
+/// A primary zone created or deleted, or a secondary zone gone stale, expired or
+/// recovered, published to `DnsState::subscribe_zones` receivers.
+#[derive(Debug, Clone)]
+pub struct ZoneEvent {
+    pub change: ZoneChange,
+    pub origin: LowerName,
+    pub timestamp: SystemTime,
+}
+
+/// Where the rollovers of a signed zone's keys stand, see `DnsState::key_rollovers`.
+#[derive(Debug, Clone)]
+pub struct KeyRollovers {
+    pub zsk: Option<Rollover>,
+    pub ksk: Option<Rollover>,
+    /// When the current ZSK started signing
+    pub zsk_signing_since: Option<SystemTime>,
+    /// The step the rollovers take next and when, if one is scheduled
+    pub next_step: Option<(RolloverStep, SystemTime)>,
+    /// The DS of the KSK being rolled in, in zone file syntax
+    pub next_ds: Option<String>,
+}
+
+/// What a `StateChange` changed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeScope {
+    /// The records of a primary zone, or the pools, health checks, geo records, rollouts,
+    /// values on schedules, name patterns or view records of names in it.
+    Zone(LowerName),
+    ZoneDeleted(LowerName),
+    /// The runtime blocklist entries.
+    Blocklist,
+    /// Everything, e.g. when a snapshot was restored.
+    All,
+}
+
+impl fmt::Display for ChangeScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChangeScope::Zone(origin) => write!(f, "zone {}", origin),
+            ChangeScope::ZoneDeleted(origin) => write!(f, "zone {} deleted", origin),
+            ChangeScope::Blocklist => write!(f, "blocklist"),
+            ChangeScope::All => write!(f, "all"),
+        }
+    }
+}
+
+/// A change to the replicated state, published to `DnsState::subscribe_changes` receivers.
+#[derive(Debug, Clone)]
+pub struct StateChange {
+    /// Position of the change in the sequence of changes made to the state.
+    pub sequence: u64,
+    pub timestamp: SystemTime,
+    pub scope: ChangeScope,
+}
+
+/// Outcome of `DnsState::import_records`.
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub inserted: u32,
+    /// Records identical to one already in their zone.
+    pub skipped: u32,
+    /// Records that could not be imported, by position in the input, with the reason.
+    pub failed: Vec<(usize, String)>,
+}
+
+/// Filters and position of a `DnsState::query_records` page.
+#[derive(Debug, Default)]
+pub struct RecordQuery {
+    /// Only names starting with this, compared case-insensitively. Unicode filters
+    /// match the Unicode form of punycode names.
+    pub name_prefix: String,
+    /// Only names equal to or under this, e.g. a zone origin; a trailing dot is optional.
+    pub name_suffix: String,
+    /// Only records of this type; all types when empty.
+    pub record_type: String,
+    /// Only records of this owner; those of any owner when empty.
+    pub owner: String,
+    /// Only records with all of these tags; a tag with an empty value matches any value.
+    pub tags: BTreeMap<String, String>,
+    /// Only records in the zones of this tenant; those of every zone when unset.
+    pub tenant: Option<String>,
+    /// Only records sorting after this `(name, record_type, value)` key.
+    pub after: Option<(String, String, String)>,
+    pub limit: usize,
+}
+
+/// A page of `DnsState::query_records`.
+#[derive(Debug, Default)]
+pub struct RecordPage {
+    pub records: Vec<RecordEntry>,
+    /// Whether more records sort after the last of the page.
+    pub more: bool,
+    /// Records matching the filters, on every page.
+    pub total: usize,
+}
+
+/// A single mutation in a changeset applied by `DnsState::apply_changeset`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOperation {
+    /// Adds a record, like `add_record`.
+    Add(RecordEntry),
+    /// Deletes all records of a type, like `delete_record`.
+    Delete { name: String, record_type: String },
+}
+
+impl ChangeOperation {
+    /// The name and record type the operation changes the records of.
+    pub fn target(&self) -> (&str, &str) {
+        match self {
+            ChangeOperation::Add(entry) => (&entry.name, &entry.record_type),
+            ChangeOperation::Delete { name, record_type } => (name, record_type),
+        }
+    }
+}
+
+/// What a mutation would change, as a dry run of it reports.
+#[derive(Debug, Clone, Default)]
+pub struct ChangePlan {
+    /// Records the mutation would add, update or delete, PTR records and generated SOA
+    /// and NS records included, in the order it would change them
+    pub changes: Vec<RecordEvent>,
+    /// SOA serials of the zones the mutation would change
+    pub serials: Vec<SerialChange>,
+}
+
+/// The SOA serial a zone would move to.
+#[derive(Debug, Clone)]
+pub struct SerialChange {
+    pub origin: LowerName,
+    /// Serial before the change, `None` when the zone has no SOA.
+    pub serial: Option<u32>,
+    /// Serial after the change, `None` when the zone would still have no SOA.
+    pub next: Option<u32>,
+}
+
+/// Whether records of `record_type` are managed by rdns rather than by zone specs: the
+/// SOA, whose serial rdns bumps, and the DNSSEC records signing generates.
+fn is_managed(record_type: RecordType) -> bool {
+    record_type == RecordType::SOA || dnssec::is_generated(record_type)
+}
+
+/// The event a planned change to `record` would publish.
+fn planned(change: RecordChange, record: &Record) -> RecordEvent {
+    RecordEvent {
+        change,
+        record: RecordEntry::from(record),
+        timestamp: SystemTime::now(),
+    }
+}
+
+/// A zone's authority together with the working copy of its records a changeset is
+/// staged in.
+type StagedZone = (Arc<InMemoryAuthority>, BTreeMap<RrKey, Arc<RecordSet>>);
+
+/// Events buffered per subscriber before it is considered lagging.
+const EVENT_CAPACITY: usize = 1024;
+
+/// Holds the state of the DNS server, including the authoritative data and catalog.
+pub struct DnsState {
+    /// Authorities registered for serving, published to the request handler.
+    snapshots: CatalogSnapshots,
+    /// One authority per hosted zone, keyed by zone origin.
+    zones: HashMap<LowerName, Arc<InMemoryAuthority>>,
+    /// Optional store that every mutation is written through to.
+    store: Option<Store>,
+    /// Changes published since the state was last persisted, journaled along with it.
+    unjournaled: Mutex<Vec<JournalEntry>>,
+    /// DNSSEC signing configuration; zones are served unsigned when unset.
+    dnssec: Option<DnssecOptions>,
+    /// Secondaries notified of zone changes; zones are not transferable when unset.
+    transfer: Option<TransferOptions>,
+    /// Catalog zone listing the primary zones; unset when none is served.
+    catalog_zone: Option<CatalogZone>,
+    /// Changes to the zones, answering IXFR requests; unset when zones aren't transferable.
+    journal: Option<Journal>,
+    /// Keys of the config and those created through the control API.
+    tsig_keys: TsigKeyring,
+    soa: Option<SoaOptions>,
+    ttl: TtlPolicy,
+    /// Limits on the records added through the APIs; unset when unlimited.
+    quotas: Option<QuotaOptions>,
+    /// Records the hosted zones hold, counted again for a zone whenever it changes
+    /// rather than for every zone on each addition.
+    usage: Mutex<RecordUsage>,
+    /// Tenants of the config and those created through the control API.
+    tenants: Tenants,
+    /// Tenants owning the zones created by their tokens or assigned to them at runtime,
+    /// on top of the zones the config gives tenants.
+    zone_tenants: HashMap<LowerName, String>,
+    /// How queries are answered while the instance is drained.
+    drain: watch::Sender<DrainMode>,
+    templates: Vec<ZoneTemplate>,
+    /// Default TTLs of the zones created from templates that give one.
+    default_ttls: HashMap<LowerName, u32>,
+    /// Kept versions of the primary zones; unset when no history is kept.
+    history: Option<History>,
+    /// Origins of the secondary zones, which are only modified by zone transfers.
+    secondaries: HashMap<LowerName, SecondaryZone>,
+    /// Origins of the zones whose A and AAAA records get matching PTR records.
+    auto_reverse: HashSet<LowerName>,
+    /// Origins of the zones frozen for maintenance, whose changes are rejected until they
+    /// are thawed.
+    frozen: HashSet<LowerName>,
+    /// Cache of forwarded answers; unset when forwarding is disabled.
+    cache: Option<Arc<ResponseCache>>,
+    /// Forwarding rules, shared with the forwarder.
+    forward_rules: Arc<ForwardRules>,
+    /// Rewrite rules, shared with the request handler.
+    rewrite_rules: Arc<RewriteRules>,
+    /// Query rules, shared with the request handler.
+    query_rules: Arc<QueryRules>,
+    /// Registry shared with the request handler and the control server.
+    metrics: Arc<Metrics>,
+    /// Record changes made through the record mutation methods.
+    events: broadcast::Sender<RecordEvent>,
+    /// Zones created and deleted through `create_zone` and `delete_zone`.
+    zone_events: broadcast::Sender<ZoneEvent>,
+    /// Queries answered by the DNS listeners.
+    queries: broadcast::Sender<QueryEvent>,
+    /// Changes to the state, as followers replicate them.
+    changes: broadcast::Sender<StateChange>,
+    /// Sequence number of the latest change.
+    sequence: AtomicU64,
+    /// Address of the leader this instance follows; changes other than those replicated
+    /// from the leader are rejected while it is set.
+    leader: Option<String>,
+    /// Weighted and failover pools, shared with the request handler.
+    pools: Arc<PoolTable>,
+    /// Health checks of record values, shared with the request handler.
+    health: Arc<HealthMonitor>,
+    /// Client locator for geo records; unset when GeoDNS is disabled.
+    geo: Option<Arc<GeoLocator>>,
+    /// Geo records, shared with the request handler.
+    geo_records: Arc<GeoTable>,
+    /// Rollouts, shared with the request handler.
+    rollouts: Arc<RolloutTable>,
+    /// Values on schedules, shared with the request handler.
+    timed: Arc<TimedTable>,
+    /// Denial of existence of the signed zones not using NSEC, shared with the request
+    /// handler.
+    denials: Arc<Denials>,
+    /// Name patterns, most specific first, shared with the request handler.
+    patterns: Arc<PatternTable>,
+    /// Overrides of forwarded names, shared with the request handler.
+    overrides: Arc<OverrideTable>,
+    /// Aliases, shared with the request handler.
+    aliases: Arc<AliasTable>,
+    /// Leases of the records added with one, removed once they lapse.
+    leases: Leases,
+    /// Record changes queued until they are due.
+    scheduled: Schedule,
+    /// Record changes proposed for an admin's approval.
+    approvals: Approvals,
+    /// Comments, owners and tags of the records given any.
+    metadata: MetadataTable,
+    /// Service registrations and the records they publish.
+    services: ServiceTable,
+    /// Split-horizon views and their overlays, shared with the request handler.
+    views: Arc<Views>,
+    /// Blocklists and runtime entries, shared with the request handler; unset when
+    /// blocking is disabled.
+    blocklist: Option<Arc<Blocklist>>,
+    /// Query statistics, fed by the request handler; unset when they are disabled.
+    stats: Option<Arc<QueryStats>>,
+}
+
+impl DnsState {
+    /// Constructs a new `DnsState`, reloading any zones and records persisted in `store`
+    /// and creating an empty authoritative zone for each configured zone not already present.
+    /// When forwarding is configured, the forwarder is registered for the root zone.
+    pub async fn new(options: &DnsOptions, store: Option<Store>, metrics: Arc<Metrics>) -> Result<Self, RdnsError> {
+        let snapshot = match &store {
+            Some(store) => store.load().await?,
+            None => None,
+        };
+
+        let mut state = Self {
+            snapshots: CatalogSnapshots::default(),
+            zones: HashMap::new(),
+            store: None,
+            unjournaled: Mutex::new(Vec::new()),
+            usage: Mutex::new(RecordUsage::default()),
+            dnssec: options.dnssec.clone(),
+            transfer: options.transfer.clone(),
+            catalog_zone: None,
+            journal: options.transfer.as_ref().map(|transfer| Journal::new(transfer.journal_size)),
+            tsig_keys: TsigKeyring::default(),
+            soa: options.soa.clone(),
+            ttl: options.ttl,
+            quotas: options.quotas.clone(),
+            tenants: Tenants::default(),
+            zone_tenants: HashMap::new(),
+            drain: watch::Sender::new(DrainMode::Off),
+            templates: options.templates.clone(),
+            default_ttls: HashMap::new(),
+            history: options.history.clone().map(History::new),
+            secondaries: options
+                .secondary_zones
+                .iter()
+                .map(|zone| (LowerName::new(&zone.origin), zone.clone()))
+                .collect(),
+            auto_reverse: HashSet::new(),
+            frozen: HashSet::new(),
+            cache: None,
+            forward_rules: Arc::new(ForwardRules::default()),
+            rewrite_rules: Arc::new(RewriteRules::default()),
+            query_rules: Arc::new(QueryRules::default()),
+            metrics,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            zone_events: broadcast::channel(EVENT_CAPACITY).0,
+            queries: broadcast::channel(EVENT_CAPACITY).0,
+            changes: broadcast::channel(EVENT_CAPACITY).0,
+            sequence: AtomicU64::new(0),
+            leader: None,
+            pools: Arc::new(PoolTable::default()),
+            health: Arc::new(HealthMonitor::new(options.health.clone())),
+            geo: options.geo.as_ref().map(GeoLocator::open).transpose()?.map(Arc::new),
+            geo_records: Arc::new(GeoTable::default()),
+            rollouts: Arc::new(RolloutTable::default()),
+            timed: Arc::new(TimedTable::default()),
+            denials: Arc::new(Denials::default()),
+            patterns: Arc::new(PatternTable::default()),
+            overrides: Arc::new(OverrideTable::default()),
+            aliases: Arc::new(AliasTable::default()),
+            leases: Leases::default(),
+            scheduled: Schedule::default(),
+            approvals: Approvals::default(),
+            metadata: MetadataTable::default(),
+            services: ServiceTable::default(),
+            views: Arc::new(Views::new(&options.views)?),
+            blocklist: options.blocklist.clone().map(|blocklist| Arc::new(Blocklist::new(blocklist))),
+            stats: options
+                .stats
+                .clone()
+                .map(|stats| Arc::new(QueryStats::new(stats, options.privacy.clone()))),
+        };
+        if let Some(catalog_zone) = &options.catalog_zone {
+            let zone = CatalogZone::new(catalog_zone, state.transfer.is_some()).await;
+            state.snapshots.upsert_zone(zone.origin().clone(), zone.authority()).await;
+            state.catalog_zone = Some(zone);
+        }
+        state.tsig_keys.set_configured(options.tsig_keys.clone());
+        state.tenants.set_configured(options.tenants.clone());
+        state.rewrite_rules.set_configured(options.rewrite.clone());
+        state.query_rules.set_configured(options.query_rules.clone());
+        // Forwarding comes first, for the forwarding rules of the snapshot to be added
+        state.set_forwarding(options.forward.as_ref()).await?;
+        state.load(snapshot.unwrap_or_default()).await?;
+        state.ensure_zones(&options.zones).await?;
+        for authority in state.zones.values() {
+            state.resign(authority).await?;
+            state.snapshots.refresh(authority).await;
+            state.record_journal(authority).await;
+        }
+        if let Some(zone) = &state.catalog_zone {
+            state.record_journal(zone.authority()).await;
+        }
+        // Write-through only starts once the initial state is loaded, and the changes
+        // made from now on go on from the last one journaled
+        if let Some(store) = &store {
+            if let Some(entry) = store.journaled().await?.last() {
+                state.sequence.store(entry.sequence, Ordering::Relaxed);
+            }
+        }
+        state.store = store;
+        state.persist().await?;
+        if let Some(blocklist) = &state.blocklist {
+            tokio::spawn(blocklist::run_refresh(blocklist.clone()));
+        }
+        if let Some(stats) = &state.stats {
+            tokio::spawn(stats::run_purge(stats.clone()));
+        }
+        Ok(state)
+    }
+
+    /// Loads the zones, records and runtime entries of `snapshot` into a state that has
+    /// none yet, without persisting them.
+    async fn load(&mut self, snapshot: Snapshot) -> Result<(), RdnsError> {
+        for zone in snapshot.zones {
+            if self.is_secondary(&LowerName::new(&DnsState::parse_name(&zone.origin)?)) {
+                continue;
+            }
+            let authority = self.add_zone(&zone.origin, zone.auto_reverse).await?;
+            if let Some(ttl) = zone.default_ttl {
+                self.default_ttls.insert(authority.origin().clone(), ttl);
+            }
+            if let Some(tenant) = zone.tenant {
+                self.zone_tenants.insert(authority.origin().clone(), tenant);
+            }
+            if zone.frozen {
+                self.frozen.insert(authority.origin().clone());
+            }
+            for entry in zone.records {
+                let record = DnsState::build_record(entry.name, entry.record_type, entry.value, entry.ttl)?;
+                self.metadata.set(&record, entry.metadata);
+                self.find_zone(&LowerName::new(record.name()))?.upsert(record, 0).await;
+            }
+            self.add_soa(&authority).await;
+            self.snapshots.refresh(&authority).await;
+            self.count_records(&authority).await;
+            self.record_version(&authority).await;
+        }
+        for entry in snapshot.pools {
+            let (pool, _) = DnsState::build_pool(entry)?;
+            self.pools.write().unwrap().insert(pool.key(), Arc::new(pool));
+        }
+        for entry in snapshot.geo_records {
+            let record = DnsState::build_geo_record(entry)?;
+            self.geo_records.write().unwrap().insert(record.key(), Arc::new(record));
+        }
+        for entry in snapshot.rollouts {
+            let rollout = DnsState::build_rollout(entry)?;
+            self.rollouts.write().unwrap().insert(rollout.key(), Arc::new(rollout));
+        }
+        for entry in snapshot.timed {
+            let timed = DnsState::build_timed(entry)?;
+            self.timed.write().unwrap().entry(timed.key()).or_default().push(Arc::new(timed));
+        }
+        for entry in snapshot.patterns {
+            pattern::insert(&mut self.patterns.write().unwrap(), Pattern::new(entry)?);
+        }
+        for entry in snapshot.overrides {
+            let local = DnsState::build_override(entry)?;
+            self.overrides.write().unwrap().entry(local.name()).or_default().insert(local.record_type(), Arc::new(local));
+        }
+        for entry in snapshot.aliases {
+            let alias = Alias::new(entry)?;
+            self.aliases.write().unwrap().insert(alias.key().clone(), Arc::new(alias));
+        }
+        for entry in snapshot.leases {
+            let record = DnsState::build_record(entry.name, entry.record_type, entry.value, 0)?;
+            self.leases.set(&record, Some(entry.expires_at));
+        }
+        for change in snapshot.scheduled_changes {
+            self.scheduled.insert(change);
+        }
+        for request in snapshot.approvals {
+            self.approvals.insert(request);
+        }
+        for entry in snapshot.services {
+            let entry = entry.normalize()?;
+            self.services.write().unwrap().insert(entry.key()?, entry);
+        }
+        for view in snapshot.views {
+            let Ok(overlay) = self.views.get(&view.name) else {
+                eprintln!("Dropping the records of view {}, which is no longer configured", view.name);
+                continue;
+            };
+            for record in view.records {
+                overlay.upsert(DnsState::build_record(record.name, record.record_type, record.value, record.ttl)?);
+            }
+        }
+        match &self.blocklist {
+            Some(blocklist) => {
+                for domain in snapshot.blocklist.block {
+                    blocklist.add(&domain, Action::Block)?;
+                }
+                for domain in snapshot.blocklist.allow {
+                    blocklist.add(&domain, Action::Allow)?;
+                }
+            }
+            None if !snapshot.blocklist.block.is_empty() || !snapshot.blocklist.allow.is_empty() => {
+                eprintln!("Dropping the blocklist entries, as blocking is no longer enabled");
+            }
+            None => {}
+        }
+        for entry in snapshot.tsig_keys {
+            let name = entry.name.clone();
+            let inserted = TsigKey::new(entry).map_err(RdnsError::Other).and_then(|key| self.tsig_keys.insert(key));
+            if let Err(e) = inserted {
+                eprintln!("Dropping TSIG key {}: {}", name, e);
+            }
+        }
+        for entry in snapshot.tenants {
+            let name = entry.name.clone();
+            if let Err(e) = self.tenants.insert(entry) {
+                eprintln!("Dropping tenant {}: {}", name, e);
+            }
+        }
+        for entry in snapshot.forward_rules {
+            let domain = entry.domain.clone();
+            let inserted = ForwardRule::new(entry).map_err(RdnsError::Other).and_then(|rule| self.forward_rules.insert(rule));
+            if let Err(e) = inserted {
+                eprintln!("Dropping the forwarding rule for {}: {}", domain, e);
+            }
+        }
+        for entry in snapshot.rewrite_rules {
+            let from = entry.from.clone();
+            let inserted = RewriteRule::new(entry).and_then(|rule| self.rewrite_rules.insert(rule));
+            if let Err(e) = inserted {
+                eprintln!("Dropping the rewrite rule for {}: {}", from, e);
+            }
+        }
+        for entry in snapshot.query_rules {
+            let pattern = entry.pattern.clone();
+            let inserted = QueryRule::new(entry).and_then(|rule| self.query_rules.insert(vec![rule]));
+            if let Err(e) = inserted {
+                eprintln!("Dropping the query rule for {}: {}", pattern, e);
+            }
+        }
+        for entry in snapshot.health_checks {
+            let (name, value) = (entry.name.clone(), entry.value.clone());
+            if let Err(e) = self.start_health_check(entry).await {
+                eprintln!("Dropping health check of {} {}: {}", name, value, e);
+            }
+        }
+        self.update_catalog_zone().await;
+        Ok(())
+    }
+
+    /// Helper function to parse a record or zone name, treating it as fully qualified.
+    ///
+    /// Unicode names are IDNA-encoded, so `bücher.example` is held as
+    /// `xn--bcher-kva.example`.
+    pub(crate) fn parse_name(name: &str) -> Result<Name, RdnsError> {
+        let parsed = match name.is_ascii() {
+            true => Name::from_ascii(name),
+            false => Name::from_utf8(name),
+        };
+        let mut name = parsed.map_err(|e| RdnsError::InvalidName(format!("invalid name {}: {}", name, e)))?;
+        name.set_fqdn(true);
+        Ok(name)
+    }
+
+    /// Helper function to parse a record type mnemonic, defaulting to A when empty.
+    pub(crate) fn parse_record_type(record_type: &str) -> Result<RecordType, RdnsError> {
+        if record_type.is_empty() {
+            return Ok(RecordType::A);
+        }
+        RecordType::from_str(&record_type.to_ascii_uppercase())
+            .map_err(|_| RdnsError::InvalidArgument(format!("unknown record type {}", record_type)))
+    }
+
+    /// Helper function to construct a record from input fields, parsing `value` as
+    /// master-file rdata for the record type.
+    pub(crate) fn build_record(name: String, record_type: String, value: String, ttl: u32) -> Result<Record, RdnsError> {
+        let fqdn = DnsState::parse_name(&name)?;
+        let record_type = DnsState::parse_record_type(&record_type)?;
+        DnsState::validate_wildcard(&fqdn, record_type)?;
+        let rdata = DnsState::parse_rdata(record_type, &value)?;
+        let record = Record::from_rdata(fqdn, ttl, rdata);
+        Ok(record)
+    }
+
+    /// Parses `value` as master-file rdata for `record_type`.
+    fn parse_rdata(record_type: RecordType, value: &str) -> Result<RData, RdnsError> {
+        match record_type {
+            // hickory's own SVCB parser panics on some malformed values
+            RecordType::SVCB | RecordType::HTTPS => svcb::parse(record_type, value),
+            _ => RData::try_from_str(record_type, value)
+                .map_err(|e| RdnsError::InvalidRdata(format!("invalid {} record value {}: {}", record_type, value, e))),
+        }
+    }
+
+    /// Checks that a wildcard is well placed: RFC 4592 only gives `*` its meaning as the
+    /// entire leftmost label, and zone cuts and apex records can't be synthesized.
+    ///
+    /// A wildcard such as `*.dev.example.com` answers for names directly below
+    /// `dev.example.com` that have no records of their own; explicit records take precedence.
+    fn validate_wildcard(name: &Name, record_type: RecordType) -> Result<(), RdnsError> {
+        for (i, label) in name.iter().enumerate() {
+            if label.contains(&b'*') && (i != 0 || label != b"*") {
+                return Err(RdnsError::InvalidName(format!("invalid wildcard {}: `*` must be the entire leftmost label", name)));
+            }
+        }
+        if name.is_wildcard() && matches!(record_type, RecordType::SOA | RecordType::NS | RecordType::DS) {
+            return Err(RdnsError::InvalidName(format!("{} records can't have a wildcard owner name", record_type)));
+        }
+        Ok(())
+    }
+
+    /// Builds a record added through an API like `build_record`, with its TTL defaulted
+    /// by its zone or the TTL policy and clamped by the policy. Also returns the TTL it
+    /// was clamped to, if it was.
+    ///
+    /// The name and value are validated first, failing with `InvalidName` or `InvalidRdata`,
+    /// and SPF, DKIM and DMARC records are checked once built.
+    fn build_new_record(&self, name: String, record_type: String, value: String, ttl: u32) -> Result<(Record, Option<u32>), RdnsError> {
+        validate::name(&name)?;
+        validate::value(DnsState::parse_record_type(&record_type)?, &value)?;
+        let ttl = match ttl {
+            0 => self.zone_default_ttl(&DnsState::parse_name(&name)?).unwrap_or(0),
+            ttl => ttl,
+        };
+        let (ttl, clamped) = self.ttl.apply(ttl);
+        let record = DnsState::build_record(name, record_type, value, ttl)?;
+        mailauth::check(&record)?;
+        Ok((record, clamped.then_some(ttl)))
+    }
+
+    /// Checks a record added by a dynamic update like `build_new_record` checks one added
+    /// through an API, returning it with its TTL defaulted and clamped the same way.
+    pub(crate) fn check_update_record(&self, record: &Record) -> Result<Record, RdnsError> {
+        validate::name(&record.name().to_ascii())?;
+        let value = record.data().map(ToString::to_string).unwrap_or_default();
+        validate::value(record.record_type(), &value)?;
+        DnsState::validate_wildcard(record.name(), record.record_type())?;
+        let ttl = match record.ttl() {
+            0 => self.zone_default_ttl(record.name()).unwrap_or(0),
+            ttl => ttl,
+        };
+        let mut record = record.clone();
+        record.set_ttl(self.ttl.apply(ttl).0);
+        mailauth::check(&record)?;
+        Ok(record)
+    }
+
+    /// Checks that `record` can join the records of its name: a CNAME can't share its
+    /// name with other data (RFC 1034 section 3.6.2), nor with a CNAME of another value.
+    pub(crate) fn check_cname_conflict(records: &BTreeMap<RrKey, Arc<RecordSet>>, record: &Record) -> Result<(), RdnsError> {
+        let name = LowerName::new(record.name());
+        let coexisting = |record_type: RecordType| {
+            matches!(record_type, RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3)
+        };
+        if record.record_type() == RecordType::CNAME {
+            let other = records
+                .iter()
+                .filter(|(key, _)| key.name == name && !coexisting(key.record_type))
+                .find(|(key, set)| {
+                    key.record_type != RecordType::CNAME || set.records_without_rrsigs().any(|existing| existing.data() != record.data())
+                });
+            if let Some((key, _)) = other {
+                return Err(RdnsError::Conflict(format!("{} already has {} records, which a CNAME can't join", name, key.record_type)));
+            }
+        } else if !coexisting(record.record_type()) && records.contains_key(&RrKey::new(name.clone(), RecordType::CNAME)) {
+            return Err(RdnsError::Conflict(format!("{} already has a CNAME record, which other records can't join", name)));
+        }
+        Ok(())
+    }
+
+    /// Checks that `record` is served by the zone at `origin`, whose records are `records`:
+    /// a delegation occludes the records below it, and those at it but its NS and DS
+    /// records, which are never answered (RFC 1034 section 4.2.1), apart from the A and
+    /// AAAA glue of the delegations' nameservers. Nor can an NS record delegate a name
+    /// with such records at or below it.
+    pub(crate) fn check_occlusion(records: &BTreeMap<RrKey, Arc<RecordSet>>, origin: &LowerName, record: &Record) -> Result<(), RdnsError> {
+        let name = LowerName::new(record.name());
+        let delegating = record.record_type() == RecordType::NS && name != *origin;
+        let added = delegating.then_some(record);
+        let delegations = records.iter().filter(|(key, _)| key.record_type == RecordType::NS && key.name != *origin);
+        let nameservers: HashSet<LowerName> = delegations
+            .flat_map(|(_, set)| set.records_without_rrsigs())
+            .chain(added)
+            .filter_map(|record| match record.data() {
+                Some(RData::NS(NS(target))) => Some(LowerName::new(target)),
+                _ => None,
+            })
+            .collect();
+        let occluded = |name: &LowerName, record_type: RecordType, cut: &LowerName| {
+            let glue = matches!(record_type, RecordType::A | RecordType::AAAA) && nameservers.contains(name);
+            let at_cut = matches!(record_type, RecordType::NS | RecordType::DS | RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3);
+            !glue && (name != cut || !at_cut)
+        };
+
+        // The delegation closest to the apex takes everything below it
+        let cut = (origin.num_labels() + 1..=name.num_labels())
+            .map(|labels| LowerName::new(&Name::from(&name).trim_to(labels as usize)))
+            .find(|candidate| records.contains_key(&RrKey::new(candidate.clone(), RecordType::NS)));
+        if let Some(cut) = cut.filter(|cut| occluded(&name, record.record_type(), cut)) {
+            return Err(RdnsError::Occluded(format!(
+                "{} {} record lies at or below the delegation of {}, which hides it",
+                name,
+                record.record_type(),
+                cut
+            )));
+        }
+        if delegating {
+            if let Some(key) = records.keys().find(|key| name.zone_of(&key.name) && occluded(&key.name, key.record_type, &name)) {
+                return Err(RdnsError::Occluded(format!(
+                    "delegating {} would hide the {} record of {}",
+                    name, key.record_type, key.name
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the TTL policy applied to records added from now on.
+    pub fn set_ttl_policy(&mut self, policy: TtlPolicy) {
+        self.ttl = policy;
+    }
+
+    /// Replaces the quotas records added from now on are checked against.
+    pub fn set_quotas(&mut self, quotas: Option<QuotaOptions>) {
+        self.quotas = quotas;
+    }
+
+    /// The quotas records are checked against, if any.
+    pub fn quotas(&self) -> Option<&QuotaOptions> {
+        self.quotas.as_ref()
+    }
+
+    /// Drains the instance in `mode`, or ends draining with `DrainMode::Off`.
+    pub fn set_drain_mode(&self, mode: DrainMode) {
+        self.drain.send_replace(mode);
+    }
+
+    /// Returns a receiver of the drain mode, following it as it is changed.
+    pub fn drain_mode(&self) -> watch::Receiver<DrainMode> {
+        self.drain.subscribe()
+    }
+
+    /// The record counts of the hosted zones and estimates of the memory they take up.
+    pub fn record_usage(&self) -> RecordUsage {
+        self.usage.lock().unwrap().clone()
+    }
+
+    /// Counts the records of the zone a change was made to again.
+    async fn count_records(&self, authority: &InMemoryAuthority) {
+        let records = authority.records().await;
+        let usage = Usage::of(records.values().flat_map(|set| set.records_without_rrsigs()));
+        self.usage.lock().unwrap().insert(authority.origin().clone(), usage);
+    }
+
+    /// Drops the zone at `origin`, which is no longer hosted, from the record counts.
+    fn forget_records(&self, origin: &LowerName) {
+        self.usage.lock().unwrap().remove(origin);
+    }
+
+    /// Usage of the response cache; `None` when there is no cache.
+    pub fn cache_usage(&self) -> Option<CacheUsage> {
+        self.cache.as_ref().map(|cache| cache.usage())
+    }
+
+    /// Response times and failures of the forwarding upstreams queried so far.
+    pub fn upstream_stats(&self) -> Vec<UpstreamStats> {
+        self.forward_rules.upstream_stats()
+    }
+
+    /// Fails if `added` more records in the zone at `origin` would exceed a quota,
+    /// counting them otherwise until the zone is counted again once they're added.
+    fn check_quota(&self, origin: &LowerName, added: Usage) -> Result<(), RdnsError> {
+        match self.limits_records() {
+            true => self.admit(&mut self.usage.lock().unwrap(), origin, added),
+            false => Ok(()),
+        }
+    }
+
+    /// Fails if the zone at `origin` holding `staged` instead of its current records would
+    /// exceed a quota, counting what it grows by otherwise until the zone is counted again.
+    pub(crate) fn check_staged_quota(&self, origin: &LowerName, staged: Usage) -> Result<(), RdnsError> {
+        let mut usage = self.usage.lock().unwrap();
+        let current = usage.zone(origin);
+        if !self.limits_records() || !staged.exceeds(current) {
+            return Ok(());
+        }
+        let added = Usage {
+            records: staged.records.saturating_sub(current.records),
+            bytes: staged.bytes.saturating_sub(current.bytes),
+        };
+        self.admit(&mut usage, origin, added)
+    }
+
+    /// Whether the records added through the APIs are checked against any quota.
+    fn limits_records(&self) -> bool {
+        self.quotas.is_some() || self.tenants.limit_records()
+    }
+
+    /// Counts `added` more records in the zone at `origin` into `usage`, failing if that
+    /// would exceed the quotas of `[dns.quotas]` or of the tenant owning the zone.
+    fn admit(&self, usage: &mut RecordUsage, origin: &LowerName, added: Usage) -> Result<(), RdnsError> {
+        if let Some(tenant) = self.zone_tenant(origin) {
+            if let Some(limit) = self.tenants.get(&tenant).and_then(|entry| entry.max_records) {
+                let records: usize = self.tenant_zones(&tenant).iter().map(|zone| usage.zone(zone).records).sum();
+                if records + added.records > limit {
+                    return Err(RdnsError::QuotaExceeded(format!(
+                        "tenant {} holds {} of the {} records it may hold",
+                        tenant, records, limit
+                    )));
+                }
+            }
+        }
+        match &self.quotas {
+            Some(quotas) => quotas.admit(usage, origin, added),
+            None => {
+                usage.insert(origin.clone(), usage.zone(origin) + added);
+                Ok(())
+            }
+        }
+    }
+
+    /// The tenant owning the zone at `origin`, if any: the one it was created by or
+    /// assigned to at runtime, or else the one the config gives it to.
+    pub fn zone_tenant(&self, origin: &LowerName) -> Option<String> {
+        self.zone_tenants.get(origin).cloned().or_else(|| self.tenants.configured_owner(origin))
+    }
+
+    /// The hosted zones `tenant` owns.
+    fn tenant_zones(&self, tenant: &str) -> Vec<LowerName> {
+        self.zones
+            .keys()
+            .filter(|origin| self.zone_tenant(origin).as_deref() == Some(tenant))
+            .cloned()
+            .collect()
+    }
+
+    /// Fails with `PermissionDenied` unless `name` is in a zone owned by `tenant`; any
+    /// name is allowed without a tenant.
+    pub fn check_tenant(&self, tenant: Option<&str>, name: &str) -> Result<(), RdnsError> {
+        let Some(tenant) = tenant else {
+            return Ok(());
+        };
+        let name = LowerName::new(&DnsState::parse_name(name)?);
+        let owned = self.find_zone(&name).is_ok_and(|authority| self.zone_tenant(authority.origin()).as_deref() == Some(tenant));
+        if !owned {
+            return Err(RdnsError::PermissionDenied(format!("{} is not in a zone of tenant {}", name, tenant)));
+        }
+        Ok(())
+    }
+
+    /// Fails with `PermissionDenied` unless the zone at `origin` is owned by `tenant`; any
+    /// zone is allowed without a tenant.
+    pub fn check_tenant_zone(&self, tenant: Option<&str>, origin: &str) -> Result<(), RdnsError> {
+        let Some(tenant) = tenant else {
+            return Ok(());
+        };
+        let origin = LowerName::new(&DnsState::parse_name(origin)?);
+        if self.zone_tenant(&origin).as_deref() != Some(tenant) {
+            return Err(RdnsError::PermissionDenied(format!("zone {} is not owned by tenant {}", origin, tenant)));
+        }
+        Ok(())
+    }
+
+    /// Creates a zone for `tenant` like `create_zone_from_template`, which the tenant then
+    /// owns. A zone under a hosted zone may only be created under a zone of the tenant,
+    /// and no more zones than the tenant's `max_zones`.
+    pub async fn create_tenant_zone(&mut self, tenant: &str, origin: &str, template: Option<&str>, auto_reverse: bool) -> Result<usize, RdnsError> {
+        self.check_leader()?;
+        let Some(entry) = self.tenants.get(tenant) else {
+            return Err(RdnsError::PermissionDenied(format!("tenant {} does not exist", tenant)));
+        };
+        let origin = LowerName::new(&DnsState::parse_name(origin)?);
+        if self.zones.contains_key(&origin) {
+            return Err(RdnsError::ZoneExists(format!("zone {} already exists", origin)));
+        }
+        if let Ok(parent) = self.find_zone(&origin) {
+            if self.zone_tenant(parent.origin()).as_deref() != Some(tenant) {
+                return Err(RdnsError::PermissionDenied(format!(
+                    "zone {} is under zone {}, which is not owned by tenant {}",
+                    origin,
+                    parent.origin(),
+                    tenant
+                )));
+            }
+        }
+        if let Some(limit) = entry.max_zones {
+            let zones = self.tenant_zones(tenant).len();
+            if zones >= limit {
+                return Err(RdnsError::QuotaExceeded(format!("tenant {} owns {} of the {} zones it may own", tenant, zones, limit)));
+            }
+        }
+
+        // Owned before it is created, so that it is persisted with its owner
+        self.zone_tenants.insert(origin.clone(), tenant.to_string());
+        let result = self.create_zone_from_template(&origin.to_string(), template, auto_reverse).await;
+        if !self.zones.contains_key(&origin) {
+            self.zone_tenants.remove(&origin);
+        }
+        result
+    }
+
+    /// Creates a tenant owning the hosted zones at `zones`, which mustn't be owned by
+    /// another tenant.
+    pub async fn create_tenant(&mut self, entry: TenantEntry, zones: &[String]) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let origins = zones
+            .iter()
+            .map(|zone| DnsState::parse_name(zone).map(|name| LowerName::new(&name)))
+            .collect::<Result<Vec<_>, _>>()?;
+        for origin in &origins {
+            if !self.zones.contains_key(origin) || self.is_secondary(origin) {
+                return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+            }
+            if let Some(owner) = self.zone_tenant(origin) {
+                return Err(RdnsError::Conflict(format!("zone {} is owned by tenant {}", origin, owner)));
+            }
+        }
+        let name = entry.name.clone();
+        self.tenants.insert(entry)?;
+        for origin in origins {
+            self.zone_tenants.insert(origin, name.clone());
+        }
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Lists the tenants along with the zones each owns and the records they hold.
+    pub async fn list_tenants(&self) -> Vec<(TenantInfo, Vec<String>, usize)> {
+        let usage = self.record_usage();
+        self.tenants
+            .list()
+            .into_iter()
+            .map(|info| {
+                let origins = self.tenant_zones(&info.entry.name);
+                let records = origins.iter().map(|origin| usage.zone(origin).records).sum();
+                let mut zones: Vec<String> = origins.into_iter().map(|origin| Name::from(origin).to_ascii()).collect();
+                zones.sort();
+                (info, zones, records)
+            })
+            .collect()
+    }
+
+    /// Replaces the tenants of the config.
+    pub fn set_tenants(&self, tenants: Vec<Tenant>) {
+        self.tenants.set_configured(tenants);
+    }
+
+    /// Replaces the templates zones created from now on are populated with.
+    pub fn set_zone_templates(&mut self, templates: Vec<ZoneTemplate>) {
+        self.templates = templates;
+    }
+
+    /// The default TTL of the zone holding `name`, if its template gave it one.
+    fn zone_default_ttl(&self, name: &Name) -> Option<u32> {
+        let authority = self.find_zone(&LowerName::new(name)).ok()?;
+        self.default_ttls.get(authority.origin()).copied()
+    }
+
+    /// Helper function to construct an RrKey for name record mutation
+    fn build_record_key(name: String, record_type: String) -> Result<RrKey, RdnsError> {
+        let name = LowerName::new(&DnsState::parse_name(&name)?);
+        let rr_key = RrKey::new(name, DnsState::parse_record_type(&record_type)?);
+        Ok(rr_key)
+    }
+
+    /// Finds the authority of the most specific hosted zone containing `name`.
+    fn find_zone(&self, name: &LowerName) -> Result<&Arc<InMemoryAuthority>, RdnsError> {
+        let mut candidate = name.clone();
+        loop {
+            if let Some(authority) = self.zones.get(&candidate) {
+                return Ok(authority);
+            }
+            if candidate.is_root() {
+                return Err(RdnsError::ZoneNotFound(format!("no zone hosted for {}", name)));
+            }
+            candidate = candidate.base_name();
+        }
+    }
+
+    /// Finds the zone containing `name` like `find_zone`, failing if it is a read-only
+    /// secondary zone or a frozen one.
+    fn find_writable_zone(&self, name: &LowerName) -> Result<&Arc<InMemoryAuthority>, RdnsError> {
+        let authority = self.find_zone(name)?;
+        self.check_writable(authority.origin())?;
+        Ok(authority)
+    }
+
+    /// Fails if the zone at `origin` is a secondary, which only zone transfers modify, or
+    /// is frozen.
+    fn check_writable(&self, origin: &LowerName) -> Result<(), RdnsError> {
+        if self.is_secondary(origin) {
+            return Err(RdnsError::ReadOnlyZone(format!("zone {} is a secondary and read-only", origin)));
+        }
+        if self.is_frozen(origin) {
+            return Err(RdnsError::ZoneFrozen(format!("zone {} is frozen for maintenance", origin)));
+        }
+        Ok(())
+    }
+
+    /// Whether the zone at `origin` is frozen, see `set_zone_frozen`.
+    pub fn is_frozen(&self, origin: &LowerName) -> bool {
+        self.frozen.contains(origin)
+    }
+
+    /// Freezes the primary zone at `origin` for maintenance, or thaws it. A frozen zone is
+    /// still answered, but the record, pool, alias and zone changes of the APIs and of
+    /// dynamic updates are rejected until it is thawed, as are the lapsed leases of its
+    /// records, which are reaped once it is. Returns whether the zone was frozen before.
+    pub async fn set_zone_frozen(&mut self, origin: &str, frozen: bool) -> Result<bool, RdnsError> {
+        self.check_leader()?;
+        let origin = LowerName::new(&DnsState::parse_name(origin)?);
+        if !self.zones.contains_key(&origin) {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+        }
+        if self.is_secondary(&origin) {
+            return Err(RdnsError::ReadOnlyZone(format!("zone {} is a secondary and read-only", origin)));
+        }
+        let was_frozen = match frozen {
+            true => !self.frozen.insert(origin.clone()),
+            false => self.frozen.remove(&origin),
+        };
+        if was_frozen != frozen {
+            self.publish_change(ChangeScope::Zone(origin));
+            self.persist().await?;
+        }
+        Ok(was_frozen)
+    }
+
+    /// Whether the zone at `origin` is a secondary, which is only modified by zone transfers.
+    pub fn is_secondary(&self, origin: &LowerName) -> bool {
+        self.secondaries.contains_key(origin)
+    }
+
+    /// The secondary zone at `origin`, if it is one.
+    pub fn secondary_zone(&self, origin: &LowerName) -> Option<&SecondaryZone> {
+        self.secondaries.get(origin)
+    }
+
+    /// Creates a new, empty authoritative zone and registers it with the catalog.
+    ///
+    /// With `auto_reverse`, adding an A or AAAA record to the zone adds the matching PTR
+    /// record to the hosted reverse zone holding it, which is created as a /24 (IPv4)
+    /// or /64 (IPv6) zone when there is none, and deleting the record deletes the PTR.
+    pub async fn create_zone(&mut self, origin: &str, auto_reverse: bool) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let authority = self.add_zone(origin, auto_reverse).await?;
+        if self.add_soa(&authority).await {
+            self.resign(&authority).await?;
+        }
+        self.zone_changed(&authority).await;
+        self.publish_zone(ZoneChange::Created, authority.origin());
+        self.update_catalog_zone().await;
+        self.persist().await
+    }
+
+    /// Creates a zone like `create_zone`, populated with the records of the template
+    /// named `template`, or of the default template when none is named; without a
+    /// default template the zone is left empty. Returns the number of records added.
+    pub async fn create_zone_from_template(&mut self, origin: &str, template: Option<&str>, auto_reverse: bool) -> Result<usize, RdnsError> {
+        self.check_leader()?;
+        let template = match template {
+            Some(name) => Some(
+                self.templates
+                    .iter()
+                    .find(|template| template.name == name)
+                    .ok_or_else(|| RdnsError::InvalidArgument(format!("no zone template named {}", name)))?,
+            ),
+            None => self.templates.iter().find(|template| template.default),
+        };
+        let Some(template) = template.cloned() else {
+            return self.create_zone(origin, auto_reverse).await.map(|_| 0);
+        };
+        let records = template.records(&DnsState::parse_name(origin)?)?;
+
+        let authority = self.add_zone(origin, auto_reverse).await?;
+        if let Some(ttl) = template.default_ttl {
+            self.default_ttls.insert(authority.origin().clone(), ttl);
+        }
+        for record in &records {
+            authority.upsert(record.clone(), 0).await;
+            self.publish(RecordChange::Added, record);
+        }
+        if self.add_soa(&authority).await {
+            self.resign(&authority).await?;
+        }
+        self.zone_changed(&authority).await;
+        self.publish_zone(ZoneChange::Created, authority.origin());
+        self.update_catalog_zone().await;
+        self.persist().await?;
+        for record in &records {
+            self.add_reverse(record).await;
+        }
+        Ok(records.len())
+    }
+
+    /// Registers a new, empty zone without generating its SOA or persisting it.
+    async fn add_zone(&mut self, origin: &str, auto_reverse: bool) -> Result<Arc<InMemoryAuthority>, RdnsError> {
+        let origin = LowerName::new(&DnsState::parse_name(origin)?);
+        if self.zones.contains_key(&origin) || self.is_secondary(&origin) {
+            return Err(RdnsError::ZoneExists(format!("zone {} already exists", origin)));
+        }
+        if self.catalog_zone.as_ref().is_some_and(|zone| *zone.origin() == origin) {
+            return Err(RdnsError::ZoneExists(format!("zone {} is the catalog zone", origin)));
+        }
+        let authority = Arc::new(InMemoryAuthority::empty(origin.clone().into(), ZoneType::Primary, self.transfer.is_some()));
+        if self.is_signed(&origin) {
+            self.add_signing_keys(&authority).await?;
+        }
+
+        self.snapshots.upsert_zone(origin.clone(), &authority).await;
+        if auto_reverse {
+            self.auto_reverse.insert(origin.clone());
+        }
+        self.zones.insert(origin, authority.clone());
+        Ok(authority)
+    }
+
+    /// Generates the SOA and apex NS records of a zone without an SOA, if automatic
+    /// records are configured. Returns whether any were added.
+    async fn add_soa(&self, authority: &InMemoryAuthority) -> bool {
+        let Some(options) = &self.soa else {
+            return false;
+        };
+        let origin = authority.origin().clone();
+        let mut records = authority.records_mut().await;
+        if records.contains_key(&RrKey::new(origin.clone(), RecordType::SOA)) {
+            return false;
+        }
+        let has_ns = records.contains_key(&RrKey::new(origin.clone(), RecordType::NS));
+        let generated: Vec<Record> = options
+            .template(&origin)
+            .records(&Name::from(&origin), soa::initial_serial())
+            .into_iter()
+            .filter(|record| record.record_type() == RecordType::SOA || !has_ns)
+            .collect();
+        for record in &generated {
+            let set = records
+                .entry(RrKey::new(origin.clone(), record.record_type()))
+                .or_insert_with(|| Arc::new(RecordSet::new(record.name(), record.record_type(), 0)));
+            Arc::make_mut(set).insert(record.clone(), 0);
+        }
+        drop(records);
+        for record in &generated {
+            self.publish(RecordChange::Added, record);
+        }
+        true
+    }
+
+    /// Increments the serial of a primary zone's SOA after a change to the zone, or
+    /// generates the SOA if it has none and automatic records are configured.
+    async fn bump_serial(&self, authority: &InMemoryAuthority) {
+        if self.is_secondary(authority.origin()) || self.add_soa(authority).await {
+            return;
+        }
+        let key = RrKey::new(authority.origin().clone(), RecordType::SOA);
+        let mut records = authority.records_mut().await;
+        let Some(bumped) = records
+            .get(&key)
+            .and_then(|set| set.records_without_rrsigs().next())
+            .and_then(soa::bump_serial)
+        else {
+            return;
+        };
+        // A RecordSet only takes an SOA with a greater serial, which a wrapped one isn't
+        let mut set = RecordSet::new(bumped.name(), RecordType::SOA, 0);
+        set.insert(bumped, 0);
+        records.insert(key, Arc::new(set));
+    }
+
+    /// Creates an empty zone for each of `origins` not already hosted, returning how many
+    /// were created.
+    ///
+    /// A follower takes its zones from the leader, so creates none.
+    pub async fn ensure_zones(&mut self, origins: &[String]) -> Result<usize, RdnsError> {
+        if self.is_follower() {
+            return Ok(0);
+        }
+        let mut created = 0;
+        for origin in origins {
+            let name = LowerName::new(&DnsState::parse_name(origin)?);
+            if !self.zones.contains_key(&name) && !self.is_secondary(&name) {
+                self.create_zone(origin, false).await?;
+                created += 1;
+            }
+        }
+        Ok(created)
+    }
+
+    /// Whether the zone at `origin` is configured for DNSSEC signing.
+    fn is_signed(&self, origin: &LowerName) -> bool {
+        self.dnssec.as_ref().is_some_and(|dnssec| dnssec.zones.contains(origin))
+    }
+
+    /// Registers the zone's KSK and ZSK with its authority, loading or generating them,
+    /// along with the keys its rollovers sign with or publish.
+    async fn add_signing_keys(&self, authority: &InMemoryAuthority) -> anyhow::Result<()> {
+        let Some(options) = &self.dnssec else {
+            return Ok(());
+        };
+        let origin = Name::from(authority.origin());
+        let mut rollovers = dnssec::load_rollovers(&options.key_dir, &origin).await?;
+        if rollovers.zsk_signing_since.is_none() {
+            rollovers.zsk_signing_since = Some(SystemTime::now());
+            dnssec::save_rollovers(&options.key_dir, &origin, &rollovers).await?;
+        }
+        let keys = dnssec::zone_keys(&options.key_dir, &origin, &rollovers).await?;
+        for key in keys.signing {
+            let signer = dnssec::signer(&origin, key, options.signature_validity)?;
+            authority.add_zone_signing_key(signer).await?;
+        }
+        if *options.denial_of(authority.origin()) == Denial::WhiteLies {
+            // White lies are signed as they are answered, with signers of their own
+            let mut signers = Vec::new();
+            for key in dnssec::zone_keys(&options.key_dir, &origin, &rollovers).await?.signing {
+                signers.push(dnssec::signer(&origin, key, options.signature_validity)?);
+            }
+            self.denials.set_signers(authority.origin(), signers);
+        }
+        if !keys.published.is_empty() {
+            let dnskeys = RrKey::new(authority.origin().clone(), RecordType::DNSKEY);
+            let ttl = authority.records().await.get(&dnskeys).map_or(0, |rrset| rrset.ttl());
+            let serial = authority.serial().await;
+            for key in keys.published {
+                let dnskey = key.to_dnskey(dnssec::ALGORITHM)?;
+                let rdata = RData::DNSSEC(DNSSECRData::DNSKEY(dnskey));
+                authority.upsert(Record::from_rdata(origin.clone(), ttl, rdata), serial).await;
+            }
+        }
+        self.denials.secure(authority, options.denial_of(authority.origin())).await?;
+        rollovers.signed_at = Some(SystemTime::now());
+        dnssec::save_rollovers(&options.key_dir, &origin, &rollovers).await
+    }
+
+    /// Rebuilds the signed zone at `origin` with the keys where its rollovers stand, as
+    /// a key can't be taken from an authority once it signs.
+    async fn rekey_zone(&mut self, origin: &LowerName) -> Result<(), RdnsError> {
+        let Some(current) = self.zones.get(origin).cloned() else {
+            return Ok(());
+        };
+        let authority = Arc::new(InMemoryAuthority::empty(origin.clone().into(), ZoneType::Primary, self.transfer.is_some()));
+        {
+            let mut records = authority.records_mut().await;
+            for (key, rrset) in current.records().await {
+                if !dnssec::is_generated(key.record_type) {
+                    records.insert(key, rrset);
+                }
+            }
+        }
+        self.add_signing_keys(&authority).await?;
+
+        self.snapshots.upsert_zone(origin.clone(), &authority).await;
+        self.zones.insert(origin.clone(), authority.clone());
+        self.zone_changed(&authority).await;
+        self.persist().await?;
+        self.notify(&authority);
+        Ok(())
+    }
+
+    /// The name and DNSSEC options of the signed zone at `origin`.
+    fn signed_zone(&self, origin: &str) -> Result<(Name, &DnssecOptions), RdnsError> {
+        let origin = DnsState::parse_name(origin)?;
+        if !self.zones.contains_key(&LowerName::new(&origin)) {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+        }
+        match self.dnssec.as_ref().filter(|_| self.is_signed(&LowerName::new(&origin))) {
+            Some(options) => Ok((origin, options)),
+            None => Err(RdnsError::NotEnabled(format!("zone {} is not signed", origin))),
+        }
+    }
+
+    /// Where the rollovers of the keys of the signed zone at `origin` stand, with the
+    /// step they take next and when, and the DS of the KSK being rolled in, if any.
+    pub async fn key_rollovers(&self, origin: &str) -> Result<KeyRollovers, RdnsError> {
+        let (origin, options) = self.signed_zone(origin)?;
+        let rollovers = dnssec::load_rollovers(&options.key_dir, &origin).await?;
+        let next_ds = match rollovers.ksk {
+            Some(_) => {
+                let ksk = dnssec::load_or_generate_next_key(&options.key_dir, &origin, KeyRole::KeySigning).await?;
+                Some(format!("{} IN DS {}", origin, dnssec::ds(&origin, &ksk)?))
+            }
+            None => None,
+        };
+        Ok(KeyRollovers {
+            next_step: dnssec::next_step(&rollovers, options),
+            zsk: rollovers.zsk,
+            ksk: rollovers.ksk,
+            zsk_signing_since: rollovers.zsk_signing_since,
+            next_ds,
+        })
+    }
+
+    /// Starts rolling over the key for `role` of the signed zone at `origin`: a new ZSK
+    /// is published ahead of signing, a new KSK signs along with the old one until its
+    /// DS is confirmed. Returns a description, with the DS to publish for a KSK.
+    pub async fn start_key_rollover(&mut self, origin: &str, role: KeyRole) -> Result<String, RdnsError> {
+        self.check_leader()?;
+        let (origin, options) = self.signed_zone(origin)?;
+        let mut rollovers = dnssec::load_rollovers(&options.key_dir, &origin).await?;
+        if let Some(rollover) = rollovers.of(role) {
+            return Err(RdnsError::Conflict(format!(
+                "a {} rollover of {} is already under way, {}",
+                role, origin, rollover.phase
+            )));
+        }
+        let message = self.begin_rollover(&origin, role, &mut rollovers).await?;
+        Ok(message)
+    }
+
+    /// Generates the new key for `role` and starts its rollover in `rollovers`.
+    async fn begin_rollover(&mut self, origin: &Name, role: KeyRole, rollovers: &mut Rollovers) -> Result<String, RdnsError> {
+        let Some(options) = self.dnssec.clone() else {
+            return Ok(String::new());
+        };
+        dnssec::discard_next_key(&options.key_dir, origin, role).await?;
+        let key = dnssec::load_or_generate_next_key(&options.key_dir, origin, role).await?;
+        let phase = match role {
+            KeyRole::KeySigning => RolloverPhase::AwaitingDs,
+            KeyRole::ZoneSigning => RolloverPhase::Prepublished,
+        };
+        rollovers.set(role, Some(phase), SystemTime::now());
+        dnssec::save_rollovers(&options.key_dir, origin, rollovers).await?;
+        self.rekey_zone(&LowerName::new(origin)).await?;
+        Ok(match role {
+            KeyRole::KeySigning => format!(
+                "New KSK of {} signs along with the old one; publish its DS and confirm it within {}d: {} IN DS {}",
+                origin,
+                options.ksk_confirm_timeout.as_secs() / (24 * 60 * 60),
+                origin,
+                dnssec::ds(origin, &key)?
+            ),
+            KeyRole::ZoneSigning => format!(
+                "New ZSK of {} published; it signs in {}h",
+                origin,
+                options.zsk_prepublish.as_secs() / (60 * 60)
+            ),
+        })
+    }
+
+    /// Retires the old KSK of the signed zone at `origin` once the DS of the new one is
+    /// published by the parent zone.
+    pub async fn confirm_ksk_rollover(&mut self, origin: &str) -> Result<String, RdnsError> {
+        self.check_leader()?;
+        let (origin, options) = self.signed_zone(origin)?;
+        let key_dir = options.key_dir.clone();
+        let mut rollovers = dnssec::load_rollovers(&key_dir, &origin).await?;
+        if rollovers.ksk.is_none() {
+            return Err(RdnsError::RecordNotFound(format!("no KSK rollover of {} awaits its DS", origin)));
+        }
+        dnssec::promote_next_key(&key_dir, &origin, KeyRole::KeySigning).await?;
+        rollovers.set(KeyRole::KeySigning, None, SystemTime::now());
+        dnssec::save_rollovers(&key_dir, &origin, &rollovers).await?;
+        self.rekey_zone(&LowerName::new(&origin)).await?;
+        Ok(format!("Old KSK of {} retired", origin))
+    }
+
+    /// Rolls back the rollover of the key for `role` of the signed zone at `origin`,
+    /// dropping the new key.
+    pub async fn cancel_key_rollover(&mut self, origin: &str, role: KeyRole) -> Result<String, RdnsError> {
+        self.check_leader()?;
+        let (origin, _) = self.signed_zone(origin)?;
+        self.roll_back(&origin, role).await?;
+        Ok(format!("{} rollover of {} rolled back", role, origin))
+    }
+
+    /// Drops the new key for `role` and ends its rollover.
+    async fn roll_back(&mut self, origin: &Name, role: KeyRole) -> Result<(), RdnsError> {
+        let Some(options) = &self.dnssec else {
+            return Ok(());
+        };
+        let key_dir = options.key_dir.clone();
+        let mut rollovers = dnssec::load_rollovers(&key_dir, origin).await?;
+        if rollovers.of(role).is_none() {
+            return Err(RdnsError::RecordNotFound(format!("no {} rollover of {} is under way", role, origin)));
+        }
+        dnssec::discard_next_key(&key_dir, origin, role).await?;
+        rollovers.set(role, None, SystemTime::now());
+        dnssec::save_rollovers(&key_dir, origin, &rollovers).await?;
+        self.rekey_zone(&LowerName::new(origin)).await
+    }
+
+    /// Whether a rollover step of a signed zone is due at `now`.
+    pub async fn has_due_rollovers(&self, now: SystemTime) -> bool {
+        let Some(options) = &self.dnssec else {
+            return false;
+        };
+        for origin in options.zones.iter().filter(|origin| self.zones.contains_key(*origin)) {
+            if let Ok(rollovers) = dnssec::load_rollovers(&options.key_dir, &Name::from(origin)).await {
+                if dnssec::next_step(&rollovers, options).is_some_and(|(_, at)| at <= now) {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Takes the rollover steps of the signed zones that are due at `now`.
+    pub async fn advance_rollovers(&mut self, now: SystemTime) {
+        let Some(options) = self.dnssec.clone() else {
+            return;
+        };
+        let origins: Vec<LowerName> = options.zones.iter().filter(|origin| self.zones.contains_key(*origin)).cloned().collect();
+        for origin in origins {
+            let name = Name::from(&origin);
+            let result = match dnssec::load_rollovers(&options.key_dir, &name).await {
+                Ok(rollovers) => match dnssec::next_step(&rollovers, &options) {
+                    Some((step, at)) if at <= now => self.take_rollover_step(&name, step, rollovers).await.map(|()| Some(step)),
+                    _ => Ok(None),
+                },
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                Ok(Some(RolloverStep::RollBackKsk)) => eprintln!(
+                    "The DS of the new KSK of {} wasn't confirmed within {}d, the KSK rollover was rolled back",
+                    origin,
+                    options.ksk_confirm_timeout.as_secs() / (24 * 60 * 60)
+                ),
+                Ok(Some(step)) => println!("Took rollover step {} of {}", step, origin),
+                Ok(None) => {}
+                Err(e) => eprintln!("Failed to roll over the keys of {}: {}", origin, e),
+            }
+        }
+    }
+
+    /// Whether a signed zone was last signed half its signature validity or longer before
+    /// `now`, so that its signatures are due to be refreshed.
+    pub async fn has_due_signatures(&self, now: SystemTime) -> bool {
+        !self.signatures_due(now).await.is_empty()
+    }
+
+    /// The signed zones whose signatures are due to be refreshed at `now`.
+    async fn signatures_due(&self, now: SystemTime) -> Vec<LowerName> {
+        let Some(options) = &self.dnssec else {
+            return Vec::new();
+        };
+        let mut due = Vec::new();
+        for origin in options.zones.iter().filter(|origin| self.zones.contains_key(*origin)) {
+            if let Ok(rollovers) = dnssec::load_rollovers(&options.key_dir, &Name::from(origin)).await {
+                if rollovers.signed_at.is_none_or(|at| at + options.signature_validity / 2 <= now) {
+                    due.push(origin.clone());
+                }
+            }
+        }
+        due
+    }
+
+    /// Re-signs the signed zones whose signatures are due to be refreshed at `now`, bumping
+    /// their serials so that secondaries transfer the new signatures.
+    pub async fn refresh_signatures(&mut self, now: SystemTime) {
+        let Some(options) = self.dnssec.clone() else {
+            return;
+        };
+        for origin in self.signatures_due(now).await {
+            let Some(authority) = self.zones.get(&origin).cloned() else {
+                continue;
+            };
+            let name = Name::from(&origin);
+            let result = async {
+                self.zone_updated(&authority).await?;
+                let mut rollovers = dnssec::load_rollovers(&options.key_dir, &name).await?;
+                rollovers.signed_at = Some(now);
+                dnssec::save_rollovers(&options.key_dir, &name, &rollovers).await
+            };
+            match result.await {
+                Ok(()) => println!("Refreshed the signatures of {}", origin),
+                Err(e) => eprintln!("Failed to refresh the signatures of {}: {}", origin, e),
+            }
+        }
+    }
+
+    async fn take_rollover_step(&mut self, origin: &Name, step: RolloverStep, mut rollovers: Rollovers) -> Result<(), RdnsError> {
+        let Some(options) = self.dnssec.clone() else {
+            return Ok(());
+        };
+        let now = SystemTime::now();
+        match step {
+            RolloverStep::StartZsk => {
+                self.begin_rollover(origin, KeyRole::ZoneSigning, &mut rollovers).await?;
+                return Ok(());
+            }
+            RolloverStep::RollBackKsk => return self.roll_back(origin, KeyRole::KeySigning).await,
+            RolloverStep::ActivateZsk => rollovers.set(KeyRole::ZoneSigning, Some(RolloverPhase::Retiring), now),
+            RolloverStep::RetireZsk => {
+                dnssec::promote_next_key(&options.key_dir, origin, KeyRole::ZoneSigning).await?;
+                rollovers.zsk_signing_since = rollovers.zsk.map(|rollover| rollover.since);
+                rollovers.set(KeyRole::ZoneSigning, None, now);
+            }
+        }
+        dnssec::save_rollovers(&options.key_dir, origin, &rollovers).await?;
+        self.rekey_zone(&LowerName::new(origin)).await
+    }
+
+    /// Regenerates NSEC or NSEC3 records and signatures after a mutation of a signed zone.
+    async fn resign(&self, authority: &InMemoryAuthority) -> anyhow::Result<()> {
+        if let Some(options) = self.dnssec.as_ref().filter(|_| self.is_signed(authority.origin())) {
+            self.denials.secure(authority, options.denial_of(authority.origin())).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the authority of the hosted zone at `origin`, if any.
+    pub fn zone(&self, origin: &LowerName) -> Option<&Arc<InMemoryAuthority>> {
+        self.zones.get(origin)
+    }
+
+    /// Bumps the serial of, re-signs and persists a zone after its records were modified
+    /// in place.
+    pub async fn zone_updated(&self, authority: &InMemoryAuthority) -> Result<(), RdnsError> {
+        self.bump_serial(authority).await;
+        self.resign(authority).await?;
+        self.zone_changed(authority).await;
+        self.persist().await?;
+        self.notify(authority);
+        Ok(())
+    }
+
+    /// Brings the members of the catalog zone, if one is served, in line with the primary
+    /// zones, notifying the secondaries when they changed.
+    async fn update_catalog_zone(&mut self) {
+        let members = self.zones.keys().filter(|origin| !self.is_secondary(origin)).cloned().collect();
+        let Some(zone) = &mut self.catalog_zone else {
+            return;
+        };
+        if !zone.set_members(members).await {
+            return;
+        }
+        let authority = zone.authority().clone();
+        self.snapshots.refresh(&authority).await;
+        self.record_journal(&authority).await;
+        self.notify(&authority);
+    }
+
+    /// Sends NOTIFY messages for a changed zone to the configured secondaries.
+    fn notify(&self, authority: &InMemoryAuthority) {
+        if let Some(transfer) = &self.transfer {
+            transfer.notify(&authority.origin().into());
+        }
+    }
+
+
+    /// Returns the SOA serial of the hosted zone at `origin`, or `None` if it is not loaded.
+    pub async fn zone_serial(&self, origin: &LowerName) -> Option<u32> {
+        Some(self.zones.get(origin)?.serial().await)
+    }
+
+    /// Replaces the contents of a secondary zone with records transferred from its
+    /// primary, (re)registering the zone with the catalog.
+    pub async fn load_secondary_zone(&mut self, origin: &Name, records: Vec<Record>) -> Result<(), RdnsError> {
+        let key = LowerName::new(origin);
+        if !self.is_secondary(&key) {
+            return Err(RdnsError::Other(anyhow::anyhow!("zone {} is not a secondary", origin)));
+        }
+        let authority = Arc::new(InMemoryAuthority::empty(origin.clone(), ZoneType::Secondary, self.transfer.is_some()));
+        for record in records {
+            authority.upsert(record, 0).await;
+        }
+
+        self.snapshots.upsert_zone(key.clone(), &authority).await;
+        self.zones.insert(key, authority.clone());
+        self.count_records(&authority).await;
+        self.record_journal(&authority).await;
+        self.notify(&authority);
+        Ok(())
+    }
+
+    /// Stops serving an expired secondary zone until it is transferred again.
+    pub async fn expire_zone(&mut self, origin: &Name) {
+        let key = LowerName::new(origin);
+        if self.zones.remove(&key).is_some() {
+            self.snapshots.remove([&key]).await;
+            self.forget_records(&key);
+            if let Some(journal) = &self.journal {
+                journal.remove(&key);
+            }
+            eprintln!("Secondary zone {} expired", origin);
+            self.publish_zone(ZoneChange::Expired, &key);
+        }
+    }
+
+    /// Reports a change in how the sync of the secondary zone `origin` fares, other than
+    /// its expiry.
+    pub fn report_secondary(&self, change: ZoneChange, origin: &Name) {
+        self.publish_zone(change, &LowerName::new(origin));
+    }
+
+    /// Returns the DS records to publish in the parent zone for a signed zone.
+    pub async fn get_zone_ds(&self, origin: &str) -> Result<Vec<String>, RdnsError> {
+        let (origin, options) = self.signed_zone(origin)?;
+        let ksk = dnssec::load_or_generate_key(&options.key_dir, &origin, KeyRole::KeySigning).await?;
+        let ds = dnssec::ds(&origin, &ksk)?;
+        Ok(vec![format!("{} IN DS {}", origin, ds)])
+    }
+
+    /// Removes a zone, and all of its records, from the catalog.
+    pub async fn delete_zone(&mut self, origin: &str) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let origin = LowerName::new(&DnsState::parse_name(origin)?);
+        self.check_writable(&origin)?;
+        let Some(authority) = self.zones.get(&origin) else {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+        };
+        if self.auto_reverse.contains(&origin) {
+            for record in DnsState::zone_records(authority).await {
+                self.delete_reverse(&record).await;
+            }
+            self.auto_reverse.remove(&origin);
+        }
+        self.default_ttls.remove(&origin);
+        self.zone_tenants.remove(&origin);
+        self.frozen.remove(&origin);
+        self.denials.remove(&origin);
+        self.zones.remove(&origin);
+        self.snapshots.remove([&origin]).await;
+        self.forget_records(&origin);
+        self.pools.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+        self.health.remove_zone(&origin);
+        self.leases.remove_zone(&origin);
+        self.scheduled.remove_zone(&origin);
+        self.metadata.remove_zone(&origin);
+        if let Some(history) = &self.history {
+            history.remove_zone(&origin);
+        }
+        if let Some(journal) = &self.journal {
+            journal.remove(&origin);
+        }
+        self.geo_records.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+        self.rollouts.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+        self.timed.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+        self.patterns.write().unwrap().retain(|pattern| !origin.zone_of(pattern.suffix()));
+        self.aliases.write().unwrap().retain(|name, _| !origin.zone_of(name));
+        self.services.write().unwrap().retain(|key, _| !origin.zone_of(key));
+        for view in self.views.iter() {
+            view.remove_zone(&origin);
+        }
+        self.publish_zone(ZoneChange::Deleted, &origin);
+        self.publish_change(ChangeScope::ZoneDeleted(origin));
+        self.update_catalog_zone().await;
+        self.persist().await
+    }
+
+    fn history(&self) -> Result<&History, RdnsError> {
+        self.history
+            .as_ref()
+            .ok_or_else(|| RdnsError::NotEnabled("zone history is not enabled".into()))
+    }
+
+    /// The change id of the newest version of the zone holding `name`, if a history is kept.
+    pub fn latest_change_id(&self, name: &str) -> Option<u64> {
+        let name = LowerName::new(&DnsState::parse_name(name).ok()?);
+        self.history.as_ref()?.latest(self.find_zone(&name).ok()?.origin())
+    }
+
+    /// Lists the kept versions of the zone at `origin`, oldest first.
+    pub fn zone_versions(&self, origin: &str) -> Result<Vec<VersionInfo>, RdnsError> {
+        let history = self.history()?;
+        let origin = LowerName::new(&DnsState::parse_name(origin)?);
+        if !self.zones.contains_key(&origin) {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+        }
+        Ok(history.versions(&origin))
+    }
+
+    /// Replaces the records of the zone at `origin` with those of a kept version in a
+    /// single swap, keeping the result as a new version, and returns the change id of the
+    /// version rolled back to. The SOA serial carries on increasing from the current one
+    /// rather than going back, so that secondaries pick the rollback up.
+    ///
+    /// PTR records generated in reverse zones for the zone's A and AAAA records, and pools
+    /// and health checks of its RRsets, are left as they are.
+    pub async fn rollback_zone(&self, origin: &str, selector: VersionSelector) -> Result<u64, RdnsError> {
+        self.check_leader()?;
+        let history = self.history()?;
+        let origin = LowerName::new(&DnsState::parse_name(origin)?);
+        self.check_writable(&origin)?;
+        let Some(authority) = self.zones.get(&origin) else {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+        };
+        let Some((change_id, version)) = history.find(&origin, selector) else {
+            return Err(RdnsError::VersionNotFound(format!("no version of zone {} with {} is kept", origin, selector)));
+        };
+
+        let current = self.stored_records(authority).await;
+        let serial = authority.serial().await;
+        let mut records = authority.records_mut().await;
+        // DNSKEY records belong to the zone's signing keys rather than its contents
+        let mut replacement: BTreeMap<RrKey, Arc<RecordSet>> = records
+            .iter()
+            .filter(|(key, _)| key.record_type == RecordType::DNSKEY)
+            .map(|(key, set)| (key.clone(), set.clone()))
+            .collect();
+        for record in &version {
+            let record = soa::with_serial(record, serial).unwrap_or_else(|| record.clone());
+            let set = replacement
+                .entry(RrKey::new(LowerName::new(record.name()), record.record_type()))
+                .or_insert_with(|| Arc::new(RecordSet::new(record.name(), record.record_type(), 0)));
+            Arc::make_mut(set).insert(record, 0);
+        }
+        *records = replacement;
+        drop(records);
+
+        for record in current.iter().filter(|record| !version.contains(record)) {
+            self.publish(RecordChange::Deleted, record);
+        }
+        for record in version.iter().filter(|record| !current.contains(record)) {
+            self.publish(RecordChange::Added, record);
+        }
+        self.zone_updated(authority).await?;
+        Ok(change_id)
+    }
+
+    /// Lists the hosted zones, or only those owned by `tenant`, along with the number of
+    /// records in each, whether PTR records are generated for them and whether they are
+    /// frozen.
+    pub async fn list_zones(&self, tenant: Option<&str>) -> Vec<(String, u32, bool, bool)> {
+        let mut result = Vec::with_capacity(self.zones.len());
+        for (origin, authority) in &self.zones {
+            if tenant.is_some_and(|tenant| self.zone_tenant(origin).as_deref() != Some(tenant)) {
+                continue;
+            }
+            let records = authority.records().await;
+            let count = records.values().map(|set| set.records_without_rrsigs().count()).sum::<usize>();
+            result.push((
+                Name::from(origin.clone()).to_ascii(),
+                count as u32,
+                self.auto_reverse.contains(origin),
+                self.is_frozen(origin),
+            ));
+        }
+        result.sort();
+        result
+    }
+
+    /// Subscribes to record changes made through `add_record`, `update_record`,
+    /// `delete_record`, `import_records` and `apply_changeset`.
+    pub fn subscribe(&self) -> broadcast::Receiver<RecordEvent> {
+        self.events.subscribe()
+    }
+
+    /// Notifies subscribers of a change to `record`.
+    fn publish(&self, change: RecordChange, record: &Record) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.events.send(RecordEvent {
+            change,
+            record: RecordEntry::from(record),
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    /// Subscribes to the zones created and deleted through `create_zone`,
+    /// `create_zone_from_template` and `delete_zone`.
+    pub fn subscribe_zones(&self) -> broadcast::Receiver<ZoneEvent> {
+        self.zone_events.subscribe()
+    }
+
+    /// Subscribes to the queries answered by the DNS listeners. Queries are only
+    /// published while there is a subscriber.
+    pub fn subscribe_queries(&self) -> broadcast::Receiver<QueryEvent> {
+        self.queries.subscribe()
+    }
+
+    /// Notifies zone subscribers of the zone at `origin` being created or deleted.
+    fn publish_zone(&self, change: ZoneChange, origin: &LowerName) {
+        let _ = self.zone_events.send(ZoneEvent {
+            change,
+            origin: origin.clone(),
+            timestamp: SystemTime::now(),
+        });
+    }
+
+    /// Subscribes to changes of the replicated state, returning the sequence number of
+    /// the latest change made before the subscription.
+    pub fn subscribe_changes(&self) -> (u64, broadcast::Receiver<StateChange>) {
+        (self.sequence(), self.changes.subscribe())
+    }
+
+    /// Sequence number of the latest change to the replicated state.
+    pub fn sequence(&self) -> u64 {
+        self.sequence.load(Ordering::Relaxed)
+    }
+
+    /// Notifies replication subscribers of a change to `scope`.
+    fn publish_change(&self, scope: ChangeScope) {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let timestamp = SystemTime::now();
+        if self.store.is_some() {
+            let mut unjournaled = self.unjournaled.lock().unwrap();
+            if unjournaled.len() >= JOURNAL_SIZE {
+                unjournaled.remove(0);
+            }
+            unjournaled.push(JournalEntry {
+                sequence,
+                timestamp,
+                scope: scope.to_string(),
+            });
+        }
+        let _ = self.changes.send(StateChange { sequence, timestamp, scope });
+    }
+
+    /// Makes this instance a follower of `leader`, or a standalone instance again when
+    /// unset. A follower rejects every change made through the mutation methods with
+    /// `NotLeader`, taking changes only through the `apply_replicated_*` methods.
+    pub fn set_leader(&mut self, leader: Option<String>) {
+        self.leader = leader;
+    }
+
+    /// Whether this instance follows a leader.
+    pub fn is_follower(&self) -> bool {
+        self.leader.is_some()
+    }
+
+    /// Fails if this instance follows a leader, which changes must be sent to instead.
+    fn check_leader(&self) -> Result<(), RdnsError> {
+        match &self.leader {
+            Some(leader) => Err(RdnsError::NotLeader(format!(
+                "this instance follows {}, send changes to the leader instead",
+                leader
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds a record to the in-memory DNS zone containing it, along with its PTR record
+    /// if the zone generates them. Returns the TTL the record was clamped to, if it was
+    /// out of bounds, and whether a record of the same value existed already.
+    pub async fn add_record(&mut self, name: String, record_type: String, value: String, ttl: u32) -> Result<Added, RdnsError> {
+        self.add_leased_record(name, record_type, value, ttl, None, None).await
+    }
+
+    /// Adds a record like `add_record`, removing it again at `expires_at` when that is
+    /// set. Without it, a lease the record had is dropped and the record kept for good.
+    /// The record's metadata is replaced with `metadata` when that is set, and kept
+    /// otherwise.
+    pub async fn add_leased_record(
+        &mut self,
+        name: String,
+        record_type: String,
+        value: String,
+        ttl: u32,
+        expires_at: Option<SystemTime>,
+        metadata: Option<RecordMetadata>,
+    ) -> Result<Added, RdnsError> {
+        self.check_leader()?;
+        if expires_at.is_some_and(|expires_at| expires_at <= SystemTime::now()) {
+            return Err(RdnsError::InvalidArgument("the lease has already lapsed".into()));
+        }
+        if let Some(metadata) = &metadata {
+            metadata.validate()?;
+        }
+        let (record, clamped) = self.build_new_record(name, record_type, value, ttl)?;
+        let authority = self.find_writable_zone(&LowerName::new(record.name()))?;
+        let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+        let records = authority.records().await;
+        DnsState::check_cname_conflict(&records, &record)?;
+        DnsState::check_occlusion(&records, authority.origin(), &record)?;
+        if let Some(set) = records.get(&key) {
+            mailauth::check_conflict(set.records_without_rrsigs(), &record)?;
+        }
+        let exists = records
+            .get(&key)
+            .is_some_and(|set| set.records_without_rrsigs().any(|existing| existing.data() == record.data()));
+        drop(records);
+        if !exists {
+            self.check_quota(authority.origin(), Usage::of([&record]))?;
+        }
+        authority.upsert(record.clone(), 0).await;
+        self.leases.set(&record, expires_at);
+        if let Some(metadata) = metadata {
+            self.metadata.set(&record, metadata);
+        }
+        let change = if exists { RecordChange::Updated } else { RecordChange::Added };
+        self.publish(change, &record);
+        self.zone_updated(authority).await?;
+        self.add_reverse(&record).await;
+        Ok(Added { clamped, duplicate: exists })
+    }
+
+    /// Replaces existing records of a name and type with a single new record, without
+    /// the window a delete followed by an add leaves open.
+    ///
+    /// Without `expected`, every record of the name and type is replaced. With it, only
+    /// the record whose rdata equals `expected` is, and the update fails if there is no
+    /// such record, so concurrent writers can detect each other's changes. Returns the TTL
+    /// the new record's was clamped to, like `add_record`.
+    ///
+    /// The new record takes `metadata` when that is set, or else the metadata of the
+    /// record it replaces, preferring a replaced record of the same value.
+    pub async fn update_record(
+        &mut self,
+        name: String,
+        record_type: String,
+        value: String,
+        ttl: u32,
+        expected: Option<String>,
+        metadata: Option<RecordMetadata>,
+    ) -> Result<Option<u32>, RdnsError> {
+        self.check_leader()?;
+        if let Some(metadata) = &metadata {
+            metadata.validate()?;
+        }
+        let (record, clamped) = self.build_new_record(name, record_type, value, ttl)?;
+        let expected = expected
+            .map(|value| DnsState::parse_rdata(record.record_type(), &value))
+            .transpose()
+            .map_err(|e| RdnsError::InvalidRdata(format!("invalid expected value: {}", e)))?;
+        let authority = self.find_writable_zone(&LowerName::new(record.name()))?;
+        let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+
+        let mut records = authority.records_mut().await;
+        let Some(set) = records.get_mut(&key) else {
+            return Err(RdnsError::RecordNotFound(format!("no {} records exist for {}", record.record_type(), record.name())));
+        };
+        let mut kept: Vec<Record> = Vec::new();
+        if let Some(expected) = &expected {
+            if !set.records_without_rrsigs().any(|existing| existing.data() == Some(expected)) {
+                return Err(RdnsError::ValueMismatch(format!(
+                    "current {} value of {} does not match the expected value",
+                    record.record_type(),
+                    record.name()
+                )));
+            }
+            kept.extend(set.records_without_rrsigs().filter(|existing| existing.data() != Some(expected)).cloned());
+            mailauth::check_conflict(&kept, &record)?;
+        }
+        let replaced: Vec<Record> = set
+            .records_without_rrsigs()
+            .filter(|existing| !kept.iter().any(|kept| kept.data() == existing.data()))
+            .cloned()
+            .collect();
+        let mut replacement = RecordSet::new(record.name(), record.record_type(), 0);
+        for existing in kept {
+            replacement.insert(existing, 0);
+        }
+        replacement.insert(record.clone(), 0);
+        *set = Arc::new(replacement);
+        drop(records);
+        let metadata = metadata.unwrap_or_else(|| {
+            let same_value = replaced.iter().filter(|replaced| replaced.data() == record.data());
+            same_value
+                .chain(&replaced)
+                .map(|replaced| self.metadata.get(replaced))
+                .find(|metadata| !metadata.is_empty())
+                .unwrap_or_default()
+        });
+        for replaced in &replaced {
+            self.leases.remove(replaced);
+            self.metadata.remove(replaced);
+        }
+        self.metadata.set(&record, metadata);
+
+        self.publish(RecordChange::Updated, &record);
+        self.zone_updated(authority).await?;
+        for replaced in &replaced {
+            self.delete_reverse(replaced).await;
+        }
+        self.add_reverse(&record).await;
+        Ok(clamped)
+    }
+
+    /// Deletes all records of a type (by key) from the in-memory DNS zone containing them.
+    pub async fn delete_record(&self, name: String, record_type: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let key = DnsState::build_record_key(name, record_type)?;
+        let authority = self.find_writable_zone(&key.name)?;
+        let Some(removed) = authority.records_mut().await.remove(&key) else {
+            return Err(RdnsError::RecordNotFound(format!("no {} records exist for {}", key.record_type, key.name)));
+        };
+        self.pools.write().unwrap().remove(&key);
+        self.health.remove_rrset(&key);
+        self.leases.remove_rrset(&key);
+        self.metadata.remove_rrset(&key);
+        for record in removed.records_without_rrsigs() {
+            self.publish(RecordChange::Deleted, record);
+        }
+        self.zone_updated(authority).await?;
+        for record in removed.records_without_rrsigs() {
+            self.delete_reverse(record).await;
+        }
+        Ok(())
+    }
+
+    /// Deletes the one record of a name and type whose rdata equals `value`, keeping the
+    /// other values of the RRset.
+    pub async fn delete_record_value(&self, name: String, record_type: String, value: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        // The TTL doesn't take part in matching
+        let target = DnsState::build_record(name, record_type, value, 0)?;
+        let authority = self.find_writable_zone(&LowerName::new(target.name()))?;
+        let key = RrKey::new(LowerName::new(target.name()), target.record_type());
+
+        let mut records = authority.records_mut().await;
+        let Some(set) = records.get_mut(&key) else {
+            return Err(RdnsError::RecordNotFound(format!("no {} records exist for {}", target.record_type(), target.name())));
+        };
+        let Some(removed) = set.records_without_rrsigs().find(|existing| existing.data() == target.data()).cloned() else {
+            return Err(RdnsError::RecordNotFound(format!("{} has no {} record with that value", target.name(), target.record_type())));
+        };
+        let remaining: Vec<Record> = set
+            .records_without_rrsigs()
+            .filter(|existing| existing.data() != target.data())
+            .cloned()
+            .collect();
+        if remaining.is_empty() {
+            records.remove(&key);
+        } else {
+            let mut replacement = RecordSet::new(target.name(), target.record_type(), 0);
+            for existing in remaining {
+                replacement.insert(existing, 0);
+            }
+            *set = Arc::new(replacement);
+        }
+        drop(records);
+        self.leases.remove(&removed);
+        self.metadata.remove(&removed);
+        if let Some(value) = removed.data() {
+            self.health.remove(&key, value);
+        }
+
+        self.publish(RecordChange::Deleted, &removed);
+        self.zone_updated(authority).await?;
+        self.delete_reverse(&removed).await;
+        Ok(())
+    }
+
+    /// Removes the records whose leases lapsed by `now`, returning how many there were.
+    pub async fn reap_leases(&self, now: SystemTime) -> usize {
+        let mut reaped = 0;
+        for lease in self.leases.lapsed(now) {
+            let description = format!("{} {} {}", lease.name, lease.record_type, lease.value);
+            let result = self.delete_record_value(lease.name.clone(), lease.record_type.clone(), lease.value.clone()).await;
+            match result {
+                Ok(()) => {
+                    println!("Removed {}, whose lease lapsed", description);
+                    reaped += 1;
+                }
+                Err(RdnsError::RecordNotFound(_) | RdnsError::ZoneNotFound(_)) => {}
+                // Kept until the zone is thawed
+                Err(RdnsError::ZoneFrozen(_)) => continue,
+                Err(e) => eprintln!("Failed to remove {}, whose lease lapsed: {}", description, e),
+            }
+            // A lease that can't be reaped isn't tried again
+            if let Ok(record) = DnsState::build_record(lease.name, lease.record_type, lease.value, 0) {
+                self.leases.remove(&record);
+            }
+        }
+        reaped
+    }
+
+    /// Queues `change` to be applied at its `effective_at`, which must be in the future,
+    /// returning the id it is queued under. The change is checked as far as it can be
+    /// ahead of time: its record must be valid and its zone hosted and writable.
+    pub async fn schedule_change(&self, change: ScheduledChange) -> Result<u64, RdnsError> {
+        self.check_leader()?;
+        if change.effective_at <= SystemTime::now() {
+            return Err(RdnsError::InvalidArgument("effective_at is not in the future".into()));
+        }
+        let key = match change.kind {
+            ScheduledKind::Add => {
+                if change.expires_at.is_some_and(|expires_at| expires_at <= change.effective_at) {
+                    return Err(RdnsError::InvalidArgument("the lease lapses before the record is added".into()));
+                }
+                if let Some(metadata) = &change.metadata {
+                    metadata.validate()?;
+                }
+                let (record, _) = self.build_new_record(change.name.clone(), change.record_type.clone(), change.value.clone(), change.ttl)?;
+                RrKey::new(LowerName::new(record.name()), record.record_type())
+            }
+            ScheduledKind::Delete => DnsState::build_record_key(change.name.clone(), change.record_type.clone())?,
+        };
+        let origin = self.find_writable_zone(&key.name)?.origin().clone();
+        let id = self.scheduled.add(change);
+        self.publish_change(ChangeScope::Zone(origin));
+        self.persist().await?;
+        Ok(id)
+    }
+
+    /// The changes queued by `schedule_change`, in the order they are due.
+    pub fn pending_changes(&self) -> Vec<ScheduledChange> {
+        self.scheduled.entries()
+    }
+
+    /// The pending change `id`, if there is one.
+    pub fn pending_change(&self, id: u64) -> Option<ScheduledChange> {
+        self.scheduled.entries().into_iter().find(|change| change.id == id)
+    }
+
+    /// Drops the pending change `id` before it is applied.
+    pub async fn cancel_change(&self, id: u64) -> Result<ScheduledChange, RdnsError> {
+        self.check_leader()?;
+        let Some(change) = self.scheduled.remove(id) else {
+            return Err(RdnsError::RecordNotFound(format!("no pending change {}", id)));
+        };
+        match self.find_zone(&LowerName::new(&DnsState::parse_name(&change.name)?)) {
+            Ok(authority) => self.publish_change(ChangeScope::Zone(authority.origin().clone())),
+            Err(_) => self.publish_change(ChangeScope::All),
+        }
+        self.persist().await?;
+        Ok(change)
+    }
+
+    /// Whether a pending change is due by `now`.
+    pub fn has_due_changes(&self, now: SystemTime) -> bool {
+        !self.scheduled.due(now).is_empty()
+    }
+
+    /// Applies the pending changes due by `now`, in the order they are due, returning how
+    /// many were. Those that fail are dropped, and those of frozen zones kept until the
+    /// zones are thawed.
+    pub async fn apply_scheduled(&mut self, now: SystemTime) -> usize {
+        let mut applied = 0;
+        let mut dropped = false;
+        for change in self.scheduled.due(now) {
+            // Taken out of the queue first, so that the snapshot the change persists
+            // doesn't hold it any more
+            self.scheduled.remove(change.id);
+            let result = match change.kind {
+                ScheduledKind::Add => self
+                    .add_leased_record(
+                        change.name.clone(),
+                        change.record_type.clone(),
+                        change.value.clone(),
+                        change.ttl,
+                        change.expires_at,
+                        change.metadata.clone(),
+                    )
+                    .await
+                    .map(|_| ()),
+                ScheduledKind::Delete => self.delete_record(change.name.clone(), change.record_type.clone()).await,
+            };
+            match result {
+                Ok(()) => {
+                    println!("Applied scheduled change {}: {}", change.id, change);
+                    applied += 1;
+                }
+                Err(RdnsError::ZoneFrozen(_)) => self.scheduled.insert(change),
+                Err(e) => {
+                    eprintln!("Failed to apply scheduled change {}: {}: {}", change.id, change, e);
+                    dropped = true;
+                }
+            }
+        }
+        if dropped {
+            self.publish_change(ChangeScope::All);
+            if let Err(e) = self.persist().await {
+                eprintln!("Failed to persist the dropped scheduled changes: {}", e);
+            }
+        }
+        applied
+    }
+
+    /// Queues `request` for an admin's approval, returning the id it is queued under. Its
+    /// operations are checked as `apply_changeset` would check them now.
+    pub async fn propose_changes(&self, request: ChangeRequest) -> Result<u64, RdnsError> {
+        self.check_leader()?;
+        if request.operations.is_empty() {
+            return Err(RdnsError::InvalidArgument("the change request has no operations".into()));
+        }
+        self.plan_changeset(request.operations.clone()).await?;
+        let id = self.approvals.add(request);
+        self.publish_change(ChangeScope::All);
+        self.persist().await?;
+        Ok(id)
+    }
+
+    /// The change requests queued by `propose_changes`, oldest first.
+    pub fn pending_approvals(&self) -> Vec<ChangeRequest> {
+        self.approvals.entries()
+    }
+
+    /// The change request `id`, if it is pending.
+    pub fn pending_approval(&self, id: u64) -> Option<ChangeRequest> {
+        self.approvals.get(id)
+    }
+
+    /// Applies the operations of change request `id` as a changeset, returning the request.
+    /// A request that fails to apply stays pending.
+    pub async fn approve_change(&self, id: u64) -> Result<ChangeRequest, RdnsError> {
+        self.check_leader()?;
+        // Taken out of the queue first, so that the snapshot the changeset persists
+        // doesn't hold it any more
+        let Some(request) = self.approvals.remove(id) else {
+            return Err(RdnsError::RecordNotFound(format!("no change request {}", id)));
+        };
+        if let Err(e) = self.apply_changeset(request.operations.clone()).await {
+            self.approvals.insert(request);
+            return Err(e);
+        }
+        self.publish_change(ChangeScope::All);
+        self.persist().await?;
+        Ok(request)
+    }
+
+    /// Drops change request `id` without applying it, returning the request.
+    pub async fn reject_change(&self, id: u64) -> Result<ChangeRequest, RdnsError> {
+        self.check_leader()?;
+        let Some(request) = self.approvals.remove(id) else {
+            return Err(RdnsError::RecordNotFound(format!("no change request {}", id)));
+        };
+        self.publish_change(ChangeScope::All);
+        self.persist().await?;
+        Ok(request)
+    }
+
+    /// The PTR record pointing back at the name of `record`, if it is an A or AAAA
+    /// record of a zone that generates them.
+    fn reverse_record(&self, record: &Record) -> Option<(IpAddr, Record)> {
+        let address = match record.data()? {
+            RData::A(a) => IpAddr::V4(a.0),
+            RData::AAAA(aaaa) => IpAddr::V6(aaaa.0),
+            _ => return None,
+        };
+        let origin = self.find_zone(&LowerName::new(record.name())).ok()?.origin();
+        if !self.auto_reverse.contains(origin) {
+            return None;
+        }
+        let ptr = Record::from_rdata(Name::from(address), record.ttl(), RData::PTR(PTR(record.name().clone())));
+        Some((address, ptr))
+    }
+
+    /// Adds the PTR record of `record`, creating its reverse zone if no hosted zone
+    /// holds it. A failure is only logged, as the record itself was added.
+    async fn add_reverse(&mut self, record: &Record) {
+        let Some((address, ptr)) = self.reverse_record(record) else {
+            return;
+        };
+        if let Err(e) = self.upsert_reverse(address, ptr).await {
+            eprintln!("Failed to add the PTR record of {} {}: {}", record.name(), address, e);
+        }
+    }
+
+    async fn upsert_reverse(&mut self, address: IpAddr, ptr: Record) -> Result<(), RdnsError> {
+        let name = LowerName::new(ptr.name());
+        if self.find_zone(&name).is_err() {
+            // Reverse zones are delegated on octet and nibble boundaries, a /24 or /64 being the usual size
+            let labels = match address {
+                IpAddr::V4(_) => 3 + 2,
+                IpAddr::V6(_) => 16 + 2,
+            };
+            self.create_zone(&ptr.name().trim_to(labels).to_string(), false).await?;
+        }
+        let authority = self.find_writable_zone(&name)?;
+        authority.upsert(ptr.clone(), 0).await;
+        self.publish(RecordChange::Added, &ptr);
+        self.zone_updated(authority).await
+    }
+
+    /// Deletes the PTR record of `record` from its reverse zone, if it has one. Other
+    /// names' PTR records of the same address are kept. A failure is only logged.
+    async fn delete_reverse(&self, record: &Record) {
+        let Some((address, ptr)) = self.reverse_record(record) else {
+            return;
+        };
+        let key = RrKey::new(LowerName::new(ptr.name()), RecordType::PTR);
+        let Ok(authority) = self.find_writable_zone(&key.name) else {
+            return;
+        };
+        let mut records = authority.records_mut().await;
+        let Some(set) = records.get_mut(&key) else {
+            return;
+        };
+        if !set.records_without_rrsigs().any(|existing| existing.data() == ptr.data()) {
+            return;
+        }
+        let remaining: Vec<Record> = set
+            .records_without_rrsigs()
+            .filter(|existing| existing.data() != ptr.data())
+            .cloned()
+            .collect();
+        if remaining.is_empty() {
+            records.remove(&key);
+        } else {
+            let mut replacement = RecordSet::new(ptr.name(), RecordType::PTR, 0);
+            for existing in remaining {
+                replacement.insert(existing, 0);
+            }
+            *set = Arc::new(replacement);
+        }
+        drop(records);
+        self.publish(RecordChange::Deleted, &ptr);
+        if let Err(e) = self.zone_updated(authority).await {
+            eprintln!("Failed to delete the PTR record of {} {}: {}", record.name(), address, e);
+        }
+    }
+
+    /// Parses the members of `entry` and builds its pool, along with the member records.
+    fn build_pool(entry: PoolEntry) -> anyhow::Result<(Pool, Vec<Record>)> {
+        let records = entry
+            .members
+            .iter()
+            .map(|member| DnsState::build_record(entry.name.clone(), entry.record_type.clone(), member.value.clone(), entry.ttl))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((Pool::new(entry, &records)?, records))
+    }
+
+    /// Groups the values of a name's A or AAAA records into a pool, replacing the RRset
+    /// with exactly the pool's members. An existing pool for the name and type is replaced.
+    pub async fn set_pool(&self, entry: PoolEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let (pool, records) = DnsState::build_pool(entry)?;
+        let key = pool.key();
+        let authority = self.find_writable_zone(&key.name)?;
+
+        let mut set = RecordSet::new(&Name::from(&key.name), key.record_type, 0);
+        for record in records {
+            set.insert(record, 0);
+        }
+        authority.records_mut().await.insert(key.clone(), Arc::new(set.clone()));
+        self.pools.write().unwrap().insert(key, Arc::new(pool));
+
+        for record in set.records_without_rrsigs() {
+            self.publish(RecordChange::Updated, record);
+        }
+        self.zone_updated(authority).await
+    }
+
+    /// Removes the pool of a name and type. Its records stay in the zone and are
+    /// answered like any other RRset.
+    pub async fn delete_pool(&self, name: String, record_type: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let key = DnsState::build_record_key(name, record_type)?;
+        if self.pools.write().unwrap().remove(&key).is_none() {
+            return Err(RdnsError::RecordNotFound(format!("no {} pool exists for {}", key.record_type, key.name)));
+        }
+        self.name_changed(&key.name);
+        self.persist().await
+    }
+
+    /// Lists every pool, sorted by name and type.
+    pub fn list_pools(&self) -> Vec<PoolEntry> {
+        let mut pools: Vec<PoolEntry> = self.pools.read().unwrap().values().map(|pool| pool.entry.clone()).collect();
+        pools.sort_by(|a, b| (&a.name, &a.record_type).cmp(&(&b.name, &b.record_type)));
+        pools
+    }
+
+    /// Adds a record to the overlay of `view`. Its name must be in a hosted zone.
+    pub async fn add_view_record(&self, view: &str, name: String, record_type: String, value: String, ttl: u32) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let overlay = self.views.get(view)?;
+        let (record, _) = self.build_new_record(name, record_type, value, ttl)?;
+        let name = LowerName::new(record.name());
+        self.find_writable_zone(&name)?;
+        overlay.upsert(record);
+        self.name_changed(&name);
+        self.persist().await
+    }
+
+    /// Deletes the records of a name and type from the overlay of `view`, so its clients
+    /// are answered from the shared zone again.
+    pub async fn delete_view_record(&self, view: &str, name: String, record_type: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let overlay = self.views.get(view)?;
+        let key = DnsState::build_record_key(name, record_type)?;
+        if overlay.remove(&key).is_empty() {
+            return Err(RdnsError::RecordNotFound(format!("view {} has no {} records for {}", view, key.record_type, key.name)));
+        }
+        self.name_changed(&key.name);
+        self.persist().await
+    }
+
+    /// Returns the split-horizon views.
+    pub fn views(&self) -> &Views {
+        &self.views
+    }
+
+    /// Returns the blocklist, if blocking is enabled.
+    pub fn blocklist(&self) -> Result<&Blocklist, RdnsError> {
+        self.blocklist
+            .as_deref()
+            .ok_or_else(|| RdnsError::NotEnabled("blocking is not enabled".into()))
+    }
+
+    /// Lists query statistics: the names and types `query` matches that were queried
+    /// the most, or with `stale` the records of its zone queried the least, along with
+    /// when tracking began.
+    pub async fn query_stats(&self, query: &StatsQuery, stale: bool) -> Result<(Vec<NameStats>, SystemTime), RdnsError> {
+        let stats = self.stats()?;
+        if !stale {
+            return Ok((stats.top(query), stats.started()));
+        }
+        let Some(zone) = &query.zone else {
+            return Err(RdnsError::InvalidArgument("listing stale records requires a zone".into()));
+        };
+        let Some(authority) = self.zones.get(zone) else {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", zone)));
+        };
+        let keys: Vec<(LowerName, RecordType)> = authority
+            .records()
+            .await
+            .keys()
+            .filter(|key| !key.record_type.is_dnssec())
+            .map(|key| (key.name.clone(), key.record_type))
+            .collect();
+        Ok((stats.stale(keys, query), stats.started()))
+    }
+
+    /// Lists the clients that sent the most queries within `window`, along with when
+    /// tracking began.
+    pub fn top_clients(&self, window: Option<Duration>, top: usize) -> Result<(Vec<ClientStats>, SystemTime), RdnsError> {
+        let stats = self.stats()?;
+        Ok((stats.top_clients(window, top), stats.started()))
+    }
+
+    /// Subscribes to the anomalies query statistics report.
+    pub fn subscribe_anomalies(&self) -> Result<broadcast::Receiver<Anomaly>, RdnsError> {
+        let stats = self.stats()?;
+        if stats.options().anomalies.is_none() {
+            return Err(RdnsError::NotEnabled("anomaly reporting is not enabled".into()));
+        }
+        Ok(stats.subscribe())
+    }
+
+    fn stats(&self) -> Result<&QueryStats, RdnsError> {
+        self.stats
+            .as_deref()
+            .ok_or_else(|| RdnsError::NotEnabled("query statistics are not enabled".into()))
+    }
+
+    /// Blocks or allows `domain` and its subdomains, overriding the blocklists.
+    pub async fn add_blocklist_entry(&self, domain: &str, action: Action) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        self.blocklist()?.add(domain, action)?;
+        self.publish_change(ChangeScope::Blocklist);
+        self.persist().await
+    }
+
+    /// Deletes a domain blocked or allowed with `add_blocklist_entry`.
+    pub async fn delete_blocklist_entry(&self, domain: &str, action: Action) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        if !self.blocklist()?.remove(domain, action)? {
+            return Err(RdnsError::RecordNotFound(format!("{} has no {} entry", domain, action)));
+        }
+        self.publish_change(ChangeScope::Blocklist);
+        self.persist().await
+    }
+
+    /// Parses the values of `entry` and builds its geo record.
+    fn build_geo_record(entry: GeoEntry) -> anyhow::Result<GeoRecord> {
+        let mut regions = HashMap::with_capacity(entry.regions.len());
+        for (region, values) in &entry.regions {
+            let records = values
+                .iter()
+                .map(|value| DnsState::build_record(entry.name.clone(), entry.record_type.clone(), value.clone(), entry.ttl))
+                .collect::<Result<Vec<_>, _>>()?;
+            regions.insert(region.clone(), records);
+        }
+        GeoRecord::new(entry, regions)
+    }
+
+    /// Answers a name and type with different values per client region, replacing any
+    /// geo record it already has. Clients outside every region get the zone's RRset.
+    pub async fn set_geo_record(&self, entry: GeoEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        if self.geo.is_none() {
+            return Err(RdnsError::NotEnabled("GeoDNS is not enabled".into()));
+        }
+        let record = DnsState::build_geo_record(entry)?;
+        let key = record.key();
+        self.find_writable_zone(&key.name)?;
+        self.geo_records.write().unwrap().insert(key.clone(), Arc::new(record));
+        self.name_changed(&key.name);
+        self.persist().await
+    }
+
+    /// Removes the geo record of a name and type, answering every client from the zone.
+    pub async fn delete_geo_record(&self, name: String, record_type: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let key = DnsState::build_record_key(name, record_type)?;
+        if self.geo_records.write().unwrap().remove(&key).is_none() {
+            return Err(RdnsError::RecordNotFound(format!("no {} geo record exists for {}", key.record_type, key.name)));
+        }
+        self.name_changed(&key.name);
+        self.persist().await
+    }
+
+    /// Lists every geo record, sorted by name and type.
+    pub fn list_geo_records(&self) -> Vec<GeoEntry> {
+        let records = self.geo_records.read().unwrap();
+        let mut entries: Vec<GeoEntry> = records.values().map(|record| record.entry.clone()).collect();
+        entries.sort_by(|a, b| (&a.name, &a.record_type).cmp(&(&b.name, &b.record_type)));
+        entries
+    }
+
+    /// Parses the new values of `entry` and builds its rollout.
+    fn build_rollout(entry: RolloutEntry) -> anyhow::Result<Rollout> {
+        let records = entry
+            .values
+            .iter()
+            .map(|value| DnsState::build_record(entry.name.clone(), entry.record_type.clone(), value.clone(), entry.ttl))
+            .collect::<Result<Vec<_>, _>>()?;
+        Rollout::new(entry, records)
+    }
+
+    /// Answers a name and type with new values for `percent` of the clients, replacing any
+    /// rollout it already has. The other clients get the zone's RRset.
+    pub async fn set_rollout(&self, entry: RolloutEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let rollout = DnsState::build_rollout(entry)?;
+        let key = rollout.key();
+        self.find_writable_zone(&key.name)?;
+        self.rollouts.write().unwrap().insert(key.clone(), Arc::new(rollout));
+        self.name_changed(&key.name);
+        self.persist().await
+    }
+
+    /// Ends the rollout of a name and type, answering every client from the zone.
+    pub async fn delete_rollout(&self, name: String, record_type: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let key = DnsState::build_record_key(name, record_type)?;
+        if self.rollouts.write().unwrap().remove(&key).is_none() {
+            return Err(RdnsError::RecordNotFound(format!("no {} rollout exists for {}", key.record_type, key.name)));
+        }
+        self.name_changed(&key.name);
+        self.persist().await
+    }
+
+    /// Finishes the rollout of a name and type: its new values replace the zone's RRset in
+    /// a single changeset, and every client gets them.
+    pub async fn promote_rollout(&self, name: String, record_type: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let key = DnsState::build_record_key(name, record_type)?;
+        let Some(rollout) = self.rollouts.read().unwrap().get(&key).cloned() else {
+            return Err(RdnsError::RecordNotFound(format!("no {} rollout exists for {}", key.record_type, key.name)));
+        };
+        let entry = &rollout.entry;
+        let mut operations = vec![ChangeOperation::Delete {
+            name: entry.name.clone(),
+            record_type: entry.record_type.clone(),
+        }];
+        operations.extend(entry.values.iter().map(|value| {
+            ChangeOperation::Add(RecordEntry {
+                name: entry.name.clone(),
+                record_type: entry.record_type.clone(),
+                value: value.clone(),
+                ttl: entry.ttl,
+                metadata: RecordMetadata::default(),
+            })
+        }));
+        self.apply_changeset(operations).await?;
+        self.rollouts.write().unwrap().remove(&key);
+        self.name_changed(&key.name);
+        self.persist().await
+    }
+
+    /// Lists every rollout, sorted by name and type.
+    pub fn list_rollouts(&self) -> Vec<RolloutEntry> {
+        let rollouts = self.rollouts.read().unwrap();
+        let mut entries: Vec<RolloutEntry> = rollouts.values().map(|rollout| rollout.entry.clone()).collect();
+        entries.sort_by(|a, b| (&a.name, &a.record_type).cmp(&(&b.name, &b.record_type)));
+        entries
+    }
+
+    /// Parses the values of `entry` and builds them with their schedule.
+    fn build_timed(entry: TimedEntry) -> anyhow::Result<TimedRecords> {
+        let records = entry
+            .values
+            .iter()
+            .map(|value| DnsState::build_record(entry.name.clone(), entry.record_type.clone(), value.clone(), entry.ttl))
+            .collect::<Result<Vec<_>, _>>()?;
+        TimedRecords::new(entry, records)
+    }
+
+    /// Answers a name and type with `value` instead of the zone's records while `schedule`
+    /// is active, along with the values it already has on that schedule, which take the
+    /// new TTL too. Returns the TTL it was clamped to, if it was out of bounds, and
+    /// whether the schedule had the value already.
+    pub async fn add_timed_record(&self, name: String, record_type: String, value: String, ttl: u32, schedule: String) -> Result<Added, RdnsError> {
+        self.check_leader()?;
+        let (record, clamped) = self.build_new_record(name, record_type, value.clone(), ttl)?;
+        let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+        self.find_writable_zone(&key.name)?;
+        let schedule = timed::normalize(&schedule);
+        let existing = self.timed.read().unwrap().get(&key).and_then(|sets| {
+            sets.iter().position(|timed| timed.entry.schedule == schedule).map(|position| (position, sets[position].clone()))
+        });
+        let mut entry = match &existing {
+            Some((_, timed)) => timed.entry.clone(),
+            None => TimedEntry {
+                name: record.name().to_ascii(),
+                record_type: record.record_type().to_string(),
+                ttl: 0,
+                schedule,
+                values: Vec::new(),
+            },
+        };
+        let duplicate = existing.as_ref().is_some_and(|(_, timed)| timed.contains(&record));
+        if !duplicate {
+            entry.values.push(value);
+        }
+        entry.ttl = record.ttl();
+        let timed = Arc::new(DnsState::build_timed(entry)?);
+        {
+            let mut table = self.timed.write().unwrap();
+            let sets = table.entry(key.clone()).or_default();
+            match existing {
+                Some((position, _)) if position < sets.len() => sets[position] = timed,
+                _ => sets.push(timed),
+            }
+        }
+        self.name_changed(&key.name);
+        self.persist().await?;
+        Ok(Added { clamped, duplicate })
+    }
+
+    /// Stops answering a name and type with the values it has on `schedule`.
+    pub async fn delete_timed_records(&self, name: String, record_type: String, schedule: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let key = DnsState::build_record_key(name, record_type)?;
+        let schedule = timed::normalize(&schedule);
+        {
+            let mut table = self.timed.write().unwrap();
+            let sets = table.get_mut(&key);
+            let removed = sets.is_some_and(|sets| {
+                let before = sets.len();
+                sets.retain(|timed| timed.entry.schedule != schedule);
+                sets.len() < before
+            });
+            if !removed {
+                return Err(RdnsError::RecordNotFound(format!(
+                    "no {} values of {} are answered on schedule {}",
+                    key.record_type, key.name, schedule
+                )));
+            }
+            table.retain(|_, sets| !sets.is_empty());
+        }
+        self.name_changed(&key.name);
+        self.persist().await
+    }
+
+    /// Lists the values on every schedule, sorted by name and type, with whether they are
+    /// answered now.
+    pub fn list_timed_records(&self) -> Vec<(TimedEntry, bool)> {
+        let now = SystemTime::now();
+        let table = self.timed.read().unwrap();
+        let mut entries: Vec<(TimedEntry, bool)> = table
+            .values()
+            .flatten()
+            .map(|timed| (timed.entry.clone(), timed.active(now)))
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| (&a.name, &a.record_type).cmp(&(&b.name, &b.record_type)));
+        entries
+    }
+
+    /// Answers the names `entry`'s pattern matches with records synthesized from them,
+    /// replacing any values the pattern already has of the type.
+    pub async fn set_pattern(&self, entry: PatternEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let pattern = Pattern::new(entry)?;
+        let suffix = pattern.suffix().clone();
+        self.find_writable_zone(&suffix)?;
+        pattern::insert(&mut self.patterns.write().unwrap(), pattern);
+        self.name_changed(&suffix);
+        self.persist().await
+    }
+
+    /// Stops answering the names a pattern matches with values of `record_type`.
+    pub async fn delete_pattern(&self, pattern: String, record_type: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let record_type = DnsState::parse_record_type(&record_type)?;
+        let pattern = format!("{}.", pattern.trim_end_matches('.').to_ascii_lowercase());
+        let removed = {
+            let mut patterns = self.patterns.write().unwrap();
+            let position = patterns.iter().position(|existing| existing.key() == (pattern.as_str(), record_type));
+            position.map(|position| patterns.remove(position))
+        };
+        let Some(removed) = removed else {
+            return Err(RdnsError::RecordNotFound(format!("no {} values exist for pattern {}", record_type, pattern)));
+        };
+        self.name_changed(removed.suffix());
+        self.persist().await
+    }
+
+    /// Lists every name pattern, sorted by pattern and type.
+    pub fn list_patterns(&self) -> Vec<PatternEntry> {
+        let patterns = self.patterns.read().unwrap();
+        let mut entries: Vec<PatternEntry> = patterns.iter().map(|pattern| pattern.entry.clone()).collect();
+        entries.sort_by(|a, b| (&a.pattern, &a.record_type).cmp(&(&b.pattern, &b.record_type)));
+        entries
+    }
+
+    /// Parses the values of `entry` and builds its override.
+    fn build_override(entry: OverrideEntry) -> Result<Override, RdnsError> {
+        let records = entry
+            .values
+            .iter()
+            .map(|value| DnsState::build_record(entry.name.clone(), entry.record_type.clone(), value.clone(), entry.ttl))
+            .collect::<Result<Vec<_>, _>>()?;
+        Override::new(entry, records)
+    }
+
+    /// Answers a name outside the hosted zones and type with local values rather than
+    /// forwarding it, replacing any override it already has of the type.
+    pub async fn set_override(&self, entry: OverrideEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let local = DnsState::build_override(entry)?;
+        let name = local.name();
+        if let Ok(authority) = self.find_zone(&name) {
+            return Err(RdnsError::InvalidArgument(format!(
+                "{} is in the hosted zone {}, change its records instead",
+                name,
+                authority.origin()
+            )));
+        }
+        {
+            let mut overrides = self.overrides.write().unwrap();
+            let types = overrides.entry(name).or_default();
+            let record_type = local.record_type();
+            let conflict = types.keys().any(|existing| *existing != record_type && (*existing == RecordType::CNAME || record_type == RecordType::CNAME));
+            if conflict {
+                return Err(RdnsError::InvalidArgument(format!("{} can't have a CNAME override along with others", local.entry.name)));
+            }
+            types.insert(record_type, Arc::new(local));
+        }
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Forwards a name and type again.
+    pub async fn delete_override(&self, name: String, record_type: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let key = DnsState::build_record_key(name, record_type)?;
+        let removed = {
+            let mut overrides = self.overrides.write().unwrap();
+            let removed = overrides.get_mut(&key.name).and_then(|types| types.remove(&key.record_type));
+            if overrides.get(&key.name).is_some_and(HashMap::is_empty) {
+                overrides.remove(&key.name);
+            }
+            removed
+        };
+        if removed.is_none() {
+            return Err(RdnsError::RecordNotFound(format!("no {} override exists for {}", key.record_type, key.name)));
+        }
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Lists every override, sorted by name and type.
+    pub fn list_overrides(&self) -> Vec<OverrideEntry> {
+        let overrides = self.overrides.read().unwrap();
+        let mut entries: Vec<OverrideEntry> = overrides.values().flat_map(|types| types.values().map(|local| local.entry.clone())).collect();
+        entries.sort_by(|a, b| (&a.name, &a.record_type).cmp(&(&b.name, &b.record_type)));
+        entries
+    }
+
+    /// Answers A and AAAA queries for the alias's name with the addresses of its target,
+    /// replacing any alias the name already has.
+    pub async fn set_alias(&self, entry: AliasEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let alias = Alias::new(entry)?;
+        let name = alias.key().clone();
+        self.find_writable_zone(&name)?;
+        self.aliases.write().unwrap().insert(name.clone(), Arc::new(alias));
+        self.name_changed(&name);
+        self.persist().await
+    }
+
+    /// Removes the alias of a name, answering its address queries from the zone again.
+    pub async fn delete_alias(&self, name: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let name = LowerName::new(&DnsState::parse_name(&name)?);
+        if self.aliases.write().unwrap().remove(&name).is_none() {
+            return Err(RdnsError::RecordNotFound(format!("no alias exists for {}", name)));
+        }
+        self.name_changed(&name);
+        self.persist().await
+    }
+
+    /// Lists every alias, sorted by name.
+    pub fn list_aliases(&self) -> Vec<AliasEntry> {
+        let aliases = self.aliases.read().unwrap();
+        let mut entries: Vec<AliasEntry> = aliases.values().map(|alias| alias.entry.clone()).collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
+
+    /// Registers an instance of a service, publishing its PTR, SRV, TXT and address
+    /// records as one changeset. Registering the instance again replaces its records.
+    pub async fn register_service(&self, entry: ServiceEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let entry = entry.normalize()?;
+        let key = entry.key()?;
+        self.find_writable_zone(&LowerName::new(&DnsState::parse_name(&entry.domain)?))?;
+        let records = entry.records();
+        let previous = self.services.write().unwrap().insert(key.clone(), entry);
+        let removed = previous.as_ref().map(ServiceEntry::records).unwrap_or_default();
+        if let Err(e) = self.update_service_records(removed, records).await {
+            let mut services = self.services.write().unwrap();
+            match previous {
+                Some(previous) => services.insert(key, previous),
+                None => services.remove(&key),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Deregisters an instance of a service, removing the records it published that no
+    /// other registration shares.
+    pub async fn deregister_service(&self, instance: &str, service: &str, protocol: &str, domain: &str) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let key = service::instance_key(instance, service, protocol, domain)?;
+        let Some(entry) = self.services.write().unwrap().remove(&key) else {
+            return Err(RdnsError::RecordNotFound(format!("service instance {} is not registered", key)));
+        };
+        if let Err(e) = self.update_service_records(entry.records(), Vec::new()).await {
+            self.services.write().unwrap().insert(key, entry);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Lists every service registration, sorted by instance name.
+    pub fn list_services(&self) -> Vec<ServiceEntry> {
+        let services = self.services.read().unwrap();
+        let mut entries: Vec<(&LowerName, &ServiceEntry)> = services.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        entries.into_iter().map(|(_, entry)| entry.clone()).collect()
+    }
+
+    /// Takes the `removed` records of a registration out of their RRsets and puts the
+    /// `added` ones in, as a single changeset. Removed records that a current
+    /// registration still publishes are kept.
+    async fn update_service_records(&self, removed: Vec<RecordEntry>, added: Vec<RecordEntry>) -> Result<(), RdnsError> {
+        let canonical = |entries: Vec<RecordEntry>| -> Result<Vec<(RrKey, RecordEntry)>, RdnsError> {
+            entries
+                .into_iter()
+                .map(|entry| {
+                    let record = DnsState::build_record(entry.name, entry.record_type, entry.value, entry.ttl)?;
+                    Ok((RrKey::new(LowerName::new(record.name()), record.record_type()), RecordEntry::from(&record)))
+                })
+                .collect()
+        };
+        let published: Vec<RecordEntry> = self.services.read().unwrap().values().flat_map(ServiceEntry::records).collect();
+        let kept: HashSet<(RrKey, String)> = canonical(published)?.into_iter().map(|(key, record)| (key, record.value)).collect();
+        let mut changes: BTreeMap<RrKey, (HashSet<String>, Vec<RecordEntry>)> = BTreeMap::new();
+        for (key, record) in canonical(removed)? {
+            if !kept.contains(&(key.clone(), record.value.clone())) {
+                changes.entry(key).or_default().0.insert(record.value);
+            }
+        }
+        for (key, record) in canonical(added)? {
+            changes.entry(key).or_default().1.push(record);
+        }
+
+        let mut operations = Vec::new();
+        for (key, (removed, added)) in changes {
+            let current = match self.get_record(key.name.to_string(), key.record_type.to_string()).await {
+                Ok(current) => current,
+                Err(RdnsError::RecordNotFound(_)) => Vec::new(),
+                Err(e) => return Err(e),
+            };
+            // An added value replaces the same value already in the RRset, e.g. with a new TTL
+            let mut remaining: Vec<RecordEntry> = current
+                .iter()
+                .filter(|record| !removed.contains(&record.value) && !added.iter().any(|new| new.value == record.value))
+                .cloned()
+                .collect();
+            remaining.extend(added);
+            let unchanged = remaining.len() == current.len()
+                && remaining.iter().all(|record| current.iter().any(|old| old.value == record.value && old.ttl == record.ttl));
+            if unchanged {
+                continue;
+            }
+            operations.push(ChangeOperation::Delete {
+                name: key.name.to_string(),
+                record_type: key.record_type.to_string(),
+            });
+            operations.extend(remaining.into_iter().map(ChangeOperation::Add));
+        }
+        if !operations.is_empty() {
+            self.apply_changeset(operations).await?;
+        }
+        Ok(())
+    }
+
+    /// Starts health-checking one value of a name's A or AAAA records, as `entry`
+    /// describes. An existing check of the value is replaced.
+    pub async fn set_health_check(&self, entry: HealthCheckEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let name = LowerName::new(&DnsState::parse_name(&entry.name)?);
+        self.start_health_check(entry).await?;
+        self.name_changed(&name);
+        self.persist().await
+    }
+
+    /// Starts the check `entry` describes, once its value is found in the zone.
+    async fn start_health_check(&self, entry: HealthCheckEntry) -> Result<(), RdnsError> {
+        let record = DnsState::build_record(entry.name.clone(), entry.record_type.clone(), entry.value.clone(), 0)?;
+        let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+        let exists = self
+            .find_zone(&key.name)?
+            .records()
+            .await
+            .get(&key)
+            .is_some_and(|set| set.records_without_rrsigs().any(|existing| existing.data() == record.data()));
+        if !exists {
+            return Err(RdnsError::RecordNotFound(format!("{} has no {} record with that value", record.name(), record.record_type())));
+        }
+        Ok(self.health.set(entry, &record)?)
+    }
+
+    /// Stops health-checking one value of a name's records, serving it unconditionally.
+    pub async fn delete_health_check(&self, name: String, record_type: String, value: String) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let target = DnsState::build_record(name, record_type, value, 0)?;
+        let key = RrKey::new(LowerName::new(target.name()), target.record_type());
+        let removed = target.data().is_some_and(|value| self.health.remove(&key, value));
+        if !removed {
+            return Err(RdnsError::RecordNotFound(format!("{} {} value is not health-checked", target.name(), target.record_type())));
+        }
+        self.name_changed(&key.name);
+        self.persist().await
+    }
+
+    /// Reports the health of each value of a name's records.
+    pub async fn record_health(&self, name: String, record_type: String) -> Result<Vec<ValueHealth>, RdnsError> {
+        let key = DnsState::build_record_key(name, record_type)?;
+        let authority = self.find_zone(&key.name)?;
+        let records = authority.records().await;
+        let Some(set) = records.get(&key) else {
+            return Err(RdnsError::RecordNotFound(format!("no {} records exist for {}", key.record_type, key.name)));
+        };
+        Ok(self.health.status(&key, set.records_without_rrsigs()))
+    }
+
+    /// Adds many records at once, like `add_record` but re-signing and persisting each
+    /// affected zone only once. Invalid records are reported in the summary rather than
+    /// failing the whole import.
+    pub async fn import_records(&self, records: Vec<RecordEntry>) -> Result<ImportSummary, RdnsError> {
+        self.check_leader()?;
+        let mut summary = ImportSummary::default();
+        let mut updated = Vec::new();
+        let mut usage = match self.limits_records() {
+            true => Some(self.record_usage()),
+            false => None,
+        };
+        for (index, entry) in records.into_iter().enumerate() {
+            let metadata = entry.metadata;
+            let result = metadata
+                .validate()
+                .and_then(|_| self.build_new_record(entry.name, entry.record_type, entry.value, entry.ttl))
+                .and_then(|(record, _)| Ok((self.find_writable_zone(&LowerName::new(record.name()))?, record)));
+            let (authority, record) = match result {
+                Ok(found) => found,
+                Err(e) => {
+                    summary.failed.push((index, e.to_string()));
+                    continue;
+                }
+            };
+
+            let records = authority.records().await;
+            if let Err(e) = DnsState::check_occlusion(&records, authority.origin(), &record) {
+                summary.failed.push((index, e.to_string()));
+                continue;
+            }
+            let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+            let (existing, conflict) = match records.get(&key) {
+                Some(set) => (
+                    set.records_without_rrsigs()
+                        .find(|existing| existing.data() == record.data())
+                        .map(Record::ttl),
+                    mailauth::check_conflict(set.records_without_rrsigs(), &record).err(),
+                ),
+                None => (None, None),
+            };
+            if let Some(e) = conflict {
+                summary.failed.push((index, e.to_string()));
+                continue;
+            }
+            let metadata_unchanged = metadata.is_empty() || self.metadata.get(&record) == metadata;
+            if existing == Some(record.ttl()) && metadata_unchanged {
+                summary.skipped += 1;
+                continue;
+            }
+            if let (Some(usage), None) = (&mut usage, existing) {
+                if let Err(e) = self.admit(usage, authority.origin(), Usage::of([&record])) {
+                    summary.failed.push((index, e.to_string()));
+                    continue;
+                }
+            }
+            authority.upsert(record.clone(), 0).await;
+            if !metadata.is_empty() {
+                self.metadata.set(&record, metadata);
+            }
+            let change = if existing.is_some() { RecordChange::Updated } else { RecordChange::Added };
+            self.publish(change, &record);
+            summary.inserted += 1;
+            if !updated.iter().any(|zone| Arc::ptr_eq(zone, authority)) {
+                updated.push(authority.clone());
+            }
+        }
+
+        for authority in &updated {
+            self.bump_serial(authority).await;
+            self.resign(authority).await?;
+            self.zone_changed(authority).await;
+            self.notify(authority);
+        }
+        if !updated.is_empty() {
+            self.persist().await?;
+        }
+        Ok(summary)
+    }
+
+    /// Imports the records and aliases converted from another provider's export of a
+    /// zone like `import_records`, creating the zone first if it isn't hosted. Failures
+    /// are reported at the position of their entry in the export.
+    pub async fn import_converted(&mut self, converted: Converted) -> Result<ImportSummary, RdnsError> {
+        self.check_leader()?;
+        let origin = LowerName::new(&DnsState::parse_name(&converted.origin)?);
+        self.check_writable(&origin)?;
+        if !self.zones.contains_key(&origin) {
+            self.create_zone(&converted.origin, false).await?;
+        }
+        let (positions, records): (Vec<usize>, Vec<RecordEntry>) = converted.records.into_iter().unzip();
+        let mut summary = self.import_records(records).await?;
+        for (index, _) in &mut summary.failed {
+            *index = positions[*index];
+        }
+        summary.failed.extend(converted.failed);
+        summary.skipped += converted.skipped;
+        for (index, alias) in converted.aliases {
+            match self.set_alias(alias).await {
+                Ok(()) => summary.inserted += 1,
+                Err(e) => summary.failed.push((index, e.to_string())),
+            }
+        }
+        summary.failed.sort_by_key(|(index, _)| *index);
+        Ok(summary)
+    }
+
+    /// Applies a list of additions and deletions atomically: either every operation
+    /// lands or, if any of them is invalid, no zone is modified. Returns the number of
+    /// operations applied.
+    ///
+    /// The operations are first applied to staged copies of the affected zones, which
+    /// replace the zones' records only once the whole changeset has been staged.
+    pub async fn apply_changeset(&self, operations: Vec<ChangeOperation>) -> Result<usize, RdnsError> {
+        let (staged, events) = self.stage_changeset(&operations).await?;
+        for (authority, records) in &staged {
+            *authority.records_mut().await = records.clone();
+        }
+        for (change, record) in &events {
+            if *change == RecordChange::Deleted {
+                self.leases.remove(record);
+                self.metadata.remove(record);
+            }
+            self.publish(*change, record);
+        }
+        for operation in operations.iter() {
+            if let ChangeOperation::Add(entry) = operation {
+                let record = DnsState::build_record(entry.name.clone(), entry.record_type.clone(), entry.value.clone(), 0);
+                if let (false, Ok(record)) = (entry.metadata.is_empty(), record) {
+                    self.metadata.set(&record, entry.metadata.clone());
+                }
+            }
+        }
+        for (authority, _) in &staged {
+            self.bump_serial(authority).await;
+            self.resign(authority).await?;
+            self.zone_changed(authority).await;
+            self.notify(authority);
+        }
+        if !staged.is_empty() {
+            self.persist().await?;
+        }
+        Ok(operations.len())
+    }
+
+    /// Stages `operations` on copies of the zones they change, failing like
+    /// `apply_changeset` if any of them is invalid or the changeset exceeds a quota. Returns
+    /// the staged zones and the record changes made to them, in order.
+    async fn stage_changeset(&self, operations: &[ChangeOperation]) -> Result<(Vec<StagedZone>, Vec<(RecordChange, Record)>), RdnsError> {
+        self.check_leader()?;
+        for (index, operation) in operations.iter().enumerate() {
+            if let ChangeOperation::Add(entry) = operation {
+                entry.metadata.validate().map_err(|e| e.context(format!("operation {}", index)))?;
+            }
+        }
+        let mut staged: Vec<StagedZone> = Vec::new();
+        let mut events = Vec::new();
+        for (index, operation) in operations.iter().enumerate() {
+            self.stage(operation.clone(), &mut staged, &mut events)
+                .await
+                .map_err(|e| e.context(format!("operation {}", index)))?;
+        }
+        if self.limits_records() {
+            // Records the changeset removes make room for those it adds elsewhere
+            let mut usage = self.record_usage();
+            let mut grown = Vec::new();
+            for (authority, records) in &staged {
+                let staged = Usage::of(records.values().flat_map(|set| set.records_without_rrsigs()));
+                let current = usage.zone(authority.origin());
+                usage.insert(authority.origin().clone(), Usage::default());
+                match staged.exceeds(current) {
+                    true => grown.push((authority.origin(), staged)),
+                    false => usage.insert(authority.origin().clone(), staged),
+                }
+            }
+            for (origin, staged) in grown {
+                self.admit(&mut usage, origin, staged)?;
+            }
+        }
+
+        Ok((staged, events))
+    }
+
+    /// Reports what `apply_changeset` would change, without changing anything. Fails
+    /// where applying the changeset would.
+    pub async fn plan_changeset(&self, operations: Vec<ChangeOperation>) -> Result<ChangePlan, RdnsError> {
+        let (staged, events) = self.stage_changeset(&operations).await?;
+        let mut plan = ChangePlan {
+            changes: events.iter().map(|(change, record)| planned(*change, record)).collect(),
+            serials: Vec::new(),
+        };
+        for (authority, records) in &staged {
+            self.plan_serial(&mut plan, authority, records);
+        }
+        Ok(plan)
+    }
+
+    /// Reports what `add_leased_record` would change, without changing anything.
+    pub async fn plan_add_record(
+        &self,
+        entry: RecordEntry,
+        expires_at: Option<SystemTime>,
+    ) -> Result<ChangePlan, RdnsError> {
+        self.check_leader()?;
+        if expires_at.is_some_and(|expires_at| expires_at <= SystemTime::now()) {
+            return Err(RdnsError::InvalidArgument("the lease has already lapsed".into()));
+        }
+        entry.metadata.validate()?;
+        let (record, _) = self.build_new_record(entry.name.clone(), entry.record_type.clone(), entry.value.clone(), entry.ttl)?;
+        let authority = self.find_writable_zone(&LowerName::new(record.name()))?;
+        let records = authority.records().await;
+        DnsState::check_cname_conflict(&records, &record)?;
+        DnsState::check_occlusion(&records, authority.origin(), &record)?;
+        if let Some(set) = records.get(&RrKey::new(LowerName::new(record.name()), record.record_type())) {
+            mailauth::check_conflict(set.records_without_rrsigs(), &record)?;
+        }
+        drop(records);
+        let mut plan = self.plan_changeset(vec![ChangeOperation::Add(entry)]).await?;
+        if let Some((_, ptr)) = self.reverse_record(&record) {
+            self.plan_reverse(&mut plan, RecordChange::Added, ptr).await;
+        }
+        Ok(plan)
+    }
+
+    /// Reports what `delete_record` would change, without changing anything.
+    pub async fn plan_delete_record(&self, name: String, record_type: String) -> Result<ChangePlan, RdnsError> {
+        self.check_leader()?;
+        let key = DnsState::build_record_key(name.clone(), record_type.clone())?;
+        let authority = self.find_writable_zone(&key.name)?;
+        let Some(removed) = authority.records().await.get(&key).cloned() else {
+            return Err(RdnsError::RecordNotFound(format!("no {} records exist for {}", key.record_type, key.name)));
+        };
+        let mut plan = self.plan_changeset(vec![ChangeOperation::Delete { name, record_type }]).await?;
+        for record in removed.records_without_rrsigs() {
+            if let Some((_, ptr)) = self.reverse_record(record) {
+                self.plan_reverse(&mut plan, RecordChange::Deleted, ptr).await;
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Adds the serial bump of the zone of `authority`, whose records would become
+    /// `records`, to `plan`, along with the SOA and NS records `bump_serial` would
+    /// generate for it.
+    fn plan_serial(&self, plan: &mut ChangePlan, authority: &InMemoryAuthority, records: &BTreeMap<RrKey, Arc<RecordSet>>) {
+        let origin = authority.origin();
+        if plan.serials.iter().any(|serial| &serial.origin == origin) {
+            return;
+        }
+        let serial = records
+            .get(&RrKey::new(origin.clone(), RecordType::SOA))
+            .and_then(|set| set.records_without_rrsigs().next())
+            .and_then(|soa| match soa.data() {
+                Some(RData::SOA(soa)) => Some(soa.serial()),
+                _ => None,
+            });
+        let next = match (serial, &self.soa) {
+            (Some(serial), _) => Some(serial.wrapping_add(1)),
+            (None, Some(options)) => {
+                let has_ns = records.contains_key(&RrKey::new(origin.clone(), RecordType::NS));
+                let serial = soa::initial_serial();
+                let generated = options.template(origin).records(&Name::from(origin), serial);
+                plan.changes.extend(
+                    generated
+                        .iter()
+                        .filter(|record| record.record_type() == RecordType::SOA || !has_ns)
+                        .map(|record| planned(RecordChange::Added, record)),
+                );
+                Some(serial)
+            }
+            (None, None) => None,
+        };
+        plan.serials.push(SerialChange {
+            origin: origin.clone(),
+            serial,
+            next,
+        });
+    }
+
+    /// Adds the change to PTR record `ptr` that `add_reverse` or `delete_reverse` would
+    /// make to `plan`.
+    async fn plan_reverse(&self, plan: &mut ChangePlan, change: RecordChange, ptr: Record) {
+        let Ok(authority) = self.find_writable_zone(&LowerName::new(ptr.name())) else {
+            // The reverse zone would be created along with the record
+            if change == RecordChange::Added {
+                plan.changes.push(planned(change, &ptr));
+            }
+            return;
+        };
+        let records = authority.records().await;
+        let exists = records
+            .get(&RrKey::new(LowerName::new(ptr.name()), RecordType::PTR))
+            .is_some_and(|set| set.records_without_rrsigs().any(|existing| existing.data() == ptr.data()));
+        let change = match (change, exists) {
+            (RecordChange::Deleted, false) => return,
+            (RecordChange::Deleted, true) => RecordChange::Deleted,
+            (_, true) => RecordChange::Updated,
+            (_, false) => RecordChange::Added,
+        };
+        plan.changes.push(planned(change, &ptr));
+        self.plan_serial(plan, authority, &records);
+    }
+
+    /// Makes the records of `types` in the zone at `origin` exactly `records`, e.g. those
+    /// an integration derives from an outside source, as a single changeset that only
+    /// touches the RRsets that differ. Returns the number of RRsets changed.
+    ///
+    /// Every record must be in the zone, or nothing is changed.
+    pub async fn sync_records(&self, origin: &str, types: &[RecordType], records: Vec<RecordEntry>) -> Result<usize, RdnsError> {
+        self.check_leader()?;
+        let (operations, changed) = self.diff_zone(origin, |record_type| types.contains(&record_type), records).await?;
+        if !operations.is_empty() {
+            self.apply_changeset(operations).await?;
+        }
+        Ok(changed)
+    }
+
+    /// Makes the zone at `origin` hold exactly the records of `spec`, as a single
+    /// changeset that only touches the RRsets that differ. The SOA and DNSSEC records are
+    /// managed by rdns, so those of the zone are kept and those of the spec ignored.
+    /// Returns what the changeset changes; with `dry_run`, nothing is changed.
+    pub async fn apply_zone_spec(&self, origin: &str, spec: Vec<RecordEntry>, dry_run: bool) -> Result<ChangePlan, RdnsError> {
+        self.check_leader()?;
+        let spec = spec
+            .into_iter()
+            .filter(|record| !DnsState::parse_record_type(&record.record_type).is_ok_and(is_managed))
+            .collect();
+        let (operations, _) = self.diff_zone(origin, |record_type| !is_managed(record_type), spec).await?;
+        if operations.is_empty() {
+            return Ok(ChangePlan::default());
+        }
+        let mut plan = self.plan_changeset(operations.clone()).await?;
+        // The records an RRset is replaced with that keep their value are updated, when
+        // their TTL changes, rather than deleted and added again
+        let mut changes: Vec<RecordEvent> = Vec::with_capacity(plan.changes.len());
+        for event in plan.changes {
+            let replaced = changes.iter().position(|other| {
+                other.change == RecordChange::Deleted
+                    && other.record.name == event.record.name
+                    && other.record.record_type == event.record.record_type
+                    && other.record.value == event.record.value
+            });
+            match replaced {
+                Some(position) if event.change == RecordChange::Added => {
+                    let replaced = changes.remove(position);
+                    if replaced.record.ttl != event.record.ttl {
+                        changes.push(RecordEvent {
+                            change: RecordChange::Updated,
+                            ..event
+                        });
+                    }
+                }
+                _ => changes.push(event),
+            }
+        }
+        plan.changes = changes;
+        if !dry_run {
+            self.apply_changeset(operations).await?;
+        }
+        Ok(plan)
+    }
+
+    /// The changeset that makes the RRsets of the zone at `origin` whose type `types`
+    /// accepts exactly `records`, and the number of RRsets it changes. The records are
+    /// compared in the form they would be stored in, their TTLs clamped.
+    ///
+    /// Fails if any of the records is invalid or outside the zone.
+    async fn diff_zone(
+        &self,
+        origin: &str,
+        types: impl Fn(RecordType) -> bool,
+        records: Vec<RecordEntry>,
+    ) -> Result<(Vec<ChangeOperation>, usize), RdnsError> {
+        let zone = LowerName::new(&DnsState::parse_name(origin)?);
+        let mut current: BTreeMap<(LowerName, RecordType), BTreeSet<(String, u32)>> = BTreeMap::new();
+        for record in self.get_zone_records(origin).await? {
+            let key = (LowerName::new(&DnsState::parse_name(&record.name)?), DnsState::parse_record_type(&record.record_type)?);
+            if types(key.1) {
+                current.entry(key).or_default().insert((record.value, record.ttl));
+            }
+        }
+        let mut desired: BTreeMap<(LowerName, RecordType), BTreeSet<(String, u32)>> = BTreeMap::new();
+        for record in records {
+            let (record, _) = self.build_new_record(record.name, record.record_type, record.value, record.ttl)?;
+            let name = LowerName::new(record.name());
+            if !zone.zone_of(&name) {
+                return Err(RdnsError::InvalidName(format!("{} is not in zone {}", name, zone)));
+            }
+            let entry = RecordEntry::from(&record);
+            desired.entry((name, record.record_type())).or_default().insert((entry.value, entry.ttl));
+        }
+
+        let mut operations = Vec::new();
+        let mut changed = 0;
+        let keys: BTreeSet<&(LowerName, RecordType)> = current.keys().chain(desired.keys()).collect();
+        for key in keys {
+            let values = desired.get(key);
+            if current.get(key) == values {
+                continue;
+            }
+            changed += 1;
+            let (name, record_type) = (key.0.to_string(), key.1.to_string());
+            operations.push(ChangeOperation::Delete {
+                name: name.clone(),
+                record_type: record_type.clone(),
+            });
+            for (value, ttl) in values.into_iter().flatten() {
+                operations.push(ChangeOperation::Add(RecordEntry {
+                    name: name.clone(),
+                    record_type: record_type.clone(),
+                    value: value.clone(),
+                    ttl: *ttl,
+                    ..Default::default()
+                }));
+            }
+        }
+        Ok((operations, changed))
+    }
+
+    /// Applies one changeset operation to the staged copy of the zone it belongs to,
+    /// staging a copy of the zone first if it hasn't been yet.
+    async fn stage(
+        &self,
+        operation: ChangeOperation,
+        staged: &mut Vec<StagedZone>,
+        events: &mut Vec<(RecordChange, Record)>,
+    ) -> Result<(), RdnsError> {
+        let (record, key) = match operation {
+            ChangeOperation::Add(entry) => {
+                let (record, _) = self.build_new_record(entry.name, entry.record_type, entry.value, entry.ttl)?;
+                let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+                (Some(record), key)
+            }
+            ChangeOperation::Delete { name, record_type } => (None, DnsState::build_record_key(name, record_type)?),
+        };
+
+        let authority = self.find_writable_zone(&key.name)?;
+        let position = match staged.iter().position(|(zone, _)| Arc::ptr_eq(zone, authority)) {
+            Some(position) => position,
+            None => {
+                let records = authority.records().await.clone();
+                staged.push((authority.clone(), records));
+                staged.len() - 1
+            }
+        };
+        let records = &mut staged[position].1;
+
+        match record {
+            Some(record) => {
+                DnsState::check_occlusion(records, authority.origin(), &record)?;
+                let set = records
+                    .entry(key)
+                    .or_insert_with(|| Arc::new(RecordSet::new(record.name(), record.record_type(), 0)));
+                mailauth::check_conflict(set.records_without_rrsigs(), &record)?;
+                let exists = set.records_without_rrsigs().any(|existing| existing.data() == record.data());
+                Arc::make_mut(set).insert(record.clone(), 0);
+                let change = if exists { RecordChange::Updated } else { RecordChange::Added };
+                events.push((change, record));
+            }
+            None => {
+                if let Some(set) = records.remove(&key) {
+                    events.extend(set.records_without_rrsigs().map(|record| (RecordChange::Deleted, record.clone())));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the contents of a zone with the records parsed from zone file `content`,
+    /// creating the zone if needed. Returns the number of records imported.
+    ///
+    /// When `origin` is empty it is taken from the zone file's `$ORIGIN`.
+    pub async fn import_zone(&mut self, origin: &str, content: &str, path: Option<PathBuf>) -> Result<usize, RdnsError> {
+        self.check_leader()?;
+        let origin = match origin {
+            "" => None,
+            origin => Some(DnsState::parse_name(origin)?),
+        };
+        let (origin, records) = zonefile::parse(content, origin, path)?;
+        let key = LowerName::new(&origin);
+        self.check_writable(&key)?;
+        if self.limits_records() {
+            // The imported records replace those the zone holds
+            let mut usage = self.record_usage();
+            usage.insert(key.clone(), Usage::default());
+            self.admit(&mut usage, &key, Usage::of(&records))?;
+        }
+        if !self.zones.contains_key(&key) {
+            self.create_zone(&origin.to_string(), false).await?;
+        }
+
+        let authority = &self.zones[&key];
+        // DNSKEY records belong to the zone's signing keys rather than its contents
+        authority
+            .records_mut()
+            .await
+            .retain(|key, _| key.record_type == RecordType::DNSKEY);
+        for record in &records {
+            authority.upsert(record.clone(), 0).await;
+        }
+        self.zone_updated(authority).await?;
+        Ok(records.len())
+    }
+
+    /// Imports a zone file from disk, see `import_zone`.
+    pub async fn import_zone_file(&mut self, zone_file: &ZoneFile) -> Result<usize, RdnsError> {
+        let content = tokio::fs::read_to_string(&zone_file.path).await.map_err(anyhow::Error::from)?;
+        let origin = zone_file.origin.clone().unwrap_or_default();
+        self.import_zone(&origin, &content, Some(zone_file.path.clone())).await
+    }
+
+    /// Serializes every record of a zone as a BIND-format zone file.
+    pub async fn export_zone(&self, origin: &str) -> Result<String, RdnsError> {
+        let origin = DnsState::parse_name(origin)?;
+        let Some(authority) = self.zones.get(&LowerName::new(&origin)) else {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+        };
+        let records = DnsState::zone_records(authority).await;
+        Ok(zonefile::format(&origin, &records))
+    }
+
+    /// The records of the zone at `origin` as a JSON export, only those of `name` when
+    /// given, and of those only the ones of `record_type` when given too.
+    pub async fn export_records(&self, origin: &str, name: Option<&str>, record_type: Option<&str>) -> Result<RecordExport, RdnsError> {
+        let origin = DnsState::parse_name(origin)?;
+        let Some(authority) = self.zones.get(&LowerName::new(&origin)) else {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+        };
+        let name = name.map(DnsState::parse_name).transpose()?.map(|name| LowerName::new(&name));
+        if let Some(name) = name.as_ref().filter(|name| !authority.origin().zone_of(name)) {
+            return Err(RdnsError::InvalidArgument(format!("{} is not in zone {}", name, origin)));
+        }
+        let record_type = record_type.map(DnsState::parse_record_type).transpose()?;
+        let records = self
+            .stored_records(authority)
+            .await
+            .iter()
+            .filter(|record| record.record_type() != RecordType::SOA)
+            .filter(|record| name.as_ref().is_none_or(|name| *name == LowerName::new(record.name())))
+            .filter(|record| record_type.is_none_or(|record_type| record.record_type() == record_type))
+            .map(|record| self.entry_of(record))
+            .collect();
+        Ok(RecordExport::new(origin.to_ascii(), authority.serial().await, records))
+    }
+
+    /// Checks a zone for common problems, such as missing glue or CNAMEs sharing their name
+    /// with other records, without changing it.
+    pub async fn check_zone(&self, origin: &str) -> Result<Vec<Finding>, RdnsError> {
+        let origin = LowerName::new(&DnsState::parse_name(origin)?);
+        let Some(authority) = self.zones.get(&origin) else {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+        };
+        let records = authority.records().await;
+        let hosted: Vec<LowerName> = self.zones.keys().cloned().collect();
+        Ok(zonecheck::check(&origin, &records, &hosted))
+    }
+
+    /// Gets the records of a name and type from the zone containing them.
+    pub async fn get_record(&self, name: String, record_type: String) -> Result<Vec<RecordEntry>, RdnsError> {
+        let key = DnsState::build_record_key(name, record_type)?;
+        let authority = self.find_zone(&key.name)?;
+        let records = authority.records().await;
+        let Some(set) = records.get(&key) else {
+            return Err(RdnsError::RecordNotFound(format!("no {} records exist for {}", key.record_type, key.name)));
+        };
+        Ok(set.records_without_rrsigs().map(|record| self.entry_of(record)).collect())
+    }
+
+    /// The entry of `record`, along with its metadata.
+    fn entry_of(&self, record: &Record) -> RecordEntry {
+        RecordEntry {
+            metadata: self.metadata.get(record),
+            ..RecordEntry::from(record)
+        }
+    }
+
+    /// The records of a name and type, or of every type for ANY, from the zone containing
+    /// them; none when no zone holds the name.
+    pub async fn lookup_records(&self, name: &LowerName, record_type: RecordType) -> Vec<Record> {
+        self.snapshots.current().lookup_records(name, record_type).await
+    }
+
+    /// Gets one page of the records matching `query`, sorted by name, type and value.
+    /// Returns the page, whether more matching records follow it and how many match.
+    pub async fn query_records(&self, query: &RecordQuery) -> Result<RecordPage, RdnsError> {
+        let record_type = match query.record_type.as_str() {
+            "" => None,
+            record_type => Some(DnsState::parse_record_type(record_type)?.to_string()),
+        };
+        let prefix = query.name_prefix.to_lowercase();
+        let suffix = query.name_suffix.to_lowercase();
+        // Unicode filters are matched against the Unicode form of the names
+        let unicode = !prefix.is_ascii() || !suffix.is_ascii();
+        let suffix = suffix.trim_end_matches('.');
+        let subdomain_suffix = format!(".{}", suffix);
+        // The suffix only matches whole labels, so `example.com` doesn't match `myexample.com`
+        let has_suffix = |name: &str| {
+            let name = name.trim_end_matches('.');
+            suffix.is_empty() || name == suffix || name.ends_with(&subdomain_suffix)
+        };
+
+        let mut matches: Vec<RecordEntry> = self
+            .tenant_records(query.tenant.as_deref())
+            .await
+            .into_iter()
+            .filter(|record| {
+                let name = match unicode {
+                    true => unicode_name(&record.name).to_lowercase(),
+                    false => record.name.to_ascii_lowercase(),
+                };
+                name.starts_with(&prefix)
+                    && has_suffix(&name)
+                    && record_type.as_ref().is_none_or(|record_type| &record.record_type == record_type)
+                    && record.metadata.matches(&query.owner, &query.tags)
+            })
+            .collect();
+        let total = matches.len();
+        matches.retain(|record| {
+            query.after.as_ref().is_none_or(|after| {
+                (&record.name, &record.record_type, &record.value) > (&after.0, &after.1, &after.2)
+            })
+        });
+        matches.sort_by(|a, b| (&a.name, &a.record_type, &a.value).cmp(&(&b.name, &b.record_type, &b.value)));
+
+        let more = matches.len() > query.limit;
+        matches.truncate(query.limit);
+        Ok(RecordPage {
+            records: matches,
+            more,
+            total,
+        })
+    }
+
+    /// Gets all records from every hosted zone (exludes RRSIGS)
+    pub async fn get_all_records(&self) -> Vec<RecordEntry> {
+        self.tenant_records(None).await
+    }
+
+    /// Gets all records from the zones owned by `tenant`, or from every hosted zone
+    /// without one.
+    pub async fn tenant_records(&self, tenant: Option<&str>) -> Vec<RecordEntry> {
+        let mut result = Vec::new();
+        for (origin, authority) in &self.zones {
+            if tenant.is_some_and(|tenant| self.zone_tenant(origin).as_deref() != Some(tenant)) {
+                continue;
+            }
+            let records = DnsState::zone_records(authority).await;
+            result.extend(records.iter().map(|record| self.entry_of(record)));
+        }
+
+        result
+    }
+
+    /// Gets every record of the zone at `origin`.
+    pub async fn get_zone_records(&self, origin: &str) -> Result<Vec<RecordEntry>, RdnsError> {
+        let origin = DnsState::parse_name(origin)?;
+        let Some(authority) = self.zones.get(&LowerName::new(&origin)) else {
+            return Err(RdnsError::ZoneNotFound(format!("zone {} does not exist", origin)));
+        };
+        Ok(DnsState::zone_records(authority).await.iter().map(|record| self.entry_of(record)).collect())
+    }
+
+    /// Gets all records from a single zone (exludes RRSIGS)
+    async fn zone_records(authority: &InMemoryAuthority) -> Vec<Record> {
+        let records = authority.records().await;
+
+        let mut result = Vec::new();
+        for (_key, record_set) in records.iter() {
+            result.extend(record_set.records_without_rrsigs().cloned());
+        }
+
+        result
+    }
+
+    /// The records of a zone as they are persisted, backed up and kept in its history:
+    /// without the DNSSEC records of signed zones, which are regenerated from the zone's keys.
+    async fn stored_records(&self, authority: &InMemoryAuthority) -> Vec<Record> {
+        let signed = self.is_signed(authority.origin());
+        DnsState::zone_records(authority)
+            .await
+            .into_iter()
+            .filter(|record| !(signed && dnssec::is_generated(record.record_type())))
+            .collect()
+    }
+
+    /// Keeps the current records of a primary zone as its newest version, if a history
+    /// is kept.
+    async fn record_version(&self, authority: &InMemoryAuthority) {
+        if let Some(history) = self.history.as_ref().filter(|_| !self.is_secondary(authority.origin())) {
+            history.record(authority.origin(), self.stored_records(authority).await);
+        }
+    }
+
+    /// Keeps the current records of a zone, signatures included, in the IXFR journal, if
+    /// zones are transferable.
+    async fn record_journal(&self, authority: &InMemoryAuthority) {
+        if let Some(journal) = &self.journal {
+            journal.record(authority.origin(), DnsState::transfer_records(authority).await);
+        }
+    }
+
+    /// The records of a zone as they are transferred, signatures included.
+    async fn transfer_records(authority: &InMemoryAuthority) -> Vec<Record> {
+        let records = authority.records().await;
+        records
+            .values()
+            .flat_map(|set| set.records_without_rrsigs().chain(set.rrsigs()))
+            .cloned()
+            .collect()
+    }
+
+    /// The answer to an IXFR request for the zone at `origin` from a secondary at `serial`,
+    /// taken from the journal of the zone, or `None` if it isn't hosted here.
+    pub async fn ixfr(&self, origin: &LowerName, serial: Option<u32>) -> Option<Vec<Record>> {
+        let authority = match self.catalog_zone.as_ref().filter(|zone| zone.origin() == origin) {
+            Some(zone) => zone.authority(),
+            None => self.zones.get(origin)?,
+        };
+        let records = DnsState::transfer_records(authority).await;
+        let soa = records.iter().find(|record| record.record_type() == RecordType::SOA && record.name() == &Name::from(origin))?.clone();
+        let current = authority.serial().await;
+        let changes = serial
+            .zip(self.journal.as_ref())
+            .and_then(|(serial, journal)| journal.changes_since(origin, serial, current));
+        Some(ixfr::answer(soa, records, serial, changes))
+    }
+
+    /// Keeps the new records of a changed primary zone as its newest version and notifies
+    /// replication subscribers of the change.
+    async fn zone_changed(&self, authority: &InMemoryAuthority) {
+        self.snapshots.refresh(authority).await;
+        self.count_records(authority).await;
+        self.record_version(authority).await;
+        self.record_journal(authority).await;
+        if !self.is_secondary(authority.origin()) {
+            self.publish_change(ChangeScope::Zone(authority.origin().clone()));
+        }
+    }
+
+    /// Notifies replication subscribers of a change to the runtime entries of `name`,
+    /// which are replicated along with the zone holding it.
+    fn name_changed(&self, name: &LowerName) {
+        match self.find_zone(name) {
+            Ok(authority) => self.publish_change(ChangeScope::Zone(authority.origin().clone())),
+            Err(_) => self.publish_change(ChangeScope::All),
+        }
+    }
+
+    /// Writes the current zones and records through to the store, if one is configured,
+    /// journaling the changes published since the last write.
+    async fn persist(&self) -> Result<(), RdnsError> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let entries = std::mem::take(&mut *self.unjournaled.lock().unwrap());
+        store.journal(&entries).await.map_err(RdnsError::StorageError)?;
+        store.persist(&self.snapshot().await).await.map_err(RdnsError::StorageError)
+    }
+
+    /// Takes a snapshot of the primary zones and their records, along with the pools,
+    /// health checks, geo records, record leases, scheduled changes, change requests,
+    /// service registrations, created TSIG keys and tenants, view overlays and runtime
+    /// blocklist entries, as they are persisted and backed up.
+    pub async fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for (origin, authority) in self.zones.iter().filter(|(origin, _)| !self.is_secondary(origin)) {
+            let records = self.stored_records(authority).await.iter().map(|record| self.entry_of(record)).collect();
+            snapshot.zones.push(ZoneSnapshot {
+                origin: Name::from(origin.clone()).to_ascii(),
+                records,
+                auto_reverse: self.auto_reverse.contains(origin),
+                default_ttl: self.default_ttls.get(origin).copied(),
+                tenant: self.zone_tenants.get(origin).cloned(),
+                frozen: self.is_frozen(origin),
+            });
+        }
+        snapshot.zones.sort_by(|a, b| a.origin.cmp(&b.origin));
+        snapshot.pools = self.list_pools();
+        snapshot.health_checks = self.health.entries();
+        snapshot.geo_records = self.list_geo_records();
+        snapshot.rollouts = self.list_rollouts();
+        snapshot.timed = self.list_timed_records().into_iter().map(|(entry, _)| entry).collect();
+        snapshot.patterns = self.list_patterns();
+        snapshot.overrides = self.list_overrides();
+        snapshot.aliases = self.list_aliases();
+        snapshot.leases = self.leases.entries();
+        snapshot.scheduled_changes = self.scheduled.entries();
+        snapshot.approvals = self.approvals.entries();
+        snapshot.services = self.list_services();
+        snapshot.tsig_keys = self.tsig_keys.created();
+        snapshot.tenants = self.tenants.created();
+        snapshot.forward_rules = self.forward_rules.created();
+        snapshot.rewrite_rules = self.rewrite_rules.created();
+        snapshot.query_rules = self.query_rules.created();
+        if let Some(blocklist) = &self.blocklist {
+            snapshot.blocklist = BlocklistSnapshot {
+                block: blocklist.entries(Action::Block),
+                allow: blocklist.entries(Action::Allow),
+            };
+        }
+        snapshot.views = self
+            .views
+            .iter()
+            .map(|view| ViewSnapshot {
+                name: view.name.clone(),
+                records: view.records().iter().map(RecordEntry::from).collect(),
+            })
+            .collect();
+        snapshot
+    }
+
+    /// Replaces the primary zones and the runtime state with those of `snapshot`, e.g. a
+    /// backup taken with `snapshot`, and persists them. Secondary zones are left alone.
+    /// If the snapshot fails to load, the previous state is put back.
+    ///
+    /// Configured zones missing from the snapshot are only created again on the next
+    /// reload or restart.
+    pub async fn restore(&mut self, snapshot: Snapshot) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        self.restore_snapshot(snapshot).await?;
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Restores `snapshot` like `restore`, without persisting it.
+    async fn restore_snapshot(&mut self, snapshot: Snapshot) -> Result<(), RdnsError> {
+        let previous = self.snapshot().await;
+        self.clear().await;
+        if let Err(e) = self.load(snapshot).await {
+            self.clear().await;
+            self.load(previous).await?;
+            return Err(e.context("can't restore snapshot"));
+        }
+        for authority in self.zones.values().filter(|authority| !self.is_secondary(authority.origin())) {
+            self.resign(authority).await?;
+            self.snapshots.refresh(authority).await;
+            self.record_journal(authority).await;
+            self.notify(authority);
+        }
+        Ok(())
+    }
+
+    /// The part of the state that `scope` covers, as a snapshot replicated to followers:
+    /// a zone along with the runtime entries of the names in it and its subzones, only the
+    /// blocklist entries, or everything. A deleted zone has an empty snapshot.
+    pub async fn replication_snapshot(&self, scope: &ChangeScope) -> Snapshot {
+        match scope {
+            ChangeScope::All => self.snapshot().await,
+            ChangeScope::ZoneDeleted(_) => Snapshot::default(),
+            ChangeScope::Blocklist => {
+                let mut snapshot = Snapshot::default();
+                if let Some(blocklist) = &self.blocklist {
+                    snapshot.blocklist = BlocklistSnapshot {
+                        block: blocklist.entries(Action::Block),
+                        allow: blocklist.entries(Action::Allow),
+                    };
+                }
+                snapshot
+            }
+            ChangeScope::Zone(origin) => {
+                let mut snapshot = self.snapshot().await;
+                let within = |name: &str| DnsState::parse_name(name).is_ok_and(|name| origin.zone_of(&LowerName::new(&name)));
+                snapshot.zones.retain(|zone| DnsState::parse_name(&zone.origin).is_ok_and(|name| LowerName::new(&name) == *origin));
+                snapshot.pools.retain(|entry| within(&entry.name));
+                snapshot.health_checks.retain(|entry| within(&entry.name));
+                snapshot.geo_records.retain(|entry| within(&entry.name));
+                snapshot.rollouts.retain(|entry| within(&entry.name));
+                snapshot.timed.retain(|entry| within(&entry.name));
+                snapshot.patterns.retain(|entry| within(entry.suffix()));
+                snapshot.aliases.retain(|entry| within(&entry.name));
+                snapshot.leases.retain(|entry| within(&entry.name));
+                snapshot.scheduled_changes.retain(|change| within(&change.name));
+                snapshot.services.retain(|entry| within(&entry.instance_name()));
+                for view in &mut snapshot.views {
+                    view.records.retain(|record| within(&record.name));
+                }
+                snapshot.blocklist = BlocklistSnapshot::default();
+                snapshot.tsig_keys.clear();
+                snapshot.tenants.clear();
+                snapshot.forward_rules.clear();
+                snapshot.rewrite_rules.clear();
+                snapshot.query_rules.clear();
+                snapshot.overrides.clear();
+                snapshot.approvals.clear();
+                snapshot
+            }
+        }
+    }
+
+    /// Applies a change replicated from the leader: replaces the part of the state that
+    /// `scope` covers with `snapshot`, as taken by `replication_snapshot`, and persists it.
+    /// Unlike the mutation methods this works on followers, and a zone is swapped in the
+    /// catalog in one go so that it is answered throughout.
+    pub async fn apply_replicated(&mut self, scope: &ChangeScope, snapshot: Snapshot) -> Result<(), RdnsError> {
+        self.replace_part(scope, snapshot).await?;
+        self.persist().await
+    }
+
+    /// Replaces the state with the snapshot in the store, as written there by another
+    /// instance sharing it, without writing it back, and notifies replication subscribers
+    /// of the change under the last sequence number journaled.
+    pub async fn reload_stored(&mut self) -> Result<(), RdnsError> {
+        let Some(store) = self.store.clone() else {
+            return Ok(());
+        };
+        let Some(snapshot) = store.load().await.map_err(RdnsError::StorageError)? else {
+            return Ok(());
+        };
+        self.replace_part(&ChangeScope::All, snapshot).await?;
+        let journaled = store.journaled().await.map_err(RdnsError::StorageError)?.last().map_or(0, |entry| entry.sequence);
+        let sequence = self.sequence.fetch_max(journaled, Ordering::Relaxed).max(journaled);
+        let _ = self.changes.send(StateChange {
+            sequence,
+            timestamp: SystemTime::now(),
+            scope: ChangeScope::All,
+        });
+        Ok(())
+    }
+
+    /// Replaces the part of the state that `scope` covers with `snapshot`, like
+    /// `apply_replicated`, without persisting it.
+    async fn replace_part(&mut self, scope: &ChangeScope, snapshot: Snapshot) -> Result<(), RdnsError> {
+        match scope {
+            ChangeScope::All => {
+                let origins: HashSet<LowerName> = snapshot
+                    .zones
+                    .iter()
+                    .map(|zone| DnsState::parse_name(&zone.origin).map(|name| LowerName::new(&name)))
+                    .collect::<Result<_, _>>()?;
+                let stale: Vec<LowerName> = self
+                    .zones
+                    .keys()
+                    .filter(|origin| !self.is_secondary(origin) && !origins.contains(*origin))
+                    .cloned()
+                    .collect();
+                for origin in stale {
+                    self.drop_replicated_zone(&origin, true).await;
+                }
+                for origin in &origins {
+                    self.drop_replicated_zone(origin, false).await;
+                }
+                self.pools.write().unwrap().clear();
+                self.health.clear();
+                self.geo_records.write().unwrap().clear();
+                self.rollouts.write().unwrap().clear();
+                self.timed.write().unwrap().clear();
+                self.patterns.write().unwrap().clear();
+                self.overrides.write().unwrap().clear();
+                self.aliases.write().unwrap().clear();
+                self.leases.clear();
+                self.scheduled.clear();
+                self.approvals.clear();
+                self.metadata.clear();
+                self.services.write().unwrap().clear();
+                self.tsig_keys.clear();
+                self.tenants.clear();
+                self.forward_rules.clear();
+                self.rewrite_rules.clear();
+        self.query_rules.clear();
+                for view in self.views.iter() {
+                    view.clear();
+                }
+                if let Some(blocklist) = &self.blocklist {
+                    blocklist.clear();
+                }
+            }
+            ChangeScope::Zone(origin) | ChangeScope::ZoneDeleted(origin) => {
+                let deleted = matches!(scope, ChangeScope::ZoneDeleted(_));
+                self.drop_replicated_zone(origin, deleted).await;
+                self.pools.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+                self.health.remove_zone(origin);
+                self.geo_records.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+                self.rollouts.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+                self.timed.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+                self.patterns.write().unwrap().retain(|pattern| !origin.zone_of(pattern.suffix()));
+                self.aliases.write().unwrap().retain(|name, _| !origin.zone_of(name));
+                self.leases.remove_zone(origin);
+                self.scheduled.remove_zone(origin);
+                self.metadata.remove_zone(origin);
+                self.services.write().unwrap().retain(|key, _| !origin.zone_of(key));
+                for view in self.views.iter() {
+                    view.remove_zone(origin);
+                }
+            }
+            ChangeScope::Blocklist => {
+                if let Some(blocklist) = &self.blocklist {
+                    blocklist.clear();
+                }
+            }
+        }
+        let origins: Vec<String> = snapshot.zones.iter().map(|zone| zone.origin.clone()).collect();
+        self.load(snapshot).await?;
+        for origin in origins {
+            if let Some(authority) = self.zones.get(&LowerName::new(&DnsState::parse_name(&origin)?)) {
+                self.resign(authority).await?;
+                self.record_journal(authority).await;
+                self.notify(authority);
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes a primary zone out of the state ahead of loading its replicated records, which
+    /// register it with the catalog again, or for good when it was deleted on the leader.
+    async fn drop_replicated_zone(&mut self, origin: &LowerName, deleted: bool) {
+        if self.is_secondary(origin) {
+            return;
+        }
+        self.zones.remove(origin);
+        self.forget_records(origin);
+        self.auto_reverse.remove(origin);
+        self.default_ttls.remove(origin);
+        self.zone_tenants.remove(origin);
+        self.frozen.remove(origin);
+        if deleted {
+            self.snapshots.remove([origin]).await;
+            if let Some(history) = &self.history {
+                history.remove_zone(origin);
+            }
+            if let Some(journal) = &self.journal {
+                journal.remove(origin);
+            }
+        }
+    }
+
+    /// Drops every primary zone along with the pools, health checks, geo records, rollouts,
+    /// values on schedules, name patterns, overrides, record leases, scheduled changes, change requests, service
+    /// registrations, created TSIG keys and tenants, view overlays and runtime blocklist
+    /// entries.
+    async fn clear(&mut self) {
+        let origins: Vec<LowerName> = self.zones.keys().filter(|origin| !self.is_secondary(origin)).cloned().collect();
+        for origin in &origins {
+            self.zones.remove(origin);
+            self.forget_records(origin);
+        }
+        self.snapshots.remove(&origins).await;
+        self.auto_reverse.clear();
+        self.default_ttls.clear();
+        self.zone_tenants.clear();
+        self.frozen.clear();
+        self.pools.write().unwrap().clear();
+        self.health.clear();
+        self.geo_records.write().unwrap().clear();
+        self.rollouts.write().unwrap().clear();
+        self.timed.write().unwrap().clear();
+        self.patterns.write().unwrap().clear();
+        self.overrides.write().unwrap().clear();
+        self.aliases.write().unwrap().clear();
+        self.leases.clear();
+        self.scheduled.clear();
+        self.approvals.clear();
+        self.metadata.clear();
+        self.services.write().unwrap().clear();
+        self.tsig_keys.clear();
+        self.tenants.clear();
+        self.forward_rules.clear();
+        self.rewrite_rules.clear();
+        self.query_rules.clear();
+        for view in self.views.iter() {
+            view.clear();
+        }
+        if let Some(blocklist) = &self.blocklist {
+            blocklist.clear();
+        }
+    }
+
+    /// Empties the cache of forwarded answers, or removes those for `name` alone if given,
+    /// returning the number of entries removed.
+    pub fn flush_cache(&self, name: Option<&str>) -> Result<usize, RdnsError> {
+        let Some(cache) = &self.cache else {
+            return Err(RdnsError::NotEnabled("forwarding is not enabled".into()));
+        };
+        let removed = match name {
+            Some(name) => cache.purge(&LowerName::new(&Self::parse_name(name)?)),
+            None => cache.flush(),
+        };
+        self.metrics.set_cache_negative_entries(cache.usage().negative);
+        Ok(removed)
+    }
+
+    /// Registers a forwarder built from `forward` for the root zone, replacing any previous
+    /// one and its cache, or stops forwarding when `forward` is unset. Forwarding rules
+    /// added through the control API are kept either way.
+    pub async fn set_forwarding(&mut self, forward: Option<&ForwardOptions>) -> Result<(), RdnsError> {
+        let root = LowerName::from(Name::root());
+        self.forward_rules.set_configured(forward)?;
+        match forward {
+            Some(forward) => {
+                let cache = Arc::new(ResponseCache::new(&forward.cache));
+                let authority = Arc::new(forward.authority(self.forward_rules.clone(), cache.clone(), self.metrics.clone()));
+                self.snapshots.upsert(root, Box::new(authority)).await;
+                self.cache = Some(cache);
+            }
+            None => {
+                self.snapshots.remove([&root]).await;
+                self.cache = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the secondaries zone changes are notified to and the size of the IXFR
+    /// journal. Whether zones can be transferred at all is fixed when they are created.
+    pub fn set_transfer_secondaries(&mut self, transfer: &TransferOptions) {
+        if let Some(current) = &mut self.transfer {
+            current.secondaries = transfer.secondaries.clone();
+            current.journal_size = transfer.journal_size;
+        }
+        if let Some(journal) = &mut self.journal {
+            journal.set_size(transfer.journal_size);
+        }
+    }
+
+    /// The TSIG keys zone transfers and dynamic updates may be signed with.
+    pub fn tsig_keys(&self) -> &TsigKeyring {
+        &self.tsig_keys
+    }
+
+    /// Replaces the TSIG keys of the config, keeping those created through the control API.
+    pub fn set_tsig_keys(&self, keys: Vec<TsigKey>) {
+        self.tsig_keys.set_configured(keys);
+    }
+
+    /// Generates a TSIG key named `name` for `algorithm`, `hmac-sha256` when empty, and
+    /// returns it along with its secret.
+    pub async fn create_tsig_key(&self, name: &str, algorithm: &str) -> Result<TsigKeyEntry, RdnsError> {
+        self.check_leader()?;
+        let key = TsigKey::generate(name, algorithm)?;
+        let entry = key.entry.clone();
+        self.tsig_keys.insert(key)?;
+        self.publish_change(ChangeScope::All);
+        self.persist().await?;
+        Ok(entry)
+    }
+
+    /// Removes a TSIG key created through the control API.
+    pub async fn delete_tsig_key(&self, name: &str) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        self.tsig_keys.remove(&LowerName::new(&DnsState::parse_name(name)?))?;
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Lists every TSIG key, without their secrets, sorted by name.
+    pub fn list_tsig_keys(&self) -> Vec<TsigKeyInfo> {
+        self.tsig_keys.list()
+    }
+
+    /// Adds a forwarding rule for the names under `domain`, matched after the rules added
+    /// before it and before those of the config.
+    pub async fn add_forward_rule(&self, entry: ForwardRuleEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let rule = ForwardRule::new(entry).map_err(|e| RdnsError::InvalidArgument(e.to_string()))?;
+        self.forward_rules.insert(rule)?;
+        self.rules_changed().await
+    }
+
+    /// Removes a forwarding rule added through the control API.
+    pub async fn delete_forward_rule(&self, domain: &str) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        self.forward_rules.remove(&LowerName::new(&DnsState::parse_name(domain)?))?;
+        self.rules_changed().await
+    }
+
+    /// Lists every forwarding rule, in the order they are matched.
+    pub fn list_forward_rules(&self) -> Vec<ForwardRuleInfo> {
+        self.forward_rules.list()
+    }
+
+    /// Replaces the rewrite rules of the config with `rules`.
+    pub fn set_rewrite_rules(&mut self, rules: Vec<RewriteRule>) {
+        self.rewrite_rules.set_configured(rules);
+    }
+
+    /// Adds a rewrite rule, tried after the rules added before it and before those of
+    /// the config.
+    pub async fn add_rewrite_rule(&self, entry: RewriteRuleEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        self.rewrite_rules.insert(RewriteRule::new(entry)?)?;
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Removes a rewrite rule added through the control API.
+    pub async fn delete_rewrite_rule(&self, from: &str) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        self.rewrite_rules.remove(from)?;
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Lists every rewrite rule, in the order they are tried.
+    pub fn list_rewrite_rules(&self) -> Vec<RewriteRuleInfo> {
+        self.rewrite_rules.list()
+    }
+
+    /// Replaces the query rules of the config with `rules`.
+    pub fn set_query_rules(&mut self, rules: Vec<QueryRule>) {
+        self.query_rules.set_configured(rules);
+    }
+
+    /// Adds a query rule, tried after the rules added before it and before those of the
+    /// config.
+    pub async fn add_query_rule(&self, entry: QueryRuleEntry) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        self.query_rules.insert(vec![QueryRule::new(entry)?])?;
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Removes a query rule added through the control API.
+    pub async fn delete_query_rule(&self, match_type: &str, pattern: &str) -> Result<(), RdnsError> {
+        self.check_leader()?;
+        let match_type: MatchType = match_type.parse()?;
+        self.query_rules.remove(match_type, pattern)?;
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Adds the rules of a rule file, after those added before them, or in their place
+    /// with `replace`. Returns how many were added; none are if one is invalid.
+    pub async fn import_query_rules(&self, content: &str, replace: bool) -> Result<usize, RdnsError> {
+        self.check_leader()?;
+        let rules = queryrules::parse_file(content, "import")?
+            .into_iter()
+            .map(QueryRule::new)
+            .collect::<Result<Vec<_>, _>>()?;
+        let count = rules.len();
+        match replace {
+            true => self.query_rules.replace(rules)?,
+            false => self.query_rules.insert(rules)?,
+        }
+        self.publish_change(ChangeScope::All);
+        self.persist().await?;
+        Ok(count)
+    }
+
+    /// Lists every query rule, in the order they are tried.
+    pub fn list_query_rules(&self) -> Vec<QueryRuleInfo> {
+        self.query_rules.list()
+    }
+
+    /// Drops the answers cached under the previous forwarding rules, then replicates and
+    /// persists the new ones.
+    async fn rules_changed(&self) -> Result<(), RdnsError> {
+        if let Some(cache) = &self.cache {
+            cache.flush();
+        }
+        self.publish_change(ChangeScope::All);
+        self.persist().await
+    }
+
+    /// Writes a final snapshot of every zone to the store, if there is one.
+    pub async fn flush(&self) -> Result<(), RdnsError> {
+        self.persist().await
+    }
+
+    /// Returns the shared metrics registry.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Returns a receiver of the snapshots of the catalog queries are answered from.
+    pub fn snapshots(&self) -> watch::Receiver<Arc<CatalogSnapshot>> {
+        self.snapshots.subscribe()
+    }
+}
+
+/// Creates a socket for `addr` with the options of `tuning`. IPv6 sockets only accept
+/// IPv6, so `[::]` can be bound alongside `0.0.0.0` on the same port rather than
+/// conflicting with it.
+fn new_socket(addr: SocketAddr, kind: Type, tuning: &SocketOptions) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    tuning.apply(&socket, addr)?;
+    Ok(socket)
+}
+
+/// Binds `count` UDP sockets on `addr`, sharing it through SO_REUSEPORT when more than one.
+/// With port 0, they share the port the first one is given.
+fn bind_udp(mut addr: SocketAddr, count: usize, tuning: &SocketOptions) -> std::io::Result<Vec<tokio::net::UdpSocket>> {
+    let mut sockets = Vec::new();
+    for _ in 0..count.max(1) {
+        let socket = new_socket(addr, Type::DGRAM, tuning)?;
+        if count > 1 {
+            set_reuse_port(&socket)?;
+        }
+        socket.bind(&addr.into())?;
+        let socket = tokio::net::UdpSocket::from_std(socket.into())?;
+        addr = socket.local_addr()?;
+        sockets.push(socket);
+    }
+    Ok(sockets)
+}
+
+#[cfg(unix)]
+fn set_reuse_port(socket: &Socket) -> std::io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(unix))]
+fn set_reuse_port(_socket: &Socket) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "several UDP sockets per address need SO_REUSEPORT, which only unix has"))
+}
+
+fn bind_tcp(addr: SocketAddr, tuning: &SocketOptions) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = new_socket(addr, Type::STREAM, tuning)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
+
+/// The sockets of the DNS server, bound before it is started, so that the addresses
+/// given with port 0 can be told before queries are sent to them.
+pub struct DnsListeners {
+    options: DnsOptions,
+    /// UDP sockets and TCP listener of each listen address, with the port bound
+    listeners: Vec<(SocketAddr, Vec<tokio::net::UdpSocket>, tokio::net::TcpListener)>,
+    tls: Option<tokio::net::TcpListener>,
+}
+
+impl DnsListeners {
+    /// Binds `udp_sockets` UDP sockets, sharing the address through SO_REUSEPORT, and a
+    /// TCP listener on each listen address, IPv4 or IPv6, unless systemd passed sockets
+    /// bound to it (see `activation`), and the DNS-over-TLS listener when TLS options are
+    /// configured. The TCP listener of an address with port 0 is bound to the port its
+    /// UDP sockets were given. Every socket gets the options of `socket`, those passed by
+    /// systemd included.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an address can't be bound.
+    pub async fn bind(options: DnsOptions) -> anyhow::Result<Self> {
+        let mut activated = ActivatedSockets::from_env()?;
+        let mut listeners = Vec::new();
+        for &addr in &options.listen_addrs {
+            let tuned = |socket: Socket| {
+                options.socket.apply(&socket, addr).map_err(|e| anyhow::anyhow!("failed to set the socket options of {}: {}", addr, e))?;
+                anyhow::Ok(socket)
+            };
+            let udp = match activated.take(addr, Type::DGRAM) {
+                Some(socket) => vec![tokio::net::UdpSocket::from_std(tuned(socket)?.into())?],
+                None => bind_udp(addr, options.udp_sockets, &options.socket).map_err(|e| anyhow::anyhow!("failed to bind {} (UDP): {}", addr, e))?,
+            };
+            let bound = udp[0].local_addr()?;
+            let tcp = match activated.take(addr, Type::STREAM) {
+                Some(socket) => tokio::net::TcpListener::from_std(tuned(socket)?.into())?,
+                None => bind_tcp(bound, &options.socket).map_err(|e| anyhow::anyhow!("failed to bind {} (TCP): {}", bound, e))?,
+            };
+            listeners.push((bound, udp, tcp));
+        }
+        activated.close_unused();
+        let tls = match &options.tls {
+            Some(tls) => {
+                let addr = tokio::net::lookup_host(&tls.listen_addr)
+                    .await?
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("{} has no address", tls.listen_addr))?;
+                Some(bind_tcp(addr, &options.socket).map_err(|e| anyhow::anyhow!("failed to bind {} (TLS): {}", addr, e))?)
+            }
+            None => None,
+        };
+        Ok(DnsListeners { options, listeners, tls })
+    }
+
+    /// The addresses UDP and TCP queries are answered on, in the order of the listen
+    /// addresses, with the ports that were bound for those given as 0.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners.iter().map(|(addr, _, _)| *addr).collect()
+    }
+
+    /// The address DNS-over-TLS queries are answered on, if TLS is configured.
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.tls.as_ref().and_then(|tls| tls.local_addr().ok())
+    }
+
+    /// The options the listeners were bound with, and the server will run with.
+    pub fn options(&self) -> &DnsOptions {
+        &self.options
+    }
+}
+
+/// Starts the DNS server on the configured UDP and TCP addresses using the provided `DnsState`.
+///
+/// Binds the listeners as `DnsListeners::bind` does and launches the `ServerFuture` from
+/// the hickory-server crate to handle requests on all of them. TCP lets clients retry
+/// truncated or oversized answers.
+///
+/// When TLS options are configured, a DNS-over-TLS listener is registered as well; when
+/// HTTPS options are configured, a DNS-over-HTTPS server sharing the same handler is spawned.
+/// Updates, transfers and dnstap output follow the latest `handler_options`. UDP
+/// responses are rate limited per client network when rate limiting is configured. `ready` is
+/// set once every listener is bound. When `shutdown` is requested the listeners stop
+/// accepting queries, and the function returns once the ones in flight are answered.
+///
+/// # Errors
+///
+/// Returns an error if the socket binding, conversion, or server execution fails.
+pub async fn run_dns_server(
+    state: Arc<RwLock<DnsState>>,
+    options: DnsOptions,
+    handler_options: watch::Receiver<Arc<HandlerOptions>>,
+    ready: watch::Sender<bool>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let listeners = DnsListeners::bind(options).await?;
+    serve_dns(listeners, state, handler_options, ready, shutdown).await
+}
+
+/// Runs the DNS server as `run_dns_server` does, on `listeners` bound beforehand.
+///
+/// # Errors
+///
+/// Returns an error if the TLS certificate can't be loaded or the server fails.
+pub async fn serve_dns(
+    listeners: DnsListeners,
+    state: Arc<RwLock<DnsState>>,
+    handler_options: watch::Receiver<Arc<HandlerOptions>>,
+    ready: watch::Sender<bool>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let DnsListeners { options, listeners, tls: tls_listener } = listeners;
+    let handler = SharedCatalog::new(state, handler_options, &options).await;
+    let doh = options.https.map(|https| {
+        let handler = handler.clone();
+        let certificates = options.certificates.clone();
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if let Err(e) = doh::run_doh_server(handler, https, certificates, shutdown).await {
+                eprintln!("DoH server failed: {}", e);
+            }
+        })
+    });
+
+    // With the PROXY protocol, TCP and DoT connections are accepted here rather than by
+    // the ServerFuture, to read their headers first
+    let mut server = ServerFuture::new(handler.clone());
+    let mut proxied = Vec::new();
+    for (addr, udp, tcp) in listeners {
+        let sockets = udp.len();
+        for udp in udp {
+            server.register_socket(udp);
+        }
+        match &options.proxy_protocol {
+            Some(proxy_protocol) => {
+                let listener = proxy::run_tcp_listener(handler.clone(), tcp, None, proxy_protocol.clone(), options.tcp_timeout, shutdown.clone());
+                proxied.push(tokio::spawn(listener));
+            }
+            None => server.register_listener(tcp, options.tcp_timeout),
+        }
+        match sockets {
+            1 => println!("DNS server listening on {} (UDP/TCP)", addr),
+            sockets => println!("DNS server listening on {} (UDP/TCP, {} UDP sockets)", addr, sockets),
+        }
+    }
+
+    if let (Some(tls), Some(tls_listener)) = (&options.tls, tls_listener) {
+        let tls_config = Arc::new(acmecert::server_config(options.certificates.as_ref(), &tls.cert_path, &tls.key_path)?);
+        let addr = tls_listener.local_addr()?;
+        match &options.proxy_protocol {
+            Some(proxy_protocol) => {
+                let acceptor = TlsAcceptor::from(tls_config);
+                let listener = proxy::run_tcp_listener(handler, tls_listener, Some(acceptor), proxy_protocol.clone(), options.tcp_timeout, shutdown.clone());
+                proxied.push(tokio::spawn(listener));
+            }
+            None => server.register_tls_listener_with_tls_config(tls_listener, options.tcp_timeout, tls_config)?,
+        }
+        println!("DNS server listening on {} (TLS)", addr);
+    }
+    if options.proxy_protocol.is_some() {
+        println!("Accepting PROXY protocol headers from trusted peers on TCP and TLS");
+    }
+    ready.send_replace(true);
+
+    let stopped = tokio::select! {
+        result = server.block_until_done() => Some(result),
+        _ = shutdown.requested() => None,
+    };
+    match stopped {
+        Some(result) => result?,
+        None => server.shutdown_gracefully().await?,
+    }
+    for listener in proxied {
+        if let Ok(Err(e)) = listener.await {
+            eprintln!("DNS TCP listener failed: {}", e);
+        }
+    }
+    if let Some(doh) = doh {
+        let _ = doh.await;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_proto::rr::rdata::A;
+    use hickory_server::authority::LookupOptions;
+    use std::net::Ipv4Addr;
+
+    /// The records `authority` answers an A query for `name` with.
+    async fn lookup_a(authority: &InMemoryAuthority, name: &str) -> Vec<Record> {
+        let name = LowerName::new(&Name::from_str(name).unwrap());
+        let lookup = authority.lookup(&name, RecordType::A, LookupOptions::default()).await.unwrap();
+        lookup.iter().cloned().collect()
+    }
+
+    #[test]
+    fn rejects_misplaced_wildcards() {
+        for (name, record_type) in [
+            ("a.*.example.com.", RecordType::A),
+            ("*foo.example.com.", RecordType::A),
+            ("*.example.com.", RecordType::SOA),
+            ("*.example.com.", RecordType::NS),
+            ("*.example.com.", RecordType::DS),
+        ] {
+            let name = Name::from_str(name).unwrap();
+            assert!(DnsState::validate_wildcard(&name, record_type).is_err(), "{} {} was accepted", name, record_type);
+        }
+        let name = Name::from_str("*.dev.example.com.").unwrap();
+        assert!(DnsState::validate_wildcard(&name, RecordType::A).is_ok());
+    }
+
+    #[tokio::test]
+    async fn explicit_records_take_precedence_over_wildcards() {
+        let authority = InMemoryAuthority::empty(Name::from_str("example.com.").unwrap(), ZoneType::Primary, false);
+        for (name, address) in [("*.dev.example.com.", [192, 0, 2, 1]), ("api.dev.example.com.", [192, 0, 2, 2])] {
+            let rdata = RData::A(A::from(Ipv4Addr::from(address)));
+            authority.upsert(Record::from_rdata(Name::from_str(name).unwrap(), 300, rdata), 0).await;
+        }
+
+        // A name without records of its own is answered from the wildcard, under its own name
+        let records = lookup_a(&authority, "web.dev.example.com.").await;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].name(), &Name::from_str("web.dev.example.com.").unwrap());
+        assert_eq!(records[0].data(), Some(&RData::A(A::new(192, 0, 2, 1))));
+
+        // The explicit sibling answers with its own record only
+        let records = lookup_a(&authority, "api.dev.example.com.").await;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].data(), Some(&RData::A(A::new(192, 0, 2, 2))));
+    }
 }
\ No newline at end of file
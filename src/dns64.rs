@@ -0,0 +1,113 @@
+//! DNS64 (RFC 6147).
+//!
+//! With `[dns.dns64]` configured, AAAA queries for names that have A records but no AAAA
+//! records of their own are answered with AAAA records synthesized from the A records,
+//! each IPv4 address embedded in the NAT64 `prefix` as RFC 6052 lays out, so that
+//! IPv6-only clients reach IPv4-only hosts through a NAT64 gateway. Names are looked up
+//! in the hosted zones or through the forwarder like those of any other query; names
+//! that don't exist stay NXDOMAIN, and AAAA records in the IPv4-mapped range
+//! `::ffff:0:0/96` count as none. Synthesized records keep the TTL of their A record, and
+//! the CNAMEs leading to it. With `clients` set, only clients in those networks, e.g. the
+//! IPv6-only ones behind the gateway, get synthesized answers. Queries with both DO and
+//! CD set don't either, as such clients validate answers themselves and synthesized
+//! records carry no signatures.
+
+use hickory_proto::rr::rdata::AAAA;
+use hickory_proto::rr::{DNSClass, LowerName, RData, Record, RecordType};
+use hickory_server::authority::{Catalog, LookupOptions};
+use hickory_server::server::Request;
+use ipnet::Ipv6Net;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::settings::Dns64Settings;
+use crate::views::ClientMatch;
+
+/// Config options for DNS64
+#[derive(Clone, Debug)]
+pub struct Dns64Options {
+    /// NAT64 prefix the IPv4 addresses are embedded in
+    pub prefix: Ipv6Net,
+    /// Clients answered with synthesized records; every client is when unset.
+    pub clients: Option<ClientMatch>,
+}
+
+impl TryFrom<Dns64Settings> for Dns64Options {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: Dns64Settings) -> anyhow::Result<Self> {
+        let prefix: Ipv6Net = cfg
+            .prefix
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid dns.dns64.prefix {}: {}", cfg.prefix, e))?;
+        if ![32, 40, 48, 56, 64, 96].contains(&prefix.prefix_len()) {
+            anyhow::bail!("dns.dns64.prefix {} must be 32, 40, 48, 56, 64 or 96 bits long", prefix);
+        }
+        let prefix = prefix.trunc();
+        // Bits 64 to 71 of the addresses are reserved (RFC 6052 section 2.2)
+        if prefix.network().octets()[8] != 0 {
+            anyhow::bail!("dns.dns64.prefix {} must have bits 64 to 71 set to zero", prefix);
+        }
+        let clients = match cfg.clients.is_empty() {
+            true => None,
+            false => Some(ClientMatch::parse(&cfg.clients)?),
+        };
+        Ok(Dns64Options { prefix, clients })
+    }
+}
+
+impl Dns64Options {
+    /// Whether `request` is a query that may be answered with synthesized records.
+    pub fn applies(&self, request: &Request) -> bool {
+        let query = request.query();
+        let validating = request.header().checking_disabled() && request.edns().is_some_and(|edns| edns.dnssec_ok());
+        query.query_type() == RecordType::AAAA
+            && query.query_class() == DNSClass::IN
+            && !validating
+            && self.clients.as_ref().is_none_or(|clients| clients.contains(request.src().ip()))
+    }
+
+    /// The synthesized answer to an AAAA query for `name`, or `None` when the name has
+    /// AAAA records of its own, doesn't exist or has no A records either.
+    pub async fn synthesize(&self, catalog: &Catalog, name: &LowerName) -> Option<Vec<Record>> {
+        let authority = catalog.find(name)?;
+        match authority.lookup(name, RecordType::AAAA, LookupOptions::default()).await {
+            Ok(lookup) if lookup.iter().any(|record| matches!(record.data(), Some(RData::AAAA(aaaa)) if aaaa.0.to_ipv4_mapped().is_none())) => {
+                return None
+            }
+            Err(e) if e.is_nx_domain() => return None,
+            // Other failures are treated like an empty answer (RFC 6147 section 5.1.2)
+            _ => {}
+        }
+
+        let mut lookup = authority.lookup(name, RecordType::A, LookupOptions::default()).await.ok()?;
+        let additionals = lookup.take_additionals();
+        let records: Vec<Record> = lookup
+            .iter()
+            .chain(additionals.iter().flat_map(|additionals| additionals.iter()))
+            .filter_map(|record| match record.data() {
+                Some(RData::A(a)) => Some(Record::from_rdata(record.name().clone(), record.ttl(), RData::AAAA(AAAA(self.embed(a.0))))),
+                Some(RData::CNAME(_)) => Some(record.clone()),
+                _ => None,
+            })
+            .collect();
+        records
+            .iter()
+            .any(|record| record.record_type() == RecordType::AAAA)
+            .then_some(records)
+    }
+
+    /// `address` embedded in the prefix, skipping bits 64 to 71 for prefixes shorter than
+    /// 96 bits.
+    fn embed(&self, address: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.prefix.network().octets();
+        let mut at = usize::from(self.prefix.prefix_len() / 8);
+        for octet in address.octets() {
+            if at == 8 {
+                at += 1;
+            }
+            octets[at] = octet;
+            at += 1;
+        }
+        Ipv6Addr::from(octets)
+    }
+}
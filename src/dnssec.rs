@@ -0,0 +1,381 @@
+//! DNSSEC signing for hosted zones.
+//!
+//! Each signed zone gets a key signing key (KSK) and a zone signing key (ZSK), both
+//! ECDSA P-256. Keys are stored as PKCS#8 files in the configured key directory and
+//! generated on first use, so a zone keeps its DS record across restarts. Signing
+//! itself (RRSIG, DNSKEY and NSEC generation) is performed by the zone's
+//! `InMemoryAuthority` once its signers are registered; NSEC3 chains and white lies are
+//! the `denial` module's.
+//!
+//! Keys are rolled over per zone. A ZSK is rolled over by pre-publishing (RFC 6781
+//! section 4.1.1.1): the new key's DNSKEY is published for `zsk_prepublish_hours` before
+//! it signs, and the old key's DNSKEY stays published for `zsk_retire_hours` after it
+//! stopped signing, so that resolvers never hold signatures by a key they don't have.
+//! ZSKs are rolled over every `zsk_rollover_days` when that is set, and on request. A KSK
+//! is rolled over on request only, as its DS has to be published by the parent zone: the
+//! new KSK signs along with the old one, the operator publishes its DS at the registrar
+//! and confirms it, and only then is the old KSK retired. A KSK rollover left unconfirmed
+//! for `ksk_confirm_timeout_days` is rolled back, dropping the new key. Where a zone's
+//! rollovers stand is kept next to its keys, in `<origin>rollover.json`, and the key
+//! being rolled in in `<origin>zsk.next.pk8` or `<origin>ksk.next.pk8`. Only the leader of
+//! a cluster rolls keys over.
+//!
+//! Signatures are regenerated whenever a signed zone changes, and a zone left unchanged
+//! is re-signed once half of `signature_validity_days` has passed since it was last
+//! signed, with its serial bumped so that secondaries transfer the new signatures. When
+//! a zone was last signed is kept with its rollovers, so the schedule holds across
+//! restarts.
+
+use hickory_proto::rr::dnssec::rdata::DS;
+use hickory_proto::rr::dnssec::{Algorithm, DigestType, KeyFormat, KeyPair, Private, SigSigner};
+use hickory_proto::rr::{LowerName, Name, RecordType};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+
+use crate::denial::{self, Denial};
+use crate::dns::DnsState;
+use crate::settings::DnssecSettings;
+
+pub const ALGORITHM: Algorithm = Algorithm::ECDSAP256SHA256;
+
+/// How often due rollover steps and signature refreshes are looked for.
+const ROLLOVER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Config options for DNSSEC signing
+#[derive(Clone)]
+pub struct DnssecOptions {
+    /// Directory holding the zones' PKCS#8 key files.
+    pub key_dir: PathBuf,
+    /// Zones that are signed; all other zones are served unsigned.
+    pub zones: HashSet<LowerName>,
+    /// How long generated signatures remain valid.
+    pub signature_validity: Duration,
+    /// How long a ZSK signs before it is rolled over; only on request when unset.
+    pub zsk_rollover: Option<Duration>,
+    /// How long a new ZSK is published before it signs.
+    pub zsk_prepublish: Duration,
+    /// How long an old ZSK stays published after it stopped signing.
+    pub zsk_retire: Duration,
+    /// How long a KSK rollover waits for its DS to be confirmed before it is rolled back.
+    pub ksk_confirm_timeout: Duration,
+    /// How signed zones deny names and types, unless listed in `zone_denial`.
+    pub denial: Denial,
+    pub zone_denial: HashMap<LowerName, Denial>,
+}
+
+impl DnssecOptions {
+    /// How the signed zone at `origin` denies names and types.
+    pub fn denial_of(&self, origin: &LowerName) -> &Denial {
+        self.zone_denial.get(origin).unwrap_or(&self.denial)
+    }
+}
+
+impl TryFrom<DnssecSettings> for DnssecOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: DnssecSettings) -> anyhow::Result<Self> {
+        let zones = cfg
+            .zones
+            .iter()
+            .map(|zone| {
+                let mut name = Name::from_ascii(zone)?;
+                name.set_fqdn(true);
+                Ok(LowerName::new(&name))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let (denial, zone_denial) = denial::parse(&cfg)?;
+        Ok(DnssecOptions {
+            key_dir: PathBuf::from(cfg.key_dir),
+            zones,
+            signature_validity: Duration::from_secs(cfg.signature_validity_days * 24 * 60 * 60),
+            zsk_rollover: (cfg.zsk_rollover_days > 0).then(|| Duration::from_secs(cfg.zsk_rollover_days * 24 * 60 * 60)),
+            zsk_prepublish: Duration::from_secs(cfg.zsk_prepublish_hours * 60 * 60),
+            zsk_retire: Duration::from_secs(cfg.zsk_retire_hours * 60 * 60),
+            ksk_confirm_timeout: Duration::from_secs(cfg.ksk_confirm_timeout_days * 24 * 60 * 60),
+            denial,
+            zone_denial,
+        })
+    }
+}
+
+/// The role a key plays in a zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRole {
+    KeySigning,
+    ZoneSigning,
+}
+
+impl KeyRole {
+    fn file_suffix(self) -> &'static str {
+        match self {
+            KeyRole::KeySigning => "ksk",
+            KeyRole::ZoneSigning => "zsk",
+        }
+    }
+}
+
+impl fmt::Display for KeyRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyRole::KeySigning => "KSK",
+            KeyRole::ZoneSigning => "ZSK",
+        })
+    }
+}
+
+/// Whether records of this type are generated by signing rather than managed by operators.
+pub fn is_generated(record_type: RecordType) -> bool {
+    matches!(
+        record_type,
+        RecordType::DNSKEY | RecordType::NSEC | RecordType::NSEC3 | RecordType::NSEC3PARAM | RecordType::RRSIG
+    )
+}
+
+/// Path of the key file for `origin` and `role`, e.g. `keys/example.com.ksk.pk8`.
+fn key_path(key_dir: &Path, origin: &Name, role: KeyRole) -> PathBuf {
+    key_dir.join(format!("{}{}.pk8", origin.to_ascii(), role.file_suffix()))
+}
+
+/// Path of the key replacing the zone's key for `role` during a rollover, e.g.
+/// `keys/example.com.zsk.next.pk8`.
+fn next_key_path(key_dir: &Path, origin: &Name, role: KeyRole) -> PathBuf {
+    key_dir.join(format!("{}{}.next.pk8", origin.to_ascii(), role.file_suffix()))
+}
+
+/// Loads the zone's key for `role`, generating and storing a new one if none exists.
+pub async fn load_or_generate_key(key_dir: &Path, origin: &Name, role: KeyRole) -> anyhow::Result<KeyPair<Private>> {
+    load_or_generate(key_dir, &key_path(key_dir, origin, role)).await
+}
+
+/// Loads the key replacing the zone's key for `role`, generating and storing a new one
+/// if none exists.
+pub async fn load_or_generate_next_key(key_dir: &Path, origin: &Name, role: KeyRole) -> anyhow::Result<KeyPair<Private>> {
+    load_or_generate(key_dir, &next_key_path(key_dir, origin, role)).await
+}
+
+/// Makes the key replacing the zone's key for `role` its key, dropping the old one.
+pub async fn promote_next_key(key_dir: &Path, origin: &Name, role: KeyRole) -> anyhow::Result<()> {
+    tokio::fs::rename(next_key_path(key_dir, origin, role), key_path(key_dir, origin, role)).await?;
+    Ok(())
+}
+
+/// Drops the key replacing the zone's key for `role`, if there is one.
+pub async fn discard_next_key(key_dir: &Path, origin: &Name, role: KeyRole) -> anyhow::Result<()> {
+    match tokio::fs::remove_file(next_key_path(key_dir, origin, role)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+async fn load_or_generate(key_dir: &Path, path: &Path) -> anyhow::Result<KeyPair<Private>> {
+    let pkcs8 = match tokio::fs::read(path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let bytes = KeyPair::<Private>::generate_pkcs8(ALGORITHM)?;
+            tokio::fs::create_dir_all(key_dir).await?;
+            tokio::fs::write(path, &bytes).await?;
+            println!("Generated DNSSEC key {}", path.display());
+            bytes
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(KeyFormat::Pkcs8.decode_key(&pkcs8, None, ALGORITHM)?)
+}
+
+/// Builds a signer for `origin` from a key pair.
+pub fn signer(origin: &Name, key: KeyPair<Private>, signature_validity: Duration) -> anyhow::Result<SigSigner> {
+    let dnskey = key.to_dnskey(ALGORITHM)?;
+    Ok(SigSigner::dnssec(dnskey, key, origin.clone(), signature_validity))
+}
+
+/// Computes the SHA-256 DS record the parent zone should publish for a key signing key.
+pub fn ds(origin: &Name, ksk: &KeyPair<Private>) -> anyhow::Result<DS> {
+    Ok(ksk.to_ds(origin, ALGORITHM, DigestType::SHA256)?)
+}
+
+/// Where the rollover of one of a zone's keys stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RolloverPhase {
+    /// The new ZSK's DNSKEY is published while the old ZSK still signs.
+    Prepublished,
+    /// The new ZSK signs while the old one's DNSKEY is still published.
+    Retiring,
+    /// The new KSK signs along with the old one until its DS is confirmed.
+    AwaitingDs,
+}
+
+impl fmt::Display for RolloverPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RolloverPhase::Prepublished => "prepublished",
+            RolloverPhase::Retiring => "retiring",
+            RolloverPhase::AwaitingDs => "awaiting-ds",
+        })
+    }
+}
+
+/// A rollover of one of a zone's keys.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Rollover {
+    pub phase: RolloverPhase,
+    /// When the phase began
+    pub since: SystemTime,
+}
+
+/// The rollovers of a zone's keys, as persisted next to them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Rollovers {
+    pub zsk: Option<Rollover>,
+    pub ksk: Option<Rollover>,
+    /// When the current ZSK started signing, which scheduled rollovers count from
+    pub zsk_signing_since: Option<SystemTime>,
+    /// When the zone was last signed as a whole, which signature refreshes count from
+    #[serde(default)]
+    pub signed_at: Option<SystemTime>,
+}
+
+impl Rollovers {
+    /// The rollover of the key for `role`, if one is under way.
+    pub fn of(&self, role: KeyRole) -> Option<Rollover> {
+        match role {
+            KeyRole::KeySigning => self.ksk,
+            KeyRole::ZoneSigning => self.zsk,
+        }
+    }
+
+    /// Puts the rollover of the key for `role` in `phase` as of `now`, or ends it.
+    pub fn set(&mut self, role: KeyRole, phase: Option<RolloverPhase>, now: SystemTime) {
+        let rollover = phase.map(|phase| Rollover { phase, since: now });
+        match role {
+            KeyRole::KeySigning => self.ksk = rollover,
+            KeyRole::ZoneSigning => self.zsk = rollover,
+        }
+    }
+}
+
+/// A step of a rollover that is taken once its time has come.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RolloverStep {
+    /// Start a scheduled ZSK rollover by publishing the new key.
+    StartZsk,
+    /// Sign with the new ZSK instead of the old one.
+    ActivateZsk,
+    /// Drop the old ZSK's DNSKEY.
+    RetireZsk,
+    /// Drop the new KSK, whose DS wasn't confirmed in time.
+    RollBackKsk,
+}
+
+impl fmt::Display for RolloverStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RolloverStep::StartZsk => "start-zsk",
+            RolloverStep::ActivateZsk => "activate-zsk",
+            RolloverStep::RetireZsk => "retire-zsk",
+            RolloverStep::RollBackKsk => "roll-back-ksk",
+        })
+    }
+}
+
+/// The step the rollovers of a zone take next, and when it is due, if one is scheduled.
+pub fn next_step(rollovers: &Rollovers, options: &DnssecOptions) -> Option<(RolloverStep, SystemTime)> {
+    let ksk = rollovers.ksk.map(|rollover| (RolloverStep::RollBackKsk, rollover.since + options.ksk_confirm_timeout));
+    let zsk = match rollovers.zsk {
+        Some(Rollover { phase: RolloverPhase::Prepublished, since }) => Some((RolloverStep::ActivateZsk, since + options.zsk_prepublish)),
+        Some(Rollover { phase: RolloverPhase::Retiring, since }) => Some((RolloverStep::RetireZsk, since + options.zsk_retire)),
+        Some(Rollover { phase: RolloverPhase::AwaitingDs, .. }) => None,
+        None => options
+            .zsk_rollover
+            .zip(rollovers.zsk_signing_since)
+            .map(|(every, since)| (RolloverStep::StartZsk, since + every)),
+    };
+    [ksk, zsk].into_iter().flatten().min_by_key(|(_, at)| *at)
+}
+
+/// Path of the rollover state of `origin`, e.g. `keys/example.com.rollover.json`.
+fn rollovers_path(key_dir: &Path, origin: &Name) -> PathBuf {
+    key_dir.join(format!("{}rollover.json", origin.to_ascii()))
+}
+
+/// Loads where the rollovers of the zone's keys stand, none when nothing was stored.
+pub async fn load_rollovers(key_dir: &Path, origin: &Name) -> anyhow::Result<Rollovers> {
+    let path = rollovers_path(key_dir, origin);
+    match tokio::fs::read(&path).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| anyhow::anyhow!("invalid {}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Rollovers::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Stores where the rollovers of the zone's keys stand.
+pub async fn save_rollovers(key_dir: &Path, origin: &Name, rollovers: &Rollovers) -> anyhow::Result<()> {
+    let path = rollovers_path(key_dir, origin);
+    let tmp = path.with_extension("json.tmp");
+    tokio::fs::create_dir_all(key_dir).await?;
+    tokio::fs::write(&tmp, serde_json::to_vec_pretty(rollovers)?).await?;
+    tokio::fs::rename(&tmp, &path).await?;
+    Ok(())
+}
+
+/// The keys a zone is served with.
+pub struct ZoneKeys {
+    /// Keys that sign the zone, and whose DNSKEYs are published.
+    pub signing: Vec<KeyPair<Private>>,
+    /// Keys whose DNSKEYs are published without signing, being rolled in or out.
+    pub published: Vec<KeyPair<Private>>,
+}
+
+/// Loads the keys the zone is served with where its rollovers stand, generating the
+/// zone's keys if it has none.
+pub async fn zone_keys(key_dir: &Path, origin: &Name, rollovers: &Rollovers) -> anyhow::Result<ZoneKeys> {
+    let mut keys = ZoneKeys {
+        signing: vec![load_or_generate_key(key_dir, origin, KeyRole::KeySigning).await?],
+        published: Vec::new(),
+    };
+    if rollovers.ksk.is_some() {
+        keys.signing.push(load_or_generate_next_key(key_dir, origin, KeyRole::KeySigning).await?);
+    }
+    let zsk = load_or_generate_key(key_dir, origin, KeyRole::ZoneSigning);
+    let next_zsk = load_or_generate_next_key(key_dir, origin, KeyRole::ZoneSigning);
+    match rollovers.zsk.map(|rollover| rollover.phase) {
+        Some(RolloverPhase::Prepublished) => {
+            keys.signing.push(zsk.await?);
+            keys.published.push(next_zsk.await?);
+        }
+        Some(RolloverPhase::Retiring) => {
+            keys.signing.push(next_zsk.await?);
+            keys.published.push(zsk.await?);
+        }
+        _ => keys.signing.push(zsk.await?),
+    }
+    Ok(keys)
+}
+
+/// Takes the rollover steps that are due and re-signs the zones whose signatures are
+/// due to be refreshed, until the state is dropped.
+pub async fn run_rollovers(state: Arc<RwLock<DnsState>>) {
+    let mut interval = tokio::time::interval(ROLLOVER_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now();
+        let (rollovers_due, signatures_due) = {
+            let state = state.read().await;
+            match state.is_follower() {
+                true => (false, false),
+                false => (state.has_due_rollovers(now).await, state.has_due_signatures(now).await),
+            }
+        };
+        if rollovers_due {
+            state.write().await.advance_rollovers(now).await;
+        }
+        if signatures_due {
+            state.write().await.refresh_signatures(now).await;
+        }
+    }
+}
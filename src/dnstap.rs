@@ -0,0 +1,305 @@
+//! dnstap output.
+//!
+//! Every request produces a query frame and every response a response frame, encoded as
+//! dnstap protobuf messages and written to a collector over a unix socket or TCP using
+//! the bidirectional Frame Streams protocol. Frames are queued and written by a
+//! background task that reconnects on failure; while the collector is unavailable or
+//! slow, frames are dropped rather than delaying answers. With `[dns.privacy]` set,
+//! client addresses are anonymized and ports left out.
+
+use hickory_proto::op::OpCode;
+use hickory_proto::rr::Record;
+use hickory_proto::serialize::binary::{BinDecodable, BinEncodable, BinEncoder};
+use hickory_server::authority::{MessageRequest, MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{Protocol, Request, ResponseHandler, ResponseInfo};
+use prost::Message as _;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tonic::async_trait;
+
+use crate::privacy::{self, PrivacyOptions};
+use crate::settings::DnstapSettings;
+
+mod pb {
+    tonic::include_proto!("dnstap");
+}
+
+/// Frame Streams content type of dnstap payloads.
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frame types and fields
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+/// Frames buffered while the collector is slow or reconnecting.
+const QUEUE_SIZE: usize = 4096;
+
+/// How long to wait before reconnecting to the collector.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Where dnstap frames are written.
+pub enum DnstapTarget {
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+/// Config options for dnstap output
+pub struct DnstapOptions {
+    pub target: DnstapTarget,
+    /// Identity of this server in emitted frames, e.g. its hostname.
+    pub identity: Option<String>,
+}
+
+impl TryFrom<DnstapSettings> for DnstapOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: DnstapSettings) -> anyhow::Result<Self> {
+        let target = match cfg.target.split_once(':') {
+            #[cfg(unix)]
+            Some(("unix", path)) => DnstapTarget::Unix(PathBuf::from(path)),
+            #[cfg(not(unix))]
+            Some(("unix", _)) => anyhow::bail!("dnstap target {} is a unix socket, which is only supported on unix", cfg.target),
+            Some(("tcp", addr)) => DnstapTarget::Tcp(addr.to_string()),
+            _ => anyhow::bail!("dnstap target must be unix:<path> or tcp:<host:port>, got {}", cfg.target),
+        };
+        Ok(DnstapOptions {
+            target,
+            identity: cfg.identity,
+        })
+    }
+}
+
+/// Handle for emitting dnstap frames, shared by all request handlers.
+#[derive(Clone)]
+pub struct Dnstap {
+    frames: mpsc::Sender<Vec<u8>>,
+    identity: Option<Vec<u8>>,
+    privacy: Option<PrivacyOptions>,
+}
+
+impl Dnstap {
+    /// Starts the background writer for `options.target`, anonymizing client addresses
+    /// as `privacy` says.
+    pub fn spawn(options: DnstapOptions, privacy: Option<PrivacyOptions>) -> Self {
+        let (frames, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(run_writer(options.target, receiver));
+        Self {
+            frames,
+            identity: options.identity.map(String::into_bytes),
+            privacy,
+        }
+    }
+
+    /// Emits a query frame for `request`, received at `query_time`.
+    pub fn log_query(&self, request: &Request, query_time: SystemTime) {
+        let kind = match request.op_code() {
+            OpCode::Update => pb::message::Type::UpdateQuery,
+            _ => pb::message::Type::AuthQuery,
+        };
+        let mut message = self.message(kind, request.src(), request.protocol(), query_time);
+        message.query_message = request.to_bytes().ok();
+        self.emit(message);
+    }
+
+    /// Emits a response frame for the wire-format `response` to a request from `src`.
+    fn log_response(&self, src: SocketAddr, protocol: Protocol, op_code: OpCode, query_time: SystemTime, response: Vec<u8>) {
+        let kind = match op_code {
+            OpCode::Update => pb::message::Type::UpdateResponse,
+            _ => pb::message::Type::AuthResponse,
+        };
+        let mut message = self.message(kind, src, protocol, query_time);
+        let (secs, nanos) = timestamp(SystemTime::now());
+        message.response_time_sec = Some(secs);
+        message.response_time_nsec = Some(nanos);
+        message.response_message = Some(response);
+        self.emit(message);
+    }
+
+    fn message(&self, kind: pb::message::Type, src: SocketAddr, protocol: Protocol, query_time: SystemTime) -> pb::Message {
+        let (family, address) = match privacy::anonymize(self.privacy.as_ref(), src.ip()) {
+            IpAddr::V4(ip) => (pb::SocketFamily::Inet, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (pb::SocketFamily::Inet6, ip.octets().to_vec()),
+        };
+        let protocol = match protocol {
+            Protocol::Tcp => pb::SocketProtocol::Tcp,
+            Protocol::Tls => pb::SocketProtocol::Dot,
+            Protocol::Https => pb::SocketProtocol::Doh,
+            Protocol::Quic => pb::SocketProtocol::Doq,
+            _ => pb::SocketProtocol::Udp,
+        };
+        let (secs, nanos) = timestamp(query_time);
+        pb::Message {
+            r#type: kind as i32,
+            socket_family: Some(family as i32),
+            socket_protocol: Some(protocol as i32),
+            query_address: Some(address),
+            query_port: self
+                .privacy
+                .as_ref()
+                .is_none_or(PrivacyOptions::keeps_addresses)
+                .then_some(src.port() as u32),
+            query_time_sec: Some(secs),
+            query_time_nsec: Some(nanos),
+            ..Default::default()
+        }
+    }
+
+    /// Queues a frame, dropping it if the writer has fallen behind.
+    fn emit(&self, message: pb::Message) {
+        let dnstap = pb::Dnstap {
+            identity: self.identity.clone(),
+            version: Some(format!("rdns {}", env!("CARGO_PKG_VERSION")).into_bytes()),
+            extra: None,
+            r#type: pb::dnstap::Type::Message as i32,
+            message: Some(message),
+        };
+        let _ = self.frames.try_send(dnstap.encode_to_vec());
+    }
+}
+
+fn timestamp(time: SystemTime) -> (u64, u32) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_secs(), since_epoch.subsec_nanos())
+}
+
+/// Response handler that emits a response frame for every response it sends.
+#[derive(Clone)]
+pub struct TapResponseHandler<R> {
+    inner: R,
+    dnstap: Dnstap,
+    src: SocketAddr,
+    protocol: Protocol,
+    op_code: OpCode,
+    query_time: SystemTime,
+}
+
+impl<R> TapResponseHandler<R> {
+    pub fn new(inner: R, dnstap: Dnstap, request: &Request, query_time: SystemTime) -> Self {
+        Self {
+            inner,
+            dnstap,
+            src: request.src(),
+            protocol: request.protocol(),
+            op_code: request.op_code(),
+            query_time,
+        }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for TapResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        // Emitting consumes the response, so it is re-read from the encoded bytes to be sent on
+        let mut buffer = Vec::with_capacity(512);
+        response
+            .destructive_emit(&mut BinEncoder::new(&mut buffer))
+            .map_err(std::io::Error::other)?;
+        let message = MessageRequest::from_bytes(&buffer).map_err(std::io::Error::other)?;
+        self.dnstap
+            .log_response(self.src, self.protocol, self.op_code, self.query_time, buffer);
+
+        let mut builder = MessageResponseBuilder::from_message_request(&message);
+        if let Some(edns) = message.edns() {
+            builder.edns(edns.clone());
+        }
+        let response = builder.build(
+            *message.header(),
+            message.answers(),
+            message.name_servers(),
+            [],
+            message.additionals().iter().chain(message.sig0()),
+        );
+        self.inner.send_response(response).await
+    }
+}
+
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+async fn connect(target: &DnstapTarget) -> std::io::Result<Box<dyn Transport>> {
+    Ok(match target {
+        #[cfg(unix)]
+        DnstapTarget::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        DnstapTarget::Tcp(addr) => Box::new(tokio::net::TcpStream::connect(addr).await?),
+    })
+}
+
+/// Writes queued frames to the collector, reconnecting whenever the connection fails.
+async fn run_writer(target: DnstapTarget, mut frames: mpsc::Receiver<Vec<u8>>) {
+    loop {
+        let result = match connect(&target).await {
+            Ok(stream) => write_frames(stream, &mut frames).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(()) => return,
+            Err(e) => eprintln!("dnstap output failed: {}", e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Performs the Frame Streams handshake, then writes frames until the queue closes.
+async fn write_frames(mut stream: Box<dyn Transport>, frames: &mut mpsc::Receiver<Vec<u8>>) -> std::io::Result<()> {
+    stream.write_all(&control_frame(CONTROL_READY)).await?;
+    if read_control_frame(&mut stream).await? != CONTROL_ACCEPT {
+        return Err(std::io::Error::other("collector did not accept the dnstap content type"));
+    }
+    stream.write_all(&control_frame(CONTROL_START)).await?;
+
+    while let Some(frame) = frames.recv().await {
+        stream.write_all(&(frame.len() as u32).to_be_bytes()).await?;
+        stream.write_all(&frame).await?;
+        stream.flush().await?;
+    }
+    stream.write_all(&control_frame(CONTROL_STOP)).await?;
+    stream.flush().await
+}
+
+/// Encodes a control frame of `kind` carrying the dnstap content type.
+fn control_frame(kind: u32) -> Vec<u8> {
+    let mut body = kind.to_be_bytes().to_vec();
+    if kind != CONTROL_STOP {
+        body.extend(FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend(CONTENT_TYPE);
+    }
+    // A zero-length "data frame" escapes the control frame that follows
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend((body.len() as u32).to_be_bytes());
+    frame.extend(body);
+    frame
+}
+
+/// Reads a control frame, returning its type.
+async fn read_control_frame(stream: &mut Box<dyn Transport>) -> std::io::Result<u32> {
+    if stream.read_u32().await? != 0 {
+        return Err(std::io::Error::other("expected a control frame"));
+    }
+    let len = stream.read_u32().await? as usize;
+    if !(4..=512).contains(&len) {
+        return Err(std::io::Error::other("invalid control frame length"));
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await?;
+    Ok(u32::from_be_bytes([body[0], body[1], body[2], body[3]]))
+}
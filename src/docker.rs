@@ -0,0 +1,217 @@
+//! Records for running Docker containers.
+//!
+//! With `[dns.docker]` configured, the Docker daemon is watched over its unix socket, or
+//! its named pipe on Windows, and
+//! every running container gets A and AAAA records for the addresses it has on its
+//! networks: `<container name>.<zone>`, or the names of its `rdns.name` label (separated
+//! by commas, relative to the zone or fully qualified within it) when it has one. The
+//! running containers are listed again whenever Docker reports one starting, stopping or
+//! being renamed, and the records of the zone brought in line with them, so records of
+//! stopped containers go away.
+//!
+//! The zone belongs to the integration: its A and AAAA records are replaced by those of
+//! the containers, while records of other types are left alone.
+
+use hyper::body::HttpBody;
+use hyper::{header, Body, StatusCode};
+use hickory_proto::rr::{LowerName, Name, RecordType};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::ClientOptions;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::RwLock;
+
+use crate::dns::{DnsState, RecordEntry};
+use crate::settings::DockerSettings;
+
+/// Delay before the socket is connected to again after a failure.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Label naming the records of a container.
+const NAME_LABEL: &str = "rdns.name";
+/// Container events after which the running containers are listed again.
+const SYNC_ACTIONS: [&str; 5] = ["start", "die", "stop", "rename", "destroy"];
+
+/// Config options for Docker container records
+#[derive(Clone)]
+pub struct DockerOptions {
+    pub socket: PathBuf,
+    pub zone: Name,
+    pub ttl: u32,
+}
+
+impl From<DockerSettings> for DockerOptions {
+    fn from(cfg: DockerSettings) -> Self {
+        let mut zone = Name::from_ascii(&cfg.zone).unwrap_or_else(|_| Name::root());
+        zone.set_fqdn(true);
+        DockerOptions {
+            socket: PathBuf::from(cfg.socket),
+            zone,
+            ttl: cfg.ttl,
+        }
+    }
+}
+
+/// A container as `GET /containers/json` lists it.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Container {
+    #[serde(default)]
+    names: Vec<String>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    network_settings: Option<NetworkSettings>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkSettings {
+    #[serde(default)]
+    networks: HashMap<String, Network>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Network {
+    #[serde(rename = "IPAddress", default)]
+    ip_address: String,
+    #[serde(rename = "GlobalIPv6Address", default)]
+    global_ipv6_address: String,
+}
+
+/// An event of `GET /events`, of which only the action is of interest.
+#[derive(Deserialize)]
+struct Event {
+    #[serde(rename = "Action", default)]
+    action: String,
+}
+
+impl Container {
+    /// The names the container's records get within `zone`.
+    fn hostnames(&self, zone: &Name) -> Vec<Name> {
+        let names: Vec<String> = match self.labels.get(NAME_LABEL) {
+            Some(label) => label.split(',').map(|name| name.trim().to_string()).filter(|name| !name.is_empty()).collect(),
+            None => self.names.iter().map(|name| name.trim_start_matches('/').to_string()).collect(),
+        };
+        let zone_key = LowerName::new(zone);
+        names
+            .into_iter()
+            .filter_map(|name| {
+                let parsed = Name::from_ascii(&name).ok()?;
+                let fqdn = if name.ends_with('.') { parsed } else { parsed.append_domain(zone).ok()? };
+                if !zone_key.zone_of(&LowerName::new(&fqdn)) {
+                    eprintln!("Skipping Docker record {}, which is not in zone {}", fqdn, zone);
+                    return None;
+                }
+                Some(fqdn.to_lowercase())
+            })
+            .collect()
+    }
+
+    /// The addresses the container has on its networks.
+    fn addresses(&self) -> Vec<IpAddr> {
+        let Some(settings) = &self.network_settings else {
+            return Vec::new();
+        };
+        let mut addresses: Vec<IpAddr> = settings
+            .networks
+            .values()
+            .flat_map(|network| [&network.ip_address, &network.global_ipv6_address])
+            .filter_map(|address| address.parse().ok())
+            .collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+}
+
+/// Sends a GET request for `path` to the Docker daemon.
+async fn get(socket: &PathBuf, path: &str) -> anyhow::Result<hyper::Response<Body>> {
+    #[cfg(unix)]
+    let stream = UnixStream::connect(socket).await;
+    #[cfg(windows)]
+    let stream = ClientOptions::new().open(socket);
+    let stream = stream.map_err(|e| anyhow::anyhow!("can't connect to {}: {}", socket.display(), e))?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(connection);
+    let request = hyper::Request::get(path).header(header::HOST, "docker").body(Body::empty())?;
+    let response = sender.send_request(request).await?;
+    if response.status() != StatusCode::OK {
+        anyhow::bail!("Docker answered {} with {}", path, response.status());
+    }
+    Ok(response)
+}
+
+/// Brings the records of the zone in line with the running containers.
+async fn sync(state: &RwLock<DnsState>, options: &DockerOptions) -> anyhow::Result<()> {
+    let body = hyper::body::to_bytes(get(&options.socket, "/containers/json").await?.into_body()).await?;
+    let containers: Vec<Container> = serde_json::from_slice(&body)?;
+    let mut records = Vec::new();
+    for container in &containers {
+        let addresses = container.addresses();
+        for name in container.hostnames(&options.zone) {
+            records.extend(addresses.iter().map(|address| RecordEntry {
+                name: name.to_string(),
+                record_type: if address.is_ipv4() { "A" } else { "AAAA" }.to_string(),
+                value: address.to_string(),
+                ttl: options.ttl,
+                ..Default::default()
+            }));
+        }
+    }
+    let origin = options.zone.to_string();
+    let changed = state
+        .read()
+        .await
+        .sync_records(&origin, &[RecordType::A, RecordType::AAAA], records)
+        .await?;
+    if changed > 0 {
+        println!("Updated {} Docker record sets in {}", changed, origin);
+    }
+    Ok(())
+}
+
+/// Follows the container events, syncing the records after each relevant one, until the
+/// connection to the daemon breaks.
+async fn watch(state: &RwLock<DnsState>, options: &DockerOptions) -> anyhow::Result<()> {
+    // Subscribe before listing, so that no change slips in between
+    let filters = form_urlencoded::byte_serialize(br#"{"type":["container"]}"#).collect::<String>();
+    let mut events = get(&options.socket, &format!("/events?filters={}", filters)).await?.into_body();
+    sync(state, options).await?;
+
+    let mut buffer = Vec::new();
+    while let Some(chunk) = events.data().await {
+        buffer.extend_from_slice(&chunk?);
+        let mut changed = false;
+        while let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=end).collect();
+            if let Ok(event) = serde_json::from_slice::<Event>(&line) {
+                changed |= SYNC_ACTIONS.contains(&event.action.as_str());
+            }
+        }
+        if changed {
+            sync(state, options).await?;
+        }
+    }
+    anyhow::bail!("Docker closed the event stream")
+}
+
+/// Keeps the records of the zone in line with the running containers, connecting to the
+/// daemon again whenever it goes away. Runs until the process exits.
+pub async fn run_docker_sync(state: Arc<RwLock<DnsState>>, options: DockerOptions) {
+    if let Err(e) = state.write().await.ensure_zones(&[options.zone.to_string()]).await {
+        eprintln!("Failed to create Docker zone {}: {}", options.zone, e);
+        return;
+    }
+    loop {
+        if let Err(e) = watch(&state, &options).await {
+            eprintln!("Docker sync from {} failed: {}", options.socket.display(), e);
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+}
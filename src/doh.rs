@@ -0,0 +1,231 @@
+//! DNS-over-HTTPS (RFC 8484) listener.
+//!
+//! Accepts `application/dns-message` queries over HTTPS, either POSTed as the request
+//! body or base64url-encoded in the `dns` query parameter of a GET, and answers them
+//! through the same `RequestHandler` as the plain DNS listeners.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hickory_proto::rr::Record;
+use hickory_proto::serialize::binary::{BinDecodable, BinEncoder};
+use hickory_server::authority::{MessageRequest, MessageResponse};
+use hickory_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+use hyper::body::HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{header, Body, Method, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tonic::async_trait;
+
+use crate::acmecert::{self, CertificateResolver};
+use crate::hardening::MAX_MESSAGE_SIZE;
+use crate::settings::DohSettings;
+use crate::shutdown::Shutdown;
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Encapsulates DNS-over-HTTPS listener options
+pub struct DohOptions {
+    pub listen_addr: String,
+    /// PEM-encoded certificate chain presented to clients.
+    pub cert_path: PathBuf,
+    /// PEM-encoded private key for the certificate.
+    pub key_path: PathBuf,
+    /// URL path queries are served on, e.g. `/dns-query`.
+    pub path: String,
+}
+
+impl From<DohSettings> for DohOptions {
+    fn from(cfg: DohSettings) -> Self {
+        DohOptions {
+            listen_addr: cfg.listen_addr,
+            cert_path: PathBuf::from(cfg.cert_path),
+            key_path: PathBuf::from(cfg.key_path),
+            path: cfg.path,
+        }
+    }
+}
+
+/// Response handler that serializes the response into a buffer instead of a socket.
+#[derive(Clone, Default)]
+pub(crate) struct BufferResponseHandler(Arc<Mutex<Option<Vec<u8>>>>);
+
+impl BufferResponseHandler {
+    pub(crate) fn take(&self) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+#[async_trait]
+impl ResponseHandler for BufferResponseHandler {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let mut buffer = Vec::with_capacity(512);
+        let mut encoder = BinEncoder::new(&mut buffer);
+        let info = response
+            .destructive_emit(&mut encoder)
+            .map_err(std::io::Error::other)?;
+        *self.0.lock().unwrap() = Some(buffer);
+        Ok(info)
+    }
+}
+
+/// Builds a bodiless HTTP response with the given status.
+fn status(code: StatusCode) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
+
+/// Extracts the wire-format DNS message from a GET or POST request, refusing POST bodies
+/// longer than a DNS message can be.
+async fn read_query(request: hyper::Request<Body>) -> Result<Vec<u8>, StatusCode> {
+    match *request.method() {
+        Method::GET => {
+            let encoded = request
+                .uri()
+                .query()
+                .and_then(|query| {
+                    form_urlencoded::parse(query.as_bytes())
+                        .find(|(key, _)| key == "dns")
+                        .map(|(_, value)| value.into_owned())
+                })
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            URL_SAFE_NO_PAD
+                .decode(encoded.trim_end_matches('='))
+                .map_err(|_| StatusCode::BAD_REQUEST)
+        }
+        Method::POST => {
+            let content_type = request.headers().get(header::CONTENT_TYPE);
+            if content_type.map(|v| v.as_bytes()) != Some(DNS_MESSAGE.as_bytes()) {
+                return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            let declared = request
+                .headers()
+                .get(header::CONTENT_LENGTH)
+                .and_then(|len| len.to_str().ok()?.parse::<usize>().ok());
+            if declared.is_some_and(|len| len > MAX_MESSAGE_SIZE) {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            // Read in chunks rather than at once, so that a body longer than declared, or
+            // sent without a length, can't grow past a DNS message either
+            let mut body = request.into_body();
+            let mut query = Vec::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+                if query.len() + chunk.len() > MAX_MESSAGE_SIZE {
+                    return Err(StatusCode::PAYLOAD_TOO_LARGE);
+                }
+                query.extend_from_slice(&chunk);
+            }
+            Ok(query)
+        }
+        _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+/// Answers a single DoH request using `handler`.
+async fn serve<H: RequestHandler + Clone>(
+    handler: H,
+    path: Arc<str>,
+    src: SocketAddr,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    if request.uri().path() != &*path {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+    let query = match read_query(request).await {
+        Ok(query) => query,
+        Err(code) => return Ok(status(code)),
+    };
+    let Ok(message) = MessageRequest::from_bytes(&query) else {
+        return Ok(status(StatusCode::BAD_REQUEST));
+    };
+
+    let response_handle = BufferResponseHandler::default();
+    let request = Request::new(message, src, Protocol::Https);
+    handler.handle_request(&request, response_handle.clone()).await;
+
+    let Some(answer) = response_handle.take() else {
+        return Ok(status(StatusCode::INTERNAL_SERVER_ERROR));
+    };
+    let response = hyper::Response::builder()
+        .header(header::CONTENT_TYPE, DNS_MESSAGE)
+        .header(header::CONTENT_LENGTH, answer.len())
+        .body(Body::from(answer))
+        .unwrap_or_else(|_| status(StatusCode::INTERNAL_SERVER_ERROR));
+    Ok(response)
+}
+
+/// Starts the DNS-over-HTTPS server, answering queries with `handler`, serving the
+/// certificate of `certificates` if given, or else the one of `options`.
+///
+/// Once `shutdown` is requested, no new connections are accepted and open connections
+/// are closed after their current request; the function returns once they all are.
+///
+/// # Errors
+///
+/// Returns an error if the certificate or key cannot be loaded or the listener cannot be bound.
+pub async fn run_doh_server<H: RequestHandler + Clone>(
+    handler: H,
+    options: DohOptions,
+    certificates: Option<Arc<CertificateResolver>>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let mut tls_config = acmecert::server_config(certificates.as_ref(), &options.cert_path, &options.key_path)?;
+    tls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+
+    let listener = TcpListener::bind(&options.listen_addr).await?;
+    let path: Arc<str> = options.path.into();
+    println!("DoH server listening on {}{}", options.listen_addr, path);
+
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, src) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // Reap finished connections so the set doesn't grow without bound
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = shutdown.clone().requested() => break,
+        };
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+        let path = path.clone();
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let Ok(stream) = acceptor.accept(stream).await else {
+                return;
+            };
+            let service = service_fn(move |request| serve(handler.clone(), path.clone(), src, request));
+            let connection = Http::new().serve_connection(stream, service);
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.requested() => {
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(e) = result {
+                eprintln!("DoH connection from {} failed: {}", src, e);
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
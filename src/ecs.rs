@@ -0,0 +1,149 @@
+//! EDNS Client Subnet (RFC 7871).
+//!
+//! The Client Subnet option of a query is parsed once as the query is handled, and the
+//! client is located by its address rather than the source address of the query: geo
+//! records always do, views only with `views` enabled, since any client can claim any
+//! subnet to be let into a view. An option with a source prefix length of 0 asks for an
+//! answer that doesn't depend on the subnet and is not used to locate the client.
+//!
+//! Forwarded queries carry no Client Subnet option unless `forward` is enabled, in which
+//! case the client's option is passed on to the upstreams with its address cut to at
+//! most `max_prefix_v4` or `max_prefix_v6` bits, so that upstreams can tailor their
+//! answers without learning the client's full address. Such answers may differ between
+//! subnets and are not cached.
+
+use hickory_proto::rr::rdata::opt::{ClientSubnet, EdnsCode, EdnsOption};
+use hickory_server::server::Request;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::settings::EcsSettings;
+
+/// Config options for Client Subnet handling
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EcsOptions {
+    /// Whether the client's option is passed on to the upstreams of forwarded queries.
+    pub forward: bool,
+    /// Longest IPv4 source prefix passed on.
+    pub max_prefix_v4: u8,
+    /// Longest IPv6 source prefix passed on.
+    pub max_prefix_v6: u8,
+    /// Whether views match the client subnet rather than the source address.
+    pub views: bool,
+}
+
+impl Default for EcsOptions {
+    fn default() -> Self {
+        EcsOptions::try_from(EcsSettings::default()).expect("default Client Subnet settings are valid")
+    }
+}
+
+impl TryFrom<EcsSettings> for EcsOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: EcsSettings) -> anyhow::Result<Self> {
+        if cfg.max_prefix_v4 > 32 {
+            anyhow::bail!("dns.ecs.max_prefix_v4 must be at most 32");
+        }
+        if cfg.max_prefix_v6 > 128 {
+            anyhow::bail!("dns.ecs.max_prefix_v6 must be at most 128");
+        }
+        Ok(EcsOptions {
+            forward: cfg.forward,
+            max_prefix_v4: cfg.max_prefix_v4,
+            max_prefix_v6: cfg.max_prefix_v6,
+            views: cfg.views,
+        })
+    }
+}
+
+/// The client subnet of a query's Client Subnet option.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subnet {
+    pub address: IpAddr,
+    pub source_prefix: u8,
+}
+
+impl Subnet {
+    /// The subnet of the Client Subnet option of `request`, if it has one.
+    pub fn of_request(request: &Request) -> Option<Subnet> {
+        let Some(EdnsOption::Subnet(subnet)) = request.edns()?.options().get(EdnsCode::Subnet) else {
+            return None;
+        };
+        // ClientSubnet keeps its fields private, so they are read back from its wire form:
+        // family, source prefix length, scope prefix length, then the truncated address
+        let bytes = Vec::<u8>::try_from(subnet).ok()?;
+        let (family, source_prefix, address) = (u16::from_be_bytes([*bytes.first()?, *bytes.get(1)?]), *bytes.get(2)?, bytes.get(4..)?);
+        let address = match family {
+            1 => {
+                let mut octets = [0; 4];
+                octets.get_mut(..address.len())?.copy_from_slice(address);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            2 => {
+                let mut octets = [0; 16];
+                octets.get_mut(..address.len())?.copy_from_slice(address);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        Some(Subnet { address, source_prefix })
+    }
+
+    /// The address the client is located by, unless the option asks for an answer that
+    /// doesn't depend on the subnet.
+    pub fn locating_address(&self) -> Option<IpAddr> {
+        (self.source_prefix > 0).then_some(self.address)
+    }
+
+    /// The subnet cut to at most the prefix lengths of `options`, as passed on upstream.
+    pub fn truncated(&self, options: &EcsOptions) -> Subnet {
+        match self.address {
+            IpAddr::V4(address) => {
+                let prefix = self.source_prefix.min(options.max_prefix_v4);
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                Subnet {
+                    address: IpAddr::V4(Ipv4Addr::from(u32::from(address) & mask)),
+                    source_prefix: prefix,
+                }
+            }
+            IpAddr::V6(address) => {
+                let prefix = self.source_prefix.min(options.max_prefix_v6);
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                Subnet {
+                    address: IpAddr::V6(Ipv6Addr::from(u128::from(address) & mask)),
+                    source_prefix: prefix,
+                }
+            }
+        }
+    }
+
+    /// The Client Subnet option for the subnet, with `scope` as its scope prefix length.
+    pub fn option(&self, scope: u8) -> EdnsOption {
+        EdnsOption::Subnet(ClientSubnet::new(self.address, self.source_prefix, scope))
+    }
+}
+
+/// The address the client of `request` is located by: that of its client subnet, or
+/// the source address of the query.
+pub fn client_address(request: &Request, subnet: Option<&Subnet>) -> IpAddr {
+    subnet
+        .and_then(Subnet::locating_address)
+        .unwrap_or_else(|| request.src().ip())
+}
+
+tokio::task_local! {
+    /// Subnet passed on with the query being forwarded, if any.
+    static FORWARDED_SUBNET: Option<Subnet>;
+}
+
+/// Runs `handling`, a query being answered through the catalog, with `subnet` as the one
+/// the forwarder passes on upstream.
+pub async fn forwarding<F: Future>(subnet: Option<Subnet>, handling: F) -> F::Output {
+    FORWARDED_SUBNET.scope(subnet, handling).await
+}
+
+/// The subnet to pass on with the query being forwarded, as set by `forwarding`.
+pub fn forwarded_subnet() -> Option<Subnet> {
+    FORWARDED_SUBNET.try_with(|subnet| *subnet).ok().flatten()
+}
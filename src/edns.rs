@@ -0,0 +1,126 @@
+//! EDNS(0) settings of responses (RFC 6891).
+//!
+//! `[dns.edns]` tunes the OPT record of responses and the buffer sizes they are kept
+//! within, alongside `dns.max_udp_payload`:
+//!
+//! - `advertised_payload` is the UDP payload size the OPT record of responses advertises.
+//!   UDP responses are kept within it too. When unset, responses advertise the size they
+//!   were kept within.
+//! - With `honor_client_payload`, the default, UDP responses are kept within the buffer
+//!   size the query's EDNS advertises. Without it, they fill `dns.max_udp_payload` whatever
+//!   the client advertised, for networks whose clients advertise less than they take.
+//! - `allowed_options` lists the options responses may carry, by name or code, e.g.
+//!   `["cookie", "nsid", "15"]`; the others are left out. Responses carry any option when
+//!   it is unset.
+//!
+//! Responses are truncated to these sizes by `hardening`.
+
+use hickory_proto::rr::rdata::opt::EdnsCode;
+use hickory_proto::rr::Record;
+use hickory_server::authority::MessageResponse;
+use hickory_server::server::{ResponseHandler, ResponseInfo};
+use std::sync::Arc;
+use tonic::async_trait;
+
+use crate::settings::EdnsSettings;
+
+/// Config options for the EDNS section of responses
+#[derive(Clone, Debug, PartialEq)]
+pub struct EdnsOptions {
+    /// UDP payload size responses advertise, and are kept within over UDP.
+    pub advertised_payload: Option<u16>,
+    /// Whether UDP responses are kept within the buffer size of the query's EDNS.
+    pub honor_client_payload: bool,
+    /// Options responses may carry; any when unset.
+    pub allowed_options: Option<Vec<EdnsCode>>,
+}
+
+impl Default for EdnsOptions {
+    fn default() -> Self {
+        EdnsOptions::try_from(EdnsSettings::default()).expect("default EDNS settings are valid")
+    }
+}
+
+impl TryFrom<EdnsSettings> for EdnsOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: EdnsSettings) -> anyhow::Result<Self> {
+        if let Some(bytes) = cfg.advertised_payload.filter(|bytes| !(512..=4096).contains(bytes)) {
+            anyhow::bail!("dns.edns.advertised_payload must be from 512 to 4096 bytes, not {}", bytes);
+        }
+        let allowed_options = cfg
+            .allowed_options
+            .map(|options| options.iter().map(|option| option_code(option)).collect::<anyhow::Result<_>>())
+            .transpose()?;
+        Ok(EdnsOptions {
+            advertised_payload: cfg.advertised_payload,
+            honor_client_payload: cfg.honor_client_payload,
+            allowed_options,
+        })
+    }
+}
+
+/// The code of the EDNS option `name`, its mnemonic or its number.
+fn option_code(name: &str) -> anyhow::Result<EdnsCode> {
+    if let Ok(code) = name.parse::<u16>() {
+        return Ok(EdnsCode::from(code));
+    }
+    let code = match name.to_ascii_lowercase().as_str() {
+        "llq" => 1,
+        "ul" => 2,
+        "nsid" => 3,
+        "dau" => 5,
+        "dhu" => 6,
+        "n3u" => 7,
+        "subnet" | "ecs" => 8,
+        "expire" => 9,
+        "cookie" => 10,
+        "keepalive" | "tcp-keepalive" => 11,
+        "padding" => 12,
+        "chain" => 13,
+        "ede" => 15,
+        _ => anyhow::bail!("dns.edns.allowed_options has an unknown option {}, give its code instead", name),
+    };
+    Ok(EdnsCode::from(code))
+}
+
+/// Response handler that leaves the options `EdnsOptions` doesn't allow out of the
+/// response's EDNS section.
+#[derive(Clone)]
+pub struct EdnsResponseHandler<R> {
+    inner: R,
+    options: Arc<EdnsOptions>,
+}
+
+impl<R> EdnsResponseHandler<R> {
+    pub fn new(inner: R, options: Arc<EdnsOptions>) -> Self {
+        Self { inner, options }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for EdnsResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        mut response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        if let (Some(allowed), Some(edns)) = (&self.options.allowed_options, response.get_edns()) {
+            let denied: Vec<EdnsCode> = edns.options().as_ref().keys().filter(|code| !allowed.contains(code)).copied().collect();
+            if !denied.is_empty() {
+                let mut edns = edns.clone();
+                for code in denied {
+                    edns.options_mut().remove(code);
+                }
+                response.set_edns(edns);
+            }
+        }
+        self.inner.send_response(response).await
+    }
+}
@@ -0,0 +1,129 @@
+//! Failures of operations on `DnsState`.
+//!
+//! Each `RdnsError` variant is a category callers can match on: the gRPC API maps them
+//! to status codes with the category as the `google.rpc.ErrorInfo` reason, and the REST
+//! gateway to HTTP statuses. Failures that fit no category are `Other`.
+
+use std::fmt;
+use thiserror::Error;
+
+/// Why an operation on the DNS state failed.
+#[derive(Debug, Error)]
+pub enum RdnsError {
+    /// A record or zone name is malformed, or a wildcard misplaced.
+    #[error("{0}")]
+    InvalidName(String),
+    /// A record value is malformed or doesn't fit the record type.
+    #[error("{0}")]
+    InvalidRdata(String),
+    /// Some other input, such as the record type, is malformed.
+    #[error("{0}")]
+    InvalidArgument(String),
+    /// No hosted zone has the name or contains it.
+    #[error("{0}")]
+    ZoneNotFound(String),
+    /// A zone with the name is already hosted.
+    #[error("{0}")]
+    ZoneExists(String),
+    /// The records, or the pool, geo record, view record, health check or blocklist
+    /// entry, the operation applies to don't exist.
+    #[error("{0}")]
+    RecordNotFound(String),
+    /// The record conflicts with records that already exist, such as a CNAME.
+    #[error("{0}")]
+    Conflict(String),
+    /// The record lies below a delegation of its zone, which hides it, or a delegation
+    /// would hide records that exist.
+    #[error("{0}")]
+    Occluded(String),
+    /// The zone is a secondary, which only zone transfers modify.
+    #[error("{0}")]
+    ReadOnlyZone(String),
+    /// The zone is frozen for maintenance, and changes to it are rejected until it is
+    /// thawed.
+    #[error("{0}")]
+    ZoneFrozen(String),
+    /// The zone version the operation asks for isn't kept in the zone's history.
+    #[error("{0}")]
+    VersionNotFound(String),
+    /// The records no longer hold the value a compare-and-swap expected.
+    #[error("{0}")]
+    ValueMismatch(String),
+    /// The instance follows a leader, which changes must be made on instead.
+    #[error("{0}")]
+    NotLeader(String),
+    /// The feature the operation needs isn't configured.
+    #[error("{0}")]
+    NotEnabled(String),
+    /// The records to add would exceed the quota of their zone, their tenant or of all
+    /// zones.
+    #[error("{0}")]
+    QuotaExceeded(String),
+    /// The caller's tenant doesn't own the zone the operation applies to.
+    #[error("{0}")]
+    PermissionDenied(String),
+    /// The change was applied in memory but couldn't be persisted.
+    #[error("failed to persist state: {0}")]
+    StorageError(#[source] anyhow::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl RdnsError {
+    /// The category as an upper snake case reason, e.g. `ZONE_NOT_FOUND`.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            RdnsError::InvalidName(_) => "INVALID_NAME",
+            RdnsError::InvalidRdata(_) => "INVALID_RDATA",
+            RdnsError::InvalidArgument(_) => "INVALID_ARGUMENT",
+            RdnsError::ZoneNotFound(_) => "ZONE_NOT_FOUND",
+            RdnsError::ZoneExists(_) => "ZONE_EXISTS",
+            RdnsError::RecordNotFound(_) => "RECORD_NOT_FOUND",
+            RdnsError::Conflict(_) => "CONFLICT",
+            RdnsError::Occluded(_) => "OCCLUDED",
+            RdnsError::ReadOnlyZone(_) => "READ_ONLY_ZONE",
+            RdnsError::ZoneFrozen(_) => "ZONE_FROZEN",
+            RdnsError::VersionNotFound(_) => "VERSION_NOT_FOUND",
+            RdnsError::ValueMismatch(_) => "VALUE_MISMATCH",
+            RdnsError::NotLeader(_) => "NOT_LEADER",
+            RdnsError::NotEnabled(_) => "NOT_ENABLED",
+            RdnsError::QuotaExceeded(_) => "QUOTA_EXCEEDED",
+            RdnsError::PermissionDenied(_) => "PERMISSION_DENIED",
+            RdnsError::StorageError(_) => "STORAGE_ERROR",
+            RdnsError::Other(_) => "UNKNOWN",
+        }
+    }
+
+    /// The error with `context` prepended to its message, keeping its category.
+    pub fn context(self, context: impl fmt::Display) -> Self {
+        let prefix = |message: String| format!("{}: {}", context, message);
+        match self {
+            RdnsError::InvalidName(message) => RdnsError::InvalidName(prefix(message)),
+            RdnsError::InvalidRdata(message) => RdnsError::InvalidRdata(prefix(message)),
+            RdnsError::InvalidArgument(message) => RdnsError::InvalidArgument(prefix(message)),
+            RdnsError::ZoneNotFound(message) => RdnsError::ZoneNotFound(prefix(message)),
+            RdnsError::ZoneExists(message) => RdnsError::ZoneExists(prefix(message)),
+            RdnsError::RecordNotFound(message) => RdnsError::RecordNotFound(prefix(message)),
+            RdnsError::Conflict(message) => RdnsError::Conflict(prefix(message)),
+            RdnsError::Occluded(message) => RdnsError::Occluded(prefix(message)),
+            RdnsError::ReadOnlyZone(message) => RdnsError::ReadOnlyZone(prefix(message)),
+            RdnsError::ZoneFrozen(message) => RdnsError::ZoneFrozen(prefix(message)),
+            RdnsError::VersionNotFound(message) => RdnsError::VersionNotFound(prefix(message)),
+            RdnsError::ValueMismatch(message) => RdnsError::ValueMismatch(prefix(message)),
+            RdnsError::NotLeader(message) => RdnsError::NotLeader(prefix(message)),
+            RdnsError::NotEnabled(message) => RdnsError::NotEnabled(prefix(message)),
+            RdnsError::QuotaExceeded(message) => RdnsError::QuotaExceeded(prefix(message)),
+            RdnsError::PermissionDenied(message) => RdnsError::PermissionDenied(prefix(message)),
+            RdnsError::StorageError(e) => RdnsError::StorageError(anyhow::anyhow!(prefix(e.to_string()))),
+            RdnsError::Other(e) => RdnsError::Other(anyhow::anyhow!(prefix(e.to_string()))),
+        }
+    }
+}
+
+/// Unwraps errors that already are an `RdnsError`, so their category survives being
+/// passed through `anyhow`.
+impl From<anyhow::Error> for RdnsError {
+    fn from(e: anyhow::Error) -> Self {
+        e.downcast::<RdnsError>().unwrap_or_else(RdnsError::Other)
+    }
+}
@@ -0,0 +1,918 @@
+//! Export of record changes and queries to Kafka or NATS.
+//!
+//! With `[events]` configured, the record changes `WatchRecords` streams and the queries
+//! the DNS listeners answer are published as JSON objects, one per event, to a NATS
+//! subject or Kafka topic each, for pipelines larger than webhooks suit:
+//!
+//! ```json
+//! {"event": "record.added", "timestamp_ms": 1700000000000, "record": {"name": "www.example.com.", "record_type": "A", "value": "192.0.2.1", "ttl": 300}}
+//! {"event": "query", "timestamp_ms": 1700000000000, "name": "www.example.com.", "type": "A", "client": "192.0.2.0", "protocol": "UDP", "response_code": "No Error", "duration_us": 84}
+//! ```
+//!
+//! Client addresses are anonymized as `[dns.privacy]` says. The sink is spoken to
+//! directly over TCP, without TLS: NATS with its text protocol, authenticating with the
+//! token or user and password of the URL, and Kafka with version 3 `Produce` requests,
+//! sent to the leader of the configured partition of each topic, as the first broker
+//! listed that can be reached names it in its metadata. A change of leader fails the
+//! batch, and the leaders are looked up again before it is sent once more. Events
+//! are queued and sent in batches; while the sink can't be reached they are kept until
+//! the queue fills up, and dropped beyond it, so a slow or lost sink never holds up
+//! queries or the API.
+
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, RwLock};
+
+use crate::dns::{DnsState, QueryEvent, RecordChange, RecordEntry, RecordEvent};
+use crate::settings::EventSettings;
+
+/// Events queued before further ones are dropped.
+const QUEUE_CAPACITY: usize = 10000;
+/// Most events sent in one batch.
+const MAX_BATCH: usize = 500;
+/// How long connecting to and each exchange with the sink may take.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delay between attempts at reaching a sink that failed.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// How often an idle connection answers the sink's keep-alive pings.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Config options for the event export
+#[derive(Clone, Debug)]
+pub struct EventOptions {
+    pub sink: Sink,
+    /// Subject or topic of the record changes; they aren't exported when unset.
+    pub records_subject: Option<String>,
+    /// Subject or topic of the queries; they aren't exported when unset.
+    pub queries_subject: Option<String>,
+}
+
+/// Where the events are published.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sink {
+    Nats {
+        address: String,
+        /// `user` and `password`, or a token alone in `user`.
+        user: Option<String>,
+        password: Option<String>,
+    },
+    Kafka {
+        brokers: Vec<String>,
+        partition: i32,
+    },
+}
+
+impl fmt::Display for Sink {
+    /// The sink's servers, without credentials.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Sink::Nats { address, .. } => write!(f, "nats://{}", address),
+            Sink::Kafka { brokers, .. } => write!(f, "kafka://{}", brokers.join(",")),
+        }
+    }
+}
+
+impl TryFrom<EventSettings> for EventOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: EventSettings) -> anyhow::Result<Self> {
+        let sink = if let Some(rest) = cfg.sink.strip_prefix("nats://") {
+            let (credentials, address) = match rest.rsplit_once('@') {
+                Some((credentials, address)) => (Some(credentials), address),
+                None => (None, rest),
+            };
+            let (user, password) = match credentials.map(|credentials| credentials.split_once(':')) {
+                Some(Some((user, password))) => (Some(user.to_string()), Some(password.to_string())),
+                Some(None) => (credentials.map(str::to_string), None),
+                None => (None, None),
+            };
+            Sink::Nats {
+                address: with_port(address, 4222),
+                user,
+                password,
+            }
+        } else if let Some(rest) = cfg.sink.strip_prefix("kafka://") {
+            Sink::Kafka {
+                brokers: rest.split(',').filter(|broker| !broker.is_empty()).map(|broker| with_port(broker, 9092)).collect(),
+                partition: cfg.partition,
+            }
+        } else {
+            anyhow::bail!("events.sink {} must be a nats:// or kafka:// URL", cfg.sink);
+        };
+        if matches!(&sink, Sink::Nats { address, .. } if address.starts_with(':')) || matches!(&sink, Sink::Kafka { brokers, .. } if brokers.is_empty()) {
+            anyhow::bail!("events.sink {} names no server", cfg.sink);
+        }
+        if cfg.records_subject.is_none() && cfg.queries_subject.is_none() {
+            anyhow::bail!("events needs a records_subject, a queries_subject or both");
+        }
+        Ok(EventOptions {
+            sink,
+            records_subject: cfg.records_subject,
+            queries_subject: cfg.queries_subject,
+        })
+    }
+}
+
+/// `address` with `port` appended unless it gives one.
+fn with_port(address: &str, port: u16) -> String {
+    let address = address.trim_end_matches('/');
+    match address.rsplit_once(':') {
+        Some((_, given)) if given.parse::<u16>().is_ok() && !address.ends_with(']') => address.to_string(),
+        _ => format!("{}:{}", address, port),
+    }
+}
+
+/// A record change, as it is published.
+#[derive(Serialize)]
+struct RecordMessage {
+    event: &'static str,
+    timestamp_ms: u64,
+    record: RecordEntry,
+}
+
+/// A query, as it is published.
+#[derive(Serialize)]
+struct QueryMessage {
+    event: &'static str,
+    timestamp_ms: u64,
+    name: String,
+    #[serde(rename = "type")]
+    query_type: String,
+    client: IpAddr,
+    protocol: String,
+    response_code: String,
+    duration_us: u64,
+}
+
+impl From<RecordEvent> for RecordMessage {
+    fn from(event: RecordEvent) -> Self {
+        RecordMessage {
+            event: match event.change {
+                RecordChange::Added => "record.added",
+                RecordChange::Updated => "record.updated",
+                RecordChange::Deleted => "record.deleted",
+            },
+            timestamp_ms: unix_millis(event.timestamp),
+            record: event.record,
+        }
+    }
+}
+
+impl From<QueryEvent> for QueryMessage {
+    fn from(event: QueryEvent) -> Self {
+        QueryMessage {
+            event: "query",
+            timestamp_ms: unix_millis(event.timestamp),
+            name: event.name,
+            query_type: event.query_type,
+            client: event.client,
+            protocol: event.protocol,
+            response_code: event.response_code,
+            duration_us: event.duration.as_micros() as u64,
+        }
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// One event to publish: its subject or topic and its JSON payload.
+type Event = (Arc<str>, Vec<u8>);
+
+/// Publishes the record changes and queries of `state` as `options` say, until the state
+/// is dropped.
+pub async fn run_exporter(state: Arc<RwLock<DnsState>>, options: EventOptions) {
+    let (mut records, mut queries) = {
+        let state = state.read().await;
+        (
+            options.records_subject.as_ref().map(|_| state.subscribe()),
+            options.queries_subject.as_ref().map(|_| state.subscribe_queries()),
+        )
+    };
+    drop(state);
+    let records_subject: Arc<str> = options.records_subject.as_deref().unwrap_or_default().into();
+    let queries_subject: Arc<str> = options.queries_subject.as_deref().unwrap_or_default().into();
+    let (queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+    tokio::spawn(publish_all(options.sink.clone(), receiver));
+    println!("Exporting events to {}", options.sink);
+
+    let mut dropped = 0u64;
+    loop {
+        let event = tokio::select! {
+            event = next(&mut records) => match event {
+                Ok(event) => serde_json::to_vec(&RecordMessage::from(event)).map(|payload| (records_subject.clone(), payload)),
+                Err(RecvError::Lagged(missed)) => {
+                    dropped += missed;
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+            event = next(&mut queries) => match event {
+                Ok(event) => serde_json::to_vec(&QueryMessage::from(event)).map(|payload| (queries_subject.clone(), payload)),
+                Err(RecvError::Lagged(missed)) => {
+                    dropped += missed;
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+        };
+        let Ok(event) = event else {
+            continue;
+        };
+        match queue.try_send(event) {
+            Ok(()) if dropped > 0 => {
+                eprintln!("Event export fell behind, {} events dropped", dropped);
+                dropped = 0;
+            }
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => dropped += 1,
+            Err(mpsc::error::TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+/// The next event of `receiver`, or never without one.
+async fn next<T: Clone>(receiver: &mut Option<broadcast::Receiver<T>>) -> Result<T, RecvError> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends the queued events to `sink` in batches, connecting again whenever it fails.
+async fn publish_all(sink: Sink, mut queue: mpsc::Receiver<Event>) {
+    let mut connection: Option<Connection> = None;
+    // Kafka brokers are tried in turn, starting after the last that failed
+    let mut next_broker = 0;
+    loop {
+        let first = tokio::select! {
+            event = queue.recv() => match event {
+                Some(event) => event,
+                None => return,
+            },
+            _ = tokio::time::sleep(KEEPALIVE_INTERVAL), if connection.is_some() => {
+                if let Some(Err(e)) = connection.as_mut().map(Connection::keepalive) {
+                    eprintln!("Event sink connection lost: {}", e);
+                    connection = None;
+                }
+                continue;
+            }
+        };
+        let mut batch = vec![first];
+        while batch.len() < MAX_BATCH {
+            match queue.try_recv() {
+                Ok(event) => batch.push(event),
+                Err(_) => break,
+            }
+        }
+        loop {
+            if connection.is_none() {
+                match Connection::open(&sink, &mut next_broker).await {
+                    Ok(opened) => connection = Some(opened),
+                    Err(e) => {
+                        eprintln!("Failed to connect to the event sink: {}", e);
+                        tokio::time::sleep(RETRY_DELAY).await;
+                        continue;
+                    }
+                }
+            }
+            let Some(open) = &mut connection else {
+                continue;
+            };
+            match tokio::time::timeout(IO_TIMEOUT, open.publish(&batch)).await {
+                Ok(Ok(())) => break,
+                Ok(Err(e)) => eprintln!("Failed to publish {} events: {}", batch.len(), e),
+                Err(_) => eprintln!("Failed to publish {} events: timed out", batch.len()),
+            }
+            connection = None;
+            next_broker += 1;
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+}
+
+/// An open connection to the sink.
+enum Connection {
+    Nats(BufReader<TcpStream>),
+    Kafka(KafkaConnection),
+}
+
+/// Connections to a Kafka cluster: to a broker its metadata is asked of, and to the
+/// leaders of the partition events are produced to.
+struct KafkaConnection {
+    bootstrap: TcpStream,
+    partition: i32,
+    /// Leaders of the partition of each topic published to so far, by topic.
+    leaders: HashMap<String, String>,
+    /// Connections to the leaders, by address.
+    streams: HashMap<String, TcpStream>,
+    correlation_id: i32,
+}
+
+impl Connection {
+    /// Connects to the sink, or for Kafka to the first broker that can be reached from
+    /// `next_broker` on.
+    async fn open(sink: &Sink, next_broker: &mut usize) -> anyhow::Result<Self> {
+        match sink {
+            Sink::Nats { address, user, password } => {
+                let stream = tokio::time::timeout(IO_TIMEOUT, TcpStream::connect(address)).await??;
+                let mut stream = BufReader::new(stream);
+                tokio::time::timeout(IO_TIMEOUT, nats_handshake(&mut stream, user.as_deref(), password.as_deref())).await??;
+                Ok(Connection::Nats(stream))
+            }
+            Sink::Kafka { brokers, partition } => {
+                let mut last_error = None;
+                for _ in 0..brokers.len() {
+                    let broker = &brokers[*next_broker % brokers.len()];
+                    match tokio::time::timeout(IO_TIMEOUT, TcpStream::connect(broker)).await {
+                        Ok(Ok(bootstrap)) => {
+                            return Ok(Connection::Kafka(KafkaConnection {
+                                bootstrap,
+                                partition: *partition,
+                                leaders: HashMap::new(),
+                                streams: HashMap::new(),
+                                correlation_id: 0,
+                            }))
+                        }
+                        Ok(Err(e)) => last_error = Some(anyhow::anyhow!("{}: {}", broker, e)),
+                        Err(_) => last_error = Some(anyhow::anyhow!("{}: timed out", broker)),
+                    }
+                    *next_broker += 1;
+                }
+                Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no Kafka brokers")))
+            }
+        }
+    }
+
+    /// Sends `batch`, returning once the sink has taken it.
+    async fn publish(&mut self, batch: &[Event]) -> anyhow::Result<()> {
+        match self {
+            Connection::Nats(stream) => {
+                answer_pings(stream)?;
+                stream.get_mut().write_all(&nats_batch(batch)).await?;
+                read_pong(stream).await
+            }
+            Connection::Kafka(kafka) => {
+                let mut topics: Vec<(&str, Vec<&[u8]>)> = Vec::new();
+                for (topic, payload) in batch {
+                    match topics.iter_mut().find(|(name, _)| *name == &**topic) {
+                        Some((_, payloads)) => payloads.push(payload),
+                        None => topics.push((topic, vec![payload])),
+                    }
+                }
+                for (topic, payloads) in topics {
+                    kafka.produce(topic, &payloads).await?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Answers the keep-alive pings the sink sent while the connection was idle.
+    fn keepalive(&mut self) -> anyhow::Result<()> {
+        match self {
+            Connection::Nats(stream) => answer_pings(stream),
+            Connection::Kafka(_) => Ok(()),
+        }
+    }
+}
+
+impl KafkaConnection {
+    /// Produces `payloads` to the partition of `topic`, returning once its leader has
+    /// taken them.
+    async fn produce(&mut self, topic: &str, payloads: &[&[u8]]) -> anyhow::Result<()> {
+        let leader = self.leader(topic).await?;
+        let stream = match self.streams.entry(leader) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let stream = tokio::time::timeout(IO_TIMEOUT, TcpStream::connect(entry.key()))
+                    .await
+                    .map_err(|_| anyhow::anyhow!("{}: timed out", entry.key()))?
+                    .map_err(|e| anyhow::anyhow!("{}: {}", entry.key(), e))?;
+                entry.insert(stream)
+            }
+        };
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let request = produce_request(self.correlation_id, topic, self.partition, payloads, unix_millis(SystemTime::now()) as i64);
+        let response = exchange(stream, &request).await?;
+        check_produce_response(&response, self.correlation_id)
+    }
+
+    /// The address of the broker leading the partition of `topic`, asking the cluster's
+    /// metadata for it the first time.
+    async fn leader(&mut self, topic: &str) -> anyhow::Result<String> {
+        if let Some(leader) = self.leaders.get(topic) {
+            return Ok(leader.clone());
+        }
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let response = exchange(&mut self.bootstrap, &metadata_request(self.correlation_id, topic)).await?;
+        let leader = partition_leader(&response, self.correlation_id, topic, self.partition)?;
+        self.leaders.insert(topic.to_string(), leader.clone());
+        Ok(leader)
+    }
+}
+
+/// Sends the size-prefixed `request` and reads the response, without its size.
+async fn exchange(stream: &mut TcpStream, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    stream.write_all(request).await?;
+    let size = stream.read_i32().await?;
+    if !(8..=1 << 20).contains(&size) {
+        anyhow::bail!("invalid Kafka response size {}", size);
+    }
+    let mut response = vec![0; size as usize];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// The NATS protocol lines publishing `batch`, followed by a PING: it flushes the batch
+/// through the server, which answers once it's taken.
+fn nats_batch(batch: &[Event]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for (subject, payload) in batch {
+        buffer.extend_from_slice(format!("PUB {} {}\r\n", subject, payload.len()).as_bytes());
+        buffer.extend_from_slice(payload);
+        buffer.extend_from_slice(b"\r\n");
+    }
+    buffer.extend_from_slice(b"PING\r\n");
+    buffer
+}
+
+/// Reads the server's INFO and sends CONNECT, checking the server accepts it.
+async fn nats_handshake(stream: &mut BufReader<TcpStream>, user: Option<&str>, password: Option<&str>) -> anyhow::Result<()> {
+    let mut info = String::new();
+    stream.read_line(&mut info).await?;
+    if !info.starts_with("INFO ") {
+        anyhow::bail!("not a NATS server");
+    }
+    if info.contains("\"tls_required\":true") {
+        anyhow::bail!("the NATS server requires TLS, which isn't supported");
+    }
+    let mut connect = serde_json::json!({
+        "verbose": false,
+        "pedantic": false,
+        "name": "rdns",
+        "lang": "rust",
+        "version": env!("CARGO_PKG_VERSION"),
+    });
+    match (user, password) {
+        (Some(user), Some(password)) => {
+            connect["user"] = user.into();
+            connect["pass"] = password.into();
+        }
+        (Some(token), None) => connect["auth_token"] = token.into(),
+        _ => {}
+    }
+    stream.get_mut().write_all(format!("CONNECT {}\r\nPING\r\n", connect).as_bytes()).await?;
+    read_pong(stream).await
+}
+
+/// Reads the server's lines up to its PONG, answering its PINGs, failing on an error.
+async fn read_pong(stream: &mut BufReader<TcpStream>) -> anyhow::Result<()> {
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 {
+            anyhow::bail!("connection closed by the NATS server");
+        }
+        match line.trim_end() {
+            "PONG" => return Ok(()),
+            "PING" => stream.get_mut().write_all(b"PONG\r\n").await?,
+            error if error.starts_with("-ERR") => anyhow::bail!("NATS server error: {}", error.trim_start_matches("-ERR").trim()),
+            _ => {}
+        }
+    }
+}
+
+/// Answers the PINGs the server sent since the connection was last used, without
+/// waiting for more.
+fn answer_pings(stream: &mut BufReader<TcpStream>) -> anyhow::Result<()> {
+    let mut received = Vec::new();
+    let mut buffer = [0u8; 1024];
+    loop {
+        match stream.get_ref().try_read(&mut buffer) {
+            Ok(0) => anyhow::bail!("connection closed by the NATS server"),
+            Ok(read) => received.extend_from_slice(&buffer[..read]),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    let received = String::from_utf8_lossy(&received);
+    if let Some(error) = received.lines().find(|line| line.starts_with("-ERR")) {
+        anyhow::bail!("NATS server error: {}", error.trim_start_matches("-ERR").trim());
+    }
+    let pings = received.lines().filter(|line| *line == "PING").count();
+    if pings > 0 {
+        stream.get_ref().try_write(&b"PONG\r\n".repeat(pings))?;
+    }
+    Ok(())
+}
+
+/// CRC-32C (Castagnoli) lookup table, as record batches are checksummed with.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x82f6_3b78 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| CRC32C_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// Appends `value` zigzag-encoded as a variable-length integer.
+fn put_varint(buffer: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn put_string(buffer: &mut Vec<u8>, value: &str) {
+    buffer.extend_from_slice(&(value.len() as i16).to_be_bytes());
+    buffer.extend_from_slice(value.as_bytes());
+}
+
+/// A v2 record batch holding `payloads` as the values of key-less records, all
+/// timestamped `now` in Unix milliseconds.
+fn record_batch(payloads: &[&[u8]], now: i64) -> Vec<u8> {
+    // Everything after the CRC, which covers it
+    let mut checked = Vec::new();
+    checked.extend_from_slice(&0i16.to_be_bytes()); // attributes: no compression
+    checked.extend_from_slice(&(payloads.len() as i32 - 1).to_be_bytes()); // last offset delta
+    checked.extend_from_slice(&now.to_be_bytes()); // first timestamp
+    checked.extend_from_slice(&now.to_be_bytes()); // max timestamp
+    checked.extend_from_slice(&(-1i64).to_be_bytes()); // producer id
+    checked.extend_from_slice(&(-1i16).to_be_bytes()); // producer epoch
+    checked.extend_from_slice(&(-1i32).to_be_bytes()); // base sequence
+    checked.extend_from_slice(&(payloads.len() as i32).to_be_bytes());
+    for (offset, payload) in payloads.iter().enumerate() {
+        let mut record = vec![0u8]; // attributes
+        put_varint(&mut record, 0); // timestamp delta
+        put_varint(&mut record, offset as i64);
+        put_varint(&mut record, -1); // no key
+        put_varint(&mut record, payload.len() as i64);
+        record.extend_from_slice(payload);
+        put_varint(&mut record, 0); // no headers
+        put_varint(&mut checked, record.len() as i64);
+        checked.extend_from_slice(&record);
+    }
+
+    let mut batch = Vec::with_capacity(checked.len() + 21);
+    batch.extend_from_slice(&0i64.to_be_bytes()); // base offset
+    batch.extend_from_slice(&((4 + 1 + 4 + checked.len()) as i32).to_be_bytes());
+    batch.extend_from_slice(&(-1i32).to_be_bytes()); // partition leader epoch
+    batch.push(2); // magic
+    batch.extend_from_slice(&crc32c(&checked).to_be_bytes());
+    batch.extend_from_slice(&checked);
+    batch
+}
+
+/// The header of a request of the API `key` at `version`.
+fn request_header(key: i16, version: i16, correlation_id: i32) -> Vec<u8> {
+    let mut request = Vec::with_capacity(64);
+    request.extend_from_slice(&key.to_be_bytes());
+    request.extend_from_slice(&version.to_be_bytes());
+    request.extend_from_slice(&correlation_id.to_be_bytes());
+    put_string(&mut request, "rdns");
+    request
+}
+
+/// `request` prefixed with its size.
+fn framed(request: Vec<u8>) -> Vec<u8> {
+    let mut framed = (request.len() as i32).to_be_bytes().to_vec();
+    framed.extend_from_slice(&request);
+    framed
+}
+
+/// A size-prefixed v1 Metadata request for `topic`.
+fn metadata_request(correlation_id: i32, topic: &str) -> Vec<u8> {
+    let mut request = request_header(3, 1, correlation_id);
+    request.extend_from_slice(&1i32.to_be_bytes());
+    put_string(&mut request, topic);
+    framed(request)
+}
+
+/// The address of the broker the Metadata `response`, without its size, names as the
+/// leader of `partition` of `topic`.
+fn partition_leader(response: &[u8], correlation_id: i32, topic: &str, partition: i32) -> anyhow::Result<String> {
+    let mut reader = Reader(response);
+    if reader.i32()? != correlation_id {
+        anyhow::bail!("Kafka response to another request");
+    }
+    let mut brokers = HashMap::new();
+    for _ in 0..reader.i32()? {
+        let node = reader.i32()?;
+        let host = reader.string()?;
+        let port = reader.i32()?;
+        reader.string()?; // rack
+        let address = match host.contains(':') {
+            true => format!("[{}]:{}", host, port),
+            false => format!("{}:{}", host, port),
+        };
+        brokers.insert(node, address);
+    }
+    reader.i32()?; // controller
+    for _ in 0..reader.i32()? {
+        let error = reader.i16()?;
+        let name = reader.string()?;
+        reader.take(1)?; // internal
+        let partitions = reader.i32()?;
+        if name != topic {
+            anyhow::bail!("Kafka metadata of another topic {}", name);
+        }
+        match error {
+            0 => {}
+            3 => anyhow::bail!("unknown Kafka topic {}", topic),
+            code => anyhow::bail!("Kafka error code {} for topic {}", code, topic),
+        }
+        for _ in 0..partitions {
+            reader.i16()?; // error, as of the partition's leader
+            let index = reader.i32()?;
+            let leader = reader.i32()?;
+            for _ in 0..2 {
+                // replicas and in-sync replicas
+                let nodes = reader.i32()?;
+                reader.take(4 * nodes.max(0) as usize)?;
+            }
+            if index != partition {
+                continue;
+            }
+            return match brokers.remove(&leader) {
+                Some(address) => Ok(address),
+                None if leader < 0 => anyhow::bail!("Kafka partition {} of {} has no leader", partition, topic),
+                None => anyhow::bail!("Kafka partition {} of {} is led by unknown broker {}", partition, topic, leader),
+            };
+        }
+    }
+    anyhow::bail!("unknown Kafka partition {} of {}", partition, topic)
+}
+
+/// A size-prefixed v3 Produce request of `payloads` to a partition of `topic`, waiting
+/// for the partition leader's acknowledgement.
+fn produce_request(correlation_id: i32, topic: &str, partition: i32, payloads: &[&[u8]], now: i64) -> Vec<u8> {
+    let batch = record_batch(payloads, now);
+    let mut request = request_header(0, 3, correlation_id);
+    request.extend_from_slice(&(-1i16).to_be_bytes()); // no transactional id
+    request.extend_from_slice(&1i16.to_be_bytes()); // acks: the leader's
+    request.extend_from_slice(&(IO_TIMEOUT.as_millis() as i32).to_be_bytes());
+    request.extend_from_slice(&1i32.to_be_bytes());
+    put_string(&mut request, topic);
+    request.extend_from_slice(&1i32.to_be_bytes());
+    request.extend_from_slice(&partition.to_be_bytes());
+    request.extend_from_slice(&(batch.len() as i32).to_be_bytes());
+    request.extend_from_slice(&batch);
+    framed(request)
+}
+
+/// Fails unless `response`, without its size, acknowledges the Produce request
+/// `correlation_id` for its partition.
+fn check_produce_response(response: &[u8], correlation_id: i32) -> anyhow::Result<()> {
+    let mut reader = Reader(response);
+    if reader.i32()? != correlation_id {
+        anyhow::bail!("Kafka response to another request");
+    }
+    reader.i32()?; // one topic
+    reader.string()?;
+    reader.i32()?; // one partition
+    reader.i32()?; // its index
+    match reader.i16()? {
+        0 => Ok(()),
+        3 => anyhow::bail!("unknown Kafka topic or partition"),
+        6 => anyhow::bail!("the Kafka broker doesn't lead the partition"),
+        code => anyhow::bail!("Kafka error code {}", code),
+    }
+}
+
+/// Reads the fields of a Kafka response in turn.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.0.len() < len {
+            anyhow::bail!("truncated Kafka response");
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn i16(&mut self) -> anyhow::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    /// A string, empty when null.
+    fn string(&mut self) -> anyhow::Result<String> {
+        let len = self.i16()?;
+        Ok(String::from_utf8_lossy(self.take(len.max(0) as usize)?).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::HEXLOWER;
+    use tokio::net::TcpListener;
+
+    /// A v1 Metadata response naming `brokers` and the leaders of `partitions` of `topic`.
+    fn metadata_response(correlation_id: i32, brokers: &[(i32, &str, i32)], topic: &str, error: i16, partitions: &[(i32, i32)]) -> Vec<u8> {
+        let mut response = correlation_id.to_be_bytes().to_vec();
+        response.extend_from_slice(&(brokers.len() as i32).to_be_bytes());
+        for (node, host, port) in brokers {
+            response.extend_from_slice(&node.to_be_bytes());
+            put_string(&mut response, host);
+            response.extend_from_slice(&port.to_be_bytes());
+            response.extend_from_slice(&(-1i16).to_be_bytes());
+        }
+        response.extend_from_slice(&0i32.to_be_bytes());
+        response.extend_from_slice(&1i32.to_be_bytes());
+        response.extend_from_slice(&error.to_be_bytes());
+        put_string(&mut response, topic);
+        response.push(0);
+        response.extend_from_slice(&(partitions.len() as i32).to_be_bytes());
+        for (index, leader) in partitions {
+            response.extend_from_slice(&0i16.to_be_bytes());
+            response.extend_from_slice(&index.to_be_bytes());
+            response.extend_from_slice(&leader.to_be_bytes());
+            for _ in 0..2 {
+                response.extend_from_slice(&1i32.to_be_bytes());
+                response.extend_from_slice(&leader.to_be_bytes());
+            }
+        }
+        response
+    }
+
+    /// A v3 Produce response for one partition of `topic` with `error`.
+    fn produce_response(correlation_id: i32, topic: &str, error: i16) -> Vec<u8> {
+        let mut response = correlation_id.to_be_bytes().to_vec();
+        response.extend_from_slice(&1i32.to_be_bytes());
+        put_string(&mut response, topic);
+        response.extend_from_slice(&1i32.to_be_bytes());
+        response.extend_from_slice(&0i32.to_be_bytes());
+        response.extend_from_slice(&error.to_be_bytes());
+        response.extend_from_slice(&[0; 20]); // base offset, log append time and throttle time
+        response
+    }
+
+    /// Reads a size-prefixed request from `stream`, returning its API key and correlation id.
+    async fn read_request(stream: &mut TcpStream) -> (i16, i32, Vec<u8>) {
+        let size = stream.read_i32().await.unwrap();
+        let mut request = vec![0; size as usize];
+        stream.read_exact(&mut request).await.unwrap();
+        let key = i16::from_be_bytes(request[0..2].try_into().unwrap());
+        let correlation_id = i32::from_be_bytes(request[4..8].try_into().unwrap());
+        (key, correlation_id, request)
+    }
+
+    #[test]
+    fn computes_crc32c_and_varints() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        for (value, encoded) in [(0, vec![0x00]), (-1, vec![0x01]), (1, vec![0x02]), (150, vec![0xac, 0x02]), (-65, vec![0x81, 0x01])] {
+            let mut buffer = Vec::new();
+            put_varint(&mut buffer, value);
+            assert_eq!(buffer, encoded, "{}", value);
+        }
+    }
+
+    #[test]
+    fn encodes_record_batches_and_produce_requests() {
+        let payloads: [&[u8]; 2] = [br#"{"a":1}"#, br#"{"b":2}"#];
+        assert_eq!(
+            HEXLOWER.encode(&record_batch(&payloads, 1_700_000_000_000)),
+            "00000000000000000000004dffffffff02840b357e0000000000010000018bcfe568000000018bcfe56800ffffffffffffffffffffffffffff000000021a000000010e7b2261223a317d001a000002010e7b2262223a327d00"
+        );
+
+        let request = produce_request(7, "rdns.records", 2, &payloads, 1_700_000_000_000);
+        assert_eq!(i32::from_be_bytes(request[0..4].try_into().unwrap()) as usize, request.len() - 4);
+        let mut reader = Reader(&request[4..]);
+        assert_eq!((reader.i16().unwrap(), reader.i16().unwrap(), reader.i32().unwrap()), (0, 3, 7));
+        assert_eq!(reader.string().unwrap(), "rdns");
+        assert_eq!(reader.i16().unwrap(), -1);
+        assert_eq!(reader.i16().unwrap(), 1);
+        reader.i32().unwrap();
+        assert_eq!(reader.i32().unwrap(), 1);
+        assert_eq!(reader.string().unwrap(), "rdns.records");
+        assert_eq!((reader.i32().unwrap(), reader.i32().unwrap()), (1, 2));
+        let size = reader.i32().unwrap() as usize;
+        assert_eq!(reader.take(size).unwrap(), record_batch(&payloads, 1_700_000_000_000));
+        assert!(reader.0.is_empty());
+    }
+
+    #[test]
+    fn finds_partition_leaders() {
+        let brokers = [(1, "kafka-1", 9092), (2, "2001:db8::2", 9093)];
+        let response = metadata_response(5, &brokers, "events", 0, &[(0, 1), (1, 2), (2, -1)]);
+        assert_eq!(partition_leader(&response, 5, "events", 0).unwrap(), "kafka-1:9092");
+        assert_eq!(partition_leader(&response, 5, "events", 1).unwrap(), "[2001:db8::2]:9093");
+        assert!(partition_leader(&response, 5, "events", 2).unwrap_err().to_string().contains("no leader"));
+        assert!(partition_leader(&response, 5, "events", 3).unwrap_err().to_string().contains("unknown Kafka partition"));
+        assert!(partition_leader(&response, 6, "events", 0).is_err());
+
+        let unknown = metadata_response(5, &brokers, "events", 3, &[]);
+        assert!(partition_leader(&unknown, 5, "events", 0).unwrap_err().to_string().contains("unknown Kafka topic"));
+        let orphaned = metadata_response(5, &brokers, "events", 0, &[(0, 9)]);
+        assert!(partition_leader(&orphaned, 5, "events", 0).unwrap_err().to_string().contains("unknown broker 9"));
+        assert!(partition_leader(&response[..response.len() - 3], 5, "events", 2).is_err());
+    }
+
+    #[test]
+    fn checks_produce_responses() {
+        assert!(check_produce_response(&produce_response(3, "events", 0), 3).is_ok());
+        assert!(check_produce_response(&produce_response(3, "events", 0), 4).is_err());
+        assert!(check_produce_response(&produce_response(3, "events", 6), 3)
+            .unwrap_err()
+            .to_string()
+            .contains("doesn't lead"));
+        assert!(check_produce_response(&produce_response(3, "events", 0)[..12], 3).is_err());
+    }
+
+    #[tokio::test]
+    async fn produces_to_partition_leaders() {
+        let bootstrap = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let leader = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let bootstrap_address = bootstrap.local_addr().unwrap().to_string();
+        let leader_port = leader.local_addr().unwrap().port() as i32;
+        let metadata = tokio::spawn(async move {
+            let (mut stream, _) = bootstrap.accept().await.unwrap();
+            let (key, correlation_id, _) = read_request(&mut stream).await;
+            assert_eq!(key, 3);
+            let response = metadata_response(correlation_id, &[(1, "127.0.0.1", leader_port)], "events", 0, &[(0, 1)]);
+            stream.write_all(&framed(response)).await.unwrap();
+        });
+        let produce = tokio::spawn(async move {
+            let (mut stream, _) = leader.accept().await.unwrap();
+            let (key, correlation_id, request) = read_request(&mut stream).await;
+            assert_eq!(key, 0);
+            stream.write_all(&framed(produce_response(correlation_id, "events", 0))).await.unwrap();
+            request
+        });
+
+        let sink = Sink::Kafka {
+            brokers: vec!["127.0.0.1:1".into(), bootstrap_address],
+            partition: 0,
+        };
+        let mut connection = Connection::open(&sink, &mut 0).await.unwrap();
+        connection.publish(&[("events".into(), b"{}".to_vec())]).await.unwrap();
+        metadata.await.unwrap();
+        let request = produce.await.unwrap();
+        assert!(request.windows(6).any(|window| window == b"events"));
+    }
+
+    #[tokio::test]
+    async fn publishes_to_nats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.get_mut().write_all(b"INFO {\"server_id\":\"test\"}\r\n").await.unwrap();
+            let mut connect = String::new();
+            stream.read_line(&mut connect).await.unwrap();
+            let mut ping = String::new();
+            stream.read_line(&mut ping).await.unwrap();
+            assert_eq!(ping, "PING\r\n");
+            stream.get_mut().write_all(b"PONG\r\n").await.unwrap();
+            let expected = b"PUB rdns.records 7\r\n{\"a\":1}\r\nPUB rdns.queries 2\r\n{}\r\nPING\r\n";
+            let mut published = vec![0; expected.len()];
+            stream.read_exact(&mut published).await.unwrap();
+            assert_eq!(published, expected);
+            stream.get_mut().write_all(b"PONG\r\n").await.unwrap();
+            connect
+        });
+
+        let sink = Sink::Nats {
+            address,
+            user: Some("secret".into()),
+            password: None,
+        };
+        let mut connection = Connection::open(&sink, &mut 0).await.unwrap();
+        let batch = [("rdns.records".into(), br#"{"a":1}"#.to_vec()), ("rdns.queries".into(), b"{}".to_vec())];
+        connection.publish(&batch).await.unwrap();
+        let connect = server.await.unwrap();
+        let connect: serde_json::Value = serde_json::from_str(connect.trim_start_matches("CONNECT ")).unwrap();
+        assert_eq!(connect["auth_token"], "secret");
+        assert!(connect.get("user").is_none());
+    }
+}
@@ -0,0 +1,150 @@
+//! Address family policies.
+//!
+//! Clients on networks whose IPv6 is broken wait for IPv6 connections to time out before
+//! falling back to IPv4, and some applications prefer one family regardless. With
+//! `address_family`, globally or for the clients of a view:
+//!
+//! - `"filter-aaaa"` answers AAAA queries for names with A records, as the hosted zones
+//!   or the forwarder have them, with no records, so that clients only connect over
+//!   IPv4, and leaves AAAA records out of the additional section. Names with AAAA
+//!   records alone are still answered with them.
+//! - `"prefer-ipv4"` and `"prefer-ipv6"` put the addresses of that family first where
+//!   answers mix them, e.g. the glue of the additional section, for clients that take the
+//!   first address.
+//! - `"both"`, the default, answers as the records are.
+
+use hickory_proto::rr::{LowerName, Record, RecordType};
+use hickory_server::authority::{Catalog, LookupOptions, MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{ResponseHandler, ResponseInfo};
+use std::fmt;
+use std::str::FromStr;
+use tonic::async_trait;
+
+use crate::roundrobin;
+
+/// How the addresses of each family are answered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// As the records are
+    #[default]
+    Both,
+    /// Without AAAA records for names with A records
+    FilterAaaa,
+    /// IPv4 addresses first
+    PreferIpv4,
+    /// IPv6 addresses first
+    PreferIpv6,
+}
+
+impl FromStr for AddressFamily {
+    type Err = anyhow::Error;
+
+    fn from_str(policy: &str) -> anyhow::Result<Self> {
+        match policy.to_ascii_lowercase().as_str() {
+            "both" => Ok(AddressFamily::Both),
+            "filter-aaaa" => Ok(AddressFamily::FilterAaaa),
+            "prefer-ipv4" => Ok(AddressFamily::PreferIpv4),
+            "prefer-ipv6" => Ok(AddressFamily::PreferIpv6),
+            _ => anyhow::bail!("unknown address_family {}, expected both, filter-aaaa, prefer-ipv4 or prefer-ipv6", policy),
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddressFamily::Both => "both",
+            AddressFamily::FilterAaaa => "filter-aaaa",
+            AddressFamily::PreferIpv4 => "prefer-ipv4",
+            AddressFamily::PreferIpv6 => "prefer-ipv6",
+        })
+    }
+}
+
+/// Whether an AAAA query for `name` is to be answered with no records under
+/// `family`: the name has A records.
+pub async fn filters_aaaa(family: AddressFamily, catalog: &Catalog, name: &LowerName) -> bool {
+    if family != AddressFamily::FilterAaaa {
+        return false;
+    }
+    let Some(authority) = catalog.find(name) else {
+        return false;
+    };
+    match authority.lookup(name, RecordType::A, LookupOptions::default()).await {
+        Ok(lookup) => lookup.iter().any(|record| record.record_type() == RecordType::A),
+        Err(_) => false,
+    }
+}
+
+fn is_address(record: &Record) -> bool {
+    matches!(record.record_type(), RecordType::A | RecordType::AAAA)
+}
+
+/// `records` with their addresses of type `preferred` moved ahead of the others, in the
+/// places the addresses held, so that CNAME chains and other records stay in place.
+fn prefer(records: &[Record], preferred: RecordType) -> Vec<&Record> {
+    let mut addresses: Vec<&Record> = records.iter().filter(|record| is_address(record)).collect();
+    addresses.sort_by_key(|record| record.record_type() != preferred);
+    let mut addresses = addresses.into_iter();
+    records
+        .iter()
+        .map(|record| match is_address(record) {
+            true => addresses.next().unwrap_or(record),
+            false => record,
+        })
+        .collect()
+}
+
+/// Response handler that applies an address family policy to the answer and additional
+/// sections.
+#[derive(Clone)]
+pub struct FamilyResponseHandler<R> {
+    inner: R,
+    family: AddressFamily,
+}
+
+impl<R> FamilyResponseHandler<R> {
+    pub fn new(inner: R, family: AddressFamily) -> Self {
+        Self { inner, family }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for FamilyResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let preferred = match self.family {
+            AddressFamily::Both => return self.inner.send_response(response).await,
+            AddressFamily::FilterAaaa | AddressFamily::PreferIpv4 => RecordType::A,
+            AddressFamily::PreferIpv6 => RecordType::AAAA,
+        };
+        let message = roundrobin::reread(response)?;
+        let answers = prefer(message.answers(), preferred);
+        let additionals: Vec<&Record> = prefer(message.additionals(), preferred)
+            .into_iter()
+            .filter(|record| self.family != AddressFamily::FilterAaaa || record.record_type() != RecordType::AAAA)
+            .collect();
+
+        let mut builder = MessageResponseBuilder::from_message_request(&message);
+        if let Some(edns) = message.edns() {
+            builder.edns(edns.clone());
+        }
+        let response = builder.build(
+            *message.header(),
+            answers,
+            message.name_servers(),
+            [],
+            additionals.into_iter().chain(message.sig0()),
+        );
+        self.inner.send_response(response).await
+    }
+}
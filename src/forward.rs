@@ -0,0 +1,647 @@
+//! Forwarding of queries for names outside the hosted zones.
+//!
+//! When configured, a forwarding authority is registered for the root zone. The catalog
+//! always picks the most specific zone for a query, so hosted zones are still answered
+//! authoritatively and only the remaining names are resolved through the upstreams.
+//! Upstream answers are cached in a `ResponseCache` shared with the control API, and
+//! served stale from it when configured and no upstream can be reached. Answers hit
+//! often are prefetched, refreshed in the background shortly before they expire.
+//! Concurrent queries for the same uncached name and type are coalesced into a single
+//! upstream lookup whose answer they all share.
+//! Upstreams may be queried over TLS or HTTPS, and are retried, hedged and skipped while
+//! down as configured (see `upstream`). rdns doesn't resolve
+//! iteratively itself: upstreams are asked for the full name, and minimizing the names
+//! revealed to authoritative servers (RFC 9156) is left to them.
+//!
+//! Rules send the names under a domain, e.g. `corp.internal`, to upstreams of their own.
+//! They are tried in order, those added through the `ForwardingRules` service first and
+//! then those of `[[dns.forward.rules]]`, and the first whose domain holds the name wins;
+//! names no rule matches go to `upstreams`, and are refused without any. Rules added at
+//! runtime are persisted with the state, and may override a rule of the config for the
+//! same domain. The cache is emptied whenever the rules change.
+//!
+//! A query whose Client Subnet option is passed on (see `ecs`) is only sent to the
+//! plain upstreams, and its answer bypasses the cache. With `[dns.forward.dnssec]`
+//! configured, the upstreams are asked for signatures so that their answers can be
+//! validated (see `validator`), and answers holding private addresses are screened with
+//! `[dns.forward.rebind]` (see `rebind`).
+
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::{LowerName, Name, RecordType};
+use hickory_server::authority::{Authority, LookupError, LookupOptions, MessageRequest, UpdateResult, ZoneType};
+use hickory_server::server::RequestInfo;
+use hickory_server::store::forwarder::ForwardLookup;
+use rustls::ClientConfig;
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tonic::async_trait;
+
+use crate::cache::{Answer, CacheOptions, ResponseCache};
+use crate::ecs::{self, Subnet};
+use crate::error::RdnsError;
+use crate::metrics::Metrics;
+use crate::rebind::RebindOptions;
+use crate::settings::{ForwardRuleSettings, ForwardSettings};
+use crate::upstream::{self, ExchangeOptions, HealthOptions, Selection, Tuning, Upstream, UpstreamHealth, UpstreamStats, Upstreams};
+use crate::validator::{self, ValidationOptions, Validator, Verdict};
+
+/// Config options for forwarding
+pub struct ForwardOptions {
+    /// Upstreams of the names no rule matches
+    pub upstreams: Vec<Upstream>,
+    /// Rules of the config, in the order they are matched
+    pub rules: Vec<ForwardRule>,
+    /// TLS config of the encrypted upstreams, those of the rules included.
+    pub tls: Arc<ClientConfig>,
+    /// DNSSEC validation of the answers, of every route alike
+    pub validation: Option<Arc<ValidationOptions>>,
+    /// DNS rebinding protection of the answers, of every route alike
+    pub rebind: Option<Arc<RebindOptions>>,
+    pub cache: CacheOptions,
+    pub exchange: ExchangeOptions,
+}
+
+impl TryFrom<ForwardSettings> for ForwardOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ForwardSettings) -> anyhow::Result<Self> {
+        let upstreams = parse_upstreams(&cfg.upstreams)?;
+        let rules = cfg
+            .rules
+            .into_iter()
+            .map(|rule| ForwardRule::new(ForwardRuleEntry::from(rule)))
+            .collect::<anyhow::Result<Vec<ForwardRule>>>()?;
+        if upstreams.is_empty() && rules.is_empty() {
+            anyhow::bail!("forwarding requires at least one upstream or rule");
+        }
+        let mut domains = HashSet::new();
+        for rule in &rules {
+            if !domains.insert(&rule.domain) {
+                anyhow::bail!("dns.forward.rules has more than one rule for {}", rule.domain);
+            }
+        }
+        let tuning = |timeout_ms: u64, retries| {
+            if timeout_ms == 0 {
+                anyhow::bail!("dns.forward.timeout_ms must be at least 1");
+            }
+            Ok(Tuning {
+                timeout: Duration::from_millis(timeout_ms),
+                retries,
+            })
+        };
+        let default_tuning = tuning(cfg.timeout_ms, cfg.retries)?;
+        let overrides = cfg
+            .overrides
+            .iter()
+            .map(|(upstream, overrides)| {
+                let overridden = upstream
+                    .parse()
+                    .map_err(|e| anyhow::anyhow!("invalid dns.forward.overrides upstream {}: {}", upstream, e))?;
+                let tuning = tuning(overrides.timeout_ms.unwrap_or(cfg.timeout_ms), overrides.retries.unwrap_or(cfg.retries))?;
+                Ok((overridden, tuning))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if cfg.hedge_ms == Some(0) {
+            anyhow::bail!("dns.forward.hedge_ms must be at least 1");
+        }
+        if cfg.health.max_failures == 0 {
+            anyhow::bail!("dns.forward.health.max_failures must be at least 1");
+        }
+        let selection = match cfg.selection.as_str() {
+            "ordered" => Selection::Ordered,
+            "fastest" if cfg.probe_secs == 0 => anyhow::bail!("dns.forward.probe_secs must be at least 1"),
+            "fastest" => Selection::Fastest {
+                probe_every: Duration::from_secs(cfg.probe_secs),
+            },
+            other => anyhow::bail!("invalid dns.forward.selection {}, expected ordered or fastest", other),
+        };
+        Ok(ForwardOptions {
+            upstreams,
+            rules,
+            tls: upstream::client_config(cfg.tls_ca_file.as_deref().map(Path::new), &cfg.tls_pins)?,
+            validation: cfg.dnssec.map(ValidationOptions::try_from).transpose()?.map(Arc::new),
+            rebind: cfg.rebind.map(RebindOptions::try_from).transpose()?.map(Arc::new),
+            cache: CacheOptions::from(cfg.cache),
+            exchange: ExchangeOptions {
+                tuning: default_tuning,
+                overrides,
+                hedge_after: cfg.hedge_ms.map(Duration::from_millis),
+                randomize_case: cfg.randomize_case,
+                health: HealthOptions {
+                    max_failures: cfg.health.max_failures,
+                    down_for: Duration::from_secs(cfg.health.down_secs),
+                },
+                selection,
+            },
+        })
+    }
+}
+
+impl ForwardOptions {
+    /// Builds an authority for the root zone that resolves every query through the route
+    /// `rules` give it, caching answers in `cache`.
+    pub fn authority(&self, rules: Arc<ForwardRules>, cache: Arc<ResponseCache>, metrics: Arc<Metrics>) -> Forwarder {
+        Forwarder {
+            origin: LowerName::from(Name::root()),
+            rules,
+            cache,
+            metrics,
+            pending: Arc::default(),
+        }
+    }
+}
+
+fn parse_upstreams(upstreams: &[String]) -> anyhow::Result<Vec<Upstream>> {
+    upstreams.iter().map(|upstream| upstream.parse()).collect()
+}
+
+/// A forwarding rule, as configured or added through the control API and persisted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ForwardRuleEntry {
+    /// Domain whose names, its own included, are forwarded to `upstreams`
+    pub domain: String,
+    pub upstreams: Vec<String>,
+}
+
+impl From<ForwardRuleSettings> for ForwardRuleEntry {
+    fn from(cfg: ForwardRuleSettings) -> Self {
+        ForwardRuleEntry {
+            domain: cfg.domain,
+            upstreams: cfg.upstreams,
+        }
+    }
+}
+
+/// A forwarding rule as listed through the control API.
+#[derive(Debug, Clone)]
+pub struct ForwardRuleInfo {
+    pub entry: ForwardRuleEntry,
+    /// Whether the rule comes from the config rather than `AddForwardingRule`
+    pub configured: bool,
+}
+
+/// A forwarding rule with its domain and upstreams parsed.
+#[derive(Debug, Clone)]
+pub struct ForwardRule {
+    /// The entry with its domain in canonical form: lower-cased and fully qualified
+    pub entry: ForwardRuleEntry,
+    pub domain: LowerName,
+    pub upstreams: Vec<Upstream>,
+}
+
+impl ForwardRule {
+    pub fn new(mut entry: ForwardRuleEntry) -> anyhow::Result<Self> {
+        let mut domain = Name::from_utf8(&entry.domain).map_err(|e| anyhow::anyhow!("invalid forwarding rule domain {}: {}", entry.domain, e))?;
+        domain.set_fqdn(true);
+        let domain = LowerName::new(&domain);
+        let upstreams = parse_upstreams(&entry.upstreams)?;
+        if upstreams.is_empty() {
+            anyhow::bail!("forwarding rule for {} has no upstreams", domain);
+        }
+        entry.domain = domain.to_string();
+        Ok(ForwardRule { entry, domain, upstreams })
+    }
+
+    /// The rule of the names no other rule matches.
+    fn fallback(upstreams: Vec<Upstream>) -> Self {
+        ForwardRule {
+            entry: ForwardRuleEntry {
+                domain: ".".into(),
+                upstreams: Vec::new(),
+            },
+            domain: LowerName::from(Name::root()),
+            upstreams,
+        }
+    }
+}
+
+/// What the routes of every rule share.
+#[derive(Clone)]
+struct RouteOptions {
+    tls: Arc<ClientConfig>,
+    validation: Option<Arc<ValidationOptions>>,
+    rebind: Option<Arc<RebindOptions>>,
+    exchange: ExchangeOptions,
+    health: Arc<UpstreamHealth>,
+}
+
+impl RouteOptions {
+    fn new(forward: &ForwardOptions, health: Arc<UpstreamHealth>) -> Self {
+        RouteOptions {
+            tls: forward.tls.clone(),
+            validation: forward.validation.clone(),
+            rebind: forward.rebind.clone(),
+            exchange: forward.exchange.clone(),
+            health,
+        }
+    }
+}
+
+/// A rule along with its upstreams.
+struct Route {
+    rule: ForwardRule,
+    upstreams: Arc<Upstreams>,
+    /// Validator every query is resolved through, if validating
+    validator: Option<Validator>,
+    /// Screening of the answers for private addresses, if enabled
+    rebind: Option<Arc<RebindOptions>>,
+}
+
+impl Route {
+    fn new(rule: ForwardRule, options: &RouteOptions) -> anyhow::Result<Self> {
+        let upstreams = Arc::new(Upstreams::new(&rule.upstreams, &options.exchange, options.health.clone(), options.tls.clone()));
+        let validator = options
+            .validation
+            .clone()
+            .map(|validation| Validator::new(validation, upstreams.clone()));
+        Ok(Route {
+            rule,
+            upstreams,
+            validator,
+            rebind: options.rebind.clone(),
+        })
+    }
+
+    /// `answer` to the query for `name` as it may be passed on, see `rebind`.
+    fn screen(&self, name: &LowerName, answer: Answer) -> Result<Answer, LookupError> {
+        match &self.rebind {
+            Some(rebind) => rebind.screen(name, answer),
+            None => Ok(answer),
+        }
+    }
+}
+
+/// The forwarding rules of the config and those added through the control API, shared
+/// between the forwarder and the state.
+#[derive(Default)]
+pub struct ForwardRules {
+    /// Rules of the config, replaced when it is reloaded
+    configured: RwLock<Vec<Arc<Route>>>,
+    /// Rules added through the control API, matched before those of the config
+    created: RwLock<Vec<Arc<Route>>>,
+    /// Route of the names no rule matches, to the upstreams of `[dns.forward]`
+    fallback: RwLock<Option<Arc<Route>>>,
+    /// Options of the routes; unset while forwarding is disabled.
+    options: RwLock<Option<RouteOptions>>,
+    /// Health of the upstreams, kept when the routes are rebuilt
+    health: Arc<UpstreamHealth>,
+}
+
+impl ForwardRules {
+    /// Replaces the rules of the config with those of `forward`, rebuilding those added
+    /// through the control API with its TLS config and validation, or disables the rules
+    /// when `forward` is unset.
+    pub fn set_configured(&self, forward: Option<&ForwardOptions>) -> anyhow::Result<()> {
+        let Some(forward) = forward else {
+            self.configured.write().unwrap().clear();
+            *self.fallback.write().unwrap() = None;
+            *self.options.write().unwrap() = None;
+            return Ok(());
+        };
+        let options = RouteOptions::new(forward, self.health.clone());
+        let fallback = match forward.upstreams.is_empty() {
+            true => None,
+            false => Some(Arc::new(Route::new(ForwardRule::fallback(forward.upstreams.clone()), &options)?)),
+        };
+        let configured = forward
+            .rules
+            .iter()
+            .map(|rule| Route::new(rule.clone(), &options).map(Arc::new))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut created = self.created.write().unwrap();
+        *created = created
+            .iter()
+            .map(|route| Route::new(route.rule.clone(), &options).map(Arc::new))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        *self.configured.write().unwrap() = configured;
+        *self.fallback.write().unwrap() = fallback;
+        *self.options.write().unwrap() = Some(options);
+        Ok(())
+    }
+
+    /// Adds a rule through the control API, matched after those added before it,
+    /// failing with `Conflict` if one was already added for its domain.
+    pub fn insert(&self, rule: ForwardRule) -> Result<(), RdnsError> {
+        let Some(options) = self.options.read().unwrap().clone() else {
+            return Err(RdnsError::NotEnabled("forwarding is not enabled".into()));
+        };
+        let mut created = self.created.write().unwrap();
+        if created.iter().any(|route| route.rule.domain == rule.domain) {
+            return Err(RdnsError::Conflict(format!("a forwarding rule for {} already exists", rule.domain)));
+        }
+        let route = Route::new(rule, &options).map_err(RdnsError::Other)?;
+        created.push(Arc::new(route));
+        Ok(())
+    }
+
+    /// Removes a rule added through the control API; those of the config can't be.
+    pub fn remove(&self, domain: &LowerName) -> Result<(), RdnsError> {
+        let mut created = self.created.write().unwrap();
+        if let Some(index) = created.iter().position(|route| route.rule.domain == *domain) {
+            created.remove(index);
+            return Ok(());
+        }
+        match self.configured.read().unwrap().iter().any(|route| route.rule.domain == *domain) {
+            true => Err(RdnsError::InvalidArgument(format!("forwarding rule for {} is configured in Config.toml", domain))),
+            false => Err(RdnsError::RecordNotFound(format!("no forwarding rule for {}", domain))),
+        }
+    }
+
+    /// Drops the rules added through the control API.
+    pub fn clear(&self) {
+        self.created.write().unwrap().clear();
+    }
+
+    /// The rules added through the control API, in the order they are matched.
+    pub fn created(&self) -> Vec<ForwardRuleEntry> {
+        self.created.read().unwrap().iter().map(|route| route.rule.entry.clone()).collect()
+    }
+
+    /// Every rule, in the order they are matched.
+    pub fn list(&self) -> Vec<ForwardRuleInfo> {
+        let info = |route: &Arc<Route>, configured| ForwardRuleInfo {
+            entry: route.rule.entry.clone(),
+            configured,
+        };
+        let mut rules: Vec<ForwardRuleInfo> = self.created.read().unwrap().iter().map(|route| info(route, false)).collect();
+        rules.extend(self.configured.read().unwrap().iter().map(|route| info(route, true)));
+        rules
+    }
+
+    /// The response times and failures of the upstreams of every rule and the fallback,
+    /// those not queried yet left out.
+    pub fn upstream_stats(&self) -> Vec<UpstreamStats> {
+        let mut routes: Vec<Arc<Route>> = self.fallback.read().unwrap().iter().cloned().collect();
+        routes.extend(self.created.read().unwrap().iter().cloned());
+        routes.extend(self.configured.read().unwrap().iter().cloned());
+        let upstreams: Vec<Upstream> = routes.iter().flat_map(|route| route.upstreams.upstreams().cloned()).collect();
+        self.health.stats(&upstreams)
+    }
+
+    /// Whether queries for `name` are forwarded, rather than refused as no rule matches
+    /// it and there are no upstreams for the other names.
+    pub fn forwards(&self, name: &LowerName) -> bool {
+        self.route(name).is_some()
+    }
+
+    /// The route of the first rule whose domain holds `name`, or the fallback.
+    fn route(&self, name: &LowerName) -> Option<Arc<Route>> {
+        let matches = |route: &&Arc<Route>| route.rule.domain.zone_of(name);
+        if let Some(route) = self.created.read().unwrap().iter().find(matches) {
+            return Some(route.clone());
+        }
+        if let Some(route) = self.configured.read().unwrap().iter().find(matches) {
+            return Some(route.clone());
+        }
+        self.fallback.read().unwrap().clone()
+    }
+}
+
+/// Payload size advertised in queries sent upstream, as recommended by DNS Flag Day 2020.
+pub const MAX_PAYLOAD: u16 = 1232;
+
+/// Sends `query` to `upstream` over UDP, retrying over TCP if the answer is truncated.
+/// Answers must echo the question of `query`, the case of its name included.
+pub async fn exchange(upstream: SocketAddr, query: &Message) -> std::io::Result<Message> {
+    let bytes = query.to_vec().map_err(std::io::Error::other)?;
+    let local: SocketAddr = if upstream.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(upstream).await?;
+    socket.send(&bytes).await?;
+    let mut buffer = vec![0; usize::from(MAX_PAYLOAD)];
+    let response = loop {
+        let len = socket.recv(&mut buffer).await?;
+        // Stray datagrams, e.g. late answers to an earlier query, are skipped, as are
+        // spoofed answers that guessed the ID but not the case of the name
+        match Message::from_vec(&buffer[..len]) {
+            Ok(response) if response.id() == query.id() && echoes_question(query, &response) => break response,
+            Ok(response) if response.id() == query.id() => {
+                crate::debug!("Ignored an answer from {} whose question doesn't match the query", upstream);
+                continue;
+            }
+            _ => continue,
+        }
+    };
+    if !response.truncated() {
+        return Ok(response);
+    }
+
+    let mut stream = TcpStream::connect(upstream).await?;
+    let len = u16::try_from(bytes.len()).map_err(std::io::Error::other)?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(&bytes).await?;
+    let len = stream.read_u16().await?;
+    let mut buffer = vec![0; usize::from(len)];
+    stream.read_exact(&mut buffer).await?;
+    let response = Message::from_vec(&buffer).map_err(std::io::Error::other)?;
+    if !echoes_question(query, &response) {
+        return Err(std::io::Error::other(format!("answer from {} doesn't match the query", upstream)));
+    }
+    Ok(response)
+}
+
+/// Whether `response` holds the question of `query`, with the case of its name.
+fn echoes_question(query: &Message, response: &Message) -> bool {
+    query.queries().len() == response.queries().len()
+        && query.queries().iter().zip(response.queries()).all(|(asked, echoed)| {
+            asked.name().eq_case(echoed.name()) && asked.query_type() == echoed.query_type() && asked.query_class() == echoed.query_class()
+        })
+}
+
+/// Forwarding authority that answers from the response cache when it can.
+pub struct Forwarder {
+    origin: LowerName,
+    rules: Arc<ForwardRules>,
+    cache: Arc<ResponseCache>,
+    metrics: Arc<Metrics>,
+    /// Upstream lookups in flight, shared by the queries made while they are
+    pending: Arc<Mutex<HashMap<(LowerName, RecordType), PendingLookup>>>,
+}
+
+/// An upstream lookup that may be awaited by several queries, its error shared too.
+type PendingLookup = Shared<BoxFuture<'static, Result<(Answer, bool), Arc<LookupError>>>>;
+
+#[async_trait]
+impl Authority for Forwarder {
+    type Lookup = ForwardLookup;
+
+    fn zone_type(&self) -> ZoneType {
+        ZoneType::Forward
+    }
+
+    fn is_axfr_allowed(&self) -> bool {
+        false
+    }
+
+    async fn update(&self, _update: &MessageRequest) -> UpdateResult<bool> {
+        Err(ResponseCode::NotImp)
+    }
+
+    fn origin(&self) -> &LowerName {
+        &self.origin
+    }
+
+    async fn lookup(
+        &self,
+        name: &LowerName,
+        rtype: RecordType,
+        _lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        let cached = self.cache.get(name, rtype);
+        self.metrics.observe_cache(cached.is_some());
+        if let Some((answer, secure)) = cached {
+            crate::debug!("Answered {} {} from the cache", name, rtype);
+            validator::note(if secure { Verdict::Secure } else { Verdict::Insecure });
+            if self.cache.claim_prefetch(name, rtype) {
+                self.prefetch(name, rtype);
+            }
+            return answer.lookup().map(ForwardLookup);
+        }
+        let route = self.route(name)?;
+        let result = self.coalesced(route, name, rtype).await;
+        self.metrics.set_cache_negative_entries(self.cache.usage().negative);
+        match result.map_err(|e| duplicate(&e)) {
+            Ok((answer, secure)) => {
+                validator::note(if secure { Verdict::Secure } else { Verdict::Insecure });
+                answer.lookup().map(ForwardLookup)
+            }
+            Err(LookupError::Io(e)) => match self.cache.get_stale(name, rtype) {
+                Some((answer, secure)) => {
+                    crate::debug!("Answered {} {} from the cache with a stale answer: {}", name, rtype, e);
+                    validator::note(if secure { Verdict::Secure } else { Verdict::Insecure });
+                    answer.lookup().map(ForwardLookup)
+                }
+                None => Err(LookupError::Io(e)),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn search(
+        &self,
+        request_info: RequestInfo<'_>,
+        lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        let (name, rtype) = (request_info.query.name(), request_info.query.query_type());
+        if let Some(subnet) = ecs::forwarded_subnet() {
+            let route = self.route(name)?;
+            if let Some(validator) = &route.validator {
+                let (answer, _) = validator.lookup(name, rtype, Some(&subnet)).await?;
+                return route.screen(name, answer)?.lookup().map(ForwardLookup);
+            }
+            if route.upstreams.has_plain() {
+                let answer = lookup_upstream(&route.upstreams, name, rtype, Some(&subnet)).await?;
+                return route.screen(name, answer)?.lookup().map(ForwardLookup);
+            }
+        }
+        self.lookup(name, rtype, lookup_options).await
+    }
+
+    async fn get_nsec_records(
+        &self,
+        _name: &LowerName,
+        _lookup_options: LookupOptions,
+    ) -> Result<Self::Lookup, LookupError> {
+        Err(LookupError::from(std::io::Error::other("NSEC records aren't looked up through the forwarder")))
+    }
+}
+
+impl Forwarder {
+    /// The route queries for `name` are forwarded along.
+    fn route(&self, name: &LowerName) -> Result<Arc<Route>, LookupError> {
+        self.rules.route(name).ok_or(LookupError::from(ResponseCode::Refused))
+    }
+
+    /// Resolves a query for `name` and `rtype` along `route` and caches the answer,
+    /// joining the lookup already in flight for them if there is one.
+    async fn coalesced(&self, route: Arc<Route>, name: &LowerName, rtype: RecordType) -> Result<(Answer, bool), Arc<LookupError>> {
+        let key = (name.clone(), rtype);
+        let lookup = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending.get(&key) {
+                Some(lookup) => lookup.clone(),
+                None => {
+                    let (cache, all, done) = (self.cache.clone(), self.pending.clone(), key.clone());
+                    let lookup = async move {
+                        let (name, rtype) = &done;
+                        let result = resolve(&route, name, *rtype).await;
+                        if let Ok((answer, secure)) = &result {
+                            cache.insert(name, *rtype, answer.clone(), *secure);
+                        }
+                        all.lock().unwrap().remove(&done);
+                        result.map_err(Arc::new)
+                    }
+                    .boxed()
+                    .shared();
+                    pending.insert(key, lookup.clone());
+                    lookup
+                }
+            }
+        };
+        lookup.await
+    }
+
+    /// Refreshes the cached answer for `name` and `rtype` in the background.
+    fn prefetch(&self, name: &LowerName, rtype: RecordType) {
+        let Some(route) = self.rules.route(name) else {
+            return;
+        };
+        let (cache, name) = (self.cache.clone(), name.clone());
+        tokio::spawn(async move {
+            if let Ok((answer, secure)) = resolve(&route, &name, rtype).await {
+                cache.insert(&name, rtype, answer, secure);
+            }
+        });
+    }
+}
+
+/// A copy of `error`, for each of the queries sharing a lookup.
+fn duplicate(error: &LookupError) -> LookupError {
+    match error {
+        LookupError::NameExists => LookupError::NameExists,
+        LookupError::ResponseCode(code) => LookupError::ResponseCode(*code),
+        LookupError::Io(e) => LookupError::Io(std::io::Error::new(e.kind(), e.to_string())),
+        e => LookupError::Io(std::io::Error::other(e.to_string())),
+    }
+}
+
+/// Resolves a query along `route`, returning whether the answer was validated as secure.
+async fn resolve(route: &Route, name: &LowerName, rtype: RecordType) -> Result<(Answer, bool), LookupError> {
+    let (answer, secure) = match &route.validator {
+        Some(validator) => {
+            let (answer, verdict) = validator.lookup(name, rtype, None).await?;
+            (answer, verdict == Verdict::Secure)
+        }
+        None => (lookup_upstream(&route.upstreams, name, rtype, None).await?, false),
+    };
+    Ok((route.screen(name, answer)?, secure))
+}
+
+/// Resolves a query through `upstreams`, passing `subnet` on in a Client Subnet option
+/// to the plain ones if given.
+async fn lookup_upstream(upstreams: &Arc<Upstreams>, name: &LowerName, rtype: RecordType, subnet: Option<&Subnet>) -> Result<Answer, LookupError> {
+    let question = Query::query(Name::from(name), rtype);
+    let mut query = Message::new();
+    query
+        .set_id(rand::random())
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(question.clone());
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_PAYLOAD);
+    if let Some(subnet) = subnet {
+        edns.options_mut().insert(subnet.option(0));
+    }
+    query.set_edns(edns);
+
+    let response = upstreams.query(&query, subnet.is_some()).await?;
+    Answer::of(question, &response)
+}
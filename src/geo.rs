@@ -0,0 +1,169 @@
+//! GeoDNS answers based on the client's location.
+//!
+//! A geo record gives a name and type different values per region. The client is located
+//! in a MaxMind country or city database by the address in its EDNS Client Subnet option
+//! (see `ecs`), or by the source address of the query when it carries none. The most
+//! specific region with values wins: the country code (e.g. `DE`), then a configured
+//! region listing the country, then the continent code (e.g. `EU`), then a configured
+//! region listing the continent. Those values are answered directly instead of through
+//! the catalog; a client that matches no region, or that the database doesn't know, gets
+//! the zone's own RRset. Geo answers are not DNSSEC-signed.
+
+use hickory_proto::rr::rdata::opt::EdnsOption;
+use hickory_proto::rr::{LowerName, Record, RecordType, RrKey};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use maxminddb::{geoip2, Reader};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use crate::dns;
+use crate::ecs::{self, Subnet};
+use crate::settings::GeoSettings;
+
+/// Config options for GeoDNS
+pub struct GeoOptions {
+    /// MaxMind database clients are located in.
+    pub database: PathBuf,
+    /// Named regions and the upper-cased country and continent codes each covers.
+    pub regions: Vec<(String, Vec<String>)>,
+}
+
+impl TryFrom<GeoSettings> for GeoOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: GeoSettings) -> anyhow::Result<Self> {
+        let mut regions = Vec::with_capacity(cfg.regions.len());
+        for (name, codes) in cfg.regions {
+            if codes.is_empty() {
+                anyhow::bail!("geo region {} lists no country or continent codes", name);
+            }
+            let codes = codes.iter().map(|code| code.to_ascii_uppercase()).collect();
+            regions.push((name.to_ascii_uppercase(), codes));
+        }
+        Ok(GeoOptions {
+            database: PathBuf::from(cfg.database),
+            regions,
+        })
+    }
+}
+
+/// A geo record in its textual form, as managed through the control API and persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoEntry {
+    pub name: String,
+    pub record_type: String,
+    pub ttl: u32,
+    /// Values in zone file syntax by region: a country code, continent code or
+    /// configured region name
+    pub regions: BTreeMap<String, Vec<String>>,
+}
+
+/// Geo records by the name and type they answer for, shared with the request handler.
+pub type GeoTable = RwLock<HashMap<RrKey, Arc<GeoRecord>>>;
+
+/// A validated geo record, with the parsed values of each region.
+pub struct GeoRecord {
+    pub entry: GeoEntry,
+    key: RrKey,
+    /// Records by upper-cased region name.
+    regions: HashMap<String, Vec<Record>>,
+}
+
+impl GeoRecord {
+    /// Builds a geo record from `entry` and the records parsed from its values, by region.
+    pub fn new(mut entry: GeoEntry, regions: HashMap<String, Vec<Record>>) -> anyhow::Result<Self> {
+        if let Some((region, _)) = regions.iter().find(|(_, records)| records.is_empty()) {
+            anyhow::bail!("geo region {} has no values", region);
+        }
+        let Some(first) = regions.values().flatten().next() else {
+            anyhow::bail!("a geo record needs values for at least one region");
+        };
+        if matches!(first.record_type(), RecordType::SOA | RecordType::NS | RecordType::CNAME) {
+            anyhow::bail!("{} records can't vary by region", first.record_type());
+        }
+        if first.name().is_wildcard() {
+            anyhow::bail!("geo records can't be wildcards");
+        }
+        entry.name = first.name().to_ascii();
+        entry.record_type = first.record_type().to_string();
+        let key = RrKey::new(LowerName::new(first.name()), first.record_type());
+        let regions = regions
+            .into_iter()
+            .map(|(region, records)| (region.to_ascii_uppercase(), records))
+            .collect();
+        Ok(Self { entry, key, regions })
+    }
+
+    /// The name and type the record answers for.
+    pub fn key(&self) -> RrKey {
+        self.key.clone()
+    }
+}
+
+/// Locates clients in the MaxMind database.
+pub struct GeoLocator {
+    reader: Reader<Vec<u8>>,
+    regions: Vec<(String, Vec<String>)>,
+}
+
+/// Values picked for a client, with the Client Subnet option to send back.
+pub struct GeoAnswer<'r> {
+    records: &'r [Record],
+    subnet: Option<EdnsOption>,
+}
+
+impl GeoLocator {
+    /// Opens the database `options` names.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the database can't be read.
+    pub fn open(options: &GeoOptions) -> anyhow::Result<Self> {
+        let reader = Reader::open_readfile(&options.database)
+            .map_err(|e| anyhow::anyhow!("can't open geo database {}: {}", options.database.display(), e))?;
+        Ok(Self {
+            reader,
+            regions: options.regions.clone(),
+        })
+    }
+
+    /// Region names the client at `address` belongs to, most specific first, and the
+    /// prefix length of the database network it was found in.
+    fn locate(&self, address: IpAddr) -> Option<(Vec<String>, usize)> {
+        let (location, prefix) = self.reader.lookup_prefix::<geoip2::Country>(address).ok()?;
+        let country = location.country.and_then(|country| country.iso_code).map(str::to_ascii_uppercase);
+        let continent = location.continent.and_then(|continent| continent.code).map(str::to_ascii_uppercase);
+
+        let mut names = Vec::new();
+        for code in [country, continent].into_iter().flatten() {
+            let covering = self.regions.iter().filter(|(_, codes)| codes.contains(&code));
+            let covering: Vec<String> = covering.map(|(name, _)| name.clone()).collect();
+            names.push(code);
+            names.extend(covering);
+        }
+        Some((names, prefix))
+    }
+
+    /// Picks the values of `record` for the client of `request`, whose Client Subnet
+    /// option holds `subnet`, or `None` if its location has none.
+    pub fn select<'r>(&self, request: &Request, subnet: Option<&Subnet>, record: &'r GeoRecord) -> Option<GeoAnswer<'r>> {
+        let (names, prefix) = self.locate(ecs::client_address(request, subnet))?;
+        let records = names.iter().find_map(|name| record.regions.get(name))?;
+        Some(GeoAnswer {
+            records,
+            subnet: subnet.map(|subnet| {
+                // The answer depends on as much of the subnet as the database network covers
+                let scope = if subnet.source_prefix > 0 { prefix as u8 } else { 0 };
+                subnet.option(scope)
+            }),
+        })
+    }
+}
+
+/// Answers `request` authoritatively with the records of `answer`.
+pub async fn send_answer<R: ResponseHandler>(request: &Request, answer: GeoAnswer<'_>, response_handle: R) -> ResponseInfo {
+    dns::send_records(request, answer.records, answer.subnet, response_handle).await
+}
@@ -0,0 +1,154 @@
+//! Bounds on the messages exchanged with clients.
+//!
+//! Queries reach rdns from anyone, so their handling must hold up to whatever bytes are
+//! sent, and its answers must not grow past what the path back can carry:
+//!
+//! - UDP responses are kept within `dns.max_udp_payload` bytes (1232 by default, which
+//!   avoids IP fragmentation on common paths), whatever buffer size the query's EDNS
+//!   advertises, and within 512 bytes for queries without EDNS (RFC 1035). Answers that
+//!   don't fit are truncated with the TC bit set, so the client retries over TCP.
+//! - Messages that can't be parsed are answered with FORMERR when their header can be
+//!   read, and otherwise dropped, on the TCP and TLS connections rdns accepts itself as
+//!   on those of hickory-server.
+//! - DoH request bodies are read up to the largest DNS message, 65535 bytes.
+//!
+//! The request path is fuzzed by the targets under `fuzz/`, run with `cargo fuzz run
+//! request` and `cargo fuzz run proxy_header`.
+
+use async_trait::async_trait;
+use hickory_proto::op::{Header, MessageType, ResponseCode};
+use hickory_proto::rr::Record;
+use hickory_proto::serialize::binary::{BinDecodable, BinEncodable, BinEncoder};
+use hickory_server::authority::{MessageRequest, MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{Protocol, Request, RequestHandler, ResponseHandler, ResponseInfo};
+use std::net::SocketAddr;
+
+use crate::doh::BufferResponseHandler;
+use crate::edns::EdnsOptions;
+
+/// Largest DNS message, as bounded by the length prefix of TCP (RFC 1035 section 4.2.2).
+pub const MAX_MESSAGE_SIZE: usize = u16::MAX as usize;
+
+/// Largest UDP response to queries without EDNS (RFC 1035 section 4.2.1).
+const CLASSIC_UDP_SIZE: u16 = 512;
+
+/// The size a response to `request` must fit in: at most `max_udp_payload` over UDP,
+/// within the client's buffer size and the advertised one as `edns` has it, and 512
+/// bytes without EDNS; `None` over the stream transports, which carry any size.
+pub fn udp_limit(request: &Request, max_udp_payload: u16, edns: &EdnsOptions) -> Option<u16> {
+    if !matches!(request.protocol(), Protocol::Udp) {
+        return None;
+    }
+    let limit = match request.edns() {
+        Some(client) if edns.honor_client_payload => max_udp_payload.min(client.max_payload()),
+        Some(_) => max_udp_payload,
+        None => return Some(CLASSIC_UDP_SIZE),
+    };
+    let limit = edns.advertised_payload.map_or(limit, |advertised| limit.min(advertised));
+    Some(limit.max(CLASSIC_UDP_SIZE))
+}
+
+/// Truncates responses to the size their transport allows, see `udp_limit`.
+#[derive(Clone)]
+pub struct PayloadResponseHandler<R> {
+    inner: R,
+    /// Size responses must fit in; they are passed on as they are when unset.
+    limit: Option<u16>,
+    /// UDP payload size responses advertise, rather than `limit`.
+    advertised: Option<u16>,
+    /// Bytes of `limit` left for the TSIG record responses are signed with afterwards.
+    signature: u16,
+}
+
+impl<R> PayloadResponseHandler<R> {
+    pub fn new(inner: R, limit: Option<u16>, advertised: Option<u16>, signature: u16) -> Self {
+        Self {
+            inner,
+            limit,
+            advertised,
+            signature,
+        }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for PayloadResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        mut response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let Some(limit) = self.limit else {
+            if let (Some(advertised), Some(edns)) = (self.advertised, response.get_edns()) {
+                let mut edns = edns.clone();
+                edns.set_max_payload(advertised);
+                response.set_edns(edns);
+            }
+            return self.inner.send_response(response).await;
+        };
+        // hickory-server truncates UDP responses to the buffer size of their EDNS section,
+        // or to 4096 bytes without one, and fills truncated responses to the brim, leaving
+        // no room for the OPT record. So the response is truncated here to leave room for
+        // it, and for the TSIG record it may be signed with next, then rebuilt with it
+        let edns = response.get_edns().clone().map(|mut edns| {
+            edns.set_max_payload(self.advertised.unwrap_or(limit));
+            edns
+        });
+        let reserved = match &edns {
+            Some(edns) => Record::from(edns).to_bytes().map_err(std::io::Error::other)?.len(),
+            None => 0,
+        };
+        let mut buffer = Vec::with_capacity(usize::from(limit));
+        let mut encoder = BinEncoder::new(&mut buffer);
+        encoder.set_max_size(limit.saturating_sub(reserved as u16).saturating_sub(self.signature));
+        response.destructive_emit(&mut encoder).map_err(std::io::Error::other)?;
+        let message = MessageRequest::from_bytes(&buffer).map_err(std::io::Error::other)?;
+
+        let mut builder = MessageResponseBuilder::from_message_request(&message);
+        if let Some(edns) = edns {
+            builder.edns(edns);
+        }
+        let response = builder.build(
+            *message.header(),
+            message.answers(),
+            message.name_servers(),
+            [],
+            message.additionals().iter().chain(message.sig0()),
+        );
+        self.inner.send_response(response).await
+    }
+}
+
+/// A FORMERR response to `query`, a message that couldn't be parsed, or `None` when not
+/// even its header can be read or it isn't a query, which are left unanswered.
+pub fn format_error(query: &[u8]) -> Option<Vec<u8>> {
+    let header = Header::from_bytes(query.get(..Header::len())?).ok()?;
+    if header.message_type() != MessageType::Query {
+        return None;
+    }
+    let mut response = Header::response_from_request(&header);
+    response.set_response_code(ResponseCode::FormErr);
+    response.to_bytes().ok()
+}
+
+/// Answers the wire-format `query` with `handler` as coming from `src` over `protocol`,
+/// returning the wire-format answer, a FORMERR for messages that can't be parsed, or
+/// `None` for those left unanswered.
+pub async fn answer<H: RequestHandler>(handler: &H, query: &[u8], src: SocketAddr, protocol: Protocol) -> Option<Vec<u8>> {
+    let Ok(message) = MessageRequest::from_bytes(query) else {
+        return format_error(query);
+    };
+    if message.message_type() != MessageType::Query {
+        return None;
+    }
+    let response_handle = BufferResponseHandler::default();
+    let request = Request::new(message, src, protocol);
+    handler.handle_request(&request, response_handle.clone()).await;
+    response_handle.take()
+}
@@ -0,0 +1,434 @@
+//! Health-checked record values.
+//!
+//! A check probes one value of an A or AAAA RRset on an interval, by requesting a path
+//! over HTTP, opening a TCP connection, or sending an ICMP echo with the system `ping`.
+//! A value is withheld from answers after `fall` consecutive failed probes and served
+//! again after `rise` consecutive successful ones. When every value of an RRset is
+//! withheld the whole RRset is answered, since an empty answer helps no client.
+
+use hickory_proto::rr::{LowerName, RData, Record, RecordType, RrKey};
+use hickory_server::authority::MessageResponse;
+use hickory_server::server::{ResponseHandler, ResponseInfo};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tonic::async_trait;
+
+use crate::roundrobin;
+use crate::settings::HealthSettings;
+use crate::zonefile;
+
+/// Config options for health checks
+#[derive(Clone)]
+pub struct HealthOptions {
+    /// Time between probes of checks that don't set their own.
+    pub interval: Duration,
+    /// How long a probe may take, for checks that don't set their own.
+    pub timeout: Duration,
+    /// Consecutive failures after which a value is withheld.
+    pub fall: u32,
+    /// Consecutive successes after which a withheld value is served again.
+    pub rise: u32,
+}
+
+impl From<HealthSettings> for HealthOptions {
+    fn from(cfg: HealthSettings) -> Self {
+        HealthOptions {
+            interval: Duration::from_secs(cfg.interval_secs),
+            timeout: Duration::from_secs(cfg.timeout_secs),
+            fall: cfg.fall.max(1),
+            rise: cfg.rise.max(1),
+        }
+    }
+}
+
+/// How a value is probed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckKind {
+    /// A request for `path` must get a 2xx or 3xx status
+    Http,
+    /// A connection to `port` must be accepted
+    Tcp,
+    /// An echo request must be answered
+    Icmp,
+}
+
+impl FromStr for CheckKind {
+    type Err = anyhow::Error;
+
+    fn from_str(kind: &str) -> anyhow::Result<Self> {
+        match kind.to_ascii_lowercase().as_str() {
+            "http" => Ok(CheckKind::Http),
+            "tcp" => Ok(CheckKind::Tcp),
+            "icmp" => Ok(CheckKind::Icmp),
+            _ => anyhow::bail!("unknown health check kind {}, expected http, tcp or icmp", kind),
+        }
+    }
+}
+
+impl fmt::Display for CheckKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CheckKind::Http => "http",
+            CheckKind::Tcp => "tcp",
+            CheckKind::Icmp => "icmp",
+        })
+    }
+}
+
+/// A health check in its textual form, as managed through the control API and persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheckEntry {
+    pub name: String,
+    /// `A` or `AAAA`
+    pub record_type: String,
+    /// The address probed, one of the values of the RRset
+    pub value: String,
+    pub kind: CheckKind,
+    /// Port probed by HTTP and TCP checks; HTTP defaults to 80
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Path requested by HTTP checks, `/` when unset
+    #[serde(default)]
+    pub path: Option<String>,
+    /// Seconds between probes, the configured default when unset
+    #[serde(default)]
+    pub interval_secs: Option<u64>,
+    /// Seconds a probe may take, the configured default when unset
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+/// Outcome of the probes of a check so far.
+#[derive(Debug, Clone)]
+pub struct CheckStatus {
+    /// Whether the value is served; values start out healthy
+    pub healthy: bool,
+    /// Failed probes since the last successful one
+    pub failures: u32,
+    /// Successful probes since the last failed one
+    successes: u32,
+    pub last_checked: Option<SystemTime>,
+    /// Why the last probe failed, if it did
+    pub last_error: Option<String>,
+}
+
+impl Default for CheckStatus {
+    fn default() -> Self {
+        CheckStatus {
+            healthy: true,
+            failures: 0,
+            successes: 0,
+            last_checked: None,
+            last_error: None,
+        }
+    }
+}
+
+impl CheckStatus {
+    fn observe(&mut self, outcome: Result<(), String>, options: &HealthOptions) {
+        self.last_checked = Some(SystemTime::now());
+        match outcome {
+            Ok(()) => {
+                self.successes = self.successes.saturating_add(1);
+                self.failures = 0;
+                self.last_error = None;
+                if self.successes >= options.rise {
+                    self.healthy = true;
+                }
+            }
+            Err(e) => {
+                self.failures = self.failures.saturating_add(1);
+                self.successes = 0;
+                self.last_error = Some(e);
+                if self.failures >= options.fall {
+                    self.healthy = false;
+                }
+            }
+        }
+    }
+}
+
+/// Health of one value of an RRset, with its check if it has one.
+pub struct ValueHealth {
+    pub value: String,
+    pub check: Option<(HealthCheckEntry, CheckStatus)>,
+}
+
+/// Everything a probe needs, moved into the task running it.
+struct Probe {
+    kind: CheckKind,
+    address: IpAddr,
+    port: Option<u16>,
+    path: String,
+    /// Host header sent by HTTP checks, the record name
+    host: String,
+    timeout: Duration,
+    http: Client<HttpConnector>,
+}
+
+impl Probe {
+    async fn run(&self) -> Result<(), String> {
+        match tokio::time::timeout(self.timeout, self.attempt()).await {
+            Ok(outcome) => outcome,
+            Err(_) => Err(format!("no response within {:?}", self.timeout)),
+        }
+    }
+
+    async fn attempt(&self) -> Result<(), String> {
+        match self.kind {
+            CheckKind::Tcp => {
+                let addr = SocketAddr::new(self.address, self.port.unwrap_or_default());
+                TcpStream::connect(addr).await.map(drop).map_err(|e| e.to_string())
+            }
+            CheckKind::Http => {
+                let addr = SocketAddr::new(self.address, self.port.unwrap_or(80));
+                let request = hyper::Request::get(format!("http://{}{}", addr, self.path))
+                    .header(hyper::header::HOST, &self.host)
+                    .body(Body::empty())
+                    .map_err(|e| e.to_string())?;
+                let response = self.http.request(request).await.map_err(|e| e.to_string())?;
+                let status = response.status();
+                if status.is_success() || status.is_redirection() {
+                    Ok(())
+                } else {
+                    Err(format!("HTTP status {}", status))
+                }
+            }
+            CheckKind::Icmp => {
+                let status = Command::new("ping")
+                    .arg("-c1")
+                    .arg("-W")
+                    .arg(self.timeout.as_secs().max(1).to_string())
+                    .arg(self.address.to_string())
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .kill_on_drop(true)
+                    .status()
+                    .await
+                    .map_err(|e| format!("can't run ping: {}", e))?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err("no echo reply".into())
+                }
+            }
+        }
+    }
+}
+
+/// A running check of one value.
+struct Check {
+    entry: HealthCheckEntry,
+    value: RData,
+    status: Arc<Mutex<CheckStatus>>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Check {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Runs the health checks and tracks which values are currently withheld.
+pub struct HealthMonitor {
+    options: HealthOptions,
+    /// Checks by the name and type of the RRset holding their value.
+    checks: RwLock<HashMap<RrKey, Vec<Check>>>,
+    http: Client<HttpConnector>,
+}
+
+impl HealthMonitor {
+    pub fn new(options: HealthOptions) -> Self {
+        Self {
+            options,
+            checks: RwLock::new(HashMap::new()),
+            http: Client::new(),
+        }
+    }
+
+    /// Starts probing the value of `record` as `entry` describes, replacing any check of
+    /// the same value.
+    pub fn set(&self, mut entry: HealthCheckEntry, record: &Record) -> anyhow::Result<()> {
+        let address = match record.data() {
+            Some(RData::A(a)) => IpAddr::V4(a.0),
+            Some(RData::AAAA(aaaa)) => IpAddr::V6(aaaa.0),
+            _ => anyhow::bail!("health checks can only probe A or AAAA records, not {}", record.record_type()),
+        };
+        if entry.kind == CheckKind::Tcp && entry.port.is_none() {
+            anyhow::bail!("TCP health checks need a port");
+        }
+        if entry.path.as_ref().is_some_and(|path| !path.starts_with('/')) {
+            anyhow::bail!("health check path must start with /");
+        }
+        let Some(value) = record.data().cloned() else {
+            anyhow::bail!("record has no value");
+        };
+        entry.name = record.name().to_ascii();
+        entry.record_type = record.record_type().to_string();
+        entry.value = zonefile::format_rdata(&value);
+
+        let probe = Probe {
+            kind: entry.kind,
+            address,
+            port: entry.port,
+            path: entry.path.clone().unwrap_or_else(|| "/".into()),
+            host: record.name().to_ascii().trim_end_matches('.').to_string(),
+            timeout: entry.timeout_secs.map_or(self.options.timeout, Duration::from_secs),
+            http: self.http.clone(),
+        };
+        let interval = entry.interval_secs.map_or(self.options.interval, Duration::from_secs);
+        let status = Arc::new(Mutex::new(CheckStatus::default()));
+        let task = tokio::spawn(run_probes(probe, interval.max(Duration::from_secs(1)), status.clone(), self.options.clone()));
+
+        let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+        let mut checks = self.checks.write().unwrap();
+        let checks = checks.entry(key).or_default();
+        checks.retain(|check| check.value != value);
+        checks.push(Check { entry, value, status, task });
+        Ok(())
+    }
+
+    /// Stops checking `value` of an RRset, returning whether it was checked.
+    pub fn remove(&self, key: &RrKey, value: &RData) -> bool {
+        let mut checks = self.checks.write().unwrap();
+        let Some(rrset) = checks.get_mut(key) else {
+            return false;
+        };
+        let before = rrset.len();
+        rrset.retain(|check| &check.value != value);
+        let removed = rrset.len() != before;
+        if rrset.is_empty() {
+            checks.remove(key);
+        }
+        removed
+    }
+
+    /// Stops checking every value of an RRset.
+    pub fn remove_rrset(&self, key: &RrKey) {
+        self.checks.write().unwrap().remove(key);
+    }
+
+    /// Stops checking every value in the zone `origin`.
+    pub fn remove_zone(&self, origin: &LowerName) {
+        self.checks.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+    }
+
+    /// Stops every check.
+    pub fn clear(&self) {
+        self.checks.write().unwrap().clear();
+    }
+
+    /// Lists every check, sorted by name, type and value.
+    pub fn entries(&self) -> Vec<HealthCheckEntry> {
+        let checks = self.checks.read().unwrap();
+        let mut entries: Vec<HealthCheckEntry> = checks.values().flatten().map(|check| check.entry.clone()).collect();
+        entries.sort_by(|a, b| (&a.name, &a.record_type, &a.value).cmp(&(&b.name, &b.record_type, &b.value)));
+        entries
+    }
+
+    /// Reports the health of each record in the RRset `key`.
+    pub fn status<'r>(&self, key: &RrKey, records: impl Iterator<Item = &'r Record>) -> Vec<ValueHealth> {
+        let checks = self.checks.read().unwrap();
+        let checks = checks.get(key);
+        records
+            .filter_map(|record| record.data())
+            .map(|value| ValueHealth {
+                value: zonefile::format_rdata(value),
+                check: checks
+                    .and_then(|checks| checks.iter().find(|check| &check.value == value))
+                    .map(|check| (check.entry.clone(), check.status.lock().unwrap().clone())),
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.checks.read().unwrap().is_empty()
+    }
+
+    /// Whether `record` may be served: it has no check, or its check is passing.
+    pub fn is_healthy(&self, record: &Record) -> bool {
+        if !matches!(record.record_type(), RecordType::A | RecordType::AAAA) {
+            return true;
+        }
+        let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+        let checks = self.checks.read().unwrap();
+        let Some(checks) = checks.get(&key) else {
+            return true;
+        };
+        checks
+            .iter()
+            .find(|check| Some(&check.value) == record.data())
+            .is_none_or(|check| check.status.lock().unwrap().healthy)
+    }
+
+    /// Drops the unhealthy values of each RRset in `answers`, unless that would leave the
+    /// RRset empty.
+    fn filter_answers<'r>(&self, answers: &'r [Record]) -> Vec<&'r Record> {
+        let mut filtered = Vec::with_capacity(answers.len());
+        for rrset in answers.chunk_by(|a, b| a.name() == b.name() && a.record_type() == b.record_type()) {
+            let healthy: Vec<&Record> = rrset.iter().filter(|record| self.is_healthy(record)).collect();
+            if healthy.is_empty() {
+                filtered.extend(rrset);
+            } else {
+                filtered.extend(healthy);
+            }
+        }
+        filtered
+    }
+}
+
+/// Probes every `interval` until the check is removed, recording each outcome.
+async fn run_probes(probe: Probe, interval: Duration, status: Arc<Mutex<CheckStatus>>, options: HealthOptions) {
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        let outcome = probe.run().await;
+        status.lock().unwrap().observe(outcome, &options);
+    }
+}
+
+/// Response handler that withholds unhealthy values from the answers.
+#[derive(Clone)]
+pub struct HealthResponseHandler<R> {
+    inner: R,
+    monitor: Arc<HealthMonitor>,
+}
+
+impl<R> HealthResponseHandler<R> {
+    pub fn new(inner: R, monitor: Arc<HealthMonitor>) -> Self {
+        Self { inner, monitor }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for HealthResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let monitor = self.monitor.clone();
+        roundrobin::rewrite_answers(&mut self.inner, response, move |answers| monitor.filter_answers(answers)).await
+    }
+}
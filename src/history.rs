@@ -0,0 +1,145 @@
+//! Versioned history of zone contents.
+//!
+//! With `[dns.history]` configured, the records of a primary zone are kept as a new
+//! version whenever the zone is created, loaded or changed, along with the SOA serial it
+//! was given and a change id that increases across every zone. The last `versions`
+//! versions of each zone are held in memory, so the history starts afresh on restart;
+//! `RollbackZone` puts the records of one of them back, as a new version of its own.
+
+use hickory_proto::rr::{LowerName, RData, Record};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::settings::HistorySettings;
+
+/// Config options for zone history
+#[derive(Clone)]
+pub struct HistoryOptions {
+    /// Versions kept per zone.
+    pub versions: usize,
+}
+
+impl TryFrom<HistorySettings> for HistoryOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: HistorySettings) -> anyhow::Result<Self> {
+        if cfg.versions == 0 {
+            anyhow::bail!("dns.history.versions must be at least 1");
+        }
+        Ok(HistoryOptions { versions: cfg.versions })
+    }
+}
+
+/// One version of a zone's records.
+pub struct ZoneVersion {
+    /// Id of the change that produced the version.
+    pub change_id: u64,
+    /// Serial of the zone's SOA in the version, if it has one.
+    pub serial: Option<u32>,
+    pub timestamp: SystemTime,
+    pub records: Vec<Record>,
+}
+
+/// A kept version, as reported through the control API.
+pub struct VersionInfo {
+    pub change_id: u64,
+    pub serial: Option<u32>,
+    pub timestamp: SystemTime,
+    pub record_count: usize,
+}
+
+/// Which version of a zone to roll back to.
+#[derive(Clone, Copy, Debug)]
+pub enum VersionSelector {
+    ChangeId(u64),
+    Serial(u32),
+}
+
+impl fmt::Display for VersionSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VersionSelector::ChangeId(change_id) => write!(f, "change id {}", change_id),
+            VersionSelector::Serial(serial) => write!(f, "serial {}", serial),
+        }
+    }
+}
+
+/// The kept versions of each zone, oldest first.
+pub struct History {
+    options: HistoryOptions,
+    next_change_id: AtomicU64,
+    zones: Mutex<HashMap<LowerName, VecDeque<ZoneVersion>>>,
+}
+
+impl History {
+    pub fn new(options: HistoryOptions) -> Self {
+        Self {
+            options,
+            next_change_id: AtomicU64::new(1),
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keeps `records` as the newest version of the zone at `origin`, dropping its oldest
+    /// version if it has as many as are kept. Returns the version's change id.
+    pub fn record(&self, origin: &LowerName, records: Vec<Record>) -> u64 {
+        let change_id = self.next_change_id.fetch_add(1, Ordering::Relaxed);
+        let serial = records.iter().find_map(|record| match record.data() {
+            Some(RData::SOA(soa)) => Some(soa.serial()),
+            _ => None,
+        });
+        let mut zones = self.zones.lock().unwrap();
+        let versions = zones.entry(origin.clone()).or_default();
+        if versions.len() == self.options.versions {
+            versions.pop_front();
+        }
+        versions.push_back(ZoneVersion {
+            change_id,
+            serial,
+            timestamp: SystemTime::now(),
+            records,
+        });
+        change_id
+    }
+
+    /// The kept versions of the zone at `origin`, oldest first.
+    pub fn versions(&self, origin: &LowerName) -> Vec<VersionInfo> {
+        let zones = self.zones.lock().unwrap();
+        zones.get(origin).map_or_else(Vec::new, |versions| {
+            versions
+                .iter()
+                .map(|version| VersionInfo {
+                    change_id: version.change_id,
+                    serial: version.serial,
+                    timestamp: version.timestamp,
+                    record_count: version.records.len(),
+                })
+                .collect()
+        })
+    }
+
+    /// The change id of the newest version of the zone at `origin`.
+    pub fn latest(&self, origin: &LowerName) -> Option<u64> {
+        let zones = self.zones.lock().unwrap();
+        zones.get(origin)?.back().map(|version| version.change_id)
+    }
+
+    /// The records of the version of the zone at `origin` that `selector` picks, along
+    /// with its change id. A serial picks the newest version with that serial.
+    pub fn find(&self, origin: &LowerName, selector: VersionSelector) -> Option<(u64, Vec<Record>)> {
+        let zones = self.zones.lock().unwrap();
+        let version = zones.get(origin)?.iter().rev().find(|version| match selector {
+            VersionSelector::ChangeId(change_id) => version.change_id == change_id,
+            VersionSelector::Serial(serial) => version.serial == Some(serial),
+        })?;
+        Some((version.change_id, version.records.clone()))
+    }
+
+    /// Forgets the versions of the zone at `origin`.
+    pub fn remove_zone(&self, origin: &LowerName) {
+        self.zones.lock().unwrap().remove(origin);
+    }
+}
@@ -0,0 +1,147 @@
+//! Records for the entries of hosts files.
+//!
+//! With `[dns.hosts]` configured, every `<address> <name> [<alias>...]` entry of the
+//! hosts files gets an A or AAAA record for each of its names. Names with a single label
+//! are put under the zone, fully qualified ones are kept when they are within it and
+//! skipped otherwise; loopback, unspecified and multicast addresses, such as those of
+//! `localhost` and `ip6-allnodes`, are skipped as well. The files are checked every
+//! `poll_secs` seconds and read again once one of them changes, so entries edited by
+//! hand are served within seconds.
+//!
+//! The zone belongs to the integration: its A and AAAA records are replaced by those of
+//! the entries, while records of other types are left alone. A file that can't be read
+//! leaves the records as they are until it can be again.
+
+use hickory_proto::rr::{LowerName, Name, RecordType};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+
+use crate::dns::{DnsState, RecordEntry};
+use crate::settings::HostsSettings;
+
+/// Config options for hosts file records
+#[derive(Clone)]
+pub struct HostsOptions {
+    pub files: Vec<PathBuf>,
+    pub zone: Name,
+    pub ttl: u32,
+    pub poll_interval: Duration,
+}
+
+impl TryFrom<HostsSettings> for HostsOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: HostsSettings) -> anyhow::Result<Self> {
+        if cfg.files.is_empty() {
+            anyhow::bail!("dns.hosts.files must name at least one file");
+        }
+        let mut zone = Name::from_ascii(&cfg.zone)?;
+        zone.set_fqdn(true);
+        Ok(HostsOptions {
+            files: cfg.files.into_iter().map(PathBuf::from).collect(),
+            zone,
+            ttl: cfg.ttl,
+            poll_interval: Duration::from_secs(cfg.poll_secs.max(1)),
+        })
+    }
+}
+
+/// Whether `address` is one hosts files list for the local machine only.
+fn is_local(address: &IpAddr) -> bool {
+    address.is_loopback() || address.is_unspecified() || address.is_multicast()
+}
+
+/// The records for the entries of a hosts file, within `zone`.
+fn parse(content: &str, zone: &Name, ttl: u32) -> Vec<RecordEntry> {
+    let zone_key = LowerName::new(zone);
+    let mut records = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(Ok(address)) = fields.next().map(str::parse::<IpAddr>) else {
+            continue;
+        };
+        if is_local(&address) {
+            continue;
+        }
+        for name in fields {
+            let Ok(mut parsed) = Name::from_ascii(name) else {
+                continue;
+            };
+            let fqdn = if parsed.num_labels() == 1 && !parsed.is_fqdn() {
+                parsed.append_domain(zone).ok()
+            } else {
+                parsed.set_fqdn(true);
+                Some(parsed)
+            };
+            match fqdn {
+                Some(fqdn) if zone_key.zone_of(&LowerName::new(&fqdn)) => records.push(RecordEntry {
+                    name: fqdn.to_lowercase().to_string(),
+                    record_type: if address.is_ipv4() { "A" } else { "AAAA" }.to_string(),
+                    value: address.to_string(),
+                    ttl,
+                    ..Default::default()
+                }),
+                _ => eprintln!("Skipping hosts entry {}, which is not in zone {}", name, zone),
+            }
+        }
+    }
+    records
+}
+
+/// When each of the files was last modified, unset for those that can't be checked.
+async fn modified(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    let mut times = Vec::with_capacity(files.len());
+    for file in files {
+        let metadata = tokio::fs::metadata(file).await;
+        times.push(metadata.and_then(|metadata| metadata.modified()).ok());
+    }
+    times
+}
+
+/// Brings the records of the zone in line with the hosts files.
+async fn sync(state: &RwLock<DnsState>, options: &HostsOptions) -> anyhow::Result<()> {
+    let mut records = Vec::new();
+    for file in &options.files {
+        let content = tokio::fs::read_to_string(file)
+            .await
+            .map_err(|e| anyhow::anyhow!("can't read {}: {}", file.display(), e))?;
+        records.extend(parse(&content, &options.zone, options.ttl));
+    }
+    let origin = options.zone.to_string();
+    let changed = state
+        .read()
+        .await
+        .sync_records(&origin, &[RecordType::A, RecordType::AAAA], records)
+        .await?;
+    if changed > 0 {
+        println!("Updated {} hosts file record sets in {}", changed, origin);
+    }
+    Ok(())
+}
+
+/// Keeps the records of the zone in line with the hosts files, reading them again
+/// whenever one changes. Runs until the process exits.
+pub async fn run_hosts_sync(state: Arc<RwLock<DnsState>>, options: HostsOptions) {
+    if let Err(e) = state.write().await.ensure_zones(&[options.zone.to_string()]).await {
+        eprintln!("Failed to create hosts zone {}: {}", options.zone, e);
+        return;
+    }
+    // Modification times of the files as of the last successful sync
+    let mut synced: Option<Vec<Option<SystemTime>>> = None;
+    let mut interval = tokio::time::interval(options.poll_interval);
+    loop {
+        interval.tick().await;
+        let times = modified(&options.files).await;
+        if synced.as_ref() == Some(&times) {
+            continue;
+        }
+        match sync(&state, &options).await {
+            Ok(()) => synced = Some(times),
+            Err(e) => eprintln!("Hosts file sync failed: {}", e),
+        }
+    }
+}
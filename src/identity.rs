@@ -0,0 +1,142 @@
+//! Answers identifying the instance that responded.
+//!
+//! With `[dns.identity]` configured, CHAOS-class TXT queries for `hostname.bind` and
+//! `id.server` are answered with the hostname and those for `version.bind` and
+//! `version.server` with the version string, and responses to queries carrying the EDNS
+//! NSID option (RFC 5001) get it back holding the hostname. Behind an anycast address
+//! this tells monitoring which instance answered. An empty string refuses the queries
+//! for that name, as do all CHAOS queries when identity answers are not configured.
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use hickory_proto::rr::rdata::TXT;
+use hickory_proto::rr::{DNSClass, LowerName, Name, RData, Record, RecordType};
+use hickory_server::authority::{MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use std::str::FromStr;
+use tonic::async_trait;
+
+use crate::dns;
+use crate::roundrobin;
+use crate::settings::IdentitySettings;
+
+/// Config options for identity answers
+#[derive(Clone, Debug)]
+pub struct IdentityOptions {
+    /// Answer to `hostname.bind` and `id.server`, and the NSID; refused when unset.
+    pub hostname: Option<String>,
+    /// Answer to `version.bind` and `version.server`; refused when unset.
+    pub version: Option<String>,
+    /// Whether the NSID option is answered.
+    pub nsid: bool,
+}
+
+impl From<IdentitySettings> for IdentityOptions {
+    fn from(cfg: IdentitySettings) -> Self {
+        let hostname = cfg.hostname.unwrap_or_else(system_hostname);
+        IdentityOptions {
+            hostname: (!hostname.is_empty()).then_some(hostname),
+            version: (!cfg.version.is_empty()).then_some(cfg.version),
+            nsid: cfg.nsid,
+        }
+    }
+}
+
+/// The name of the host rdns runs on.
+fn system_hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_string())
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .unwrap_or_else(|| "rdns".into())
+}
+
+impl IdentityOptions {
+    /// The TXT value answered for the CHAOS name `name`, if any.
+    fn chaos_value(&self, name: &LowerName) -> Option<&str> {
+        let is = |known: &str| Name::from_str(known).is_ok_and(|known| LowerName::new(&known) == *name);
+        if is("hostname.bind.") || is("id.server.") {
+            self.hostname.as_deref()
+        } else if is("version.bind.") || is("version.server.") {
+            self.version.as_deref()
+        } else {
+            None
+        }
+    }
+
+    /// The NSID option to put in the response to `request`, if it asks for one.
+    pub fn nsid_for(&self, request: &Request) -> Option<EdnsOption> {
+        let hostname = self.hostname.as_ref().filter(|_| self.nsid)?;
+        request.edns()?.options().get(EdnsCode::NSID)?;
+        Some(EdnsOption::Unknown(u16::from(EdnsCode::NSID), hostname.as_bytes().to_vec()))
+    }
+}
+
+/// Answers a CHAOS-class query from `options`, refusing it for names without a value.
+pub async fn answer_chaos<R: ResponseHandler>(request: &Request, options: Option<&IdentityOptions>, response_handle: R) -> ResponseInfo {
+    let query = request.query();
+    let Some(value) = options.and_then(|options| options.chaos_value(query.name())) else {
+        return dns::send_error(request, ResponseCode::Refused, response_handle).await;
+    };
+    let records = match query.query_type() {
+        RecordType::TXT | RecordType::ANY => {
+            let mut record = Record::from_rdata(query.original().name().clone(), 0, RData::TXT(TXT::new(vec![value.to_string()])));
+            record.set_dns_class(DNSClass::CH);
+            vec![record]
+        }
+        _ => Vec::new(),
+    };
+    dns::send_records(request, &records, None, response_handle).await
+}
+
+/// Response handler that adds an NSID option to the response's EDNS section.
+#[derive(Clone)]
+pub struct NsidResponseHandler<R> {
+    inner: R,
+    /// The option to add; responses are passed on as they are when unset.
+    nsid: Option<EdnsOption>,
+}
+
+impl<R> NsidResponseHandler<R> {
+    pub fn new(inner: R, nsid: Option<EdnsOption>) -> Self {
+        Self { inner, nsid }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for NsidResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let Some(nsid) = self.nsid.clone() else {
+            return self.inner.send_response(response).await;
+        };
+        // The EDNS section can't be changed in place, so the response is encoded and
+        // re-read, then rebuilt with the option added
+        let message = roundrobin::reread(response)?;
+
+        let mut builder = MessageResponseBuilder::from_message_request(&message);
+        if let Some(edns) = message.edns() {
+            let mut edns = edns.clone();
+            edns.options_mut().insert(nsid);
+            builder.edns(edns);
+        }
+        let response = builder.build(
+            *message.header(),
+            message.answers(),
+            message.name_servers(),
+            [],
+            message.additionals().iter().chain(message.sig0()),
+        );
+        self.inner.send_response(response).await
+    }
+}
@@ -0,0 +1,161 @@
+//! Incremental zone transfers (RFC 1995).
+//!
+//! With zone transfers enabled, the changes to each zone are kept in a journal as they
+//! are made, as the records removed and added between one SOA serial and the next; the
+//! last `ixfr_journal` changes of each zone are kept. An IXFR request names the serial
+//! the secondary has, and is answered with the changes since then, or with the whole
+//! zone like an AXFR when the journal doesn't reach back that far. Zones whose records
+//! change without a new serial, e.g. as they have no SOA that is bumped, start their
+//! journal afresh. IXFR requests over UDP are answered with the current SOA alone, which
+//! asks secondaries that are behind to retry over TCP.
+
+use hickory_proto::rr::{LowerName, RData, Record, RecordType};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// The changes between two versions of a zone.
+#[derive(Debug, Clone)]
+pub struct Delta {
+    /// The SOA of the older version, followed by the records it had that the newer one
+    /// lacks
+    pub removed: Vec<Record>,
+    /// The SOA of the newer version, followed by the records it added
+    pub added: Vec<Record>,
+}
+
+/// The current records of a zone and the changes that led to them, oldest first.
+struct ZoneJournal {
+    serial: u32,
+    records: BTreeSet<Record>,
+    deltas: VecDeque<Delta>,
+}
+
+/// Journals of the hosted zones, by zone origin.
+pub struct Journal {
+    /// Changes kept per zone.
+    size: usize,
+    zones: Mutex<HashMap<LowerName, ZoneJournal>>,
+}
+
+/// The serial and SOA among `records`, if there is one.
+fn find_soa(records: &BTreeSet<Record>) -> Option<(u32, &Record)> {
+    records.iter().find_map(|record| match record.data() {
+        Some(RData::SOA(soa)) => Some((soa.serial(), record)),
+        _ => None,
+    })
+}
+
+/// Whether serial `a` comes after `b` in RFC 1982 serial arithmetic.
+fn is_newer(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < 1 << 31
+}
+
+impl Journal {
+    pub fn new(size: usize) -> Self {
+        Journal {
+            size,
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes how many changes are kept per zone, dropping the oldest ones beyond it.
+    pub fn set_size(&mut self, size: usize) {
+        self.size = size;
+        for journal in self.zones.get_mut().unwrap().values_mut() {
+            trim(&mut journal.deltas, size);
+        }
+    }
+
+    /// Notes that the zone at `origin` now has `records`, keeping the changes since its
+    /// previous records as a delta.
+    pub fn record(&self, origin: &LowerName, records: Vec<Record>) {
+        let records: BTreeSet<Record> = records.into_iter().collect();
+        let mut zones = self.zones.lock().unwrap();
+        let Some((serial, soa)) = find_soa(&records) else {
+            zones.remove(origin);
+            return;
+        };
+        let Some(journal) = zones.get_mut(origin) else {
+            zones.insert(origin.clone(), ZoneJournal { serial, records, deltas: VecDeque::new() });
+            return;
+        };
+        if journal.records == records {
+            return;
+        }
+        if !is_newer(serial, journal.serial) {
+            // Secondaries can't tell the new records from the old ones by serial
+            journal.deltas.clear();
+        } else if self.size > 0 {
+            let (_, old_soa) = find_soa(&journal.records).expect("journalled zones have an SOA");
+            let mut removed = vec![old_soa.clone()];
+            removed.extend(journal.records.difference(&records).filter(|record| record.record_type() != RecordType::SOA).cloned());
+            let mut added = vec![soa.clone()];
+            added.extend(records.difference(&journal.records).filter(|record| record.record_type() != RecordType::SOA).cloned());
+            journal.deltas.push_back(Delta { removed, added });
+            trim(&mut journal.deltas, self.size);
+        }
+        journal.serial = serial;
+        journal.records = records;
+    }
+
+    /// Forgets the journal of the zone at `origin`.
+    pub fn remove(&self, origin: &LowerName) {
+        self.zones.lock().unwrap().remove(origin);
+    }
+
+    pub fn clear(&self) {
+        self.zones.lock().unwrap().clear();
+    }
+
+    /// The changes to the zone at `origin` from its version with `serial` up to that with
+    /// `current`, or `None` if the journal doesn't cover them.
+    pub fn changes_since(&self, origin: &LowerName, serial: u32, current: u32) -> Option<Vec<Delta>> {
+        let zones = self.zones.lock().unwrap();
+        let journal = zones.get(origin).filter(|journal| journal.serial == current)?;
+        if journal.serial == serial {
+            return Some(Vec::new());
+        }
+        let start = journal.deltas.iter().position(|delta| soa_serial(&delta.removed) == Some(serial))?;
+        Some(journal.deltas.iter().skip(start).cloned().collect())
+    }
+}
+
+/// The serial of the SOA leading `records`.
+fn soa_serial(records: &[Record]) -> Option<u32> {
+    match records.first()?.data() {
+        Some(RData::SOA(soa)) => Some(soa.serial()),
+        _ => None,
+    }
+}
+
+/// Drops the oldest of `deltas` beyond the last `size`.
+fn trim(deltas: &mut VecDeque<Delta>, size: usize) {
+    while deltas.len() > size {
+        deltas.pop_front();
+    }
+}
+
+/// The answer to an IXFR request from a secondary at `serial`, for a zone whose current
+/// SOA is `soa` and records `records`: the SOA alone when the secondary is up to date,
+/// otherwise `changes` in IXFR format, or the whole zone in AXFR format without them.
+pub fn answer(soa: Record, records: Vec<Record>, serial: Option<u32>, changes: Option<Vec<Delta>>) -> Vec<Record> {
+    let current = match soa.data() {
+        Some(RData::SOA(current)) => current.serial(),
+        _ => return vec![soa],
+    };
+    if serial.is_some_and(|serial| !is_newer(current, serial)) {
+        return vec![soa];
+    }
+    let mut answer = vec![soa.clone()];
+    match changes {
+        Some(changes) => {
+            for delta in changes {
+                answer.extend(delta.removed);
+                answer.extend(delta.added);
+            }
+        }
+        None => answer.extend(records.into_iter().filter(|record| record.record_type() != RecordType::SOA)),
+    }
+    answer.push(soa);
+    answer
+}
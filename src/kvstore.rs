@@ -0,0 +1,475 @@
+//! Record storage on etcd or Consul KV.
+//!
+//! With `storage.backend = "etcd"` or `"consul"`, the snapshot and the journal are kept
+//! under `storage.prefix` in the key-value store at `storage.endpoint`, through its HTTP
+//! API: the v3 JSON gateway of etcd, or the KV and transaction endpoints of Consul, with
+//! `storage.token` as the Consul ACL token. Several instances can then serve the same
+//! records, e.g. stateless ones behind a load balancer, each taking changes through its
+//! own control API.
+//!
+//! Every instance watches the snapshot for writes by the others, and reloads its state
+//! from it when one is made, swapping the zones in the catalog in one go like a cluster
+//! follower does. Snapshots are written with a compare-and-swap on the revision last
+//! loaded or written: a change made by an instance that hasn't yet reloaded the change of
+//! another fails with a storage error instead of overwriting it, and is undone by the
+//! reload that follows. The snapshot must fit in a single value of the store, at most
+//! 1.5 MiB for etcd and 512 KiB for Consul by default.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Request, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tonic::async_trait;
+
+use crate::persist::{self, JournalEntry, Snapshot, ZoneStore};
+
+/// Key of the snapshot, under the prefix.
+const SNAPSHOT_KEY: &str = "snapshot";
+/// Key of the journal, under the prefix.
+const JOURNAL_KEY: &str = "journal";
+/// Prefix of the keys when `storage.prefix` is unset.
+const DEFAULT_PREFIX: &str = "rdns/";
+/// Time a request to the store may take.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a Consul blocking query waits for the snapshot to change.
+const CONSUL_WAIT: Duration = Duration::from_secs(300);
+/// Delay before watching the snapshot again after the watch failed.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Attempts at appending to the journal while other instances append to it.
+const JOURNAL_ATTEMPTS: usize = 10;
+
+/// Which key-value store the state is kept in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvFlavor {
+    Etcd,
+    Consul,
+}
+
+/// Config options for the etcd and Consul backends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KvOptions {
+    pub flavor: KvFlavor,
+    /// Base URL of the store's HTTP API, e.g. `http://127.0.0.1:2379`
+    pub endpoint: Uri,
+    /// Prefix of the keys the snapshot and journal are kept under
+    pub prefix: String,
+    /// Consul ACL token
+    pub token: Option<String>,
+}
+
+impl KvOptions {
+    /// Options for the store at `endpoint`, checked as the `storage` settings.
+    pub fn new(flavor: KvFlavor, endpoint: &str, prefix: Option<String>, token: Option<String>) -> anyhow::Result<Self> {
+        let uri = Uri::from_str(endpoint)?;
+        if !matches!(uri.scheme_str(), Some("http" | "https")) {
+            anyhow::bail!("storage.endpoint {} must be http or https", endpoint);
+        }
+        let prefix = prefix.unwrap_or_else(|| DEFAULT_PREFIX.to_string());
+        if !prefix.chars().all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c)) {
+            anyhow::bail!("storage.prefix {} may only hold letters, digits and -_./", prefix);
+        }
+        Ok(KvOptions {
+            flavor,
+            endpoint: uri,
+            prefix,
+            token: token.filter(|token| !token.is_empty()),
+        })
+    }
+}
+
+/// A value of the store, along with the revision it was written at.
+struct Versioned {
+    revision: u64,
+    value: Vec<u8>,
+}
+
+/// Keeps the snapshot and journal in etcd or Consul KV.
+pub struct KvStore(Arc<Kv>);
+
+impl KvStore {
+    pub fn new(options: KvOptions) -> Self {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        KvStore(Arc::new(Kv {
+            options,
+            http: Client::builder().build(connector),
+            snapshot: Mutex::new(None),
+        }))
+    }
+}
+
+struct Kv {
+    options: KvOptions,
+    http: Client<HttpsConnector<HttpConnector>>,
+    /// The snapshot as last loaded or written by this instance
+    snapshot: Mutex<Option<Versioned>>,
+}
+
+impl Kv {
+    fn key(&self, name: &str) -> String {
+        format!("{}{}", self.options.prefix, name)
+    }
+
+    /// Revision of the snapshot as last loaded or written by this instance, 0 if none was.
+    fn revision(&self) -> u64 {
+        self.snapshot.lock().unwrap().as_ref().map_or(0, |snapshot| snapshot.revision)
+    }
+
+    /// Sends a request to `path` of the API.
+    async fn send(&self, method: Method, path: &str, body: Option<serde_json::Value>) -> anyhow::Result<hyper::Response<Body>> {
+        let endpoint = self.options.endpoint.to_string();
+        let uri = Uri::from_str(&format!("{}{}", endpoint.trim_end_matches('/'), path))?;
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = &self.options.token {
+            request = request.header("x-consul-token", token);
+        }
+        let body = match body {
+            Some(body) => Body::from(serde_json::to_vec(&body)?),
+            None => Body::empty(),
+        };
+        Ok(self.http.request(request.header(header::CONTENT_TYPE, "application/json").body(body)?).await?)
+    }
+
+    /// Sends a request and reads its response, failing if it takes longer than `timeout`.
+    async fn call(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+        timeout: Duration,
+    ) -> anyhow::Result<(StatusCode, Option<u64>, Bytes)> {
+        let exchange = async {
+            let response = self.send(method, path, body).await?;
+            let index = response
+                .headers()
+                .get("x-consul-index")
+                .and_then(|index| index.to_str().ok()?.parse().ok());
+            Ok::<_, anyhow::Error>((response.status(), index, hyper::body::to_bytes(response.into_body()).await?))
+        };
+        tokio::time::timeout(timeout, exchange)
+            .await
+            .map_err(|_| anyhow::anyhow!("{} didn't answer in time", self.options.endpoint))?
+    }
+
+    /// The value of `key`, or `None` if it isn't set.
+    async fn get(&self, key: &str) -> anyhow::Result<Option<Versioned>> {
+        match self.options.flavor {
+            KvFlavor::Etcd => {
+                let body = json!({ "key": STANDARD.encode(key) });
+                let (status, _, bytes) = self.call(Method::POST, "/v3/kv/range", Some(body), REQUEST_TIMEOUT).await?;
+                check(status, &bytes)?;
+                let range: EtcdRange = serde_json::from_slice(&bytes)?;
+                range.kvs.into_iter().next().map(EtcdKv::versioned).transpose()
+            }
+            KvFlavor::Consul => {
+                let (status, _, bytes) = self.call(Method::GET, &format!("/v1/kv/{}", key), None, REQUEST_TIMEOUT).await?;
+                if status == StatusCode::NOT_FOUND {
+                    return Ok(None);
+                }
+                check(status, &bytes)?;
+                let entries: Vec<ConsulEntry> = serde_json::from_slice(&bytes)?;
+                entries.into_iter().next().map(ConsulEntry::versioned).transpose()
+            }
+        }
+    }
+
+    /// Sets `key` to `value` if it is still at `revision`, 0 for a key that isn't set,
+    /// returning the revision written, or `None` if the key was changed meanwhile.
+    async fn put(&self, key: &str, value: &[u8], revision: u64) -> anyhow::Result<Option<u64>> {
+        match self.options.flavor {
+            KvFlavor::Etcd => {
+                let key = STANDARD.encode(key);
+                let body = json!({
+                    "compare": [{ "key": key, "target": "MOD", "result": "EQUAL", "mod_revision": revision.to_string() }],
+                    "success": [{ "request_put": { "key": key, "value": STANDARD.encode(value) } }],
+                });
+                let (status, _, bytes) = self.call(Method::POST, "/v3/kv/txn", Some(body), REQUEST_TIMEOUT).await?;
+                check(status, &bytes)?;
+                let txn: EtcdTxn = serde_json::from_slice(&bytes)?;
+                Ok(txn.succeeded.then_some(txn.header.revision))
+            }
+            KvFlavor::Consul => {
+                let body = json!([{ "KV": { "Verb": "cas", "Key": key, "Value": STANDARD.encode(value), "Index": revision } }]);
+                let (status, _, bytes) = self.call(Method::PUT, "/v1/txn", Some(body), REQUEST_TIMEOUT).await?;
+                // Transactions whose check fails are rolled back with a conflict
+                if status == StatusCode::CONFLICT {
+                    return Ok(None);
+                }
+                check(status, &bytes)?;
+                let txn: ConsulTxn = serde_json::from_slice(&bytes)?;
+                match txn.results.into_iter().next() {
+                    Some(result) => Ok(Some(result.kv.modify_index)),
+                    None => anyhow::bail!("Consul didn't return the index of {}", key),
+                }
+            }
+        }
+    }
+
+    /// Signals `changes` of the writes of the snapshot at revisions this instance doesn't
+    /// know, until the receiver is dropped.
+    async fn run_watch(self: Arc<Self>, changes: mpsc::Sender<()>) {
+        while !changes.is_closed() {
+            let watched = match self.options.flavor {
+                KvFlavor::Etcd => self.watch_etcd(&changes).await,
+                KvFlavor::Consul => self.watch_consul(&changes).await,
+            };
+            if let Err(e) = watched {
+                eprintln!("Watching {} for changes failed, retrying: {:#}", self.options.endpoint, e);
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    }
+
+    fn signal(&self, revision: u64, changes: &mpsc::Sender<()>) {
+        // A change pending already covers this one
+        if revision > self.revision() {
+            let _ = changes.try_send(());
+        }
+    }
+
+    /// Watches the snapshot through a watch stream of etcd, until it ends.
+    async fn watch_etcd(&self, changes: &mpsc::Sender<()>) -> anyhow::Result<()> {
+        let key = self.key(SNAPSHOT_KEY);
+        // Writes made while it wasn't watched, and those after them
+        let mut request = json!({ "key": STANDARD.encode(&key) });
+        if let Some(current) = self.get(&key).await? {
+            self.signal(current.revision, changes);
+            request["start_revision"] = json!((current.revision + 1).to_string());
+        }
+        let response = self.send(Method::POST, "/v3/watch", Some(json!({ "create_request": request }))).await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            anyhow::bail!("{}: {}", status, String::from_utf8_lossy(&body).trim());
+        }
+        let mut body = response.into_body();
+        let mut buffer = Vec::new();
+        while let Some(chunk) = body.data().await {
+            buffer.extend_from_slice(&chunk?);
+            // The gateway writes each message of the stream on a line of its own
+            while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                if line.iter().all(u8::is_ascii_whitespace) {
+                    continue;
+                }
+                let message: EtcdWatchMessage = serde_json::from_slice(&line)?;
+                if let Some(error) = message.error {
+                    anyhow::bail!("{}", error);
+                }
+                let result = message.result.unwrap_or_default();
+                if result.canceled {
+                    anyhow::bail!("watch canceled: {}", result.cancel_reason);
+                }
+                for event in result.events.into_iter().filter(|event| event.kind.as_deref() != Some("DELETE")) {
+                    self.signal(event.kv.mod_revision, changes);
+                }
+            }
+            if changes.is_closed() {
+                return Ok(());
+            }
+        }
+        anyhow::bail!("watch stream ended")
+    }
+
+    /// Watches the snapshot through Consul blocking queries.
+    async fn watch_consul(&self, changes: &mpsc::Sender<()>) -> anyhow::Result<()> {
+        let path = format!("/v1/kv/{}", self.key(SNAPSHOT_KEY));
+        let mut index = 0;
+        while !changes.is_closed() {
+            let query = format!("{}?index={}&wait={}s", path, index, CONSUL_WAIT.as_secs());
+            let (status, next, bytes) = self.call(Method::GET, &query, None, CONSUL_WAIT + REQUEST_TIMEOUT).await?;
+            if status != StatusCode::NOT_FOUND {
+                check(status, &bytes)?;
+                let entries: Vec<ConsulEntry> = serde_json::from_slice(&bytes)?;
+                if let Some(entry) = entries.first() {
+                    self.signal(entry.modify_index, changes);
+                }
+            }
+            let next = next.ok_or_else(|| anyhow::anyhow!("Consul didn't return an index"))?;
+            // The index may go backwards, e.g. when the Consul servers are restored
+            index = if next < index { 0 } else { next };
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ZoneStore for KvStore {
+    async fn load(&self) -> anyhow::Result<Option<Snapshot>> {
+        let current = self.0.get(&self.0.key(SNAPSHOT_KEY)).await?;
+        let snapshot = current.as_ref().map(|current| Snapshot::decode(&current.value)).transpose()?;
+        *self.0.snapshot.lock().unwrap() = current;
+        Ok(snapshot)
+    }
+
+    async fn persist(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let value = snapshot.encode()?;
+        let revision = match &*self.0.snapshot.lock().unwrap() {
+            // Spares the other instances a reload, e.g. on restarts
+            Some(known) if known.value == value => return Ok(()),
+            Some(known) => known.revision,
+            None => 0,
+        };
+        match self.0.put(&self.0.key(SNAPSHOT_KEY), &value, revision).await? {
+            Some(revision) => {
+                *self.0.snapshot.lock().unwrap() = Some(Versioned { revision, value });
+                Ok(())
+            }
+            None => anyhow::bail!("the records were changed by another instance meanwhile, retry once they are reloaded"),
+        }
+    }
+
+    async fn journal(&self, entries: &[JournalEntry]) -> anyhow::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let key = self.0.key(JOURNAL_KEY);
+        for _ in 0..JOURNAL_ATTEMPTS {
+            let (revision, mut journal) = match self.0.get(&key).await? {
+                Some(current) => (current.revision, serde_json::from_slice::<VecDeque<JournalEntry>>(&current.value)?),
+                None => (0, VecDeque::new()),
+            };
+            persist::append(&mut journal, entries);
+            if self.0.put(&key, &serde_json::to_vec(&journal)?, revision).await?.is_some() {
+                return Ok(());
+            }
+        }
+        anyhow::bail!("the journal kept being changed by other instances")
+    }
+
+    async fn journaled(&self) -> anyhow::Result<Vec<JournalEntry>> {
+        match self.0.get(&self.0.key(JOURNAL_KEY)).await? {
+            Some(journal) => Ok(serde_json::from_slice(&journal.value)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn watch(&self) -> Option<mpsc::Receiver<()>> {
+        let (sender, receiver) = mpsc::channel(1);
+        tokio::spawn(self.0.clone().run_watch(sender));
+        Some(receiver)
+    }
+}
+
+/// Fails with the body of an error response.
+fn check(status: StatusCode, body: &[u8]) -> anyhow::Result<()> {
+    if !status.is_success() {
+        anyhow::bail!("{}: {}", status, String::from_utf8_lossy(body).trim());
+    }
+    Ok(())
+}
+
+/// Parses the 64-bit integers that the etcd gateway writes as strings.
+fn etcd_int<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Int {
+        Number(u64),
+        Text(String),
+    }
+    match Int::deserialize(deserializer)? {
+        Int::Number(number) => Ok(number),
+        Int::Text(text) => text.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Deserialize)]
+struct EtcdKv {
+    #[serde(default)]
+    value: String,
+    #[serde(default, deserialize_with = "etcd_int")]
+    mod_revision: u64,
+}
+
+impl EtcdKv {
+    fn versioned(self) -> anyhow::Result<Versioned> {
+        Ok(Versioned {
+            revision: self.mod_revision,
+            value: STANDARD.decode(self.value)?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct EtcdRange {
+    #[serde(default)]
+    kvs: Vec<EtcdKv>,
+}
+
+#[derive(Deserialize)]
+struct EtcdHeader {
+    #[serde(default, deserialize_with = "etcd_int")]
+    revision: u64,
+}
+
+#[derive(Deserialize)]
+struct EtcdTxn {
+    header: EtcdHeader,
+    #[serde(default)]
+    succeeded: bool,
+}
+
+#[derive(Deserialize)]
+struct EtcdWatchMessage {
+    result: Option<EtcdWatchResult>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Default, Deserialize)]
+struct EtcdWatchResult {
+    #[serde(default)]
+    events: Vec<EtcdEvent>,
+    #[serde(default)]
+    canceled: bool,
+    #[serde(default)]
+    cancel_reason: String,
+}
+
+#[derive(Deserialize)]
+struct EtcdEvent {
+    /// `DELETE` for deletions, left out for writes
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    kv: EtcdKv,
+}
+
+#[derive(Deserialize)]
+struct ConsulEntry {
+    #[serde(rename = "Value")]
+    value: Option<String>,
+    #[serde(rename = "ModifyIndex")]
+    modify_index: u64,
+}
+
+impl ConsulEntry {
+    fn versioned(self) -> anyhow::Result<Versioned> {
+        Ok(Versioned {
+            revision: self.modify_index,
+            value: STANDARD.decode(self.value.unwrap_or_default())?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct ConsulTxn {
+    #[serde(rename = "Results", default)]
+    results: Vec<ConsulResult>,
+}
+
+#[derive(Deserialize)]
+struct ConsulResult {
+    #[serde(rename = "KV")]
+    kv: ConsulEntry,
+}
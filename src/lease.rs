@@ -0,0 +1,115 @@
+//! Record leases.
+//!
+//! A record added with a lease, e.g. for a CI environment or a short-lived service
+//! instance, is removed by a reaper task once the lease lapses. Adding the same record
+//! again replaces its lease, or makes it permanent when added without one, and a record
+//! deleted or replaced in the meantime drops its lease with it. Leases are persisted and
+//! replicated along with the records, and only the leader reaps them.
+
+use hickory_proto::rr::{LowerName, Record, RrKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+
+use crate::dns::DnsState;
+
+/// How often lapsed leases are looked for.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The lease of one record, as persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LeaseEntry {
+    pub name: String,
+    pub record_type: String,
+    /// Record data in zone file syntax
+    pub value: String,
+    pub expires_at: SystemTime,
+}
+
+/// The leased records, by name and type and then by value.
+#[derive(Default)]
+pub struct Leases {
+    entries: Mutex<HashMap<RrKey, HashMap<String, LeaseEntry>>>,
+}
+
+/// The key and value a record's lease is kept under.
+fn key_of(record: &Record) -> (RrKey, String) {
+    let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+    (key, record.data().map(|data| data.to_string()).unwrap_or_default())
+}
+
+impl Leases {
+    /// Leases `record` until `expires_at`, or drops its lease when that is unset.
+    pub fn set(&self, record: &Record, expires_at: Option<SystemTime>) {
+        let (key, value) = key_of(record);
+        let mut entries = self.entries.lock().unwrap();
+        match expires_at {
+            Some(expires_at) => {
+                let entry = LeaseEntry {
+                    name: record.name().to_ascii(),
+                    record_type: record.record_type().to_string(),
+                    value: value.clone(),
+                    expires_at,
+                };
+                entries.entry(key).or_default().insert(value, entry);
+            }
+            None => {
+                if let Some(values) = entries.get_mut(&key) {
+                    values.remove(&value);
+                    if values.is_empty() {
+                        entries.remove(&key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drops the lease of `record`.
+    pub fn remove(&self, record: &Record) {
+        self.set(record, None);
+    }
+
+    /// Drops the leases of every value of a name and type.
+    pub fn remove_rrset(&self, key: &RrKey) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// Drops the leases of the names in the zone at `origin` and its subzones.
+    pub fn remove_zone(&self, origin: &LowerName) {
+        self.entries.lock().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Every lease, sorted by name, type and value.
+    pub fn entries(&self) -> Vec<LeaseEntry> {
+        let entries = self.entries.lock().unwrap();
+        let mut leases: Vec<LeaseEntry> = entries.values().flat_map(|values| values.values().cloned()).collect();
+        leases.sort_by(|a, b| (&a.name, &a.record_type, &a.value).cmp(&(&b.name, &b.record_type, &b.value)));
+        leases
+    }
+
+    /// The leases that have lapsed by `now`.
+    pub fn lapsed(&self, now: SystemTime) -> Vec<LeaseEntry> {
+        self.entries()
+            .into_iter()
+            .filter(|lease| lease.expires_at <= now)
+            .collect()
+    }
+}
+
+/// Removes the records whose leases lapsed, every second. Runs until the process exits.
+pub async fn run_reaper(state: Arc<RwLock<DnsState>>) {
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let state = state.read().await;
+        if !state.is_follower() {
+            state.reap_leases(SystemTime::now()).await;
+        }
+    }
+}
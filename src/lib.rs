@@ -0,0 +1,126 @@
+//! Authoritative DNS server and control plane, as a library.
+//!
+//! The `rdns` binary is a thin wrapper around this crate, which other binaries and tests
+//! can use to embed the servers themselves:
+//! - `DnsState` holds the hosted zones, shared between the servers as an
+//!   `Arc<RwLock<DnsState>>`;
+//! - `run_dns_server` answers queries from the state over UDP and TCP, and over TLS and
+//!   HTTPS when configured;
+//! - `ControlServer` implements the gRPC management API, served by `run_grpc_server`.
+//!
+//! Both servers take their options from the config file through `Settings`, or from
+//! `DnsOptions::builder()` and `GrpcOptions::builder()` when embedded. Listen addresses
+//! with port 0 are bound to a port the OS picks, which `DnsListeners` and `GrpcListener`
+//! tell once bound; `testing::TestServer` runs both servers that way for tests.
+
+pub mod acl;
+pub mod acme;
+pub mod acmecert;
+pub mod activation;
+pub mod additionals;
+pub mod alias;
+pub mod any;
+pub mod apilimit;
+pub mod apiversion;
+pub mod approval;
+pub mod audit;
+pub mod auth;
+pub mod blocklist;
+pub mod cache;
+pub mod catalogsnapshot;
+pub mod catalogzone;
+pub mod clientid;
+pub mod cluster;
+pub mod cnamechain;
+pub mod configcheck;
+pub mod daemon;
+pub mod control;
+pub mod cookies;
+pub mod delegation;
+pub mod denial;
+pub mod dhcp;
+pub mod dns;
+pub mod dns64;
+pub mod dnssec;
+pub mod dnstap;
+pub mod docker;
+pub mod doh;
+pub mod ecs;
+pub mod edns;
+pub mod error;
+pub mod events;
+pub mod family;
+pub mod forward;
+pub mod geo;
+pub mod hardening;
+pub mod health;
+pub mod history;
+pub mod hosts;
+pub mod identity;
+pub mod ixfr;
+pub mod kvstore;
+pub mod lease;
+pub mod logging;
+pub mod mailauth;
+pub mod mdns;
+pub mod metadata;
+pub mod migrate;
+pub mod metrics;
+pub mod negative;
+pub mod notifications;
+pub mod persist;
+pub mod overrides;
+pub mod pattern;
+pub mod pipeline;
+pub mod pool;
+pub mod privacy;
+pub mod privileges;
+pub mod probe;
+pub mod proxy;
+pub mod queryrules;
+pub mod quota;
+pub mod ratelimit;
+pub mod rebind;
+pub mod recordjson;
+pub mod reload;
+pub mod rest;
+pub mod rewrite;
+pub mod rollout;
+pub mod roundrobin;
+pub mod rpz;
+pub mod runtime;
+pub mod schedule;
+pub mod script;
+pub mod secondary;
+pub mod service;
+pub mod serverinfo;
+pub mod settings;
+pub mod shutdown;
+
+pub mod soa;
+pub mod sockopt;
+pub mod stats;
+pub mod svcb;
+pub mod telemetry;
+pub mod testing;
+pub mod template;
+pub mod tenant;
+pub mod timed;
+pub mod transfer;
+pub mod tsig;
+pub mod update;
+pub mod upstream;
+pub mod validate;
+pub mod validator;
+pub mod verify;
+pub mod views;
+pub mod webhook;
+pub mod webui;
+pub mod zonecheck;
+pub mod zonedir;
+pub mod zonefile;
+
+pub use control::{run_grpc_server, serve_grpc, ControlServer, GrpcListener, GrpcOptions};
+pub use dns::{run_dns_server, serve_dns, DnsListeners, DnsOptions, DnsState, HandlerOptions};
+pub use error::RdnsError;
+pub use settings::Settings;
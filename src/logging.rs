@@ -0,0 +1,114 @@
+//! Log verbosity, adjustable while the server runs.
+//!
+//! rdns writes its messages to stdout and its errors to stderr as they happen. The log
+//! level adds detail on top of them: at `debug`, every query is logged with its client,
+//! protocol, response code and how long it took, as is every attempt at an upstream and
+//! every answer taken from the cache of forwarded answers. Client addresses are
+//! anonymized as `[dns.privacy]` says.
+//!
+//! The level is set by `logging.level` in Config.toml and may be changed through the
+//! Admin service's `SetLogLevel` call, optionally only for a while: once the duration
+//! given lapses, the level in effect before returns, so that a level bumped to chase a
+//! problem on a production instance doesn't stay bumped.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// How much the server logs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogLevel {
+    /// Startup, configuration changes and failures.
+    #[default]
+    Info,
+    /// Every query and upstream exchange as well.
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(level: &str) -> anyhow::Result<Self> {
+        match level.to_ascii_lowercase().as_str() {
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => anyhow::bail!("unknown log level {}, expected info or debug", level),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        })
+    }
+}
+
+/// The level in effect, as a `LogLevel` discriminant.
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+/// When a temporary level lapses, and the level it returns to.
+static REVERT: Mutex<Option<(SystemTime, LogLevel)>> = Mutex::new(None);
+
+/// The level in effect.
+pub fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        level if level == LogLevel::Debug as u8 => LogLevel::Debug,
+        _ => LogLevel::Info,
+    }
+}
+
+/// Whether queries and upstream exchanges are logged.
+pub fn debug_enabled() -> bool {
+    LEVEL.load(Ordering::Relaxed) == LogLevel::Debug as u8
+}
+
+/// Sets the level until it is changed again, dropping a temporary level's return.
+pub fn set_level(level: LogLevel) {
+    *REVERT.lock().unwrap() = None;
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Sets the level for `duration`, after which the level in effect before returns. A
+/// temporary level set while another is in effect returns to the level before both.
+///
+/// Must be called within a Tokio runtime.
+pub fn set_level_for(level: LogLevel, duration: Duration) {
+    let until = SystemTime::now() + duration;
+    {
+        let mut revert = REVERT.lock().unwrap();
+        let previous = revert.map_or_else(self::level, |(_, previous)| previous);
+        *revert = Some((until, previous));
+        LEVEL.store(level as u8, Ordering::Relaxed);
+    }
+    tokio::spawn(async move {
+        tokio::time::sleep(duration).await;
+        let mut revert = REVERT.lock().unwrap();
+        // Unless the level was set again meanwhile
+        if let Some((at, previous)) = *revert {
+            if at == until {
+                *revert = None;
+                LEVEL.store(previous as u8, Ordering::Relaxed);
+                println!("Log level back to {}", previous);
+            }
+        }
+    });
+}
+
+/// When a temporary level lapses, if one is in effect.
+pub fn reverts_at() -> Option<SystemTime> {
+    REVERT.lock().unwrap().map(|(at, _)| at)
+}
+
+/// Writes a message to stdout when the log level is `debug`.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::logging::debug_enabled() {
+            println!($($arg)*);
+        }
+    };
+}
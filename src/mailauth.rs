@@ -0,0 +1,439 @@
+//! Checks of the email authentication TXT records: SPF (RFC 7208), DKIM keys (RFC 6376)
+//! and DMARC policies (RFC 7489).
+//!
+//! A TXT record added through the APIs is checked as an SPF record when its value starts
+//! with `v=spf1`, as a DKIM key when its name has a `_domainkey` label or its value
+//! starts with `v=DKIM1`, and as a DMARC policy when its name starts with `_dmarc` or its
+//! value starts with `v=DMARC1`. Its strings are joined without spaces first, as
+//! receivers do, so an SPF value given unquoted, which becomes one string per term, is
+//! rejected with a hint to quote it.
+//!
+//! SPF records are held to the terms RFC 7208 defines, with well-formed addresses and
+//! prefixes, at most 10 terms causing DNS lookups, and nothing after `all`. DKIM keys
+//! need a `p` tag of base64 of an Ed25519 key or an RSA key of at least 1024 bits (RFC
+//! 8301), and DMARC policies a `p` of `none`, `quarantine` or `reject` and tags of the
+//! values RFC 7489 defines. As receivers ignore SPF records and DMARC policies once a
+//! name has more than one, adding a second one next to the first is a `Conflict`. Other
+//! failures are `InvalidRdata` errors.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hickory_proto::rr::{Name, RData, Record};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::error::RdnsError;
+use crate::validate;
+
+/// Most terms of an SPF record that cause DNS lookups (RFC 7208 section 4.6.4).
+const MAX_SPF_LOOKUPS: usize = 10;
+/// Fewest bits of a DKIM RSA key (RFC 8301 section 3.2).
+const MIN_RSA_BITS: usize = 1024;
+/// Length of a DKIM Ed25519 key (RFC 8463).
+const ED25519_KEY_LEN: usize = 32;
+/// Hint for a tag list cut short, as `;` starts a comment in an unquoted value.
+const QUOTE_HINT: &str = "; quote the value, as `;` starts a comment unless quoted";
+
+/// What an email authentication record is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Spf,
+    Dkim,
+    Dmarc,
+}
+
+/// The strings of a TXT record, joined as receivers join them.
+fn text_of(record: &Record) -> Option<(Vec<String>, String)> {
+    let RData::TXT(txt) = record.data()? else {
+        return None;
+    };
+    let strings: Vec<String> = txt.txt_data().iter().map(|string| String::from_utf8_lossy(string).into_owned()).collect();
+    let text = strings.concat();
+    Some((strings, text))
+}
+
+/// What the TXT record at `name` holding `strings` is, if it is an email authentication
+/// record.
+fn kind_of(name: &Name, strings: &[String], text: &str) -> Option<Kind> {
+    let starts_with = |prefix: &str| {
+        let first = strings.first().map(String::as_str).unwrap_or_default();
+        first.len() >= prefix.len() && first[..prefix.len()].eq_ignore_ascii_case(prefix)
+    };
+    let label = |label: &[u8]| label.eq_ignore_ascii_case(b"_domainkey");
+    if starts_with("v=spf1") {
+        Some(Kind::Spf)
+    } else if name.iter().next().is_some_and(|first| first.eq_ignore_ascii_case(b"_dmarc")) || text.starts_with("v=DMARC1") {
+        Some(Kind::Dmarc)
+    } else if name.iter().skip(1).any(label) || text.starts_with("v=DKIM1") {
+        Some(Kind::Dkim)
+    } else {
+        None
+    }
+}
+
+/// Checks `record` if it is an SPF, DKIM or DMARC record.
+pub fn check(record: &Record) -> Result<(), RdnsError> {
+    let Some((strings, text)) = text_of(record) else {
+        return Ok(());
+    };
+    let result = match kind_of(record.name(), &strings, &text) {
+        Some(Kind::Spf) => spf(&strings, &text),
+        Some(Kind::Dkim) => dkim(&text),
+        Some(Kind::Dmarc) => dmarc(record.name(), &text),
+        None => Ok(()),
+    };
+    result.map_err(RdnsError::InvalidRdata)
+}
+
+/// Checks that `record` can join the TXT records `existing` of its name: a name may only
+/// have one SPF record and one DMARC policy.
+pub fn check_conflict<'a>(existing: impl IntoIterator<Item = &'a Record>, record: &Record) -> Result<(), RdnsError> {
+    let Some((strings, text)) = text_of(record) else {
+        return Ok(());
+    };
+    let kind = match kind_of(record.name(), &strings, &text) {
+        Some(kind @ (Kind::Spf | Kind::Dmarc)) => kind,
+        _ => return Ok(()),
+    };
+    let conflicting = existing.into_iter().any(|other| {
+        other.data() != record.data()
+            && text_of(other).is_some_and(|(strings, text)| kind_of(other.name(), &strings, &text) == Some(kind))
+    });
+    if conflicting {
+        let what = if kind == Kind::Spf { "an SPF record" } else { "a DMARC policy" };
+        return Err(RdnsError::Conflict(format!(
+            "{} already has {}, and receivers ignore both once there are two; update it instead",
+            record.name(),
+            what
+        )));
+    }
+    Ok(())
+}
+
+/// Checks an SPF record (RFC 7208 section 4.5 and 12).
+fn spf(strings: &[String], text: &str) -> Result<(), String> {
+    if text.len() > 6 && !text[6..].starts_with(' ') {
+        let hint = if strings.len() > 1 && strings[0].len() == 6 {
+            ", quote the value so it isn't split into one string per term"
+        } else {
+            ""
+        };
+        return Err(format!("SPF record {} must start with `v=spf1 `{}", text, hint));
+    }
+
+    let mut lookups = 0;
+    let mut all = false;
+    let mut redirect = false;
+    let mut explanation = false;
+    for term in text[6..].split(' ').filter(|term| !term.is_empty()) {
+        if all {
+            return Err(format!("SPF term {} follows `all` and is never evaluated", term));
+        }
+        // A modifier is `name=value`, its name starting with a letter
+        if let Some((name, value)) = term.split_once('=').filter(|(name, _)| modifier_name(name)) {
+            match name.to_ascii_lowercase().as_str() {
+                "redirect" => {
+                    if redirect {
+                        return Err("SPF record may only have one redirect modifier".into());
+                    }
+                    redirect = true;
+                    lookups += 1;
+                    domain_spec(term, value)?;
+                }
+                "exp" => {
+                    if explanation {
+                        return Err("SPF record may only have one exp modifier".into());
+                    }
+                    explanation = true;
+                    domain_spec(term, value)?;
+                }
+                _ => {}
+            }
+            continue;
+        }
+
+        let mechanism = term.strip_prefix(['+', '-', '~', '?']).unwrap_or(term);
+        let name_end = mechanism.find([':', '/']).unwrap_or(mechanism.len());
+        let (name, rest) = mechanism.split_at(name_end);
+        let argument = rest.strip_prefix(':');
+        match name.to_ascii_lowercase().as_str() {
+            "all" if rest.is_empty() => all = true,
+            "include" | "exists" => {
+                lookups += 1;
+                let Some(domain) = argument else {
+                    return Err(format!("SPF mechanism {} needs a domain, e.g. {}:_spf.example.com", term, name));
+                };
+                domain_spec(term, domain)?;
+            }
+            "a" | "mx" => {
+                lookups += 1;
+                let (domain, prefixes) = match argument {
+                    Some(argument) => match argument.find('/') {
+                        Some(slash) => (Some(&argument[..slash]), &argument[slash..]),
+                        None => (Some(argument), ""),
+                    },
+                    None => (None, rest),
+                };
+                if let Some(domain) = domain {
+                    domain_spec(term, domain)?;
+                }
+                dual_prefix(term, prefixes)?;
+            }
+            "ptr" => {
+                lookups += 1;
+                if let Some(domain) = argument {
+                    domain_spec(term, domain)?;
+                }
+            }
+            "ip4" | "ip6" => {
+                let argument = argument.unwrap_or_default();
+                let (address, prefix) = match argument.split_once('/') {
+                    Some((address, prefix)) => (address, Some(prefix)),
+                    None => (argument, None),
+                };
+                if name.eq_ignore_ascii_case("ip4") {
+                    if address.parse::<Ipv4Addr>().is_err() {
+                        return Err(format!("SPF mechanism {} needs an IPv4 address, e.g. ip4:192.0.2.0/24", term));
+                    }
+                    prefix_len(term, prefix, 32)?;
+                } else {
+                    if address.parse::<Ipv6Addr>().is_err() {
+                        return Err(format!("SPF mechanism {} needs an IPv6 address, e.g. ip6:2001:db8::/32", term));
+                    }
+                    prefix_len(term, prefix, 128)?;
+                }
+            }
+            _ => return Err(format!("unknown SPF term {}", term)),
+        }
+    }
+    if lookups > MAX_SPF_LOOKUPS {
+        return Err(format!(
+            "SPF record causes {} DNS lookups, more than the {} receivers allow",
+            lookups, MAX_SPF_LOOKUPS
+        ));
+    }
+    if all && redirect {
+        return Err("SPF redirect is ignored in a record with an `all` mechanism".into());
+    }
+    Ok(())
+}
+
+/// Whether `name` is the name of an SPF modifier, rather than part of a mechanism.
+fn modifier_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic())
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// Checks the domain of an SPF term, a hostname unless it holds macros.
+fn domain_spec(term: &str, domain: &str) -> Result<(), String> {
+    if domain.is_empty() {
+        return Err(format!("SPF term {} has an empty domain", term));
+    }
+    if domain.contains('%') {
+        return Ok(());
+    }
+    validate::hostname(domain, false, "SPF domain").map_err(|e| format!("SPF term {}: {}", term, e))
+}
+
+/// Checks the `/prefix` and `//prefix6` suffix of an SPF `a` or `mx` mechanism.
+fn dual_prefix(term: &str, prefixes: &str) -> Result<(), String> {
+    if prefixes.is_empty() {
+        return Ok(());
+    }
+    let (ip4, ip6) = match prefixes.split_once("//") {
+        Some((ip4, ip6)) => (ip4, Some(ip6)),
+        None => (prefixes, None),
+    };
+    if let Some(ip4) = Some(ip4).filter(|ip4| !ip4.is_empty()) {
+        prefix_len(term, ip4.strip_prefix('/'), 32)?;
+    }
+    if ip6.is_some() {
+        prefix_len(term, ip6, 128)?;
+    }
+    Ok(())
+}
+
+/// Checks a prefix length of an SPF term, at most `max`.
+fn prefix_len(term: &str, prefix: Option<&str>, max: u8) -> Result<(), String> {
+    let Some(prefix) = prefix else {
+        return Ok(());
+    };
+    // Without leading zeros, which RFC 7208 doesn't allow
+    if prefix.parse::<u8>().is_ok_and(|len| len <= max) && (prefix == "0" || !prefix.starts_with('0')) {
+        Ok(())
+    } else {
+        Err(format!("SPF term {} has prefix length {}, expected 0 to {}", term, prefix, max))
+    }
+}
+
+/// Splits a DKIM or DMARC tag list, `tag=value; ...`, failing on a malformed or
+/// repeated tag; `what` names the record in error messages.
+fn tags<'a>(what: &str, text: &'a str) -> Result<Vec<(&'a str, &'a str)>, String> {
+    let mut tags: Vec<(&str, &str)> = Vec::new();
+    for spec in text.split(';').map(str::trim).filter(|spec| !spec.is_empty()) {
+        let Some((tag, value)) = spec.split_once('=') else {
+            return Err(format!("{} tag {} must be tag=value", what, spec));
+        };
+        let tag = tag.trim();
+        if !tag.starts_with(|c: char| c.is_ascii_alphabetic()) || !tag.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_') {
+            return Err(format!("{} tag {} has an invalid name", what, spec));
+        }
+        if tags.iter().any(|(seen, _)| *seen == tag) {
+            return Err(format!("{} tag {} is given more than once", what, tag));
+        }
+        tags.push((tag, value.trim()));
+    }
+    Ok(tags)
+}
+
+/// Checks a DKIM key record (RFC 6376 section 3.6.1).
+fn dkim(text: &str) -> Result<(), String> {
+    let tags = tags("DKIM", text)?;
+    if let Some(position) = tags.iter().position(|(tag, _)| *tag == "v") {
+        if position != 0 || tags[0].1 != "DKIM1" {
+            return Err("DKIM version must come first, as v=DKIM1".into());
+        }
+    }
+    let tag = |name: &str| tags.iter().find(|(tag, _)| *tag == name).map(|(_, value)| *value);
+    let algorithm = tag("k").unwrap_or("rsa");
+    if algorithm != "rsa" && algorithm != "ed25519" {
+        return Err(format!("DKIM key type k={} must be rsa or ed25519", algorithm));
+    }
+    if let Some(hashes) = tag("h") {
+        if let Some(hash) = hashes.split(':').map(str::trim).find(|hash| *hash != "sha1" && *hash != "sha256") {
+            return Err(format!("DKIM hash algorithm {} must be sha1 or sha256", hash));
+        }
+    }
+    let Some(key) = tag("p") else {
+        let hint = if tags.len() == 1 { QUOTE_HINT } else { "" };
+        return Err(format!("DKIM record needs the public key as its p tag{}", hint));
+    };
+    // An empty key means the key was revoked
+    let key: String = key.chars().filter(|c| !c.is_whitespace()).collect();
+    if key.is_empty() {
+        return Ok(());
+    }
+    let key = STANDARD
+        .decode(&key)
+        .map_err(|e| format!("DKIM public key p= is not valid base64: {}", e))?;
+    match algorithm {
+        "ed25519" if key.len() != ED25519_KEY_LEN => Err(format!(
+            "DKIM Ed25519 key must be {} bytes, not {}",
+            ED25519_KEY_LEN,
+            key.len()
+        )),
+        "ed25519" => Ok(()),
+        _ => match rsa_modulus_bits(&key) {
+            Some(bits) if bits < MIN_RSA_BITS => Err(format!(
+                "DKIM RSA key of {} bits is shorter than the {} bits receivers accept",
+                bits, MIN_RSA_BITS
+            )),
+            Some(_) => Ok(()),
+            None => Err("DKIM public key p= is not a DER-encoded RSA public key".into()),
+        },
+    }
+}
+
+/// Reads a DER element at the start of `data`: its tag, contents and what follows it.
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &rest[count..])
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// The size of the modulus of an RSA key, given as a SubjectPublicKeyInfo as RFC 6376
+/// says or as the bare RSAPublicKey some signers publish.
+fn rsa_modulus_bits(key: &[u8]) -> Option<usize> {
+    const SEQUENCE: u8 = 0x30;
+    const INTEGER: u8 = 0x02;
+    const BIT_STRING: u8 = 0x03;
+    let (tag, contents, _) = der_element(key)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (first, _, rest) = der_element(contents)?;
+    let rsa_key = match first {
+        // SubjectPublicKeyInfo: the algorithm, then the key as a bit string
+        SEQUENCE => {
+            let (tag, bits, _) = der_element(rest)?;
+            if tag != BIT_STRING {
+                return None;
+            }
+            let (_, key) = bits.split_first()?;
+            let (tag, contents, _) = der_element(key)?;
+            if tag != SEQUENCE {
+                return None;
+            }
+            contents
+        }
+        INTEGER => contents,
+        _ => return None,
+    };
+    let (tag, modulus, _) = der_element(rsa_key)?;
+    if tag != INTEGER {
+        return None;
+    }
+    let modulus = match modulus.iter().position(|&b| b != 0) {
+        Some(start) => &modulus[start..],
+        None => return Some(0),
+    };
+    Some(modulus.len() * 8 - modulus[0].leading_zeros() as usize)
+}
+
+/// Checks a DMARC policy record at `name` (RFC 7489 section 6.3).
+fn dmarc(name: &Name, text: &str) -> Result<(), String> {
+    if !name.iter().next().is_some_and(|first| first.eq_ignore_ascii_case(b"_dmarc")) {
+        return Err(format!("DMARC policies are only looked up at _dmarc names, not at {}", name));
+    }
+    let tags = tags("DMARC", text)?;
+    if tags.first() != Some(&("v", "DMARC1")) {
+        return Err("DMARC record must start with v=DMARC1".into());
+    }
+    let tag = |name: &str| tags.iter().find(|(tag, _)| *tag == name).map(|(_, value)| *value);
+    let policy = |tag: &str, value: &str| match value {
+        "none" | "quarantine" | "reject" => Ok(()),
+        _ => Err(format!("DMARC {}={} must be none, quarantine or reject", tag, value)),
+    };
+    let Some(p) = tag("p") else {
+        let hint = if tags.len() == 1 { QUOTE_HINT } else { "" };
+        return Err(format!("DMARC record needs a policy, e.g. p=none{}", hint));
+    };
+    policy("p", p)?;
+    if let Some(sp) = tag("sp") {
+        policy("sp", sp)?;
+    }
+    for alignment in ["adkim", "aspf"] {
+        if let Some(value) = tag(alignment).filter(|value| *value != "r" && *value != "s") {
+            return Err(format!("DMARC {}={} must be r (relaxed) or s (strict)", alignment, value));
+        }
+    }
+    if let Some(pct) = tag("pct").filter(|pct| !pct.parse::<u8>().is_ok_and(|pct| pct <= 100)) {
+        return Err(format!("DMARC pct={} must be 0 to 100", pct));
+    }
+    if let Some(ri) = tag("ri").filter(|ri| ri.parse::<u32>().is_err()) {
+        return Err(format!("DMARC ri={} must be a number of seconds", ri));
+    }
+    if let Some(fo) = tag("fo") {
+        if let Some(option) = fo.split(':').map(str::trim).find(|option| !matches!(*option, "0" | "1" | "d" | "s")) {
+            return Err(format!("DMARC failure reporting option fo={} must be 0, 1, d or s", option));
+        }
+    }
+    for reports in ["rua", "ruf"] {
+        if let Some(uris) = tag(reports) {
+            // A report URI may end in a size limit, e.g. mailto:dmarc@example.com!10m
+            if let Some(uri) = uris.split(',').map(str::trim).find(|uri| !uri.contains(':') || uri.contains(char::is_whitespace)) {
+                return Err(format!("DMARC {} address {} must be a URI, e.g. mailto:dmarc@example.com", reports, uri));
+            }
+        }
+    }
+    Ok(())
+}
@@ -4,20 +4,40 @@
 //! - A DNS UDP server using `hickory-server` on port 8053
 //! - A gRPC server using `tonic` on port 50051 to expose DNS management APIs via protobuf.
 //!
-//! The DNS server is managed by `dns::DnsState`, and the gRPC server by `control::ControlServer`.
+//! The DNS server is managed by `rdns::DnsState`, and the gRPC server by `rdns::ControlServer`,
+//! both from the library crate this binary wraps. `rdns check-config [PATH]` only checks
+//! a config, Config.toml by default, without starting anything, and on Windows
+//! `rdns service` runs the servers as a service, see `rdns::daemon`.
 
-mod control;
-mod dns;
-mod settings;
-
-use settings::Settings;
-use control::ControlServer;
-use dns::DnsState;
+use anyhow::Context;
+use rdns::acme::{self, AcmeDnsOptions, AcmeOptions};
+use rdns::acmecert::{CertificateManager, CertificateOptions};
+use rdns::audit::{AuditLog, AuditOptions};
+use rdns::auth::Authenticator;
+use rdns::cluster::{self, Cluster, ClusterOptions};
+use rdns::daemon::{Daemon, DaemonOptions};
+use rdns::dnstap::{Dnstap, DnstapOptions};
+use rdns::events::{self, EventOptions};
+use rdns::metrics::{self, Metrics, MetricsOptions};
+use rdns::notifications::{self, NotificationOptions};
+use rdns::persist::{self, StorageOptions, Store};
+use rdns::privileges::PrivilegeOptions;
+use rdns::probe::{ProbeOptions, Prober};
+use rdns::reload::{self, Reloader};
+use rdns::serverinfo;
+use rdns::rest::{self, RestOptions};
+use rdns::runtime::RuntimeOptions;
+use rdns::shutdown::{self, Shutdown, ShutdownOptions};
+use rdns::telemetry::{TelemetryOptions, Tracer};
+use rdns::verify::Listeners;
+use rdns::webhook::{self, WebhookOptions};
+use rdns::zonedir::ZoneDir;
+use rdns::{configcheck, dhcp, dnssec, docker, hosts, lease, logging, mdns, rpz, schedule, secondary};
+use rdns::{ControlServer, DnsOptions, DnsState, GrpcOptions, HandlerOptions, Settings};
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-
-use crate::control::GrpcOptions;
-use crate::dns::DnsOptions;
+use tokio::runtime::Handle;
+use tokio::sync::{watch, RwLock};
 
 /// Main entry point. Initializes shared state and starts both DNS and gRPC servers.
 ///
@@ -28,35 +48,358 @@ use crate::dns::DnsOptions;
 /// # Behavior
 ///
 /// - Initializes an `Arc<RwLock<DnsState>>` to be shared between both servers.
-/// - Spawns the DNS server in a background task.
-/// - Starts the gRPC server on port 50051 and blocks the main thread.
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// - Spawns the DNS server in a background task, on a runtime of its own if configured.
+/// - Starts the gRPC server on port 50051 and blocks the main thread until SIGTERM or
+///   SIGINT, or Ctrl-C or a service stop on Windows, then drains every server and writes
+///   a final snapshot.
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        // `rdns check-config [PATH]` only checks the config
+        Some("check-config") => {
+            let path = args.next().unwrap_or_else(|| "Config.toml".to_string());
+            std::process::exit(check_config(Path::new(&path)));
+        }
+        #[cfg(windows)]
+        Some("service") => rdns::daemon::run_service(serve),
+        _ => serve(),
+    }
+}
+
+/// Loads the config and runs the servers until they shut down.
+fn serve() -> anyhow::Result<()> {
     // load config settings
-    let settings = load_settings().expect("Failed to load config");
-    let dns_options = DnsOptions::from(settings.dns);
-    let grpc_options = GrpcOptions::from(settings.grpc);
+    let settings = Settings::load().context("failed to load the config, check it with `rdns check-config`")?;
+    // Detached before any runtime starts its threads, which the fork would leave behind
+    let daemon = Daemon::start(DaemonOptions::from(settings.daemon.clone()))?;
+    let result = start_runtimes(settings, &daemon);
+    if let Err(e) = &result {
+        daemon.failed(e);
+    }
+    result
+}
+
+/// Builds the runtimes and runs the servers on them.
+fn start_runtimes(settings: Settings, daemon: &Daemon) -> anyhow::Result<()> {
+    let runtime_options = RuntimeOptions::from(settings.runtime.clone());
+    // Built out here, as a runtime can't be dropped from within another
+    let dns_runtime = runtime_options.dns_runtime()?;
+    let dns_handle = dns_runtime.as_ref().map(|runtime| runtime.handle().clone());
+    runtime_options.main_runtime()?.block_on(run(settings, dns_handle, daemon))
+}
+
+/// Prints the problems with the config at `path`, returning the exit status: 0 when there
+/// are none, 1 otherwise.
+fn check_config(path: &Path) -> i32 {
+    let problems = configcheck::check_file(path);
+    for problem in &problems {
+        eprintln!("{}:{}", path.display(), problem);
+    }
+    match problems.len() {
+        0 => {
+            println!("{} is valid", path.display());
+            0
+        }
+        count => {
+            eprintln!("{} problem{} found in {}", count, if count == 1 { "" } else { "s" }, path.display());
+            1
+        }
+    }
+}
+
+/// Runs the servers on the current runtime, or the DNS server on `dns_runtime` if given,
+/// reporting to `daemon` once they serve and when they shut down.
+async fn run(settings: Settings, dns_runtime: Option<Handle>, daemon: &Daemon) -> anyhow::Result<()> {
+    let initial_settings = settings.clone();
+    let mut dns_options = DnsOptions::try_from(settings.dns)?;
+    let listeners = Listeners::try_from(&dns_options)?;
+    let mut grpc_options = GrpcOptions::try_from(settings.grpc)?;
+    let store = Store::from_options(StorageOptions::try_from(settings.storage)?)?;
+    let metrics = Arc::new(Metrics::new()?);
+    let probe_options = settings.probe.map(ProbeOptions::try_from).transpose()?;
+    logging::set_level(settings.logging.level.parse()?);
+    let dnstap_options = settings.logging.dnstap.map(DnstapOptions::try_from).transpose()?;
+    let tracer = settings.telemetry.map(TelemetryOptions::try_from).transpose()?.map(Tracer::spawn);
+    let shutdown_options = ShutdownOptions::from(settings.shutdown);
+    let cluster = settings.cluster.map(ClusterOptions::try_from).transpose()?.map(Cluster::new).map(Arc::new);
+    let notification_options: Vec<NotificationOptions> = settings
+        .notifications
+        .into_iter()
+        .map(NotificationOptions::try_from)
+        .collect::<anyhow::Result<_>>()?;
+    let event_options = settings.events.map(EventOptions::try_from).transpose()?;
+    let acme_dns_options = settings.acme.acme_dns.clone().map(AcmeDnsOptions::try_from).transpose()?;
+    let certificate_options = settings.acme.certificate.clone().map(CertificateOptions::try_from).transpose()?;
+    let acme_options = AcmeOptions::from(settings.acme);
+    // Set up ahead of the listeners, for them to serve the certificate obtained before
+    let certificate_manager = certificate_options
+        .map(|options| CertificateManager::new(options, acme_options.clone()))
+        .transpose()?;
+    if let Some(certificate_manager) = &certificate_manager {
+        dns_options.certificates = Some(certificate_manager.resolver());
+    }
+
+    // Initialize shared DNS state with in-memory authority, reloading persisted records
+    let store_changes = store.as_ref().and_then(|store| store.watch());
+    let mut dns_state = DnsState::new(&dns_options, store, metrics.clone()).await?;
+    for zone_file in &dns_options.zone_files {
+        let count = dns_state.import_zone_file(zone_file).await?;
+        println!("Imported {} records from {}", count, zone_file.path.display());
+    }
+    let mut zone_dir = dns_options.zone_dir.clone().map(ZoneDir::new);
+    if let Some(zone_dir) = &mut zone_dir {
+        zone_dir.sync(&mut dns_state).await;
+    }
+    // A follower only takes changes from its leader from here on
+    if let Some(leader) = cluster.as_ref().and_then(|cluster| cluster.leader()) {
+        dns_state.set_leader(Some(leader.to_string()));
+    }
+    let dns_state = Arc::new(RwLock::new(dns_state));
+
+    // Keep a follower in sync with its leader
+    if let Some(cluster) = &cluster {
+        tokio::spawn(cluster::run_follower(dns_state.clone(), cluster.clone()));
+    }
+
+    // Reload the records whenever another instance sharing the store changes them
+    if let Some(changes) = store_changes {
+        tokio::spawn(persist::run_reloader(dns_state.clone(), changes));
+    }
+
+    // Remove the leased records once their leases lapse
+    tokio::spawn(lease::run_reaper(dns_state.clone()));
+    // Apply the scheduled record changes once they are due
+    tokio::spawn(schedule::run_scheduler(dns_state.clone()));
+    // Roll the keys of signed zones over once their rollover steps are due
+    if dns_options.dnssec.is_some() {
+        tokio::spawn(dnssec::run_rollovers(dns_state.clone()));
+    }
+    // Reload the zones of the zone directory when their files change
+    if let Some(zone_dir) = zone_dir {
+        tokio::spawn(zone_dir.run(dns_state.clone()));
+    }
+
+    // Keep secondary zones in sync with their primaries
+    for zone in &dns_options.secondary_zones {
+        tokio::spawn(secondary::run_zone_sync(dns_state.clone(), zone.clone()));
+    }
+    // Keep the records of the running Docker containers in sync
+    if let Some(docker) = &dns_options.docker {
+        tokio::spawn(docker::run_docker_sync(dns_state.clone(), docker.clone()));
+    }
+    // Keep the records of the DHCP leases in sync
+    if let Some(dhcp) = &dns_options.dhcp {
+        tokio::spawn(dhcp::run_lease_sync(dns_state.clone(), dhcp.clone()));
+    }
+    // Keep the records of the hosts file entries in sync
+    if let Some(hosts) = &dns_options.hosts {
+        tokio::spawn(hosts::run_hosts_sync(dns_state.clone(), hosts.clone()));
+    }
+    // Send record and zone changes to the notification endpoints
+    if !notification_options.is_empty() {
+        tokio::spawn(notifications::run_notifier(dns_state.clone(), notification_options));
+    }
+    // Publish record changes and queries to Kafka or NATS
+    if let Some(event_options) = event_options {
+        tokio::spawn(events::run_exporter(dns_state.clone(), event_options));
+    }
+    // Answer mDNS queries for the .local zone and advertise its services
+    if let Some(mdns) = &dns_options.mdns {
+        tokio::spawn(mdns::run_responder(dns_state.clone(), mdns.clone()));
+    }
 
-    // Initialize shared DNS state with in-memory authority
-    let dns_state = Arc::new(RwLock::new(DnsState::new()?));
+    // Request handling policy, replaced when the config is reloaded
+    let (handler_options_tx, handler_options) = watch::channel(Arc::new(HandlerOptions {
+        update: dns_options.update.take(),
+        transfer: dns_options.transfer.clone(),
+        dnstap: dnstap_options.map(|dnstap| Dnstap::spawn(dnstap, dns_options.privacy.clone())),
+        tracer: tracer.clone(),
+        privacy: dns_options.privacy.clone(),
+        answer_order: dns_options.answer_order.clone(),
+        cname_chain_depth: dns_options.cname_chain_depth,
+        additional_records: dns_options.additional_records,
+        any_response: dns_options.any_response,
+        address_family: dns_options.address_family,
+        pipeline: dns_options.pipeline.clone(),
+        acl: dns_options.acl.take(),
+        rpz: rpz::load_all(&dns_options.rpz).await?,
+        negative: std::mem::take(&mut dns_options.negative),
+        ecs: dns_options.ecs,
+        identity: dns_options.identity.take(),
+        dns64: dns_options.dns64.take(),
+        script: dns_options.script.take(),
+        clients: dns_options.clients.take(),
+    }));
 
-    // Spawn the DNS server in a background task
+    // Servers stop accepting work once the shutdown signal is raised
+    let (shutdown_trigger, shutdown) = Shutdown::new();
+    let mut servers = Vec::new();
+
+    // Spawn the DNS server in a background task, tracking whether it's up for health checks
+    let (dns_ready_tx, dns_ready) = watch::channel(false);
     {
         let dns_state = dns_state.clone();
+        let shutdown = shutdown.clone();
+        let dns_server = async move {
+            let result = rdns::run_dns_server(dns_state, dns_options, handler_options, dns_ready_tx.clone(), shutdown).await;
+            if let Err(e) = result {
+                eprintln!("DNS server failed: {}", e);
+            }
+            dns_ready_tx.send_replace(false);
+        };
+        servers.push(match &dns_runtime {
+            Some(runtime) => runtime.spawn(dns_server),
+            None => tokio::spawn(dns_server),
+        });
+    }
+
+    // Switch to an unprivileged user, if configured, once the DNS listeners are bound
+    if let Some(privilege_settings) = settings.privileges {
+        let mut dns_ready = dns_ready.clone();
+        if dns_ready.wait_for(|ready| *ready).await.is_err() {
+            anyhow::bail!("DNS server stopped before its listeners were bound");
+        }
+        PrivilegeOptions::from(privilege_settings).drop_privileges()?;
+    }
+
+    // Serve metrics, if enabled, in a background task
+    if let Some(metrics_settings) = settings.metrics {
+        let metrics = metrics.clone();
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move {
+            if let Err(e) = metrics::run_metrics_server(metrics, MetricsOptions::from(metrics_settings), shutdown).await {
+                eprintln!("Metrics server failed: {}", e);
+            }
+        }));
+    }
+
+    let auth = Arc::new(Authenticator::load(grpc_options.auth.take()).await?);
+    let audit = match settings.audit {
+        Some(audit_settings) => Some(Arc::new(AuditLog::open(AuditOptions::from(audit_settings)).await?)),
+        None => None,
+    };
+
+    // Serve the REST gateway, if enabled, in a background task
+    if let Some(rest_settings) = settings.rest {
+        let dns_state = dns_state.clone();
+        let auth = auth.clone();
+        let audit = audit.clone();
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move {
+            if let Err(e) = rest::run_rest_server(dns_state, auth, audit, RestOptions::from(rest_settings), shutdown).await {
+                eprintln!("REST gateway failed: {}", e);
+            }
+        }));
+    }
+
+    // Serve the external-dns webhook provider, if enabled, in a background task
+    if let Some(webhook_settings) = settings.webhook {
+        let dns_state = dns_state.clone();
+        let audit = audit.clone();
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move {
+            let options = WebhookOptions::from(webhook_settings);
+            if let Err(e) = webhook::run_webhook_server(dns_state, audit, options, shutdown).await {
+                eprintln!("external-dns webhook failed: {}", e);
+            }
+        }));
+    }
+
+    // Serve the acme-dns compatible API, if enabled, in a background task
+    if let Some(acme_dns_options) = acme_dns_options {
+        let dns_state = dns_state.clone();
+        let audit = audit.clone();
+        let acme_options = acme_options.clone();
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move {
+            if let Err(e) = acme::run_acme_dns_server(dns_state, audit, acme_options, acme_dns_options, shutdown).await {
+                eprintln!("acme-dns API failed: {}", e);
+            }
+        }));
+    }
+
+    // Obtain and renew the DoT/DoH certificate through ACME, if enabled, once DNS is served
+    if let Some(certificate_manager) = certificate_manager {
+        let dns_state = dns_state.clone();
+        let mut dns_ready = dns_ready.clone();
+        let shutdown = shutdown.clone();
+        servers.push(tokio::spawn(async move {
+            if dns_ready.wait_for(|ready| *ready).await.is_ok() {
+                certificate_manager.run(dns_state, shutdown).await;
+            }
+        }));
+    }
+
+    // Reload the config on SIGHUP, as well as through the ReloadConfig RPC
+    let reloader = Arc::new(Reloader::new(initial_settings, dns_state.clone(), handler_options_tx, auth.clone()));
+    {
+        let reloader = reloader.clone();
         tokio::spawn(async move {
-            dns::run_dns_server(dns_state.clone(),dns_options).await.unwrap();
+            if let Err(e) = reload::reload_on_hangup(reloader).await {
+                eprintln!("Config reload on SIGHUP unavailable: {}", e);
+            }
         });
     }
 
-    control::run_grpc_server(ControlServer::new(dns_state), grpc_options).await?;
+    // Probe the DNS listeners, if configured, once DNS is served
+    let probe = probe_options.map(|probe_options| {
+        let (prober, probe) = Prober::new(listeners.clone(), probe_options, metrics.clone());
+        servers.push(tokio::spawn(prober.run(dns_ready.clone(), shutdown.clone())));
+        probe
+    });
 
-    Ok(())
-}
+    let legacy_errors = grpc_options.legacy_error_responses;
+    let mut control = ControlServer::new(dns_state.clone(), metrics, auth, reloader.clone(), shutdown.clone(), legacy_errors, audit);
+    if let Some(cluster) = cluster {
+        control = control.with_cluster(cluster);
+    }
+    if let Some(tracer) = tracer {
+        control = control.with_tracer(tracer);
+    }
+    if let Some(probe) = probe {
+        control = control.with_probe(probe);
+    }
+    control = control
+        .with_shutdown_trigger(shutdown_trigger.clone())
+        .with_acme(acme_options)
+        .with_listeners(listeners);
+    let mut dns_serving = dns_ready.clone();
+    let mut grpc = tokio::spawn(rdns::run_grpc_server(control, grpc_options, dns_ready));
+
+    // Report the service started once DNS is served, unless the gRPC server fails first
+    tokio::select! {
+        serving = dns_serving.wait_for(|ready| *ready) => if serving.is_err() {
+            anyhow::bail!("DNS server stopped before its listeners were bound");
+        },
+        result = &mut grpc => return result?,
+    }
+    let zones = dns_state.read().await.list_zones(None).await.len();
+    println!("{}", serverinfo::banner(&reloader.settings().await, zones));
+    daemon.ready();
 
-/// load settings from the Config.toml file
-fn load_settings() -> Result<Settings, config::ConfigError> {
-    let builder = config::Config::builder()
-        .add_source(config::File::with_name("Config").required(false))
-        .add_source(config::Environment::with_prefix("APP").separator("__")); // optional
-    builder.build()?.try_deserialize()
+    // Run until SIGTERM/SIGINT or a Shutdown call, or until the gRPC server stops on its own
+    tokio::select! {
+        signal = shutdown::terminate_signal() => println!("Received {}, shutting down", signal?),
+        _ = shutdown.clone().requested() => println!("Shutdown requested through the Admin API"),
+        result = &mut grpc => return result?,
+    }
+    daemon.stopping();
+    shutdown_trigger.trigger();
+    let drained = tokio::time::timeout(shutdown_options.drain_timeout, async {
+        if let Ok(Err(e)) = grpc.await {
+            eprintln!("gRPC server failed: {}", e);
+        }
+        for server in servers {
+            let _ = server.await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        eprintln!("Drain timeout elapsed, abandoning requests still in flight");
+    }
+
+    // Mutations persist while holding the state lock, so none is left half-written once it's taken
+    dns_state.write().await.flush().await?;
+    println!("Shutdown complete");
+    Ok(())
 }
@@ -0,0 +1,303 @@
+//! Multicast DNS responder.
+//!
+//! With `[dns.mdns]` configured, rdns joins the mDNS group 224.0.0.251 on port 5353 and
+//! answers queries for names in the mDNS zone, `local.` unless configured otherwise, from
+//! the records of that zone, the same ones unicast clients get. Answers to PTR queries
+//! carry the SRV and TXT records of the instances, and answers to SRV queries the
+//! addresses of the targets, so DNS-SD browsers resolve a service in one round trip.
+//!
+//! The services of `[[dns.mdns.services]]` are registered in the zone at startup, like
+//! registrations made through `RegisterService`, and every change to the zone's records
+//! is announced to the group as it is made, with TTL 0 for removed records, so that
+//! caches on the network pick it up without querying again.
+//!
+//! Responses are multicast to the group, unless the query asks for a unicast response
+//! or comes from a port other than 5353, in which case it is a legacy unicast query
+//! (RFC 6762 section 6.7) and is answered like a unicast DNS query. Answers the querier
+//! lists as already known are suppressed.
+
+use hickory_proto::op::{Message, MessageType, OpCode};
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::RwLock;
+
+use crate::dns::{DnsState, RecordChange, RecordEvent};
+use crate::service::ServiceEntry;
+use crate::settings::MdnsSettings;
+
+/// The mDNS multicast group.
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+/// Bit of a question's class asking for a unicast response.
+const UNICAST_RESPONSE: u16 = 0x8000;
+/// Largest TTL of the answers to a legacy unicast query.
+const LEGACY_TTL: u32 = 10;
+/// Largest mDNS message.
+const MAX_MESSAGE: usize = 9000;
+
+/// Config options for the multicast DNS responder
+#[derive(Clone)]
+pub struct MdnsOptions {
+    pub zone: Name,
+    pub interface: Ipv4Addr,
+    pub services: Vec<ServiceEntry>,
+}
+
+impl TryFrom<MdnsSettings> for MdnsOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: MdnsSettings) -> anyhow::Result<Self> {
+        let mut zone = Name::from_ascii(&cfg.zone)?;
+        zone.set_fqdn(true);
+        let interface = cfg
+            .interface
+            .parse()
+            .map_err(|e| anyhow::anyhow!("invalid dns.mdns.interface {}: {}", cfg.interface, e))?;
+        let mut services = Vec::with_capacity(cfg.services.len());
+        for service in cfg.services {
+            let host = match service.host.contains('.') {
+                true => service.host,
+                false => format!("{}.{}", service.host, zone),
+            };
+            let entry = ServiceEntry {
+                instance: service.instance,
+                service: service.service,
+                protocol: service.protocol,
+                domain: zone.to_string(),
+                host,
+                addresses: service.addresses,
+                port: service.port,
+                priority: 0,
+                weight: 0,
+                metadata: service.metadata,
+                ttl: service.ttl,
+            };
+            services.push(entry.normalize().map_err(|e| anyhow::anyhow!("invalid dns.mdns.services entry: {}", e))?);
+        }
+        Ok(MdnsOptions { zone, interface, services })
+    }
+}
+
+/// Binds the mDNS port, shared with any other responder on the host, and joins the group.
+fn bind(interface: Ipv4Addr) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT)).into())?;
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.join_multicast_v4(GROUP, interface)?;
+    socket.set_multicast_ttl_v4(255)?;
+    Ok(socket)
+}
+
+/// Answers the mDNS queries for the zone and announces the changes to its records.
+struct Responder {
+    state: Arc<RwLock<DnsState>>,
+    zone: LowerName,
+    socket: UdpSocket,
+}
+
+impl Responder {
+    /// A response to `query` from `from`, and where to send it; none when nothing it asks
+    /// for is in the zone or the querier knows every answer already.
+    async fn answer(&self, query: &Message, from: SocketAddr) -> Option<(Message, SocketAddr)> {
+        if query.message_type() != MessageType::Query || query.op_code() != OpCode::Query {
+            return None;
+        }
+        let legacy = from.port() != PORT;
+        let mut unicast = legacy;
+        let state = self.state.read().await;
+        let mut answers: Vec<Record> = Vec::new();
+        for question in query.queries() {
+            let name = LowerName::new(question.name());
+            if !self.zone.zone_of(&name) {
+                continue;
+            }
+            unicast |= u16::from(question.query_class()) & UNICAST_RESPONSE != 0;
+            for record in state.lookup_records(&name, question.query_type()).await {
+                if !answers.contains(&record) && !known(query, &record) {
+                    answers.push(record);
+                }
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+
+        // DNS-SD resolvers want an instance's SRV and TXT records along with the PTR
+        // record naming it, and a host's addresses along with the SRV record naming it
+        let mut additionals: Vec<Record> = Vec::new();
+        for answer in &answers {
+            let (target, types) = match answer.data() {
+                Some(RData::PTR(ptr)) => (&ptr.0, [RecordType::SRV, RecordType::TXT]),
+                Some(RData::SRV(srv)) => (srv.target(), [RecordType::A, RecordType::AAAA]),
+                _ => continue,
+            };
+            let target = LowerName::new(target);
+            if !self.zone.zone_of(&target) {
+                continue;
+            }
+            for record_type in types {
+                for record in state.lookup_records(&target, record_type).await {
+                    if !answers.contains(&record) && !additionals.contains(&record) {
+                        additionals.push(record);
+                    }
+                }
+            }
+        }
+        drop(state);
+
+        let mut response = Message::new();
+        response
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true);
+        if legacy {
+            // Legacy resolvers match the response to their query like a unicast one
+            response.set_id(query.id()).add_queries(query.queries().to_vec());
+            for record in answers.iter_mut().chain(additionals.iter_mut()) {
+                record.set_ttl(record.ttl().min(LEGACY_TTL));
+            }
+        }
+        response.add_answers(answers).add_additionals(additionals);
+        let to = if unicast { from } else { SocketAddr::from((GROUP, PORT)) };
+        Some((response, to))
+    }
+
+    /// Multicasts `records` to the group, unsolicited.
+    async fn announce(&self, records: Vec<Record>) {
+        if records.is_empty() {
+            return;
+        }
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true)
+            .add_answers(records);
+        self.send(&message, SocketAddr::V4(SocketAddrV4::new(GROUP, PORT))).await;
+    }
+
+    /// Announces a change to a record of the zone: the record's current RRset, or the
+    /// record with TTL 0 once it is deleted.
+    async fn announce_change(&self, event: RecordEvent) {
+        let entry = event.record;
+        let Ok(mut record) = DnsState::build_record(entry.name, entry.record_type, entry.value, entry.ttl) else {
+            return;
+        };
+        let name = LowerName::new(record.name());
+        if !self.zone.zone_of(&name) {
+            return;
+        }
+        let records = match event.change {
+            RecordChange::Deleted => {
+                record.set_ttl(0);
+                vec![record]
+            }
+            RecordChange::Added | RecordChange::Updated => {
+                self.state.read().await.lookup_records(&name, record.record_type()).await
+            }
+        };
+        self.announce(records).await;
+    }
+
+    async fn send(&self, message: &Message, to: SocketAddr) {
+        let result = match message.to_vec() {
+            Ok(bytes) => self.socket.send_to(&bytes, to).await.map(|_| ()),
+            Err(e) => Err(std::io::Error::other(e)),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to send mDNS response to {}: {}", to, e);
+        }
+    }
+}
+
+/// Whether the querier lists `record` among the answers it knows, with at least half its
+/// TTL left, so it needn't be sent again (RFC 6762 section 7.1).
+fn known(query: &Message, record: &Record) -> bool {
+    query.answers().iter().any(|known| {
+        known.name() == record.name()
+            && known.record_type() == record.record_type()
+            && known.data() == record.data()
+            && known.ttl() >= record.ttl() / 2
+    })
+}
+
+/// Registers the configured services, then answers mDNS queries for the zone and
+/// announces the changes to its records. Runs until the process exits.
+pub async fn run_responder(state: Arc<RwLock<DnsState>>, options: MdnsOptions) {
+    let mut announced = Vec::new();
+    {
+        let mut state = state.write().await;
+        if let Err(e) = state.ensure_zones(&[options.zone.to_string()]).await {
+            eprintln!("Failed to create mDNS zone {}: {}", options.zone, e);
+            return;
+        }
+        // On a follower the services come from the leader
+        if !state.is_follower() {
+            for service in &options.services {
+                match state.register_service(service.clone()).await {
+                    Ok(()) => announced.extend(service.records()),
+                    Err(e) => eprintln!("Failed to register mDNS service {}: {}", service.instance_name(), e),
+                }
+            }
+        }
+    }
+    let socket = match bind(options.interface) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Failed to join the mDNS group on {}: {}", options.interface, e);
+            return;
+        }
+    };
+    let mut events = state.read().await.subscribe();
+    let responder = Responder {
+        state,
+        zone: LowerName::new(&options.zone),
+        socket,
+    };
+    println!("mDNS responder listening on {}:{} for {}", GROUP, PORT, options.zone);
+
+    // RFC 6762 section 8.3: announcements are sent at least twice, a second apart
+    let records: Vec<Record> = announced
+        .into_iter()
+        .filter_map(|entry| DnsState::build_record(entry.name, entry.record_type, entry.value, entry.ttl).ok())
+        .collect();
+    for round in 0..2 {
+        if round > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        responder.announce(records.clone()).await;
+    }
+
+    let mut buffer = vec![0; MAX_MESSAGE];
+    loop {
+        tokio::select! {
+            received = responder.socket.recv_from(&mut buffer) => {
+                let (len, from) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("mDNS receive failed: {}", e);
+                        continue;
+                    }
+                };
+                let Ok(query) = Message::from_vec(&buffer[..len]) else {
+                    continue;
+                };
+                if let Some((response, to)) = responder.answer(&query, from).await {
+                    responder.send(&response, to).await;
+                }
+            }
+            event = events.recv() => match event {
+                Ok(event) => responder.announce_change(event).await,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            },
+        }
+    }
+}
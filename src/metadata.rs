@@ -0,0 +1,130 @@
+//! Record metadata.
+//!
+//! Records may carry a free-text comment, an owner and key/value tags, so that teams
+//! sharing a zone can tell whose a record is and why it exists. Metadata is kept
+//! alongside the records by name, type and value, like leases, and never served over
+//! DNS. It is returned with the records by the listings, persisted and replicated with
+//! them, and `QueryRecords` filters on the owner and tags.
+//!
+//! Adding a record with metadata replaces the metadata it had, while adding it without
+//! keeps it; a record deleted or replaced drops its metadata with it.
+
+use hickory_proto::rr::{LowerName, Record, RrKey};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+use crate::error::RdnsError;
+
+/// Longest comment, in characters.
+const MAX_COMMENT_LEN: usize = 1024;
+/// Longest owner, tag key or tag value, in characters.
+const MAX_FIELD_LEN: usize = 256;
+/// Most tags a record may have.
+const MAX_TAGS: usize = 32;
+
+/// The metadata of one record.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordMetadata {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+    /// Team or person responsible for the record
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub owner: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl RecordMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.comment.is_empty() && self.owner.is_empty() && self.tags.is_empty()
+    }
+
+    /// Fails if a field is too long, there are too many tags or a tag has no key.
+    pub fn validate(&self) -> Result<(), RdnsError> {
+        let invalid = |message: String| Err(RdnsError::InvalidArgument(message));
+        if self.comment.chars().count() > MAX_COMMENT_LEN {
+            return invalid(format!("comment is longer than {} characters", MAX_COMMENT_LEN));
+        }
+        if self.owner.chars().count() > MAX_FIELD_LEN {
+            return invalid(format!("owner is longer than {} characters", MAX_FIELD_LEN));
+        }
+        if self.tags.len() > MAX_TAGS {
+            return invalid(format!("a record may have at most {} tags", MAX_TAGS));
+        }
+        for (key, value) in &self.tags {
+            if key.is_empty() {
+                return invalid("tag keys can't be empty".into());
+            }
+            if key.chars().count() > MAX_FIELD_LEN || value.chars().count() > MAX_FIELD_LEN {
+                return invalid(format!("tag {} is longer than {} characters", key, MAX_FIELD_LEN));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the metadata has `owner`, unless that is empty, and every tag of `tags`.
+    /// A tag given with an empty value matches any value of its key.
+    pub fn matches(&self, owner: &str, tags: &BTreeMap<String, String>) -> bool {
+        (owner.is_empty() || self.owner == owner)
+            && tags.iter().all(|(key, value)| {
+                self.tags
+                    .get(key)
+                    .is_some_and(|tag| value.is_empty() || tag == value)
+            })
+    }
+}
+
+/// The metadata of the records that have any, by name and type and then by value.
+#[derive(Default)]
+pub struct MetadataTable {
+    entries: Mutex<HashMap<RrKey, HashMap<String, RecordMetadata>>>,
+}
+
+/// The key and value a record's metadata is kept under.
+fn key_of(record: &Record) -> (RrKey, String) {
+    let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+    (key, record.data().map(|data| data.to_string()).unwrap_or_default())
+}
+
+impl MetadataTable {
+    /// Sets the metadata of `record`, dropping it when `metadata` is empty.
+    pub fn set(&self, record: &Record, metadata: RecordMetadata) {
+        let (key, value) = key_of(record);
+        let mut entries = self.entries.lock().unwrap();
+        if !metadata.is_empty() {
+            entries.entry(key).or_default().insert(value, metadata);
+        } else if let Some(values) = entries.get_mut(&key) {
+            values.remove(&value);
+            if values.is_empty() {
+                entries.remove(&key);
+            }
+        }
+    }
+
+    /// The metadata of `record`, empty if it has none.
+    pub fn get(&self, record: &Record) -> RecordMetadata {
+        let (key, value) = key_of(record);
+        let entries = self.entries.lock().unwrap();
+        entries.get(&key).and_then(|values| values.get(&value)).cloned().unwrap_or_default()
+    }
+
+    /// Drops the metadata of `record`.
+    pub fn remove(&self, record: &Record) {
+        self.set(record, RecordMetadata::default());
+    }
+
+    /// Drops the metadata of every value of a name and type.
+    pub fn remove_rrset(&self, key: &RrKey) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    /// Drops the metadata of the names in the zone at `origin` and its subzones.
+    pub fn remove_zone(&self, origin: &LowerName) {
+        self.entries.lock().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
@@ -0,0 +1,315 @@
+//! Prometheus metrics.
+//!
+//! A single `Metrics` registry is shared by the DNS request handler, the forwarding
+//! cache and the gRPC control server, and exposed in the Prometheus text format on
+//! an optional HTTP `/metrics` endpoint.
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::RecordType;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, StatusCode};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::settings::MetricsSettings;
+use crate::shutdown::Shutdown;
+
+/// Config options for the metrics endpoint
+pub struct MetricsOptions {
+    pub listen_addr: String,
+}
+
+impl From<MetricsSettings> for MetricsOptions {
+    fn from(cfg: MetricsSettings) -> Self {
+        MetricsOptions {
+            listen_addr: cfg.listen_addr,
+        }
+    }
+}
+
+/// The shared registry and the collectors fed into it.
+pub struct Metrics {
+    registry: Registry,
+    queries: IntCounterVec,
+    query_duration: HistogramVec,
+    cache_hits: IntCounter,
+    cache_misses: IntCounter,
+    cache_negative_entries: IntGauge,
+    mutations: IntCounterVec,
+    rate_limited: IntCounterVec,
+    cookies: IntCounterVec,
+    transfers_refused: IntCounterVec,
+    grpc_limited: IntCounterVec,
+    secondary_refreshes: IntCounterVec,
+    secondary_last_refresh: IntGaugeVec,
+    secondary_stale: IntGaugeVec,
+    secondary_expired: IntGaugeVec,
+    probes: IntCounterVec,
+    probe_duration: HistogramVec,
+    probe_up: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("rdns".into()), None)?;
+        let queries = IntCounterVec::new(
+            Opts::new("dns_queries_total", "DNS requests answered, by query type and response code"),
+            &["type", "rcode"],
+        )?;
+        let query_duration = HistogramVec::new(
+            HistogramOpts::new("dns_query_duration_seconds", "Time taken to answer DNS requests, by query type")
+                .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0]),
+            &["type"],
+        )?;
+        let cache_hits = IntCounter::new("cache_hits_total", "Forwarded queries answered from the cache")?;
+        let cache_misses = IntCounter::new("cache_misses_total", "Forwarded queries sent upstream")?;
+        let cache_negative_entries = IntGauge::new("cache_negative_entries", "NXDOMAIN and NODATA answers in the cache")?;
+        let mutations = IntCounterVec::new(
+            Opts::new("grpc_mutations_total", "Mutating control API calls, by method and outcome"),
+            &["method", "result"],
+        )?;
+
+        let rate_limited = IntCounterVec::new(
+            Opts::new("dns_rate_limited_total", "UDP responses withheld by rate limiting, by action"),
+            &["action"],
+        )?;
+        let cookies = IntCounterVec::new(
+            Opts::new("dns_cookies_total", "Queries carrying a DNS cookie, by how it checked out"),
+            &["result"],
+        )?;
+
+        let transfers_refused = IntCounterVec::new(
+            Opts::new("dns_transfers_refused_total", "Zone transfer requests refused, by reason"),
+            &["reason"],
+        )?;
+
+        let grpc_limited = IntCounterVec::new(
+            Opts::new("grpc_limited_total", "Control API calls refused by its rate limits, by the limit exceeded"),
+            &["limit"],
+        )?;
+
+        let secondary_refreshes = IntCounterVec::new(
+            Opts::new("secondary_zone_refreshes_total", "Refreshes of secondary zones from their primaries, by zone and outcome"),
+            &["zone", "result"],
+        )?;
+        let secondary_last_refresh = IntGaugeVec::new(
+            Opts::new(
+                "secondary_zone_last_refresh_timestamp_seconds",
+                "Unix time of the last successful refresh of each secondary zone",
+            ),
+            &["zone"],
+        )?;
+        let secondary_stale = IntGaugeVec::new(
+            Opts::new("secondary_zone_stale", "Whether a secondary zone has gone unrefreshed past its refresh and retry intervals"),
+            &["zone"],
+        )?;
+        let secondary_expired = IntGaugeVec::new(
+            Opts::new("secondary_zone_expired", "Whether a secondary zone has expired and is no longer served"),
+            &["zone"],
+        )?;
+
+        let probes = IntCounterVec::new(
+            Opts::new("probes_total", "Queries of the server's own listeners, by protocol and outcome"),
+            &["protocol", "result"],
+        )?;
+        let probe_duration = HistogramVec::new(
+            HistogramOpts::new("probe_duration_seconds", "Time taken to answer queries of the server's own listeners, by protocol")
+                .buckets(vec![0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0]),
+            &["protocol"],
+        )?;
+        let probe_up = IntGaugeVec::new(
+            Opts::new("probe_up", "Whether the server answers its own queries over a protocol"),
+            &["protocol"],
+        )?;
+
+        registry.register(Box::new(queries.clone()))?;
+        registry.register(Box::new(query_duration.clone()))?;
+        registry.register(Box::new(cache_hits.clone()))?;
+        registry.register(Box::new(cache_misses.clone()))?;
+        registry.register(Box::new(cache_negative_entries.clone()))?;
+        registry.register(Box::new(mutations.clone()))?;
+        registry.register(Box::new(rate_limited.clone()))?;
+        registry.register(Box::new(cookies.clone()))?;
+        registry.register(Box::new(transfers_refused.clone()))?;
+        registry.register(Box::new(grpc_limited.clone()))?;
+        registry.register(Box::new(secondary_refreshes.clone()))?;
+        registry.register(Box::new(secondary_last_refresh.clone()))?;
+        registry.register(Box::new(secondary_stale.clone()))?;
+        registry.register(Box::new(secondary_expired.clone()))?;
+        registry.register(Box::new(probes.clone()))?;
+        registry.register(Box::new(probe_duration.clone()))?;
+        registry.register(Box::new(probe_up.clone()))?;
+        Ok(Self {
+            registry,
+            queries,
+            query_duration,
+            cache_hits,
+            cache_misses,
+            cache_negative_entries,
+            mutations,
+            rate_limited,
+            cookies,
+            transfers_refused,
+            grpc_limited,
+            secondary_refreshes,
+            secondary_last_refresh,
+            secondary_stale,
+            secondary_expired,
+            probes,
+            probe_duration,
+            probe_up,
+        })
+    }
+
+    /// Records an answered DNS request.
+    pub fn observe_query(&self, record_type: RecordType, rcode: ResponseCode, elapsed: Duration) {
+        let record_type: &str = record_type.into();
+        let rcode = rcode_label(rcode);
+        self.queries.with_label_values(&[record_type, &rcode]).inc();
+        self.query_duration
+            .with_label_values(&[record_type])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Records a forwarded query, answered from the cache or not.
+    pub fn observe_cache(&self, hit: bool) {
+        if hit {
+            self.cache_hits.inc();
+        } else {
+            self.cache_misses.inc();
+        }
+    }
+
+    /// Records how many negative answers the cache holds.
+    pub fn set_cache_negative_entries(&self, count: usize) {
+        self.cache_negative_entries.set(count as i64);
+    }
+
+    /// Records a mutating control API call.
+    pub fn observe_mutation(&self, method: &str, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.mutations.with_label_values(&[method, result]).inc();
+    }
+
+    /// Records a rate-limited response, sent truncated when `slipped` or dropped otherwise.
+    pub fn observe_rate_limited(&self, slipped: bool) {
+        let action = if slipped { "slipped" } else { "dropped" };
+        self.rate_limited.with_label_values(&[action]).inc();
+    }
+
+    /// Records a query's DNS cookie, `result` being `valid`, `invalid`, `client_only` or
+    /// `malformed`.
+    pub fn observe_cookie(&self, result: &str) {
+        self.cookies.with_label_values(&[result]).inc();
+    }
+
+    /// Records a refused zone transfer, `reason` being `disabled`, `bad_signature`,
+    /// `not_allowed` or `unsigned`.
+    pub fn observe_transfer_refused(&self, reason: &str) {
+        self.transfers_refused.with_label_values(&[reason]).inc();
+    }
+
+    /// Records a control API call refused by a rate limit, `limit` being `peer` or
+    /// `global`.
+    pub fn observe_grpc_limited(&self, limit: &str) {
+        self.grpc_limited.with_label_values(&[limit]).inc();
+    }
+
+    /// Records a refresh of the secondary zone `zone`, successful at `at` or failed when
+    /// `at` is `None`.
+    pub fn observe_secondary_refresh(&self, zone: &str, at: Option<SystemTime>) {
+        let result = if at.is_some() { "success" } else { "failure" };
+        self.secondary_refreshes.with_label_values(&[zone, result]).inc();
+        if let Some(at) = at {
+            let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            self.secondary_last_refresh.with_label_values(&[zone]).set(secs as i64);
+        }
+    }
+
+    /// Records whether the secondary zone `zone` is stale and whether it has expired.
+    pub fn set_secondary_state(&self, zone: &str, stale: bool, expired: bool) {
+        self.secondary_stale.with_label_values(&[zone]).set(stale as i64);
+        self.secondary_expired.with_label_values(&[zone]).set(expired as i64);
+    }
+
+    /// Records a probe of the listener serving `protocol`, with its outcome and the time
+    /// it took to be answered, if it was.
+    pub fn observe_probe(&self, protocol: &str, result: &str, elapsed: Option<Duration>) {
+        self.probes.with_label_values(&[protocol, result]).inc();
+        if let Some(elapsed) = elapsed {
+            self.probe_duration.with_label_values(&[protocol]).observe(elapsed.as_secs_f64());
+        }
+    }
+
+    /// Records whether the listener serving `protocol` answers probes.
+    pub fn set_probe_up(&self, protocol: &str, up: bool) {
+        self.probe_up.with_label_values(&[protocol]).set(up as i64);
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    fn render(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// The mnemonic form of `rcode`, e.g. `NXDomain`, as its Debug output gives it, without
+/// allocating for the codes answers commonly carry.
+fn rcode_label(rcode: ResponseCode) -> Cow<'static, str> {
+    match rcode {
+        ResponseCode::NoError => Cow::Borrowed("NoError"),
+        ResponseCode::FormErr => Cow::Borrowed("FormErr"),
+        ResponseCode::ServFail => Cow::Borrowed("ServFail"),
+        ResponseCode::NXDomain => Cow::Borrowed("NXDomain"),
+        ResponseCode::NotImp => Cow::Borrowed("NotImp"),
+        ResponseCode::Refused => Cow::Borrowed("Refused"),
+        ResponseCode::NotAuth => Cow::Borrowed("NotAuth"),
+        rcode => Cow::Owned(format!("{:?}", rcode)),
+    }
+}
+
+/// Answers a single HTTP request to the metrics server.
+async fn serve(metrics: Arc<Metrics>, request: hyper::Request<Body>) -> Result<hyper::Response<Body>, Infallible> {
+    let mut response = hyper::Response::new(Body::empty());
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+    match metrics.render() {
+        Ok(body) => {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_TYPE, header::HeaderValue::from_static(prometheus::TEXT_FORMAT));
+            *response.body_mut() = Body::from(body);
+        }
+        Err(e) => {
+            eprintln!("Failed to render metrics: {}", e);
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+    Ok(response)
+}
+
+/// Starts the HTTP server exposing `metrics` on `/metrics`, until `shutdown` is requested.
+///
+/// # Errors
+///
+/// Returns an error if the listen address is invalid or the server fails.
+pub async fn run_metrics_server(metrics: Arc<Metrics>, options: MetricsOptions, shutdown: Shutdown) -> anyhow::Result<()> {
+    let addr: SocketAddr = options.listen_addr.parse()?;
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| serve(metrics.clone(), request))) }
+    });
+    println!("Metrics server listening on {}/metrics", addr);
+    hyper::Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.requested())
+        .await?;
+    Ok(())
+}
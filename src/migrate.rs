@@ -0,0 +1,389 @@
+//! Zones exported from other DNS providers.
+//!
+//! `ImportProviderRecords` takes a zone as another provider exports it and imports its
+//! records like `ImportRecords`, so that migrating off cloud DNS takes no hand-written
+//! converter:
+//!
+//! - Route53: the JSON of `aws route53 list-resource-record-sets`, its values being in
+//!   zone file syntax already. Alias records of type A or AAAA become rdns aliases of
+//!   their target; those of other types, and records of weighted, latency, geolocation or
+//!   failover routing, have no counterpart and are reported as failed.
+//! - Cloudflare: the BIND file of the dashboard's DNS export. The automatic TTL, written
+//!   as 1, becomes `DEFAULT_TTL`, the `cf_tags` of a record its tags, e.g. `cf-proxied`,
+//!   so that records served through the Cloudflare proxy before can be found, and a
+//!   CNAME at the apex, which Cloudflare flattens, an alias.
+//! - JSON: rdns's own export of `ExportRecords`, see `recordjson`, for zone contents kept
+//!   under version control to be put back.
+//!
+//! The SOA and NS records at the apex name the provider's own nameservers, so they are
+//! left out and counted as skipped. Records outside the zone are reported as failed.
+//! Failures are reported at the position of the entry in the export they came from: the
+//! record set of a Route53 export, or the record of a Cloudflare or JSON one.
+
+use hickory_proto::rr::{LowerName, Name};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::alias::AliasEntry;
+use crate::dns::{DnsState, RecordEntry};
+use crate::error::RdnsError;
+use crate::metadata::RecordMetadata;
+use crate::recordjson::RecordExport;
+
+/// TTL of records with a provider's automatic TTL, and of Route53 alias records.
+pub const DEFAULT_TTL: u32 = 300;
+
+/// Export formats `convert` understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Route53,
+    Cloudflare,
+    Json,
+}
+
+/// The contents of an export, as records and aliases to import.
+#[derive(Debug, Default)]
+pub struct Converted {
+    pub origin: String,
+    /// Records by position of the entry they came from
+    pub records: Vec<(usize, RecordEntry)>,
+    /// Aliases by position of the entry they came from
+    pub aliases: Vec<(usize, AliasEntry)>,
+    /// Entries that can't be imported, by position, with the reason
+    pub failed: Vec<(usize, String)>,
+    /// The provider's SOA and NS records left out
+    pub skipped: u32,
+}
+
+impl Converted {
+    /// Adds an entry of the export, reporting it as failed if it lies outside the zone
+    /// and skipping the apex SOA and NS records.
+    fn add(&mut self, origin: &LowerName, index: usize, entry: RecordEntry) {
+        let name = match DnsState::parse_name(&entry.name) {
+            Ok(name) => LowerName::new(&name),
+            Err(e) => return self.failed.push((index, e.to_string())),
+        };
+        if !origin.zone_of(&name) {
+            return self.failed.push((index, format!("{} is not in zone {}", name, origin)));
+        }
+        let record_type = entry.record_type.to_ascii_uppercase();
+        if &name == origin && (record_type == "SOA" || record_type == "NS") {
+            self.skipped += 1;
+            return;
+        }
+        self.records.push((index, entry));
+    }
+
+    /// Adds `alias`, reporting it as failed if its name lies outside the zone.
+    fn alias(&mut self, origin: &LowerName, index: usize, alias: AliasEntry) {
+        match DnsState::parse_name(&alias.name) {
+            Ok(name) if origin.zone_of(&LowerName::new(&name)) => self.aliases.push((index, alias)),
+            Ok(name) => self.failed.push((index, format!("{} is not in zone {}", name, origin))),
+            Err(e) => self.failed.push((index, e.to_string())),
+        }
+    }
+}
+
+/// Converts the export `content` of the zone at `origin`, which is taken from the export
+/// when empty.
+///
+/// # Errors
+///
+/// Fails if the export can't be parsed, or no origin is given and the export names none.
+pub fn convert(format: ExportFormat, content: &str, origin: &str) -> Result<Converted, RdnsError> {
+    match format {
+        ExportFormat::Route53 => route53(content, origin),
+        ExportFormat::Cloudflare => cloudflare(content, origin),
+        ExportFormat::Json => json(content, origin),
+    }
+}
+
+fn json(content: &str, origin: &str) -> Result<Converted, RdnsError> {
+    let export = RecordExport::parse(content)?;
+    let origin = match origin {
+        "" => export.origin,
+        origin => origin.to_string(),
+    };
+    let zone = LowerName::new(&DnsState::parse_name(&origin)?);
+    let mut converted = Converted {
+        origin: zone.to_string(),
+        ..Default::default()
+    };
+    for (index, entry) in export.records.into_iter().enumerate() {
+        converted.add(&zone, index, entry);
+    }
+    Ok(converted)
+}
+
+/// A Route53 record set listing, or just its record sets.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Route53Export {
+    Listing {
+        #[serde(rename = "ResourceRecordSets")]
+        record_sets: Vec<Route53RecordSet>,
+    },
+    RecordSets(Vec<Route53RecordSet>),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Route53RecordSet {
+    name: String,
+    #[serde(rename = "Type")]
+    record_type: String,
+    #[serde(rename = "TTL")]
+    ttl: Option<u32>,
+    #[serde(default)]
+    resource_records: Vec<Route53Value>,
+    alias_target: Option<Route53AliasTarget>,
+    /// Set on the record sets of routing policies other than simple routing
+    set_identifier: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Route53Value {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Route53AliasTarget {
+    #[serde(rename = "DNSName")]
+    dns_name: String,
+}
+
+/// Decodes the `\ooo` octal escapes of a Route53 name, such as `\052` for `*`.
+fn route53_name(name: &str) -> String {
+    let mut decoded = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(position) = rest.find('\\') {
+        decoded.push_str(&rest[..position]);
+        let escape = rest.get(position + 1..position + 4);
+        match escape.and_then(|digits| u8::from_str_radix(digits, 8).ok()) {
+            Some(byte) if byte.is_ascii_graphic() && byte != b'.' && byte != b'\\' => {
+                decoded.push(byte as char);
+                rest = &rest[position + 4..];
+            }
+            _ => {
+                decoded.push('\\');
+                rest = &rest[position + 1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn route53(content: &str, origin: &str) -> Result<Converted, RdnsError> {
+    let export: Route53Export = serde_json::from_str(content)
+        .map_err(|e| RdnsError::InvalidArgument(format!("invalid Route53 export: {}", e)))?;
+    let record_sets = match export {
+        Route53Export::Listing { record_sets } | Route53Export::RecordSets(record_sets) => record_sets,
+    };
+    let origin = match origin {
+        "" => record_sets
+            .iter()
+            .find(|set| set.record_type == "SOA")
+            .map(|set| route53_name(&set.name))
+            .ok_or_else(|| RdnsError::InvalidArgument("the export has no SOA record, give the origin of the zone".into()))?,
+        origin => origin.to_string(),
+    };
+    let zone = LowerName::new(&DnsState::parse_name(&origin)?);
+    let mut converted = Converted {
+        origin: zone.to_string(),
+        ..Default::default()
+    };
+
+    for (index, set) in record_sets.into_iter().enumerate() {
+        let name = route53_name(&set.name);
+        if let Some(identifier) = set.set_identifier {
+            converted.failed.push((
+                index,
+                format!("{} {} is part of routing policy {:?}, which has no counterpart", name, set.record_type, identifier),
+            ));
+            continue;
+        }
+        if let Some(target) = set.alias_target {
+            if set.record_type != "A" && set.record_type != "AAAA" {
+                converted.failed.push((index, format!("{} is an alias of type {}, only A and AAAA aliases are supported", name, set.record_type)));
+                continue;
+            }
+            let alias = AliasEntry {
+                name,
+                target: target.dns_name,
+                ttl: DEFAULT_TTL,
+            };
+            converted.alias(&zone, index, alias);
+            continue;
+        }
+        // SPF records are served as TXT records since RFC 7208
+        let record_type = if set.record_type == "SPF" { "TXT".to_string() } else { set.record_type };
+        for value in set.resource_records {
+            let entry = RecordEntry {
+                name: name.clone(),
+                record_type: record_type.clone(),
+                value: value.value,
+                ttl: set.ttl.unwrap_or(DEFAULT_TTL),
+                metadata: RecordMetadata::default(),
+            };
+            converted.add(&zone, index, entry);
+        }
+    }
+    Ok(converted)
+}
+
+/// Splits a zone file line into its data and its comment, at the first `;` outside a
+/// quoted string.
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (position, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            ';' if !quoted => return (&line[..position], Some(&line[position + 1..])),
+            _ => {}
+        }
+    }
+    (line, None)
+}
+
+/// The tags of a record's `cf_tags=key:value,...` comment, and the rest of the comment.
+fn cloudflare_metadata(comment: &str) -> RecordMetadata {
+    let (comment, tags) = match comment.find("cf_tags=") {
+        Some(position) => (&comment[..position], &comment[position + "cf_tags=".len()..]),
+        None => (comment, ""),
+    };
+    let tags: BTreeMap<String, String> = tags
+        .split(',')
+        .filter_map(|tag| {
+            let (key, value) = tag.trim().split_once(':')?;
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect();
+    RecordMetadata {
+        comment: comment.trim().to_string(),
+        tags,
+        ..Default::default()
+    }
+}
+
+/// The absolute form of owner `name` of a zone file at `origin`. Cloudflare writes names
+/// fully qualified, but has been seen to leave the dot off the apex.
+fn absolute(name: &str, origin: &str) -> String {
+    let bare = origin.trim_end_matches('.');
+    if name == "@" {
+        origin.to_string()
+    } else if name.ends_with('.') {
+        name.to_string()
+    } else if name.eq_ignore_ascii_case(bare) || name.to_ascii_lowercase().ends_with(&format!(".{}", bare.to_ascii_lowercase())) {
+        format!("{}.", name)
+    } else {
+        format!("{}.{}", name, origin)
+    }
+}
+
+/// `line` past its first `fields` whitespace separated fields.
+fn skip_fields(line: &str, fields: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..fields {
+        rest = rest[rest.find(char::is_whitespace).unwrap_or(rest.len())..].trim_start();
+    }
+    rest
+}
+
+fn cloudflare(content: &str, origin: &str) -> Result<Converted, RdnsError> {
+    // Records spread over several lines by parentheses are joined first
+    let mut lines: Vec<(String, Option<String>)> = Vec::new();
+    let mut pending: Option<(String, Option<String>)> = None;
+    let mut header_origin = None;
+    for line in content.lines() {
+        let (data, comment) = split_comment(line);
+        if data.trim().is_empty() {
+            if let Some(domain) = comment.and_then(|comment| comment.trim_start_matches(';').trim().strip_prefix("Domain:")) {
+                header_origin.get_or_insert_with(|| domain.trim().to_string());
+            }
+            continue;
+        }
+        let (mut joined, mut joined_comment) = pending.take().unwrap_or_default();
+        if !joined.is_empty() {
+            joined.push(' ');
+        }
+        joined.push_str(data.trim());
+        if let Some(comment) = comment {
+            joined_comment.get_or_insert_with(String::new).push_str(comment);
+        }
+        match joined.matches('(').count() > joined.matches(')').count() {
+            true => pending = Some((joined, joined_comment)),
+            false => lines.push((joined.replace(['(', ')'], " "), joined_comment)),
+        }
+    }
+
+    let mut origin = origin.to_string();
+    for (line, _) in &lines {
+        if let Some(declared) = line.strip_prefix("$ORIGIN") {
+            if origin.is_empty() {
+                origin = declared.trim().to_string();
+            }
+        }
+    }
+    if origin.is_empty() {
+        origin = header_origin
+            .ok_or_else(|| RdnsError::InvalidArgument("the export names no domain, give the origin of the zone".into()))?;
+    }
+    let zone = LowerName::new(&DnsState::parse_name(&origin)?);
+    let origin = zone.to_string();
+    let apex = Name::from_str(&origin).map_err(|e| RdnsError::InvalidName(e.to_string()))?;
+    let mut converted = Converted {
+        origin: origin.clone(),
+        ..Default::default()
+    };
+
+    let records = lines.into_iter().filter(|(line, _)| !line.starts_with('$'));
+    for (index, (line, comment)) in records.enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let Some(class) = fields.iter().position(|field| field.eq_ignore_ascii_case("IN")) else {
+            converted.failed.push((index, format!("expected NAME TTL IN TYPE VALUE, got {}", line)));
+            continue;
+        };
+        let (Some(name), Some(ttl), Some(record_type)) = (fields.first(), fields.get(1).filter(|_| class == 2), fields.get(class + 1)) else {
+            converted.failed.push((index, format!("expected NAME TTL IN TYPE VALUE, got {}", line)));
+            continue;
+        };
+        let Ok(ttl) = ttl.parse::<u32>() else {
+            converted.failed.push((index, format!("invalid TTL {}", ttl)));
+            continue;
+        };
+        // The value is kept as written, quoted strings and all
+        let value = skip_fields(&line, class + 2).trim_end().to_string();
+        if value.is_empty() {
+            converted.failed.push((index, format!("{} {} record without a value", name, record_type)));
+            continue;
+        }
+        let name = absolute(name, &origin);
+        let ttl = if ttl == 1 { DEFAULT_TTL } else { ttl };
+        let record_type = record_type.to_ascii_uppercase();
+        let is_apex = Name::from_str(&name).is_ok_and(|name| name.eq(&apex));
+        if record_type == "CNAME" && is_apex {
+            let alias = AliasEntry {
+                name,
+                target: value,
+                ttl,
+            };
+            converted.alias(&zone, index, alias);
+            continue;
+        }
+        let entry = RecordEntry {
+            name,
+            record_type,
+            value,
+            ttl,
+            metadata: comment.map(|comment| cloudflare_metadata(&comment)).unwrap_or_default(),
+        };
+        converted.add(&zone, index, entry);
+    }
+    Ok(converted)
+}
@@ -0,0 +1,196 @@
+//! Answers for names that don't exist.
+//!
+//! Names a hosted zone has no records for are answered NXDOMAIN, with the zone's SOA in
+//! the authority section if it has one, unless a `[[dns.negative]]` entry for the zone
+//! says otherwise:
+//!
+//! - `wildcard` answers every unknown name as if the zone had a wildcard with `records`:
+//!   the records of the queried type, or NODATA if it has none;
+//! - `redirect` resolves unknown names to the landing `addresses`, e.g. of a search or
+//!   parking page, while other types stay NXDOMAIN so mail isn't sent to the landing host.
+//!
+//! The catalog's answer is rewritten as it is sent, so names that exist, whether through
+//! their own records, a real wildcard or a view, are never affected.
+
+use hickory_proto::op::{OpCode, ResponseCode};
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use hickory_server::authority::{MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use std::net::IpAddr;
+use tonic::async_trait;
+
+use crate::dns::DnsState;
+use crate::roundrobin;
+use crate::settings::NegativeSettings;
+
+/// What unknown names of a zone are answered with.
+#[derive(Clone, Debug)]
+pub enum NegativeAction {
+    Nxdomain,
+    /// The records of the queried type, NODATA if there are none
+    Wildcard(Vec<Record>),
+    /// The address records of A and AAAA queries, NXDOMAIN for other types
+    Redirect(Vec<Record>),
+}
+
+/// The negative answer policy of one zone.
+#[derive(Clone, Debug)]
+pub struct NegativePolicy {
+    pub zone: LowerName,
+    pub action: NegativeAction,
+}
+
+impl TryFrom<NegativeSettings> for NegativePolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: NegativeSettings) -> anyhow::Result<Self> {
+        let mut zone = Name::from_ascii(&cfg.zone).map_err(|e| anyhow::anyhow!("invalid dns.negative zone {}: {}", cfg.zone, e))?;
+        zone.set_fqdn(true);
+        let action = match cfg.policy.to_ascii_lowercase().as_str() {
+            "nxdomain" => NegativeAction::Nxdomain,
+            "wildcard" => {
+                let mut records = Vec::with_capacity(cfg.records.len());
+                for record in &cfg.records {
+                    let (record_type, value) = record
+                        .trim()
+                        .split_once(char::is_whitespace)
+                        .ok_or_else(|| anyhow::anyhow!("invalid dns.negative record {}, expected `TYPE value`", record))?;
+                    let record = DnsState::build_record(zone.to_string(), record_type.into(), value.trim().into(), cfg.ttl)
+                        .map_err(|e| anyhow::anyhow!("invalid dns.negative record for {}: {}", zone, e))?;
+                    records.push(record);
+                }
+                if records.is_empty() {
+                    anyhow::bail!("dns.negative zone {} has policy wildcard but no records", zone);
+                }
+                NegativeAction::Wildcard(records)
+            }
+            "redirect" => {
+                let mut records = Vec::with_capacity(cfg.addresses.len());
+                for address in &cfg.addresses {
+                    let address: IpAddr = address
+                        .parse()
+                        .map_err(|e| anyhow::anyhow!("invalid dns.negative address {}: {}", address, e))?;
+                    let rdata = match address {
+                        IpAddr::V4(address) => RData::A(address.into()),
+                        IpAddr::V6(address) => RData::AAAA(address.into()),
+                    };
+                    records.push(Record::from_rdata(zone.clone(), cfg.ttl, rdata));
+                }
+                if records.is_empty() {
+                    anyhow::bail!("dns.negative zone {} has policy redirect but no addresses", zone);
+                }
+                NegativeAction::Redirect(records)
+            }
+            _ => anyhow::bail!("unknown dns.negative policy {}, expected nxdomain, wildcard or redirect", cfg.policy),
+        };
+        Ok(NegativePolicy {
+            zone: LowerName::new(&zone),
+            action,
+        })
+    }
+}
+
+impl NegativePolicy {
+    /// The answers for unknown `name` queried for `record_type`; none when it stays
+    /// NXDOMAIN.
+    fn answers(&self, name: &Name, record_type: RecordType) -> Option<Vec<Record>> {
+        let matches = |record: &&Record| record_type == RecordType::ANY || record.record_type() == record_type;
+        let answers = match &self.action {
+            NegativeAction::Nxdomain => return None,
+            // A wildcard CNAME answers queries of every type
+            NegativeAction::Wildcard(records) => records
+                .iter()
+                .filter(|record| matches(record) || record.record_type() == RecordType::CNAME)
+                .cloned()
+                .collect(),
+            NegativeAction::Redirect(records) => {
+                let answers: Vec<Record> = records.iter().filter(matches).cloned().collect();
+                if answers.is_empty() {
+                    return None;
+                }
+                answers
+            }
+        };
+        Some(
+            answers
+                .into_iter()
+                .map(|mut record| {
+                    record.set_name(name.clone());
+                    record
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The policy of the most specific zone of `policies` containing the name `request`
+/// queries for, unless unknown names there get NXDOMAIN anyway.
+pub fn policy_for(policies: &[NegativePolicy], request: &Request) -> Option<NegativePolicy> {
+    if request.op_code() != OpCode::Query {
+        return None;
+    }
+    let name = request.query().name();
+    policies
+        .iter()
+        .filter(|policy| policy.zone.zone_of(name))
+        .max_by_key(|policy| policy.zone.num_labels())
+        .filter(|policy| !matches!(policy.action, NegativeAction::Nxdomain))
+        .cloned()
+}
+
+/// Response handler that answers NXDOMAIN responses as the zone's policy says.
+#[derive(Clone)]
+pub struct NegativeResponseHandler<R> {
+    inner: R,
+    /// The policy to apply; responses are passed on as they are when unset.
+    policy: Option<NegativePolicy>,
+}
+
+impl<R> NegativeResponseHandler<R> {
+    pub fn new(inner: R, policy: Option<NegativePolicy>) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for NegativeResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let Some(policy) = self.policy.as_ref().filter(|_| response.header().response_code() == ResponseCode::NXDomain) else {
+            return self.inner.send_response(response).await;
+        };
+        // The response can't be inspected in place, so it is encoded and re-read, then
+        // rebuilt with the synthesized answers
+        let message = roundrobin::reread(response)?;
+
+        let query = message.query();
+        let answers = policy.answers(query.original().name(), query.query_type());
+        let mut header = *message.header();
+        if answers.is_some() {
+            header.set_response_code(ResponseCode::NoError);
+        }
+        let answers = answers.unwrap_or_default();
+        let mut builder = MessageResponseBuilder::from_message_request(&message);
+        if let Some(edns) = message.edns() {
+            builder.edns(edns.clone());
+        }
+        // The SOA stays in the authority section of NODATA answers for negative caching
+        let response = builder.build(
+            header,
+            answers.iter(),
+            message.name_servers().iter().filter(|_| answers.is_empty()),
+            [],
+            message.additionals().iter().chain(message.sig0()),
+        );
+        self.inner.send_response(response).await
+    }
+}
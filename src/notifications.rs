@@ -0,0 +1,275 @@
+//! Outbound webhooks notified of record and zone changes.
+//!
+//! With `[[notifications]]` configured, every record added, updated or deleted and every
+//! zone created or deleted is POSTed as a JSON object to the endpoints listed, so that
+//! external systems (CMDBs, chat alerts) learn of DNS changes without polling
+//! `GetAllRecords`. So is every secondary zone going stale, expiring or recovering, for
+//! transfer failures to be noticed before the zone stops being served:
+//!
+//! ```json
+//! {"event": "record.added", "timestamp": 1700000000, "record": {"name": "www.example.com.", "record_type": "A", "value": "192.0.2.1", "ttl": 300}}
+//! {"event": "zone.deleted", "timestamp": 1700000000, "zone": "example.com."}
+//! ```
+//!
+//! An endpoint may be limited to the changes under some zones and to some events. With a
+//! `secret`, each request carries an `X-Rdns-Signature: sha256=<hex>` header holding the
+//! HMAC-SHA256 of its body keyed with the secret, for the receiver to check it came from
+//! rdns. Deliveries failing with a connection error or a non-2xx status are retried with
+//! exponential backoff, then dropped with an error logged. Every endpoint is sent its
+//! changes in order from a queue of its own, so that a slow endpoint holds up neither the
+//! others nor the API; changes beyond a full queue are dropped and logged.
+//!
+//! A follower sends no notifications, as changes are only made on its leader.
+
+use hickory_proto::rr::{LowerName, Name};
+use hyper::client::HttpConnector;
+use hyper::{header, Body, Client, Method, Uri};
+use hyper_rustls::HttpsConnector;
+use serde::Serialize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, RwLock};
+
+use crate::dns::{DnsState, RecordChange, RecordEntry, RecordEvent, ZoneChange, ZoneEvent};
+use crate::settings::NotificationSettings;
+
+/// Changes queued per endpoint before further ones are dropped.
+const QUEUE_CAPACITY: usize = 1000;
+/// Delay before the first retry of a failed delivery, doubled for every further one.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest delay between two attempts at a delivery.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Header holding the HMAC-SHA256 signature of the body.
+const SIGNATURE_HEADER: &str = "x-rdns-signature";
+
+/// Kinds of change an endpoint may be notified of.
+const EVENTS: [&str; 8] = [
+    "record.added",
+    "record.updated",
+    "record.deleted",
+    "zone.created",
+    "zone.deleted",
+    "zone.stale",
+    "zone.expired",
+    "zone.recovered",
+];
+
+/// Config options for one notification endpoint
+#[derive(Clone)]
+pub struct NotificationOptions {
+    pub url: Uri,
+    /// Key of the signature of the request bodies, unsigned when unset.
+    pub secret: Option<String>,
+    /// Only changes of names at or under these zones; all changes when empty.
+    pub zones: Vec<LowerName>,
+    /// Only these kinds of change, e.g. `record.deleted`; all kinds when empty.
+    pub events: Vec<String>,
+    /// Attempts after the first at delivering a change.
+    pub max_retries: u32,
+    /// How long a single attempt may take.
+    pub timeout: Duration,
+}
+
+impl TryFrom<NotificationSettings> for NotificationOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: NotificationSettings) -> anyhow::Result<Self> {
+        let url = Uri::from_str(&cfg.url)?;
+        if !matches!(url.scheme_str(), Some("http" | "https")) {
+            anyhow::bail!("notification url {} must be http or https", cfg.url);
+        }
+        if let Some(event) = cfg.events.iter().find(|event| !EVENTS.contains(&event.as_str())) {
+            anyhow::bail!("unknown notification event {}, expected one of {}", event, EVENTS.join(", "));
+        }
+        if cfg.timeout_secs == 0 {
+            anyhow::bail!("notification timeout_secs must be at least 1");
+        }
+        let zones = cfg
+            .zones
+            .iter()
+            .map(|zone| {
+                let mut origin = Name::from_str(zone)?;
+                origin.set_fqdn(true);
+                Ok(LowerName::new(&origin))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(NotificationOptions {
+            url,
+            secret: cfg.secret.filter(|secret| !secret.is_empty()),
+            zones,
+            events: cfg.events,
+            max_retries: cfg.max_retries,
+            timeout: Duration::from_secs(cfg.timeout_secs),
+        })
+    }
+}
+
+impl NotificationOptions {
+    /// Whether the endpoint is notified of `notification`.
+    fn wants(&self, notification: &Notification) -> bool {
+        (self.events.is_empty() || self.events.iter().any(|event| event == notification.event))
+            && (self.zones.is_empty() || self.zones.iter().any(|zone| zone.zone_of(&notification.name)))
+    }
+}
+
+/// A change, as it is sent to the endpoints.
+#[derive(Serialize)]
+struct Notification {
+    event: &'static str,
+    /// Seconds since the Unix epoch.
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    record: Option<RecordEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    zone: Option<String>,
+    /// Name the change applies to, as endpoints filter on it
+    #[serde(skip)]
+    name: LowerName,
+}
+
+impl Notification {
+    fn of_record(event: RecordEvent) -> Option<Self> {
+        let name = LowerName::new(&Name::from_str(&event.record.name).ok()?);
+        Some(Notification {
+            event: match event.change {
+                RecordChange::Added => "record.added",
+                RecordChange::Updated => "record.updated",
+                RecordChange::Deleted => "record.deleted",
+            },
+            timestamp: unix_secs(event.timestamp),
+            record: Some(event.record),
+            zone: None,
+            name,
+        })
+    }
+
+    fn of_zone(event: ZoneEvent) -> Self {
+        Notification {
+            event: match event.change {
+                ZoneChange::Created => "zone.created",
+                ZoneChange::Deleted => "zone.deleted",
+                ZoneChange::Stale => "zone.stale",
+                ZoneChange::Expired => "zone.expired",
+                ZoneChange::Recovered => "zone.recovered",
+            },
+            timestamp: unix_secs(event.timestamp),
+            record: None,
+            zone: Some(event.origin.to_string()),
+            name: event.origin,
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Sends the record and zone changes made to `state` to the `endpoints`, until the state
+/// is dropped.
+pub async fn run_notifier(state: Arc<RwLock<DnsState>>, endpoints: Vec<NotificationOptions>) {
+    let (mut records, mut zones) = {
+        let state = state.read().await;
+        (state.subscribe(), state.subscribe_zones())
+    };
+    drop(state);
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let http = Client::builder().build(connector);
+    let queues: Vec<(NotificationOptions, mpsc::Sender<Vec<u8>>)> = endpoints
+        .into_iter()
+        .map(|endpoint| {
+            let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+            tokio::spawn(deliver_all(http.clone(), endpoint.clone(), receiver));
+            (endpoint, sender)
+        })
+        .collect();
+    for (endpoint, _) in &queues {
+        println!("Notifying {} of record and zone changes", endpoint.url);
+    }
+
+    loop {
+        let notification = tokio::select! {
+            event = records.recv() => match event {
+                Ok(event) => Notification::of_record(event),
+                Err(RecvError::Lagged(missed)) => {
+                    eprintln!("Notifications fell behind, {} record changes not sent", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+            event = zones.recv() => match event {
+                Ok(event) => Some(Notification::of_zone(event)),
+                Err(RecvError::Lagged(missed)) => {
+                    eprintln!("Notifications fell behind, {} zone changes not sent", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            },
+        };
+        let Some(notification) = notification else {
+            continue;
+        };
+        let Ok(body) = serde_json::to_vec(&notification) else {
+            continue;
+        };
+        for (endpoint, queue) in &queues {
+            if endpoint.wants(&notification) && queue.try_send(body.clone()).is_err() {
+                eprintln!("Notification queue of {} is full, dropping a {} change", endpoint.url, notification.event);
+            }
+        }
+    }
+}
+
+/// Delivers the bodies queued for `endpoint` one after the other.
+async fn deliver_all(http: Client<HttpsConnector<HttpConnector>>, endpoint: NotificationOptions, mut queue: mpsc::Receiver<Vec<u8>>) {
+    while let Some(body) = queue.recv().await {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            match deliver(&http, &endpoint, &body).await {
+                Ok(()) => break,
+                Err(e) if attempt < endpoint.max_retries => {
+                    crate::debug!("Notifying {} failed, retrying in {}s: {}", endpoint.url, backoff.as_secs(), e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                    attempt += 1;
+                }
+                Err(e) => {
+                    eprintln!("Notifying {} failed after {} attempts, dropping the change: {}", endpoint.url, attempt + 1, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// POSTs `body` to `endpoint` once, signed if it has a secret.
+async fn deliver(http: &Client<HttpsConnector<HttpConnector>>, endpoint: &NotificationOptions, body: &[u8]) -> anyhow::Result<()> {
+    let mut request = hyper::Request::builder()
+        .method(Method::POST)
+        .uri(endpoint.url.clone())
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::USER_AGENT, concat!("rdns/", env!("CARGO_PKG_VERSION")));
+    if let Some(secret) = &endpoint.secret {
+        request = request.header(SIGNATURE_HEADER, format!("sha256={}", sign(secret, body)));
+    }
+    let request = request.body(Body::from(body.to_vec()))?;
+    let response = tokio::time::timeout(endpoint.timeout, http.request(request))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out after {}s", endpoint.timeout.as_secs()))??;
+    if !response.status().is_success() {
+        anyhow::bail!("HTTP {}", response.status());
+    }
+    Ok(())
+}
+
+/// Hex-encoded HMAC-SHA256 of `body` keyed with `secret`.
+fn sign(secret: &str, body: &[u8]) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    data_encoding::HEXLOWER.encode(ring::hmac::sign(&key, body).as_ref())
+}
@@ -0,0 +1,82 @@
+//! Local overrides of forwarded names.
+//!
+//! Split DNS often needs a single host of a domain answered locally, such as an intranet
+//! address for `vpn.example.com`, while every other name of the domain is still resolved
+//! upstream. Hosting the zone would take over the whole domain, so an override pins a
+//! name and type to local values instead, and the names around it keep being forwarded.
+//! An overridden name is answered with no records for the types it has no override of,
+//! as a hosted name would be, and with its CNAME for any type when it has one.
+//!
+//! Overrides are of names outside the hosted zones, whose records are changed directly.
+//! They are answered before the forwarder and its cache, apply to each name alone and
+//! not to the names below it, and their answers aren't DNSSEC-signed.
+
+use hickory_proto::rr::{LowerName, Record, RecordType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::error::RdnsError;
+
+/// An override in its textual form, as managed through the control API and persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OverrideEntry {
+    pub name: String,
+    pub record_type: String,
+    pub ttl: u32,
+    /// Values in zone file syntax
+    pub values: Vec<String>,
+}
+
+/// Overrides by name, then by type.
+pub type OverrideTable = RwLock<HashMap<LowerName, HashMap<RecordType, Arc<Override>>>>;
+
+/// A validated override, with its values parsed.
+pub struct Override {
+    pub entry: OverrideEntry,
+    records: Vec<Record>,
+}
+
+impl Override {
+    /// Builds an override from `entry` and the records parsed from its values.
+    pub fn new(mut entry: OverrideEntry, mut records: Vec<Record>) -> Result<Self, RdnsError> {
+        let Some(first) = records.first() else {
+            return Err(RdnsError::InvalidArgument(format!("the override of {} needs at least one value", entry.name)));
+        };
+        if matches!(first.record_type(), RecordType::SOA | RecordType::NS) {
+            return Err(RdnsError::InvalidArgument(format!("{} records can't be overridden", first.record_type())));
+        }
+        if first.name().is_wildcard() {
+            return Err(RdnsError::InvalidArgument("overrides can't be of wildcards".into()));
+        }
+        let name = first.name().to_lowercase();
+        entry.name = name.to_ascii();
+        entry.record_type = first.record_type().to_string();
+        for record in &mut records {
+            record.set_name(name.clone());
+        }
+        Ok(Override { entry, records })
+    }
+
+    /// The name the override answers for.
+    pub fn name(&self) -> LowerName {
+        LowerName::new(self.records[0].name())
+    }
+
+    pub fn record_type(&self) -> RecordType {
+        self.records[0].record_type()
+    }
+}
+
+/// The records answering `name` of `record_type`, or `None` when the name has no
+/// override, for the query to go on.
+pub fn answer(overrides: &OverrideTable, name: &LowerName, record_type: RecordType) -> Option<Vec<Record>> {
+    let overrides = overrides.read().unwrap();
+    let types = overrides.get(name)?;
+    let records = types
+        .get(&record_type)
+        .or_else(|| types.get(&RecordType::CNAME))
+        .map(|found| found.records.clone())
+        .unwrap_or_default();
+    Some(records)
+}
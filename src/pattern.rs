@@ -0,0 +1,291 @@
+//! Records synthesized from the query name.
+//!
+//! Ephemeral environments, such as one per branch under review, come and go faster than
+//! records would be kept for them. A name pattern answers every name it matches instead,
+//! e.g. `{branch}.preview.example.com` answers `fix-login.preview.example.com` and
+//! `main.preview.example.com` alike. A placeholder matches any one label, and the values
+//! are templates the labels it matched are filled into, so that the answers may carry
+//! them too: `0 0 8080 {branch}.k8s.example.net.` as an SRV value, for instance. The
+//! `{ip}` placeholder only matches an address written with dashes, like `10-0-0-1` or
+//! `2001-db8--1`, which makes `{ip}` as a value answer with the address the name embeds,
+//! nip.io-style.
+//!
+//! A name several patterns match is answered by the most specific one: comparing from the
+//! right, a literal label beats a placeholder, and `{ip}` beats the others. Names a
+//! pattern matches are answered with no records for the types it has no values of, or
+//! whose values the name can't fill, e.g. an IPv6 address into an A value, and with its
+//! values for any type when it is a CNAME. Patterns answer before the hosted zones, and
+//! their answers aren't DNSSEC-signed.
+
+use hickory_proto::rr::{LowerName, Name, Record, RecordType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Arc, RwLock};
+
+use crate::dns::DnsState;
+use crate::error::RdnsError;
+
+/// Placeholder matching the labels that are an address written with dashes.
+const IP: &str = "ip";
+
+/// A name pattern in its textual form, as managed through the control API and persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PatternEntry {
+    /// Name whose labels may be placeholders such as `{branch}`
+    pub pattern: String,
+    pub record_type: String,
+    pub ttl: u32,
+    /// Values in zone file syntax, which may hold the placeholders of the pattern
+    pub values: Vec<String>,
+}
+
+impl PatternEntry {
+    /// The part of the pattern right of its rightmost placeholder, which is a name in the
+    /// zone of the names it matches.
+    pub fn suffix(&self) -> &str {
+        suffix(&self.pattern)
+    }
+}
+
+/// The part of `pattern` right of its rightmost placeholder.
+pub fn suffix(pattern: &str) -> &str {
+    pattern.rsplit_once('}').map_or(pattern, |(_, suffix)| suffix.trim_start_matches('.'))
+}
+
+/// Name patterns, most specific first.
+pub type PatternTable = RwLock<Vec<Arc<Pattern>>>;
+
+/// A label of a pattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Label {
+    /// Matches this label alone, lower-cased.
+    Literal(String),
+    /// Matches any label, filled in for the placeholder of this name.
+    Placeholder(String),
+    /// Matches a label that is an address written with dashes.
+    Ip,
+}
+
+impl Label {
+    /// How specific the label is, lower being more.
+    fn rank(&self) -> u8 {
+        match self {
+            Label::Literal(_) => 0,
+            Label::Ip => 1,
+            Label::Placeholder(_) => 2,
+        }
+    }
+}
+
+/// A validated name pattern.
+pub struct Pattern {
+    pub entry: PatternEntry,
+    /// Labels from the leftmost.
+    labels: Vec<Label>,
+    record_type: RecordType,
+    /// The name right of the rightmost placeholder, which is in the zone of the names
+    /// matched.
+    suffix: LowerName,
+}
+
+impl Pattern {
+    /// Parses `entry`, bringing its pattern into canonical form: lower-cased and fully
+    /// qualified. Its values are checked by filling them in for a sample name.
+    pub fn new(mut entry: PatternEntry) -> Result<Self, RdnsError> {
+        let pattern = entry.pattern.trim_end_matches('.').to_ascii_lowercase();
+        let mut labels = Vec::new();
+        for label in pattern.split('.') {
+            let label = match label.strip_prefix('{').and_then(|label| label.strip_suffix('}')) {
+                Some(IP) => Label::Ip,
+                Some(name) if !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') => {
+                    Label::Placeholder(name.to_string())
+                }
+                Some(_) => return Err(RdnsError::InvalidArgument(format!("{} is not a valid placeholder", label))),
+                None => Label::Literal(label.to_string()),
+            };
+            if !matches!(label, Label::Literal(_)) && labels.contains(&label) {
+                return Err(RdnsError::InvalidArgument(format!("{} has placeholder {} twice", entry.pattern, placeholder(&label))));
+            }
+            labels.push(label);
+        }
+        if labels.iter().all(|label| matches!(label, Label::Literal(_))) {
+            return Err(RdnsError::InvalidArgument(format!("{} has no placeholder, add a record instead", entry.pattern)));
+        }
+        entry.pattern = format!("{}.", pattern);
+        if entry.suffix() == "." {
+            return Err(RdnsError::InvalidArgument(format!("{} must end in the name of a zone", entry.pattern)));
+        }
+        let suffix = LowerName::new(&DnsState::parse_name(entry.suffix())?);
+        let record_type = DnsState::parse_record_type(&entry.record_type)?;
+        if matches!(record_type, RecordType::SOA | RecordType::NS) {
+            return Err(RdnsError::InvalidArgument(format!("{} records can't be synthesized", record_type)));
+        }
+        if entry.values.is_empty() {
+            return Err(RdnsError::InvalidArgument(format!("{} needs at least one value", entry.pattern)));
+        }
+        entry.record_type = record_type.to_string();
+        let pattern = Pattern {
+            entry,
+            labels,
+            record_type,
+            suffix,
+        };
+        for value in &pattern.entry.values {
+            pattern.check_template(value)?;
+        }
+        Ok(pattern)
+    }
+
+    /// The pattern and type the values answer for.
+    pub fn key(&self) -> (&str, RecordType) {
+        (&self.entry.pattern, self.record_type)
+    }
+
+    /// The name of the zone the names matched are in, or of a name in it.
+    pub fn suffix(&self) -> &LowerName {
+        &self.suffix
+    }
+
+    /// Orders patterns most specific first, see the module docs.
+    fn specificity(&self) -> Vec<u8> {
+        self.labels.iter().rev().map(Label::rank).collect()
+    }
+
+    /// The placeholders of `name` and the labels they matched, if the pattern matches it.
+    fn matches(&self, name: &Name) -> Option<HashMap<&str, String>> {
+        // num_labels() leaves out a leading wildcard, which is matched as any other label
+        if name.iter().count() != self.labels.len() {
+            return None;
+        }
+        let mut filled = HashMap::new();
+        for (label, part) in self.labels.iter().zip(name.iter()) {
+            let part = std::str::from_utf8(part).ok()?.to_ascii_lowercase();
+            match label {
+                Label::Literal(literal) if *literal == part => {}
+                Label::Literal(_) => return None,
+                Label::Placeholder(placeholder) => {
+                    filled.insert(placeholder.as_str(), part);
+                }
+                Label::Ip => {
+                    filled.insert(IP, dashed_address(&part)?.to_string());
+                }
+            }
+        }
+        Some(filled)
+    }
+
+    /// The records answering `name` of `record_type`, or `None` if the pattern doesn't
+    /// match it or is of another type. Values that can't be filled for it are left out.
+    fn answer(&self, name: &Name, record_type: RecordType) -> Option<Vec<Record>> {
+        if self.record_type != record_type && self.record_type != RecordType::CNAME {
+            return None;
+        }
+        let filled = self.matches(name)?;
+        let records = self
+            .entry
+            .values
+            .iter()
+            .filter_map(|value| {
+                let value = fill(value, &filled);
+                DnsState::build_record(name.to_string(), self.entry.record_type.clone(), value, self.entry.ttl).ok()
+            })
+            .collect();
+        Some(records)
+    }
+
+    /// Fails if `value` holds a placeholder the pattern doesn't have, or isn't a valid
+    /// value of the pattern's type once filled for a sample name.
+    fn check_template(&self, value: &str) -> Result<(), RdnsError> {
+        let mut rest = value;
+        while let Some(start) = rest.find('{') {
+            let Some(end) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 1..start + end];
+            let known = self.labels.iter().any(|label| match label {
+                Label::Placeholder(placeholder) => placeholder == name,
+                Label::Ip => name == IP,
+                Label::Literal(_) => false,
+            });
+            if !known {
+                return Err(RdnsError::InvalidArgument(format!("{} has no placeholder {{{}}}", self.entry.pattern, name)));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        let sample_ip = match self.record_type {
+            RecordType::AAAA => IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+            _ => IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+        };
+        let mut filled = HashMap::new();
+        let mut sample = Vec::new();
+        for label in &self.labels {
+            match label {
+                Label::Literal(literal) => sample.push(literal.clone()),
+                Label::Placeholder(placeholder) => {
+                    filled.insert(placeholder.as_str(), "sample".to_string());
+                    sample.push("sample".to_string());
+                }
+                Label::Ip => {
+                    filled.insert(IP, sample_ip.to_string());
+                    sample.push(sample_ip.to_string().replace(['.', ':'], "-"));
+                }
+            }
+        }
+        DnsState::build_record(sample.join("."), self.entry.record_type.clone(), fill(value, &filled), self.entry.ttl)?;
+        Ok(())
+    }
+}
+
+/// Inserts `pattern` into `patterns`, replacing the one of the same pattern and type,
+/// and keeping them most specific first.
+pub fn insert(patterns: &mut Vec<Arc<Pattern>>, pattern: Pattern) {
+    patterns.retain(|existing| existing.key() != pattern.key());
+    let position = patterns.partition_point(|existing| existing.specificity() <= pattern.specificity());
+    patterns.insert(position, Arc::new(pattern));
+}
+
+/// The records answering `name` of `record_type`, from the most specific pattern that
+/// matches it; `None` when none does, for the query to go on to the zones.
+pub fn answer(patterns: &PatternTable, name: &Name, record_type: RecordType) -> Option<Vec<Record>> {
+    let patterns = patterns.read().unwrap();
+    let mut matched = None;
+    for pattern in patterns.iter() {
+        // Less specific patterns are left to the names the first one matching doesn't match
+        if matched.as_ref().is_some_and(|specificity| *specificity != pattern.specificity()) {
+            break;
+        }
+        if pattern.matches(name).is_none() {
+            continue;
+        }
+        matched = Some(pattern.specificity());
+        if let Some(records) = pattern.answer(name, record_type) {
+            return Some(records);
+        }
+    }
+    matched.map(|_| Vec::new())
+}
+
+/// The name of a placeholder label, as written in patterns.
+fn placeholder(label: &Label) -> String {
+    match label {
+        Label::Placeholder(name) => format!("{{{}}}", name),
+        Label::Ip => format!("{{{}}}", IP),
+        Label::Literal(literal) => literal.clone(),
+    }
+}
+
+/// `template` with its placeholders replaced by the labels they matched.
+fn fill(template: &str, filled: &HashMap<&str, String>) -> String {
+    filled
+        .iter()
+        .fold(template.to_string(), |value, (placeholder, label)| value.replace(&format!("{{{}}}", placeholder), label))
+}
+
+/// The address `label` writes with dashes rather than dots or colons, if it is one.
+fn dashed_address(label: &str) -> Option<IpAddr> {
+    if let Ok(v4) = label.replace('-', ".").parse::<Ipv4Addr>() {
+        return Some(IpAddr::V4(v4));
+    }
+    label.replace('-', ":").parse::<Ipv6Addr>().ok().map(IpAddr::V6)
+}
@@ -0,0 +1,515 @@
+//! Record persistence.
+//!
+//! Stores a JSON snapshot of every hosted zone and its records so that `DnsState` can
+//! reload them on startup. The snapshot is rewritten in full on every mutation, along
+//! with a journal of the latest changes, from whose last sequence number the changes
+//! made after a restart go on. Where it is stored is up to the `ZoneStore` backend
+//! picked with `storage.backend`:
+//!
+//! - `file`, the default when `storage.path` is set: a JSON file, written to a
+//!   temporary file and renamed so a crash mid-write never leaves a truncated snapshot
+//!   behind, and the journal in a `.journal` file next to it.
+//! - `sqlite`: a SQLite database at `storage.path`, each write a transaction.
+//! - `memory`: nothing outlives the process, for embedding and tests that restart
+//!   `DnsState` within it.
+//! - `etcd` and `consul`: a key-value store that several instances share, see
+//!   [`crate::kvstore`].
+//!
+//! Applications embedding the servers may bring backends of their own, e.g. on S3, by
+//! implementing `ZoneStore` and passing them to `Store::new`.
+//!
+//! Snapshots carry the version of their format, so that the `BackupState` backups made
+//! from them can be restored by later releases, and backups made by a later release
+//! are refused rather than misread.
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, RwLock};
+use tonic::async_trait;
+
+use crate::alias::AliasEntry;
+use crate::approval::ChangeRequest;
+use crate::dns::{DnsState, RecordEntry};
+use crate::forward::ForwardRuleEntry;
+use crate::geo::GeoEntry;
+use crate::health::HealthCheckEntry;
+use crate::kvstore::{KvFlavor, KvOptions, KvStore};
+use crate::lease::LeaseEntry;
+use crate::overrides::OverrideEntry;
+use crate::pattern::PatternEntry;
+use crate::pool::PoolEntry;
+use crate::queryrules::QueryRuleEntry;
+use crate::rewrite::RewriteRuleEntry;
+use crate::rollout::RolloutEntry;
+use crate::schedule::ScheduledChange;
+use crate::service::ServiceEntry;
+use crate::settings::StorageSettings;
+use crate::tenant::TenantEntry;
+use crate::timed::TimedEntry;
+use crate::tsig::TsigKeyEntry;
+
+/// Version of the snapshot format written by this release.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A hosted zone and its records, as stored in the snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZoneSnapshot {
+    pub origin: String,
+    pub records: Vec<RecordEntry>,
+    /// Whether the zone's A and AAAA records get matching PTR records.
+    #[serde(default)]
+    pub auto_reverse: bool,
+    /// TTL of the records added to the zone with a TTL of 0, from its template.
+    #[serde(default)]
+    pub default_ttl: Option<u32>,
+    /// Tenant owning the zone, when it was created by or assigned to one at runtime.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Whether the zone is frozen for maintenance.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub frozen: bool,
+}
+
+/// The record overlay of a split-horizon view, as stored in the snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ViewSnapshot {
+    pub name: String,
+    pub records: Vec<RecordEntry>,
+}
+
+/// The runtime blocklist entries, as stored in the snapshot.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlocklistSnapshot {
+    pub block: Vec<String>,
+    pub allow: Vec<String>,
+}
+
+/// The full on-disk state of the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Format version; snapshots written before it was recorded are version 1.
+    #[serde(default = "default_version")]
+    pub version: u32,
+    pub zones: Vec<ZoneSnapshot>,
+    /// Weighted and failover pools over records of the zones.
+    #[serde(default)]
+    pub pools: Vec<PoolEntry>,
+    /// Health checks of record values.
+    #[serde(default)]
+    pub health_checks: Vec<HealthCheckEntry>,
+    /// Records answered with different values per client region.
+    #[serde(default)]
+    pub geo_records: Vec<GeoEntry>,
+    /// Records answered with new values for a share of the clients.
+    #[serde(default)]
+    pub rollouts: Vec<RolloutEntry>,
+    /// Records answered with other values while a schedule is active.
+    #[serde(default)]
+    pub timed: Vec<TimedEntry>,
+    /// Name patterns answered with records synthesized from the query name.
+    #[serde(default)]
+    pub patterns: Vec<PatternEntry>,
+    /// Forwarded names answered locally instead.
+    #[serde(default)]
+    pub overrides: Vec<OverrideEntry>,
+    /// Names answered with the addresses of another.
+    #[serde(default)]
+    pub aliases: Vec<AliasEntry>,
+    #[serde(default)]
+    pub views: Vec<ViewSnapshot>,
+    /// Leases of records added with one.
+    #[serde(default)]
+    pub leases: Vec<LeaseEntry>,
+    /// Record changes queued until they are due.
+    #[serde(default)]
+    pub scheduled_changes: Vec<ScheduledChange>,
+    /// Record changes proposed for an admin's approval.
+    #[serde(default)]
+    pub approvals: Vec<ChangeRequest>,
+    /// Service registrations, whose records are in the zones.
+    #[serde(default)]
+    pub services: Vec<ServiceEntry>,
+    /// Domains blocked or allowed through the control API.
+    #[serde(default)]
+    pub blocklist: BlocklistSnapshot,
+    /// TSIG keys created through the control API.
+    #[serde(default)]
+    pub tsig_keys: Vec<TsigKeyEntry>,
+    /// Tenants created through the control API.
+    #[serde(default)]
+    pub tenants: Vec<TenantEntry>,
+    /// Forwarding rules added through the control API, in the order they are matched.
+    #[serde(default)]
+    pub forward_rules: Vec<ForwardRuleEntry>,
+    /// Rewrite rules added through the control API, in the order they are tried.
+    #[serde(default)]
+    pub rewrite_rules: Vec<RewriteRuleEntry>,
+    /// Query rules added through the control API, in the order they are tried.
+    #[serde(default)]
+    pub query_rules: Vec<QueryRuleEntry>,
+}
+
+fn default_version() -> u32 {
+    1
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            zones: Vec::new(),
+            pools: Vec::new(),
+            health_checks: Vec::new(),
+            geo_records: Vec::new(),
+            rollouts: Vec::new(),
+            timed: Vec::new(),
+            patterns: Vec::new(),
+            overrides: Vec::new(),
+            aliases: Vec::new(),
+            views: Vec::new(),
+            leases: Vec::new(),
+            scheduled_changes: Vec::new(),
+            approvals: Vec::new(),
+            services: Vec::new(),
+            blocklist: BlocklistSnapshot::default(),
+            tsig_keys: Vec::new(),
+            tenants: Vec::new(),
+            forward_rules: Vec::new(),
+            rewrite_rules: Vec::new(),
+            query_rules: Vec::new(),
+        }
+    }
+}
+
+impl Snapshot {
+    /// Parses a snapshot, as written by `encode`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `bytes` isn't a snapshot, or is one of a newer format version.
+    pub fn decode(bytes: &[u8]) -> anyhow::Result<Self> {
+        let snapshot: Snapshot = serde_json::from_slice(bytes).map_err(|e| anyhow::anyhow!("invalid snapshot: {}", e))?;
+        if snapshot.version > SNAPSHOT_VERSION {
+            anyhow::bail!(
+                "snapshot is of format version {}, but at most version {} is supported",
+                snapshot.version,
+                SNAPSHOT_VERSION
+            );
+        }
+        Ok(snapshot)
+    }
+
+    /// Serializes the snapshot as JSON.
+    pub fn encode(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// Entries kept in the journal of changes.
+pub const JOURNAL_SIZE: usize = 1024;
+
+/// A change made to the state, as journaled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Position of the change in the sequence of changes made to the state
+    pub sequence: u64,
+    pub timestamp: SystemTime,
+    /// What the change covers, e.g. `zone example.com.`
+    pub scope: String,
+}
+
+/// A backend the state is persisted to.
+#[async_trait]
+pub trait ZoneStore: Send + Sync {
+    /// Loads the snapshot, returning `None` if nothing has been persisted yet.
+    async fn load(&self) -> anyhow::Result<Option<Snapshot>>;
+
+    /// Replaces the persisted snapshot with `snapshot`.
+    async fn persist(&self, snapshot: &Snapshot) -> anyhow::Result<()>;
+
+    /// Appends `entries` to the journal, keeping its latest `JOURNAL_SIZE` entries.
+    async fn journal(&self, entries: &[JournalEntry]) -> anyhow::Result<()>;
+
+    /// The journal, oldest entry first.
+    async fn journaled(&self) -> anyhow::Result<Vec<JournalEntry>>;
+
+    /// Signals the writes of other instances sharing the store, for the state to be
+    /// reloaded; `None` for backends that only this instance writes.
+    fn watch(&self) -> Option<mpsc::Receiver<()>> {
+        None
+    }
+}
+
+/// Which backend the state is persisted to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageBackend {
+    Memory,
+    File(PathBuf),
+    Sqlite(PathBuf),
+    Kv(KvOptions),
+}
+
+/// Config options for record persistence
+pub struct StorageOptions {
+    /// Backend the state is persisted to; persistence is disabled when unset.
+    pub backend: Option<StorageBackend>,
+}
+
+impl TryFrom<StorageSettings> for StorageOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: StorageSettings) -> anyhow::Result<Self> {
+        let path = cfg.path.map(PathBuf::from);
+        let backend = match (cfg.backend.map(|backend| backend.to_ascii_lowercase()).as_deref(), path) {
+            (None, None) => None,
+            (None | Some("file"), Some(path)) => Some(StorageBackend::File(path)),
+            (Some("sqlite"), Some(path)) => Some(StorageBackend::Sqlite(path)),
+            (Some("memory"), _) => Some(StorageBackend::Memory),
+            (Some(backend @ ("etcd" | "consul")), _) => {
+                let flavor = if backend == "etcd" { KvFlavor::Etcd } else { KvFlavor::Consul };
+                let endpoint = cfg
+                    .endpoint
+                    .ok_or_else(|| anyhow::anyhow!("storage.endpoint is required by the {} backend", backend))?;
+                Some(StorageBackend::Kv(KvOptions::new(flavor, &endpoint, cfg.prefix, cfg.token)?))
+            }
+            (Some(backend @ ("file" | "sqlite")), None) => anyhow::bail!("storage.path is required by the {} backend", backend),
+            (Some(backend), _) => anyhow::bail!("unknown storage.backend {}, expected memory, file, sqlite, etcd or consul", backend),
+        };
+        Ok(StorageOptions { backend })
+    }
+}
+
+/// The backend `DnsState` persists its state to.
+#[derive(Clone)]
+pub struct Store(Arc<dyn ZoneStore>);
+
+impl Store {
+    /// Wraps `backend`, e.g. one of an embedding application's own.
+    pub fn new(backend: impl ZoneStore + 'static) -> Self {
+        Self(Arc::new(backend))
+    }
+
+    /// Opens the configured backend, or returns `None` if persistence is disabled.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the backend can't be opened, e.g. the SQLite database is corrupt.
+    pub fn from_options(options: StorageOptions) -> anyhow::Result<Option<Self>> {
+        Ok(match options.backend {
+            None => None,
+            Some(StorageBackend::Memory) => Some(Store::new(MemoryStore::default())),
+            Some(StorageBackend::File(path)) => Some(Store::new(FileStore::open(path)?)),
+            Some(StorageBackend::Sqlite(path)) => Some(Store::new(SqliteStore::open(path)?)),
+            Some(StorageBackend::Kv(options)) => Some(Store::new(KvStore::new(options))),
+        })
+    }
+}
+
+impl Deref for Store {
+    type Target = dyn ZoneStore;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+/// Reloads `state` from its store whenever `changes` signals a write by another instance
+/// sharing it. Runs until the store stops watching.
+pub async fn run_reloader(state: Arc<RwLock<DnsState>>, mut changes: mpsc::Receiver<()>) {
+    while changes.recv().await.is_some() {
+        if let Err(e) = state.write().await.reload_stored().await {
+            eprintln!("Failed to reload the records changed by another instance: {}", e);
+        }
+    }
+}
+
+/// Appends `entries` to `journal`, dropping the oldest entries beyond `JOURNAL_SIZE`.
+pub(crate) fn append(journal: &mut VecDeque<JournalEntry>, entries: &[JournalEntry]) {
+    journal.extend(entries.iter().cloned());
+    while journal.len() > JOURNAL_SIZE {
+        journal.pop_front();
+    }
+}
+
+/// Keeps the snapshot and journal in memory only.
+#[derive(Default)]
+pub struct MemoryStore {
+    snapshot: Mutex<Option<Vec<u8>>>,
+    journal: Mutex<VecDeque<JournalEntry>>,
+}
+
+#[async_trait]
+impl ZoneStore for MemoryStore {
+    async fn load(&self) -> anyhow::Result<Option<Snapshot>> {
+        self.snapshot.lock().unwrap().as_deref().map(Snapshot::decode).transpose()
+    }
+
+    async fn persist(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        *self.snapshot.lock().unwrap() = Some(snapshot.encode()?);
+        Ok(())
+    }
+
+    async fn journal(&self, entries: &[JournalEntry]) -> anyhow::Result<()> {
+        append(&mut self.journal.lock().unwrap(), entries);
+        Ok(())
+    }
+
+    async fn journaled(&self) -> anyhow::Result<Vec<JournalEntry>> {
+        Ok(self.journal.lock().unwrap().iter().cloned().collect())
+    }
+}
+
+/// Reads and writes snapshots at a fixed path, and the journal in a `.journal` file next
+/// to it, one JSON entry per line.
+pub struct FileStore {
+    path: PathBuf,
+    journal: tokio::sync::Mutex<VecDeque<JournalEntry>>,
+}
+
+impl FileStore {
+    /// Opens the store at `path`, reading the journal written so far.
+    pub fn open(path: PathBuf) -> anyhow::Result<Self> {
+        let journal = match std::fs::read_to_string(path.with_extension("journal")) {
+            Ok(content) => content
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<Result<_, _>>()
+                .map_err(|e| anyhow::anyhow!("invalid journal: {}", e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => VecDeque::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path,
+            journal: tokio::sync::Mutex::new(journal),
+        })
+    }
+
+    /// Atomically replaces the file at `path` with `content`.
+    async fn replace(path: &std::path::Path, content: &[u8]) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ZoneStore for FileStore {
+    async fn load(&self) -> anyhow::Result<Option<Snapshot>> {
+        match tokio::fs::read(&self.path).await {
+            Ok(bytes) => Ok(Some(Snapshot::decode(&bytes)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn persist(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        FileStore::replace(&self.path, &snapshot.encode()?).await
+    }
+
+    async fn journal(&self, entries: &[JournalEntry]) -> anyhow::Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut journal = self.journal.lock().await;
+        append(&mut journal, entries);
+        let mut content = Vec::new();
+        for entry in journal.iter() {
+            serde_json::to_writer(&mut content, entry)?;
+            content.push(b'\n');
+        }
+        FileStore::replace(&self.path.with_extension("journal"), &content).await
+    }
+
+    async fn journaled(&self) -> anyhow::Result<Vec<JournalEntry>> {
+        Ok(self.journal.lock().await.iter().cloned().collect())
+    }
+}
+
+/// Keeps the snapshot and journal in a SQLite database.
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it and its tables if need be.
+    pub fn open(path: PathBuf) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        let connection = Connection::open(&path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS snapshot (id INTEGER PRIMARY KEY CHECK (id = 0), data BLOB NOT NULL);
+             CREATE TABLE IF NOT EXISTS journal (sequence INTEGER PRIMARY KEY, timestamp_ms INTEGER NOT NULL, scope TEXT NOT NULL);",
+        )?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Runs `f` on the connection, off the async runtime's threads.
+    async fn with<T: Send + 'static>(&self, f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static) -> anyhow::Result<T> {
+        let connection = self.connection.clone();
+        Ok(tokio::task::spawn_blocking(move || f(&mut connection.lock().unwrap())).await??)
+    }
+}
+
+#[async_trait]
+impl ZoneStore for SqliteStore {
+    async fn load(&self) -> anyhow::Result<Option<Snapshot>> {
+        let data: Option<Vec<u8>> = self
+            .with(|connection| connection.query_row("SELECT data FROM snapshot WHERE id = 0", [], |row| row.get(0)).optional())
+            .await?;
+        data.as_deref().map(Snapshot::decode).transpose()
+    }
+
+    async fn persist(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        let data = snapshot.encode()?;
+        self.with(move |connection| connection.execute("INSERT OR REPLACE INTO snapshot (id, data) VALUES (0, ?1)", params![data]))
+            .await?;
+        Ok(())
+    }
+
+    async fn journal(&self, entries: &[JournalEntry]) -> anyhow::Result<()> {
+        let entries = entries.to_vec();
+        self.with(move |connection| {
+            let transaction = connection.transaction()?;
+            for entry in &entries {
+                let timestamp_ms = entry.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+                transaction.execute(
+                    "INSERT OR REPLACE INTO journal (sequence, timestamp_ms, scope) VALUES (?1, ?2, ?3)",
+                    params![entry.sequence as i64, timestamp_ms, entry.scope],
+                )?;
+            }
+            transaction.execute(
+                "DELETE FROM journal WHERE sequence NOT IN (SELECT sequence FROM journal ORDER BY sequence DESC LIMIT ?1)",
+                params![JOURNAL_SIZE as i64],
+            )?;
+            transaction.commit()
+        })
+        .await
+    }
+
+    async fn journaled(&self) -> anyhow::Result<Vec<JournalEntry>> {
+        self.with(|connection| {
+            let mut statement = connection.prepare("SELECT sequence, timestamp_ms, scope FROM journal ORDER BY sequence")?;
+            let entries = statement.query_map([], |row| {
+                Ok(JournalEntry {
+                    sequence: row.get::<_, i64>(0)? as u64,
+                    timestamp: UNIX_EPOCH + Duration::from_millis(row.get::<_, i64>(1)? as u64),
+                    scope: row.get(2)?,
+                })
+            })?;
+            entries.collect()
+        })
+        .await
+    }
+}
@@ -0,0 +1,159 @@
+//! Order of the stages a query passes through.
+//!
+//! Each query is offered to the stages of `dns.pipeline` in turn, and the first one that
+//! answers it ends the chain, like the plugins of a CoreDNS server block. A stage left
+//! out of the list is skipped, even if its feature is configured, so that e.g. geo
+//! records can be given precedence over views, or the CHAOS answers switched off without
+//! removing `[dns.identity]`. Queries no stage answers go on to the hosted zones, the
+//! response cache and the forwarder, which answer every query and so always come last.
+//! Response rate limiting always comes before the pipeline, and dnstap logging, NSID
+//! and the negative-answer policies apply to the responses of every stage.
+
+use hickory_server::server::ResponseInfo;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+/// A stage of the pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Stage {
+    /// Refuses clients and names the access lists don't permit
+    Acl,
+    /// Answers CHAOS queries identifying the instance
+    Chaos,
+    /// Answers AAAA queries of names with A records with no records for the clients
+    /// whose address family policy filters them
+    Family,
+    /// Answers forwarded names overridden locally
+    Override,
+    /// Answers names rewritten by a response policy zone
+    Rpz,
+    /// Answers blocked names from the sinkhole
+    Blocklist,
+    /// Answers the queries the hook of the script answers or blocks
+    Script,
+    /// Answers names a query rule matches with its fixed action
+    Rules,
+    /// Answers delegated names with a referral
+    Delegation,
+    /// Answers ANY queries minimally
+    Any,
+    /// Answers from the overlay of the client's view
+    Views,
+    /// Answers names with values on a schedule with those of the active one
+    Timed,
+    /// Answers geo records with the values for the client's region
+    Geo,
+    /// Answers names being rolled out with the new values for the clients rolled out to
+    Rollout,
+    /// Answers names a name pattern matches with records synthesized from them
+    Pattern,
+    /// Answers aliases with their target's addresses
+    Alias,
+    /// Answers AAAA queries of names without AAAA records with synthesized addresses
+    Dns64,
+    /// Answers names a rewrite rule matches with the records of the name it maps them to
+    Rewrite,
+}
+
+/// Stages in the order they run by default.
+pub const DEFAULT: [Stage; 18] = [
+    Stage::Acl,
+    Stage::Chaos,
+    Stage::Family,
+    Stage::Override,
+    Stage::Rpz,
+    Stage::Blocklist,
+    Stage::Script,
+    Stage::Rules,
+    Stage::Delegation,
+    Stage::Any,
+    Stage::Views,
+    Stage::Timed,
+    Stage::Geo,
+    Stage::Rollout,
+    Stage::Pattern,
+    Stage::Alias,
+    Stage::Dns64,
+    Stage::Rewrite,
+];
+
+impl FromStr for Stage {
+    type Err = anyhow::Error;
+
+    fn from_str(stage: &str) -> anyhow::Result<Self> {
+        DEFAULT
+            .into_iter()
+            .find(|known| known.to_string().eq_ignore_ascii_case(stage))
+            .ok_or_else(|| {
+                let known: Vec<String> = DEFAULT.iter().map(Stage::to_string).collect();
+                anyhow::anyhow!("unknown dns.pipeline stage {}, expected one of {}", stage, known.join(", "))
+            })
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Acl => "acl",
+            Stage::Chaos => "chaos",
+            Stage::Family => "family",
+            Stage::Override => "override",
+            Stage::Rpz => "rpz",
+            Stage::Blocklist => "blocklist",
+            Stage::Script => "script",
+            Stage::Rules => "rules",
+            Stage::Delegation => "delegation",
+            Stage::Any => "any",
+            Stage::Views => "views",
+            Stage::Timed => "timed",
+            Stage::Geo => "geo",
+            Stage::Rollout => "rollout",
+            Stage::Pattern => "pattern",
+            Stage::Alias => "alias",
+            Stage::Dns64 => "dns64",
+            Stage::Rewrite => "rewrite",
+        })
+    }
+}
+
+/// The stages queries pass through, in order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline { stages: DEFAULT.to_vec() }
+    }
+}
+
+impl Pipeline {
+    /// Parses the stages of `dns.pipeline`, each of which may be listed once.
+    pub fn parse(stages: &[String]) -> anyhow::Result<Self> {
+        let mut seen = HashSet::new();
+        let stages = stages
+            .iter()
+            .map(|stage| {
+                let stage: Stage = stage.parse()?;
+                if !seen.insert(stage) {
+                    anyhow::bail!("dns.pipeline lists stage {} more than once", stage);
+                }
+                Ok(stage)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Pipeline { stages })
+    }
+
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+}
+
+/// What a stage did with a query: answered it with the response sent, or passed it and
+/// the response handle on to the next one.
+pub enum Flow<R> {
+    Answered(ResponseInfo),
+    Next(R),
+}
@@ -0,0 +1,176 @@
+//! Weighted and failover record pools.
+//!
+//! A pool groups the values of a name's A or AAAA RRset for simple traffic steering.
+//! All values stay in the zone, so transfers and exports see the full RRset, but an
+//! answer for the pooled name carries a single value: one of the available primary
+//! members, picked at random in proportion to its weight, or one of the available backup
+//! members when no primary is. A member is available when it is enabled and not failing
+//! its health check, if it has one. Values in the RRset that aren't pool members are
+//! never served while the pool exists.
+
+use hickory_proto::rr::{LowerName, RData, Record, RecordType, RrKey};
+use hickory_server::authority::MessageResponse;
+use hickory_server::server::{ResponseHandler, ResponseInfo};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tonic::async_trait;
+
+use crate::health::HealthMonitor;
+use crate::roundrobin;
+
+/// A pool in its textual form, as managed through the control API and persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolEntry {
+    pub name: String,
+    /// `A` or `AAAA`
+    pub record_type: String,
+    pub ttl: u32,
+    pub members: Vec<PoolMemberEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolMemberEntry {
+    /// Address in zone file syntax
+    pub value: String,
+    /// Relative share of answers among the members of the same role
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// Only answered when no primary member is enabled
+    #[serde(default)]
+    pub backup: bool,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Pools by the name and type of the RRset they cover, shared with the request handler.
+pub type PoolTable = RwLock<HashMap<RrKey, Arc<Pool>>>;
+
+/// A validated pool, with the parsed value of each member.
+pub struct Pool {
+    pub entry: PoolEntry,
+    name: LowerName,
+    record_type: RecordType,
+    values: Vec<RData>,
+}
+
+impl Pool {
+    /// Builds a pool from `entry` and `records`, its members parsed as records in order.
+    pub fn new(mut entry: PoolEntry, records: &[Record]) -> anyhow::Result<Self> {
+        let Some(first) = records.first() else {
+            anyhow::bail!("a pool needs at least one member");
+        };
+        if !matches!(first.record_type(), RecordType::A | RecordType::AAAA) {
+            anyhow::bail!("pools can only hold A or AAAA records, not {}", first.record_type());
+        }
+        let values: Vec<RData> = records.iter().filter_map(|record| record.data().cloned()).collect();
+        for (i, value) in values.iter().enumerate() {
+            if values[..i].contains(value) {
+                anyhow::bail!("pool member {} is listed more than once", value);
+            }
+        }
+        entry.name = first.name().to_ascii();
+        entry.record_type = first.record_type().to_string();
+        Ok(Self {
+            entry,
+            name: LowerName::new(first.name()),
+            record_type: first.record_type(),
+            values,
+        })
+    }
+
+    /// The name and type of the RRset the pool covers.
+    pub fn key(&self) -> RrKey {
+        RrKey::new(self.name.clone(), self.record_type)
+    }
+
+    /// Whether `record` belongs to the RRset the pool covers.
+    fn covers(&self, record: &Record) -> bool {
+        record.record_type() == self.record_type && LowerName::new(record.name()) == self.name
+    }
+
+    /// Picks the record to answer with among `records`, or `None` if none of them is an
+    /// available member.
+    fn select<'r>(&self, records: &[&'r Record], health: &HealthMonitor) -> Option<&'r Record> {
+        let candidates: Vec<(&'r Record, &PoolMemberEntry)> = records
+            .iter()
+            .filter_map(|&record| {
+                let index = self.values.iter().position(|value| Some(value) == record.data())?;
+                let member = &self.entry.members[index];
+                (member.enabled && health.is_healthy(record)).then_some((record, member))
+            })
+            .collect();
+        let any_primary = candidates.iter().any(|(_, member)| !member.backup);
+        let tier: Vec<_> = candidates
+            .into_iter()
+            .filter(|(_, member)| member.backup != any_primary)
+            .collect();
+
+        let total: u64 = tier.iter().map(|(_, member)| u64::from(member.weight)).sum();
+        if total == 0 {
+            return tier.first().map(|(record, _)| *record);
+        }
+        let mut pick = rand::thread_rng().gen_range(0..total);
+        for (record, member) in &tier {
+            if pick < u64::from(member.weight) {
+                return Some(record);
+            }
+            pick -= u64::from(member.weight);
+        }
+        None
+    }
+
+    /// Replaces the pool's RRset in `answers` with the selected member, leaving other
+    /// records alone.
+    fn pick_answers<'r>(&self, answers: &'r [Record], health: &HealthMonitor) -> Vec<&'r Record> {
+        let rrset: Vec<&Record> = answers.iter().filter(|record| self.covers(record)).collect();
+        match self.select(&rrset, health) {
+            Some(chosen) => answers
+                .iter()
+                .filter(|&record| !self.covers(record) || std::ptr::eq(record, chosen))
+                .collect(),
+            None => answers.iter().collect(),
+        }
+    }
+}
+
+/// Response handler that answers a pooled name with the member its pool selects.
+#[derive(Clone)]
+pub struct PoolResponseHandler<R> {
+    inner: R,
+    pool: Arc<Pool>,
+    health: Arc<HealthMonitor>,
+}
+
+impl<R> PoolResponseHandler<R> {
+    pub fn new(inner: R, pool: Arc<Pool>, health: Arc<HealthMonitor>) -> Self {
+        Self { inner, pool, health }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for PoolResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let (pool, health) = (self.pool.clone(), self.health.clone());
+        roundrobin::rewrite_answers(&mut self.inner, response, move |answers| pool.pick_answers(answers, &health)).await
+    }
+}
@@ -0,0 +1,119 @@
+//! Client address privacy.
+//!
+//! With `[dns.privacy]` set, the addresses of DNS clients are anonymized wherever they
+//! are recorded: in dnstap frames, in query statistics and the anomalies they report,
+//! and in trace spans. `truncate`, the default, keeps only the network of an address,
+//! its first `ipv4_prefix` or `ipv6_prefix` bits, /24 and /48 unless set. `hash`
+//! replaces an address with a pseudonym of the same family derived from it with
+//! HMAC-SHA256, so that the queries of one client can still be told apart from those of
+//! others without revealing who it is; the key is random unless `hash_key` is set, so
+//! pseudonyms change on restart. Addresses are still used unaltered to answer queries,
+//! e.g. by access lists, views and rate limiting, none of which record them.
+//!
+//! With `client_retention_mins` set, the per-client query statistics of a client are
+//! purged once it hasn't queried for that long, along with the client's share of the
+//! distinct client counts of the names it queried.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+use crate::settings::PrivacySettings;
+
+/// How client addresses are anonymized.
+#[derive(Debug, Clone)]
+pub enum Anonymization {
+    None,
+    /// Truncated to their network of the given prefix lengths
+    Truncate { ipv4_prefix: u8, ipv6_prefix: u8 },
+    /// Replaced with keyed pseudonyms
+    Hash(ring::hmac::Key),
+}
+
+/// Config options for client address privacy
+#[derive(Debug, Clone)]
+pub struct PrivacyOptions {
+    pub anonymization: Anonymization,
+    /// How long per-client statistics are kept after a client's last query.
+    pub client_retention: Option<Duration>,
+}
+
+impl TryFrom<PrivacySettings> for PrivacyOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: PrivacySettings) -> anyhow::Result<Self> {
+        let anonymization = match cfg.anonymize.to_ascii_lowercase().as_str() {
+            "none" => Anonymization::None,
+            "truncate" => {
+                if cfg.ipv4_prefix > 32 {
+                    anyhow::bail!("dns.privacy.ipv4_prefix must be at most 32, got {}", cfg.ipv4_prefix);
+                }
+                if cfg.ipv6_prefix > 128 {
+                    anyhow::bail!("dns.privacy.ipv6_prefix must be at most 128, got {}", cfg.ipv6_prefix);
+                }
+                Anonymization::Truncate {
+                    ipv4_prefix: cfg.ipv4_prefix,
+                    ipv6_prefix: cfg.ipv6_prefix,
+                }
+            }
+            "hash" => {
+                let key = match cfg.hash_key {
+                    Some(key) if key.is_empty() => anyhow::bail!("dns.privacy.hash_key must not be empty"),
+                    Some(key) => key.into_bytes(),
+                    None => rand::random::<[u8; 32]>().to_vec(),
+                };
+                Anonymization::Hash(ring::hmac::Key::new(ring::hmac::HMAC_SHA256, &key))
+            }
+            other => anyhow::bail!("dns.privacy.anonymize must be truncate, hash or none, got {}", other),
+        };
+        if cfg.client_retention_mins == Some(0) {
+            anyhow::bail!("dns.privacy.client_retention_mins must be at least 1");
+        }
+        Ok(PrivacyOptions {
+            anonymization,
+            client_retention: cfg.client_retention_mins.map(|mins| Duration::from_secs(mins * 60)),
+        })
+    }
+}
+
+impl PrivacyOptions {
+    /// `address` as it may be recorded.
+    pub fn anonymize(&self, address: IpAddr) -> IpAddr {
+        match &self.anonymization {
+            Anonymization::None => address,
+            Anonymization::Truncate { ipv4_prefix, ipv6_prefix } => match address {
+                IpAddr::V4(v4) => {
+                    let mask = u32::MAX.checked_shl(32 - u32::from(*ipv4_prefix)).unwrap_or(0);
+                    IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
+                }
+                IpAddr::V6(v6) => {
+                    let mask = u128::MAX.checked_shl(128 - u32::from(*ipv6_prefix)).unwrap_or(0);
+                    IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
+                }
+            },
+            Anonymization::Hash(key) => match address {
+                IpAddr::V4(v4) => {
+                    let tag = ring::hmac::sign(key, &v4.octets());
+                    let mut octets = [0; 4];
+                    octets.copy_from_slice(&tag.as_ref()[..4]);
+                    IpAddr::V4(Ipv4Addr::from(octets))
+                }
+                IpAddr::V6(v6) => {
+                    let tag = ring::hmac::sign(key, &v6.octets());
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(&tag.as_ref()[..16]);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+            },
+        }
+    }
+
+    /// Whether addresses are recorded as they are.
+    pub fn keeps_addresses(&self) -> bool {
+        matches!(self.anonymization, Anonymization::None)
+    }
+}
+
+/// `address` as `privacy` allows it to be recorded; unaltered without privacy options.
+pub fn anonymize(privacy: Option<&PrivacyOptions>, address: IpAddr) -> IpAddr {
+    privacy.map_or(address, |privacy| privacy.anonymize(address))
+}
@@ -0,0 +1,116 @@
+//! Dropping root privileges.
+//!
+//! rdns may be started as root to bind port 53 and then switch to an unprivileged user
+//! and group, configured under `[privileges]`, once the DNS listeners are bound. The
+//! supplementary groups are dropped along with the switch, and as the process no longer
+//! runs as root, the kernel clears every capability of its threads; regaining root is
+//! checked to fail before serving on. Files written afterwards, such as the state and
+//! audit log, must be writable by that user.
+//!
+//! There are no users to switch to on Windows, where the service is run under a
+//! low-privileged account, such as `NT AUTHORITY\LocalService`, instead.
+
+#[cfg(unix)]
+use std::ffi::CString;
+
+use crate::settings::PrivilegeSettings;
+
+/// Config options for dropping privileges
+#[derive(Debug, Clone)]
+pub struct PrivilegeOptions {
+    /// User to switch to, by name or numeric ID.
+    pub user: String,
+    /// Group to switch to, by name or numeric ID; the user's primary group when unset.
+    pub group: Option<String>,
+}
+
+impl From<PrivilegeSettings> for PrivilegeOptions {
+    fn from(cfg: PrivilegeSettings) -> Self {
+        PrivilegeOptions {
+            user: cfg.user,
+            group: cfg.group.filter(|group| !group.is_empty()),
+        }
+    }
+}
+
+impl PrivilegeOptions {
+    /// Switches the process to the configured user and group.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the user or group doesn't exist, the process doesn't run as
+    /// root, or the switch fails.
+    #[cfg(unix)]
+    pub fn drop_privileges(&self) -> anyhow::Result<()> {
+        let (uid, primary_gid) = lookup_user(&self.user)?;
+        let gid = match &self.group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid,
+        };
+        // Safety: these calls take no pointers but to `gid`, which outlives them, and glibc
+        // applies the ID changes to every thread of the process
+        unsafe {
+            if libc::geteuid() != 0 {
+                anyhow::bail!("dropping privileges to {} requires starting as root", self.user);
+            }
+            if libc::setgroups(1, &gid) != 0 {
+                return Err(anyhow::anyhow!("failed to drop supplementary groups: {}", std::io::Error::last_os_error()));
+            }
+            if libc::setgid(gid) != 0 {
+                return Err(anyhow::anyhow!("failed to switch to group {}: {}", gid, std::io::Error::last_os_error()));
+            }
+            if libc::setuid(uid) != 0 {
+                return Err(anyhow::anyhow!("failed to switch to user {}: {}", uid, std::io::Error::last_os_error()));
+            }
+            if uid != 0 && libc::setuid(0) == 0 {
+                anyhow::bail!("root privileges could be regained after switching to user {}", uid);
+            }
+        }
+        println!("Dropped privileges to uid {} gid {}", uid, gid);
+        Ok(())
+    }
+
+    /// Fails, as there are no users to switch to outside of unix.
+    #[cfg(not(unix))]
+    pub fn drop_privileges(&self) -> anyhow::Result<()> {
+        anyhow::bail!("dropping privileges to {} is only supported on unix; run the service under a low-privileged account instead", self.user)
+    }
+}
+
+/// The user ID and primary group ID of `user`, a name or numeric ID.
+#[cfg(unix)]
+fn lookup_user(user: &str) -> anyhow::Result<(libc::uid_t, libc::gid_t)> {
+    let name = CString::new(user)?;
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut found = std::ptr::null_mut();
+    // Safety: every pointer is valid for the call, and `buffer` for its length
+    let result = unsafe { libc::getpwnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+    if result == 0 && !found.is_null() {
+        return Ok((entry.pw_uid, entry.pw_gid));
+    }
+    let uid = user.parse().map_err(|_| anyhow::anyhow!("no user named {}", user))?;
+    let mut found = std::ptr::null_mut();
+    // Safety: as above
+    let result = unsafe { libc::getpwuid_r(uid, &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+    match result == 0 && !found.is_null() {
+        true => Ok((uid, entry.pw_gid)),
+        // Numeric IDs without a passwd entry are used as they are, e.g. in containers
+        false => Ok((uid, uid)),
+    }
+}
+
+/// The group ID of `group`, a name or numeric ID.
+#[cfg(unix)]
+fn lookup_group(group: &str) -> anyhow::Result<libc::gid_t> {
+    let name = CString::new(group)?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut found = std::ptr::null_mut();
+    // Safety: every pointer is valid for the call, and `buffer` for its length
+    let result = unsafe { libc::getgrnam_r(name.as_ptr(), &mut entry, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+    if result == 0 && !found.is_null() {
+        return Ok(entry.gr_gid);
+    }
+    group.parse().map_err(|_| anyhow::anyhow!("no group named {}", group))
+}
@@ -0,0 +1,161 @@
+//! Probes of the server's own DNS listeners.
+//!
+//! The gRPC health service only tells that the control plane is up. With `[probe]`
+//! configured, the server also queries its own listeners for a canary name every
+//! `interval_secs`, over UDP and TCP and over DoT and DoH when they are served, the way
+//! `VerifyRecord` does (see `verify`), so that a listener that stopped answering, or a
+//! canary zone that stopped being served, is noticed. A probe succeeds when it is answered
+//! within `timeout_ms` with a record of `record_type`.
+//!
+//! Every probe is counted in `rdns_probes_total` by protocol and outcome: `ok`, `slow`
+//! when answered later than `latency_slo_ms`, the latency objective, or `failed`. The
+//! time answered probes took goes into `rdns_probe_duration_seconds`. A protocol whose
+//! probes fail `failures` times in a row is down: it is logged, `rdns_probe_up` is 0 for
+//! it, and the health service reports the server as not serving until the protocol's
+//! probes succeed again. Slow probes count against the objective, not the health.
+
+use futures_util::future::join_all;
+use hickory_proto::rr::{Name, RecordType};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::MissedTickBehavior;
+
+use crate::metrics::Metrics;
+use crate::settings::ProbeSettings;
+use crate::shutdown::Shutdown;
+use crate::verify::{Listeners, Protocol};
+
+/// Config options for probes
+#[derive(Debug, Clone)]
+pub struct ProbeOptions {
+    /// Canary name queried.
+    pub name: Name,
+    pub record_type: RecordType,
+    /// Time between rounds of probes.
+    pub interval: Duration,
+    /// How long a probe may take before it fails.
+    pub timeout: Duration,
+    /// Time within which probes should be answered.
+    pub latency_slo: Duration,
+    /// Failed probes in a row after which a protocol is down.
+    pub failures: u32,
+}
+
+impl TryFrom<ProbeSettings> for ProbeOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ProbeSettings) -> anyhow::Result<Self> {
+        let name = Name::from_str(&cfg.name).map_err(|e| anyhow::anyhow!("invalid probe.name {}: {}", cfg.name, e))?;
+        let record_type = RecordType::from_str(&cfg.record_type.to_ascii_uppercase())
+            .map_err(|_| anyhow::anyhow!("invalid probe.record_type {}", cfg.record_type))?;
+        if cfg.interval_secs == 0 || cfg.timeout_ms == 0 || cfg.failures == 0 {
+            anyhow::bail!("probe.interval_secs, probe.timeout_ms and probe.failures must be at least 1");
+        }
+        Ok(ProbeOptions {
+            name,
+            record_type,
+            interval: Duration::from_secs(cfg.interval_secs),
+            timeout: Duration::from_millis(cfg.timeout_ms),
+            latency_slo: Duration::from_millis(cfg.latency_slo_ms),
+            failures: cfg.failures,
+        })
+    }
+}
+
+/// Probes the listeners and reports whether they all answer.
+pub struct Prober {
+    listeners: Listeners,
+    options: ProbeOptions,
+    metrics: Arc<Metrics>,
+    healthy: watch::Sender<bool>,
+}
+
+impl Prober {
+    /// A prober of `listeners`, with the receiver of whether they all answer, true until
+    /// a protocol is down.
+    pub fn new(listeners: Listeners, options: ProbeOptions, metrics: Arc<Metrics>) -> (Self, watch::Receiver<bool>) {
+        let (healthy, receiver) = watch::channel(true);
+        let prober = Prober {
+            listeners,
+            options,
+            metrics,
+            healthy,
+        };
+        (prober, receiver)
+    }
+
+    /// Probes every protocol served each interval, from once `dns_ready` is true until
+    /// `shutdown` is requested.
+    pub async fn run(self, mut dns_ready: watch::Receiver<bool>, shutdown: Shutdown) {
+        if dns_ready.wait_for(|ready| *ready).await.is_err() {
+            return;
+        }
+        let protocols = self.listeners.protocols();
+        let mut failures = vec![0; protocols.len()];
+        for protocol in &protocols {
+            self.metrics.set_probe_up(&label(*protocol), true);
+        }
+        let mut interval = tokio::time::interval(self.options.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown.clone().requested() => return,
+            }
+            let results = join_all(protocols.iter().map(|protocol| self.probe(*protocol))).await;
+            for ((protocol, result), failed) in protocols.iter().zip(results).zip(&mut failures) {
+                let was_up = *failed < self.options.failures;
+                match result {
+                    Ok(()) => {
+                        if !was_up {
+                            println!("Probes of {} over {} are answered again", self.options.name, protocol);
+                        }
+                        *failed = 0;
+                    }
+                    Err(e) => {
+                        *failed += 1;
+                        if was_up && *failed >= self.options.failures {
+                            eprintln!("Probes of {} over {} failed {} times in a row, the last: {}", self.options.name, protocol, failed, e);
+                        }
+                    }
+                }
+                self.metrics.set_probe_up(&label(*protocol), *failed < self.options.failures);
+            }
+            let healthy = failures.iter().all(|failed| *failed < self.options.failures);
+            self.healthy.send_if_modified(|current| std::mem::replace(current, healthy) != healthy);
+        }
+    }
+
+    /// Queries the listener serving `protocol` for the canary name, recording the outcome.
+    async fn probe(&self, protocol: Protocol) -> anyhow::Result<()> {
+        let label = label(protocol);
+        let answer = self
+            .listeners
+            .query_within(&self.options.name, self.options.record_type, protocol, self.options.timeout)
+            .await;
+        let result = match &answer {
+            Ok(answer) if answer.holds(self.options.record_type, None) => Ok(()),
+            Ok(answer) => Err(anyhow::anyhow!(
+                "answered {} with no {} record",
+                answer.response.response_code(),
+                self.options.record_type
+            )),
+            Err(e) => Err(anyhow::anyhow!("{}", e)),
+        };
+        let outcome = match (&result, &answer) {
+            (Ok(()), Ok(answer)) if answer.rtt > self.options.latency_slo => "slow",
+            (Ok(()), _) => "ok",
+            (Err(_), _) => "failed",
+        };
+        crate::debug!("Probe of {} over {}: {}", self.options.name, protocol, outcome);
+        self.metrics.observe_probe(&label, outcome, answer.as_ref().ok().map(|answer| answer.rtt));
+        result
+    }
+}
+
+/// The metrics label of `protocol`, e.g. `udp`.
+fn label(protocol: Protocol) -> String {
+    protocol.to_string().to_ascii_lowercase()
+}
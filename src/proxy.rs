@@ -0,0 +1,342 @@
+//! PROXY protocol (v1 and v2) on the TCP and DNS-over-TLS listeners.
+//!
+//! Behind an L4 load balancer every TCP connection comes from the balancer, hiding the
+//! client. Balancers speaking the PROXY protocol announce the client address in a header
+//! before the connection's data. Connections from the trusted networks configured under
+//! `[dns.proxy_protocol]` must start with one, and their queries are handled as coming
+//! from the address it announces, so that ACLs, rate limits, logs and statistics see the
+//! real client; connections from anywhere else are served as they are. `LOCAL` headers,
+//! sent by balancers for their own health checks, keep the balancer's address.
+//!
+//! With the PROXY protocol configured, rdns accepts the TCP and DoT connections itself
+//! rather than through hickory-server, reading the header before the TLS handshake.
+//! Both must be done within five seconds, and at most `max_connections` connections are
+//! served at once; further ones wait in the listen backlog until one closes.
+
+use hickory_server::server::{Protocol, RequestHandler};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+use crate::hardening;
+use crate::settings::ProxyProtocolSettings;
+use crate::shutdown::Shutdown;
+use crate::views::ClientMatch;
+
+/// Signature starting a v2 header.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, including its CRLF.
+const V1_MAX_LEN: usize = 107;
+/// Longest v2 header after its fixed part; the TLVs balancers send take far less.
+const V2_MAX_LEN: usize = 1024;
+/// How long a peer may take to send its header and complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Config options for the PROXY protocol
+#[derive(Clone)]
+pub struct ProxyProtocolOptions {
+    /// Peers whose connections start with a PROXY header.
+    pub trusted: ClientMatch,
+    /// Connections served at once, per listener.
+    pub max_connections: usize,
+}
+
+impl TryFrom<ProxyProtocolSettings> for ProxyProtocolOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ProxyProtocolSettings) -> anyhow::Result<Self> {
+        if cfg.trusted.is_empty() {
+            anyhow::bail!("dns.proxy_protocol.trusted must list the load balancers' networks");
+        }
+        if cfg.max_connections == 0 {
+            anyhow::bail!("dns.proxy_protocol.max_connections must be at least 1");
+        }
+        Ok(ProxyProtocolOptions {
+            trusted: ClientMatch::parse(&cfg.trusted)?,
+            max_connections: cfg.max_connections,
+        })
+    }
+}
+
+/// Reads the PROXY header starting `stream`, returning the client address it announces,
+/// or `None` for `LOCAL` connections and those of unknown or non-IP clients.
+///
+/// Exactly the header is read, leaving the connection's data in `stream`.
+///
+/// # Errors
+///
+/// Returns an `InvalidData` error if the stream doesn't start with a valid header.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        return read_v2(stream).await;
+    }
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY header"));
+    }
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+/// Parses a v1 header line without its CRLF, e.g. `PROXY TCP4 192.0.2.1 198.51.100.1 56324 53`.
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unknown PROXY v1 protocol")),
+    }
+    let malformed = || invalid("malformed PROXY v1 addresses");
+    let [source, destination, source_port, destination_port] = fields.collect::<Vec<_>>()[..] else {
+        return Err(malformed());
+    };
+    let ip = source.parse::<IpAddr>().map_err(|_| malformed())?;
+    destination.parse::<IpAddr>().map_err(|_| malformed())?;
+    destination_port.parse::<u16>().map_err(|_| malformed())?;
+    let port = source_port.parse::<u16>().map_err(|_| malformed())?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+/// Reads the rest of a v2 header, after its signature.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut fixed = [0u8; 4];
+    stream.read_exact(&mut fixed).await?;
+    let [version_command, family, len @ ..] = fixed;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let len = u16::from_be_bytes(len) as usize;
+    if len > V2_MAX_LEN {
+        return Err(invalid("PROXY v2 header too long"));
+    }
+    let mut addresses = vec![0u8; len];
+    stream.read_exact(&mut addresses).await?;
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unknown PROXY v2 command")),
+    }
+    // The addresses, source first, are followed by the ports; TLVs after them are ignored
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        1 | 2 => Err(invalid("truncated PROXY v2 addresses")),
+        // Unspecified and Unix socket clients have no address to use
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Accepts DNS connections on `listener`, over TLS with `tls`, reading the PROXY header
+/// of those from trusted peers, and answers their queries with `handler`.
+///
+/// Connections are closed after `timeout` without a query, and at most `max_connections`
+/// of the options are open at once. Once `shutdown` is requested,
+/// no new connections are accepted and open ones are closed after their current query;
+/// the function returns once they all are.
+pub async fn run_tcp_listener<H: RequestHandler + Clone>(
+    handler: H,
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    options: ProxyProtocolOptions,
+    timeout: Duration,
+    shutdown: Shutdown,
+) -> io::Result<()> {
+    let permits = Arc::new(Semaphore::new(options.max_connections));
+    let mut connections = JoinSet::new();
+    loop {
+        let permit = tokio::select! {
+            permit = permits.clone().acquire_owned() => permit.expect("the semaphore is never closed"),
+            _ = shutdown.clone().requested() => break,
+        };
+        let (mut stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            // Reap finished connections so the set doesn't grow without bound
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            _ = shutdown.clone().requested() => break,
+        };
+        let handler = handler.clone();
+        let tls = tls.clone();
+        let trusted = options.trusted.contains(peer.ip());
+        let shutdown = shutdown.clone();
+        connections.spawn(async move {
+            let _permit = permit;
+            let mut src = peer;
+            if trusted {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_header(&mut stream)).await {
+                    Ok(Ok(client)) => src = client.unwrap_or(peer),
+                    Ok(Err(e)) => {
+                        eprintln!("Invalid PROXY header from {}: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        eprintln!("No PROXY header from {} in time", peer);
+                        return;
+                    }
+                }
+            }
+            match tls {
+                Some(acceptor) => {
+                    if let Ok(Ok(stream)) = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        serve(handler, stream, src, Protocol::Tls, timeout, shutdown).await;
+                    }
+                }
+                None => serve(handler, stream, src, Protocol::Tcp, timeout, shutdown).await,
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Answers the length-prefixed queries of one connection, as coming from `src`.
+async fn serve<H, S>(handler: H, mut stream: S, src: SocketAddr, protocol: Protocol, timeout: Duration, shutdown: Shutdown)
+where
+    H: RequestHandler,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut query = Vec::new();
+    loop {
+        let len = tokio::select! {
+            len = tokio::time::timeout(timeout, stream.read_u16()) => len,
+            _ = shutdown.clone().requested() => return,
+        };
+        let Ok(Ok(len)) = len else {
+            return;
+        };
+        query.resize(len as usize, 0);
+        if !matches!(tokio::time::timeout(timeout, stream.read_exact(&mut query)).await, Ok(Ok(_))) {
+            return;
+        }
+        // Messages left unanswered are skipped, as hickory-server's own connections do
+        let Some(answer) = hardening::answer(&handler, &query, src, protocol).await else {
+            continue;
+        };
+        let Ok(len) = u16::try_from(answer.len()) else {
+            return;
+        };
+        // Written at once, so that TLS sends the answer in one record
+        let mut framed = Vec::with_capacity(answer.len() + 2);
+        framed.extend_from_slice(&len.to_be_bytes());
+        framed.extend_from_slice(&answer);
+        if stream.write_all(&framed).await.is_err() || stream.flush().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the header starting `data`, and what is left of it.
+    async fn read(data: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = data;
+        let header = read_header(&mut stream).await;
+        (header, stream.to_vec())
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1_headers() {
+        let (client, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 53\r\n\x00\x1d").await;
+        assert_eq!(client.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"\x00\x1d");
+        let (client, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::53 56324 53\r\n").await;
+        assert_eq!(client.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        let (client, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(client.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn reads_v2_headers() {
+        // The addresses and ports, followed by a TLV that is skipped
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0, 53, 0x04, 0, 1, 0];
+        let mut data = v2(1, 0x11, &addresses);
+        data.extend(b"\x00\x1d");
+        let (client, rest) = read(&data).await;
+        assert_eq!(client.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"\x00\x1d");
+
+        let mut addresses = [0u8; 36];
+        addresses[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses[32..34].copy_from_slice(&56324u16.to_be_bytes());
+        let (client, _) = read(&v2(1, 0x21, &addresses)).await;
+        assert_eq!(client.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // LOCAL connections and Unix sockets keep the peer's address
+        assert_eq!(read(&v2(0, 0x00, &[])).await.0.unwrap(), None);
+        assert_eq!(read(&v2(1, 0x31, &[0; 216])).await.0.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_signatures() {
+        for data in [&b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"[..], b"\x00\x1d\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00", b"PROXY TCP5 192.0.2.1 198.51.100.1 1 53\r\n"] {
+            assert_eq!(read(data).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        // Versions other than 2 after the v2 signature
+        let mut data = v2(1, 0x11, &[0; 12]);
+        data[12] = 0x11;
+        assert_eq!(read(&data).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut data = v2(1, 0x11, &[0; 12]);
+        data[12] = 0x2f;
+        assert_eq!(read(&data).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_truncated_headers() {
+        for data in [&b"PROXY TCP4 192.0.2.1"[..], b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n", b"PROX", &V2_SIGNATURE[..]] {
+            assert!(read(data).await.0.is_err(), "{:?}", data);
+        }
+        let data = v2(1, 0x11, &[0; 12]);
+        assert_eq!(read(&data[..data.len() - 1]).await.0.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        // Addresses shorter than their family
+        assert_eq!(read(&v2(1, 0x11, &[0; 8])).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(read(&v2(1, 0x21, &[0; 20])).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_headers() {
+        let mut line = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 53".to_vec();
+        line.resize(200, b' ');
+        line.extend(b"\r\n");
+        assert_eq!(read(&line).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        // The length is checked before anything is read, so the rest needn't be sent
+        let mut data = v2(1, 0x11, &[]);
+        data[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert_eq!(read(&data).await.0.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(read(&v2(1, 0x11, &[0; V2_MAX_LEN])).await.0.is_ok());
+    }
+}
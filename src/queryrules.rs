@@ -0,0 +1,423 @@
+//! Query rules answering matched names with a fixed action.
+//!
+//! A query rule matches names exactly, by domain suffix, or by regular expression, and
+//! answers the queries for them without consulting the hosted zones: with NXDOMAIN, with
+//! a static answer, or with the answer of upstreams of its own. They keep devices from
+//! reaching telemetry and tracking endpoints, e.g. `suffix telemetry.example.com
+//! nxdomain`, or pin single names to local addresses or resolvers.
+//!
+//! - `exact` rules match their name alone, `suffix` rules their domain and every name
+//!   under it, and `regex` rules the names their pattern matches, in lower-cased and
+//!   fully qualified form like `metrics.example.com.`
+//! - `answer` rules answer with their records, given as type and value like `A 0.0.0.0`,
+//!   for the types they have records of, with their CNAME for any type, and with no
+//!   records for the other types
+//! - `forward` rules send the query to their upstreams, addresses with an optional port,
+//!   tried in order; their answers are neither cached nor validated
+//!
+//! Rules are tried in order, those added through the `QueryRules` service first, then
+//! those of `[[dns.query_rules]]` and of the `dns.query_rule_files`, and the first that
+//! matches wins, as the `rules` stage of the pipeline. Rule files hold a rule per line:
+//! the match type, the name or pattern, then the action and its records or upstreams,
+//! NXDOMAIN when left out; answer lines of the same name add to the records of one rule,
+//! and `#` starts a comment:
+//!
+//! ```text
+//! suffix telemetry.example.com
+//! exact  ads.example.net      answer A 0.0.0.0
+//! exact  ads.example.net      answer AAAA ::
+//! regex  ^metrics[0-9]*\.     forward 10.0.0.53 10.0.0.54:5353
+//! ```
+
+use hickory_proto::op::{Edns, Message, ResponseCode};
+use hickory_proto::rr::{LowerName, Name, Record, RecordType};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use crate::dns::{self, DnsState};
+use crate::error::RdnsError;
+use crate::forward;
+use crate::settings::QueryRuleSettings;
+
+/// TTL of the records of an answer rule that sets none.
+const DEFAULT_TTL: u32 = 300;
+/// Port of the upstreams of a forward rule that give none.
+const DNS_PORT: u16 = 53;
+
+/// A query rule, as configured or added through the control API and persisted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryRuleEntry {
+    /// Name, domain or regular expression the rule matches, as `match_type` says
+    pub pattern: String,
+    /// "exact", "suffix" or "regex"
+    pub match_type: String,
+    /// "nxdomain", "answer" or "forward"
+    pub action: String,
+    /// Records of an answer rule, as type and value like `A 0.0.0.0`, or upstreams of a
+    /// forward rule
+    #[serde(default)]
+    pub values: Vec<String>,
+    /// TTL of the records of an answer rule
+    #[serde(default)]
+    pub ttl: u32,
+}
+
+impl From<QueryRuleSettings> for QueryRuleEntry {
+    fn from(cfg: QueryRuleSettings) -> Self {
+        QueryRuleEntry {
+            pattern: cfg.pattern,
+            match_type: cfg.match_type,
+            action: cfg.action,
+            values: cfg.values,
+            ttl: cfg.ttl,
+        }
+    }
+}
+
+/// A query rule as listed through the control API.
+#[derive(Debug, Clone)]
+pub struct QueryRuleInfo {
+    pub entry: QueryRuleEntry,
+    /// Whether the rule comes from the config rather than `AddQueryRule`
+    pub configured: bool,
+}
+
+/// How a rule matches names.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchType {
+    Exact,
+    Suffix,
+    Regex,
+}
+
+impl FromStr for MatchType {
+    type Err = RdnsError;
+
+    fn from_str(match_type: &str) -> Result<Self, RdnsError> {
+        match match_type.to_ascii_lowercase().as_str() {
+            "exact" => Ok(MatchType::Exact),
+            "" | "suffix" => Ok(MatchType::Suffix),
+            "regex" => Ok(MatchType::Regex),
+            _ => Err(RdnsError::InvalidArgument(format!("unknown match type {}, expected exact, suffix or regex", match_type))),
+        }
+    }
+}
+
+impl fmt::Display for MatchType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MatchType::Exact => "exact",
+            MatchType::Suffix => "suffix",
+            MatchType::Regex => "regex",
+        })
+    }
+}
+
+/// How a rule matches names, with its name or pattern parsed.
+#[derive(Debug, Clone)]
+enum Matcher {
+    Exact(LowerName),
+    Suffix(LowerName),
+    Regex(Regex),
+}
+
+/// What a rule answers the queries it matches with.
+#[derive(Debug, Clone)]
+enum Action {
+    Nxdomain,
+    /// Records whose owner is replaced with the query name.
+    Answer(Vec<Record>),
+    Forward(Vec<SocketAddr>),
+}
+
+/// A query rule with its pattern and action parsed.
+#[derive(Debug, Clone)]
+pub struct QueryRule {
+    /// The entry in canonical form: the name of an exact or suffix rule lower-cased and
+    /// fully qualified, and the match type and action lower-cased
+    pub entry: QueryRuleEntry,
+    matcher: Matcher,
+    action: Action,
+}
+
+impl QueryRule {
+    pub fn new(mut entry: QueryRuleEntry) -> Result<Self, RdnsError> {
+        let match_type: MatchType = entry.match_type.parse()?;
+        let matcher = match match_type {
+            MatchType::Regex => Matcher::Regex(
+                Regex::new(&entry.pattern).map_err(|e| RdnsError::InvalidArgument(format!("invalid query rule pattern {}: {}", entry.pattern, e)))?,
+            ),
+            MatchType::Exact | MatchType::Suffix => {
+                let name = LowerName::new(&DnsState::parse_name(&entry.pattern)?);
+                entry.pattern = name.to_string();
+                match match_type {
+                    MatchType::Exact => Matcher::Exact(name),
+                    _ => Matcher::Suffix(name),
+                }
+            }
+        };
+        entry.match_type = match_type.to_string();
+        entry.action = entry.action.to_ascii_lowercase();
+        let action = match entry.action.as_str() {
+            "" | "nxdomain" => {
+                entry.action = "nxdomain".into();
+                Action::Nxdomain
+            }
+            "answer" => Action::Answer(parse_records(&entry)?),
+            "forward" => {
+                let upstreams = entry.values.iter().map(|upstream| parse_upstream(upstream)).collect::<Result<Vec<_>, _>>()?;
+                if upstreams.is_empty() {
+                    return Err(RdnsError::InvalidArgument(format!("query rule for {} has no upstreams", entry.pattern)));
+                }
+                Action::Forward(upstreams)
+            }
+            action => {
+                return Err(RdnsError::InvalidArgument(format!("unknown query rule action {}, expected nxdomain, answer or forward", action)));
+            }
+        };
+        Ok(QueryRule { entry, matcher, action })
+    }
+
+    /// Whether the rule matches `name`.
+    pub(crate) fn matches(&self, name: &LowerName) -> bool {
+        match &self.matcher {
+            Matcher::Exact(exact) => exact == name,
+            Matcher::Suffix(domain) => domain.zone_of(name),
+            Matcher::Regex(regex) => regex.is_match(&name.to_string()),
+        }
+    }
+
+    /// Whether the rule is for the same names as one for `match_type` and `pattern`.
+    fn is_for(&self, match_type: MatchType, pattern: &str) -> bool {
+        if self.entry.match_type != match_type.to_string() {
+            return false;
+        }
+        match match_type {
+            MatchType::Regex => self.entry.pattern == pattern,
+            // The name may be given in any case, with or without a final dot
+            _ => DnsState::parse_name(pattern).is_ok_and(|name| name.to_lowercase().to_string() == self.entry.pattern),
+        }
+    }
+}
+
+/// The records of an answer rule, checked by building them for a sample name.
+fn parse_records(entry: &QueryRuleEntry) -> Result<Vec<Record>, RdnsError> {
+    if entry.values.is_empty() {
+        return Err(RdnsError::InvalidArgument(format!("query rule for {} has no records to answer with", entry.pattern)));
+    }
+    let ttl = if entry.ttl > 0 { entry.ttl } else { DEFAULT_TTL };
+    let records = entry
+        .values
+        .iter()
+        .map(|value| {
+            let (record_type, value) = value.trim().split_once(char::is_whitespace).unwrap_or((value.trim(), ""));
+            DnsState::build_record("rule.invalid.".into(), record_type.to_string(), value.trim().to_string(), ttl)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(record) = records.iter().find(|record| matches!(record.record_type(), RecordType::SOA | RecordType::NS)) {
+        return Err(RdnsError::InvalidArgument(format!("query rules can't answer with {} records", record.record_type())));
+    }
+    Ok(records)
+}
+
+/// Parses an upstream address, with port 53 unless it gives one.
+fn parse_upstream(upstream: &str) -> Result<SocketAddr, RdnsError> {
+    upstream
+        .parse::<SocketAddr>()
+        .or_else(|_| upstream.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+        .map_err(|_| RdnsError::InvalidArgument(format!("invalid upstream {}, expected an address with an optional port", upstream)))
+}
+
+/// Parses the rules of a rule file, see the module docs. `source` names the file in
+/// errors.
+pub fn parse_file(content: &str, source: &str) -> Result<Vec<QueryRuleEntry>, RdnsError> {
+    let mut entries: Vec<QueryRuleEntry> = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let invalid = |message: String| RdnsError::InvalidArgument(format!("{}:{}: {}", source, number + 1, message));
+        let mut fields = line.split_whitespace();
+        let (Some(match_type), Some(pattern)) = (fields.next(), fields.next()) else {
+            return Err(invalid("expected a match type and a name or pattern".into()));
+        };
+        let match_type: MatchType = match_type.parse().map_err(|e: RdnsError| invalid(e.to_string()))?;
+        let action = fields.next().unwrap_or("nxdomain").to_ascii_lowercase();
+        let values: Vec<String> = match action.as_str() {
+            // An answer is a single record, whose value may hold spaces
+            "answer" => {
+                let record: Vec<&str> = fields.collect();
+                vec![record.join(" ")]
+            }
+            _ => fields.map(str::to_string).collect(),
+        };
+        let entry = QueryRuleEntry {
+            pattern: pattern.to_string(),
+            match_type: match_type.to_string(),
+            action,
+            values,
+            ttl: 0,
+        };
+        // Records of the same name make up one answer
+        if let Some(previous) = entries.last_mut() {
+            if entry.action == "answer" && previous.action == "answer" && previous.match_type == entry.match_type && previous.pattern == entry.pattern {
+                previous.values.extend(entry.values);
+                continue;
+            }
+        }
+        entries.push(entry);
+    }
+    // Checked once the records of each answer are gathered
+    for entry in &entries {
+        QueryRule::new(entry.clone()).map_err(|e| RdnsError::InvalidArgument(format!("{}: {}", source, e)))?;
+    }
+    Ok(entries)
+}
+
+/// The rules of the config: those of `rules`, then those of each of `files` in turn.
+///
+/// # Errors
+///
+/// Fails if a rule is invalid, or a file can't be read.
+pub fn load_configured(rules: &[QueryRuleSettings], files: &[String]) -> anyhow::Result<Vec<QueryRule>> {
+    let mut entries: Vec<QueryRuleEntry> = rules.iter().cloned().map(QueryRuleEntry::from).collect();
+    for file in files {
+        let content = std::fs::read_to_string(file).map_err(|e| anyhow::anyhow!("can't read the query rules of {}: {}", file, e))?;
+        entries.extend(parse_file(&content, file)?);
+    }
+    Ok(entries.into_iter().map(QueryRule::new).collect::<Result<_, _>>()?)
+}
+
+/// The configured query rules and those added through the control API.
+#[derive(Default)]
+pub struct QueryRules {
+    configured: RwLock<Vec<Arc<QueryRule>>>,
+    created: RwLock<Vec<Arc<QueryRule>>>,
+}
+
+impl QueryRules {
+    /// Replaces the rules of the config.
+    pub fn set_configured(&self, rules: Vec<QueryRule>) {
+        *self.configured.write().unwrap() = rules.into_iter().map(Arc::new).collect();
+    }
+
+    /// Adds rules through the control API, tried after those added before them, failing
+    /// with `Conflict` and adding none if one was already added for the same names.
+    pub fn insert(&self, rules: Vec<QueryRule>) -> Result<(), RdnsError> {
+        let mut created = self.created.write().unwrap();
+        for (i, rule) in rules.iter().enumerate() {
+            let match_type = rule.entry.match_type.parse()?;
+            let same = |existing: &QueryRule| existing.is_for(match_type, &rule.entry.pattern);
+            if created.iter().any(|existing| same(existing)) || rules[..i].iter().any(same) {
+                return Err(RdnsError::Conflict(format!("{} query rule for {} already exists", rule.entry.match_type, rule.entry.pattern)));
+            }
+        }
+        created.extend(rules.into_iter().map(Arc::new));
+        Ok(())
+    }
+
+    /// Replaces the rules added through the control API with `rules`.
+    pub fn replace(&self, rules: Vec<QueryRule>) -> Result<(), RdnsError> {
+        let previous = std::mem::take(&mut *self.created.write().unwrap());
+        let inserted = self.insert(rules);
+        if inserted.is_err() {
+            *self.created.write().unwrap() = previous;
+        }
+        inserted
+    }
+
+    /// Removes a rule added through the control API; those of the config can't be.
+    pub fn remove(&self, match_type: MatchType, pattern: &str) -> Result<(), RdnsError> {
+        let mut created = self.created.write().unwrap();
+        if let Some(index) = created.iter().position(|rule| rule.is_for(match_type, pattern)) {
+            created.remove(index);
+            return Ok(());
+        }
+        match self.configured.read().unwrap().iter().any(|rule| rule.is_for(match_type, pattern)) {
+            true => Err(RdnsError::InvalidArgument(format!("{} query rule for {} is configured", match_type, pattern))),
+            false => Err(RdnsError::RecordNotFound(format!("no {} query rule for {}", match_type, pattern))),
+        }
+    }
+
+    /// Drops the rules added through the control API.
+    pub fn clear(&self) {
+        self.created.write().unwrap().clear();
+    }
+
+    /// The rules added through the control API, in the order they are tried.
+    pub fn created(&self) -> Vec<QueryRuleEntry> {
+        self.created.read().unwrap().iter().map(|rule| rule.entry.clone()).collect()
+    }
+
+    /// Every rule, in the order they are tried.
+    pub fn list(&self) -> Vec<QueryRuleInfo> {
+        let info = |rule: &Arc<QueryRule>, configured| QueryRuleInfo {
+            entry: rule.entry.clone(),
+            configured,
+        };
+        let mut rules: Vec<QueryRuleInfo> = self.created.read().unwrap().iter().map(|rule| info(rule, false)).collect();
+        rules.extend(self.configured.read().unwrap().iter().map(|rule| info(rule, true)));
+        rules
+    }
+
+    /// The first rule matching `name`.
+    pub fn find(&self, name: &LowerName) -> Option<Arc<QueryRule>> {
+        let created = self.created.read().unwrap();
+        let configured = self.configured.read().unwrap();
+        created.iter().chain(configured.iter()).find(|rule| rule.matches(name)).cloned()
+    }
+}
+
+/// Answers `request` as `rule` says.
+pub async fn send_answer<R: ResponseHandler>(request: &Request, rule: &QueryRule, response_handle: R) -> ResponseInfo {
+    match &rule.action {
+        Action::Nxdomain => dns::send_error(request, ResponseCode::NXDomain, response_handle).await,
+        Action::Answer(records) => {
+            let query_type = request.query().query_type();
+            let cname = records.iter().any(|record| record.record_type() == RecordType::CNAME);
+            let name = request.query().original().name();
+            let records: Vec<Record> = records
+                .iter()
+                .filter(|record| record.record_type() == query_type || (cname && record.record_type() == RecordType::CNAME))
+                .map(|record| {
+                    let mut record = record.clone();
+                    record.set_name(name.clone());
+                    record
+                })
+                .collect();
+            dns::send_records(request, &records, None, response_handle).await
+        }
+        Action::Forward(upstreams) => match forward_query(request, upstreams).await {
+            Some(response) if response.response_code() == ResponseCode::NoError => {
+                dns::send_records(request, response.answers(), None, response_handle).await
+            }
+            Some(response) => dns::send_error(request, response.response_code(), response_handle).await,
+            None => dns::send_error(request, ResponseCode::ServFail, response_handle).await,
+        },
+    }
+}
+
+/// The answer of the first of `upstreams` that answers the query of `request`.
+async fn forward_query(request: &Request, upstreams: &[SocketAddr]) -> Option<Message> {
+    let mut query = Message::new();
+    query.set_id(rand::random());
+    query.set_recursion_desired(true);
+    query.add_query(request.query().original().clone());
+    let mut edns = Edns::new();
+    edns.set_max_payload(forward::MAX_PAYLOAD);
+    query.set_edns(edns);
+    for upstream in upstreams {
+        match tokio::time::timeout(std::time::Duration::from_secs(5), forward::exchange(*upstream, &query)).await {
+            Ok(Ok(response)) => return Some(response),
+            Ok(Err(e)) => eprintln!("Query rule upstream {} failed for {}: {}", upstream, Name::from(request.query().name()), e),
+            Err(_) => eprintln!("Query rule upstream {} timed out for {}", upstream, Name::from(request.query().name())),
+        }
+    }
+    None
+}
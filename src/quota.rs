@@ -0,0 +1,172 @@
+//! Quotas on the number of records.
+//!
+//! Records added through the APIs count against a quota for their zone and one for all
+//! zones together, so that a runaway automation loop can't grow the state without bound;
+//! additions beyond either are refused with `QuotaExceeded`. Zones may be given quotas of
+//! their own, others get the default zone quota. A hard cap on the memory the records of
+//! all zones take up, estimated from their wire size like the response cache does, keeps
+//! large records from getting around the counts. Records of zone transfers and generated
+//! PTR records aren't limited, but do count.
+
+use hickory_proto::rr::{LowerName, Name, Record};
+use hickory_proto::serialize::binary::BinEncodable;
+use std::collections::HashMap;
+use std::ops::Add;
+use std::str::FromStr;
+
+use crate::error::RdnsError;
+use crate::settings::QuotaSettings;
+
+/// Rough per-record overhead of the record set and bookkeeping, on top of its wire size.
+const RECORD_OVERHEAD: usize = 96;
+
+/// Config options for record quotas
+#[derive(Clone, Debug, Default)]
+pub struct QuotaOptions {
+    /// Records all zones together may hold, unlimited when unset.
+    pub max_records: Option<usize>,
+    /// Records a zone without a quota of its own may hold, unlimited when unset.
+    pub max_zone_records: Option<usize>,
+    /// Estimated memory all records together may take up, in bytes, unlimited when unset.
+    pub max_bytes: Option<usize>,
+    /// Quotas of particular zones, by origin.
+    pub zones: HashMap<LowerName, usize>,
+}
+
+impl TryFrom<QuotaSettings> for QuotaOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: QuotaSettings) -> anyhow::Result<Self> {
+        let zones = cfg
+            .zones
+            .into_iter()
+            .map(|zone| {
+                let mut origin = Name::from_str(&zone.zone)?;
+                origin.set_fqdn(true);
+                Ok((LowerName::new(&origin), zone.max_records))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(QuotaOptions {
+            max_records: cfg.max_records.filter(|&records| records > 0),
+            max_zone_records: cfg.max_zone_records,
+            max_bytes: cfg.max_memory_mb.filter(|&mb| mb > 0).map(|mb| mb * 1024 * 1024),
+            zones,
+        })
+    }
+}
+
+impl QuotaOptions {
+    /// The quota of the zone at `origin`, if it has one.
+    pub fn zone_limit(&self, origin: &LowerName) -> Option<usize> {
+        self.zones.get(origin).copied().or(self.max_zone_records)
+    }
+
+    /// Counts `added` more records in the zone at `origin` into `usage`, failing if that
+    /// would exceed the zone's quota, the total one or the memory cap.
+    pub fn admit(&self, usage: &mut RecordUsage, origin: &LowerName, added: Usage) -> Result<(), RdnsError> {
+        let zone = usage.zone(origin);
+        if let Some(limit) = self.zone_limit(origin) {
+            if zone.records + added.records > limit {
+                return Err(RdnsError::QuotaExceeded(format!(
+                    "zone {} holds {} of the {} records it may hold",
+                    origin, zone.records, limit
+                )));
+            }
+        }
+        if let Some(limit) = self.max_records {
+            if usage.total.records + added.records > limit {
+                return Err(RdnsError::QuotaExceeded(format!(
+                    "the zones hold {} of the {} records they may hold together",
+                    usage.total.records, limit
+                )));
+            }
+        }
+        if let Some(limit) = self.max_bytes {
+            if usage.total.bytes + added.bytes > limit {
+                return Err(RdnsError::QuotaExceeded(format!(
+                    "the records take an estimated {} KiB of the {} KiB they may take in memory",
+                    usage.total.bytes / 1024,
+                    limit / 1024
+                )));
+            }
+        }
+        usage.insert(origin.clone(), zone + added);
+        Ok(())
+    }
+}
+
+/// How many records there are, and an estimate of the memory they take up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub records: usize,
+    /// Estimated bytes, see `record_size`.
+    pub bytes: usize,
+}
+
+impl Usage {
+    /// The usage of `records`.
+    pub fn of<'a>(records: impl IntoIterator<Item = &'a Record>) -> Self {
+        records.into_iter().fold(Usage::default(), |usage, record| usage + Usage { records: 1, bytes: record_size(record) })
+    }
+
+    /// Whether this is more than `other` in records or bytes.
+    pub fn exceeds(&self, other: Usage) -> bool {
+        self.records > other.records || self.bytes > other.bytes
+    }
+}
+
+impl Add for Usage {
+    type Output = Usage;
+
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            records: self.records + other.records,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+}
+
+/// Estimated memory a hosted record takes up: its wire size plus the bookkeeping of the
+/// record set holding it.
+pub fn record_size(record: &Record) -> usize {
+    RECORD_OVERHEAD + record.to_bytes().map_or(0, |bytes| bytes.len())
+}
+
+/// Record counts and sizes of the hosted zones, as quotas are checked against them.
+#[derive(Clone, Debug, Default)]
+pub struct RecordUsage {
+    total: Usage,
+    zones: HashMap<LowerName, Usage>,
+}
+
+impl RecordUsage {
+    /// Records the zone at `origin` holds `usage`.
+    pub fn insert(&mut self, origin: LowerName, usage: Usage) {
+        let previous = self.zones.insert(origin, usage).unwrap_or_default();
+        self.total = Usage {
+            records: self.total.records + usage.records - previous.records,
+            bytes: self.total.bytes + usage.bytes - previous.bytes,
+        };
+    }
+
+    /// Forgets the zone at `origin`, which is no longer hosted.
+    pub fn remove(&mut self, origin: &LowerName) {
+        self.insert(origin.clone(), Usage::default());
+        self.zones.remove(origin);
+    }
+
+    /// The usage of the zone at `origin`.
+    pub fn zone(&self, origin: &LowerName) -> Usage {
+        self.zones.get(origin).copied().unwrap_or_default()
+    }
+
+    /// The usage of every zone, by origin.
+    pub fn zones(&self) -> impl Iterator<Item = (&LowerName, Usage)> {
+        self.zones.iter().map(|(origin, usage)| (origin, *usage))
+    }
+
+    /// The usage of all zones together.
+    pub fn total(&self) -> Usage {
+        self.total
+    }
+}
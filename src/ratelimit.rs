@@ -0,0 +1,215 @@
+//! Response rate limiting (RRL) of UDP clients.
+//!
+//! Spoofed UDP queries make a DNS server reflect and amplify traffic towards the forged
+//! source, so responses over UDP are limited per client network: each IPv4 /24 or IPv6
+//! /56 (by default) earns `responses_per_second` credits a second, and a response costs
+//! one. A network out of credit has its responses dropped, except every `slip`th one,
+//! which is sent empty with the TC bit set so a genuine client can retry over TCP, which
+//! can't be spoofed. Credit can go as far below zero as a full window's worth, so a
+//! network that floods keeps being limited for up to a window after it slows down.
+
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::settings::RateLimitSettings;
+
+/// Client networks tracked at most: when full, idle ones are forgotten, and if none are,
+/// the least recently seen tenth.
+const MAX_TRACKED: usize = 65_536;
+
+/// Config options for response rate limiting
+#[derive(Clone)]
+pub struct RateLimitOptions {
+    pub responses_per_second: u32,
+    pub window: Duration,
+    /// Every how many limited responses is sent truncated; none are when 0.
+    pub slip: u32,
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+}
+
+impl TryFrom<RateLimitSettings> for RateLimitOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: RateLimitSettings) -> anyhow::Result<Self> {
+        if cfg.responses_per_second == 0 {
+            anyhow::bail!("rate_limit.responses_per_second must be at least 1");
+        }
+        if cfg.window_secs == 0 {
+            anyhow::bail!("rate_limit.window_secs must be at least 1");
+        }
+        if cfg.ipv4_prefix_len > 32 || cfg.ipv6_prefix_len > 128 {
+            anyhow::bail!("rate_limit prefix lengths must be at most 32 for IPv4 and 128 for IPv6");
+        }
+        Ok(RateLimitOptions {
+            responses_per_second: cfg.responses_per_second,
+            window: Duration::from_secs(cfg.window_secs),
+            slip: cfg.slip,
+            ipv4_prefix_len: cfg.ipv4_prefix_len,
+            ipv6_prefix_len: cfg.ipv6_prefix_len,
+        })
+    }
+}
+
+/// What to do with the response to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// Send it as usual.
+    Send,
+    /// Send an empty, truncated response instead.
+    Slip,
+    /// Send nothing.
+    Drop,
+}
+
+/// Credit of one client network.
+struct Bucket {
+    credit: f64,
+    updated: Instant,
+    /// Responses limited since the network was last within its rate.
+    limited: u32,
+}
+
+/// Tracks the responses sent to each client network.
+pub struct RateLimiter {
+    options: RateLimitOptions,
+    buckets: Mutex<HashMap<IpNet, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(options: RateLimitOptions) -> Self {
+        Self {
+            options,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The network `address` is accounted to.
+    fn network(&self, address: IpAddr) -> IpNet {
+        // IPv4 clients of dual-stack sockets arrive as mapped IPv6 addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            v4 => v4,
+        };
+        let prefix_len = match address {
+            IpAddr::V4(_) => self.options.ipv4_prefix_len,
+            IpAddr::V6(_) => self.options.ipv6_prefix_len,
+        };
+        // The prefix lengths are validated with the options
+        IpNet::new(address, prefix_len).map(|network| network.trunc()).unwrap_or(IpNet::from(address))
+    }
+
+    /// Charges a response to the client at `address` and decides how it is sent.
+    pub fn check(&self, address: IpAddr) -> Verdict {
+        let network = self.network(address);
+        let rate = f64::from(self.options.responses_per_second);
+        let floor = -rate * self.options.window.as_secs_f64();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(&network) {
+            evict(&mut buckets, now, self.options.window);
+        }
+        let bucket = buckets.entry(network).or_insert(Bucket {
+            credit: rate,
+            updated: now,
+            limited: 0,
+        });
+        let earned = now.duration_since(bucket.updated).as_secs_f64() * rate;
+        bucket.credit = ((bucket.credit + earned).min(rate) - 1.0).max(floor);
+        bucket.updated = now;
+        if bucket.credit >= 0.0 {
+            bucket.limited = 0;
+            return Verdict::Send;
+        }
+        bucket.limited = bucket.limited.wrapping_add(1);
+        match self.options.slip {
+            0 => Verdict::Drop,
+            slip if bucket.limited.is_multiple_of(slip) => Verdict::Slip,
+            _ => Verdict::Drop,
+        }
+    }
+}
+
+/// Makes room in `buckets`: drops the networks idle for a window, as they are back to
+/// full credit anyway, or else the least recently seen tenth, so the scan is repeated
+/// at most once every tenth of the capacity.
+fn evict(buckets: &mut HashMap<IpNet, Bucket>, now: Instant, window: Duration) {
+    buckets.retain(|_, bucket| now.duration_since(bucket.updated) < window);
+    if buckets.len() <= MAX_TRACKED * 9 / 10 {
+        return;
+    }
+    let mut times: Vec<Instant> = buckets.values().map(|bucket| bucket.updated).collect();
+    let count = buckets.len() - MAX_TRACKED * 9 / 10;
+    let (_, cutoff, _) = times.select_nth_unstable(count - 1);
+    let cutoff = *cutoff;
+    buckets.retain(|_, bucket| bucket.updated > cutoff);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(slip: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitOptions {
+            responses_per_second: 5,
+            window: Duration::from_secs(15),
+            slip,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+        })
+    }
+
+    #[test]
+    fn limits_networks_over_their_rate() {
+        let limiter = limiter(2);
+        let client = IpAddr::from([192, 0, 2, 1]);
+        for _ in 0..5 {
+            assert_eq!(limiter.check(client), Verdict::Send);
+        }
+        assert_eq!(limiter.check(client), Verdict::Drop);
+        assert_eq!(limiter.check(client), Verdict::Slip);
+        // The rest of the /24 shares the credit, other networks don't
+        assert_eq!(limiter.check(IpAddr::from([192, 0, 2, 200])), Verdict::Drop);
+        assert_eq!(limiter.check(IpAddr::from([192, 0, 3, 1])), Verdict::Send);
+        // As do IPv4 clients of dual-stack sockets
+        assert_eq!(limiter.check("::ffff:192.0.2.9".parse().unwrap()), Verdict::Slip);
+    }
+
+    #[test]
+    fn never_slips_when_slip_is_zero() {
+        let limiter = limiter(0);
+        let client = IpAddr::from([198, 51, 100, 1]);
+        let verdicts: Vec<Verdict> = (0..10).map(|_| limiter.check(client)).collect();
+        assert_eq!(verdicts.iter().filter(|verdict| **verdict == Verdict::Send).count(), 5);
+        assert!(verdicts[5..].iter().all(|verdict| *verdict == Verdict::Drop));
+    }
+
+    #[test]
+    fn tracks_a_bounded_number_of_networks() {
+        let limiter = limiter(2);
+        for index in 0..MAX_TRACKED as u32 + 10 {
+            limiter.check(IpAddr::from((index << 8).to_be_bytes()));
+        }
+        let tracked = limiter.buckets.lock().unwrap().len();
+        assert!(tracked <= MAX_TRACKED, "{} networks tracked", tracked);
+        assert!(tracked >= MAX_TRACKED * 9 / 10, "{} networks tracked", tracked);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let settings = |responses_per_second, window_secs, ipv4_prefix_len| RateLimitSettings {
+            responses_per_second,
+            window_secs,
+            ipv4_prefix_len,
+            ..RateLimitSettings::default()
+        };
+        assert!(RateLimitOptions::try_from(settings(5, 15, 24)).is_ok());
+        assert!(RateLimitOptions::try_from(settings(0, 15, 24)).is_err());
+        assert!(RateLimitOptions::try_from(settings(5, 0, 24)).is_err());
+        assert!(RateLimitOptions::try_from(settings(5, 15, 33)).is_err());
+    }
+}
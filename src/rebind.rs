@@ -0,0 +1,126 @@
+//! DNS rebinding protection of forwarded answers.
+//!
+//! A web page can have the browser resolve a name of its own domain to an address of the
+//! client's network, and then reach the services there as if they were of the page's
+//! origin. With `[dns.forward.rebind]`, the upstreams' answers are screened for private
+//! (RFC 1918, RFC 4193), shared (RFC 6598), loopback, link-local and unspecified
+//! addresses: their A and AAAA records are stripped, leaving the other records or a
+//! NODATA answer, or with `action = "refuse"` the query is refused. Names under
+//! `allowed_domains`, such as those of an internal domain resolved upstream, may be
+//! answered with any address. Screened answers are cached as they are passed on.
+//!
+//! The hosted zones and overrides are answered locally, and aren't screened.
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{LowerName, Name, RData, Record};
+use hickory_server::authority::LookupError;
+use hickory_server::resolver::lookup::Lookup;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::cache::{Answer, Negative};
+use crate::settings::RebindSettings;
+
+/// What is done with answers holding private addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebindAction {
+    /// Their private addresses are left out.
+    Strip,
+    /// The query is refused.
+    Refuse,
+}
+
+impl FromStr for RebindAction {
+    type Err = anyhow::Error;
+
+    fn from_str(action: &str) -> anyhow::Result<Self> {
+        match action.to_ascii_lowercase().as_str() {
+            "strip" => Ok(RebindAction::Strip),
+            "refuse" => Ok(RebindAction::Refuse),
+            _ => anyhow::bail!("unknown dns.forward.rebind.action {}, expected strip or refuse", action),
+        }
+    }
+}
+
+/// Config options for DNS rebinding protection
+#[derive(Debug, Clone)]
+pub struct RebindOptions {
+    pub action: RebindAction,
+    /// Domains whose names, their own included, may be answered with private addresses
+    pub allowed_domains: Vec<LowerName>,
+}
+
+impl TryFrom<RebindSettings> for RebindOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: RebindSettings) -> anyhow::Result<Self> {
+        let allowed_domains = cfg
+            .allowed_domains
+            .iter()
+            .map(|domain| {
+                let mut name = Name::from_utf8(domain).map_err(|e| anyhow::anyhow!("invalid dns.forward.rebind.allowed_domains domain {}: {}", domain, e))?;
+                name.set_fqdn(true);
+                Ok(LowerName::new(&name))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(RebindOptions {
+            action: cfg.action.parse()?,
+            allowed_domains,
+        })
+    }
+}
+
+impl RebindOptions {
+    /// `answer` to the query for `name` as it may be passed on, or the error the query is
+    /// refused with.
+    pub fn screen(&self, name: &LowerName, answer: Answer) -> Result<Answer, LookupError> {
+        let Answer::Records(lookup) = &answer else {
+            return Ok(answer);
+        };
+        if !lookup.records().iter().any(is_private_record) || self.allowed_domains.iter().any(|domain| domain.zone_of(name)) {
+            return Ok(answer);
+        }
+        crate::debug!("Caught private addresses in the upstream answer for {}", name);
+        if self.action == RebindAction::Refuse {
+            return Err(LookupError::from(ResponseCode::Refused));
+        }
+        let records: Vec<Record> = lookup.records().iter().filter(|record| !is_private_record(record)).cloned().collect();
+        if records.is_empty() {
+            return Ok(Answer::Negative(Negative::NoData, None));
+        }
+        Ok(Answer::Records(Lookup::new_with_deadline(lookup.query().clone(), records.into(), lookup.valid_until())))
+    }
+}
+
+/// Whether `record` is an A or AAAA record of a private address.
+fn is_private_record(record: &Record) -> bool {
+    match record.data() {
+        Some(RData::A(a)) => is_private(IpAddr::V4(a.0)),
+        Some(RData::AAAA(aaaa)) => is_private(IpAddr::V6(aaaa.0)),
+        _ => false,
+    }
+}
+
+/// Whether `ip` is an address of the client's own networks rather than of the internet.
+pub fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_private_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_private_v4(v4),
+            None => is_private_v6(v6),
+        },
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    // 100.64.0.0/10 is shared by carrier-grade NATs (RFC 6598), and 0.0.0.0/8 reaches
+    // the host itself on some systems
+    ip.is_private() || ip.is_loopback() || ip.is_link_local() || first == 0 || (first == 100 && second & 0xc0 == 64)
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    // fc00::/7 holds the unique local addresses, fe80::/10 the link-local ones
+    ip.is_loopback() || ip.is_unspecified() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+}
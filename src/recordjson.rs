@@ -0,0 +1,92 @@
+//! Zone contents as JSON.
+//!
+//! `ExportRecords` writes the records of a zone, or of one name in it, as a JSON document
+//! of a stable schema, so that external tooling can diff zone contents and keep them under
+//! version control:
+//!
+//! ```json
+//! {
+//!   "schema": "rdns.records/v1",
+//!   "origin": "example.com.",
+//!   "serial": 2026101501,
+//!   "exported_at": "2026-10-15T05:06:14Z",
+//!   "records": [
+//!     { "name": "www.example.com.", "record_type": "A", "value": "192.0.2.1", "ttl": 300,
+//!       "metadata": { "owner": "web", "tags": { "env": "prod" } } }
+//!   ]
+//! }
+//! ```
+//!
+//! Records are those of the zone file, in their IDNA form and with their values in zone
+//! file syntax, sorted by name, type and value so that exports of an unchanged zone only
+//! differ in `exported_at`. The SOA record is left out, its serial being in the envelope,
+//! as are the DNSSEC records generated for signed zones; records without metadata have no
+//! `metadata`. Fields may be added to the schema, but not renamed or removed, without a
+//! new schema version.
+//!
+//! `ImportProviderRecords` takes a document back in its `JSON` format, importing the
+//! records like `ImportRecords`.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::SystemTime;
+
+use crate::dns::RecordEntry;
+use crate::error::RdnsError;
+
+/// Version of the schema documents are written in, and the only one read.
+pub const SCHEMA: &str = "rdns.records/v1";
+
+/// An export of the records of a zone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordExport {
+    pub schema: String,
+    pub origin: String,
+    /// SOA serial of the zone when it was exported
+    pub serial: u32,
+    #[serde(serialize_with = "to_rfc3339", deserialize_with = "from_rfc3339")]
+    pub exported_at: SystemTime,
+    pub records: Vec<RecordEntry>,
+}
+
+impl RecordExport {
+    /// An export of `records` of the zone at `origin`, taken now, with the records sorted.
+    pub fn new(origin: String, serial: u32, mut records: Vec<RecordEntry>) -> Self {
+        records.sort_by(|a, b| (&a.name, &a.record_type, &a.value).cmp(&(&b.name, &b.record_type, &b.value)));
+        RecordExport {
+            schema: SCHEMA.to_string(),
+            origin,
+            serial,
+            exported_at: SystemTime::now(),
+            records,
+        }
+    }
+
+    /// The document of the export, pretty-printed so that it diffs line by line.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("record exports serialize")
+    }
+
+    /// Parses a document written by `to_json`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `content` isn't a document of the schema `SCHEMA`.
+    pub fn parse(content: &str) -> Result<Self, RdnsError> {
+        let export: RecordExport =
+            serde_json::from_str(content).map_err(|e| RdnsError::InvalidArgument(format!("invalid JSON export: {}", e)))?;
+        if export.schema != SCHEMA {
+            return Err(RdnsError::InvalidArgument(format!("unsupported schema {}, expected {}", export.schema, SCHEMA)));
+        }
+        Ok(export)
+    }
+}
+
+fn to_rfc3339<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&prost_types::Timestamp::from(*time))
+}
+
+fn from_rfc3339<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    let timestamp: prost_types::Timestamp = text.parse().map_err(serde::de::Error::custom)?;
+    SystemTime::try_from(timestamp).map_err(serde::de::Error::custom)
+}
@@ -0,0 +1,485 @@
+//! Live configuration reload.
+//!
+//! On SIGHUP or a `ReloadConfig` call, Config.toml is read again and compared with the
+//! settings in effect. Changes to the hosted zones, dynamic update policy, transfer
+//! secondaries, policies and IXFR journal size, forwarding, Client Subnet handling,
+//! identity answers, answer ordering, CNAME chain depth, additional records, ANY
+//! responses, address family policies, the query pipeline, access lists, TTL bounds, zone
+//! templates, tenants, rewrite rules, query rules, TSIG keys, response policy zones,
+//! negative answer policies, DNS64, scripts, client devices, dnstap output, the log level
+//! and API tokens are applied immediately, and policy zone, script and query rule files
+//! are read again even when unchanged; the rest (listeners, the control API limits, the
+//! UDP payload size, TLS, storage, the audit log, the external-dns webhook, change
+//! notifications, the event export, ACME challenges and certificates, DNSSEC, the catalog
+//! zone, automatic SOA records, zone history, the zone directory, secondary zones, health
+//! check defaults, GeoDNS, views, rate limiting, DNS cookies, EDNS settings, blocklists,
+//! Docker container, DHCP lease and hosts file records, the mDNS responder, query
+//! statistics, client privacy, tracing, self-probes, the drain timeout, daemon mode) is
+//! only read at startup, so those changes are reported as requiring a restart and
+//! otherwise left alone. So is the cluster role, and on a follower the zones come from
+//! the leader instead.
+//!
+//! The `Reload` call of the Admin service also reads the configured zone files again.
+
+use std::sync::Arc;
+use std::time::SystemTime;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex, RwLock};
+
+use crate::acl::AclOptions;
+use crate::any::AnyResponse;
+use crate::dns64::Dns64Options;
+use crate::ecs::EcsOptions;
+use crate::family::AddressFamily;
+use crate::identity::IdentityOptions;
+use crate::negative::NegativePolicy;
+use crate::roundrobin::AnswerOrderOptions;
+use crate::pipeline::Pipeline;
+use crate::quota::QuotaOptions;
+use crate::auth::{AuthOptions, Authenticator};
+use crate::clientid::{self, ClientOptions};
+use crate::dns::{DnsState, HandlerOptions, TtlPolicy};
+use crate::dnstap::{Dnstap, DnstapOptions};
+use crate::error::RdnsError;
+use crate::forward::ForwardOptions;
+use crate::logging::{self, LogLevel};
+use crate::queryrules;
+use crate::rewrite::{RewriteRule, RewriteRuleEntry};
+use crate::rpz::{self, RpzOptions};
+use crate::script::Script;
+use crate::settings::Settings;
+use crate::template;
+use crate::transfer::TransferOptions;
+use crate::tenant::Tenant;
+use crate::tsig;
+use crate::zonefile::ZoneFile;
+use crate::update::UpdateOptions;
+
+/// Outcome of a reload, naming the settings that changed.
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+    /// Settings now in effect.
+    pub applied: Vec<String>,
+    /// Settings that changed but only take effect after a restart.
+    pub restart_required: Vec<String>,
+}
+
+impl ReloadReport {
+    fn restart_if_changed<T: PartialEq>(&mut self, name: &str, current: &T, new: &T) {
+        if current != new {
+            self.restart_required.push(name.to_string());
+        }
+    }
+
+    /// One-line description of the reload, for logs and RPC responses.
+    pub fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.applied.is_empty() {
+            parts.push(format!("applied {}", self.applied.join(", ")));
+        }
+        if !self.restart_required.is_empty() {
+            parts.push(format!("restart required for {}", self.restart_required.join(", ")));
+        }
+        if parts.is_empty() {
+            return "No configuration changes".to_string();
+        }
+        format!("Configuration reloaded: {}", parts.join("; "))
+    }
+}
+
+/// Re-reads the config and applies what can be changed while running.
+pub struct Reloader {
+    /// Settings in effect, updated as changes are applied.
+    current: Mutex<Settings>,
+    /// When the config was last reloaded, if it has been.
+    reloaded_at: std::sync::Mutex<Option<SystemTime>>,
+    state: Arc<RwLock<DnsState>>,
+    handler_options: watch::Sender<Arc<HandlerOptions>>,
+    auth: Arc<Authenticator>,
+}
+
+impl Reloader {
+    /// Constructs a `Reloader` for a server started with `settings`.
+    pub fn new(
+        settings: Settings,
+        state: Arc<RwLock<DnsState>>,
+        handler_options: watch::Sender<Arc<HandlerOptions>>,
+        auth: Arc<Authenticator>,
+    ) -> Self {
+        Self {
+            current: Mutex::new(settings),
+            reloaded_at: std::sync::Mutex::new(None),
+            state,
+            handler_options,
+            auth,
+        }
+    }
+
+    /// Reloads Config.toml, applying the changes that can be applied live.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the config can't be read or a changed section is invalid, in
+    /// which case nothing is applied.
+    pub async fn reload(&self) -> anyhow::Result<ReloadReport> {
+        let new = Settings::load()?;
+        let mut current = self.current.lock().await;
+        let mut report = ReloadReport::default();
+
+        report.restart_if_changed("dns.listen_addrs", &current.dns.listen_addrs, &new.dns.listen_addrs);
+        report.restart_if_changed("dns.tcp_timeout_secs", &current.dns.tcp_timeout_secs, &new.dns.tcp_timeout_secs);
+        report.restart_if_changed("dns.udp_sockets", &current.dns.udp_sockets, &new.dns.udp_sockets);
+        report.restart_if_changed("dns.socket", &current.dns.socket, &new.dns.socket);
+        report.restart_if_changed("dns.max_udp_payload", &current.dns.max_udp_payload, &new.dns.max_udp_payload);
+        report.restart_if_changed("dns.zone_files", &current.dns.zone_files, &new.dns.zone_files);
+        report.restart_if_changed("dns.zone_dir", &current.dns.zone_dir, &new.dns.zone_dir);
+        report.restart_if_changed("dns.secondary_zones", &current.dns.secondary_zones, &new.dns.secondary_zones);
+        report.restart_if_changed("dns.tls", &current.dns.tls, &new.dns.tls);
+        report.restart_if_changed("dns.https", &current.dns.https, &new.dns.https);
+        report.restart_if_changed("dns.dnssec", &current.dns.dnssec, &new.dns.dnssec);
+        report.restart_if_changed("dns.catalog_zone", &current.dns.catalog_zone, &new.dns.catalog_zone);
+        report.restart_if_changed("dns.soa", &current.dns.soa, &new.dns.soa);
+        report.restart_if_changed("dns.history", &current.dns.history, &new.dns.history);
+        report.restart_if_changed("dns.health", &current.dns.health, &new.dns.health);
+        report.restart_if_changed("dns.geo", &current.dns.geo, &new.dns.geo);
+        report.restart_if_changed("dns.blocklist", &current.dns.blocklist, &new.dns.blocklist);
+        report.restart_if_changed("dns.rate_limit", &current.dns.rate_limit, &new.dns.rate_limit);
+        report.restart_if_changed("dns.cookies", &current.dns.cookies, &new.dns.cookies);
+        report.restart_if_changed("dns.edns", &current.dns.edns, &new.dns.edns);
+        report.restart_if_changed("dns.views", &current.dns.views, &new.dns.views);
+        report.restart_if_changed("dns.docker", &current.dns.docker, &new.dns.docker);
+        report.restart_if_changed("dns.dhcp", &current.dns.dhcp, &new.dns.dhcp);
+        report.restart_if_changed("dns.hosts", &current.dns.hosts, &new.dns.hosts);
+        report.restart_if_changed("dns.mdns", &current.dns.mdns, &new.dns.mdns);
+        report.restart_if_changed("dns.stats", &current.dns.stats, &new.dns.stats);
+        report.restart_if_changed("dns.privacy", &current.dns.privacy, &new.dns.privacy);
+        report.restart_if_changed("dns.proxy_protocol", &current.dns.proxy_protocol, &new.dns.proxy_protocol);
+        report.restart_if_changed("grpc.listen_addr", &current.grpc.listen_addr, &new.grpc.listen_addr);
+        report.restart_if_changed("grpc.unix_socket_mode", &current.grpc.unix_socket_mode, &new.grpc.unix_socket_mode);
+        report.restart_if_changed("grpc.tls", &current.grpc.tls, &new.grpc.tls);
+        report.restart_if_changed(
+            "grpc.legacy_error_responses",
+            &current.grpc.legacy_error_responses,
+            &new.grpc.legacy_error_responses,
+        );
+        report.restart_if_changed("grpc.limits", &current.grpc.limits, &new.grpc.limits);
+        report.restart_if_changed("storage", &current.storage, &new.storage);
+        report.restart_if_changed("metrics", &current.metrics, &new.metrics);
+        report.restart_if_changed("probe", &current.probe, &new.probe);
+        report.restart_if_changed("rest", &current.rest, &new.rest);
+        report.restart_if_changed("audit", &current.audit, &new.audit);
+        report.restart_if_changed("cluster", &current.cluster, &new.cluster);
+        report.restart_if_changed("webhook", &current.webhook, &new.webhook);
+        report.restart_if_changed("notifications", &current.notifications, &new.notifications);
+        report.restart_if_changed("events", &current.events, &new.events);
+        report.restart_if_changed("acme", &current.acme, &new.acme);
+        report.restart_if_changed("telemetry", &current.telemetry, &new.telemetry);
+        report.restart_if_changed("shutdown", &current.shutdown, &new.shutdown);
+        report.restart_if_changed("runtime", &current.runtime, &new.runtime);
+        report.restart_if_changed("privileges", &current.privileges, &new.privileges);
+        report.restart_if_changed("daemon", &current.daemon, &new.daemon);
+
+        // Zones are created transferable or not, and the interceptor is only installed
+        // at startup, so switching transfers or authentication on or off needs a restart
+        let transfer_changed = current.dns.transfer != new.dns.transfer;
+        let transfer_live = current.dns.transfer.is_some() == new.dns.transfer.is_some();
+        if transfer_changed && !transfer_live {
+            report.restart_required.push("dns.transfer".into());
+        }
+        let auth_changed = current.grpc.auth != new.grpc.auth;
+        let auth_live = current.grpc.auth.is_some() == new.grpc.auth.is_some();
+        if auth_changed && !auth_live {
+            report.restart_required.push("grpc.auth".into());
+        }
+        let zones_changed = current.dns.zones != new.dns.zones;
+        let update_changed = current.dns.update != new.dns.update;
+        let forward_changed = current.dns.forward != new.dns.forward;
+        let dnstap_changed = current.logging.dnstap != new.logging.dnstap;
+        let log_level_changed = current.logging.level != new.logging.level;
+        let answer_order_changed = current.dns.round_robin != new.dns.round_robin || current.dns.answer_order != new.dns.answer_order;
+        let cname_chain_depth_changed = current.dns.cname_chain_depth != new.dns.cname_chain_depth;
+        let additional_records_changed = current.dns.additional_records != new.dns.additional_records;
+        let any_response_changed = current.dns.any_response != new.dns.any_response;
+        let address_family_changed = current.dns.address_family != new.dns.address_family;
+        let pipeline_changed = current.dns.pipeline != new.dns.pipeline;
+        let acl_changed = current.dns.acl != new.dns.acl;
+        let ecs_changed = current.dns.ecs != new.dns.ecs;
+        let identity_changed = current.dns.identity != new.dns.identity;
+        let negative_changed = current.dns.negative != new.dns.negative;
+        let dns64_changed = current.dns.dns64 != new.dns.dns64;
+        // Scripts are read again even when unchanged in the config, like policy zone files
+        let script_changed = current.dns.script != new.dns.script || new.dns.script.is_some();
+        let clients_changed = current.dns.clients != new.dns.clients;
+        let ttl_changed = current.dns.ttl != new.dns.ttl;
+        let quotas_changed = current.dns.quotas != new.dns.quotas;
+        let templates_changed = current.dns.templates != new.dns.templates;
+        let tenants_changed = current.dns.tenants != new.dns.tenants;
+        let rewrite_changed = current.dns.rewrite != new.dns.rewrite;
+        // Rule files are read again even when unchanged in the config, like policy zones
+        let query_rules_changed = current.dns.query_rules != new.dns.query_rules
+            || current.dns.query_rule_files != new.dns.query_rule_files
+            || !new.dns.query_rule_files.is_empty();
+        let tsig_keys_changed = current.dns.tsig_keys != new.dns.tsig_keys;
+        // Policy zone files are read again even when unchanged in the config, as feeds
+        // are updated in place
+        let rpz_changed = current.dns.rpz != new.dns.rpz || !new.dns.rpz.is_empty();
+
+        // Validate every live change before applying any of them
+        let update = new.dns.update.clone().map(UpdateOptions::from);
+        let transfer = if transfer_live { &new.dns.transfer } else { &current.dns.transfer };
+        let transfer = transfer.clone().map(TransferOptions::try_from).transpose()?;
+        let forward = new.dns.forward.clone().map(ForwardOptions::try_from).transpose()?;
+        let dnstap = new.logging.dnstap.clone().map(DnstapOptions::try_from).transpose()?;
+        let log_level: LogLevel = new.logging.level.parse()?;
+        let any_response: AnyResponse = new.dns.any_response.parse()?;
+        let address_family: AddressFamily = new.dns.address_family.parse()?;
+        let pipeline = Pipeline::parse(&new.dns.pipeline)?;
+        let acl = new.dns.acl.clone().map(AclOptions::try_from).transpose()?;
+        let ecs = EcsOptions::try_from(new.dns.ecs.clone())?;
+        let identity = new.dns.identity.clone().map(IdentityOptions::from);
+        let dns64 = new.dns.dns64.clone().map(Dns64Options::try_from).transpose()?;
+        let script = new.dns.script.clone().map(Script::try_from).transpose()?.map(Arc::new);
+        // Views are only read at startup, so devices must use those in effect
+        if let Some(clients) = &new.dns.clients {
+            clientid::check_views(clients, &current.dns.views)?;
+        }
+        let clients = new.dns.clients.clone().map(ClientOptions::try_from).transpose()?;
+        let answer_order = AnswerOrderOptions::parse(new.dns.round_robin, &new.dns.answer_order)?;
+        let negative: Vec<NegativePolicy> = new.dns.negative.iter().cloned().map(NegativePolicy::try_from).collect::<anyhow::Result<_>>()?;
+        let ttl = TtlPolicy::try_from(new.dns.ttl.clone())?;
+        let quotas = new.dns.quotas.clone().map(QuotaOptions::try_from).transpose()?;
+        let templates = template::parse_all(&new.dns.templates)?;
+        let tenants: Vec<Tenant> = new.dns.tenants.iter().cloned().map(Tenant::try_from).collect::<anyhow::Result<_>>()?;
+        let rewrite = new
+            .dns
+            .rewrite
+            .iter()
+            .cloned()
+            .map(|rule| RewriteRule::new(RewriteRuleEntry::from(rule)))
+            .collect::<Result<Vec<_>, RdnsError>>()?;
+        let query_rules = queryrules::load_configured(&new.dns.query_rules, &new.dns.query_rule_files)?;
+        let tsig_keys = tsig::configured_keys(&new.dns)?;
+        let rpz_options: Vec<RpzOptions> = new.dns.rpz.iter().cloned().map(RpzOptions::from).collect();
+        let rpz = rpz::load_all(&rpz_options).await?;
+        let auth = match (&new.grpc.auth, auth_changed && auth_live) {
+            (Some(auth), true) => Some(AuthOptions::try_from(auth.clone())?),
+            _ => None,
+        };
+
+        if log_level_changed {
+            logging::set_level(log_level);
+            current.logging.level = new.logging.level.clone();
+            report.applied.push("logging.level".into());
+        }
+        if let Some(auth) = auth {
+            self.auth.reload(auth).await?;
+            current.grpc.auth = new.grpc.auth.clone();
+            report.applied.push("grpc.auth".into());
+        }
+        {
+            let mut state = self.state.write().await;
+            if zones_changed {
+                let created = state.ensure_zones(&new.dns.zones).await?;
+                current.dns.zones = new.dns.zones.clone();
+                report.applied.push(format!("dns.zones ({} created)", created));
+            }
+            if forward_changed {
+                state.set_forwarding(forward.as_ref()).await?;
+                current.dns.forward = new.dns.forward.clone();
+                report.applied.push("dns.forward".into());
+            }
+            if transfer_changed && transfer_live {
+                if let Some(transfer) = &transfer {
+                    state.set_transfer_secondaries(transfer);
+                }
+                current.dns.transfer = new.dns.transfer.clone();
+                report.applied.push("dns.transfer".into());
+            }
+            if ttl_changed {
+                state.set_ttl_policy(ttl);
+                current.dns.ttl = new.dns.ttl.clone();
+                report.applied.push("dns.ttl".into());
+            }
+            if quotas_changed {
+                state.set_quotas(quotas);
+                current.dns.quotas = new.dns.quotas.clone();
+                report.applied.push("dns.quotas".into());
+            }
+            if templates_changed {
+                state.set_zone_templates(templates);
+                current.dns.templates = new.dns.templates.clone();
+                report.applied.push("dns.templates".into());
+            }
+            if tenants_changed {
+                state.set_tenants(tenants);
+                current.dns.tenants = new.dns.tenants.clone();
+                report.applied.push("dns.tenants".into());
+            }
+            if rewrite_changed {
+                state.set_rewrite_rules(rewrite);
+                current.dns.rewrite = new.dns.rewrite.clone();
+                report.applied.push("dns.rewrite".into());
+            }
+            if query_rules_changed {
+                state.set_query_rules(query_rules);
+                current.dns.query_rules = new.dns.query_rules.clone();
+                current.dns.query_rule_files = new.dns.query_rule_files.clone();
+                report.applied.push("dns.query_rules".into());
+            }
+            // Keys of dns.update are held along with those of dns.tsig_keys
+            if tsig_keys_changed || update_changed {
+                state.set_tsig_keys(tsig_keys);
+            }
+            if tsig_keys_changed {
+                current.dns.tsig_keys = new.dns.tsig_keys.clone();
+                report.applied.push("dns.tsig_keys".into());
+            }
+        }
+        if update_changed || (transfer_changed && transfer_live) || dnstap_changed || answer_order_changed || cname_chain_depth_changed || additional_records_changed || any_response_changed || address_family_changed || pipeline_changed || acl_changed || rpz_changed || ecs_changed || identity_changed || negative_changed || dns64_changed || script_changed || clients_changed {
+            let (privacy, tracer) = {
+                let options = self.handler_options.borrow();
+                (options.privacy.clone(), options.tracer.clone())
+            };
+            let dnstap = if dnstap_changed {
+                dnstap.map(|dnstap| Dnstap::spawn(dnstap, privacy.clone()))
+            } else {
+                self.handler_options.borrow().dnstap.clone()
+            };
+            self.handler_options.send_replace(Arc::new(HandlerOptions {
+                update,
+                transfer,
+                dnstap,
+                tracer,
+                privacy,
+                answer_order,
+                cname_chain_depth: new.dns.cname_chain_depth,
+                additional_records: new.dns.additional_records,
+                any_response,
+                address_family,
+                pipeline,
+                acl,
+                rpz,
+                negative,
+                ecs,
+                identity,
+                dns64,
+                script,
+                clients,
+            }));
+            if update_changed {
+                current.dns.update = new.dns.update.clone();
+                report.applied.push("dns.update".into());
+            }
+            if dnstap_changed {
+                current.logging.dnstap = new.logging.dnstap.clone();
+                report.applied.push("logging.dnstap".into());
+            }
+            if answer_order_changed {
+                current.dns.round_robin = new.dns.round_robin;
+                current.dns.answer_order = new.dns.answer_order.clone();
+                report.applied.push("dns.answer_order".into());
+            }
+            if cname_chain_depth_changed {
+                current.dns.cname_chain_depth = new.dns.cname_chain_depth;
+                report.applied.push("dns.cname_chain_depth".into());
+            }
+            if additional_records_changed {
+                current.dns.additional_records = new.dns.additional_records;
+                report.applied.push("dns.additional_records".into());
+            }
+            if any_response_changed {
+                current.dns.any_response = new.dns.any_response.clone();
+                report.applied.push("dns.any_response".into());
+            }
+            if address_family_changed {
+                current.dns.address_family = new.dns.address_family.clone();
+                report.applied.push("dns.address_family".into());
+            }
+            if pipeline_changed {
+                current.dns.pipeline = new.dns.pipeline.clone();
+                report.applied.push("dns.pipeline".into());
+            }
+            if acl_changed {
+                current.dns.acl = new.dns.acl.clone();
+                report.applied.push("dns.acl".into());
+            }
+            if rpz_changed {
+                current.dns.rpz = new.dns.rpz.clone();
+                report.applied.push("dns.rpz".into());
+            }
+            if ecs_changed {
+                current.dns.ecs = new.dns.ecs.clone();
+                report.applied.push("dns.ecs".into());
+            }
+            if identity_changed {
+                current.dns.identity = new.dns.identity.clone();
+                report.applied.push("dns.identity".into());
+            }
+            if negative_changed {
+                current.dns.negative = new.dns.negative.clone();
+                report.applied.push("dns.negative".into());
+            }
+            if dns64_changed {
+                current.dns.dns64 = new.dns.dns64.clone();
+                report.applied.push("dns.dns64".into());
+            }
+            if script_changed {
+                current.dns.script = new.dns.script.clone();
+                report.applied.push("dns.script".into());
+            }
+            if clients_changed {
+                current.dns.clients = new.dns.clients.clone();
+                report.applied.push("dns.clients".into());
+            }
+        }
+        *self.reloaded_at.lock().unwrap() = Some(SystemTime::now());
+        Ok(report)
+    }
+
+    /// The settings in effect.
+    pub async fn settings(&self) -> Settings {
+        self.current.lock().await.clone()
+    }
+
+    /// When the config was last reloaded, if it has been since the start.
+    pub fn reloaded_at(&self) -> Option<SystemTime> {
+        *self.reloaded_at.lock().unwrap()
+    }
+
+    /// Reads the zone files of the settings in effect again, replacing the zones loaded
+    /// from them. A file that fails to load leaves its zone as it was.
+    pub async fn reload_zone_files(&self) -> Vec<(ZoneFile, Result<usize, RdnsError>)> {
+        let zone_files: Vec<ZoneFile> = self.current.lock().await.dns.zone_files.iter().cloned().map(ZoneFile::from).collect();
+        let mut results = Vec::with_capacity(zone_files.len());
+        for zone_file in zone_files {
+            let result = self.state.write().await.import_zone_file(&zone_file).await;
+            results.push((zone_file, result));
+        }
+        results
+    }
+}
+
+/// Reloads the config every time the process receives SIGHUP.
+///
+/// # Errors
+///
+/// Returns an error if the signal handler can't be installed.
+#[cfg(unix)]
+pub async fn reload_on_hangup(reloader: Arc<Reloader>) -> anyhow::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        match reloader.reload().await {
+            Ok(report) => println!("{}", report.summary()),
+            Err(e) => eprintln!("Config reload failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Fails, as there is no SIGHUP outside of unix; the config is reloaded through the
+/// `ReloadConfig` call instead.
+#[cfg(not(unix))]
+pub async fn reload_on_hangup(_reloader: Arc<Reloader>) -> anyhow::Result<()> {
+    anyhow::bail!("there is no SIGHUP on this platform, reload through ReloadConfig instead")
+}
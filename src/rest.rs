@@ -0,0 +1,502 @@
+//! REST/JSON gateway for the control API.
+//!
+//! Exposes the record and zone operations of the gRPC control interface as JSON over
+//! plain HTTP, for tooling and dashboards that can't speak gRPC:
+//!
+//! - `GET /v1/records` lists every record, or with `?zone=<origin>` those of one zone,
+//!   with Unicode names given `?unicode=true`, `POST /v1/records` adds one, removed again after its `lease_seconds` when given
+//!   and with the `metadata` given, and `DELETE /v1/records?name=<name>&type=<type>` deletes the records of a name and
+//!   type, or with `&value=<value>` only the record holding that value, and
+//!   `PUT /v1/records` replaces the records of a name and type with one, or given an
+//!   `expected_value` only the record holding it, failing when that is no longer held
+//! - `GET /v1/zones` lists the zones, `POST /v1/zones` creates one, populated from the
+//!   zone template named by `template` or the default template, and
+//!   `DELETE /v1/zones/<origin>` deletes one
+//! - `GET /v1/stats` lists the most queried names, `?top=<n>` of them, counted over
+//!   the last `?window=<seconds>` when given, when query statistics are enabled
+//!
+//! With `ui` set, the web dashboard of [`crate::webui`] is served at `/ui`.
+//!
+//! Requests are authenticated with the same bearer tokens and roles as the gRPC API, and
+//! their changes recorded in the same audit log. Tokens of a tenant only see and manage
+//! the zones their tenant owns, as over gRPC.
+//!
+//! Request bodies are capped at 1 MiB; larger ones are rejected with 413 Payload Too
+//! Large before they are read in full.
+
+use hyper::body::HttpBody;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+
+use crate::audit::{self, Actor, AuditLog, Change};
+use crate::auth::{Authenticator, Role};
+use crate::control;
+use crate::dns::{DnsState, RecordEntry};
+use crate::error::RdnsError;
+use crate::settings::RestSettings;
+use crate::shutdown::Shutdown;
+use crate::stats::{NameStats, StatsQuery};
+use crate::webui;
+
+/// Config options for the REST gateway
+pub struct RestOptions {
+    pub listen_addr: String,
+    /// Whether the web dashboard is served at `/ui`
+    pub ui: bool,
+}
+
+impl From<RestSettings> for RestOptions {
+    fn from(cfg: RestSettings) -> Self {
+        RestOptions {
+            listen_addr: cfg.listen_addr,
+            ui: cfg.ui,
+        }
+    }
+}
+
+/// Outcome of a mutation, mirroring the gRPC `ControlResponse`.
+#[derive(Serialize)]
+struct MutationResponse {
+    success: bool,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct ZoneEntry {
+    origin: String,
+    record_count: u32,
+    auto_reverse: bool,
+    frozen: bool,
+}
+
+/// A record to add, removed again after `lease_seconds` when that is set.
+#[derive(Deserialize)]
+struct NewRecord {
+    #[serde(flatten)]
+    record: RecordEntry,
+    #[serde(default)]
+    lease_seconds: u32,
+}
+
+/// A record value replacing the current values of its name and type, provided its
+/// `expected_value` is still held when that is set.
+#[derive(Deserialize)]
+struct UpdatedRecord {
+    #[serde(flatten)]
+    record: RecordEntry,
+    #[serde(default)]
+    expected_value: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CreateZoneBody {
+    origin: String,
+    #[serde(default)]
+    auto_reverse: bool,
+    /// Zone template to populate the zone with, the default template when unset
+    template: Option<String>,
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    /// When tracking began, in seconds since the Unix epoch
+    since: u64,
+    entries: Vec<StatsEntry>,
+}
+
+#[derive(Serialize)]
+struct StatsEntry {
+    name: String,
+    record_type: String,
+    /// Queries within the window
+    queries: u64,
+    /// Queries since tracking began
+    total: u64,
+    clients: usize,
+    /// When the last query came in, in seconds since the Unix epoch
+    last_queried: Option<u64>,
+}
+
+impl From<NameStats> for StatsEntry {
+    fn from(stats: NameStats) -> Self {
+        StatsEntry {
+            name: stats.name.to_string(),
+            record_type: stats.record_type.to_string(),
+            queries: stats.queries,
+            total: stats.total,
+            clients: stats.clients,
+            last_queried: stats.last_queried.map(unix_seconds),
+        }
+    }
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// Builds a JSON response with the given status.
+fn json<T: Serialize>(code: StatusCode, body: &T) -> hyper::Response<Body> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    let mut response = hyper::Response::new(Body::from(body));
+    *response.status_mut() = code;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, header::HeaderValue::from_static("application/json"));
+    response
+}
+
+fn error(code: StatusCode, message: impl Into<String>) -> hyper::Response<Body> {
+    json(code, &ErrorBody { error: message.into() })
+}
+
+/// The HTTP status a failure of category `e` calls for.
+pub(crate) fn status_code(e: &RdnsError) -> StatusCode {
+    match e {
+        RdnsError::ZoneExists(_) | RdnsError::Conflict(_) => StatusCode::CONFLICT,
+        RdnsError::ZoneNotFound(_) | RdnsError::RecordNotFound(_) | RdnsError::VersionNotFound(_) => StatusCode::NOT_FOUND,
+        RdnsError::ReadOnlyZone(_)
+        | RdnsError::ZoneFrozen(_)
+        | RdnsError::Occluded(_)
+        | RdnsError::ValueMismatch(_)
+        | RdnsError::NotLeader(_)
+        | RdnsError::NotEnabled(_) => StatusCode::PRECONDITION_FAILED,
+        RdnsError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+        RdnsError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+        RdnsError::StorageError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        RdnsError::InvalidName(_) | RdnsError::InvalidRdata(_) | RdnsError::InvalidArgument(_) | RdnsError::Other(_) => {
+            StatusCode::BAD_REQUEST
+        }
+    }
+}
+
+/// Answers a mutation with its outcome, like the gRPC mutation RPCs, with the status
+/// code a rejected record calls for.
+fn mutation(result: Result<String, RdnsError>) -> hyper::Response<Body> {
+    match result {
+        Ok(message) => json(StatusCode::OK, &MutationResponse { success: true, message }),
+        Err(e) => json(
+            status_code(&e),
+            &MutationResponse {
+                success: false,
+                message: format!("Error: {}", e),
+            },
+        ),
+    }
+}
+
+/// Largest JSON request body accepted, in bytes, by the gateway and by the other HTTP
+/// APIs reading bodies with [`read_json`].
+pub(crate) const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Why a JSON request body was rejected.
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum BodyError {
+    #[error("request body exceeds {MAX_BODY_SIZE} bytes")]
+    TooLarge,
+    #[error(transparent)]
+    Read(#[from] hyper::Error),
+    #[error(transparent)]
+    Malformed(#[from] serde_json::Error),
+}
+
+impl BodyError {
+    /// 413 Payload Too Large for a body over the limit, 400 Bad Request otherwise.
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            BodyError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            BodyError::Read(_) | BodyError::Malformed(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+/// Parses a JSON request body of at most `MAX_BODY_SIZE` bytes.
+pub(crate) async fn read_json<T: for<'de> Deserialize<'de>>(request: hyper::Request<Body>) -> Result<T, BodyError> {
+    let declared = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<usize>().ok());
+    if declared.is_some_and(|len| len > MAX_BODY_SIZE) {
+        return Err(BodyError::TooLarge);
+    }
+    // Read in chunks, so that a body longer than declared, or sent without a length,
+    // is cut off at the limit as well
+    let mut body = request.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > MAX_BODY_SIZE {
+            return Err(BodyError::TooLarge);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Answers a mutation whose request body was rejected.
+fn rejected_body(e: BodyError) -> hyper::Response<Body> {
+    json(
+        e.status(),
+        &MutationResponse {
+            success: false,
+            message: format!("Error: invalid request body: {}", e),
+        },
+    )
+}
+
+/// Returns the value of query parameter `key`, if present.
+fn query_param(request: &hyper::Request<Body>, key: &str) -> Option<String> {
+    let query = request.uri().query()?;
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Answers a single REST request.
+async fn serve(
+    state: Arc<RwLock<DnsState>>,
+    auth: Arc<Authenticator>,
+    audit: Option<Arc<AuditLog>>,
+    ui: bool,
+    peer: SocketAddr,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    // The dashboard holds no data and authenticates its own calls
+    if ui && request.method() == Method::GET && matches!(request.uri().path(), "/ui" | "/ui/") {
+        return Ok(webui::page());
+    }
+    let required = if request.method() == Method::GET { Role::Read } else { Role::Admin };
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let (actor, tenant) = match auth.authenticate(authorization) {
+        Ok(caller) if caller.role >= required => (
+            Actor {
+                token: caller.token,
+                peer: Some(peer.to_string()),
+            },
+            caller.tenant,
+        ),
+        Ok(_) => return Ok(error(StatusCode::FORBIDDEN, format!("this call requires the {} role", required))),
+        Err(message) => return Ok(error(StatusCode::UNAUTHORIZED, message)),
+    };
+    let audit = audit.as_deref();
+    let tenant = tenant.as_deref();
+
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let response = match (method, segments.as_slice()) {
+        (Method::GET, ["v1", "records"]) => {
+            let state = state.read().await;
+            let mut records = match query_param(&request, "zone") {
+                Some(zone) => {
+                    let records = match state.check_tenant_zone(tenant, &zone) {
+                        Ok(()) => state.get_zone_records(&zone).await,
+                        Err(e) => Err(e),
+                    };
+                    match records {
+                        Ok(records) => records,
+                        Err(e) => return Ok(error(status_code(&e), e.to_string())),
+                    }
+                }
+                None => state.tenant_records(tenant).await,
+            };
+            if query_param(&request, "unicode").is_some_and(|unicode| unicode == "true") {
+                records = records.into_iter().map(RecordEntry::into_unicode).collect();
+            }
+            json(StatusCode::OK, &records)
+        }
+        (Method::POST, ["v1", "records"]) => {
+            let result = match read_json::<NewRecord>(request).await {
+                Ok(NewRecord { record, lease_seconds }) => {
+                    let expires_at = (lease_seconds > 0).then(|| SystemTime::now() + Duration::from_secs(lease_seconds.into()));
+                    let mut state = state.write().await;
+                    if let Err(e) = state.check_tenant(tenant, &record.name) {
+                        return Ok(mutation(Err(e)));
+                    }
+                    let change = Change::records(audit, &state, actor, "AddRecord", &record.name, &record.record_type).await;
+                    let result = state
+                        .add_leased_record(record.name, record.record_type, record.value, record.ttl, expires_at, Some(record.metadata).filter(|metadata| !metadata.is_empty()))
+                        .await;
+                    audit::finish(change, &state, &result).await;
+                    result.map(control::record_added)
+                }
+                Err(e) => return Ok(rejected_body(e)),
+            };
+            mutation(result)
+        }
+        (Method::PUT, ["v1", "records"]) => {
+            let result = match read_json::<UpdatedRecord>(request).await {
+                Ok(UpdatedRecord { record, expected_value }) => {
+                    let mut state = state.write().await;
+                    if let Err(e) = state.check_tenant(tenant, &record.name) {
+                        return Ok(mutation(Err(e)));
+                    }
+                    let change = Change::records(audit, &state, actor, "UpdateRecord", &record.name, &record.record_type).await;
+                    let result = state
+                        .update_record(
+                            record.name,
+                            record.record_type,
+                            record.value,
+                            record.ttl,
+                            expected_value.filter(|value| !value.is_empty()),
+                            Some(record.metadata).filter(|metadata| !metadata.is_empty()),
+                        )
+                        .await;
+                    audit::finish(change, &state, &result).await;
+                    result.map(|clamped| control::with_clamped_ttl("Record updated", clamped))
+                }
+                Err(e) => return Ok(rejected_body(e)),
+            };
+            mutation(result)
+        }
+        (Method::DELETE, ["v1", "records"]) => {
+            let Some(name) = query_param(&request, "name") else {
+                return Ok(error(StatusCode::BAD_REQUEST, "missing name parameter"));
+            };
+            let record_type = query_param(&request, "type").unwrap_or_default();
+            let state = state.write().await;
+            if let Err(e) = state.check_tenant(tenant, &name) {
+                return Ok(mutation(Err(e)));
+            }
+            let result = match query_param(&request, "value") {
+                Some(value) => {
+                    let change = Change::records(audit, &state, actor, "DeleteRecordValue", &name, &record_type).await;
+                    let result = state.delete_record_value(name, record_type, value).await;
+                    audit::finish(change, &state, &result).await;
+                    result
+                }
+                None => {
+                    let change = Change::records(audit, &state, actor, "DeleteRecord", &name, &record_type).await;
+                    let result = state.delete_record(name, record_type).await;
+                    audit::finish(change, &state, &result).await;
+                    result
+                }
+            };
+            mutation(result.map(|_| "Record deleted".to_string()))
+        }
+        (Method::GET, ["v1", "zones"]) => {
+            let zones: Vec<ZoneEntry> = state
+                .read()
+                .await
+                .list_zones(tenant)
+                .await
+                .into_iter()
+                .map(|(origin, record_count, auto_reverse, frozen)| ZoneEntry {
+                    origin,
+                    record_count,
+                    auto_reverse,
+                    frozen,
+                })
+                .collect();
+            json(StatusCode::OK, &zones)
+        }
+        (Method::POST, ["v1", "zones"]) => {
+            let result = match read_json::<CreateZoneBody>(request).await {
+                Ok(body) => {
+                    let mut state = state.write().await;
+                    let change = Change::zone(audit, &state, actor, "CreateZone", &body.origin).await;
+                    let result = match tenant {
+                        Some(tenant) => {
+                            state
+                                .create_tenant_zone(tenant, &body.origin, body.template.as_deref(), body.auto_reverse)
+                                .await
+                        }
+                        None => {
+                            state
+                                .create_zone_from_template(&body.origin, body.template.as_deref(), body.auto_reverse)
+                                .await
+                        }
+                    };
+                    audit::finish(change, &state, &result).await;
+                    result
+                }
+                Err(e) => return Ok(rejected_body(e)),
+            };
+            mutation(result.map(control::zone_created))
+        }
+        (Method::DELETE, ["v1", "zones", origin]) => {
+            let mut state = state.write().await;
+            if let Err(e) = state.check_tenant_zone(tenant, origin) {
+                return Ok(mutation(Err(e)));
+            }
+            let change = Change::zone(audit, &state, actor, "DeleteZone", origin).await;
+            let result = state.delete_zone(origin).await;
+            audit::finish(change, &state, &result).await;
+            mutation(result.map(|_| "Zone deleted".to_string()))
+        }
+        (Method::GET, ["v1", "stats"]) => {
+            if let Some(tenant) = tenant {
+                return Ok(error(StatusCode::FORBIDDEN, format!("this call isn't available to the tokens of tenant {}", tenant)));
+            }
+            let number = |key: &str| query_param(&request, key).and_then(|value| value.parse::<u64>().ok()).unwrap_or(0);
+            let query = StatsQuery {
+                zone: None,
+                name: None,
+                record_type: None,
+                window: Some(number("window")).filter(|&secs| secs > 0).map(Duration::from_secs),
+                top: control::stats_top(number("top").try_into().unwrap_or(u32::MAX)),
+            };
+            match state.read().await.query_stats(&query, false).await {
+                Ok((stats, since)) => json(
+                    StatusCode::OK,
+                    &StatsResponse {
+                        since: unix_seconds(since),
+                        entries: stats.into_iter().map(StatsEntry::from).collect(),
+                    },
+                ),
+                Err(e) => error(status_code(&e), e.to_string()),
+            }
+        }
+        _ => error(StatusCode::NOT_FOUND, "no such endpoint"),
+    };
+    Ok(response)
+}
+
+/// Starts the REST gateway, serving the records and zones in `state` until `shutdown`
+/// is requested, and recording changes in `audit` when it is set.
+///
+/// # Errors
+///
+/// Returns an error if the listen address is invalid or the server fails.
+pub async fn run_rest_server(
+    state: Arc<RwLock<DnsState>>,
+    auth: Arc<Authenticator>,
+    audit: Option<Arc<AuditLog>>,
+    options: RestOptions,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let addr: SocketAddr = options.listen_addr.parse()?;
+    let ui = options.ui;
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let auth = auth.clone();
+        let audit = audit.clone();
+        let peer = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| serve(state.clone(), auth.clone(), audit.clone(), ui, peer, request)))
+        }
+    });
+    println!("REST gateway listening on {}", addr);
+    if ui {
+        println!("Web dashboard at http://{}/ui", addr);
+    }
+    hyper::Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.requested())
+        .await?;
+    Ok(())
+}
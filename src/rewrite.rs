@@ -0,0 +1,258 @@
+//! Query name rewriting.
+//!
+//! A rewrite rule answers the queries for some names with the records of others, e.g.
+//! those for the names under `staging.example.com` with the records of the same names
+//! under `prod.example.com`. A suffix rule maps its `from` domain and every name under
+//! it to the same name under `to`. A regex rule, with `regex` set, maps the names its
+//! `from` pattern matches, in lower-cased and fully qualified form like
+//! `www.staging.example.com.`, to `to` with group references such as `$1` expanded.
+//!
+//! The new name is looked up in the hosted zones or through the forwarder, whose answers
+//! are validated as usual, and the answer is rewritten back: owner names, and the names
+//! in CNAME, NS, PTR, MX and SRV records, under `to` of a suffix rule or equal to the new
+//! name of a regex rule, become the names the client asked about. Names that don't exist
+//! stay NXDOMAIN. Rules are tried in order, those added through the `RewriteRules`
+//! service first, as the `rewrite` stage of the pipeline.
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::rdata::{CNAME, MX, NS, PTR, SRV};
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use hickory_server::authority::{AuthorityObject, LookupError, LookupOptions};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+
+use crate::dns::{self, DnsState};
+use crate::error::RdnsError;
+use crate::settings::RewriteRuleSettings;
+
+/// A rewrite rule, as configured or added through the control API and persisted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RewriteRuleEntry {
+    /// Domain whose names, its own included, are rewritten, or a pattern of the names
+    pub from: String,
+    /// Domain the names are moved under, or the name they become
+    pub to: String,
+    /// Whether `from` is a regular expression rather than a domain
+    #[serde(default)]
+    pub regex: bool,
+}
+
+impl From<RewriteRuleSettings> for RewriteRuleEntry {
+    fn from(cfg: RewriteRuleSettings) -> Self {
+        RewriteRuleEntry {
+            from: cfg.from,
+            to: cfg.to,
+            regex: cfg.regex,
+        }
+    }
+}
+
+/// A rewrite rule as listed through the control API.
+#[derive(Debug, Clone)]
+pub struct RewriteRuleInfo {
+    pub entry: RewriteRuleEntry,
+    /// Whether the rule comes from the config rather than `AddRewriteRule`
+    pub configured: bool,
+}
+
+/// How a rule matches and rewrites names.
+#[derive(Debug, Clone)]
+enum Pattern {
+    Suffix { from: Name, to: Name },
+    Regex { from: Regex, to: String },
+}
+
+/// A rewrite rule with its names or pattern parsed.
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    /// The entry with the domains of a suffix rule in canonical form: lower-cased and
+    /// fully qualified
+    pub entry: RewriteRuleEntry,
+    pattern: Pattern,
+}
+
+impl RewriteRule {
+    pub fn new(mut entry: RewriteRuleEntry) -> Result<Self, RdnsError> {
+        let pattern = if entry.regex {
+            let from = Regex::new(&entry.from)
+                .map_err(|e| RdnsError::InvalidArgument(format!("invalid rewrite pattern {}: {}", entry.from, e)))?;
+            if entry.to.is_empty() {
+                return Err(RdnsError::InvalidArgument(format!("rewrite rule for {} has no target", entry.from)));
+            }
+            Pattern::Regex { from, to: entry.to.clone() }
+        } else {
+            let from = DnsState::parse_name(&entry.from)?.to_lowercase();
+            let to = DnsState::parse_name(&entry.to)?.to_lowercase();
+            if from == to {
+                return Err(RdnsError::InvalidArgument(format!("{} can't be rewritten to itself", from)));
+            }
+            entry.from = from.to_string();
+            entry.to = to.to_string();
+            Pattern::Suffix { from, to }
+        };
+        Ok(RewriteRule { entry, pattern })
+    }
+
+    /// The name `name` is rewritten to, if the rule matches it.
+    fn rewrite(&self, name: &LowerName) -> Option<Name> {
+        match &self.pattern {
+            Pattern::Suffix { from, to } => replace_suffix(&Name::from(name), from, to),
+            Pattern::Regex { from, to } => {
+                let name = name.to_string();
+                let captures = from.captures(&name)?;
+                let mut rewritten = String::new();
+                captures.expand(to, &mut rewritten);
+                DnsState::parse_name(&rewritten).ok()
+            }
+        }
+    }
+
+    /// The name of the answer `name` is rewritten back to, for a query for `original`
+    /// rewritten to `rewritten`, or `None` if it stays as it is.
+    fn restore(&self, name: &Name, original: &Name, rewritten: &Name) -> Option<Name> {
+        let restored = match &self.pattern {
+            Pattern::Suffix { from, to } => replace_suffix(name, to, from)?,
+            Pattern::Regex { .. } => (name == rewritten).then(|| original.clone())?,
+        };
+        // Names are compared case-insensitively, so the client gets back the case it used
+        Some(if restored == *original { original.clone() } else { restored })
+    }
+
+    /// `record` with the names rewritten back.
+    fn restore_record(&self, record: &Record, original: &Name, rewritten: &Name) -> Record {
+        let mut record = record.clone();
+        if let Some(name) = self.restore(record.name(), original, rewritten) {
+            record.set_name(name);
+        }
+        let restore = |name: &Name| self.restore(name, original, rewritten).unwrap_or_else(|| name.clone());
+        let rdata = match record.data() {
+            Some(RData::CNAME(cname)) => Some(RData::CNAME(CNAME(restore(&cname.0)))),
+            Some(RData::NS(ns)) => Some(RData::NS(NS(restore(&ns.0)))),
+            Some(RData::PTR(ptr)) => Some(RData::PTR(PTR(restore(&ptr.0)))),
+            Some(RData::MX(mx)) => Some(RData::MX(MX::new(mx.preference(), restore(mx.exchange())))),
+            Some(RData::SRV(srv)) => Some(RData::SRV(SRV::new(srv.priority(), srv.weight(), srv.port(), restore(srv.target())))),
+            _ => None,
+        };
+        if let Some(rdata) = rdata {
+            record.set_data(Some(rdata));
+        }
+        record
+    }
+}
+
+/// `name` with its suffix `from` replaced by `to`, if it is at or under `from`.
+fn replace_suffix(name: &Name, from: &Name, to: &Name) -> Option<Name> {
+    if !from.zone_of(name) {
+        return None;
+    }
+    let prefix = usize::from(name.num_labels() - from.num_labels());
+    let prefix = Name::from_labels(name.iter().take(prefix)).ok()?;
+    prefix.append_domain(to).ok()
+}
+
+/// The configured rewrite rules and those added through the control API.
+#[derive(Default)]
+pub struct RewriteRules {
+    configured: RwLock<Vec<Arc<RewriteRule>>>,
+    created: RwLock<Vec<Arc<RewriteRule>>>,
+}
+
+impl RewriteRules {
+    /// Replaces the rules of the config.
+    pub fn set_configured(&self, rules: Vec<RewriteRule>) {
+        *self.configured.write().unwrap() = rules.into_iter().map(Arc::new).collect();
+    }
+
+    /// Adds a rule through the control API, tried after those added before it, failing
+    /// with `Conflict` if one was already added for its `from`.
+    pub fn insert(&self, rule: RewriteRule) -> Result<(), RdnsError> {
+        let mut created = self.created.write().unwrap();
+        if created.iter().any(|existing| existing.entry.from == rule.entry.from) {
+            return Err(RdnsError::Conflict(format!("a rewrite rule for {} already exists", rule.entry.from)));
+        }
+        created.push(Arc::new(rule));
+        Ok(())
+    }
+
+    /// Removes a rule added through the control API; those of the config can't be.
+    pub fn remove(&self, from: &str) -> Result<(), RdnsError> {
+        // The domain of a suffix rule may be given in any case, with or without a final dot
+        let domain = DnsState::parse_name(from).ok().map(|name| name.to_lowercase().to_string());
+        let matches = |rule: &Arc<RewriteRule>| match rule.entry.regex {
+            true => rule.entry.from == from,
+            false => domain.as_deref() == Some(rule.entry.from.as_str()),
+        };
+        let mut created = self.created.write().unwrap();
+        if let Some(index) = created.iter().position(matches) {
+            created.remove(index);
+            return Ok(());
+        }
+        match self.configured.read().unwrap().iter().any(matches) {
+            true => Err(RdnsError::InvalidArgument(format!("rewrite rule for {} is configured in Config.toml", from))),
+            false => Err(RdnsError::RecordNotFound(format!("no rewrite rule for {}", from))),
+        }
+    }
+
+    /// Drops the rules added through the control API.
+    pub fn clear(&self) {
+        self.created.write().unwrap().clear();
+    }
+
+    /// The rules added through the control API, in the order they are tried.
+    pub fn created(&self) -> Vec<RewriteRuleEntry> {
+        self.created.read().unwrap().iter().map(|rule| rule.entry.clone()).collect()
+    }
+
+    /// Every rule, in the order they are tried.
+    pub fn list(&self) -> Vec<RewriteRuleInfo> {
+        let info = |rule: &Arc<RewriteRule>, configured| RewriteRuleInfo {
+            entry: rule.entry.clone(),
+            configured,
+        };
+        let mut rules: Vec<RewriteRuleInfo> = self.created.read().unwrap().iter().map(|rule| info(rule, false)).collect();
+        rules.extend(self.configured.read().unwrap().iter().map(|rule| info(rule, true)));
+        rules
+    }
+
+    /// The first rule matching `name`, with the name it rewrites it to.
+    pub fn find(&self, name: &LowerName) -> Option<(Arc<RewriteRule>, LowerName)> {
+        let created = self.created.read().unwrap();
+        let configured = self.configured.read().unwrap();
+        created
+            .iter()
+            .chain(configured.iter())
+            .find_map(|rule| rule.rewrite(name).map(|rewritten| (rule.clone(), LowerName::new(&rewritten))))
+    }
+}
+
+/// Answers `request` with the records `authority` has for `rewritten`, the name `rule`
+/// rewrote the query name to, rewritten back.
+pub async fn send_answer<R: ResponseHandler>(
+    request: &Request,
+    authority: &dyn AuthorityObject,
+    rule: &RewriteRule,
+    rewritten: &LowerName,
+    response_handle: R,
+) -> ResponseInfo {
+    let query_type = request.query().query_type();
+    let mut lookup = match authority.lookup(rewritten, query_type, LookupOptions::default()).await {
+        Ok(lookup) => lookup,
+        Err(LookupError::NameExists) => return dns::send_records(request, &[], None, response_handle).await,
+        Err(e) if e.is_nx_domain() => return dns::send_error(request, ResponseCode::NXDomain, response_handle).await,
+        Err(e) if e.is_refused() => return dns::send_error(request, ResponseCode::Refused, response_handle).await,
+        Err(_) => return dns::send_error(request, ResponseCode::ServFail, response_handle).await,
+    };
+    let original = request.query().original().name();
+    let rewritten = Name::from(rewritten);
+    // The records a CNAME leads to come among the additionals of hosted zones
+    let additionals = lookup.take_additionals();
+    let records: Vec<Record> = lookup
+        .iter()
+        .chain(additionals.iter().flat_map(|additionals| additionals.iter()))
+        .filter(|record| record.record_type() == query_type || record.record_type() == RecordType::CNAME)
+        .map(|record| rule.restore_record(record, original, &rewritten))
+        .collect();
+    dns::send_records(request, &records, None, response_handle).await
+}
@@ -0,0 +1,113 @@
+//! Percentage-based rollouts of new record values.
+//!
+//! A rollout moves a name and type to new values gradually, e.g. during an IP migration:
+//! `percent` of the clients are answered with the new values, and the others with the
+//! zone's own RRset. Clients are put in one of 100 buckets by a hash of the name and of
+//! the address they are located by (that of their EDNS Client Subnet, see `ecs`, or
+//! their source address), so a client keeps getting the same values, every instance
+//! sharing the rollout picks the same ones, and raising the percentage only moves more
+//! clients over. `PromoteRollout` finishes the migration by making the new values the
+//! zone's RRset; deleting the rollout instead leaves every client on the old ones. The
+//! new values are answered directly rather than through the catalog, and aren't
+//! DNSSEC-signed.
+
+use hickory_proto::rr::rdata::opt::EdnsOption;
+use hickory_proto::rr::{LowerName, Record, RecordType, RrKey};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+use crate::dns;
+use crate::ecs::{self, Subnet};
+
+/// A rollout in its textual form, as managed through the control API and persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RolloutEntry {
+    pub name: String,
+    pub record_type: String,
+    pub ttl: u32,
+    /// New values in zone file syntax
+    pub values: Vec<String>,
+    /// Share of the clients answered with the new values, from 0 to 100
+    pub percent: u8,
+}
+
+/// Rollouts by the name and type they answer for, shared with the request handler.
+pub type RolloutTable = RwLock<HashMap<RrKey, Arc<Rollout>>>;
+
+/// A validated rollout, with its new values parsed.
+pub struct Rollout {
+    pub entry: RolloutEntry,
+    key: RrKey,
+    records: Vec<Record>,
+}
+
+/// Values picked for a client, with the Client Subnet option to send back.
+pub struct RolloutAnswer<'r> {
+    records: &'r [Record],
+    subnet: Option<EdnsOption>,
+}
+
+impl Rollout {
+    /// Builds a rollout from `entry` and the records parsed from its values.
+    pub fn new(mut entry: RolloutEntry, records: Vec<Record>) -> anyhow::Result<Self> {
+        if entry.percent > 100 {
+            anyhow::bail!("a rollout's percent must be from 0 to 100, not {}", entry.percent);
+        }
+        let Some(first) = records.first() else {
+            anyhow::bail!("a rollout needs at least one new value");
+        };
+        if matches!(first.record_type(), RecordType::SOA | RecordType::NS | RecordType::CNAME) {
+            anyhow::bail!("{} records can't be rolled out", first.record_type());
+        }
+        if first.name().is_wildcard() {
+            anyhow::bail!("rollouts can't be of wildcards");
+        }
+        entry.name = first.name().to_ascii();
+        entry.record_type = first.record_type().to_string();
+        let key = RrKey::new(LowerName::new(first.name()), first.record_type());
+        Ok(Self { entry, key, records })
+    }
+
+    /// The name and type the rollout answers for.
+    pub fn key(&self) -> RrKey {
+        self.key.clone()
+    }
+
+    /// The new values, for the client of `request` whose Client Subnet option holds
+    /// `subnet` when its bucket is among those rolled out to, or `None` for it to get
+    /// the zone's RRset.
+    pub fn select(&self, request: &Request, subnet: Option<&Subnet>) -> Option<RolloutAnswer<'_>> {
+        if self.bucket(ecs::client_address(request, subnet)) >= self.entry.percent {
+            return None;
+        }
+        Some(RolloutAnswer {
+            records: &self.records,
+            // The answer depends on the whole subnet the client gave
+            subnet: subnet.map(|subnet| subnet.option(subnet.source_prefix)),
+        })
+    }
+
+    /// The bucket, from 0 to 99, the client at `address` falls in for this rollout.
+    fn bucket(&self, address: IpAddr) -> u8 {
+        let mut context = digest::Context::new(&digest::SHA256);
+        context.update(self.key.name.to_string().as_bytes());
+        // IPv4 clients of dual-stack sockets arrive as mapped IPv6 addresses
+        match address.to_canonical() {
+            IpAddr::V4(v4) => context.update(&v4.octets()),
+            IpAddr::V6(v6) => context.update(&v6.octets()),
+        }
+        let hash = context.finish();
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&hash.as_ref()[..8]);
+        (u64::from_be_bytes(prefix) % 100) as u8
+    }
+}
+
+/// Answers `request` authoritatively with the records of `answer`.
+pub async fn send_answer<R: ResponseHandler>(request: &Request, answer: RolloutAnswer<'_>, response_handle: R) -> ResponseInfo {
+    dns::send_records(request, answer.records, answer.subnet, response_handle).await
+}
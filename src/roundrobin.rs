@@ -0,0 +1,287 @@
+//! Ordering of multi-value answers.
+//!
+//! The records of each answer RRset are sent in the order their policy says: as they are
+//! stored (`fixed`), rotated by one position more than for the previous response with
+//! the same RRset (`round-robin`), so clients that take the first address of a name
+//! spread across all of its values, in a random order (`shuffle`), or with the addresses
+//! sharing the longest prefix with the client's first (`proximity`), the client being
+//! that of the EDNS Client Subnet option if the query has one. `[[dns.answer_order]]`
+//! entries set the policy of a zone or a single name, and optionally of one type there;
+//! RRsets no entry covers are rotated if `dns.round_robin` is set.
+
+use hickory_proto::rr::{LowerName, RData, Record, RecordType};
+use hickory_proto::serialize::binary::{BinDecodable, BinEncoder};
+use hickory_server::authority::{MessageRequest, MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{ResponseHandler, ResponseInfo};
+use rand::seq::SliceRandom;
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tonic::async_trait;
+
+use crate::settings::AnswerOrderSettings;
+
+/// Rotation counters kept for round-robin RRsets.
+const ROTATION_COUNTERS: usize = 4096;
+
+thread_local! {
+    /// Buffer responses are encoded into to be re-read, kept per thread so that answering
+    /// doesn't allocate a new one for every response.
+    static SCRATCH: RefCell<Vec<u8>> = RefCell::new(Vec::with_capacity(512));
+}
+
+/// How the records of an answer RRset are ordered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AnswerOrder {
+    /// As the zone stores them
+    #[default]
+    Fixed,
+    /// Rotated by one position more on every response with the RRset
+    RoundRobin,
+    /// In a random order on every response
+    Shuffle,
+    /// Addresses sharing the longest prefix with the client's first
+    Proximity,
+}
+
+impl FromStr for AnswerOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "fixed" => Ok(AnswerOrder::Fixed),
+            "round-robin" => Ok(AnswerOrder::RoundRobin),
+            "shuffle" => Ok(AnswerOrder::Shuffle),
+            "proximity" => Ok(AnswerOrder::Proximity),
+            _ => anyhow::bail!("unknown dns.answer_order order {}, expected fixed, round-robin, shuffle or proximity", s),
+        }
+    }
+}
+
+/// The answer order of a zone or a single name.
+#[derive(Clone, Debug)]
+pub struct OrderRule {
+    /// The zone the rule applies to, or the single name when `exact`
+    pub name: LowerName,
+    pub exact: bool,
+    /// The type the rule applies to, every type when unset
+    pub record_type: Option<RecordType>,
+    pub order: AnswerOrder,
+}
+
+impl TryFrom<&AnswerOrderSettings> for OrderRule {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: &AnswerOrderSettings) -> anyhow::Result<Self> {
+        let (name, exact) = match (&cfg.zone, &cfg.name) {
+            (Some(zone), None) => (zone, false),
+            (None, Some(name)) => (name, true),
+            _ => anyhow::bail!("dns.answer_order entries need either a zone or a name"),
+        };
+        let mut name = hickory_proto::rr::Name::from_ascii(name).map_err(|e| anyhow::anyhow!("invalid dns.answer_order name {}: {}", name, e))?;
+        name.set_fqdn(true);
+        let record_type = match &cfg.record_type {
+            Some(record_type) => Some(
+                RecordType::from_str(&record_type.to_ascii_uppercase())
+                    .map_err(|e| anyhow::anyhow!("invalid dns.answer_order record_type {}: {}", record_type, e))?,
+            ),
+            None => None,
+        };
+        Ok(OrderRule {
+            name: LowerName::new(&name),
+            exact,
+            record_type,
+            order: cfg.order.parse()?,
+        })
+    }
+}
+
+/// The answer order policy: the rules of zones and names, and the order of the RRsets
+/// none covers.
+#[derive(Clone, Debug, Default)]
+pub struct AnswerOrderOptions {
+    pub default: AnswerOrder,
+    pub rules: Arc<Vec<OrderRule>>,
+}
+
+impl AnswerOrderOptions {
+    /// The policy of `dns.round_robin` and the `dns.answer_order` entries.
+    pub fn parse(round_robin: bool, rules: &[AnswerOrderSettings]) -> anyhow::Result<Self> {
+        Ok(AnswerOrderOptions {
+            default: if round_robin { AnswerOrder::RoundRobin } else { AnswerOrder::Fixed },
+            rules: Arc::new(rules.iter().map(OrderRule::try_from).collect::<anyhow::Result<_>>()?),
+        })
+    }
+
+    /// Whether every answer is sent in the order it is stored.
+    pub fn is_fixed(&self) -> bool {
+        self.default == AnswerOrder::Fixed && self.rules.iter().all(|rule| rule.order == AnswerOrder::Fixed)
+    }
+
+    /// The order of the RRset of `name` and `record_type`: that of the most specific rule
+    /// covering it, a name's over its zones', a longer zone's over a shorter one's, and a
+    /// rule for the type over one for every type.
+    pub fn order_of(&self, name: &LowerName, record_type: RecordType) -> AnswerOrder {
+        self.rules
+            .iter()
+            .filter(|rule| if rule.exact { rule.name == *name } else { rule.name.zone_of(name) })
+            .filter(|rule| rule.record_type.is_none_or(|rule_type| rule_type == record_type))
+            .max_by_key(|rule| (rule.exact, rule.name.num_labels(), rule.record_type.is_some()))
+            .map_or(self.default, |rule| rule.order)
+    }
+}
+
+/// How far round-robin RRsets have been rotated, kept across responses. RRsets share a
+/// fixed number of counters by the hash of their name and type, so memory doesn't grow
+/// with the names answered; those sharing one just rotate less evenly.
+pub struct Rotations {
+    counters: Box<[AtomicUsize]>,
+}
+
+impl Default for Rotations {
+    fn default() -> Self {
+        Rotations {
+            counters: (0..ROTATION_COUNTERS).map(|_| AtomicUsize::new(0)).collect(),
+        }
+    }
+}
+
+impl Rotations {
+    /// The offset to rotate the RRset of `name` and `record_type` by this time.
+    fn next(&self, name: &LowerName, record_type: RecordType) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (name, record_type).hash(&mut hasher);
+        self.counters[hasher.finish() as usize % self.counters.len()].fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Response handler that orders each answer RRset as its policy says before sending it
+/// on.
+#[derive(Clone)]
+pub struct OrderResponseHandler<R> {
+    inner: R,
+    options: AnswerOrderOptions,
+    rotations: Arc<Rotations>,
+    /// The address `proximity` orders by
+    client: IpAddr,
+}
+
+impl<R> OrderResponseHandler<R> {
+    pub fn new(inner: R, options: AnswerOrderOptions, rotations: Arc<Rotations>, client: IpAddr) -> Self {
+        Self {
+            inner,
+            options,
+            rotations,
+            client,
+        }
+    }
+}
+
+/// Orders each run of records sharing a name and type as `options` say, keeping the
+/// order of the runs themselves so CNAME chains stay intact.
+fn order<'r>(records: &'r [Record], options: &AnswerOrderOptions, rotations: &Rotations, client: IpAddr) -> Vec<&'r Record> {
+    let mut ordered = Vec::with_capacity(records.len());
+    for rrset in records.chunk_by(|a, b| a.name() == b.name() && a.record_type() == b.record_type()) {
+        let mut rrset: Vec<&Record> = rrset.iter().collect();
+        if rrset.len() > 1 {
+            let (name, record_type) = (LowerName::new(rrset[0].name()), rrset[0].record_type());
+            match options.order_of(&name, record_type) {
+                AnswerOrder::Fixed => {}
+                AnswerOrder::RoundRobin => {
+                    let split = rotations.next(&name, record_type) % rrset.len();
+                    rrset.rotate_left(split);
+                }
+                AnswerOrder::Shuffle => rrset.shuffle(&mut rand::thread_rng()),
+                // A stable sort, so addresses as close keep their stored order
+                AnswerOrder::Proximity => rrset.sort_by_key(|record| Reverse(shared_prefix(record, client))),
+            }
+        }
+        ordered.extend(rrset);
+    }
+    ordered
+}
+
+/// The leading bits the address of `record` shares with `client`; none for records of
+/// other types or the other family.
+fn shared_prefix(record: &Record, client: IpAddr) -> u32 {
+    match (record.data(), client) {
+        (Some(RData::A(address)), IpAddr::V4(client)) => (u32::from(address.0) ^ u32::from(client)).leading_zeros(),
+        (Some(RData::AAAA(address)), IpAddr::V6(client)) => (u128::from(address.0) ^ u128::from(client)).leading_zeros(),
+        _ => 0,
+    }
+}
+
+/// Encodes `response` and reads it back as a message whose sections can be inspected,
+/// for the response handlers that rebuild responses. Shared by those handlers.
+pub fn reread<'a, A, N, S, D>(response: MessageResponse<'_, 'a, A, N, S, D>) -> std::io::Result<MessageRequest>
+where
+    A: Iterator<Item = &'a Record> + Send + 'a,
+    N: Iterator<Item = &'a Record> + Send + 'a,
+    S: Iterator<Item = &'a Record> + Send + 'a,
+    D: Iterator<Item = &'a Record> + Send + 'a,
+{
+    SCRATCH.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        buffer.clear();
+        response
+            .destructive_emit(&mut BinEncoder::new(&mut buffer))
+            .map_err(std::io::Error::other)?;
+        MessageRequest::from_bytes(&buffer).map_err(std::io::Error::other)
+    })
+}
+
+/// Sends `response` through `inner` with its answer section replaced by
+/// `rewrite(answers)`. Shared by the handlers that steer which answers are served.
+pub async fn rewrite_answers<'a, R, A, N, S, D, F>(
+    inner: &mut R,
+    response: MessageResponse<'_, 'a, A, N, S, D>,
+    rewrite: F,
+) -> std::io::Result<ResponseInfo>
+where
+    R: ResponseHandler,
+    A: Iterator<Item = &'a Record> + Send + 'a,
+    N: Iterator<Item = &'a Record> + Send + 'a,
+    S: Iterator<Item = &'a Record> + Send + 'a,
+    D: Iterator<Item = &'a Record> + Send + 'a,
+    F: for<'r> FnOnce(&'r [Record]) -> Vec<&'r Record>,
+{
+    // The answer iterators can't be inspected in place, so the response is encoded and
+    // re-read, then rebuilt with the rewritten answers
+    let message = reread(response)?;
+
+    let mut builder = MessageResponseBuilder::from_message_request(&message);
+    if let Some(edns) = message.edns() {
+        builder.edns(edns.clone());
+    }
+    let response = builder.build(
+        *message.header(),
+        rewrite(message.answers()),
+        message.name_servers(),
+        [],
+        message.additionals().iter().chain(message.sig0()),
+    );
+    inner.send_response(response).await
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for OrderResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let (options, rotations, client) = (self.options.clone(), self.rotations.clone(), self.client);
+        rewrite_answers(&mut self.inner, response, move |answers| order(answers, &options, &rotations, client)).await
+    }
+}
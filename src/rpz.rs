@@ -0,0 +1,224 @@
+//! Response Policy Zones (RPZ).
+//!
+//! A policy zone is a zone file whose owner names, relative to its origin, are the query
+//! names it rewrites, e.g. `ads.example.net.rpz.local.` for `ads.example.net`, or
+//! `*.example.net.rpz.local.` for every name below `example.net`. Each name's records
+//! give the action taken for queries of it:
+//!
+//! - `CNAME .` answers NXDOMAIN;
+//! - `CNAME *.` answers NODATA;
+//! - `CNAME rpz-passthru.` exempts the name from rewriting by later rules and zones;
+//! - `CNAME rpz-drop.` sends no response at all;
+//! - any other records are Local-Data, answered in place of the real records.
+//!
+//! Zones are checked in the order they are configured and the first one with a rule for
+//! the name wins; within a zone an exact name wins over a wildcard, and a more specific
+//! wildcard over a less specific one. Only QNAME triggers are supported: the IP, NSIP,
+//! NSDNAME and client IP triggers of a zone are skipped with a warning. Policy zones are
+//! read again whenever the config is reloaded, so feeds can be updated in place.
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::dns;
+use crate::settings::RpzSettings;
+use crate::zonefile;
+
+/// Rightmost trigger labels of the trigger types that aren't supported.
+const UNSUPPORTED_TRIGGERS: [&[u8]; 4] = [b"rpz-ip", b"rpz-nsip", b"rpz-nsdname", b"rpz-client-ip"];
+
+/// A policy zone file loaded at startup and on every reload.
+#[derive(Clone)]
+pub struct RpzOptions {
+    /// Origin of the policy zone; taken from the file's `$ORIGIN` when unset.
+    pub origin: Option<String>,
+    pub path: PathBuf,
+}
+
+impl From<RpzSettings> for RpzOptions {
+    fn from(cfg: RpzSettings) -> Self {
+        RpzOptions {
+            origin: cfg.origin,
+            path: PathBuf::from(cfg.path),
+        }
+    }
+}
+
+/// The action a policy zone takes for a query name.
+pub enum Rule {
+    NxDomain,
+    NoData,
+    Passthru,
+    Drop,
+    /// Records answered instead, with the policy zone's trigger as their owner.
+    LocalData(Vec<Record>),
+}
+
+impl Rule {
+    /// The action a record of a policy zone stands for, or `None` for Local-Data.
+    fn action(record: &Record) -> Option<Self> {
+        let Some(RData::CNAME(target)) = record.data() else {
+            return None;
+        };
+        match target.0.to_lowercase().to_ascii().as_str() {
+            "." => Some(Rule::NxDomain),
+            "*." => Some(Rule::NoData),
+            "rpz-passthru." => Some(Rule::Passthru),
+            "rpz-drop." => Some(Rule::Drop),
+            _ => None,
+        }
+    }
+}
+
+/// The rules of one policy zone.
+pub struct PolicyZone {
+    pub origin: Name,
+    /// Rules by the query name they apply to.
+    names: HashMap<LowerName, Rule>,
+    /// Rules by the domain whose subdomains they apply to.
+    wildcards: HashMap<LowerName, Rule>,
+}
+
+impl PolicyZone {
+    /// Reads the policy zone `options` names.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can't be read or isn't a valid zone file.
+    pub async fn load(options: &RpzOptions) -> anyhow::Result<Self> {
+        let content = tokio::fs::read_to_string(&options.path)
+            .await
+            .map_err(|e| anyhow::anyhow!("can't read policy zone {}: {}", options.path.display(), e))?;
+        let origin = match &options.origin {
+            Some(origin) => {
+                let mut origin = Name::from_ascii(origin)?;
+                origin.set_fqdn(true);
+                Some(origin)
+            }
+            None => None,
+        };
+        let (origin, records) = zonefile::parse(&content, origin, Some(options.path.clone()))?;
+
+        let mut zone = Self {
+            origin,
+            names: HashMap::new(),
+            wildcards: HashMap::new(),
+        };
+        let mut skipped = 0;
+        for record in records {
+            if !zone.add(record) {
+                skipped += 1;
+            }
+        }
+        println!(
+            "Loaded {} policy rules for {} from {}",
+            zone.names.len() + zone.wildcards.len(),
+            zone.origin,
+            options.path.display()
+        );
+        if skipped > 0 {
+            eprintln!("Skipped {} records of policy zone {} with unsupported triggers", skipped, zone.origin);
+        }
+        Ok(zone)
+    }
+
+    /// Adds the rule `record` stands for, returning false if its trigger isn't
+    /// supported. The zone's own SOA and NS records are ignored.
+    fn add(&mut self, record: Record) -> bool {
+        let labels: Vec<&[u8]> = record.name().iter().collect();
+        let origin_labels = self.origin.iter().count();
+        if !self.origin.zone_of(record.name()) || labels.len() <= origin_labels {
+            return true;
+        }
+        let trigger = &labels[..labels.len() - origin_labels];
+        let unsupported = trigger
+            .last()
+            .is_some_and(|last| UNSUPPORTED_TRIGGERS.iter().any(|kind| last.eq_ignore_ascii_case(kind)));
+        if unsupported {
+            return false;
+        }
+        let (rules, trigger) = match trigger.split_first() {
+            Some((&b"*", parent)) => (&mut self.wildcards, parent),
+            _ => (&mut self.names, trigger),
+        };
+        let Ok(mut name) = Name::from_labels(trigger.iter().copied()) else {
+            return false;
+        };
+        name.set_fqdn(true);
+
+        let key = LowerName::new(&name);
+        match Rule::action(&record) {
+            Some(action) => {
+                rules.insert(key, action);
+            }
+            // An explicit action overrides any Local-Data of the same name
+            None => {
+                if let Rule::LocalData(records) = rules.entry(key).or_insert_with(|| Rule::LocalData(Vec::new())) {
+                    records.push(record);
+                }
+            }
+        }
+        true
+    }
+
+    /// The rule of the zone for `name`, if any.
+    fn rule(&self, name: &LowerName) -> Option<&Rule> {
+        if let Some(rule) = self.names.get(name) {
+            return Some(rule);
+        }
+        let mut parent = name.clone();
+        while !parent.is_root() {
+            parent = parent.base_name();
+            if let Some(rule) = self.wildcards.get(&parent) {
+                return Some(rule);
+            }
+        }
+        None
+    }
+}
+
+/// Reads every policy zone of `options`, in order.
+///
+/// # Errors
+///
+/// Returns an error if any zone fails to load.
+pub async fn load_all(options: &[RpzOptions]) -> anyhow::Result<Vec<PolicyZone>> {
+    let mut zones = Vec::with_capacity(options.len());
+    for zone in options {
+        zones.push(PolicyZone::load(zone).await?);
+    }
+    Ok(zones)
+}
+
+/// The rule rewriting queries for `name`, or `None` if they are answered as usual,
+/// because no zone has a rule for them or the first one that does passes them through.
+pub fn rewrite<'z>(zones: &'z [PolicyZone], name: &LowerName) -> Option<&'z Rule> {
+    let rule = zones.iter().find_map(|zone| zone.rule(name))?;
+    (!matches!(rule, Rule::Passthru)).then_some(rule)
+}
+
+/// Answers `request` as `rule` rewrites it.
+pub async fn send_rewritten<R: ResponseHandler>(request: &Request, rule: &Rule, response_handle: R) -> ResponseInfo {
+    match rule {
+        Rule::NxDomain => dns::send_error(request, ResponseCode::NXDomain, response_handle).await,
+        Rule::NoData | Rule::Passthru => dns::send_records(request, &[], None, response_handle).await,
+        Rule::Drop => dns::drop_response(request),
+        Rule::LocalData(records) => {
+            let name = request.query().original().name().clone();
+            let query_type = request.query().query_type();
+            let records: Vec<Record> = records
+                .iter()
+                .filter(|record| record.record_type() == query_type || record.record_type() == RecordType::CNAME)
+                .map(|record| {
+                    let mut record = record.clone();
+                    record.set_name(name.clone());
+                    record
+                })
+                .collect();
+            dns::send_records(request, &records, None, response_handle).await
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! Tokio runtimes the servers run on.
+//!
+//! By default everything shares one multi-threaded runtime with a worker per CPU. The
+//! worker and blocking thread counts can be set under `[runtime]`, and with
+//! `dedicated_dns` the DNS server gets a runtime of its own, so that control-plane work
+//! such as API calls, health checks and persistence never competes with queries for
+//! workers. Its threads can then be pinned to dedicated cores from outside, e.g. with
+//! `taskset`, as they are named `rdns-dns`.
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::settings::RuntimeSettings;
+
+/// Config options for the runtimes
+#[derive(Debug, Clone)]
+pub struct RuntimeOptions {
+    /// Workers of the main runtime; one per CPU when unset.
+    pub worker_threads: Option<usize>,
+    /// Upper bound on the threads of each runtime's blocking pool.
+    pub max_blocking_threads: usize,
+    /// Whether the DNS server runs on a runtime of its own.
+    pub dedicated_dns: bool,
+    /// Workers of the DNS runtime; one per CPU when unset.
+    pub dns_worker_threads: Option<usize>,
+}
+
+impl From<RuntimeSettings> for RuntimeOptions {
+    fn from(cfg: RuntimeSettings) -> Self {
+        RuntimeOptions {
+            worker_threads: Some(cfg.worker_threads).filter(|&threads| threads > 0),
+            max_blocking_threads: cfg.max_blocking_threads.max(1),
+            dedicated_dns: cfg.dedicated_dns,
+            dns_worker_threads: Some(cfg.dns_worker_threads).filter(|&threads| threads > 0),
+        }
+    }
+}
+
+impl RuntimeOptions {
+    /// Builds the runtime everything but a dedicated DNS server runs on.
+    pub fn main_runtime(&self) -> std::io::Result<Runtime> {
+        self.build("rdns", self.worker_threads)
+    }
+
+    /// Builds the runtime of the DNS server, if it gets one of its own.
+    pub fn dns_runtime(&self) -> std::io::Result<Option<Runtime>> {
+        match self.dedicated_dns {
+            true => self.build("rdns-dns", self.dns_worker_threads).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    fn build(&self, name: &str, worker_threads: Option<usize>) -> std::io::Result<Runtime> {
+        let mut builder = Builder::new_multi_thread();
+        builder
+            .enable_all()
+            .thread_name(name)
+            .max_blocking_threads(self.max_blocking_threads);
+        if let Some(threads) = worker_threads {
+            builder.worker_threads(threads);
+        }
+        builder.build()
+    }
+}
@@ -0,0 +1,136 @@
+//! Scheduled record changes.
+//!
+//! An `AddRecord` or `DeleteRecord` given an `effective_at` in the future isn't applied
+//! right away but queued, for cutovers coordinated with other systems, and a scheduler
+//! task applies the queued changes once they are due, in the order they are due. Until
+//! then they are listed with `ListPendingChanges` and may be cancelled. A change is
+//! checked when it is queued, but applied as the call would have been at its time, so it
+//! may still fail then, e.g. when its zone was deleted in the meantime; it is logged and
+//! dropped. Changes to a frozen zone wait for it to be thawed.
+//!
+//! Pending changes are persisted and replicated along with the records of their zone,
+//! and only the leader applies them.
+
+use hickory_proto::rr::LowerName;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+
+use crate::dns::DnsState;
+use crate::metadata::RecordMetadata;
+
+/// How often due changes are looked for.
+const APPLY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a scheduled change does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduledKind {
+    /// Adds a record, like `AddRecord`.
+    Add,
+    /// Deletes the records of a name and type, like `DeleteRecord`.
+    Delete,
+}
+
+/// A change queued until `effective_at`, as persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledChange {
+    pub id: u64,
+    pub kind: ScheduledKind,
+    pub name: String,
+    pub record_type: String,
+    /// Record data in zone file syntax, for additions
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub value: String,
+    #[serde(default)]
+    pub ttl: u32,
+    /// When the added record's lease lapses, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<SystemTime>,
+    /// Metadata the added record is given, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<RecordMetadata>,
+    pub effective_at: SystemTime,
+}
+
+impl fmt::Display for ScheduledChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ScheduledKind::Add => write!(f, "add {} {} {}", self.name, self.record_type, self.value),
+            ScheduledKind::Delete => write!(f, "delete {} {}", self.name, self.record_type),
+        }
+    }
+}
+
+/// The pending changes, by id.
+#[derive(Default)]
+pub struct Schedule {
+    changes: Mutex<BTreeMap<u64, ScheduledChange>>,
+    /// Id of the last change queued, so that ids aren't reused once changes are gone.
+    last_id: AtomicU64,
+}
+
+impl Schedule {
+    /// Queues `change`, giving it the next id, which is returned.
+    pub fn add(&self, mut change: ScheduledChange) -> u64 {
+        change.id = self.last_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let id = change.id;
+        self.changes.lock().unwrap().insert(id, change);
+        id
+    }
+
+    /// Queues `change` under the id it has, e.g. as loaded from a snapshot.
+    pub fn insert(&self, change: ScheduledChange) {
+        self.last_id.fetch_max(change.id, Ordering::Relaxed);
+        self.changes.lock().unwrap().insert(change.id, change);
+    }
+
+    /// Takes the change `id` out of the queue.
+    pub fn remove(&self, id: u64) -> Option<ScheduledChange> {
+        self.changes.lock().unwrap().remove(&id)
+    }
+
+    /// Drops the changes of the names in the zone at `origin` and its subzones.
+    pub fn remove_zone(&self, origin: &LowerName) {
+        self.changes
+            .lock()
+            .unwrap()
+            .retain(|_, change| !DnsState::parse_name(&change.name).is_ok_and(|name| origin.zone_of(&LowerName::new(&name))));
+    }
+
+    pub fn clear(&self) {
+        self.changes.lock().unwrap().clear();
+    }
+
+    /// Every pending change, in the order they are due.
+    pub fn entries(&self) -> Vec<ScheduledChange> {
+        let mut changes: Vec<ScheduledChange> = self.changes.lock().unwrap().values().cloned().collect();
+        changes.sort_by_key(|change| (change.effective_at, change.id));
+        changes
+    }
+
+    /// The changes due by `now`, in the order they are due.
+    pub fn due(&self, now: SystemTime) -> Vec<ScheduledChange> {
+        self.entries().into_iter().take_while(|change| change.effective_at <= now).collect()
+    }
+}
+
+/// Applies the changes that are due, every second. Runs until the process exits.
+pub async fn run_scheduler(state: Arc<RwLock<DnsState>>) {
+    let mut interval = tokio::time::interval(APPLY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now();
+        let due = {
+            let state = state.read().await;
+            !state.is_follower() && state.has_due_changes(now)
+        };
+        if due {
+            state.write().await.apply_scheduled(now).await;
+        }
+    }
+}
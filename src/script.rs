@@ -0,0 +1,379 @@
+//! Per-query scripting hooks.
+//!
+//! With `[dns.script]` configured, queries are handed to a Rhai script as the `script`
+//! stage of the pipeline, so that policies the built-in stages don't cover can be written
+//! without changing rdns. The script may define two functions, each given the query as a
+//! map with its `name`, lower-cased and fully qualified like `www.example.com.`, its
+//! `type` like `"AAAA"`, the `client` address and the `protocol` like `"udp"`:
+//!
+//! - `query(q)` runs before lookup, at the stage's place in the pipeline. Returning `()`
+//!   passes the query on to the next stage, `"nxdomain"`, `"refuse"` or `"servfail"`
+//!   blocks it with that response code, and an array of records answers it with them.
+//! - `answer(q, records)` runs after lookup on the answers of the hosted zones, the
+//!   response cache and the forwarder, with the records of the answer section and the
+//!   response code as `q.code`, like `"NXDOMAIN"`. Returning `()` leaves the answer as
+//!   it is, a response code replaces it as above, and an array of records replaces the
+//!   answer section, which rewrites, removes or adds records; answering a negative
+//!   response with records makes it a NOERROR answer.
+//!
+//! Records are maps with a `type` and a `value` in master-file syntax, and optionally a
+//! `name`, that of the query by default, and a `ttl`, `dns.script.ttl` by default:
+//!
+//! ```text
+//! fn query(q) {
+//!     if q.name.ends_with(".corp.example.com.") && !q.client.starts_with("10.") {
+//!         return "refuse";
+//!     }
+//!     if q.name == "wpad.example.com." {
+//!         return [#{ type: "A", value: "10.0.0.80", ttl: 60 }];
+//!     }
+//! }
+//!
+//! fn answer(q, records) {
+//!     if q.code == "NOERROR" && q.type == "TXT" {
+//!         records.push(#{ type: "TXT", value: "\"served by rdns\"" });
+//!         return records;
+//!     }
+//! }
+//! ```
+//!
+//! Only the functions are called; statements outside of them don't run. A hook that
+//! fails, returns anything else or runs more than `max_operations` operations is logged
+//! and treated as if it had returned `()`. Hooks run on the threads for blocking work,
+//! so a slow one holds up its own query only. The script is read at startup and again on
+//! every reload, and the answer hook only runs while the `script` stage is in the
+//! pipeline.
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{Name, Record, RecordType};
+use hickory_server::authority::{MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tonic::async_trait;
+
+use crate::dns::{self, DnsState};
+use crate::roundrobin;
+use crate::settings::ScriptSettings;
+use crate::zonefile;
+
+/// Longest string a hook may build.
+const MAX_STRING_SIZE: usize = 64 * 1024;
+/// Most elements an array or map built by a hook may hold.
+const MAX_COLLECTION_SIZE: usize = 1024;
+/// Deepest a hook may nest function calls.
+const MAX_CALL_LEVELS: usize = 32;
+
+/// A compiled script and the hooks it defines.
+pub struct Script {
+    /// Path the script was read from, for the logs
+    path: String,
+    engine: Engine,
+    ast: AST,
+    /// TTL of the records the hooks return without one
+    ttl: u32,
+    /// Whether the script defines `query(q)`
+    query_hook: bool,
+    /// Whether the script defines `answer(q, records)`
+    answer_hook: bool,
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Script")
+            .field("path", &self.path)
+            .field("ttl", &self.ttl)
+            .field("query_hook", &self.query_hook)
+            .field("answer_hook", &self.answer_hook)
+            .finish()
+    }
+}
+
+impl TryFrom<ScriptSettings> for Script {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ScriptSettings) -> anyhow::Result<Self> {
+        if cfg.max_operations == 0 {
+            anyhow::bail!("dns.script.max_operations must be at least 1");
+        }
+        let source = std::fs::read_to_string(&cfg.path).map_err(|e| anyhow::anyhow!("failed to read dns.script {}: {}", cfg.path, e))?;
+        let mut engine = Engine::new();
+        engine
+            .set_max_operations(cfg.max_operations)
+            .set_max_call_levels(MAX_CALL_LEVELS)
+            .set_max_string_size(MAX_STRING_SIZE)
+            .set_max_array_size(MAX_COLLECTION_SIZE)
+            .set_max_map_size(MAX_COLLECTION_SIZE);
+        let ast = engine
+            .compile(&source)
+            .map_err(|e| anyhow::anyhow!("invalid dns.script {}: {}", cfg.path, e))?;
+        let defines = |name: &str, params: usize| ast.iter_functions().any(|f| f.name == name && f.params.len() == params);
+        let (query_hook, answer_hook) = (defines("query", 1), defines("answer", 2));
+        if !query_hook && !answer_hook {
+            anyhow::bail!("dns.script {} defines neither query(q) nor answer(q, records)", cfg.path);
+        }
+        Ok(Script {
+            path: cfg.path,
+            engine,
+            ast,
+            ttl: cfg.ttl,
+            query_hook,
+            answer_hook,
+        })
+    }
+}
+
+/// A query, as the hooks are given it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptQuery {
+    /// Name queried, as the client wrote it
+    pub name: Name,
+    pub record_type: RecordType,
+    pub client: IpAddr,
+    /// Transport the query came over, like "udp"
+    pub protocol: String,
+}
+
+impl ScriptQuery {
+    /// The query of `request`.
+    pub fn of_request(request: &Request) -> Self {
+        ScriptQuery {
+            name: request.query().original().name().clone(),
+            record_type: request.query().query_type(),
+            client: request.src().ip(),
+            protocol: request.protocol().to_string().to_ascii_lowercase(),
+        }
+    }
+
+    /// The map the hooks are given, with the response code of the answer if there is one.
+    fn to_map(&self, code: Option<ResponseCode>) -> Map {
+        let mut map = Map::new();
+        map.insert("name".into(), self.name.to_lowercase().to_string().into());
+        map.insert("type".into(), self.record_type.to_string().into());
+        map.insert("client".into(), self.client.to_string().into());
+        map.insert("protocol".into(), self.protocol.clone().into());
+        if let Some(code) = code {
+            // The variant names are the mnemonics, like NXDomain for NXDOMAIN
+            map.insert("code".into(), format!("{:?}", code).to_ascii_uppercase().into());
+        }
+        map
+    }
+}
+
+/// What a hook answers a query with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// A response with this code and no records
+    Respond(ResponseCode),
+    /// A NOERROR response with these records
+    Answer(Vec<Record>),
+}
+
+impl Script {
+    /// Whether the script defines a hook run before lookup.
+    pub fn hooks_queries(&self) -> bool {
+        self.query_hook
+    }
+
+    /// Whether the script defines a hook run on answers.
+    pub fn hooks_answers(&self) -> bool {
+        self.answer_hook
+    }
+
+    /// Runs the `query` hook, returning the reply it answers `query` with, or `None`
+    /// when it passes the query on.
+    pub fn query(&self, query: &ScriptQuery) -> Option<Reply> {
+        if !self.query_hook {
+            return None;
+        }
+        let result = self.call("query", (query.to_map(None),));
+        self.reply("query", query, result)
+    }
+
+    /// Runs the `answer` hook on an answer to `query` with the response `code` and the
+    /// `answers`, returning the reply replacing it, or `None` when it is left as it is.
+    pub fn answer(&self, query: &ScriptQuery, code: ResponseCode, answers: &[Record]) -> Option<Reply> {
+        if !self.answer_hook {
+            return None;
+        }
+        let records: Array = answers.iter().filter_map(record_map).map(Dynamic::from_map).collect();
+        let result = self.call("answer", (query.to_map(Some(code)), records));
+        self.reply("answer", query, result)
+    }
+
+    /// Runs the `query` hook like `query`, on a thread for blocking work: a hook may run
+    /// up to `max_operations` operations, which mustn't hold up the runtime threads
+    /// answering other queries.
+    pub async fn spawn_query(self: Arc<Self>, query: ScriptQuery) -> Option<Reply> {
+        if !self.query_hook {
+            return None;
+        }
+        spawn(move || self.query(&query)).await
+    }
+
+    /// Runs the `answer` hook like `answer`, on a thread for blocking work.
+    pub async fn spawn_answer(self: Arc<Self>, query: ScriptQuery, code: ResponseCode, answers: Vec<Record>) -> Option<Reply> {
+        if !self.answer_hook {
+            return None;
+        }
+        spawn(move || self.answer(&query, code, &answers)).await
+    }
+
+    fn call(&self, hook: &str, args: impl FuncArgs) -> Result<Dynamic, Box<EvalAltResult>> {
+        let options = CallFnOptions::new().eval_ast(false);
+        self.engine.call_fn_with_options(options, &mut Scope::new(), &self.ast, hook, args)
+    }
+
+    /// The reply a hook returned, logging the failures and unusable results.
+    fn reply(&self, hook: &str, query: &ScriptQuery, result: Result<Dynamic, Box<EvalAltResult>>) -> Option<Reply> {
+        let result = result.map_err(|e| e.to_string()).and_then(|result| self.parse_reply(query, result));
+        match result {
+            Ok(reply) => reply,
+            Err(e) => {
+                eprintln!("Script {} hook of {} failed for {} {}: {}", hook, self.path, query.name, query.record_type, e);
+                None
+            }
+        }
+    }
+
+    fn parse_reply(&self, query: &ScriptQuery, result: Dynamic) -> Result<Option<Reply>, String> {
+        if result.is_unit() {
+            return Ok(None);
+        }
+        if result.is_string() {
+            let action = result.into_string()?;
+            let code = match action.to_ascii_lowercase().as_str() {
+                "nxdomain" => ResponseCode::NXDomain,
+                "refuse" => ResponseCode::Refused,
+                "servfail" => ResponseCode::ServFail,
+                _ => return Err(format!("unknown action {}, expected nxdomain, refuse or servfail", action)),
+            };
+            return Ok(Some(Reply::Respond(code)));
+        }
+        if result.is_array() {
+            let records = result
+                .into_array()?
+                .into_iter()
+                .map(|record| self.parse_record(query, record))
+                .collect::<Result<_, _>>()?;
+            return Ok(Some(Reply::Answer(records)));
+        }
+        Err(format!("expected (), an action or an array of records, got {}", result.type_name()))
+    }
+
+    fn parse_record(&self, query: &ScriptQuery, record: Dynamic) -> Result<Record, String> {
+        let type_name = record.type_name();
+        let record = record.try_cast::<Map>().ok_or_else(|| format!("expected records as maps, got {}", type_name))?;
+        let text = |key: &str| -> Result<Option<String>, String> {
+            record
+                .get(key)
+                .map(|value| value.clone().into_string().map_err(|got| format!("expected record {} as a string, got {}", key, got)))
+                .transpose()
+        };
+        let name = text("name")?.unwrap_or_else(|| query.name.to_string());
+        let record_type = text("type")?.ok_or("record without a type")?;
+        let value = text("value")?.ok_or("record without a value")?;
+        let ttl = match record.get("ttl") {
+            Some(ttl) => {
+                let ttl = ttl.as_int().map_err(|got| format!("expected record ttl as an integer, got {}", got))?;
+                u32::try_from(ttl).map_err(|_| format!("record ttl {} out of range", ttl))?
+            }
+            None => self.ttl,
+        };
+        DnsState::build_record(name, record_type, value, ttl).map_err(|e| e.to_string())
+    }
+}
+
+/// A record as the hooks are given it, `None` for records without data.
+fn record_map(record: &Record) -> Option<Map> {
+    let mut map = Map::new();
+    map.insert("name".into(), record.name().to_string().into());
+    map.insert("type".into(), record.record_type().to_string().into());
+    map.insert("ttl".into(), Dynamic::from_int(record.ttl().into()));
+    map.insert("value".into(), zonefile::format_rdata(record.data()?).into());
+    Some(map)
+}
+
+/// Sends the reply of the query hook.
+pub async fn send_reply<R: ResponseHandler>(request: &Request, reply: Reply, response_handle: R) -> ResponseInfo {
+    match reply {
+        Reply::Respond(code) => dns::send_error(request, code, response_handle).await,
+        Reply::Answer(records) => dns::send_records(request, &records, None, response_handle).await,
+    }
+}
+
+/// Runs `hook` on a thread for blocking work, treating a hook that panicked as one that
+/// returned `()`.
+async fn spawn(hook: impl FnOnce() -> Option<Reply> + Send + 'static) -> Option<Reply> {
+    tokio::task::spawn_blocking(hook).await.unwrap_or_else(|e| {
+        eprintln!("Script hook failed: {}", e);
+        None
+    })
+}
+
+/// Response handler running the answer hook of a script on the responses sent.
+#[derive(Clone)]
+pub struct ScriptResponseHandler<R> {
+    inner: R,
+    hook: Option<(Arc<Script>, ScriptQuery)>,
+}
+
+impl<R> ScriptResponseHandler<R> {
+    /// Runs the answer hook of `script`, if given, on the responses to `request`.
+    pub fn new(inner: R, script: Option<Arc<Script>>, request: &Request) -> Self {
+        let hook = script.map(|script| (script, ScriptQuery::of_request(request)));
+        Self { inner, hook }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for ScriptResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        let Some((script, query)) = &self.hook else {
+            return self.inner.send_response(response).await;
+        };
+        let message = roundrobin::reread(response)?;
+        let mut header = *message.header();
+        let reply = script
+            .clone()
+            .spawn_answer(query.clone(), header.response_code(), message.answers().to_vec())
+            .await;
+
+        let mut builder = MessageResponseBuilder::from_message_request(&message);
+        if let Some(edns) = message.edns() {
+            builder.edns(edns.clone());
+        }
+        let additionals = message.additionals().iter().chain(message.sig0());
+        match reply {
+            None => {
+                let response = builder.build(header, message.answers(), message.name_servers(), [], additionals);
+                self.inner.send_response(response).await
+            }
+            Some(Reply::Respond(code)) => {
+                header.set_response_code(code);
+                self.inner.send_response(builder.build_no_records(header)).await
+            }
+            Some(Reply::Answer(records)) => {
+                // The authority section of a negative answer holds its proof, which the
+                // records now contradict
+                let negative = header.response_code() != ResponseCode::NoError || message.answers().is_empty();
+                let name_servers = if negative { &[][..] } else { message.name_servers() };
+                header.set_response_code(ResponseCode::NoError);
+                let response = builder.build(header, records.iter(), name_servers, [], additionals);
+                self.inner.send_response(response).await
+            }
+        }
+    }
+}
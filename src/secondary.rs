@@ -0,0 +1,200 @@
+//! Secondary zones.
+//!
+//! A secondary zone is pulled from its primary with AXFR and served read-only. A
+//! background task per zone follows the zone's SOA timers: it compares serials with the
+//! primary every `refresh` seconds, retries every `retry` seconds after a failed refresh,
+//! and stops serving the zone once `expire` seconds pass without a successful one. A
+//! NOTIFY (RFC 1996) for the zone from its primary's address, signed with the zone's
+//! `tsig_key` if it names one, makes the task refresh the zone right away instead.
+//!
+//! A zone unrefreshed for longer than `refresh` and `retry` together has missed a refresh
+//! and its retry, and is reported as stale ahead of its expiry: with a `zone.stale`
+//! change notification and the `rdns_secondary_zone_stale` metric. Its expiry is reported
+//! as `zone.expired` and `rdns_secondary_zone_expired`, and the next successful refresh
+//! of a stale or expired zone as `zone.recovered`.
+
+use futures_util::StreamExt;
+use hickory_client::client::{AsyncClient, ClientHandle};
+use hickory_client::tcp::TcpClientStream;
+use hickory_proto::iocompat::AsyncIoTokioAsStd;
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::rdata::SOA;
+use hickory_proto::rr::{DNSClass, LowerName, Name, RData, RecordType};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, RwLock};
+
+use crate::dns::{self, DnsState, ZoneChange};
+use crate::settings::SecondaryZoneSettings;
+use crate::transfer;
+
+/// Retry interval used until the zone's SOA has been transferred once.
+const INITIAL_RETRY: Duration = Duration::from_secs(30);
+
+/// A zone replicated from a primary server.
+#[derive(Clone)]
+pub struct SecondaryZone {
+    pub origin: Name,
+    pub primary: SocketAddr,
+    /// Key NOTIFY messages must be signed with, if any
+    pub tsig_key: Option<LowerName>,
+    /// Wakes the sync task to refresh the zone ahead of its timers.
+    refresh_now: Arc<Notify>,
+}
+
+impl TryFrom<SecondaryZoneSettings> for SecondaryZone {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: SecondaryZoneSettings) -> anyhow::Result<Self> {
+        let mut origin = Name::from_ascii(&cfg.origin)?;
+        origin.set_fqdn(true);
+        let tsig_key = cfg
+            .tsig_key
+            .map(|key| Name::from_ascii(&key).map(|mut name| {
+                name.set_fqdn(true);
+                LowerName::new(&name)
+            }))
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("invalid tsig_key of secondary zone {}: {}", origin, e))?;
+        Ok(SecondaryZone {
+            origin,
+            primary: transfer::parse_server_addr(&cfg.primary)?,
+            tsig_key,
+            refresh_now: Arc::new(Notify::new()),
+        })
+    }
+}
+
+/// Answers a NOTIFY for a secondary zone, waking its sync task when the NOTIFY comes from
+/// the zone's primary and, if the zone names a TSIG key, is signed with it.
+pub async fn handle_notify<R: ResponseHandler>(state: &RwLock<DnsState>, request: &Request, response_handle: R) -> ResponseInfo {
+    let (zone, verified) = {
+        let state = state.read().await;
+        (state.secondary_zone(request.query().name()).cloned(), state.tsig_keys().verify(request))
+    };
+    let Some(zone) = zone else {
+        return dns::send_error(request, ResponseCode::NotAuth, response_handle).await;
+    };
+    if request.src().ip() != zone.primary.ip() {
+        eprintln!("Ignored NOTIFY for {} from {}, which is not its primary", zone.origin, request.src());
+        return dns::send_error(request, ResponseCode::Refused, response_handle).await;
+    }
+    let signature = match verified {
+        Ok(signature) => signature,
+        Err(e) => {
+            eprintln!("Rejected NOTIFY for {} from unverified client: {}", zone.origin, e);
+            return dns::send_error(request, ResponseCode::NotAuth, response_handle).await;
+        }
+    };
+    let signed_with = signature.as_ref().map(|signature| LowerName::new(signature.key_name()));
+    if zone.tsig_key.is_some() && signed_with != zone.tsig_key {
+        eprintln!("Ignored NOTIFY for {} not signed with its TSIG key", zone.origin);
+        return dns::send_error(request, ResponseCode::Refused, response_handle).await;
+    }
+
+    zone.refresh_now.notify_one();
+    dns::send_error(request, ResponseCode::NoError, response_handle).await
+}
+
+/// How far a secondary zone's sync has fallen behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Freshness {
+    Fresh,
+    Stale,
+    Expired,
+}
+
+/// Keeps a secondary zone in sync with its primary. Runs until the process exits.
+pub async fn run_zone_sync(state: Arc<RwLock<DnsState>>, zone: SecondaryZone) {
+    let metrics = state.read().await.metrics();
+    let label = zone.origin.to_string();
+    metrics.set_secondary_state(&label, false, false);
+    // SOA of the last successful refresh, and when it happened
+    let mut last_refresh: Option<(SOA, Instant)> = None;
+    let mut freshness = Freshness::Fresh;
+    loop {
+        let delay = match refresh(&state, &zone).await {
+            Ok(soa) => {
+                metrics.observe_secondary_refresh(&label, Some(SystemTime::now()));
+                if freshness != Freshness::Fresh {
+                    println!("Secondary zone {} refreshed again", zone.origin);
+                    state.read().await.report_secondary(ZoneChange::Recovered, &zone.origin);
+                    metrics.set_secondary_state(&label, false, false);
+                    freshness = Freshness::Fresh;
+                }
+                let delay = Duration::from_secs(soa.refresh().max(1) as u64);
+                last_refresh = Some((soa, Instant::now()));
+                delay
+            }
+            Err(e) => {
+                eprintln!("Failed to refresh {} from {}: {}", zone.origin, zone.primary, e);
+                metrics.observe_secondary_refresh(&label, None);
+                match &last_refresh {
+                    Some((soa, at)) => {
+                        let elapsed = at.elapsed();
+                        let retry = Duration::from_secs(soa.retry().max(1) as u64);
+                        let stale_after = Duration::from_secs(soa.refresh().max(1) as u64) + retry;
+                        let expire = Duration::from_secs(soa.expire().max(0) as u64);
+                        if elapsed >= expire && freshness < Freshness::Expired {
+                            state.write().await.expire_zone(&zone.origin).await;
+                            metrics.set_secondary_state(&label, true, true);
+                            freshness = Freshness::Expired;
+                        } else if elapsed >= stale_after && freshness < Freshness::Stale {
+                            eprintln!("Secondary zone {} is stale, unrefreshed for {}s", zone.origin, elapsed.as_secs());
+                            state.read().await.report_secondary(ZoneChange::Stale, &zone.origin);
+                            metrics.set_secondary_state(&label, true, false);
+                            freshness = Freshness::Stale;
+                        }
+                        // Retry no later than the zone expires, so that it isn't served
+                        // past its expiry
+                        match expire.checked_sub(elapsed) {
+                            Some(left) if !left.is_zero() => retry.min(left),
+                            _ => retry,
+                        }
+                    }
+                    None => INITIAL_RETRY,
+                }
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = zone.refresh_now.notified() => println!("Refreshing {} on NOTIFY from its primary", zone.origin),
+        }
+    }
+}
+
+/// Whether serial `a` is newer than serial `b`, using RFC 1982 serial arithmetic.
+fn serial_newer(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Checks the primary's serial and transfers the zone if it changed, returning the
+/// primary's current SOA.
+async fn refresh(state: &RwLock<DnsState>, zone: &SecondaryZone) -> anyhow::Result<SOA> {
+    let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(zone.primary);
+    let (mut client, background) = AsyncClient::new(stream, sender, None).await?;
+    tokio::spawn(background);
+
+    let response = client.query(zone.origin.clone(), DNSClass::IN, RecordType::SOA).await?;
+    let Some(soa) = response.answers().iter().find_map(|r| r.data().and_then(RData::as_soa)).cloned() else {
+        anyhow::bail!("primary returned no SOA ({})", response.response_code());
+    };
+
+    let current = state.read().await.zone_serial(&LowerName::new(&zone.origin)).await;
+    if current.is_some_and(|serial| !serial_newer(soa.serial(), serial)) {
+        return Ok(soa);
+    }
+
+    let mut records = Vec::new();
+    let mut transfer = client.zone_transfer(zone.origin.clone(), None);
+    while let Some(response) = transfer.next().await {
+        records.extend(response?.answers().iter().cloned());
+    }
+    let count = records.len();
+    state.write().await.load_secondary_zone(&zone.origin, records).await?;
+    println!("Transferred {} records for {} (serial {})", count, zone.origin, soa.serial());
+    Ok(soa)
+}
@@ -0,0 +1,135 @@
+//! What an instance runs.
+//!
+//! The version and the git commit the binary was built from, the optional features its
+//! config enables, named after their sections (`dns.dnssec`, `metrics`, ...), and the
+//! listeners it serves on. They are printed as a single `key=value` line when the server
+//! starts, for log pipelines to pick up, and returned by `GetServerInfo` (`rdnsctl
+//! server-info`) along with the number of zones served, so that fleet tooling can
+//! inventory instances and check that a config rollout took effect. The features follow
+//! the config reloads; the listeners are those the server was started with, since they
+//! change only with a restart.
+
+use crate::settings::Settings;
+
+/// Version of the running binary.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Git commit the binary was built from, `unknown` when built outside a checkout.
+pub const COMMIT: &str = env!("RDNS_COMMIT");
+
+/// An address the server serves a protocol on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listener {
+    /// e.g. `dns`, `dns-tls`, `grpc` or `metrics`
+    pub protocol: &'static str,
+    pub address: String,
+}
+
+/// The listeners `settings` configures.
+pub fn listeners(settings: &Settings) -> Vec<Listener> {
+    let listener = |protocol, address: &str| Listener {
+        protocol,
+        address: address.to_string(),
+    };
+    let mut listeners: Vec<Listener> = settings.dns.listen_addrs.iter().map(|addr| listener("dns", addr)).collect();
+    if let Some(tls) = &settings.dns.tls {
+        listeners.push(listener("dns-tls", &tls.listen_addr));
+    }
+    if let Some(https) = &settings.dns.https {
+        listeners.push(listener("dns-https", &format!("{}{}", https.listen_addr, https.path)));
+    }
+    listeners.push(listener("grpc", &settings.grpc.listen_addr));
+    if let Some(metrics) = &settings.metrics {
+        listeners.push(listener("metrics", &metrics.listen_addr));
+    }
+    if let Some(rest) = &settings.rest {
+        listeners.push(listener("rest", &rest.listen_addr));
+    }
+    if let Some(webhook) = &settings.webhook {
+        listeners.push(listener("webhook", &webhook.listen_addr));
+    }
+    if let Some(acme_dns) = &settings.acme.acme_dns {
+        listeners.push(listener("acme-dns", &acme_dns.listen_addr));
+    }
+    listeners
+}
+
+/// The optional features `settings` enables, by the name of their section.
+pub fn features(settings: &Settings) -> Vec<String> {
+    let dns = &settings.dns;
+    let enabled = [
+        ("dns.zone_dir", dns.zone_dir.is_some()),
+        ("dns.secondary_zones", !dns.secondary_zones.is_empty()),
+        ("dns.tls", dns.tls.is_some()),
+        ("dns.https", dns.https.is_some()),
+        ("dns.dnssec", dns.dnssec.is_some()),
+        ("dns.update", dns.update.is_some()),
+        ("dns.transfer", dns.transfer.is_some()),
+        ("dns.catalog_zone", dns.catalog_zone.is_some()),
+        ("dns.soa", dns.soa.is_some()),
+        ("dns.history", dns.history.is_some()),
+        ("dns.forward", dns.forward.is_some()),
+        ("dns.geo", dns.geo.is_some()),
+        ("dns.views", !dns.views.is_empty()),
+        ("dns.acl", dns.acl.is_some()),
+        ("dns.rate_limit", dns.rate_limit.is_some()),
+        ("dns.cookies", dns.cookies.is_some()),
+        ("dns.blocklist", dns.blocklist.is_some()),
+        ("dns.rpz", !dns.rpz.is_empty()),
+        ("dns.negative", !dns.negative.is_empty()),
+        ("dns.docker", dns.docker.is_some()),
+        ("dns.dhcp", dns.dhcp.is_some()),
+        ("dns.hosts", dns.hosts.is_some()),
+        ("dns.mdns", dns.mdns.is_some()),
+        ("dns.identity", dns.identity.is_some()),
+        ("dns.dns64", dns.dns64.is_some()),
+        ("dns.script", dns.script.is_some()),
+        ("dns.rewrite", !dns.rewrite.is_empty()),
+        ("dns.query_rules", !dns.query_rules.is_empty() || !dns.query_rule_files.is_empty()),
+        ("dns.clients", dns.clients.is_some()),
+        ("dns.stats", dns.stats.is_some()),
+        ("dns.privacy", dns.privacy.is_some()),
+        ("dns.proxy_protocol", dns.proxy_protocol.is_some()),
+        ("dns.quotas", dns.quotas.is_some()),
+        ("dns.tenants", !dns.tenants.is_empty()),
+        ("grpc.tls", settings.grpc.tls.is_some()),
+        ("grpc.auth", settings.grpc.auth.is_some()),
+        ("grpc.limits", settings.grpc.limits.is_some()),
+        ("metrics", settings.metrics.is_some()),
+        ("probe", settings.probe.is_some()),
+        ("rest", settings.rest.is_some()),
+        ("rest.ui", settings.rest.as_ref().is_some_and(|rest| rest.ui)),
+        ("audit", settings.audit.is_some()),
+        ("cluster", settings.cluster.is_some()),
+        ("webhook", settings.webhook.is_some()),
+        ("notifications", !settings.notifications.is_empty()),
+        ("events", settings.events.is_some()),
+        ("acme.acme_dns", settings.acme.acme_dns.is_some()),
+        ("acme.certificate", settings.acme.certificate.is_some()),
+        ("telemetry", settings.telemetry.is_some()),
+        ("logging.dnstap", settings.logging.dnstap.is_some()),
+        ("privileges", settings.privileges.is_some()),
+    ];
+    enabled
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(feature, _)| feature.to_string())
+        .collect()
+}
+
+/// The startup banner of a server running `settings` with `zones` zones, e.g.
+/// `rdns started version=0.1.0 commit=0123456789ab zones=2 listeners=dns:0.0.0.0:53,grpc:0.0.0.0:50051 features=metrics`.
+pub fn banner(settings: &Settings, zones: usize) -> String {
+    let listeners: Vec<String> = listeners(settings)
+        .into_iter()
+        .map(|listener| format!("{}:{}", listener.protocol, listener.address))
+        .collect();
+    format!(
+        "rdns started version={} commit={} zones={} listeners={} features={}",
+        VERSION,
+        COMMIT,
+        zones,
+        listeners.join(","),
+        features(settings).join(",")
+    )
+}
@@ -0,0 +1,147 @@
+//! Service registration.
+//!
+//! `RegisterService` publishes an instance of a service as the records DNS-SD (RFC 6763)
+//! and plain SRV clients look for, e.g. for instance `web1` of `_http._tcp.example.com.`
+//! on `web1.example.com.` port 8080:
+//!
+//! - `_http._tcp.example.com. PTR web1._http._tcp.example.com.` to browse the instances
+//! - `_http._tcp.example.com. SRV 0 0 8080 web1.example.com.` for plain SRV lookups
+//! - `web1._http._tcp.example.com.` SRV and TXT records, the TXT holding the metadata as
+//!   `key=value` strings
+//! - A and AAAA records of the host for the given addresses
+//!
+//! The records of a registration are added as one changeset and remembered along with
+//! it, so registering the instance again replaces them and `DeregisterService` takes
+//! exactly them away again, keeping those other registrations share, such as the
+//! address of a host running several services.
+
+use hickory_proto::rr::LowerName;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::RwLock;
+
+use crate::dns::{DnsState, RecordEntry};
+use crate::error::RdnsError;
+
+/// A registered instance of a service, as managed through the control API and persisted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceEntry {
+    /// Instance name, unique within the service, e.g. `web1`
+    pub instance: String,
+    /// Service name without the leading underscore, e.g. `http`
+    pub service: String,
+    /// `tcp` or `udp`
+    pub protocol: String,
+    /// Domain the service is published under, e.g. `example.com.`
+    pub domain: String,
+    /// Host the instance runs on
+    pub host: String,
+    /// Addresses the host gets A and AAAA records for; none when it has them already
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    pub port: u16,
+    #[serde(default)]
+    pub priority: u16,
+    #[serde(default)]
+    pub weight: u16,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    pub ttl: u32,
+}
+
+/// Registrations by the name of their instance, e.g. `web1._http._tcp.example.com.`.
+pub type ServiceTable = RwLock<HashMap<LowerName, ServiceEntry>>;
+
+/// The key of the registration of `instance` of a service.
+pub fn instance_key(instance: &str, service: &str, protocol: &str, domain: &str) -> Result<LowerName, RdnsError> {
+    let service = service.trim_start_matches('_');
+    let protocol = protocol.trim_start_matches('_');
+    let name = DnsState::parse_name(&format!("{}._{}._{}.{}", instance, service, protocol, domain))?;
+    Ok(LowerName::new(&name))
+}
+
+/// Whether `label` is a valid service label: letters, digits and inner hyphens.
+fn valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
+
+/// Quotes `value` as a TXT character string.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl ServiceEntry {
+    /// Checks the entry and brings its names into canonical form: lower-cased and fully
+    /// qualified.
+    pub fn normalize(mut self) -> Result<Self, RdnsError> {
+        self.service = self.service.trim_start_matches('_').to_ascii_lowercase();
+        self.protocol = self.protocol.trim_start_matches('_').to_ascii_lowercase();
+        // RFC 6335 limits service names to 15 characters
+        if !valid_label(&self.service) || self.service.len() > 15 {
+            return Err(RdnsError::InvalidArgument(format!("invalid service name {}", self.service)));
+        }
+        if self.protocol != "tcp" && self.protocol != "udp" {
+            return Err(RdnsError::InvalidArgument(format!("protocol must be tcp or udp, not {}", self.protocol)));
+        }
+        if self.instance.is_empty() || self.instance.contains('.') || self.instance.len() > 63 {
+            return Err(RdnsError::InvalidArgument(format!("invalid instance name {}", self.instance)));
+        }
+        if self.port == 0 {
+            return Err(RdnsError::InvalidArgument("port must be set".into()));
+        }
+        self.domain = DnsState::parse_name(&self.domain)?.to_lowercase().to_string();
+        self.host = DnsState::parse_name(&self.host)?.to_lowercase().to_string();
+        Ok(self)
+    }
+
+    /// `_<service>._<protocol>.<domain>`, the name the instances are browsed under.
+    pub fn service_name(&self) -> String {
+        format!("_{}._{}.{}", self.service, self.protocol, self.domain)
+    }
+
+    /// The name of the instance, which identifies the registration.
+    pub fn instance_name(&self) -> String {
+        format!("{}.{}", self.instance, self.service_name())
+    }
+
+    /// The key the registration is kept under.
+    pub fn key(&self) -> Result<LowerName, RdnsError> {
+        instance_key(&self.instance, &self.service, &self.protocol, &self.domain)
+    }
+
+    /// The records the registration publishes.
+    pub fn records(&self) -> Vec<RecordEntry> {
+        let record = |name: String, record_type: &str, value: String| RecordEntry {
+            name,
+            record_type: record_type.to_string(),
+            value,
+            ttl: self.ttl,
+            ..Default::default()
+        };
+        let srv = format!("{} {} {} {}", self.priority, self.weight, self.port, self.host);
+        let txt = match self.metadata.is_empty() {
+            // DNS-SD wants a TXT record even without metadata
+            true => quote(""),
+            false => self
+                .metadata
+                .iter()
+                .map(|(key, value)| quote(&format!("{}={}", key, value)))
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+        let mut records = vec![
+            record(self.service_name(), "PTR", self.instance_name()),
+            record(self.service_name(), "SRV", srv.clone()),
+            record(self.instance_name(), "SRV", srv),
+            record(self.instance_name(), "TXT", txt),
+        ];
+        for address in &self.addresses {
+            let record_type = if address.contains(':') { "AAAA" } else { "A" };
+            records.push(record(self.host.clone(), record_type, address.clone()));
+        }
+        records
+    }
+}
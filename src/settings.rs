@@ -1,18 +1,1870 @@
 /// Defines configuration structure for Config.toml
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Settings {
     pub dns: DnsSettings,
     pub grpc: GrpcSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
+    /// Optional Prometheus metrics endpoint
+    pub metrics: Option<MetricsSettings>,
+    /// Optional probes of the DNS listeners by the server itself, reported in the metrics
+    /// and the gRPC health service; none are sent when absent
+    pub probe: Option<ProbeSettings>,
+    /// Optional REST/JSON gateway to the control API
+    pub rest: Option<RestSettings>,
+    /// Optional audit log of control-plane mutations
+    pub audit: Option<AuditSettings>,
+    /// Optional leader/follower replication between instances
+    pub cluster: Option<ClusterSettings>,
+    /// Optional external-dns webhook provider
+    pub webhook: Option<WebhookSettings>,
+    /// Endpoints notified of record and zone changes
+    #[serde(default)]
+    pub notifications: Vec<NotificationSettings>,
+    /// Optional export of record changes and queries to Kafka or NATS
+    pub events: Option<EventSettings>,
+    /// ACME DNS-01 challenge records
+    #[serde(default)]
+    pub acme: AcmeSettings,
+    /// Optional OpenTelemetry tracing of DNS queries and control API calls
+    pub telemetry: Option<TelemetrySettings>,
+    /// Optional switch to an unprivileged user once the DNS listeners are bound
+    pub privileges: Option<PrivilegeSettings>,
+    #[serde(default)]
+    pub logging: LoggingSettings,
+    #[serde(default)]
+    pub shutdown: ShutdownSettings,
+    #[serde(default)]
+    pub runtime: RuntimeSettings,
+    /// Running in the background as a service, outside of containers
+    #[serde(default)]
+    pub daemon: DaemonSettings,
 }
 
-#[derive(Debug, Deserialize)]
+impl Settings {
+    /// Loads settings from the Config.toml file, overridden by `APP__*` environment variables.
+    pub fn load() -> Result<Self, config::ConfigError> {
+        let builder = config::Config::builder()
+            .add_source(config::File::with_name("Config").required(false))
+            .add_source(config::Environment::with_prefix("APP").separator("__")); // optional
+        builder.build()?.try_deserialize()
+    }
+
+    /// Parses the settings of Config.toml `content`, overridden by `APP__*` environment
+    /// variables as `load` does.
+    pub fn parse(content: &str) -> Result<Self, config::ConfigError> {
+        let builder = config::Config::builder()
+            .add_source(config::File::from_str(content, config::FileFormat::Toml))
+            .add_source(config::Environment::with_prefix("APP").separator("__"));
+        builder.build()?.try_deserialize()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct DnsSettings {
+    /// Addresses to serve UDP and TCP on, e.g. `["0.0.0.0:53", "[::]:53"]`; a single
+    /// address may be given as a plain string
+    #[serde(alias = "listen_addr", deserialize_with = "one_or_many")]
+    pub listen_addrs: Vec<String>,
+    /// Idle timeout for TCP connections, in seconds
+    #[serde(default = "default_tcp_timeout_secs")]
+    pub tcp_timeout_secs: u64,
+    /// UDP sockets bound on each listen address with SO_REUSEPORT, for the kernel to
+    /// spread queries across; one per CPU when 0
+    #[serde(default = "default_udp_sockets")]
+    pub udp_sockets: usize,
+    /// Buffer sizes, DSCP marking and IP_FREEBIND of the listener sockets
+    #[serde(default)]
+    pub socket: SocketSettings,
+    /// Largest UDP response sent to clients advertising a larger EDNS buffer, in bytes,
+    /// from 512 to 4096; longer answers are truncated for the client to retry over TCP
+    #[serde(default = "default_max_udp_payload")]
+    pub max_udp_payload: u16,
+    /// Rotate the order of multi-value answers on every response
+    #[serde(default)]
+    pub round_robin: bool,
+    /// How the answers of the zones and names listed are ordered; others are rotated if
+    /// `round_robin` is set and answered in the order they are stored otherwise
+    #[serde(default)]
+    pub answer_order: Vec<AnswerOrderSettings>,
+    /// CNAMEs followed at most through the hosted zones to answer a query with the
+    /// records of the chain's target; chains aren't completed with 0
+    #[serde(default = "default_cname_chain_depth")]
+    pub cname_chain_depth: usize,
+    /// Add the addresses of MX, SRV and NS targets hosted in any zone to the additional
+    /// section of answers, rather than only those in the zone of the queried name
+    #[serde(default = "default_additional_records")]
+    pub additional_records: bool,
+    /// How ANY queries for hosted names are answered: `hinfo`, `subset` or `full`
+    #[serde(default = "default_any_response")]
+    pub any_response: String,
+    /// How the addresses of each family are answered: `both`, `filter-aaaa`,
+    /// `prefer-ipv4` or `prefer-ipv6`
+    #[serde(default = "default_address_family")]
+    pub address_family: String,
+    /// Stages queries pass through before the hosted zones and the forwarder, in order;
+    /// stages left out are skipped
+    #[serde(default = "default_pipeline")]
+    pub pipeline: Vec<String>,
+    /// Zones to host at startup
+    #[serde(default = "default_zones")]
+    pub zones: Vec<String>,
+    /// BIND-format zone files imported at startup
+    #[serde(default)]
+    pub zone_files: Vec<ZoneFileSettings>,
+    /// Optional directory of BIND-format zone files, each loaded at startup and reloaded
+    /// when it changes
+    pub zone_dir: Option<ZoneDirSettings>,
+    /// Zones pulled from a primary with AXFR and served read-only
+    #[serde(default)]
+    pub secondary_zones: Vec<SecondaryZoneSettings>,
+    /// Optional DNS-over-TLS listener
+    pub tls: Option<TlsSettings>,
+    /// Optional DNS-over-HTTPS listener
+    pub https: Option<DohSettings>,
+    /// Optional DNSSEC signing; zones are served unsigned when absent
+    pub dnssec: Option<DnssecSettings>,
+    /// Optional RFC 2136 dynamic updates; UPDATE messages are refused when absent
+    pub update: Option<UpdateSettings>,
+    /// Optional zone transfers to secondaries; AXFR is refused when absent
+    pub transfer: Option<TransferSettings>,
+    /// Keys zone transfers and dynamic updates may be signed with
+    #[serde(default)]
+    pub tsig_keys: Vec<TsigKeySettings>,
+    /// Optional catalog zone (RFC 9432) listing the primary zones; none is served when absent
+    pub catalog_zone: Option<CatalogZoneSettings>,
+    /// Optional SOA and NS records generated for primary zones that have none
+    pub soa: Option<SoaSettings>,
+    /// Default and bounds of the TTLs of records added through the APIs
+    #[serde(default)]
+    pub ttl: TtlSettings,
+    /// Boilerplate records that zones created through the APIs are populated with
+    #[serde(default)]
+    pub templates: Vec<ZoneTemplateSettings>,
+    /// Optional history of zone versions; zones can't be rolled back when absent
+    pub history: Option<HistorySettings>,
+    /// Optional forwarding for names outside the hosted zones; such queries are refused when absent
+    pub forward: Option<ForwardSettings>,
+    /// Handling of the EDNS Client Subnet option of queries
+    #[serde(default)]
+    pub ecs: EcsSettings,
+    /// Defaults for health checks added through the control API
+    #[serde(default)]
+    pub health: HealthSettings,
+    /// Optional GeoDNS answers; geo records are not applied when absent
+    pub geo: Option<GeoSettings>,
+    /// Split-horizon views, matched in order against the client address
+    #[serde(default)]
+    pub views: Vec<ViewSettings>,
+    /// Optional client access control; every client may query when absent
+    pub acl: Option<AclSettings>,
+    /// Optional response rate limiting of UDP clients; responses are unlimited when absent
+    pub rate_limit: Option<RateLimitSettings>,
+    /// Optional DNS cookies (RFC 7873); queries' cookies are ignored when absent
+    pub cookies: Option<CookieSettings>,
+    /// The EDNS section of responses and the buffer sizes they are kept within
+    #[serde(default)]
+    pub edns: EdnsSettings,
+    /// Optional blocking of the domains on blocklists; nothing is blocked when absent
+    pub blocklist: Option<BlocklistSettings>,
+    /// Response policy zone files, checked in order against every query
+    #[serde(default)]
+    pub rpz: Vec<RpzSettings>,
+    /// How the zones listed answer names that don't exist; others answer NXDOMAIN
+    #[serde(default)]
+    pub negative: Vec<NegativeSettings>,
+    /// Optional records for the running Docker containers
+    pub docker: Option<DockerSettings>,
+    /// Optional records for the hostnames of DHCP leases
+    pub dhcp: Option<DhcpSettings>,
+    /// Optional records for the entries of hosts files
+    pub hosts: Option<HostsSettings>,
+    /// Optional multicast DNS responder for a `.local` zone
+    pub mdns: Option<MdnsSettings>,
+    /// Optional answers to CHAOS and NSID queries identifying the instance; CHAOS
+    /// queries are refused when absent
+    pub identity: Option<IdentitySettings>,
+    /// Optional synthesis of AAAA answers from A records for IPv6-only clients behind
+    /// NAT64; AAAA queries are answered as they are when absent
+    pub dns64: Option<Dns64Settings>,
+    /// Optional Rhai script with hooks run before and after lookup, answering, blocking
+    /// or rewriting queries as the `script` stage of the pipeline; none run when absent
+    pub script: Option<ScriptSettings>,
+    /// Rules answering the queries for some names with the records of others, tried in
+    /// order after those added through the control API
+    #[serde(default)]
+    pub rewrite: Vec<RewriteRuleSettings>,
+    /// Rules answering the queries for matching names with NXDOMAIN, static records or
+    /// upstreams of their own, tried in order after those added through the control API
+    #[serde(default)]
+    pub query_rules: Vec<QueryRuleSettings>,
+    /// Files of query rules, one per line, tried after `query_rules`
+    #[serde(default)]
+    pub query_rule_files: Vec<String>,
+    /// Optional identification of client devices by EDNS options or MAC address, for
+    /// per-device views, query rules and logging; clients are told apart by address only
+    /// when absent
+    pub clients: Option<ClientSettings>,
+    /// Optional counts of the queries by name and type, listed through the control API;
+    /// none are kept when absent
+    pub stats: Option<StatsSettings>,
+    /// Optional anonymization of client addresses in dnstap output, query statistics,
+    /// logs and traces, and how long per-client statistics are kept
+    pub privacy: Option<PrivacySettings>,
+    /// Optional PROXY protocol headers on the TCP and DoT listeners, announcing the
+    /// clients behind a load balancer
+    pub proxy_protocol: Option<ProxyProtocolSettings>,
+    /// Optional limits on the records added through the APIs, per zone and in total
+    pub quotas: Option<QuotaSettings>,
+    /// Teams whose API tokens only manage the zones they own, and their quotas
+    #[serde(default)]
+    pub tenants: Vec<TenantSettings>,
+}
+
+/// Accepts either a single string or a list of strings.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+pub(crate) fn default_tcp_timeout_secs() -> u64 {
+    5
+}
+
+pub(crate) fn default_udp_sockets() -> usize {
+    1
+}
+
+/// Options of the sockets of the DNS listeners, the system defaults when unset.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct SocketSettings {
+    /// SO_RCVBUF of the listener sockets, in bytes
+    pub recv_buffer_bytes: Option<usize>,
+    /// SO_SNDBUF of the listener sockets, in bytes
+    pub send_buffer_bytes: Option<usize>,
+    /// DiffServ codepoint (0-63) the answers are marked with, e.g. 46 for EF
+    pub dscp: Option<u8>,
+    /// Bind listen addresses not (yet) configured on the host, such as anycast VIPs
+    #[serde(default)]
+    pub freebind: bool,
+}
+
+pub(crate) fn default_max_udp_payload() -> u16 {
+    1232
+}
+
+pub(crate) fn default_unix_socket_mode() -> u32 {
+    0o660
+}
+
+fn default_cname_chain_depth() -> usize {
+    8
+}
+
+fn default_additional_records() -> bool {
+    true
+}
+
+fn default_any_response() -> String {
+    "hinfo".into()
+}
+
+fn default_address_family() -> String {
+    "both".into()
+}
+
+fn default_pipeline() -> Vec<String> {
+    ["acl", "chaos", "family", "override", "rpz", "blocklist", "script", "rules", "delegation", "any", "views", "timed", "geo", "rollout", "pattern", "alias", "dns64", "rewrite"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_zones() -> Vec<String> {
+    vec!["example.com.".into()]
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ZoneFileSettings {
+    /// Zone origin, defaults to the `$ORIGIN` declared in the file
+    pub origin: Option<String>,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ZoneDirSettings {
+    pub path: String,
+    /// How often the directory is checked for changes
+    #[serde(default = "default_zone_dir_poll_secs")]
+    pub poll_secs: u64,
+}
+
+fn default_zone_dir_poll_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DockerSettings {
+    /// Path of the Docker daemon's unix socket, or of its named pipe on Windows
+    #[serde(default = "default_docker_socket")]
+    pub socket: String,
+    /// Zone the container records are kept in, created if needed
+    #[serde(default = "default_docker_zone")]
+    pub zone: String,
+    #[serde(default = "default_docker_ttl")]
+    pub ttl: u32,
+}
+
+fn default_docker_socket() -> String {
+    match cfg!(windows) {
+        true => r"\\.\pipe\docker_engine".into(),
+        false => "/var/run/docker.sock".into(),
+    }
+}
+
+fn default_docker_zone() -> String {
+    "docker.local".into()
+}
+
+fn default_docker_ttl() -> u32 {
+    60
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DhcpSettings {
+    /// Lease file written by the DHCP server
+    pub path: String,
+    /// Format of the lease file, `dnsmasq` or `isc`
+    #[serde(default = "default_dhcp_format")]
+    pub format: String,
+    /// Zone the lease records are kept in, created if needed
+    pub zone: String,
+    #[serde(default = "default_dhcp_ttl")]
+    pub ttl: u32,
+    /// How often the lease file is read again
+    #[serde(default = "default_dhcp_poll_secs")]
+    pub poll_secs: u64,
+}
+
+fn default_dhcp_format() -> String {
+    "dnsmasq".into()
+}
+
+fn default_dhcp_ttl() -> u32 {
+    60
+}
+
+fn default_dhcp_poll_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HostsSettings {
+    /// Hosts files to read, e.g. `["/etc/hosts"]`; a single path may be given as a
+    /// plain string
+    #[serde(alias = "file", deserialize_with = "one_or_many")]
+    pub files: Vec<String>,
+    /// Zone the entries are kept in, created if needed
+    pub zone: String,
+    #[serde(default = "default_hosts_ttl")]
+    pub ttl: u32,
+    /// How often the files are checked for changes
+    #[serde(default = "default_hosts_poll_secs")]
+    pub poll_secs: u64,
+}
+
+fn default_hosts_ttl() -> u32 {
+    300
+}
+
+fn default_hosts_poll_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MdnsSettings {
+    /// Zone answered over multicast DNS, created if needed
+    #[serde(default = "default_mdns_zone")]
+    pub zone: String,
+    /// Address of the interface the multicast group is joined on; `0.0.0.0` lets the
+    /// system choose
+    #[serde(default = "default_mdns_interface")]
+    pub interface: String,
+    /// Services registered in the zone at startup and announced on the network
+    #[serde(default)]
+    pub services: Vec<MdnsServiceSettings>,
+}
+
+fn default_mdns_zone() -> String {
+    "local.".into()
+}
+
+fn default_mdns_interface() -> String {
+    "0.0.0.0".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MdnsServiceSettings {
+    /// Instance name, e.g. `printer`
+    pub instance: String,
+    /// Service name without the leading underscore, e.g. `ipp`
+    pub service: String,
+    #[serde(default = "default_mdns_protocol")]
+    pub protocol: String,
+    /// Host the service runs on; a single label is put under the zone
+    pub host: String,
+    pub port: u16,
+    /// Addresses the host gets A and AAAA records for
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// Key/value pairs of the TXT record
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default = "default_mdns_ttl")]
+    pub ttl: u32,
+}
+
+fn default_mdns_protocol() -> String {
+    "tcp".into()
+}
+
+fn default_mdns_ttl() -> u32 {
+    120
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct IdentitySettings {
+    /// Answer to `hostname.bind` and `id.server`, and the NSID of responses; defaults to
+    /// the host name, and an empty string refuses the queries
+    pub hostname: Option<String>,
+    /// Answer to `version.bind` and `version.server`; an empty string refuses the queries
+    #[serde(default = "default_identity_version")]
+    pub version: String,
+    /// Whether the NSID option of queries is answered with the hostname
+    #[serde(default = "default_identity_nsid")]
+    pub nsid: bool,
+}
+
+fn default_identity_version() -> String {
+    format!("rdns {}", env!("CARGO_PKG_VERSION"))
+}
+
+fn default_identity_nsid() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Dns64Settings {
+    /// NAT64 prefix the IPv4 addresses are embedded in, 32, 40, 48, 56, 64 or 96 bits long
+    #[serde(default = "default_dns64_prefix")]
+    pub prefix: String,
+    /// Client networks answered with synthesized records; every client is when empty
+    #[serde(default)]
+    pub clients: Vec<String>,
+}
+
+fn default_dns64_prefix() -> String {
+    "64:ff9b::/96".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ScriptSettings {
+    /// Rhai script defining `query(q)`, `answer(q, records)` or both
+    pub path: String,
+    /// TTL of the records the hooks return without one
+    #[serde(default = "default_script_ttl")]
+    pub ttl: u32,
+    /// Operations a hook may run for a query before it is stopped
+    #[serde(default = "default_script_max_operations")]
+    pub max_operations: u64,
+}
+
+fn default_script_ttl() -> u32 {
+    300
+}
+
+fn default_script_max_operations() -> u64 {
+    10_000
+}
+
+/// Rewriting of the names under `from`, its own included, to the same names under `to`,
+/// or, with `regex` set, of the names matching the pattern `from` to `to` with its group
+/// references expanded.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RewriteRuleSettings {
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub regex: bool,
+}
+
+/// A fixed answer to the queries for names matching `pattern`, see `queryrules`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct QueryRuleSettings {
+    pub pattern: String,
+    /// "exact", "suffix" or "regex"
+    #[serde(default = "default_query_rule_match_type")]
+    pub match_type: String,
+    /// "nxdomain", "answer" or "forward"
+    #[serde(default = "default_query_rule_action")]
+    pub action: String,
+    /// Records to answer with, as type and value like "A 0.0.0.0", or upstreams to
+    /// forward to, addresses with an optional port
+    #[serde(default)]
+    pub values: Vec<String>,
+    /// TTL of the records answered with, 300 when 0
+    #[serde(default)]
+    pub ttl: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClientSettings {
+    /// EDNS option code carrying the client's MAC address, as dnsmasq's `--add-mac` sends
+    #[serde(default = "default_client_mac_option")]
+    pub mac_option: u16,
+    /// EDNS option code carrying the client's ID, as dnsmasq's `--add-cpe-id` sends
+    #[serde(default = "default_client_id_option")]
+    pub id_option: u16,
+    /// Clients whose EDNS options are trusted to identify devices, as CIDR blocks or
+    /// addresses; every client's are when empty
+    #[serde(default)]
+    pub edns_from: Vec<String>,
+    /// Whether the MAC addresses of clients on attached networks are looked up in the
+    /// neighbor table of the host
+    #[serde(default)]
+    pub neighbor_table: bool,
+    /// How often the neighbor table is read again
+    #[serde(default = "default_neighbor_refresh_secs")]
+    pub neighbor_refresh_secs: u64,
+    /// Devices, in the order they are matched
+    #[serde(default)]
+    pub devices: Vec<DeviceSettings>,
+}
+
+fn default_client_mac_option() -> u16 {
+    65001
+}
+
+fn default_client_id_option() -> u16 {
+    65074
+}
+
+fn default_neighbor_refresh_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DeviceSettings {
+    pub name: String,
+    /// MAC addresses the device is recognized by
+    #[serde(default)]
+    pub macs: Vec<String>,
+    /// Client IDs the device is recognized by
+    #[serde(default)]
+    pub ids: Vec<String>,
+    /// View the device is answered from, whatever its address
+    pub view: Option<String>,
+    /// Query rules of the device, in the line format of rule files, tried before the others
+    #[serde(default)]
+    pub rules: Vec<String>,
+    /// Whether the device's queries are logged; false leaves them out of dnstap output,
+    /// logs, query statistics and traces
+    #[serde(default = "default_device_log")]
+    pub log: bool,
+}
+
+fn default_device_log() -> bool {
+    true
+}
+
+fn default_query_rule_match_type() -> String {
+    "suffix".into()
+}
+
+fn default_query_rule_action() -> String {
+    "nxdomain".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StatsSettings {
+    /// Names and types tracked at most; the least recently queried are dropped for new ones
+    #[serde(default = "default_stats_max_names")]
+    pub max_names: usize,
+    /// How long per-minute counts are kept, the longest time window that can be asked
+    /// about, in minutes
+    #[serde(default = "default_stats_retention_mins")]
+    pub retention_mins: u64,
+    /// Distinct clients counted per name and type at most
+    #[serde(default = "default_stats_max_clients")]
+    pub max_clients: usize,
+    /// Clients whose queries are counted at most; the least recently seen are dropped for
+    /// new ones
+    #[serde(default = "default_stats_max_tracked_clients")]
+    pub max_tracked_clients: usize,
+    /// Thresholds of the anomalies reported through `WatchAnomalies`; none are when unset
+    pub anomalies: Option<AnomalySettings>,
+}
+
+fn default_stats_max_names() -> usize {
+    100_000
+}
+
+fn default_stats_retention_mins() -> u64 {
+    1440
+}
+
+fn default_stats_max_clients() -> usize {
+    1000
+}
+
+fn default_stats_max_tracked_clients() -> usize {
+    10_000
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AnomalySettings {
+    /// A minute with this many times the queries of the average minute before it is a spike
+    #[serde(default = "default_anomaly_spike_factor")]
+    pub spike_factor: u64,
+    /// Queries per second below which no minute is a spike, however quiet those before
+    #[serde(default = "default_anomaly_spike_min_qps")]
+    pub spike_min_qps: u64,
+    /// Minutes the average a spike is measured against is taken over
+    #[serde(default = "default_anomaly_baseline_mins")]
+    pub baseline_mins: u64,
+    /// NXDOMAIN answers per minute to a single client that make a flood
+    #[serde(default = "default_anomaly_nxdomain_per_min")]
+    pub nxdomain_per_min: u64,
+}
+
+fn default_anomaly_spike_factor() -> u64 {
+    5
+}
+
+fn default_anomaly_spike_min_qps() -> u64 {
+    50
+}
+
+fn default_anomaly_baseline_mins() -> u64 {
+    10
+}
+
+fn default_anomaly_nxdomain_per_min() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PrivacySettings {
+    /// How client addresses are anonymized: `truncate` to their network, `hash` to a
+    /// keyed pseudonym of the same family, or `none`
+    #[serde(default = "default_privacy_anonymize")]
+    pub anonymize: String,
+    /// Prefix length IPv4 addresses are truncated to
+    #[serde(default = "default_privacy_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    /// Prefix length IPv6 addresses are truncated to
+    #[serde(default = "default_privacy_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+    /// Key of the `hash` pseudonyms, which stay the same across restarts with it; a
+    /// random key is generated at startup when unset
+    pub hash_key: Option<String>,
+    /// How long per-client statistics are kept after a client's last query, in minutes;
+    /// as long as they are tracked when unset
+    pub client_retention_mins: Option<u64>,
+}
+
+fn default_privacy_anonymize() -> String {
+    "truncate".into()
+}
+
+fn default_privacy_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_privacy_ipv6_prefix() -> u8 {
+    48
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RpzSettings {
+    /// Policy zone origin, defaults to the `$ORIGIN` declared in the file
+    pub origin: Option<String>,
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AnswerOrderSettings {
+    /// Zone whose names the order applies to, `.` for every name
+    pub zone: Option<String>,
+    /// Single name the order applies to, instead of a zone
+    pub name: Option<String>,
+    /// Record type the order applies to, every type when unset
+    pub record_type: Option<String>,
+    /// `fixed`, `round-robin`, `shuffle` or `proximity`
+    pub order: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct NegativeSettings {
+    pub zone: String,
+    /// `nxdomain`, `wildcard` or `redirect`
+    #[serde(default = "default_negative_policy")]
+    pub policy: String,
+    /// Records unknown names get under `wildcard`, as `TYPE value`, e.g. `TXT "parked"`
+    #[serde(default)]
+    pub records: Vec<String>,
+    /// Landing addresses unknown names resolve to under `redirect`
+    #[serde(default)]
+    pub addresses: Vec<String>,
+    /// TTL of the synthesized answers
+    #[serde(default = "default_negative_ttl")]
+    pub ttl: u32,
+}
+
+fn default_negative_policy() -> String {
+    "nxdomain".into()
+}
+
+fn default_negative_ttl() -> u32 {
+    300
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ZoneTemplateSettings {
+    pub name: String,
+    /// Whether zones created without naming a template get this one
+    #[serde(default)]
+    pub default: bool,
+    /// Records as `NAME [TTL] TYPE value`, NAME relative to the zone or `@` for its
+    /// apex, e.g. `@ MX 10 mail.example.net.` or `* CNAME @`; an `@` in the value names
+    /// the apex too
+    #[serde(default)]
+    pub records: Vec<String>,
+    /// TTL of the records that don't give one
+    #[serde(default = "default_template_ttl")]
+    pub ttl: u32,
+    /// TTL of the records later added to the zone with a TTL of 0, instead of
+    /// `dns.ttl.default_ttl`
+    pub default_ttl: Option<u32>,
+}
+
+fn default_template_ttl() -> u32 {
+    3600
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SecondaryZoneSettings {
+    pub origin: String,
+    /// Primary server, as `ip` or `ip:port`
+    pub primary: String,
+    /// TSIG key NOTIFY messages from the primary must be signed with; unsigned ones are
+    /// accepted when unset
+    #[serde(default)]
+    pub tsig_key: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TlsSettings {
+    #[serde(default = "default_tls_listen_addr")]
+    pub listen_addr: String,
+    /// Left out when `[acme.certificate]` provides the certificate
+    #[serde(default)]
+    pub cert_path: String,
+    #[serde(default)]
+    pub key_path: String,
+}
+
+fn default_tls_listen_addr() -> String {
+    "0.0.0.0:853".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DohSettings {
+    #[serde(default = "default_doh_listen_addr")]
     pub listen_addr: String,
+    /// Left out when `[acme.certificate]` provides the certificate
+    #[serde(default)]
+    pub cert_path: String,
+    #[serde(default)]
+    pub key_path: String,
+    #[serde(default = "default_doh_path")]
+    pub path: String,
+}
+
+fn default_doh_listen_addr() -> String {
+    "0.0.0.0:443".into()
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DnssecSettings {
+    /// Directory KSK/ZSK key files are loaded from and generated into
+    #[serde(default = "default_key_dir")]
+    pub key_dir: String,
+    /// Zones to sign
+    #[serde(default)]
+    pub zones: Vec<String>,
+    #[serde(default = "default_signature_validity_days")]
+    pub signature_validity_days: u64,
+    /// Days a ZSK signs before it is rolled over; 0 rolls ZSKs over only on request
+    #[serde(default)]
+    pub zsk_rollover_days: u64,
+    /// Hours a new ZSK is published before it signs, at least the DNSKEY TTL
+    #[serde(default = "default_zsk_prepublish_hours")]
+    pub zsk_prepublish_hours: u64,
+    /// Hours an old ZSK stays published after it stopped signing, at least the zone's
+    /// largest TTL
+    #[serde(default = "default_zsk_retire_hours")]
+    pub zsk_retire_hours: u64,
+    /// Days a KSK rollover waits for its DS to be confirmed before it is rolled back
+    #[serde(default = "default_ksk_confirm_timeout_days")]
+    pub ksk_confirm_timeout_days: u64,
+    /// How names and types are proven not to exist: nsec, nsec3 or white-lies
+    #[serde(default = "default_denial")]
+    pub denial: String,
+    /// Salt of the NSEC3 hashes, in hex; none when empty
+    #[serde(default)]
+    pub nsec3_salt: String,
+    /// Extra NSEC3 hash iterations
+    #[serde(default)]
+    pub nsec3_iterations: u16,
+    /// Leave unsigned delegations out of the NSEC3 chain
+    #[serde(default)]
+    pub nsec3_opt_out: bool,
+    /// Per-zone overrides of the denial settings above
+    #[serde(default)]
+    pub zone_denial: Vec<ZoneDenialSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ZoneDenialSettings {
+    pub zone: String,
+    pub denial: Option<String>,
+    pub nsec3_salt: Option<String>,
+    pub nsec3_iterations: Option<u16>,
+    pub nsec3_opt_out: Option<bool>,
+}
+
+fn default_key_dir() -> String {
+    "data/keys".into()
+}
+
+fn default_signature_validity_days() -> u64 {
+    30
+}
+
+fn default_zsk_prepublish_hours() -> u64 {
+    24
+}
+
+fn default_zsk_retire_hours() -> u64 {
+    24
+}
+
+fn default_ksk_confirm_timeout_days() -> u64 {
+    7
+}
+
+fn default_denial() -> String {
+    "nsec".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UpdateSettings {
+    /// Accept updates that carry no TSIG signature
+    #[serde(default)]
+    pub allow_unsigned: bool,
+    /// More keys, accepted like those of `dns.tsig_keys`
+    #[serde(default)]
+    pub tsig_keys: Vec<TsigKeySettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TsigKeySettings {
+    /// Key name, as configured on the client, e.g. `update-key.`
+    pub name: String,
+    /// HMAC algorithm, e.g. `hmac-sha256`
+    #[serde(default = "default_tsig_algorithm")]
+    pub algorithm: String,
+    /// Base64-encoded shared secret
+    pub secret: String,
+}
+
+fn default_tsig_algorithm() -> String {
+    "hmac-sha256".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TransferSettings {
+    /// Secondary servers, as `ip` or `ip:port`, allowed to AXFR or IXFR and sent NOTIFY messages
+    #[serde(default)]
+    pub secondaries: Vec<String>,
+    /// Refuse transfers that aren't signed with one of the TSIG keys
+    #[serde(default)]
+    pub require_tsig: bool,
+    /// Changes kept per zone to answer IXFR requests with, 0 to always send whole zones
+    #[serde(default = "default_ixfr_journal")]
+    pub ixfr_journal: usize,
+    /// Zones transferred to the clients their own policy allows rather than to the secondaries
+    #[serde(default)]
+    pub zones: Vec<ZoneTransferSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ZoneTransferSettings {
+    pub zone: String,
+    /// Client networks allowed to AXFR or IXFR the zone
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// TSIG keys whose signed transfer requests are allowed from any address
+    #[serde(default)]
+    pub allow_keys: Vec<String>,
+}
+
+fn default_ixfr_journal() -> usize {
+    100
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct CatalogZoneSettings {
+    /// Origin of the catalog zone, e.g. `catalog.invalid`
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TtlSettings {
+    /// TTL of records added with a TTL of 0
+    pub default_ttl: u32,
+    /// TTLs below this are raised to it
+    pub min_ttl: u32,
+    /// TTLs above this are lowered to it
+    pub max_ttl: u32,
+}
+
+impl Default for TtlSettings {
+    fn default() -> Self {
+        TtlSettings {
+            default_ttl: 300,
+            min_ttl: 30,
+            max_ttl: 86_400,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HistorySettings {
+    /// Versions kept per zone, the oldest being dropped first
+    #[serde(default = "default_history_versions")]
+    pub versions: usize,
+}
+
+fn default_history_versions() -> usize {
+    20
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SoaSettings {
+    /// Primary name server of the zones, e.g. `ns1.example.com`
+    pub mname: String,
+    /// Mailbox of the person responsible for the zones, as `hostmaster.example.com` or
+    /// `hostmaster@example.com`
+    pub rname: String,
+    /// Name servers of the NS records; `mname` alone when empty
+    #[serde(default)]
+    pub nameservers: Vec<String>,
+    #[serde(default = "default_soa_refresh_secs")]
+    pub refresh_secs: u32,
+    #[serde(default = "default_soa_retry_secs")]
+    pub retry_secs: u32,
+    #[serde(default = "default_soa_expire_secs")]
+    pub expire_secs: u32,
+    /// TTL of negative answers
+    #[serde(default = "default_soa_minimum_ttl")]
+    pub minimum_ttl: u32,
+    /// TTL of the SOA and NS records themselves
+    #[serde(default = "default_soa_ttl")]
+    pub ttl: u32,
+    /// Per-zone overrides of the settings above
+    #[serde(default)]
+    pub zones: Vec<ZoneSoaSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ZoneSoaSettings {
+    pub zone: String,
+    pub mname: Option<String>,
+    pub rname: Option<String>,
+    pub nameservers: Option<Vec<String>>,
+    pub refresh_secs: Option<u32>,
+    pub retry_secs: Option<u32>,
+    pub expire_secs: Option<u32>,
+    pub minimum_ttl: Option<u32>,
+    pub ttl: Option<u32>,
+}
+
+fn default_soa_refresh_secs() -> u32 {
+    3600
+}
+
+fn default_soa_retry_secs() -> u32 {
+    600
+}
+
+fn default_soa_expire_secs() -> u32 {
+    1_209_600
+}
+
+fn default_soa_minimum_ttl() -> u32 {
+    300
+}
+
+fn default_soa_ttl() -> u32 {
+    3600
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ForwardSettings {
+    /// Upstream resolvers of the names no rule matches, as `ip` or `ip:port`,
+    /// `tls://host[:port][#name]` or `https://host[:port]/dns-query`; those names are
+    /// refused without any
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+    /// Upstreams of the names under given domains, tried in order
+    #[serde(default)]
+    pub rules: Vec<ForwardRuleSettings>,
+    /// PEM file of the CA certificates TLS and HTTPS upstreams are verified against,
+    /// instead of the Mozilla root store
+    pub tls_ca_file: Option<String>,
+    /// Base64 SHA-256 digests of public keys, one of which the certificate chain of the
+    /// TLS and HTTPS upstreams must hold
+    #[serde(default)]
+    pub tls_pins: Vec<String>,
+    /// Optional DNSSEC validation of the upstreams' answers; they are passed on as they
+    /// come when absent
+    pub dnssec: Option<ValidationSettings>,
+    /// Cache for forwarded answers
+    #[serde(default)]
+    pub cache: CacheSettings,
+    /// How long an upstream has to answer each attempt at a query, in milliseconds
+    #[serde(default = "default_upstream_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Attempts made after the first when an upstream times out or fails
+    #[serde(default = "default_upstream_retries")]
+    pub retries: u32,
+    /// Delay after which a query the first upstream hasn't answered is also sent to the
+    /// next one, in milliseconds; upstreams are only tried in turn when absent
+    pub hedge_ms: Option<u64>,
+    /// Randomize the case of the names asked of plain upstreams (0x20), whose answers
+    /// must echo it, so that off-path spoofed answers are caught
+    #[serde(default = "default_randomize_case")]
+    pub randomize_case: bool,
+    /// Timeouts and retries of single upstreams, keyed by the upstream as it is listed
+    #[serde(default)]
+    pub overrides: BTreeMap<String, UpstreamOverrideSettings>,
+    /// When upstreams are considered down and skipped
+    #[serde(default)]
+    pub health: UpstreamHealthSettings,
+    /// Order the upstreams of a route are tried in: `ordered` as they are listed, or
+    /// `fastest` by their measured response times and failure rates
+    #[serde(default = "default_upstream_selection")]
+    pub selection: String,
+    /// How often each upstream `fastest` doesn't prefer is sent a copy of a query to
+    /// measure it, in seconds
+    #[serde(default = "default_upstream_probe_secs")]
+    pub probe_secs: u64,
+    /// Optional DNS rebinding protection of the upstreams' answers; they are passed on
+    /// whatever addresses they hold when absent
+    pub rebind: Option<RebindSettings>,
+}
+
+fn default_randomize_case() -> bool {
+    true
+}
+
+fn default_upstream_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_upstream_retries() -> u32 {
+    1
+}
+
+fn default_upstream_selection() -> String {
+    "ordered".into()
+}
+
+fn default_upstream_probe_secs() -> u64 {
+    30
+}
+
+/// Timeout and retries of an upstream, those of `[dns.forward]` when unset.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct UpstreamOverrideSettings {
+    pub timeout_ms: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct UpstreamHealthSettings {
+    /// Queries in a row an upstream fails to answer before it is considered down
+    pub max_failures: u32,
+    /// How long an upstream that is down is skipped before it is tried again
+    pub down_secs: u64,
+}
+
+impl Default for UpstreamHealthSettings {
+    fn default() -> Self {
+        UpstreamHealthSettings {
+            max_failures: 3,
+            down_secs: 30,
+        }
+    }
+}
+
+/// Forwarding of the names under `domain`, its own included, to `upstreams`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ForwardRuleSettings {
+    pub domain: String,
+    /// Upstream resolvers, in the same forms as those of `[dns.forward]`
+    pub upstreams: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ValidationSettings {
+    /// DS records the chains of trust start from, as `<zone> DS <key tag> <algorithm>
+    /// <digest type> <digest>`; the root zone's key signing keys by default
+    #[serde(default = "default_trust_anchors")]
+    pub trust_anchors: Vec<String>,
+    /// Domains whose names are answered without validation, e.g. as their signatures are
+    /// known to be broken
+    #[serde(default)]
+    pub negative_trust_anchors: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RebindSettings {
+    /// What is done with answers holding private addresses: "strip" them or "refuse"
+    /// the query
+    #[serde(default = "default_rebind_action")]
+    pub action: String,
+    /// Domains whose names may be answered with private addresses, e.g. internal ones
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+}
+
+fn default_rebind_action() -> String {
+    "strip".into()
+}
+
+fn default_trust_anchors() -> Vec<String> {
+    vec![
+        ". DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D".into(),
+        ". DS 38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16".into(),
+    ]
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CacheSettings {
+    pub max_entries: usize,
+    /// Upper bound on the memory used by cached records, in megabytes
+    pub max_memory_mb: usize,
+    /// How long past their TTL answers are served when no upstream can be reached, in
+    /// seconds; never when 0
+    pub stale_window_secs: u64,
+    /// TTL of the answers served stale
+    pub stale_ttl: u32,
+    /// Hits after which an answer is refreshed before it expires; never when 0
+    pub prefetch_min_hits: u32,
+    /// Share of an answer's TTL, in percent, left when it is refreshed
+    pub prefetch_percent: u32,
+    /// Longest TTL NXDOMAIN and NODATA answers are cached for, in seconds; never when 0
+    pub max_negative_ttl: u32,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            max_entries: 10_000,
+            max_memory_mb: 32,
+            stale_window_secs: 0,
+            stale_ttl: 30,
+            prefetch_min_hits: 0,
+            prefetch_percent: 10,
+            max_negative_ttl: 3600,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct EcsSettings {
+    /// Whether the Client Subnet option of forwarded queries is passed on to the
+    /// upstreams; it is stripped otherwise
+    pub forward: bool,
+    /// Longest prefix of an IPv4 client address passed on
+    pub max_prefix_v4: u8,
+    /// Longest prefix of an IPv6 client address passed on
+    pub max_prefix_v6: u8,
+    /// Whether views match the address of the Client Subnet option rather than the
+    /// source address of the query
+    pub views: bool,
+}
+
+impl Default for EcsSettings {
+    fn default() -> Self {
+        EcsSettings {
+            forward: false,
+            max_prefix_v4: 24,
+            max_prefix_v6: 56,
+            views: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HealthSettings {
+    /// Seconds between probes of checks that don't set their own
+    pub interval_secs: u64,
+    /// Seconds a probe may take, for checks that don't set their own
+    pub timeout_secs: u64,
+    /// Consecutive failed probes after which a value is withheld from answers
+    pub fall: u32,
+    /// Consecutive successful probes after which a withheld value is served again
+    pub rise: u32,
+}
+
+impl Default for HealthSettings {
+    fn default() -> Self {
+        HealthSettings {
+            interval_secs: 10,
+            timeout_secs: 2,
+            fall: 3,
+            rise: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GeoSettings {
+    /// MaxMind GeoIP2/GeoLite2 Country or City database
+    pub database: String,
+    /// Named regions, each listing the country and continent codes it covers
+    #[serde(default)]
+    pub regions: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ViewSettings {
+    pub name: String,
+    /// Client networks answered from the view, as CIDR blocks or addresses
+    pub match_clients: Vec<String>,
+    /// Address family policy of the view's clients; `dns.address_family` when unset
+    #[serde(default)]
+    pub address_family: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct QuotaSettings {
+    /// Records all zones together may hold; unlimited when unset or 0
+    pub max_records: Option<usize>,
+    /// Records each zone may hold, unless it has a quota of its own; unlimited when unset
+    pub max_zone_records: Option<usize>,
+    /// Estimated memory the records of all zones may take up together, in MiB; unlimited
+    /// when unset or 0
+    pub max_memory_mb: Option<usize>,
+    /// Quotas of particular zones
+    pub zones: Vec<ZoneQuotaSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ZoneQuotaSettings {
+    pub zone: String,
+    pub max_records: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TenantSettings {
+    pub name: String,
+    /// Zones the tenant owns, on top of those its tokens create
+    #[serde(default)]
+    pub zones: Vec<String>,
+    /// Zones the tenant may own; unlimited when unset
+    pub max_zones: Option<usize>,
+    /// Records the tenant's zones may hold together; unlimited when unset
+    pub max_records: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ProxyProtocolSettings {
+    /// Networks of the load balancers, whose connections must start with a PROXY header
+    pub trusted: Vec<String>,
+    /// Connections served at once by each TCP and DoT listener
+    #[serde(default = "default_proxy_max_connections")]
+    pub max_connections: usize,
+}
+
+fn default_proxy_max_connections() -> usize {
+    1024
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AclSettings {
+    /// Client networks permitted to send requests; every client not denied is when empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Client networks refused, even when also allowed
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Clients permitted to have queries forwarded upstream; every permitted client is when absent
+    pub recursion: Option<AccessListSettings>,
+    /// Lists for the names in particular zones
+    #[serde(default)]
+    pub zones: Vec<ZoneAclSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AccessListSettings {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ZoneAclSettings {
+    pub zone: String,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Responses per second each client network is sent
+    pub responses_per_second: u32,
+    /// Seconds over which the rate is averaged; a network that keeps exceeding it stays
+    /// limited for up to this long after it slows down
+    pub window_secs: u64,
+    /// Every how many limited responses one is sent truncated instead of dropped, so
+    /// real clients can retry over TCP; 0 drops every limited response
+    pub slip: u32,
+    /// Prefix length IPv4 clients are grouped by
+    pub ipv4_prefix_len: u8,
+    /// Prefix length IPv6 clients are grouped by
+    pub ipv6_prefix_len: u8,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            responses_per_second: 20,
+            window_secs: 15,
+            slip: 2,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CookieSettings {
+    /// Seconds each server secret is used for before the next one replaces it; cookies
+    /// of the previous one stay valid. At least 3600, the lifetime of a server cookie
+    pub rotate_secs: u64,
+    /// Secret the server secrets are derived from, shared by the instances behind an
+    /// anycast address so that they accept each other's cookies; random when absent
+    pub secret: Option<String>,
+    /// UDP answers longer than this many bytes are truncated for clients without a valid
+    /// server cookie, for them to retry over TCP; every answer is sent when absent
+    pub require_above_bytes: Option<u16>,
+}
+
+impl Default for CookieSettings {
+    fn default() -> Self {
+        CookieSettings {
+            rotate_secs: 86400,
+            secret: None,
+            require_above_bytes: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct EdnsSettings {
+    /// UDP payload size the OPT record of responses advertises, from 512 to 4096 bytes;
+    /// UDP responses are kept within it too. Responses advertise the size they were kept
+    /// within when absent
+    pub advertised_payload: Option<u16>,
+    /// Keep UDP responses within the buffer size the EDNS of queries advertises; with
+    /// false, they fill max_udp_payload whatever clients advertise
+    pub honor_client_payload: bool,
+    /// EDNS options responses may carry, by mnemonic (nsid, subnet, cookie, padding, ede,
+    /// ...) or code; any option when absent
+    pub allowed_options: Option<Vec<String>>,
+}
+
+impl Default for EdnsSettings {
+    fn default() -> Self {
+        EdnsSettings {
+            advertised_payload: None,
+            honor_client_payload: true,
+            allowed_options: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BlocklistSettings {
+    /// Blocklists in hosts, adblock or plain domain format, as HTTP(S) URLs or file paths
+    #[serde(default)]
+    pub lists: Vec<String>,
+    /// Seconds between downloads of the lists
+    #[serde(default = "default_blocklist_refresh_secs")]
+    pub refresh_secs: u64,
+    /// Addresses blocked names resolve to; they don't exist (NXDOMAIN) when empty
+    #[serde(default)]
+    pub sinkhole: Vec<String>,
+    /// TTL of sinkhole answers
+    #[serde(default = "default_blocklist_ttl")]
+    pub ttl: u32,
+}
+
+fn default_blocklist_refresh_secs() -> u64 {
+    86_400
+}
+
+fn default_blocklist_ttl() -> u32 {
+    300
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct GrpcSettings {
+    /// TCP address, or `unix:` followed by the path of a Unix domain socket
     pub listen_addr: String,
+    /// Permissions of the Unix domain socket, e.g. `0o660` for its owner and group
+    #[serde(default = "default_unix_socket_mode")]
+    pub unix_socket_mode: u32,
+    /// Optional TLS for the control API, plaintext when absent
+    pub tls: Option<GrpcTlsSettings>,
+    /// Optional bearer-token authentication; every caller is an admin when absent
+    pub auth: Option<AuthSettings>,
+    /// Report failed mutations as `success = false` responses instead of error statuses,
+    /// for clients written before the statuses were introduced
+    #[serde(default)]
+    pub legacy_error_responses: bool,
+    /// Optional rate and concurrency limits of the calls; unlimited when absent
+    pub limits: Option<GrpcLimitSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct GrpcLimitSettings {
+    /// Calls per second from all callers together; unlimited when unset
+    pub requests_per_second: Option<u32>,
+    /// Calls per second from each client address; unlimited when unset
+    pub peer_requests_per_second: Option<u32>,
+    /// Mutating calls handled at once, further ones waiting their turn; unlimited when
+    /// unset
+    pub max_mutations_in_flight: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AuthSettings {
+    #[serde(default)]
+    pub tokens: Vec<ApiTokenSettings>,
+    /// JSON file with further tokens, in the same form as `tokens`
+    pub tokens_file: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ApiTokenSettings {
+    pub name: String,
+    pub token: String,
+    /// `read` or `admin`
+    pub role: String,
+    /// Tenant the token is scoped to; the token manages every zone when unset
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GrpcTlsSettings {
+    pub cert_path: String,
+    pub key_path: String,
+    /// CA that client certificates must be signed by; clients are not authenticated when unset
+    pub client_ca_path: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+pub struct StorageSettings {
+    /// Where the state is persisted: "memory", "file", "sqlite", "etcd" or "consul";
+    /// "file" when a path is set, and nothing is persisted otherwise
+    pub backend: Option<String>,
+    /// Path of the JSON snapshot file, or of the SQLite database
+    pub path: Option<String>,
+    /// URL of the etcd or Consul HTTP API
+    pub endpoint: Option<String>,
+    /// Prefix of the etcd or Consul keys, "rdns/" when unset
+    pub prefix: Option<String>,
+    /// Consul ACL token
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct MetricsSettings {
+    #[serde(default = "default_metrics_listen_addr")]
+    pub listen_addr: String,
+}
+
+fn default_metrics_listen_addr() -> String {
+    "0.0.0.0:9100".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ProbeSettings {
+    /// Canary name queried, which must have a record of `record_type`
+    pub name: String,
+    #[serde(default = "default_probe_record_type")]
+    pub record_type: String,
+    /// Seconds between rounds of probes, one over each protocol served
+    #[serde(default = "default_probe_interval_secs")]
+    pub interval_secs: u64,
+    /// Milliseconds a probe may take before it fails
+    #[serde(default = "default_probe_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Milliseconds within which probes should be answered; slower ones are counted
+    /// as missing the latency objective
+    #[serde(default = "default_probe_latency_slo_ms")]
+    pub latency_slo_ms: u64,
+    /// Failed probes in a row after which a protocol is down and the server unhealthy
+    #[serde(default = "default_probe_failures")]
+    pub failures: u32,
+}
+
+fn default_probe_record_type() -> String {
+    "A".into()
+}
+
+fn default_probe_interval_secs() -> u64 {
+    10
+}
+
+fn default_probe_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_probe_latency_slo_ms() -> u64 {
+    100
+}
+
+fn default_probe_failures() -> u32 {
+    3
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RestSettings {
+    #[serde(default = "default_rest_listen_addr")]
+    pub listen_addr: String,
+    /// Whether the web dashboard is served at `/ui`
+    #[serde(default)]
+    pub ui: bool,
+}
+
+fn default_rest_listen_addr() -> String {
+    "127.0.0.1:8080".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AuditSettings {
+    /// Path of the append-only log file, one JSON entry per line
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClusterSettings {
+    /// `leader` or `follower`
+    pub role: String,
+    /// Control API URL of the leader, which a follower replicates from
+    pub leader: Option<String>,
+    /// Admin API token a follower presents to the leader
+    pub token: Option<String>,
+    /// CA that the leader's TLS certificate is signed by, for an `https` leader URL
+    pub ca_path: Option<String>,
+    /// Seconds between heartbeats from the leader; a follower reconnects after missing three
+    #[serde(default = "default_cluster_heartbeat_secs")]
+    pub heartbeat_secs: u64,
+}
+
+fn default_cluster_heartbeat_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WebhookSettings {
+    #[serde(default = "default_webhook_listen_addr")]
+    pub listen_addr: String,
+    /// Domains external-dns may manage; every hosted zone when empty
+    #[serde(default)]
+    pub domain_filter: Vec<String>,
+}
+
+fn default_webhook_listen_addr() -> String {
+    "127.0.0.1:8888".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct NotificationSettings {
+    /// http(s) URL the changes are POSTed to
+    pub url: String,
+    /// Key of the HMAC-SHA256 signature sent with each change; unsigned when unset
+    pub secret: Option<String>,
+    /// Only changes of names under these zones; all changes when empty
+    #[serde(default)]
+    pub zones: Vec<String>,
+    /// Only these kinds of change, e.g. `record.deleted`; all kinds when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Attempts after the first at delivering a change
+    #[serde(default = "default_notification_retries")]
+    pub max_retries: u32,
+    #[serde(default = "default_notification_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_notification_retries() -> u32 {
+    5
+}
+
+fn default_notification_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct EventSettings {
+    /// `nats://[token@|user:password@]host[:port]` or `kafka://broker[:port][,broker...]`
+    pub sink: String,
+    /// NATS subject or Kafka topic of the record changes; not exported when unset
+    pub records_subject: Option<String>,
+    /// NATS subject or Kafka topic of the queries; not exported when unset
+    pub queries_subject: Option<String>,
+    /// Kafka partition the events are produced to
+    #[serde(default)]
+    pub partition: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AcmeSettings {
+    /// TTL of the challenge records
+    pub ttl: u32,
+    /// Seconds after which a challenge record is removed, if it hasn't been cleared
+    pub cleanup_secs: u64,
+    /// Optional acme-dns compatible HTTP API
+    pub acme_dns: Option<AcmeDnsSettings>,
+    /// Optional certificate of the DoT and DoH listeners, obtained through ACME
+    pub certificate: Option<AcmeCertificateSettings>,
+}
+
+impl Default for AcmeSettings {
+    fn default() -> Self {
+        AcmeSettings {
+            ttl: 60,
+            cleanup_secs: 3600,
+            acme_dns: None,
+            certificate: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AcmeDnsSettings {
+    #[serde(default = "default_acme_dns_listen_addr")]
+    pub listen_addr: String,
+    #[serde(default)]
+    pub accounts: Vec<AcmeAccountSettings>,
+}
+
+fn default_acme_dns_listen_addr() -> String {
+    "127.0.0.1:8553".into()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AcmeAccountSettings {
+    /// Sent by clients as `X-Api-User`
+    pub username: String,
+    /// Sent by clients as `X-Api-Key`
+    pub password: String,
+    /// Domains the account may set challenges of, their subdomains included
+    pub domains: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct AcmeCertificateSettings {
+    /// Names the certificate is for, each in a hosted zone
+    pub domains: Vec<String>,
+    /// Directory URL of the certificate authority
+    #[serde(default = "default_acme_directory_url")]
+    pub directory_url: String,
+    /// Contact URLs of the account, e.g. "mailto:admin@example.com"
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Directory the account key, certificate and its key are stored in
+    #[serde(default = "default_acme_certificate_dir")]
+    pub dir: String,
+    /// Days before the certificate expires that it is renewed
+    #[serde(default = "default_acme_renew_before_days")]
+    pub renew_before_days: u64,
+    /// Seconds between checks of whether the certificate is due for renewal
+    #[serde(default = "default_acme_check_interval_secs")]
+    pub check_interval_secs: u64,
+    /// Seconds to wait for the challenge records to reach every server of the zones
+    #[serde(default = "default_acme_propagation_secs")]
+    pub propagation_secs: u64,
+}
+
+fn default_acme_directory_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".into()
+}
+
+fn default_acme_certificate_dir() -> String {
+    "data/certs".into()
+}
+
+fn default_acme_renew_before_days() -> u64 {
+    30
+}
+
+fn default_acme_check_interval_secs() -> u64 {
+    43200
+}
+
+fn default_acme_propagation_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct LoggingSettings {
+    /// How much is logged: "info", or "debug" to log every query as well
+    pub level: String,
+    /// Optional dnstap output of every query and response
+    pub dnstap: Option<DnstapSettings>,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        LoggingSettings {
+            level: "info".to_string(),
+            dnstap: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct DnstapSettings {
+    /// Collector to write frames to, as `unix:<path>` or `tcp:<host:port>`
+    pub target: String,
+    /// Server identity recorded in each frame
+    pub identity: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TelemetrySettings {
+    /// OTLP/gRPC collector spans are exported to, e.g. `http://localhost:4317`
+    pub otlp_endpoint: String,
+    /// CA certificate the collector's certificate is verified against, for an https
+    /// endpoint
+    pub ca_cert: Option<String>,
+    /// `service.name` of the exported spans
+    #[serde(default = "default_telemetry_service_name")]
+    pub service_name: String,
+    /// Fraction of the DNS queries and control API calls traced, unless a call carries
+    /// the trace context of a caller
+    #[serde(default = "default_telemetry_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+fn default_telemetry_service_name() -> String {
+    "rdns".into()
+}
+
+fn default_telemetry_sample_ratio() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ShutdownSettings {
+    /// How long in-flight requests may take to finish on SIGTERM/SIGINT, in seconds
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        ShutdownSettings { drain_timeout_secs: 10 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PrivilegeSettings {
+    /// User to switch to, by name or numeric ID
+    pub user: String,
+    /// Group to switch to, by name or numeric ID; the user's primary group when unset
+    pub group: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DaemonSettings {
+    /// Fork into the background once the config is loaded
+    pub detach: bool,
+    /// File the process ID is written to while rdns runs
+    pub pid_file: Option<String>,
+    /// File the output of the detached process is appended to; discarded when unset
+    pub log_file: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RuntimeSettings {
+    /// Worker threads of the main runtime; one per CPU when 0
+    pub worker_threads: usize,
+    /// Upper bound on the threads of each runtime's blocking pool
+    pub max_blocking_threads: usize,
+    /// Serve DNS on a runtime of its own, apart from the control plane
+    pub dedicated_dns: bool,
+    /// Worker threads of the DNS runtime; one per CPU when 0
+    pub dns_worker_threads: usize,
+}
+
+impl Default for RuntimeSettings {
+    fn default() -> Self {
+        RuntimeSettings {
+            worker_threads: 0,
+            max_blocking_threads: 512,
+            dedicated_dns: false,
+            dns_worker_threads: 0,
+        }
+    }
 }
@@ -0,0 +1,129 @@
+//! Graceful shutdown.
+//!
+//! On SIGTERM or SIGINT the shutdown signal is raised: every listener stops accepting
+//! new work while in-flight queries and API calls, including `WatchRecords` streams, are
+//! brought to an end. The process waits up to the drain timeout for the servers to
+//! finish, then writes a final snapshot before exiting. The `Shutdown` call of the
+//! Admin service raises the same signal, as do Ctrl-C and a service stop on Windows.
+//!
+//! Ahead of maintenance an instance can be drained instead, through the Admin service's
+//! `DrainMode` call: it stops reporting itself healthy, so orchestration and load
+//! balancers move clients elsewhere, and may also turn queries away while it keeps
+//! running.
+
+use std::fmt;
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+use crate::settings::ShutdownSettings;
+
+/// Config options for shutting down
+pub struct ShutdownOptions {
+    /// How long in-flight work is given to finish once shutdown begins.
+    pub drain_timeout: Duration,
+}
+
+impl From<ShutdownSettings> for ShutdownOptions {
+    fn from(cfg: ShutdownSettings) -> Self {
+        ShutdownOptions {
+            drain_timeout: Duration::from_secs(cfg.drain_timeout_secs),
+        }
+    }
+}
+
+/// Receiving end of the shutdown signal, handed to every server.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<bool>);
+
+impl Shutdown {
+    /// Creates the shutdown signal, raised with `trigger`.
+    pub fn new() -> (ShutdownTrigger, Self) {
+        let (sender, receiver) = watch::channel(false);
+        (ShutdownTrigger(sender), Self(receiver))
+    }
+
+    /// Resolves once shutdown has begun, or the trigger is gone.
+    pub async fn requested(mut self) {
+        let _ = self.0.wait_for(|&requested| requested).await;
+    }
+}
+
+/// Sending end of the shutdown signal.
+#[derive(Clone)]
+pub struct ShutdownTrigger(watch::Sender<bool>);
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// How queries are answered while the instance is drained.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DrainMode {
+    /// Not drained: queries are answered and health reported as usual.
+    #[default]
+    Off,
+    /// UDP queries are answered with an empty, truncated response, moving clients to TCP.
+    Truncate,
+    /// Queries are refused, so resolvers move on to the other servers of the zone.
+    Refuse,
+    /// Queries are answered as usual; only the health service reports not serving.
+    Unhealthy,
+}
+
+impl DrainMode {
+    /// Whether the instance reports itself healthy in this mode.
+    pub fn healthy(self) -> bool {
+        self == DrainMode::Off
+    }
+}
+
+impl fmt::Display for DrainMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DrainMode::Off => "off",
+            DrainMode::Truncate => "truncate",
+            DrainMode::Refuse => "refuse",
+            DrainMode::Unhealthy => "unhealthy",
+        })
+    }
+}
+
+/// Resolves on the first SIGTERM or SIGINT.
+///
+/// # Errors
+///
+/// Returns an error if the signal handlers can't be installed.
+#[cfg(unix)]
+pub async fn terminate_signal() -> anyhow::Result<&'static str> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(tokio::select! {
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    })
+}
+
+/// Resolves on the first Ctrl-C or Ctrl-Break, on the console window being closed, or
+/// on the Service Control Manager stopping the service.
+///
+/// # Errors
+///
+/// Returns an error if the console handlers can't be installed.
+#[cfg(windows)]
+pub async fn terminate_signal() -> anyhow::Result<&'static str> {
+    use tokio::signal::windows;
+
+    let mut ctrl_c = windows::ctrl_c()?;
+    let mut ctrl_break = windows::ctrl_break()?;
+    let mut ctrl_close = windows::ctrl_close()?;
+    Ok(tokio::select! {
+        _ = ctrl_c.recv() => "CTRL_C_EVENT",
+        _ = ctrl_break.recv() => "CTRL_BREAK_EVENT",
+        _ = ctrl_close.recv() => "CTRL_CLOSE_EVENT",
+        control = crate::daemon::service_stop() => control,
+    })
+}
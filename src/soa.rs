@@ -0,0 +1,166 @@
+//! Automatic SOA and NS records.
+//!
+//! Zones created through the control API, or empty at startup, have no SOA, which
+//! secondaries need to notice changes and resolvers need to cache negative answers.
+//! With `[dns.soa]` configured, every primary zone without an SOA gets one generated from
+//! the settings, along with NS records at its apex if it has none, and the serial of a
+//! zone's SOA is incremented on every change made to the zone.
+
+use hickory_proto::rr::rdata::{NS, SOA};
+use hickory_proto::rr::{LowerName, Name, RData, Record};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::settings::{SoaSettings, ZoneSoaSettings};
+
+/// The SOA and NS records of one zone, or of every zone without its own.
+#[derive(Clone)]
+pub struct SoaTemplate {
+    pub mname: Name,
+    pub rname: Name,
+    pub nameservers: Vec<Name>,
+    pub refresh: i32,
+    pub retry: i32,
+    pub expire: i32,
+    pub minimum: u32,
+    pub ttl: u32,
+}
+
+impl SoaTemplate {
+    /// The SOA record of zone `origin` with `serial`, followed by its NS records.
+    pub fn records(&self, origin: &Name, serial: u32) -> Vec<Record> {
+        let soa = SOA::new(
+            self.mname.clone(),
+            self.rname.clone(),
+            serial,
+            self.refresh,
+            self.retry,
+            self.expire,
+            self.minimum,
+        );
+        let mut records = vec![Record::from_rdata(origin.clone(), self.ttl, RData::SOA(soa))];
+        records.extend(
+            self.nameservers
+                .iter()
+                .map(|nameserver| Record::from_rdata(origin.clone(), self.ttl, RData::NS(NS(nameserver.clone())))),
+        );
+        records
+    }
+}
+
+/// Config options for automatic SOA and NS records
+#[derive(Clone)]
+pub struct SoaOptions {
+    pub default: SoaTemplate,
+    /// Templates of the zones with overrides.
+    pub zones: Vec<(LowerName, SoaTemplate)>,
+}
+
+impl SoaOptions {
+    /// The template of the zone at `origin`.
+    pub fn template(&self, origin: &LowerName) -> &SoaTemplate {
+        self.zones
+            .iter()
+            .find(|(zone, _)| zone == origin)
+            .map_or(&self.default, |(_, template)| template)
+    }
+}
+
+impl TryFrom<SoaSettings> for SoaOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: SoaSettings) -> anyhow::Result<Self> {
+        let default = SoaTemplate {
+            mname: parse_name(&cfg.mname)?,
+            rname: parse_mailbox(&cfg.rname)?,
+            nameservers: parse_nameservers(&cfg.nameservers, &cfg.mname)?,
+            refresh: parse_timer("refresh_secs", cfg.refresh_secs)?,
+            retry: parse_timer("retry_secs", cfg.retry_secs)?,
+            expire: parse_timer("expire_secs", cfg.expire_secs)?,
+            minimum: cfg.minimum_ttl,
+            ttl: cfg.ttl,
+        };
+        let zones = cfg
+            .zones
+            .into_iter()
+            .map(|zone| Ok((LowerName::new(&parse_name(&zone.zone)?), override_template(&default, zone)?)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(SoaOptions { default, zones })
+    }
+}
+
+/// `default` with the settings `cfg` overrides.
+fn override_template(default: &SoaTemplate, cfg: ZoneSoaSettings) -> anyhow::Result<SoaTemplate> {
+    let mname = cfg.mname.as_deref().map(parse_name).transpose()?.unwrap_or_else(|| default.mname.clone());
+    let nameservers = match (&cfg.nameservers, &cfg.mname) {
+        (Some(nameservers), _) => parse_nameservers(nameservers, &mname.to_string())?,
+        // NS records that only default to the default mname follow the zone's own
+        (None, Some(_)) if default.nameservers == [default.mname.clone()] => vec![mname.clone()],
+        (None, _) => default.nameservers.clone(),
+    };
+    Ok(SoaTemplate {
+        rname: cfg.rname.as_deref().map(parse_mailbox).transpose()?.unwrap_or_else(|| default.rname.clone()),
+        nameservers,
+        refresh: cfg.refresh_secs.map(|secs| parse_timer("refresh_secs", secs)).transpose()?.unwrap_or(default.refresh),
+        retry: cfg.retry_secs.map(|secs| parse_timer("retry_secs", secs)).transpose()?.unwrap_or(default.retry),
+        expire: cfg.expire_secs.map(|secs| parse_timer("expire_secs", secs)).transpose()?.unwrap_or(default.expire),
+        minimum: cfg.minimum_ttl.unwrap_or(default.minimum),
+        ttl: cfg.ttl.unwrap_or(default.ttl),
+        mname,
+    })
+}
+
+fn parse_name(name: &str) -> anyhow::Result<Name> {
+    let mut parsed = Name::from_str(name).map_err(|e| anyhow::anyhow!("invalid name {}: {}", name, e))?;
+    parsed.set_fqdn(true);
+    Ok(parsed)
+}
+
+/// Parses an SOA mailbox, accepting `user@example.com` for `user.example.com`.
+fn parse_mailbox(mailbox: &str) -> anyhow::Result<Name> {
+    parse_name(&mailbox.replacen('@', ".", 1))
+}
+
+fn parse_nameservers(nameservers: &[String], mname: &str) -> anyhow::Result<Vec<Name>> {
+    if nameservers.is_empty() {
+        return Ok(vec![parse_name(mname)?]);
+    }
+    nameservers.iter().map(|nameserver| parse_name(nameserver)).collect()
+}
+
+/// SOA timers are signed 32-bit numbers on the wire.
+fn parse_timer(setting: &str, secs: u32) -> anyhow::Result<i32> {
+    i32::try_from(secs).map_err(|_| anyhow::anyhow!("soa.{} must be at most {}", setting, i32::MAX))
+}
+
+/// Serial of a newly generated SOA: the current Unix time, so that a zone generated again
+/// after its state was lost doesn't go back to a serial its secondaries already have.
+pub fn initial_serial() -> u32 {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |elapsed| elapsed.as_secs());
+    secs as u32
+}
+
+/// `record` with the serial of its SOA incremented, using RFC 1982 serial arithmetic.
+pub fn bump_serial(record: &Record) -> Option<Record> {
+    let Some(RData::SOA(soa)) = record.data() else {
+        return None;
+    };
+    with_serial(record, soa.serial().wrapping_add(1))
+}
+
+/// `record` with the serial of its SOA replaced by `serial`.
+pub fn with_serial(record: &Record, serial: u32) -> Option<Record> {
+    let Some(RData::SOA(soa)) = record.data() else {
+        return None;
+    };
+    let soa = SOA::new(
+        soa.mname().clone(),
+        soa.rname().clone(),
+        serial,
+        soa.refresh(),
+        soa.retry(),
+        soa.expire(),
+        soa.minimum(),
+    );
+    Some(Record::from_rdata(record.name().clone(), record.ttl(), RData::SOA(soa)))
+}
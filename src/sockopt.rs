@@ -0,0 +1,104 @@
+//! Socket options of the DNS listeners.
+//!
+//! `[dns.socket]` tunes the UDP sockets and the TCP and DoT listeners as they are bound,
+//! and the sockets systemd passes in as they are taken over:
+//!
+//! - `recv_buffer_bytes` and `send_buffer_bytes` set SO_RCVBUF and SO_SNDBUF, so that
+//!   bursts of queries aren't dropped before the workers read them. The kernel doubles
+//!   the sizes given and caps them at `net.core.rmem_max` and `net.core.wmem_max`.
+//! - `dscp` marks the answers sent with a DiffServ codepoint, e.g. 46 for expedited
+//!   forwarding, through IP_TOS on IPv4 and IPV6_TCLASS on IPv6, which only unix has.
+//! - `freebind` sets IP_FREEBIND, so that addresses not (yet) configured on the host, such
+//!   as anycast VIPs brought up by a routing daemon after rdns starts, can be bound. It
+//!   is only available on Linux.
+
+use socket2::Socket;
+use std::net::SocketAddr;
+
+use crate::settings::SocketSettings;
+
+/// Options of the sockets of the DNS listeners
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SocketOptions {
+    /// SO_RCVBUF, the system default when unset
+    pub recv_buffer_bytes: Option<usize>,
+    /// SO_SNDBUF, the system default when unset
+    pub send_buffer_bytes: Option<usize>,
+    /// DiffServ codepoint the answers are marked with, unmarked when unset
+    pub dscp: Option<u8>,
+    /// Whether addresses not configured on the host can be bound
+    pub freebind: bool,
+}
+
+impl TryFrom<SocketSettings> for SocketOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: SocketSettings) -> anyhow::Result<Self> {
+        if cfg.dscp.is_some_and(|dscp| dscp > 63) {
+            anyhow::bail!("dns.socket.dscp must be from 0 to 63");
+        }
+        if cfg.recv_buffer_bytes == Some(0) || cfg.send_buffer_bytes == Some(0) {
+            anyhow::bail!("dns.socket buffer sizes must be at least 1 byte");
+        }
+        if cfg.freebind && !cfg!(target_os = "linux") {
+            anyhow::bail!("dns.socket.freebind is only supported on Linux");
+        }
+        Ok(SocketOptions {
+            recv_buffer_bytes: cfg.recv_buffer_bytes,
+            send_buffer_bytes: cfg.send_buffer_bytes,
+            dscp: cfg.dscp,
+            freebind: cfg.freebind,
+        })
+    }
+}
+
+impl SocketOptions {
+    /// Sets the options on `socket`, to be bound to `addr` or already bound to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the system rejects an option.
+    pub fn apply(&self, socket: &Socket, addr: SocketAddr) -> std::io::Result<()> {
+        if let Some(bytes) = self.recv_buffer_bytes {
+            socket.set_recv_buffer_size(bytes)?;
+        }
+        if let Some(bytes) = self.send_buffer_bytes {
+            socket.set_send_buffer_size(bytes)?;
+        }
+        if let Some(dscp) = self.dscp {
+            // The codepoint takes the upper six bits of the traffic class, ECN the rest
+            let class = u32::from(dscp) << 2;
+            match addr {
+                SocketAddr::V4(_) => socket.set_tos(class)?,
+                SocketAddr::V6(_) => set_tclass_v6(socket, class)?,
+            }
+        }
+        if self.freebind {
+            set_freebind(socket, addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn set_tclass_v6(socket: &Socket, class: u32) -> std::io::Result<()> {
+    socket.set_tclass_v6(class)
+}
+
+#[cfg(not(unix))]
+fn set_tclass_v6(_socket: &Socket, _class: u32) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "IPV6_TCLASS is only supported on unix"))
+}
+
+#[cfg(target_os = "linux")]
+fn set_freebind(socket: &Socket, addr: SocketAddr) -> std::io::Result<()> {
+    match addr {
+        SocketAddr::V4(_) => socket.set_freebind(true),
+        SocketAddr::V6(_) => socket.set_freebind_ipv6(true),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_freebind(_socket: &Socket, _addr: SocketAddr) -> std::io::Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "IP_FREEBIND is only supported on Linux"))
+}
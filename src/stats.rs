@@ -0,0 +1,524 @@
+//! Query statistics.
+//!
+//! With `[dns.stats]` enabled, every query is counted by its name and type, along with
+//! the distinct clients that sent it and when it was last sent. `GetQueryStats` of the
+//! `Stats` service lists the most queried names, optionally of one zone and within a
+//! recent time window, to find hot names, or the records of a zone that were queried
+//! least recently or not at all, to find stale ones. Counts are kept per minute for
+//! `retention_mins`, the longest window that can be asked about, alongside the totals
+//! since tracking began. Up to `max_clients` distinct clients are counted per name and
+//! type, and up to `max_names` names and types are tracked, the least recently queried
+//! being dropped to make room. Statistics are kept in memory and start afresh on
+//! restart.
+//!
+//! Queries are also counted per client, up to `max_tracked_clients` of them, along with
+//! the NXDOMAIN answers they got, and `GetTopClients` lists the clients that sent the
+//! most. With `[dns.stats.anomalies]` set, a minute with `spike_factor` times the queries
+//! of the average of the `baseline_mins` before it, and at least `spike_min_qps` per
+//! second, is reported as a query spike, and a client getting `nxdomain_per_min` NXDOMAIN
+//! answers within a minute as an NXDOMAIN flood, e.g. a random-subdomain attack. Each is
+//! reported once per minute, to the subscribers of `WatchAnomalies` and in the log.
+//! Clients are counted by their addresses as `[dns.privacy]` anonymizes them, and
+//! purged once they haven't queried for its `client_retention_mins`.
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{LowerName, RecordType};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+
+use crate::privacy::{self, PrivacyOptions};
+use crate::settings::{AnomalySettings, StatsSettings};
+
+/// Anomalies buffered for each subscriber that is behind.
+const ANOMALY_CAPACITY: usize = 64;
+
+/// How often clients past their retention are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Config options for query statistics
+#[derive(Debug, Clone)]
+pub struct StatsOptions {
+    /// Names and types tracked at most.
+    pub max_names: usize,
+    /// How long per-minute counts are kept, the longest window that can be asked about.
+    pub retention: Duration,
+    /// Distinct clients counted per name and type at most.
+    pub max_clients: usize,
+    /// Clients whose queries are counted at most.
+    pub max_tracked_clients: usize,
+    /// Thresholds of the anomalies reported; none are when unset.
+    pub anomalies: Option<AnomalyOptions>,
+}
+
+/// Thresholds of the anomalies reported
+#[derive(Debug, Clone)]
+pub struct AnomalyOptions {
+    /// Times the queries of the average minute that make a spike
+    pub spike_factor: u64,
+    /// Queries per minute below which no minute is a spike
+    pub spike_min_queries: u64,
+    /// Minutes the average is taken over
+    pub baseline_mins: u64,
+    /// NXDOMAIN answers per minute to a single client that make a flood
+    pub nxdomain_per_min: u64,
+}
+
+impl TryFrom<AnomalySettings> for AnomalyOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: AnomalySettings) -> anyhow::Result<Self> {
+        if cfg.spike_factor < 2 {
+            anyhow::bail!("dns.stats.anomalies.spike_factor must be at least 2");
+        }
+        if cfg.baseline_mins == 0 {
+            anyhow::bail!("dns.stats.anomalies.baseline_mins must be at least 1");
+        }
+        if cfg.nxdomain_per_min == 0 {
+            anyhow::bail!("dns.stats.anomalies.nxdomain_per_min must be at least 1");
+        }
+        Ok(AnomalyOptions {
+            spike_factor: cfg.spike_factor,
+            spike_min_queries: cfg.spike_min_qps.saturating_mul(60),
+            baseline_mins: cfg.baseline_mins,
+            nxdomain_per_min: cfg.nxdomain_per_min,
+        })
+    }
+}
+
+impl TryFrom<StatsSettings> for StatsOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: StatsSettings) -> anyhow::Result<Self> {
+        if cfg.max_names == 0 {
+            anyhow::bail!("dns.stats.max_names must be at least 1");
+        }
+        if cfg.retention_mins == 0 {
+            anyhow::bail!("dns.stats.retention_mins must be at least 1");
+        }
+        if cfg.max_tracked_clients == 0 {
+            anyhow::bail!("dns.stats.max_tracked_clients must be at least 1");
+        }
+        Ok(StatsOptions {
+            max_names: cfg.max_names,
+            retention: Duration::from_secs(cfg.retention_mins * 60),
+            max_clients: cfg.max_clients,
+            max_tracked_clients: cfg.max_tracked_clients,
+            anomalies: cfg.anomalies.map(AnomalyOptions::try_from).transpose()?,
+        })
+    }
+}
+
+/// Queries counted for a name and type.
+struct Counter {
+    total: u64,
+    /// Queries per minute since the epoch, oldest first, for the minutes with any
+    minutes: VecDeque<(u64, u64)>,
+    /// Distinct clients, with when each last sent a query
+    clients: HashMap<IpAddr, SystemTime>,
+    last_queried: SystemTime,
+}
+
+impl Counter {
+    /// Queries since minute `since`, or in total without one.
+    fn queries_since(&self, since: Option<u64>) -> u64 {
+        match since {
+            Some(since) => self.minutes.iter().filter(|(minute, _)| *minute >= since).map(|(_, count)| count).sum(),
+            None => self.total,
+        }
+    }
+}
+
+/// Queries counted for a client.
+struct ClientCounter {
+    total: u64,
+    nxdomain: u64,
+    /// Queries and NXDOMAIN answers per minute since the epoch, oldest first, for the
+    /// minutes with any
+    minutes: VecDeque<(u64, u64, u64)>,
+    last_queried: SystemTime,
+    /// Minute an NXDOMAIN flood was last reported in
+    flagged: Option<u64>,
+}
+
+/// Queries counted across all clients, for spotting spikes.
+#[derive(Default)]
+struct Rate {
+    /// Queries per minute since the epoch, oldest first, for the minutes with any
+    minutes: VecDeque<(u64, u64)>,
+    /// Minute a spike was last reported in
+    flagged: Option<u64>,
+}
+
+/// The statistics of a name and type, as listed through the control API.
+#[derive(Debug, Clone)]
+pub struct NameStats {
+    pub name: LowerName,
+    pub record_type: RecordType,
+    /// Queries within the window asked about
+    pub queries: u64,
+    /// Queries since tracking began
+    pub total: u64,
+    pub clients: usize,
+    /// Whether more clients sent queries than were counted
+    pub clients_capped: bool,
+    /// When the last query came in; `None` if none did since tracking began
+    pub last_queried: Option<SystemTime>,
+}
+
+/// The statistics of a client, as listed through the control API.
+#[derive(Debug, Clone)]
+pub struct ClientStats {
+    pub client: IpAddr,
+    /// Queries within the window asked about
+    pub queries: u64,
+    /// NXDOMAIN answers within the window asked about
+    pub nxdomain: u64,
+    /// Queries since tracking began
+    pub total: u64,
+    pub last_queried: SystemTime,
+}
+
+/// A kind of anomaly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyKind {
+    /// Far more queries in a minute than in those before it
+    QuerySpike,
+    /// Many NXDOMAIN answers to a single client in a minute
+    NxdomainFlood,
+}
+
+impl fmt::Display for AnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AnomalyKind::QuerySpike => "query spike",
+            AnomalyKind::NxdomainFlood => "NXDOMAIN flood",
+        })
+    }
+}
+
+/// An anomaly, as reported to the subscribers of `WatchAnomalies`.
+#[derive(Debug, Clone)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    /// The client of an NXDOMAIN flood
+    pub client: Option<IpAddr>,
+    /// Queries, or NXDOMAIN answers, counted within the minute when it was reported
+    pub count: u64,
+    /// The count that made it an anomaly
+    pub threshold: u64,
+    pub timestamp: SystemTime,
+}
+
+/// Which statistics are listed.
+#[derive(Debug, Clone, Default)]
+pub struct StatsQuery {
+    /// Zone the names must be at or under
+    pub zone: Option<LowerName>,
+    pub name: Option<LowerName>,
+    pub record_type: Option<RecordType>,
+    /// How far back queries are counted, up to the retention; since tracking began when
+    /// unset
+    pub window: Option<Duration>,
+    /// Entries listed at most
+    pub top: usize,
+}
+
+impl StatsQuery {
+    fn matches(&self, name: &LowerName, record_type: RecordType) -> bool {
+        self.zone.as_ref().is_none_or(|zone| zone.zone_of(name))
+            && self.name.as_ref().is_none_or(|wanted| wanted == name)
+            && self.record_type.is_none_or(|wanted| wanted == record_type)
+    }
+}
+
+/// Query counts by name and type.
+pub struct QueryStats {
+    options: StatsOptions,
+    privacy: Option<PrivacyOptions>,
+    started: SystemTime,
+    counters: Mutex<HashMap<(LowerName, RecordType), Counter>>,
+    clients: Mutex<HashMap<IpAddr, ClientCounter>>,
+    rate: Mutex<Rate>,
+    anomalies: broadcast::Sender<Anomaly>,
+}
+
+/// The minute since the epoch `time` falls in.
+fn minute_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60
+}
+
+impl QueryStats {
+    pub fn new(options: StatsOptions, privacy: Option<PrivacyOptions>) -> Self {
+        QueryStats {
+            options,
+            privacy,
+            started: SystemTime::now(),
+            counters: Mutex::new(HashMap::new()),
+            clients: Mutex::new(HashMap::new()),
+            rate: Mutex::new(Rate::default()),
+            anomalies: broadcast::channel(ANOMALY_CAPACITY).0,
+        }
+    }
+
+    pub fn options(&self) -> &StatsOptions {
+        &self.options
+    }
+
+    /// When tracking began.
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    /// Subscribes to the anomalies reported from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Anomaly> {
+        self.anomalies.subscribe()
+    }
+
+    /// Counts a query for `name` and `record_type` from `client`, answered with
+    /// `response_code`, reporting the anomalies it makes.
+    pub fn observe(&self, name: &LowerName, record_type: RecordType, client: IpAddr, response_code: ResponseCode) {
+        let now = SystemTime::now();
+        let minute = minute_of(now);
+        let oldest = minute.saturating_sub(self.options.retention.as_secs() / 60);
+        let client = privacy::anonymize(self.privacy.as_ref(), client);
+        self.count_name(name, record_type, client, now, minute, oldest);
+        let flood = self.count_client(client, response_code == ResponseCode::NXDomain, now, minute, oldest);
+        let spike = self.count_rate(now, minute);
+        for anomaly in flood.into_iter().chain(spike) {
+            match anomaly.client {
+                Some(client) => eprintln!("Anomaly: {} to {}, {} NXDOMAIN answers within a minute", anomaly.kind, client, anomaly.count),
+                None => eprintln!("Anomaly: {}, {} queries within a minute", anomaly.kind, anomaly.count),
+            }
+            // Sending only fails when nobody is subscribed
+            let _ = self.anomalies.send(anomaly);
+        }
+    }
+
+    fn count_name(&self, name: &LowerName, record_type: RecordType, client: IpAddr, now: SystemTime, minute: u64, oldest: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        let key = (name.clone(), record_type);
+        if !counters.contains_key(&key) && counters.len() >= self.options.max_names {
+            evict(&mut counters, |counter| counter.last_queried);
+        }
+        let counter = counters.entry(key).or_insert_with(|| Counter {
+            total: 0,
+            minutes: VecDeque::new(),
+            clients: HashMap::new(),
+            last_queried: now,
+        });
+        counter.total += 1;
+        counter.last_queried = now;
+        match counter.minutes.back_mut() {
+            Some((last, count)) if *last == minute => *count += 1,
+            _ => counter.minutes.push_back((minute, 1)),
+        }
+        while counter.minutes.front().is_some_and(|(minute, _)| *minute < oldest) {
+            counter.minutes.pop_front();
+        }
+        if counter.clients.len() < self.options.max_clients || counter.clients.contains_key(&client) {
+            counter.clients.insert(client, now);
+        }
+    }
+
+    /// Counts a query from `client`, returning the NXDOMAIN flood it makes, if any.
+    fn count_client(&self, client: IpAddr, nxdomain: bool, now: SystemTime, minute: u64, oldest: u64) -> Option<Anomaly> {
+        let mut clients = self.clients.lock().unwrap();
+        if !clients.contains_key(&client) && clients.len() >= self.options.max_tracked_clients {
+            evict(&mut clients, |counter| counter.last_queried);
+        }
+        let counter = clients.entry(client).or_insert_with(|| ClientCounter {
+            total: 0,
+            nxdomain: 0,
+            minutes: VecDeque::new(),
+            last_queried: now,
+            flagged: None,
+        });
+        counter.total += 1;
+        counter.nxdomain += u64::from(nxdomain);
+        counter.last_queried = now;
+        match counter.minutes.back_mut() {
+            Some((last, queries, nxdomains)) if *last == minute => {
+                *queries += 1;
+                *nxdomains += u64::from(nxdomain);
+            }
+            _ => counter.minutes.push_back((minute, 1, u64::from(nxdomain))),
+        }
+        while counter.minutes.front().is_some_and(|(minute, _, _)| *minute < oldest) {
+            counter.minutes.pop_front();
+        }
+
+        let threshold = self.options.anomalies.as_ref()?.nxdomain_per_min;
+        let (_, _, count) = *counter.minutes.back()?;
+        if !nxdomain || count < threshold || counter.flagged == Some(minute) {
+            return None;
+        }
+        counter.flagged = Some(minute);
+        Some(Anomaly {
+            kind: AnomalyKind::NxdomainFlood,
+            client: Some(client),
+            count,
+            threshold,
+            timestamp: now,
+        })
+    }
+
+    /// Counts a query across all clients, returning the spike it makes, if any.
+    fn count_rate(&self, now: SystemTime, minute: u64) -> Option<Anomaly> {
+        let anomalies = self.options.anomalies.as_ref()?;
+        let mut rate = self.rate.lock().unwrap();
+        match rate.minutes.back_mut() {
+            Some((last, count)) if *last == minute => *count += 1,
+            _ => rate.minutes.push_back((minute, 1)),
+        }
+        let first = minute.saturating_sub(anomalies.baseline_mins);
+        while rate.minutes.front().is_some_and(|(minute, _)| *minute < first) {
+            rate.minutes.pop_front();
+        }
+
+        // Until a whole baseline has been counted, the minutes before tracking began
+        // would make any minute look like a spike
+        if minute_of(self.started) >= first {
+            return None;
+        }
+        let before: u64 = rate.minutes.iter().filter(|(at, _)| *at < minute).map(|(_, count)| count).sum();
+        let threshold = (before / anomalies.baseline_mins)
+            .saturating_mul(anomalies.spike_factor)
+            .max(anomalies.spike_min_queries);
+        let (_, count) = *rate.minutes.back()?;
+        if count < threshold || rate.flagged == Some(minute) {
+            return None;
+        }
+        rate.flagged = Some(minute);
+        Some(Anomaly {
+            kind: AnomalyKind::QuerySpike,
+            client: None,
+            count,
+            threshold,
+            timestamp: now,
+        })
+    }
+
+    /// Drops the clients that last sent a query before `cutoff`, from the per-client
+    /// counts and from the distinct clients of every name and type.
+    pub fn purge_clients(&self, cutoff: SystemTime) {
+        self.clients.lock().unwrap().retain(|_, counter| counter.last_queried >= cutoff);
+        for counter in self.counters.lock().unwrap().values_mut() {
+            counter.clients.retain(|_, last_queried| *last_queried >= cutoff);
+        }
+    }
+
+    /// The first minute of `window`, which reaches back as far as the retention at most.
+    fn window_start(&self, window: Option<Duration>) -> Option<u64> {
+        let window = window?.min(self.options.retention);
+        Some(minute_of(SystemTime::now().checked_sub(window).unwrap_or(UNIX_EPOCH)))
+    }
+
+    fn stats_of(&self, name: &LowerName, record_type: RecordType, counter: Option<&Counter>, since: Option<u64>) -> NameStats {
+        NameStats {
+            name: name.clone(),
+            record_type,
+            queries: counter.map_or(0, |counter| counter.queries_since(since)),
+            total: counter.map_or(0, |counter| counter.total),
+            clients: counter.map_or(0, |counter| counter.clients.len()),
+            clients_capped: counter.is_some_and(|counter| counter.clients.len() >= self.options.max_clients),
+            last_queried: counter.map(|counter| counter.last_queried),
+        }
+    }
+
+    /// The names and types `query` matches that were queried the most within its
+    /// window, most queried first.
+    pub fn top(&self, query: &StatsQuery) -> Vec<NameStats> {
+        let since = self.window_start(query.window);
+        let counters = self.counters.lock().unwrap();
+        let mut stats: Vec<NameStats> = counters
+            .iter()
+            .filter(|((name, record_type), _)| query.matches(name, *record_type))
+            .map(|((name, record_type), counter)| self.stats_of(name, *record_type, Some(counter), since))
+            .filter(|stats| stats.queries > 0)
+            .collect();
+        drop(counters);
+        stats.sort_by(|a, b| b.queries.cmp(&a.queries).then_with(|| (&a.name, a.record_type).cmp(&(&b.name, b.record_type))));
+        stats.truncate(query.top);
+        stats
+    }
+
+    /// The clients that sent the most queries within `window`, or since tracking began
+    /// without one, at most `top` of them.
+    pub fn top_clients(&self, window: Option<Duration>, top: usize) -> Vec<ClientStats> {
+        let since = self.window_start(window);
+        let clients = self.clients.lock().unwrap();
+        let mut stats: Vec<ClientStats> = clients
+            .iter()
+            .map(|(client, counter)| {
+                let (queries, nxdomain) = match since {
+                    Some(since) => counter
+                        .minutes
+                        .iter()
+                        .filter(|(minute, _, _)| *minute >= since)
+                        .fold((0, 0), |(queries, nxdomain), (_, q, n)| (queries + q, nxdomain + n)),
+                    None => (counter.total, counter.nxdomain),
+                };
+                ClientStats {
+                    client: *client,
+                    queries,
+                    nxdomain,
+                    total: counter.total,
+                    last_queried: counter.last_queried,
+                }
+            })
+            .filter(|stats| stats.queries > 0)
+            .collect();
+        drop(clients);
+        stats.sort_by(|a, b| b.queries.cmp(&a.queries).then_with(|| a.client.cmp(&b.client)));
+        stats.truncate(top);
+        stats
+    }
+
+    /// Those of the hosted names and types `keys` that `query` matches and that weren't
+    /// queried within its window, or at all without one, least recently queried first.
+    pub fn stale(&self, keys: impl IntoIterator<Item = (LowerName, RecordType)>, query: &StatsQuery) -> Vec<NameStats> {
+        let since = self.window_start(query.window);
+        let counters = self.counters.lock().unwrap();
+        let mut stats: Vec<NameStats> = keys
+            .into_iter()
+            .filter(|(name, record_type)| query.matches(name, *record_type))
+            .map(|key| self.stats_of(&key.0, key.1, counters.get(&key), since))
+            .filter(|stats| stats.queries == 0)
+            .collect();
+        drop(counters);
+        stats.sort_by(|a, b| (a.last_queried, &a.name, a.record_type).cmp(&(b.last_queried, &b.name, b.record_type)));
+        stats.truncate(query.top);
+        stats
+    }
+}
+
+/// Purges the clients of `stats` past the retention of its privacy options, if they
+/// set one.
+pub async fn run_purge(stats: Arc<QueryStats>) {
+    let Some(retention) = stats.privacy.as_ref().and_then(|privacy| privacy.client_retention) else {
+        return;
+    };
+    let mut ticks = tokio::time::interval(PURGE_INTERVAL);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticks.tick().await;
+        stats.purge_clients(SystemTime::now().checked_sub(retention).unwrap_or(UNIX_EPOCH));
+    }
+}
+
+/// Drops the least recently queried tenth of `counters`, at least one of them.
+fn evict<K: Eq + Hash, V>(counters: &mut HashMap<K, V>, last_queried: impl Fn(&V) -> SystemTime) {
+    let mut times: Vec<SystemTime> = counters.values().map(&last_queried).collect();
+    if times.is_empty() {
+        return;
+    }
+    let count = (times.len() / 10).max(1);
+    let (_, cutoff, _) = times.select_nth_unstable(count - 1);
+    let cutoff = *cutoff;
+    counters.retain(|_, counter| last_queried(counter) > cutoff);
+}
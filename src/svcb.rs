@@ -0,0 +1,266 @@
+//! SVCB and HTTPS records (RFC 9460).
+//!
+//! Values are given in presentation format, `priority target key=value...`, e.g.
+//! `1 . alpn=h2,h3 port=443 ipv4hint=192.0.2.1` for an HTTPS record pointing clients
+//! at the owner name itself, or `0 cdn.example.net.` for one in AliasMode. The keys
+//! `mandatory`, `alpn`, `no-default-alpn`, `port`, `ipv4hint`, `ipv6hint` and `ech` are
+//! supported, and a value may be quoted. Records are checked as RFC 9460 asks of zone
+//! file parsers: no key twice, none in AliasMode, mandatory keys present, and
+//! `no-default-alpn` only with `alpn`. Values are formatted back the same way, so they
+//! survive persistence and zone file exports.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hickory_proto::rr::rdata::svcb::{Alpn, EchConfig, IpHint, Mandatory, SvcParamKey, SvcParamValue, SVCB};
+use hickory_proto::rr::rdata::{A, AAAA, HTTPS};
+use hickory_proto::rr::{Name, RData, RecordType};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::error::RdnsError;
+
+/// The parameters of an SVCB or HTTPS record, as given through the control API.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServiceBinding {
+    /// 0 for AliasMode, otherwise the preference of the endpoint, lowest first
+    pub priority: u16,
+    /// Endpoint name, `.` for the owner name itself
+    pub target: String,
+    pub alpn: Vec<String>,
+    pub no_default_alpn: bool,
+    pub port: Option<u16>,
+    pub ipv4_hints: Vec<Ipv4Addr>,
+    pub ipv6_hints: Vec<Ipv6Addr>,
+}
+
+impl ServiceBinding {
+    /// The record value in presentation format.
+    pub fn value(&self) -> String {
+        let mut value = format!("{} {}", self.priority, if self.target.is_empty() { "." } else { &self.target });
+        if !self.alpn.is_empty() {
+            value.push_str(&format!(" alpn={}", join(self.alpn.iter().map(|id| escape_alpn(id)))));
+        }
+        if self.no_default_alpn {
+            value.push_str(" no-default-alpn");
+        }
+        if let Some(port) = self.port {
+            value.push_str(&format!(" port={}", port));
+        }
+        if !self.ipv4_hints.is_empty() {
+            value.push_str(&format!(" ipv4hint={}", join(self.ipv4_hints.iter().map(Ipv4Addr::to_string))));
+        }
+        if !self.ipv6_hints.is_empty() {
+            value.push_str(&format!(" ipv6hint={}", join(self.ipv6_hints.iter().map(Ipv6Addr::to_string))));
+        }
+        value
+    }
+}
+
+fn join(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<_>>().join(",")
+}
+
+/// Escapes the commas and backslashes of an ALPN id inside a comma-separated list.
+fn escape_alpn(id: &str) -> String {
+    id.replace('\\', "\\\\").replace(',', "\\,")
+}
+
+/// The presentation name of `key`.
+fn key_name(key: SvcParamKey) -> String {
+    match key {
+        SvcParamKey::EchConfig => "ech".into(),
+        other => other.to_string(),
+    }
+}
+
+fn invalid(record_type: RecordType, value: &str, reason: impl std::fmt::Display) -> RdnsError {
+    RdnsError::InvalidRdata(format!("invalid {} record value {}: {}", record_type, value, reason))
+}
+
+/// Splits `value` at whitespace outside double quotes, removing the quotes.
+fn tokens(value: &str) -> Result<Vec<String>, &'static str> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let (mut quoted, mut escaped, mut started) = (false, false, false);
+    for c in value.chars() {
+        if escaped {
+            // Escapes are kept for the list parser, which needs to tell `\,` from `,`
+            token.push('\\');
+            token.push(c);
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' => escaped = true,
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if started || !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+                started = false;
+                continue;
+            }
+            c => token.push(c),
+        }
+        started = true;
+    }
+    if quoted {
+        return Err("unclosed quoted string");
+    }
+    if escaped {
+        return Err("dangling backslash");
+    }
+    if started || !token.is_empty() {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Splits a comma-separated list, honouring `\,` and `\\` escapes.
+fn list(value: &str) -> Vec<String> {
+    let mut items = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    items.last_mut().expect("items is never empty").push(next);
+                }
+            }
+            ',' => items.push(String::new()),
+            c => items.last_mut().expect("items is never empty").push(c),
+        }
+    }
+    items
+}
+
+/// Parses the value of a `record_type` (SVCB or HTTPS) record in presentation format.
+pub fn parse(record_type: RecordType, value: &str) -> Result<RData, RdnsError> {
+    let tokens = tokens(value).map_err(|reason| invalid(record_type, value, reason))?;
+    let mut tokens = tokens.into_iter();
+    let priority: u16 = tokens
+        .next()
+        .ok_or_else(|| invalid(record_type, value, "missing priority"))?
+        .parse()
+        .map_err(|_| invalid(record_type, value, "priority must be a number from 0 to 65535"))?;
+    let target = tokens.next().ok_or_else(|| invalid(record_type, value, "missing target name"))?;
+    let target = Name::from_ascii(&target).map_err(|e| invalid(record_type, value, format!("invalid target {}: {}", target, e)))?;
+
+    let mut params: Vec<(SvcParamKey, SvcParamValue)> = Vec::new();
+    for param in tokens {
+        let (key, param_value) = match param.split_once('=') {
+            Some((key, param_value)) => (key, Some(param_value)),
+            None => (param.as_str(), None),
+        };
+        let required = || {
+            param_value
+                .filter(|param_value| !param_value.is_empty())
+                .ok_or_else(|| invalid(record_type, value, format!("{} needs a value", key)))
+        };
+        let (key, param_value) = match key.to_ascii_lowercase().as_str() {
+            "mandatory" => {
+                let keys = list(required()?)
+                    .iter()
+                    .map(|key| match key.as_str() {
+                        "ech" => Ok(SvcParamKey::EchConfig),
+                        key => key.parse().map_err(|_| invalid(record_type, value, format!("unknown mandatory key {}", key))),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                (SvcParamKey::Mandatory, SvcParamValue::Mandatory(Mandatory(keys)))
+            }
+            "alpn" => {
+                let ids = list(required()?);
+                if ids.iter().any(String::is_empty) {
+                    return Err(invalid(record_type, value, "alpn ids must not be empty"));
+                }
+                (SvcParamKey::Alpn, SvcParamValue::Alpn(Alpn(ids)))
+            }
+            "no-default-alpn" => {
+                if param_value.is_some_and(|param_value| !param_value.is_empty()) {
+                    return Err(invalid(record_type, value, "no-default-alpn takes no value"));
+                }
+                (SvcParamKey::NoDefaultAlpn, SvcParamValue::NoDefaultAlpn)
+            }
+            "port" => {
+                let port = required()?
+                    .parse()
+                    .map_err(|_| invalid(record_type, value, "port must be a number from 0 to 65535"))?;
+                (SvcParamKey::Port, SvcParamValue::Port(port))
+            }
+            "ipv4hint" => {
+                let hints = list(required()?)
+                    .iter()
+                    .map(|hint| hint.parse::<Ipv4Addr>().map(A::from))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid(record_type, value, "ipv4hint must list IPv4 addresses"))?;
+                (SvcParamKey::Ipv4Hint, SvcParamValue::Ipv4Hint(IpHint(hints)))
+            }
+            "ipv6hint" => {
+                let hints = list(required()?)
+                    .iter()
+                    .map(|hint| hint.parse::<Ipv6Addr>().map(AAAA::from))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| invalid(record_type, value, "ipv6hint must list IPv6 addresses"))?;
+                (SvcParamKey::Ipv6Hint, SvcParamValue::Ipv6Hint(IpHint(hints)))
+            }
+            "ech" | "echconfig" => {
+                let config = STANDARD
+                    .decode(required()?)
+                    .map_err(|_| invalid(record_type, value, "ech must be base64"))?;
+                (SvcParamKey::EchConfig, SvcParamValue::EchConfig(EchConfig(config)))
+            }
+            _ => return Err(invalid(record_type, value, format!("unsupported key {}", key))),
+        };
+        if params.iter().any(|(existing, _)| *existing == key) {
+            return Err(invalid(record_type, value, format!("{} is given twice", key_name(key))));
+        }
+        params.push((key, param_value));
+    }
+
+    if priority == 0 && !params.is_empty() {
+        return Err(invalid(record_type, value, "AliasMode records (priority 0) take no parameters"));
+    }
+    let has = |key: SvcParamKey| params.iter().any(|(existing, _)| *existing == key);
+    if has(SvcParamKey::NoDefaultAlpn) && !has(SvcParamKey::Alpn) {
+        return Err(invalid(record_type, value, "no-default-alpn requires alpn"));
+    }
+    if let Some((_, SvcParamValue::Mandatory(Mandatory(keys)))) = params.iter().find(|(key, _)| *key == SvcParamKey::Mandatory) {
+        for key in keys {
+            if *key == SvcParamKey::Mandatory || !has(*key) {
+                return Err(invalid(record_type, value, format!("mandatory key {} is not given", key_name(*key))));
+            }
+        }
+    }
+    // Parameters go on the wire in ascending key order
+    params.sort_by_key(|(key, _)| u16::from(*key));
+
+    let svcb = SVCB::new(priority, target, params);
+    Ok(match record_type {
+        RecordType::HTTPS => RData::HTTPS(HTTPS(svcb)),
+        _ => RData::SVCB(svcb),
+    })
+}
+
+/// Formats `svcb` in presentation format, as read back by `parse`.
+pub fn format(svcb: &SVCB) -> String {
+    let mut value = format!("{} {}", svcb.svc_priority(), svcb.target_name());
+    for (key, param) in svcb.svc_params() {
+        let param = match param {
+            SvcParamValue::Mandatory(Mandatory(keys)) => join(keys.iter().map(|key| key_name(*key))),
+            SvcParamValue::Alpn(Alpn(ids)) => join(ids.iter().map(|id| escape_alpn(id))),
+            SvcParamValue::NoDefaultAlpn => {
+                value.push_str(" no-default-alpn");
+                continue;
+            }
+            SvcParamValue::Port(port) => port.to_string(),
+            SvcParamValue::Ipv4Hint(IpHint(hints)) => join(hints.iter().map(|hint| hint.0.to_string())),
+            SvcParamValue::Ipv6Hint(IpHint(hints)) => join(hints.iter().map(|hint| hint.0.to_string())),
+            SvcParamValue::EchConfig(EchConfig(config)) => STANDARD.encode(config),
+            SvcParamValue::Unknown(unknown) => String::from_utf8_lossy(&unknown.0).into_owned(),
+        };
+        match param.contains(char::is_whitespace) {
+            true => value.push_str(&format!(" {}=\"{}\"", key_name(*key), param.replace('\\', "\\\\").replace('"', "\\\""))),
+            false => value.push_str(&format!(" {}={}", key_name(*key), param)),
+        }
+    }
+    value
+}
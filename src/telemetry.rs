@@ -0,0 +1,472 @@
+//! OpenTelemetry tracing.
+//!
+//! With `[telemetry]` configured, every DNS request and every control API call is
+//! recorded as a span and exported over OTLP/gRPC to `otlp_endpoint`, e.g. an
+//! OpenTelemetry collector or a tracing backend that accepts OTLP. A control API call
+//! carrying a W3C `traceparent` header joins the caller's trace, so a deployment
+//! script's own spans include the mutations it made. DNS queries carry no trace context
+//! and start traces of their own; their spans link to the span of the latest mutation
+//! made through the control API, so that the queries answered after a change can be
+//! found from it and their latency compared with that of the queries before.
+//!
+//! Spans are sampled at `sample_ratio`, or as the caller's trace context says, and are
+//! queued and exported in batches by a background task; while the collector is
+//! unavailable or slow, spans are dropped rather than delaying answers.
+
+use hickory_server::server::{Protocol, Request};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tonic::codegen::http;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint};
+
+use crate::privacy::{self, PrivacyOptions};
+use crate::settings::TelemetrySettings;
+
+mod pb {
+    // The variants of AnyValue are named as in the schema
+    #![allow(clippy::enum_variant_names)]
+    tonic::include_proto!("opentelemetry.proto.collector.trace.v1");
+}
+
+use pb::span::SpanKind;
+use pb::trace_service_client::TraceServiceClient;
+
+/// Spans buffered while the collector is slow or unavailable.
+const QUEUE_SIZE: usize = 4096;
+
+/// Spans exported at most per request.
+const MAX_BATCH: usize = 512;
+
+/// How long spans are collected before a batch is exported.
+const BATCH_DELAY: Duration = Duration::from_secs(5);
+
+/// Trace flag of sampled spans (W3C Trace Context).
+const FLAG_SAMPLED: u8 = 0x01;
+
+/// Config options for OpenTelemetry tracing
+#[derive(Debug, Clone)]
+pub struct TelemetryOptions {
+    /// OTLP/gRPC collector spans are exported to
+    pub endpoint: String,
+    /// CA certificate the collector's certificate is verified against.
+    pub ca_cert: Option<String>,
+    pub service_name: String,
+    /// Fraction of the spans without a caller's trace context that are sampled
+    pub sample_ratio: f64,
+}
+
+impl TryFrom<TelemetrySettings> for TelemetryOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: TelemetrySettings) -> anyhow::Result<Self> {
+        Endpoint::from_shared(cfg.otlp_endpoint.clone())
+            .map_err(|e| anyhow::anyhow!("invalid telemetry.otlp_endpoint {}: {}", cfg.otlp_endpoint, e))?;
+        if !(0.0..=1.0).contains(&cfg.sample_ratio) {
+            anyhow::bail!("telemetry.sample_ratio must be between 0 and 1, got {}", cfg.sample_ratio);
+        }
+        Ok(TelemetryOptions {
+            endpoint: cfg.otlp_endpoint,
+            ca_cert: cfg.ca_cert,
+            service_name: cfg.service_name,
+            sample_ratio: cfg.sample_ratio,
+        })
+    }
+}
+
+/// The identity of a span, as propagated in a `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl SpanContext {
+    /// Parses a W3C `traceparent` header value, e.g.
+    /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    pub fn from_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let (trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?);
+        // Later versions may append fields, version 00 may not
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        let context = SpanContext {
+            trace_id: decode_hex(trace_id)?,
+            span_id: decode_hex(span_id)?,
+            sampled: decode_hex::<1>(flags)?[0] & FLAG_SAMPLED != 0,
+        };
+        (context.trace_id != [0; 16] && context.span_id != [0; 8]).then_some(context)
+    }
+
+    fn flags(&self) -> u32 {
+        match self.sampled {
+            true => u32::from(FLAG_SAMPLED),
+            false => 0,
+        }
+    }
+}
+
+fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// A value of a span attribute.
+pub enum Value {
+    String(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::String(value)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Int(value)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+fn key_value(key: &str, value: Value) -> pb::KeyValue {
+    let value = match value {
+        Value::String(value) => pb::any_value::Value::StringValue(value),
+        Value::Int(value) => pb::any_value::Value::IntValue(value),
+        Value::Bool(value) => pb::any_value::Value::BoolValue(value),
+    };
+    pb::KeyValue {
+        key: key.to_string(),
+        value: Some(pb::AnyValue { value: Some(value) }),
+    }
+}
+
+/// Handle for recording spans, shared by the request handlers and the control server.
+#[derive(Clone)]
+pub struct Tracer {
+    spans: mpsc::Sender<pb::Span>,
+    sample_ratio: f64,
+    /// The span of the latest mutation made through the control API.
+    last_mutation: Arc<Mutex<Option<SpanContext>>>,
+}
+
+impl Tracer {
+    /// Starts the background exporter for `options.endpoint`.
+    pub fn spawn(options: TelemetryOptions) -> Self {
+        let (spans, receiver) = mpsc::channel(QUEUE_SIZE);
+        let sample_ratio = options.sample_ratio;
+        tokio::spawn(run_exporter(options, receiver));
+        Tracer {
+            spans,
+            sample_ratio,
+            last_mutation: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Starts a span named `name`, a child of `parent` sampled as it is when given, or
+    /// the root of a new trace sampled at the configured ratio.
+    fn start(&self, name: impl Into<String>, kind: SpanKind, parent: Option<SpanContext>) -> Span {
+        let context = SpanContext {
+            trace_id: parent.map_or_else(rand::random, |parent| parent.trace_id),
+            span_id: rand::random(),
+            sampled: parent.map_or_else(|| rand::random::<f64>() < self.sample_ratio, |parent| parent.sampled),
+        };
+        Span {
+            tracer: self.clone(),
+            context,
+            parent_span_id: parent.map(|parent| parent.span_id),
+            name: name.into(),
+            kind,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+            links: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// Starts the span of the DNS `request`, linked to the span of the latest mutation,
+    /// with the client's address anonymized as `privacy` says.
+    pub fn start_request(&self, request: &Request, privacy: Option<&PrivacyOptions>) -> Span {
+        let query = request.query();
+        let mut span = self.start(format!("DNS {:?}", request.op_code()), SpanKind::Server, None);
+        span.set_attribute("dns.question.name", query.name().to_string());
+        span.set_attribute("dns.question.type", query.query_type().to_string());
+        span.set_attribute("dns.question.class", query.query_class().to_string());
+        span.set_attribute("client.address", privacy::anonymize(privacy, request.src().ip()).to_string());
+        if privacy.is_none_or(PrivacyOptions::keeps_addresses) {
+            span.set_attribute("client.port", i64::from(request.src().port()));
+        }
+        let transport = match request.protocol() {
+            Protocol::Udp => "udp",
+            _ => "tcp",
+        };
+        span.set_attribute("network.transport", transport);
+        span.set_attribute("network.protocol.name", "dns");
+        span.set_attribute("rdns.protocol", request.protocol().to_string());
+        if let Some(mutation) = *self.last_mutation.lock().unwrap() {
+            span.link(mutation);
+        }
+        span
+    }
+}
+
+tokio::task_local! {
+    /// The span of the control API call being handled.
+    static CURRENT: (Tracer, SpanContext);
+}
+
+/// Marks the control API call being handled as a mutation, which the DNS request spans
+/// that follow are linked to; does nothing when calls aren't traced.
+pub fn record_mutation() {
+    let _ = CURRENT.try_with(|(tracer, context)| {
+        if context.sampled {
+            *tracer.last_mutation.lock().unwrap() = Some(*context);
+        }
+    });
+}
+
+/// A span being recorded, exported when dropped if it is sampled.
+pub struct Span {
+    tracer: Tracer,
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    attributes: Vec<pb::KeyValue>,
+    links: Vec<pb::span::Link>,
+    error: Option<String>,
+}
+
+impl Span {
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    pub fn set_attribute(&mut self, key: &str, value: impl Into<Value>) {
+        if self.context.sampled {
+            self.attributes.push(key_value(key, value.into()));
+        }
+    }
+
+    /// Links the span to `context`, a span of another trace it follows from.
+    pub fn link(&mut self, context: SpanContext) {
+        if self.context.sampled {
+            self.links.push(pb::span::Link {
+                trace_id: context.trace_id.to_vec(),
+                span_id: context.span_id.to_vec(),
+                attributes: vec![key_value("rdns.link", Value::from("latest mutation"))],
+                flags: context.flags(),
+            });
+        }
+    }
+
+    /// Marks the span as failed with `message`.
+    pub fn set_error(&mut self, message: impl Into<String>) {
+        self.error = Some(message.into());
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if !self.context.sampled {
+            return;
+        }
+        let status = match self.error.take() {
+            Some(message) => pb::Status {
+                message,
+                code: pb::status::StatusCode::Error as i32,
+            },
+            None => pb::Status::default(),
+        };
+        let span = pb::Span {
+            trace_id: self.context.trace_id.to_vec(),
+            span_id: self.context.span_id.to_vec(),
+            parent_span_id: self.parent_span_id.map(|id| id.to_vec()).unwrap_or_default(),
+            flags: self.context.flags(),
+            name: std::mem::take(&mut self.name),
+            kind: self.kind as i32,
+            start_time_unix_nano: unix_nanos(self.start),
+            end_time_unix_nano: unix_nanos(SystemTime::now()),
+            attributes: std::mem::take(&mut self.attributes),
+            links: std::mem::take(&mut self.links),
+            status: Some(status),
+        };
+        // Sending only fails when the queue is full or the exporter has stopped
+        let _ = self.tracer.spans.try_send(span);
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// Exports the spans received from `spans` in batches until every tracer is dropped.
+async fn run_exporter(options: TelemetryOptions, mut spans: mpsc::Receiver<pb::Span>) {
+    let mut client = match connect(&options).await {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Tracing disabled, OTLP endpoint {} unusable: {}", options.endpoint, e);
+            return;
+        }
+    };
+    let resource = pb::Resource {
+        attributes: vec![
+            key_value("service.name", Value::from(options.service_name.as_str())),
+            key_value("service.version", Value::from(env!("CARGO_PKG_VERSION"))),
+        ],
+    };
+    let mut failing = false;
+    while let Some(first) = spans.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::sleep(BATCH_DELAY);
+        tokio::pin!(deadline);
+        while batch.len() < MAX_BATCH {
+            tokio::select! {
+                span = spans.recv() => match span {
+                    Some(span) => batch.push(span),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        let request = pb::ExportTraceServiceRequest {
+            resource_spans: vec![pb::ResourceSpans {
+                resource: Some(resource.clone()),
+                scope_spans: vec![pb::ScopeSpans {
+                    scope: Some(pb::InstrumentationScope {
+                        name: "rdns".into(),
+                        version: env!("CARGO_PKG_VERSION").into(),
+                    }),
+                    spans: batch,
+                }],
+            }],
+        };
+        // Report failures once, not for every batch until the collector is back
+        match client.export(request).await {
+            Ok(_) if failing => {
+                println!("Exporting spans to {} again", options.endpoint);
+                failing = false;
+            }
+            Ok(_) => {}
+            Err(e) if !failing => {
+                eprintln!("Failed to export spans to {}: {}", options.endpoint, e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+async fn connect(options: &TelemetryOptions) -> anyhow::Result<TraceServiceClient<tonic::transport::Channel>> {
+    let mut endpoint = Endpoint::from_shared(options.endpoint.clone())?;
+    if options.endpoint.starts_with("https://") || options.ca_cert.is_some() {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &options.ca_cert {
+            config = config.ca_certificate(Certificate::from_pem(tokio::fs::read(ca).await?));
+        }
+        endpoint = endpoint.tls_config(config)?;
+    }
+    // Connects on the first export, and again after the collector goes away
+    Ok(TraceServiceClient::new(endpoint.connect_lazy()))
+}
+
+/// Tower layer recording a span for every call to the control API, when tracing is
+/// configured.
+#[derive(Clone)]
+pub struct TraceLayer(pub Option<Tracer>);
+
+impl<S> tower::Layer<S> for TraceLayer {
+    type Service = TraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceService {
+            inner,
+            tracer: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TraceService<S> {
+    inner: S,
+    tracer: Option<Tracer>,
+}
+
+impl<S, B, ResBody> tower::Service<http::Request<B>> for TraceService<S>
+where
+    S: tower::Service<http::Request<B>, Response = http::Response<ResBody>> + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: std::fmt::Display,
+    B: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        // Health checks and reflection are polled too often to be worth a trace
+        let path = request.uri().path().trim_start_matches('/').to_string();
+        let Some(tracer) = self.tracer.clone().filter(|_| !path.starts_with("grpc.")) else {
+            return Box::pin(self.inner.call(request));
+        };
+        let parent = request
+            .headers()
+            .get("traceparent")
+            .and_then(|value| value.to_str().ok())
+            .and_then(SpanContext::from_traceparent);
+        let mut span = tracer.start(path.clone(), SpanKind::Server, parent);
+        let (service, method) = path.split_once('/').unwrap_or((&path, ""));
+        span.set_attribute("rpc.system", "grpc");
+        span.set_attribute("rpc.service", service);
+        span.set_attribute("rpc.method", method);
+        let future = CURRENT.scope((tracer, span.context()), self.inner.call(request));
+        Box::pin(async move {
+            let result = future.await;
+            match &result {
+                Ok(response) => {
+                    // Failed calls answer with the status in the headers, successful ones
+                    // in the trailers
+                    match tonic::Status::from_header_map(response.headers()) {
+                        Some(status) if status.code() != tonic::Code::Ok => {
+                            span.set_attribute("rpc.grpc.status_code", status.code() as i64);
+                            span.set_error(status.message());
+                        }
+                        _ => span.set_attribute("rpc.grpc.status_code", tonic::Code::Ok as i64),
+                    }
+                }
+                Err(e) => span.set_error(e.to_string()),
+            }
+            result
+        })
+    }
+}
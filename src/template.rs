@@ -0,0 +1,146 @@
+//! Zone templates.
+//!
+//! Zones are created empty apart from their generated SOA and NS records, so every new
+//! zone otherwise needs the same boilerplate added record by record. A
+//! `[[dns.templates]]` entry lists such records, e.g. an NS set, MX and SPF records or a
+//! wildcard, with names relative to the zone. Zones created through
+//! `CreateZoneFromTemplate` are populated with the records of the template it names, and
+//! those created through `CreateZone` or the REST gateway with the records of the
+//! `default` template, if there is one. A template can also give its zones a default TTL
+//! of their own, for records added to them later with a TTL of 0.
+
+use hickory_proto::rr::{LowerName, Name, Record, RecordSet, RecordType, RrKey};
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::dns::DnsState;
+use crate::error::RdnsError;
+use crate::settings::ZoneTemplateSettings;
+use crate::validate;
+
+/// One record of a template.
+#[derive(Clone, Debug)]
+struct TemplateRecord {
+    /// Owner name relative to the zone, `@` for its apex
+    name: String,
+    ttl: u32,
+    record_type: RecordType,
+    value: String,
+}
+
+/// A named set of records new zones are populated with.
+#[derive(Clone, Debug)]
+pub struct ZoneTemplate {
+    pub name: String,
+    /// Whether zones created without naming a template get this one
+    pub default: bool,
+    /// TTL of the records added to the zones with a TTL of 0
+    pub default_ttl: Option<u32>,
+    records: Vec<TemplateRecord>,
+}
+
+/// Splits the first whitespace-separated field off `line`.
+fn next_field(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    if line.is_empty() {
+        return None;
+    }
+    Some(line.split_once(char::is_whitespace).unwrap_or((line, "")))
+}
+
+impl TryFrom<ZoneTemplateSettings> for ZoneTemplate {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ZoneTemplateSettings) -> anyhow::Result<Self> {
+        let mut records = Vec::with_capacity(cfg.records.len());
+        for line in &cfg.records {
+            let invalid = || anyhow::anyhow!("invalid dns.templates record {}, expected `NAME [TTL] TYPE value`", line);
+            let (name, rest) = next_field(line).ok_or_else(invalid)?;
+            let (mut field, mut rest) = next_field(rest).ok_or_else(invalid)?;
+            let ttl = match field.parse::<u32>() {
+                Ok(ttl) => {
+                    (field, rest) = next_field(rest).ok_or_else(invalid)?;
+                    ttl
+                }
+                Err(_) => cfg.ttl,
+            };
+            let record_type = RecordType::from_str(&field.to_ascii_uppercase())
+                .map_err(|_| anyhow::anyhow!("unknown record type {} in dns.templates record {}", field, line))?;
+            let value = rest.trim();
+            if value.is_empty() {
+                return Err(invalid());
+            }
+            records.push(TemplateRecord {
+                name: name.to_string(),
+                ttl,
+                record_type,
+                value: value.to_string(),
+            });
+        }
+        let template = ZoneTemplate {
+            name: cfg.name,
+            default: cfg.default,
+            default_ttl: cfg.default_ttl,
+            records,
+        };
+        // Records that can't be built or conflict for some zone do for every zone
+        let placeholder = Name::from_ascii("template.invalid.")?;
+        let invalid = |e: RdnsError| anyhow::anyhow!("invalid dns.templates template {}: {}", template.name, e);
+        let mut sets: BTreeMap<RrKey, Arc<RecordSet>> = BTreeMap::new();
+        for record in template.records(&placeholder).map_err(invalid)? {
+            DnsState::check_cname_conflict(&sets, &record).map_err(invalid)?;
+            let set = sets
+                .entry(RrKey::new(LowerName::new(record.name()), record.record_type()))
+                .or_insert_with(|| Arc::new(RecordSet::new(record.name(), record.record_type(), 0)));
+            Arc::make_mut(set).insert(record, 0);
+        }
+        Ok(template)
+    }
+}
+
+impl ZoneTemplate {
+    /// The records of the template for the zone at `origin`.
+    pub fn records(&self, origin: &Name) -> Result<Vec<Record>, RdnsError> {
+        let apex = origin.to_ascii();
+        let zone = LowerName::new(origin);
+        self.records
+            .iter()
+            .map(|record| {
+                let name = match record.name.as_str() {
+                    "@" => apex.clone(),
+                    name if name.ends_with('.') => name.to_string(),
+                    name => format!("{}.{}", name, apex),
+                };
+                if !zone.zone_of(&LowerName::new(&DnsState::parse_name(&name)?)) {
+                    return Err(RdnsError::InvalidName(format!("{} is not in zone {}", name, apex)));
+                }
+                let value = record
+                    .value
+                    .split(' ')
+                    .map(|token| if token == "@" { apex.as_str() } else { token })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                validate::name(&name)?;
+                validate::value(record.record_type, &value)?;
+                DnsState::build_record(name, record.record_type.to_string(), value, record.ttl)
+            })
+            .collect()
+    }
+}
+
+/// Parses the configured templates, of which names must be unique and at most one may
+/// be the default.
+pub fn parse_all(settings: &[ZoneTemplateSettings]) -> anyhow::Result<Vec<ZoneTemplate>> {
+    let templates: Vec<ZoneTemplate> = settings.iter().cloned().map(ZoneTemplate::try_from).collect::<anyhow::Result<_>>()?;
+    let mut names = HashSet::new();
+    for template in &templates {
+        if !names.insert(template.name.as_str()) {
+            anyhow::bail!("dns.templates template {} is defined more than once", template.name);
+        }
+    }
+    if templates.iter().filter(|template| template.default).count() > 1 {
+        anyhow::bail!("only one dns.templates template can be the default");
+    }
+    Ok(templates)
+}
@@ -0,0 +1,161 @@
+//! Tenants: teams sharing one rdns, each managing only the zones it owns.
+//!
+//! API tokens may be scoped to a tenant (`tenant` of a token in `[grpc.auth]`). Such
+//! tokens may only manage the records and zones of their tenant's zones: those listed for
+//! it under `[[dns.tenants]]` or given to `CreateTenant`, and the zones its tokens create,
+//! which the tenant then owns. Listings only show them their tenant's zones and records,
+//! and every other call is refused. Tokens without a tenant manage every zone.
+//!
+//! A tenant may be limited in the zones it owns and in the records they hold together,
+//! on top of the quotas of `[dns.quotas]`. Tenants come from the config or are created
+//! through `CreateTenant`, in which case they are persisted with the state, as is the
+//! tenant owning each zone created or assigned at runtime.
+
+use hickory_proto::rr::LowerName;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+
+use crate::dns::DnsState;
+use crate::error::RdnsError;
+use crate::settings::TenantSettings;
+
+/// A tenant, as configured or created through the control API and persisted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TenantEntry {
+    pub name: String,
+    /// Zones the tenant may own, unlimited when unset.
+    #[serde(default)]
+    pub max_zones: Option<usize>,
+    /// Records the tenant's zones may hold together, unlimited when unset.
+    #[serde(default)]
+    pub max_records: Option<usize>,
+}
+
+/// A tenant and the zones the config gives it.
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub entry: TenantEntry,
+    pub zones: HashSet<LowerName>,
+}
+
+impl TryFrom<TenantSettings> for Tenant {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: TenantSettings) -> anyhow::Result<Self> {
+        let entry = TenantEntry {
+            name: cfg.name,
+            max_zones: cfg.max_zones,
+            max_records: cfg.max_records,
+        };
+        validate_name(&entry.name)?;
+        let zones = cfg
+            .zones
+            .iter()
+            .map(|zone| Ok(LowerName::new(&DnsState::parse_name(zone)?)))
+            .collect::<Result<_, RdnsError>>()?;
+        Ok(Tenant { entry, zones })
+    }
+}
+
+/// Fails unless `name` is a usable tenant name.
+pub fn validate_name(name: &str) -> Result<(), RdnsError> {
+    if name.is_empty() || name.len() > 64 || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(RdnsError::InvalidArgument(format!(
+            "invalid tenant name {:?}, expected up to 64 letters, digits, - and _",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// A tenant as listed, with whether it comes from the config.
+#[derive(Debug, Clone)]
+pub struct TenantInfo {
+    pub entry: TenantEntry,
+    pub configured: bool,
+}
+
+/// The tenants, by name.
+#[derive(Default)]
+pub struct Tenants {
+    /// Tenants of the config, replaced when it is reloaded
+    configured: RwLock<HashMap<String, Tenant>>,
+    /// Tenants created through the control API
+    created: RwLock<HashMap<String, TenantEntry>>,
+}
+
+impl Tenants {
+    /// Replaces the tenants of the config.
+    pub fn set_configured(&self, tenants: Vec<Tenant>) {
+        *self.configured.write().unwrap() = tenants.into_iter().map(|tenant| (tenant.entry.name.clone(), tenant)).collect();
+    }
+
+    /// Adds a tenant created through the control API, failing with `Conflict` if a tenant
+    /// of the same name exists.
+    pub fn insert(&self, entry: TenantEntry) -> Result<(), RdnsError> {
+        validate_name(&entry.name)?;
+        let mut created = self.created.write().unwrap();
+        if created.contains_key(&entry.name) || self.configured.read().unwrap().contains_key(&entry.name) {
+            return Err(RdnsError::Conflict(format!("tenant {} already exists", entry.name)));
+        }
+        created.insert(entry.name.clone(), entry);
+        Ok(())
+    }
+
+    /// The tenant called `name`, if there is one.
+    pub fn get(&self, name: &str) -> Option<TenantEntry> {
+        if let Some(tenant) = self.configured.read().unwrap().get(name) {
+            return Some(tenant.entry.clone());
+        }
+        self.created.read().unwrap().get(name).cloned()
+    }
+
+    /// The configured tenant the config gives the zone at `origin`, if any.
+    pub fn configured_owner(&self, origin: &LowerName) -> Option<String> {
+        self.configured
+            .read()
+            .unwrap()
+            .values()
+            .find(|tenant| tenant.zones.contains(origin))
+            .map(|tenant| tenant.entry.name.clone())
+    }
+
+    /// Whether any tenant is limited in the records it may hold.
+    pub fn limit_records(&self) -> bool {
+        self.configured.read().unwrap().values().any(|tenant| tenant.entry.max_records.is_some())
+            || self.created.read().unwrap().values().any(|entry| entry.max_records.is_some())
+    }
+
+    /// Drops the tenants created through the control API.
+    pub fn clear(&self) {
+        self.created.write().unwrap().clear();
+    }
+
+    /// The tenants created through the control API, sorted by name.
+    pub fn created(&self) -> Vec<TenantEntry> {
+        let mut entries: Vec<TenantEntry> = self.created.read().unwrap().values().cloned().collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
+
+    /// Every tenant, sorted by name.
+    pub fn list(&self) -> Vec<TenantInfo> {
+        let mut tenants: Vec<TenantInfo> = self
+            .configured
+            .read()
+            .unwrap()
+            .values()
+            .map(|tenant| TenantInfo {
+                entry: tenant.entry.clone(),
+                configured: true,
+            })
+            .collect();
+        tenants.extend(self.created.read().unwrap().values().map(|entry| TenantInfo {
+            entry: entry.clone(),
+            configured: false,
+        }));
+        tenants.sort_by(|a, b| a.entry.name.cmp(&b.entry.name));
+        tenants
+    }
+}
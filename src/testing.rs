@@ -0,0 +1,145 @@
+//! Ephemeral in-process servers for end-to-end tests.
+//!
+//! `TestServer::start` runs the DNS server and the gRPC control API on loopback ports
+//! picked by the OS, rather than fixed ones, so that tests can run in parallel, and
+//! returns the addresses they were bound to once both answer. There is no persistence,
+//! authentication or audit log unless the options given say otherwise, and `ReloadConfig`
+//! re-reads the Config.toml of the working directory as the binary's does.
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! let server = rdns::testing::TestServer::start(&["example.com."]).await?;
+//! // Send queries to server.dns_addr and calls to server.grpc_endpoint()
+//! server.shutdown().await
+//! # }
+//! ```
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::{watch, RwLock};
+use tokio::task::JoinHandle;
+
+use crate::audit::{AuditLog, AuditOptions};
+use crate::auth::Authenticator;
+use crate::control::{self, ControlServer, GrpcListener, GrpcOptions};
+use crate::dns::{self, DnsListeners, DnsOptions, DnsState, HandlerOptions};
+use crate::metrics::Metrics;
+use crate::reload::Reloader;
+use crate::settings::Settings;
+use crate::shutdown::{Shutdown, ShutdownTrigger};
+use crate::verify::Listeners;
+
+/// Loopback address with a port picked by the OS.
+const EPHEMERAL: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// DNS and gRPC servers running in the background of the current runtime.
+pub struct TestServer {
+    /// State both servers share, for setting up records without the API
+    pub state: Arc<RwLock<DnsState>>,
+    /// Address UDP and TCP queries are answered on
+    pub dns_addr: SocketAddr,
+    /// Address DNS-over-TLS queries are answered on, if TLS is configured
+    pub tls_addr: Option<SocketAddr>,
+    /// Address the control API is served on
+    pub grpc_addr: SocketAddr,
+    trigger: ShutdownTrigger,
+    servers: Vec<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl TestServer {
+    /// Starts servers hosting `zones` on ephemeral loopback ports.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a zone name is invalid or a server fails to start.
+    pub async fn start(zones: &[&str]) -> anyhow::Result<Self> {
+        let mut options = DnsOptions::builder().listen_addr(EPHEMERAL);
+        for zone in zones {
+            options = options.zone(*zone);
+        }
+        let grpc = GrpcOptions::builder().listen_addr(EPHEMERAL.to_string()).build()?;
+        Self::start_with(options.build()?, grpc, None).await
+    }
+
+    /// Starts servers with `dns` and `grpc` options, whose listen addresses may give port
+    /// 0 for the OS to pick one, recording changes in the audit log of `audit` if given.
+    /// Queries are answered with the default `HandlerOptions`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an address can't be bound or a server fails to start.
+    pub async fn start_with(dns: DnsOptions, mut grpc: GrpcOptions, audit: Option<AuditOptions>) -> anyhow::Result<Self> {
+        let metrics = Arc::new(Metrics::new()?);
+        let state = Arc::new(RwLock::new(DnsState::new(&dns, None, metrics.clone()).await?));
+        let auth = Arc::new(Authenticator::load(grpc.auth.take()).await?);
+        let audit = match audit {
+            Some(audit) => Some(Arc::new(AuditLog::open(audit).await?)),
+            None => None,
+        };
+        let (handler_options_tx, handler_options) = watch::channel(Arc::new(HandlerOptions::default()));
+        let (trigger, shutdown) = Shutdown::new();
+
+        let dns_listeners = DnsListeners::bind(dns).await?;
+        let listeners = Listeners::try_from(&dns_listeners)?;
+        let dns_addr = dns_listeners.local_addrs()[0];
+        let tls_addr = dns_listeners.tls_addr();
+        let grpc_listener = GrpcListener::bind(&grpc).await?;
+        let grpc_addr = grpc_listener
+            .local_addr()
+            .ok_or_else(|| anyhow::anyhow!("test servers serve the control API over TCP only"))?;
+
+        let settings = settings(dns_addr, grpc_addr)?;
+        let reloader = Arc::new(Reloader::new(settings, state.clone(), handler_options_tx, auth.clone()));
+        let legacy_errors = grpc.legacy_error_responses;
+        let control = ControlServer::new(state.clone(), metrics, auth, reloader, shutdown.clone(), legacy_errors, audit)
+            .with_shutdown_trigger(trigger.clone())
+            .with_listeners(listeners);
+
+        let (ready_tx, mut ready) = watch::channel(false);
+        let dns_server = tokio::spawn(dns::serve_dns(dns_listeners, state.clone(), handler_options, ready_tx, shutdown));
+        if ready.wait_for(|&ready| ready).await.is_err() {
+            return match dns_server.await? {
+                Err(e) => Err(e),
+                Ok(()) => Err(anyhow::anyhow!("DNS server stopped before its listeners were bound")),
+            };
+        }
+        let grpc_server = tokio::spawn(control::serve_grpc(control, grpc, grpc_listener, ready));
+        Ok(TestServer {
+            state,
+            dns_addr,
+            tls_addr,
+            grpc_addr,
+            trigger,
+            servers: vec![dns_server, grpc_server],
+        })
+    }
+
+    /// The URL gRPC clients connect to the control API at, e.g. `http://127.0.0.1:41873`.
+    pub fn grpc_endpoint(&self) -> String {
+        format!("http://{}", self.grpc_addr)
+    }
+
+    /// Stops both servers, returning once the queries and calls in flight are answered.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error a server failed with, if one did.
+    pub async fn shutdown(self) -> anyhow::Result<()> {
+        self.trigger.trigger();
+        for server in self.servers {
+            server.await??;
+        }
+        Ok(())
+    }
+}
+
+/// The settings `ReloadConfig` compares the reloaded config with: those of a config
+/// listening on the addresses bound.
+fn settings(dns_addr: SocketAddr, grpc_addr: SocketAddr) -> anyhow::Result<Settings> {
+    let toml = format!("[dns]\nlisten_addrs = [\"{}\"]\n\n[grpc]\nlisten_addr = \"{}\"\n", dns_addr, grpc_addr);
+    let settings = config::Config::builder()
+        .add_source(config::File::from_str(&toml, config::FileFormat::Toml))
+        .build()?
+        .try_deserialize()?;
+    Ok(settings)
+}
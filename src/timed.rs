@@ -0,0 +1,514 @@
+//! Record values answered on a schedule.
+//!
+//! A value added with a schedule (`AddRecord` with `schedule` set, or `rdnsctl record add
+//! --schedule`) isn't added to the zone: while the schedule is active, its name and type
+//! are answered with the values of that schedule instead of the zone's RRset, e.g. the
+//! address of a maintenance page during a nightly window, and outside it with the zone's
+//! records as usual. A name and type may have values on several schedules; the first of
+//! them added that is active is answered. Schedules take two forms:
+//!
+//! - a daily window, `HH:MM-HH:MM`, optionally on some days of the week only and at an
+//!   offset from UTC, e.g. `22:00-06:00`, `sat,sun 00:00-24:00` or `mon-fri 22:00-02:00
+//!   +02:00`. A window ending before it starts runs past midnight, and belongs to the day
+//!   it starts on
+//! - a cron expression of minute, hour, day of month, month and day of week (0 or 7 for
+//!   Sunday), in UTC, followed by how long each run lasts, e.g. `cron 0 2 * * 0 3h` for
+//!   three hours from 02:00 every Sunday. Fields take `*`, numbers, ranges, lists and
+//!   steps, like `*/15`, `1-5` or `0,30`; runs last from a minute to 31 days
+//!
+//! The `timed` stage of the pipeline answers the values. Like rollouts, they are answered
+//! directly rather than through the catalog, and aren't DNSSEC-signed. Resolvers cache the
+//! answers for their TTL, which bounds how late the start and end of a window are seen.
+
+use hickory_proto::rr::{LowerName, Record, RecordType, RrKey};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dns;
+
+const MINUTES_PER_DAY: u64 = 1440;
+
+/// Longest run of a cron schedule, in minutes.
+const MAX_DURATION: u64 = 31 * MINUTES_PER_DAY;
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Values of a name and type on one schedule in their textual form, as managed through
+/// the control API and persisted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimedEntry {
+    pub name: String,
+    pub record_type: String,
+    pub ttl: u32,
+    pub schedule: String,
+    /// Values in zone file syntax
+    pub values: Vec<String>,
+}
+
+/// Values on a schedule by the name and type they answer for, in the order they were
+/// added, shared with the request handler.
+pub type TimedTable = RwLock<HashMap<RrKey, Vec<Arc<TimedRecords>>>>;
+
+/// Validated values on a schedule, with the values and the schedule parsed.
+pub struct TimedRecords {
+    pub entry: TimedEntry,
+    key: RrKey,
+    schedule: Schedule,
+    records: Vec<Record>,
+}
+
+impl TimedRecords {
+    /// Builds the values on a schedule from `entry` and the records parsed from its values.
+    pub fn new(mut entry: TimedEntry, records: Vec<Record>) -> anyhow::Result<Self> {
+        let schedule: Schedule = entry.schedule.parse()?;
+        let Some(first) = records.first() else {
+            anyhow::bail!("values on a schedule need at least one value");
+        };
+        if matches!(first.record_type(), RecordType::SOA | RecordType::NS | RecordType::CNAME) {
+            anyhow::bail!("{} records can't be answered on a schedule", first.record_type());
+        }
+        if first.name().is_wildcard() {
+            anyhow::bail!("wildcards can't be answered on a schedule");
+        }
+        entry.name = first.name().to_ascii();
+        entry.record_type = first.record_type().to_string();
+        entry.schedule = normalize(&entry.schedule);
+        let key = RrKey::new(LowerName::new(first.name()), first.record_type());
+        Ok(Self { entry, key, schedule, records })
+    }
+
+    /// The name and type the values answer for.
+    pub fn key(&self) -> RrKey {
+        self.key.clone()
+    }
+
+    /// Whether the values are answered at `now`.
+    pub fn active(&self, now: SystemTime) -> bool {
+        self.schedule.active(now)
+    }
+
+    /// Whether `record` is among the values.
+    pub fn contains(&self, record: &Record) -> bool {
+        self.records.iter().any(|existing| existing.data() == record.data())
+    }
+}
+
+/// `schedule` in the form it is kept and matched in, lowercase with single spaces.
+pub fn normalize(schedule: &str) -> String {
+    schedule.split_whitespace().collect::<Vec<_>>().join(" ").to_ascii_lowercase()
+}
+
+/// The values answering for `key` at `now`: those of the first of its schedules that is
+/// active, if any is.
+pub fn select(table: &TimedTable, key: &RrKey, now: SystemTime) -> Option<Arc<TimedRecords>> {
+    let table = table.read().unwrap();
+    table.get(key)?.iter().find(|timed| timed.active(now)).cloned()
+}
+
+/// Answers `request` authoritatively with the values of `timed`.
+pub async fn send_answer<R: ResponseHandler>(request: &Request, timed: &TimedRecords, response_handle: R) -> ResponseInfo {
+    dns::send_records(request, &timed.records, None, response_handle).await
+}
+
+/// When values are answered.
+#[derive(Debug, Clone, PartialEq)]
+enum Schedule {
+    /// Every day in `days`, from `start` to `end` minutes past midnight at `offset`
+    /// minutes from UTC, past the next midnight when `end` isn't after `start`.
+    Window { days: [bool; 7], start: u64, end: u64, offset: i64 },
+    /// For `duration` minutes from every minute the cron fields match.
+    Cron { fields: CronFields, duration: u64 },
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(schedule: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = schedule.split_whitespace().collect();
+        if words.first() == Some(&"cron") {
+            let [_, minute, hour, day, month, weekday, duration] = words[..] else {
+                anyhow::bail!("invalid schedule {}, expected cron MINUTE HOUR DAY MONTH WEEKDAY DURATION", schedule);
+            };
+            let duration = parse_duration(duration)?;
+            let fields = CronFields::parse(minute, hour, day, month, weekday)?;
+            return Ok(Schedule::Cron { fields, duration });
+        }
+
+        let window = words.iter().position(|word| word.contains(':') && word.contains('-') && !word.starts_with(['+', '-']));
+        let Some(window) = window else {
+            anyhow::bail!("invalid schedule {}, expected a window like 22:00-06:00 or a cron expression", schedule);
+        };
+        let days = match &words[..window] {
+            [] => [true; 7],
+            [days] => parse_days(days)?,
+            _ => anyhow::bail!("invalid schedule {}, days go before the window, like sat,sun 00:00-24:00", schedule),
+        };
+        let offset = match &words[window + 1..] {
+            [] => 0,
+            [offset] => parse_offset(offset)?,
+            _ => anyhow::bail!("invalid schedule {}, only an offset like +02:00 may follow the window", schedule),
+        };
+        let (start, end) = words[window].split_once('-').unwrap_or_default();
+        let (start, end) = (parse_time(start)?, parse_time(end)?);
+        if start == end || start == MINUTES_PER_DAY {
+            anyhow::bail!("invalid schedule {}, the window is empty", schedule);
+        }
+        Ok(Schedule::Window { days, start, end, offset })
+    }
+}
+
+impl Schedule {
+    fn active(&self, now: SystemTime) -> bool {
+        let minute = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60;
+        match self {
+            Schedule::Window { days, start, end, offset } => {
+                let minute = minute.saturating_add_signed(*offset);
+                let (day, time) = (minute / MINUTES_PER_DAY, minute % MINUTES_PER_DAY);
+                // 1970-01-01 was a Thursday, the fourth day from Monday
+                let weekday = ((day + 3) % 7) as usize;
+                let yesterday = (weekday + 6) % 7;
+                match start < end {
+                    true => days[weekday] && (*start..*end).contains(&time),
+                    false => (days[weekday] && time >= *start) || (days[yesterday] && time < *end),
+                }
+            }
+            Schedule::Cron { fields, duration } => {
+                fields.last_run(minute, *duration).is_some_and(|run| minute - run < *duration)
+            }
+        }
+    }
+}
+
+/// The minutes, hours, days and months a cron expression matches, as bit sets.
+#[derive(Debug, Clone, PartialEq)]
+struct CronFields {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    /// Days of the week, Sunday first.
+    weekdays: u64,
+    /// Whether days of the month and of the week were both restricted, in which case a
+    /// day matching either is matched, as cron does.
+    either_day: bool,
+}
+
+impl CronFields {
+    fn parse(minute: &str, hour: &str, day: &str, month: &str, weekday: &str) -> anyhow::Result<Self> {
+        let weekdays = parse_field(weekday, 0, 7)?;
+        Ok(CronFields {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days: parse_field(day, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            // 7 is Sunday too
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            either_day: day != "*" && weekday != "*",
+        })
+    }
+
+    /// Whether the day `day` days after the epoch is matched.
+    fn matches_day(&self, day: u64) -> bool {
+        let (_, month, day_of_month) = civil_date(day);
+        // 1970-01-01 was a Thursday, the fifth day from Sunday
+        let weekday = (day + 4) % 7;
+        let by_date = self.days & 1 << day_of_month != 0;
+        let by_weekday = self.weekdays & 1 << weekday != 0;
+        let by_day = match self.either_day {
+            true => by_date || by_weekday,
+            false => by_date && by_weekday,
+        };
+        self.months & 1 << month != 0 && by_day
+    }
+
+    /// The last minute since the epoch matched at or before `now`, looking back at most
+    /// `within` minutes.
+    fn last_run(&self, now: u64, within: u64) -> Option<u64> {
+        let today = now / MINUTES_PER_DAY;
+        let first = now.saturating_sub(within) / MINUTES_PER_DAY;
+        for day in (first..=today).rev() {
+            if !self.matches_day(day) {
+                continue;
+            }
+            let until = match day == today {
+                true => now % MINUTES_PER_DAY,
+                false => MINUTES_PER_DAY - 1,
+            };
+            let run = (0..=until / 60).rev().filter(|hour| self.hours & 1 << hour != 0).find_map(|hour| {
+                let last = if hour == until / 60 { until % 60 } else { 59 };
+                (0..=last).rev().find(|minute| self.minutes & 1 << minute != 0).map(|minute| hour * 60 + minute)
+            });
+            if let Some(run) = run {
+                return Some(day * MINUTES_PER_DAY + run);
+            }
+        }
+        None
+    }
+}
+
+/// Parses a cron field of values from `min` to `max` into a bit set.
+fn parse_field(field: &str, min: u64, max: u64) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("invalid cron field {}, expected values from {} to {}", field, min, max);
+    let mut set = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u64>().map_err(|_| invalid())?),
+            None => (item, 1),
+        };
+        let (low, high) = match range {
+            "*" => (min, max),
+            _ => match range.split_once('-') {
+                Some((low, high)) => (low.parse().map_err(|_| invalid())?, high.parse().map_err(|_| invalid())?),
+                None => {
+                    let value = range.parse().map_err(|_| invalid())?;
+                    (value, if item.contains('/') { max } else { value })
+                }
+            },
+        };
+        if step == 0 || low < min || high > max || low > high {
+            return Err(invalid());
+        }
+        for value in (low..=high).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+    Ok(set)
+}
+
+/// Parses a duration like `90m`, `2h` or `1d` into minutes.
+fn parse_duration(duration: &str) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("invalid duration {}, expected minutes, hours or days like 90m, 2h or 1d", duration);
+    let (count, unit) = [("m", 1), ("h", 60), ("d", MINUTES_PER_DAY)]
+        .into_iter()
+        .find_map(|(suffix, unit)| duration.strip_suffix(suffix).map(|count| (count, unit)))
+        .ok_or_else(invalid)?;
+    let count: u64 = count.parse().map_err(|_| invalid())?;
+    let minutes = count.saturating_mul(unit);
+    if !(1..=MAX_DURATION).contains(&minutes) {
+        anyhow::bail!("invalid duration {}, runs last from a minute to 31 days", duration);
+    }
+    Ok(minutes)
+}
+
+/// Parses days of the week like `mon-fri` or `sat,sun`.
+fn parse_days(days: &str) -> anyhow::Result<[bool; 7]> {
+    let day = |name: &str| {
+        WEEKDAYS
+            .iter()
+            .position(|day| name.eq_ignore_ascii_case(day))
+            .ok_or_else(|| anyhow::anyhow!("invalid day {}, expected one of {}", name, WEEKDAYS.join(", ")))
+    };
+    let mut set = [false; 7];
+    for item in days.split(',') {
+        let (first, last) = match item.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(item)?, day(item)?),
+        };
+        // Ranges like fri-mon wrap around the weekend
+        let mut current = first;
+        loop {
+            set[current] = true;
+            if current == last {
+                break;
+            }
+            current = (current + 1) % 7;
+        }
+    }
+    Ok(set)
+}
+
+/// Parses a time of day like `22:00`, or `24:00` for the end of the day, into minutes
+/// past midnight.
+fn parse_time(time: &str) -> anyhow::Result<u64> {
+    let invalid = || anyhow::anyhow!("invalid time {}, expected HH:MM", time);
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let (hours, minutes): (u64, u64) = (hours.parse().map_err(|_| invalid())?, minutes.parse().map_err(|_| invalid())?);
+    if hours > 24 || minutes >= 60 || hours * 60 + minutes > MINUTES_PER_DAY {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// Parses an offset from UTC like `+02:00` or `-05:30`, or `UTC`, into minutes.
+fn parse_offset(offset: &str) -> anyhow::Result<i64> {
+    if offset.eq_ignore_ascii_case("utc") {
+        return Ok(0);
+    }
+    let invalid = || anyhow::anyhow!("invalid offset {}, expected one like +02:00 or -05:30", offset);
+    let (sign, time) = match offset.split_at_checked(1) {
+        Some(("+", time)) => (1, time),
+        Some(("-", time)) => (-1, time),
+        _ => return Err(invalid()),
+    };
+    let minutes = parse_time(time).map_err(|_| invalid())?;
+    if minutes > 14 * 60 {
+        return Err(invalid());
+    }
+    Ok(sign * minutes as i64)
+}
+
+/// The year, month and day of the month of the day `days` days after the epoch.
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    // Counting years from March, so that leap days end them
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// The time at `date`, like `2026-03-29T01:30`, in UTC.
+    fn at(date: &str) -> SystemTime {
+        let number = |range: std::ops::Range<usize>| date[range].parse::<u64>().unwrap();
+        let (year, month, day) = (number(0..4), number(5..7), number(8..10));
+        let minutes = number(11..13) * 60 + number(14..16);
+        // The inverse of civil_date, counting years from March
+        let year = if month <= 2 { year - 1 } else { year };
+        let (era, year_of_era) = (year / 400, year % 400);
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        UNIX_EPOCH + Duration::from_secs((days * MINUTES_PER_DAY + minutes) * 60)
+    }
+
+    fn schedule(schedule: &str) -> Schedule {
+        schedule.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_cron_fields() {
+        assert_eq!(parse_field("*/15", 0, 59).unwrap(), 1 | 1 << 15 | 1 << 30 | 1 << 45);
+        assert_eq!(parse_field("5/20", 0, 59).unwrap(), 1 << 5 | 1 << 25 | 1 << 45);
+        assert_eq!(parse_field("1-5", 0, 7).unwrap(), 0b11_1110);
+        assert_eq!(parse_field("0,30", 0, 59).unwrap(), 1 | 1 << 30);
+        assert_eq!(parse_field("*", 1, 12).unwrap(), 0b1_1111_1111_1110);
+        for field in ["", "60", "5-1", "*/0", "a", "1-", "-1", "1,,2"] {
+            assert!(parse_field(field, 0, 59).is_err(), "{}", field);
+        }
+        // 7 is Sunday as 0 is
+        let fields = CronFields::parse("0", "0", "*", "*", "7").unwrap();
+        assert_eq!(fields.weekdays, 1);
+    }
+
+    #[test]
+    fn parses_durations_days_times_and_offsets() {
+        assert_eq!(parse_duration("90m").unwrap(), 90);
+        assert_eq!(parse_duration("2h").unwrap(), 120);
+        assert_eq!(parse_duration("31d").unwrap(), MAX_DURATION);
+        for duration in ["", "m", "0m", "32d", "1w", "-1h", "1é", "é", "1hé", "18446744073709551615d"] {
+            assert!(parse_duration(duration).is_err(), "{}", duration);
+        }
+        assert_eq!(parse_days("fri-mon").unwrap(), [true, false, false, false, true, true, true]);
+        assert_eq!(parse_days("Sat,sun").unwrap(), [false, false, false, false, false, true, true]);
+        assert!(parse_days("mon-xyz").is_err());
+        assert_eq!(parse_time("24:00").unwrap(), MINUTES_PER_DAY);
+        for time in ["24:01", "12:60", "1200", "307445734561825861:00", "é:00"] {
+            assert!(parse_time(time).is_err(), "{}", time);
+        }
+        assert_eq!(parse_offset("-05:30").unwrap(), -330);
+        assert_eq!(parse_offset("UTC").unwrap(), 0);
+        for offset in ["02:00", "+14:01", "é", ""] {
+            assert!(parse_offset(offset).is_err(), "{}", offset);
+        }
+    }
+
+    #[test]
+    fn windows_past_midnight_belong_to_the_day_they_start() {
+        // 2026-10-16 is a Friday
+        let friday_night = schedule("fri 22:00-02:00");
+        assert!(friday_night.active(at("2026-10-16T23:00")));
+        assert!(friday_night.active(at("2026-10-17T01:59")));
+        assert!(!friday_night.active(at("2026-10-17T02:00")));
+        assert!(!friday_night.active(at("2026-10-16T21:59")));
+        // Friday before 02:00 is Thursday night, and Saturday night isn't scheduled
+        assert!(!friday_night.active(at("2026-10-16T01:00")));
+        assert!(!friday_night.active(at("2026-10-17T23:00")));
+        let weekend = schedule("sat,sun 00:00-24:00");
+        assert!(weekend.active(at("2026-10-18T23:59")));
+        assert!(!weekend.active(at("2026-10-19T00:00")));
+    }
+
+    #[test]
+    fn offsets_are_fixed_across_daylight_saving_changes() {
+        // Central Europe moved from +01:00 to +02:00 at 01:00 UTC on 2026-03-29: the
+        // window stays at 07:00-15:00 UTC either side of it
+        let office = schedule("mon-fri 09:00-17:00 +02:00");
+        assert!(office.active(at("2026-03-27T07:00")));
+        assert!(!office.active(at("2026-03-27T15:00")));
+        assert!(office.active(at("2026-03-30T07:00")));
+        assert!(!office.active(at("2026-03-30T06:59")));
+        // Offsets move the day a window starts on too
+        let monday = schedule("mon 00:00-01:00 -05:00");
+        assert!(monday.active(at("2026-03-30T05:30")));
+        assert!(!monday.active(at("2026-03-30T00:30")));
+    }
+
+    #[test]
+    fn cron_runs_span_month_and_year_ends() {
+        let month_end = schedule("cron 0 22 31 * * 6h");
+        assert!(month_end.active(at("2026-01-31T22:00")));
+        assert!(month_end.active(at("2026-02-01T03:59")));
+        assert!(!month_end.active(at("2026-02-01T04:00")));
+        // April has no 31st
+        assert!(!month_end.active(at("2026-04-30T23:00")));
+        assert!(!month_end.active(at("2026-05-01T01:00")));
+        assert!(month_end.active(at("2026-12-31T23:00")));
+        assert!(month_end.active(at("2027-01-01T03:00")));
+
+        let leap_day = schedule("cron 0 0 29 2 * 1d");
+        assert!(leap_day.active(at("2028-02-29T12:00")));
+        assert!(!leap_day.active(at("2028-03-01T00:00")));
+        assert!(!leap_day.active(at("2027-03-01T12:00")));
+
+        // Days of the month and of the week restricted together match either
+        let either = schedule("cron 30 12 1 * 1 1h");
+        assert!(either.active(at("2026-10-01T12:30")));
+        assert!(either.active(at("2026-10-05T13:29")));
+        assert!(!either.active(at("2026-10-06T12:30")));
+        assert!(!either.active(at("2026-10-05T12:29")));
+    }
+
+    #[test]
+    fn computes_civil_dates() {
+        assert_eq!(civil_date(0), (1970, 1, 1));
+        assert_eq!(civil_date(11_016), (2000, 2, 29));
+        assert_eq!(civil_date(20_741), (2026, 10, 15));
+        assert_eq!(at("2026-10-15T00:00"), UNIX_EPOCH + Duration::from_secs(20_741 * 86_400));
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        for invalid in [
+            "",
+            "22:00",
+            "22:00-22:00",
+            "24:00-01:00",
+            "25:00-26:00",
+            "mon,xyz 10:00-11:00",
+            "mon tue 10:00-11:00",
+            "10:00-11:00 +15:00",
+            "10:00-11:00 +02:00 utc",
+            "cron 0 2 * * 0",
+            "cron 0 2 * * 0 0m",
+            "cron 0 2 * * 0 1é",
+            "cron 61 * * * * 1h",
+            "cron 0 2 30 2 8 1h",
+        ] {
+            assert!(invalid.parse::<Schedule>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Outbound zone transfers.
+//!
+//! Lets rdns act as a (hidden) primary: configured secondaries may pull hosted zones
+//! with AXFR or IXFR, and are sent a NOTIFY (RFC 1996) whenever a zone changes so
+//! they can refresh it without waiting for their SOA refresh timer.
+//!
+//! Zones listed in `[[dns.transfer.zones]]` are transferred to the clients their policy
+//! allows instead: those in its networks, and those signing the request with one of its
+//! TSIG keys. Refused transfers are logged and counted in `dns_transfers_refused_total`.
+
+use hickory_client::client::{AsyncClient, ClientHandle};
+use hickory_client::udp::UdpClientStream;
+use hickory_proto::rr::{DNSClass, LowerName, Name, Record, RecordType};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+use crate::settings::{TransferSettings, ZoneTransferSettings};
+use crate::views::ClientMatch;
+
+/// How long to wait for a secondary to acknowledge a NOTIFY.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times a NOTIFY is sent before giving up on a secondary.
+const NOTIFY_ATTEMPTS: usize = 3;
+
+/// Config options for outbound zone transfers
+#[derive(Clone)]
+pub struct TransferOptions {
+    /// Secondaries allowed to transfer zones, and notified when they change.
+    pub secondaries: Vec<SocketAddr>,
+    /// Whether transfers must be signed with a TSIG key.
+    pub require_tsig: bool,
+    /// Changes kept per zone for IXFR.
+    pub journal_size: usize,
+    /// Zones transferred to the clients of their own policy rather than to secondaries.
+    pub zones: Vec<(LowerName, TransferPolicy)>,
+}
+
+/// The clients allowed to transfer a zone.
+#[derive(Clone)]
+pub struct TransferPolicy {
+    networks: ClientMatch,
+    /// TSIG keys whose signed requests are allowed from anywhere
+    keys: Vec<LowerName>,
+}
+
+impl TransferPolicy {
+    /// Whether a client at `ip`, whose request is signed with `key` if any, may transfer.
+    fn allows(&self, ip: IpAddr, key: Option<&Name>) -> bool {
+        self.networks.contains(ip) || key.is_some_and(|key| self.keys.contains(&LowerName::new(key)))
+    }
+}
+
+impl TryFrom<ZoneTransferSettings> for TransferPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ZoneTransferSettings) -> anyhow::Result<Self> {
+        let keys = cfg
+            .allow_keys
+            .iter()
+            .map(|key| lower_fqdn(key))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(TransferPolicy {
+            networks: ClientMatch::parse(&cfg.allow)?,
+            keys,
+        })
+    }
+}
+
+impl TryFrom<TransferSettings> for TransferOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: TransferSettings) -> anyhow::Result<Self> {
+        let secondaries = cfg
+            .secondaries
+            .iter()
+            .map(|secondary| parse_server_addr(secondary))
+            .collect::<anyhow::Result<_>>()?;
+        let zones = cfg
+            .zones
+            .into_iter()
+            .map(|zone| {
+                Ok((lower_fqdn(&zone.zone)?, TransferPolicy::try_from(zone)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(TransferOptions {
+            secondaries,
+            require_tsig: cfg.require_tsig,
+            journal_size: cfg.ixfr_journal,
+            zones,
+        })
+    }
+}
+
+/// Parses `name` as a fully qualified name, whether or not it ends with a dot.
+fn lower_fqdn(name: &str) -> anyhow::Result<LowerName> {
+    let mut name = Name::from_str(name)?;
+    name.set_fqdn(true);
+    Ok(LowerName::new(&name))
+}
+
+/// Parses a name server address given as `ip` or `ip:port`, defaulting to port 53.
+pub fn parse_server_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    match addr.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+        Err(_) => Ok(addr.parse()?),
+    }
+}
+
+impl TransferOptions {
+    /// Whether a client at `ip`, whose request is signed with `key` if any, may transfer
+    /// the zone at `origin`: as its policy has it, or as a secondary if it has none.
+    pub fn allows(&self, origin: &LowerName, ip: IpAddr, key: Option<&Name>) -> bool {
+        match self.zones.iter().find(|(zone, _)| zone == origin) {
+            Some((_, policy)) => policy.allows(ip, key),
+            None => self.secondaries.iter().any(|secondary| secondary.ip() == ip),
+        }
+    }
+
+    /// Notifies every secondary that the zone at `origin` changed.
+    ///
+    /// Notifications are sent in the background; failures are logged, not returned.
+    pub fn notify(&self, origin: &Name) {
+        for &secondary in &self.secondaries {
+            let origin = origin.clone();
+            tokio::spawn(async move {
+                if let Err(e) = send_notify(&origin, secondary).await {
+                    eprintln!("Failed to notify {} of change to {}: {}", secondary, origin, e);
+                }
+            });
+        }
+    }
+}
+
+/// Sends a NOTIFY for `origin` to `secondary`, retrying until it is acknowledged.
+async fn send_notify(origin: &Name, secondary: SocketAddr) -> anyhow::Result<()> {
+    let stream = UdpClientStream::<UdpSocket>::with_timeout(secondary, NOTIFY_TIMEOUT);
+    let (mut client, background) = AsyncClient::connect(stream).await?;
+    tokio::spawn(background);
+
+    let mut result = Ok(());
+    for _ in 0..NOTIFY_ATTEMPTS {
+        match client
+            .notify(origin.clone(), DNSClass::IN, RecordType::SOA, None::<Record>)
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(e) => result = Err(e.into()),
+        }
+    }
+    result
+}
@@ -0,0 +1,558 @@
+//! TSIG (RFC 8945) transaction signatures.
+//!
+//! Holds the shared secrets configured for authenticating DNS messages, verifies the
+//! TSIG record of incoming requests against them and signs the matching responses
+//! with the same key. Keys come from `[[dns.tsig_keys]]` (and `[[dns.update.tsig_keys]]`)
+//! or are generated through `CreateTsigKey`, in which case they are persisted with the
+//! state. Any of them is accepted for dynamic updates and zone transfers alike.
+//!
+//! Requests that fail verification are answered with NOTAUTH and a TSIG record carrying
+//! the error: BADKEY for an unknown key, BADSIG for a wrong MAC, both unsigned, and
+//! BADTIME for a valid MAC signed more than five minutes from the server's time, signed
+//! and with the server's time so the client can tell its clock is off.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hickory_proto::rr::dnssec::rdata::tsig::{make_tsig_record, signed_bitmessage_to_buf, TsigAlgorithm, TSIG};
+use hickory_proto::rr::dnssec::rdata::DNSSECRData;
+use hickory_proto::rr::dnssec::tsig::TSigner;
+use hickory_proto::rr::{LowerName, Name, RData, Record, RecordType};
+use hickory_proto::serialize::binary::{BinEncodable, BinEncoder};
+use hickory_server::authority::{MessageRequest, MessageResponse, MessageResponseBuilder};
+use hickory_server::server::{ResponseHandler, ResponseInfo};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::async_trait;
+
+use crate::error::RdnsError;
+use crate::roundrobin;
+use crate::settings::{DnsSettings, TsigKeySettings};
+
+/// Permitted clock skew between client and server, in seconds.
+const FUDGE: u16 = 300;
+
+/// TSIG errors of responses to requests that fail verification (RFC 8945 section 3).
+const BADSIG: u16 = 16;
+const BADKEY: u16 = 17;
+const BADTIME: u16 = 18;
+
+/// Algorithm of the keys created without one.
+const DEFAULT_ALGORITHM: &str = "hmac-sha256";
+
+/// A key, as configured or created through the control API and persisted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TsigKeyEntry {
+    pub name: String,
+    pub algorithm: String,
+    /// Base64-encoded shared secret
+    pub secret: String,
+}
+
+impl From<TsigKeySettings> for TsigKeyEntry {
+    fn from(cfg: TsigKeySettings) -> Self {
+        TsigKeyEntry {
+            name: cfg.name,
+            algorithm: cfg.algorithm,
+            secret: cfg.secret,
+        }
+    }
+}
+
+/// A key as listed through the control API, without its secret.
+#[derive(Debug, Clone)]
+pub struct TsigKeyInfo {
+    pub name: String,
+    pub algorithm: String,
+    /// Whether the key comes from the config rather than `CreateTsigKey`
+    pub configured: bool,
+}
+
+/// A key with its secret decoded, able to verify and sign messages.
+#[derive(Clone)]
+pub struct TsigKey {
+    /// The entry with its name in canonical form: lower-cased and fully qualified
+    pub entry: TsigKeyEntry,
+    signer: TSigner,
+}
+
+impl TsigKey {
+    pub fn new(mut entry: TsigKeyEntry) -> anyhow::Result<Self> {
+        let mut name = Name::from_ascii(&entry.name).map_err(|e| anyhow::anyhow!("invalid TSIG key name {}: {}", entry.name, e))?;
+        name.set_fqdn(true);
+        let name = name.to_lowercase();
+        entry.algorithm = entry.algorithm.trim_end_matches('.').to_ascii_lowercase();
+        let algorithm = TsigAlgorithm::from_name(Name::from_ascii(&entry.algorithm)?);
+        if !algorithm.supported() {
+            anyhow::bail!("unsupported TSIG algorithm {} of key {}", entry.algorithm, name);
+        }
+        let secret = STANDARD
+            .decode(entry.secret.trim())
+            .map_err(|e| anyhow::anyhow!("invalid secret of TSIG key {}: {}", name, e))?;
+        let signer = TSigner::new(secret, algorithm, name.clone(), FUDGE)?;
+        entry.name = name.to_string();
+        Ok(TsigKey { entry, signer })
+    }
+
+    /// Generates a key named `name` with a random secret as long as the digest of
+    /// `algorithm`, `hmac-sha256` when empty.
+    pub fn generate(name: &str, algorithm: &str) -> Result<Self, RdnsError> {
+        let algorithm = match algorithm {
+            "" => DEFAULT_ALGORITHM.to_string(),
+            algorithm => algorithm.to_ascii_lowercase(),
+        };
+        let length = match algorithm.as_str() {
+            "hmac-sha384" => 48,
+            "hmac-sha512" => 64,
+            _ => 32,
+        };
+        let mut secret = vec![0; length];
+        rand::thread_rng().fill_bytes(&mut secret);
+        TsigKey::new(TsigKeyEntry {
+            name: name.to_string(),
+            algorithm,
+            secret: STANDARD.encode(secret),
+        })
+        .map_err(|e| RdnsError::InvalidArgument(e.to_string()))
+    }
+
+    pub fn key(&self) -> LowerName {
+        LowerName::new(self.signer.signer_name())
+    }
+}
+
+/// Parses the keys of `[[dns.tsig_keys]]` and `[[dns.update.tsig_keys]]`, of which names
+/// must be unique.
+pub fn configured_keys(cfg: &DnsSettings) -> anyhow::Result<Vec<TsigKey>> {
+    let update_keys = cfg.update.iter().flat_map(|update| &update.tsig_keys);
+    let keys: Vec<TsigKey> = cfg
+        .tsig_keys
+        .iter()
+        .chain(update_keys)
+        .cloned()
+        .map(|key| TsigKey::new(TsigKeyEntry::from(key)))
+        .collect::<anyhow::Result<_>>()?;
+    let mut names = HashSet::new();
+    for key in &keys {
+        if !names.insert(key.key()) {
+            anyhow::bail!("TSIG key {} is configured more than once", key.entry.name);
+        }
+    }
+    Ok(keys)
+}
+
+/// The set of keys requests may be signed with, by key name.
+#[derive(Default)]
+pub struct TsigKeyring {
+    /// Keys of the config, replaced when it is reloaded
+    configured: RwLock<HashMap<LowerName, TsigKey>>,
+    /// Keys created through the control API
+    created: RwLock<HashMap<LowerName, TsigKey>>,
+}
+
+/// A verified request signature, used to sign the response to that request.
+#[derive(Clone)]
+pub struct RequestSignature {
+    signer: TSigner,
+    mac: Vec<u8>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+impl TsigKeyring {
+    /// Replaces the keys of the config.
+    pub fn set_configured(&self, keys: Vec<TsigKey>) {
+        *self.configured.write().unwrap() = keys.into_iter().map(|key| (key.key(), key)).collect();
+    }
+
+    /// Adds a key created through the control API, failing with `Conflict` if a key
+    /// of the same name exists.
+    pub fn insert(&self, key: TsigKey) -> Result<(), RdnsError> {
+        let name = key.key();
+        let mut created = self.created.write().unwrap();
+        if created.contains_key(&name) || self.configured.read().unwrap().contains_key(&name) {
+            return Err(RdnsError::Conflict(format!("TSIG key {} already exists", name)));
+        }
+        created.insert(name, key);
+        Ok(())
+    }
+
+    /// Removes a key created through the control API; those of the config can't be.
+    pub fn remove(&self, name: &LowerName) -> Result<(), RdnsError> {
+        if self.created.write().unwrap().remove(name).is_some() {
+            return Ok(());
+        }
+        match self.configured.read().unwrap().contains_key(name) {
+            true => Err(RdnsError::InvalidArgument(format!("TSIG key {} is configured in Config.toml", name))),
+            false => Err(RdnsError::RecordNotFound(format!("no TSIG key named {}", name))),
+        }
+    }
+
+    /// Drops the keys created through the control API.
+    pub fn clear(&self) {
+        self.created.write().unwrap().clear();
+    }
+
+    /// The keys created through the control API, sorted by name.
+    pub fn created(&self) -> Vec<TsigKeyEntry> {
+        let mut entries: Vec<TsigKeyEntry> = self.created.read().unwrap().values().map(|key| key.entry.clone()).collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
+
+    /// Every key, sorted by name.
+    pub fn list(&self) -> Vec<TsigKeyInfo> {
+        let info = |key: &TsigKey, configured| TsigKeyInfo {
+            name: key.entry.name.clone(),
+            algorithm: key.entry.algorithm.clone(),
+            configured,
+        };
+        let mut keys: Vec<TsigKeyInfo> = self.configured.read().unwrap().values().map(|key| info(key, true)).collect();
+        keys.extend(self.created.read().unwrap().values().map(|key| info(key, false)));
+        keys.sort_by(|a, b| a.name.cmp(&b.name));
+        keys
+    }
+
+    /// Verifies the TSIG record of `request`, returning `None` if the request is unsigned.
+    ///
+    /// The MAC is checked against the request as re-encoded by hickory, which matches the
+    /// bytes on the wire for any client that compresses names the usual way, as BIND's
+    /// and hickory's do.
+    ///
+    /// # Errors
+    ///
+    /// Returns the TSIG error if the key is unknown, the MAC does not match or the
+    /// signature time is outside the permitted clock skew.
+    pub fn verify(&self, request: &MessageRequest) -> Result<Option<RequestSignature>, TsigError> {
+        self.verify_at(request, now())
+    }
+
+    fn verify_at(&self, request: &MessageRequest, now: u64) -> Result<Option<RequestSignature>, TsigError> {
+        let Some(record) = request.sig0().last() else {
+            return Ok(None);
+        };
+        let Some(RData::DNSSEC(DNSSECRData::TSIG(tsig))) = record.data() else {
+            return Err(TsigError::Unsupported(record.record_type()));
+        };
+        let rejected = |error| TsigError::Rejected {
+            error,
+            key: Box::new(record.name().clone()),
+            algorithm: Box::new(tsig.algorithm().clone()),
+        };
+        let name = LowerName::new(record.name());
+        let key = self.configured.read().unwrap().get(&name).or(self.created.read().unwrap().get(&name)).cloned();
+        let Some(TsigKey { signer, .. }) = key.filter(|key| key.signer.algorithm() == tsig.algorithm()) else {
+            return Err(rejected(BADKEY));
+        };
+
+        // The MAC is checked before the time, so that only holders of the key learn the
+        // server's time from BADTIME
+        let bytes = request.to_bytes().map_err(|_| rejected(BADSIG))?;
+        let (tbv, _) = signed_bitmessage_to_buf(None, &bytes, true).map_err(|_| rejected(BADSIG))?;
+        signer.verify(&tbv, tsig.mac()).map_err(|_| rejected(BADSIG))?;
+        let signature = RequestSignature {
+            signer,
+            mac: tsig.mac().to_vec(),
+        };
+        if now.abs_diff(tsig.time()) > u64::from(tsig.fudge()) {
+            return Err(TsigError::BadTime { signature, time: tsig.time() });
+        }
+        Ok(Some(signature))
+    }
+}
+
+/// Why the TSIG record of a request failed verification.
+#[derive(Clone)]
+pub enum TsigError {
+    /// The request is signed with another kind of signature, like SIG(0).
+    Unsupported(RecordType),
+    /// The key is unknown, BADKEY, or the MAC doesn't match, BADSIG.
+    Rejected { error: u16, key: Box<Name>, algorithm: Box<TsigAlgorithm> },
+    /// The MAC matches but was made at `time`, too far from the server's time.
+    BadTime { signature: RequestSignature, time: u64 },
+}
+
+impl fmt::Display for TsigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TsigError::Unsupported(record_type) => write!(f, "unsupported {} signature", record_type),
+            TsigError::Rejected { error: BADKEY, key, .. } => write!(f, "unknown TSIG key {}", key),
+            TsigError::Rejected { key, .. } => write!(f, "invalid TSIG signature with key {}", key),
+            TsigError::BadTime { .. } => write!(f, "TSIG signature time outside the permitted clock skew"),
+        }
+    }
+}
+
+impl TsigError {
+    /// The TSIG record to append to the wire-format response `message` to a request
+    /// rejected with this error, if it has one.
+    pub fn response_record(&self, message: &[u8], id: u16) -> anyhow::Result<Option<Record>> {
+        self.response_record_at(message, id, now())
+    }
+
+    fn response_record_at(&self, message: &[u8], id: u16, now: u64) -> anyhow::Result<Option<Record>> {
+        match self {
+            TsigError::Unsupported(_) => Ok(None),
+            // Responses to requests of unknown keys or wrong MACs can't be signed
+            TsigError::Rejected { error, key, algorithm } => {
+                let tsig = TSIG::new(*algorithm.clone(), now, FUDGE, Vec::new(), id, *error, Vec::new());
+                Ok(Some(make_tsig_record(*key.clone(), tsig)))
+            }
+            // BADTIME responses are signed at the request's time, with the server's time as
+            // other data (RFC 8945 section 5.2.3)
+            TsigError::BadTime { signature, time } => {
+                let mut other = Vec::with_capacity(6);
+                let mut encoder = BinEncoder::new(&mut other);
+                encoder.emit_u16((now >> 32) as u16)?;
+                encoder.emit_u32(now as u32)?;
+                signature.sign(message, id, *time, BADTIME, other).map(Some)
+            }
+        }
+    }
+}
+
+impl RequestSignature {
+    /// Name of the key the request was signed with.
+    pub fn key_name(&self) -> &Name {
+        self.signer.signer_name()
+    }
+
+    /// Signs the wire-format response `message`, returning the TSIG record that must be
+    /// appended as the last additional record.
+    pub fn sign_response(&self, message: &[u8], id: u16) -> anyhow::Result<Record> {
+        self.sign(message, id, now(), 0, Vec::new())
+    }
+
+    fn sign(&self, message: &[u8], id: u16, time: u64, error: u16, other: Vec<u8>) -> anyhow::Result<Record> {
+        let pre_tsig = TSIG::new(self.signer.algorithm().clone(), time, FUDGE, Vec::new(), id, error, other);
+
+        // The response MAC covers the request MAC, the response and the TSIG variables
+        let mut tbs = Vec::with_capacity(message.len() + 128);
+        let mut encoder = BinEncoder::new(&mut tbs);
+        encoder.emit_u16(self.mac.len() as u16)?;
+        encoder.emit_vec(&self.mac)?;
+        encoder.emit_vec(message)?;
+        pre_tsig.emit_tsig_for_mac(&mut encoder, self.key_name())?;
+
+        let mac = self.signer.sign(&tbs)?;
+        Ok(make_tsig_record(self.key_name().clone(), pre_tsig.set_mac(mac)))
+    }
+}
+
+/// Response handler that signs responses with the key of the request when it was
+/// verified, or appends the TSIG error when it failed verification. It must be the last
+/// to change the response before it is sent, as the MAC covers the response as sent, so
+/// it wraps every other handler of a request.
+#[derive(Clone)]
+pub struct TsigResponseHandler<R> {
+    inner: R,
+    verified: Result<Option<RequestSignature>, TsigError>,
+}
+
+impl<R> TsigResponseHandler<R> {
+    pub fn new(inner: R, verified: Result<Option<RequestSignature>, TsigError>) -> Self {
+        Self { inner, verified }
+    }
+
+    /// The TSIG record to append to the wire-format response `message`, if any.
+    fn record(&self, message: &[u8], id: u16) -> anyhow::Result<Option<Record>> {
+        match &self.verified {
+            Ok(Some(signature)) => signature.sign_response(message, id).map(Some),
+            Ok(None) => Ok(None),
+            Err(e) => e.response_record(message, id),
+        }
+    }
+
+    /// Bytes the TSIG record takes in responses, which truncated responses must leave
+    /// room for.
+    pub fn reserved(&self) -> u16 {
+        match self.record(&[], 0) {
+            Ok(Some(tsig)) => tsig.to_bytes().map_or(0, |bytes| bytes.len() as u16),
+            _ => 0,
+        }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for TsigResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        if matches!(self.verified, Ok(None) | Err(TsigError::Unsupported(_))) {
+            return self.inner.send_response(response).await;
+        }
+        // The response is encoded and re-read to get at its records, then rebuilt: once
+        // without the TSIG record for the MAC to cover, and once more with it
+        let message = roundrobin::reread(response)?;
+        let opt = opt_record(&message);
+
+        let mut unsigned = Vec::with_capacity(512);
+        rebuild(&message, opt.as_ref(), None)
+            .destructive_emit(&mut BinEncoder::new(&mut unsigned))
+            .map_err(std::io::Error::other)?;
+        let tsig = self.record(&unsigned, message.id()).map_err(std::io::Error::other)?;
+        self.inner.send_response(rebuild(&message, opt.as_ref(), tsig.as_ref())).await
+    }
+}
+
+/// The OPT record of `message`, if it has EDNS. It is passed as an additional record
+/// rather than as EDNS, which hickory emits after the additional records, as the TSIG
+/// record must come last.
+fn opt_record(message: &MessageRequest) -> Option<Record> {
+    let mut edns = message.edns()?.clone();
+    edns.set_rcode_high(message.response_code().high());
+    Some(Record::from(&edns))
+}
+
+/// The response `message` as it was sent, with `opt` and then `tsig` appended to its
+/// additional section.
+fn rebuild<'q>(
+    message: &'q MessageRequest,
+    opt: Option<&'q Record>,
+    tsig: Option<&'q Record>,
+) -> MessageResponse<
+    'q,
+    'q,
+    impl Iterator<Item = &'q Record> + Send + 'q,
+    impl Iterator<Item = &'q Record> + Send + 'q,
+    impl Iterator<Item = &'q Record> + Send + 'q,
+    impl Iterator<Item = &'q Record> + Send + 'q,
+> {
+    MessageResponseBuilder::from_message_request(message).build(
+        *message.header(),
+        message.answers(),
+        message.name_servers(),
+        [],
+        message.additionals().iter().chain(opt).chain(tsig),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::HEXLOWER;
+    use hickory_proto::serialize::binary::BinDecodable;
+
+    // Known answers computed independently of hickory, following RFC 8945 section 4.3:
+    // an UPDATE of example.com. adding www.example.com. A 192.0.2.1, its owner name
+    // compressed as BIND's nsupdate does, signed with tsig-key. at 1700000000
+    const SECRET: &str = "cmRucyB0c2lnIGtub3duIGFuc3dlciBrZXkgMzJieSE=";
+    const TIME: u64 = 1_700_000_000;
+    const REQUEST: &str = "2a2a28000001000000010001076578616d706c6503636f6d000006000103777777c00c000100010000012c0004c000020108747369672d6b65790000fa00ff00000000003d0b686d61632d7368613235360000006553f100012c002088e7b7c3cb1a3d6d9fdfd23820092744cca6cba8df1ee27cd44f2f9644884b4f2a2a00000000";
+    const REQUEST_MAC: &str = "88e7b7c3cb1a3d6d9fdfd23820092744cca6cba8df1ee27cd44f2f9644884b4f";
+    /// The NOERROR response, and its TSIG record signed at 1700000100.
+    const RESPONSE: &str = "2a2aa8000001000000000000076578616d706c6503636f6d0000060001";
+    const RESPONSE_TSIG: &str = "08747369672d6b65790000fa00ff00000000003d0b686d61632d7368613235360000006553f164012c00209844bb7415d1e079c40c2129658aa3fb09e43e5158c97d34db97b72f91f9db2b2a2a00000000";
+    /// The NOTAUTH response to the request received at 1700001000, and its BADTIME record.
+    const BADTIME_RESPONSE: &str = "2a2aa8090001000000000000076578616d706c6503636f6d0000060001";
+    const BADTIME_TSIG: &str = "08747369672d6b65790000fa00ff0000000000430b686d61632d7368613235360000006553f100012c0020476afdc8df80cbd5eb0507485278e2ac1b37078329dc16562a67b80cc1ae160d2a2a0012000600006553f4e8";
+    /// The unsigned BADSIG record answering the request at 1700000500.
+    const BADSIG_TSIG: &str = "08747369672d6b65790000fa00ff00000000001d0b686d61632d7368613235360000006553f2f4012c00002a2a00100000";
+
+    fn hex(data: &str) -> Vec<u8> {
+        HEXLOWER.decode(data.as_bytes()).unwrap()
+    }
+
+    fn keyring(name: &str, algorithm: &str) -> TsigKeyring {
+        let keyring = TsigKeyring::default();
+        let key = TsigKey::new(TsigKeyEntry {
+            name: name.into(),
+            algorithm: algorithm.into(),
+            secret: SECRET.into(),
+        });
+        keyring.set_configured(vec![key.unwrap()]);
+        keyring
+    }
+
+    fn request(bytes: &[u8]) -> MessageRequest {
+        MessageRequest::from_bytes(bytes).unwrap()
+    }
+
+    fn error_of(verified: Result<Option<RequestSignature>, TsigError>) -> u16 {
+        match verified {
+            Err(TsigError::Rejected { error, .. }) => error,
+            Err(TsigError::BadTime { .. }) => BADTIME,
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn verifies_known_signed_requests() {
+        let request = request(&hex(REQUEST));
+        // The MAC is checked against the request as hickory re-encodes it
+        assert_eq!(request.to_bytes().unwrap(), hex(REQUEST));
+        let keyring = keyring("TSIG-key", "hmac-sha256");
+        let signature = keyring.verify_at(&request, TIME).ok().flatten().unwrap();
+        assert_eq!(signature.mac, hex(REQUEST_MAC));
+        assert_eq!(signature.key_name(), &Name::from_ascii("tsig-key.").unwrap());
+    }
+
+    #[test]
+    fn rejects_bad_keys_and_signatures() {
+        let request = request(&hex(REQUEST));
+        assert_eq!(error_of(keyring("other-key.", "hmac-sha256").verify_at(&request, TIME)), BADKEY);
+        assert_eq!(error_of(keyring("tsig-key.", "hmac-sha512").verify_at(&request, TIME)), BADKEY);
+
+        let keyring = keyring("tsig-key.", "hmac-sha256");
+        // 192.0.2.1 changed to 192.0.2.2
+        let mut tampered = hex(REQUEST);
+        tampered[48] = 2;
+        assert_eq!(error_of(keyring.verify_at(&self::request(&tampered), TIME)), BADSIG);
+        let mut forged = hex(REQUEST);
+        let last = forged.len() - 7;
+        forged[last] ^= 1;
+        assert_eq!(error_of(keyring.verify_at(&self::request(&forged), TIME)), BADSIG);
+
+        // Unsigned requests are left to the caller
+        let unsigned = MessageRequest::from_bytes(&hex(RESPONSE)).unwrap();
+        assert!(matches!(keyring.verify_at(&unsigned, TIME), Ok(None)));
+    }
+
+    #[test]
+    fn rejects_signatures_outside_the_clock_skew() {
+        let request = request(&hex(REQUEST));
+        let keyring = keyring("tsig-key.", "hmac-sha256");
+        for now in [TIME - 300, TIME + 300] {
+            assert!(keyring.verify_at(&request, now).is_ok());
+        }
+        for now in [0, TIME - 301, TIME + 301, u64::MAX] {
+            assert_eq!(error_of(keyring.verify_at(&request, now)), BADTIME);
+        }
+    }
+
+    #[test]
+    fn signs_responses_and_errors_with_known_answers() {
+        let request = request(&hex(REQUEST));
+        let keyring = keyring("tsig-key.", "hmac-sha256");
+        let signature = keyring.verify_at(&request, TIME).ok().flatten().unwrap();
+        let tsig = signature.sign(&hex(RESPONSE), 0x2a2a, TIME + 100, 0, Vec::new()).unwrap();
+        assert_eq!(tsig.to_bytes().unwrap(), hex(RESPONSE_TSIG));
+
+        let Err(badtime) = keyring.verify_at(&request, TIME + 1000) else {
+            panic!("verified a request signed 1000 seconds ago");
+        };
+        let tsig = badtime.response_record_at(&hex(BADTIME_RESPONSE), 0x2a2a, TIME + 1000).unwrap();
+        assert_eq!(tsig.unwrap().to_bytes().unwrap(), hex(BADTIME_TSIG));
+
+        let mut tampered = hex(REQUEST);
+        tampered[48] = 2;
+        let Err(badsig) = keyring.verify_at(&self::request(&tampered), TIME) else {
+            panic!("verified a tampered request");
+        };
+        let tsig = badsig.response_record_at(&hex(BADTIME_RESPONSE), 0x2a2a, TIME + 500).unwrap();
+        assert_eq!(tsig.unwrap().to_bytes().unwrap(), hex(BADSIG_TSIG));
+    }
+}
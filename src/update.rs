@@ -0,0 +1,399 @@
+//! RFC 2136 dynamic updates.
+//!
+//! UPDATE messages are answered here rather than by the catalog: the zone section names
+//! a hosted zone, the prerequisite section is checked against its current contents and,
+//! if every prerequisite holds, the update section is applied atomically. Changes go
+//! through `DnsState`, so they are re-signed and persisted like any other mutation.
+//!
+//! Added records are held to what records added through the APIs are: their names and
+//! values are validated, their TTLs defaulted and clamped by the TTL policy, and the
+//! zone's records once updated checked against the quotas. Nor may they join a CNAME or
+//! lie below a delegation. An update adding a record that fails is refused as a whole.
+//!
+//! Requests are authenticated with TSIG, against the keys held by `DnsState`; unsigned
+//! updates are only accepted when explicitly allowed.
+
+use hickory_proto::op::{Header, MessageType, OpCode, ResponseCode};
+use hickory_proto::rr::{DNSClass, LowerName, Record, RecordSet, RecordType, RrKey};
+use hickory_server::authority::{MessageRequest, MessageResponseBuilder};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::dns::DnsState;
+use crate::error::RdnsError;
+use crate::mailauth;
+use crate::quota::Usage;
+use crate::settings::UpdateSettings;
+use crate::tsig::TsigKeyring;
+
+type Records = BTreeMap<RrKey, Arc<RecordSet>>;
+
+/// Config options for dynamic updates
+pub struct UpdateOptions {
+    /// Whether updates without a TSIG signature are accepted.
+    pub allow_unsigned: bool,
+}
+
+impl From<UpdateSettings> for UpdateOptions {
+    fn from(cfg: UpdateSettings) -> Self {
+        UpdateOptions {
+            allow_unsigned: cfg.allow_unsigned,
+        }
+    }
+}
+
+/// Answers an UPDATE request, applying it to `state` if it is authorized and valid.
+///
+/// Updates are refused outright when `options` is unset.
+pub async fn handle_update<R: ResponseHandler>(
+    state: &RwLock<DnsState>,
+    options: Option<&UpdateOptions>,
+    request: &Request,
+    mut response_handle: R,
+) -> ResponseInfo {
+    let code = {
+        let state = state.read().await;
+        match authorize(options, state.tsig_keys(), request) {
+            Ok(()) => apply(&state, request).await,
+            Err(code) => code,
+        }
+    };
+
+    let mut header = Header::new();
+    header
+        .set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Update)
+        .set_response_code(code);
+    let response = MessageResponseBuilder::from_message_request(request).build_no_records(header);
+    match response_handle.send_response(response).await {
+        Ok(info) => info,
+        Err(e) => {
+            eprintln!("Failed to send update response: {}", e);
+            let mut header = Header::new();
+            header.set_response_code(ResponseCode::ServFail);
+            header.into()
+        }
+    }
+}
+
+/// Checks the request's TSIG signature against the keys of `keyring`. The response is
+/// signed with the request's key by `TsigResponseHandler`.
+fn authorize(options: Option<&UpdateOptions>, keyring: &TsigKeyring, request: &MessageRequest) -> Result<(), ResponseCode> {
+    let Some(options) = options else {
+        return Err(ResponseCode::Refused);
+    };
+    match keyring.verify(request) {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) if options.allow_unsigned => Ok(()),
+        Ok(None) => Err(ResponseCode::Refused),
+        Err(e) => {
+            eprintln!("Rejected update from unverified client: {}", e);
+            Err(ResponseCode::NotAuth)
+        }
+    }
+}
+
+/// Validates the zone section and applies the update to the named zone.
+async fn apply(state: &DnsState, request: &MessageRequest) -> ResponseCode {
+    // Zone section: exactly one SOA "query" naming the zone to update (RFC 2136 2.3)
+    let zone = request.query();
+    if request.header().query_count() != 1 || zone.query_type() != RecordType::SOA {
+        return ResponseCode::FormErr;
+    }
+    let origin = zone.name();
+    let Some(authority) = state.zone(origin) else {
+        return ResponseCode::NotAuth;
+    };
+    // Secondaries take changes from their primary, and followers from their leader; frozen
+    // zones take none
+    if state.is_secondary(origin) || state.is_frozen(origin) || state.is_follower() {
+        return ResponseCode::Refused;
+    }
+
+    {
+        let mut records = authority.records_mut().await;
+        if let Err(code) = check_prerequisites(origin, &records, request.answers()) {
+            return code;
+        }
+        if let Err(code) = check_updates(origin, request.name_servers()) {
+            return code;
+        }
+        // Updated on a copy, so that a refused update leaves the zone as it was
+        let mut staged = records.clone();
+        let admitted = apply_updates(state, origin, &mut staged, request.name_servers()).and_then(|changed| {
+            let usage = Usage::of(staged.values().flat_map(|set| set.records_without_rrsigs()));
+            state.check_staged_quota(origin, usage).map(|_| changed)
+        });
+        match admitted {
+            Ok(true) => *records = staged,
+            Ok(false) => return ResponseCode::NoError,
+            Err(e) => {
+                eprintln!("Refused update of {}: {}", origin, e);
+                return ResponseCode::Refused;
+            }
+        }
+    }
+
+    match state.zone_updated(authority).await {
+        Ok(()) => ResponseCode::NoError,
+        Err(e) => {
+            eprintln!("Failed to commit update to {}: {}", origin, e);
+            ResponseCode::ServFail
+        }
+    }
+}
+
+/// Whether a record from an update message carries no rdata.
+fn is_empty(record: &Record) -> bool {
+    record.data().is_none_or(|rdata| rdata.record_type() == RecordType::NULL)
+}
+
+/// Checks every prerequisite against the zone's current records (RFC 2136 3.2).
+fn check_prerequisites(origin: &LowerName, records: &Records, prerequisites: &[Record]) -> Result<(), ResponseCode> {
+    let name_in_use = |name: &LowerName| records.keys().any(|key| &key.name == name);
+    let rrset = |name: &LowerName, record_type| records.get(&RrKey::new(name.clone(), record_type));
+
+    // Value-dependent prerequisites are compared per RRset once all have been collected
+    let mut expected: BTreeMap<RrKey, Vec<&Record>> = BTreeMap::new();
+
+    for prerequisite in prerequisites {
+        let name = LowerName::new(prerequisite.name());
+        if prerequisite.ttl() != 0 {
+            return Err(ResponseCode::FormErr);
+        }
+        if !origin.zone_of(&name) {
+            return Err(ResponseCode::NotZone);
+        }
+        match (prerequisite.dns_class(), prerequisite.record_type()) {
+            (DNSClass::ANY, _) | (DNSClass::NONE, _) if !is_empty(prerequisite) => return Err(ResponseCode::FormErr),
+            (DNSClass::ANY, RecordType::ANY) if !name_in_use(&name) => return Err(ResponseCode::NXDomain),
+            (DNSClass::ANY, RecordType::ANY) => (),
+            (DNSClass::ANY, record_type) if rrset(&name, record_type).is_none() => return Err(ResponseCode::NXRRSet),
+            (DNSClass::ANY, _) => (),
+            (DNSClass::NONE, RecordType::ANY) if name_in_use(&name) => return Err(ResponseCode::YXDomain),
+            (DNSClass::NONE, RecordType::ANY) => (),
+            (DNSClass::NONE, record_type) if rrset(&name, record_type).is_some() => return Err(ResponseCode::YXRRSet),
+            (DNSClass::NONE, _) => (),
+            (DNSClass::IN, _) => expected
+                .entry(RrKey::new(name, prerequisite.record_type()))
+                .or_default()
+                .push(prerequisite),
+            _ => return Err(ResponseCode::FormErr),
+        }
+    }
+
+    for (key, wanted) in expected {
+        let Some(set) = records.get(&key) else {
+            return Err(ResponseCode::NXRRSet);
+        };
+        let actual: Vec<_> = set.records_without_rrsigs().collect();
+        let matches = actual.len() == wanted.len()
+            && wanted.iter().all(|want| actual.iter().any(|have| have.data() == want.data()));
+        if !matches {
+            return Err(ResponseCode::NXRRSet);
+        }
+    }
+    Ok(())
+}
+
+/// Validates the update section before anything is applied (RFC 2136 3.4.1).
+fn check_updates(origin: &LowerName, updates: &[Record]) -> Result<(), ResponseCode> {
+    for update in updates {
+        if !origin.zone_of(&LowerName::new(update.name())) {
+            return Err(ResponseCode::NotZone);
+        }
+        let meta = matches!(
+            update.record_type(),
+            RecordType::ANY | RecordType::AXFR | RecordType::IXFR | RecordType::OPT
+        );
+        let valid = match update.dns_class() {
+            DNSClass::IN => !meta && !is_empty(update),
+            DNSClass::ANY => update.ttl() == 0 && is_empty(update) && !matches!(update.record_type(), RecordType::AXFR | RecordType::IXFR),
+            DNSClass::NONE => update.ttl() == 0 && !meta && !is_empty(update),
+            _ => false,
+        };
+        if !valid {
+            return Err(ResponseCode::FormErr);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the update section to the zone's records (RFC 2136 3.4.2), returning whether
+/// anything changed. The apex SOA and NS RRsets are never deleted. Fails on the first
+/// added record `state` doesn't accept.
+fn apply_updates(state: &DnsState, origin: &LowerName, records: &mut Records, updates: &[Record]) -> Result<bool, RdnsError> {
+    let protected = |key: &RrKey| &key.name == origin && matches!(key.record_type, RecordType::SOA | RecordType::NS);
+    let mut changed = false;
+
+    for update in updates {
+        let name = LowerName::new(update.name());
+        match (update.dns_class(), update.record_type()) {
+            (DNSClass::IN, record_type) => {
+                let record = state.check_update_record(update)?;
+                DnsState::check_cname_conflict(records, &record)?;
+                DnsState::check_occlusion(records, origin, &record)?;
+                let set = records
+                    .entry(RrKey::new(name, record_type))
+                    .or_insert_with(|| Arc::new(RecordSet::new(update.name(), record_type, 0)));
+                mailauth::check_conflict(set.records_without_rrsigs(), &record)?;
+                changed |= Arc::make_mut(set).insert(record, 0);
+            }
+            (DNSClass::ANY, RecordType::ANY) => {
+                let before = records.len();
+                records.retain(|key, _| key.name != name || protected(key));
+                changed |= records.len() != before;
+            }
+            (DNSClass::ANY, record_type) => {
+                let key = RrKey::new(name, record_type);
+                if !protected(&key) {
+                    changed |= records.remove(&key).is_some();
+                }
+            }
+            (DNSClass::NONE, record_type) => {
+                let key = RrKey::new(name, record_type);
+                if let Some(set) = records.get_mut(&key) {
+                    let mut record = update.clone();
+                    record.set_dns_class(set.dns_class());
+                    changed |= Arc::make_mut(set).remove(&record, 0);
+                    if set.is_empty() {
+                        records.remove(&key);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsOptions, TtlPolicy};
+    use crate::metrics::Metrics;
+    use crate::quota::QuotaOptions;
+    use hickory_proto::op::{Message, Query};
+    use hickory_proto::rr::Name;
+    use hickory_proto::serialize::binary::BinDecodable;
+    use std::str::FromStr;
+
+    /// A state hosting `example.com.`, with TTLs kept from 60 to 3600 seconds and at most
+    /// four records in the zone.
+    async fn state() -> DnsState {
+        let quotas = QuotaOptions {
+            max_zone_records: Some(4),
+            ..QuotaOptions::default()
+        };
+        let options = DnsOptions::builder()
+            .listen_addr("127.0.0.1:0".parse().unwrap())
+            .zone("example.com.")
+            .ttl(TtlPolicy { default: 300, min: 60, max: 3600 })
+            .quotas(quotas)
+            .build()
+            .unwrap();
+        DnsState::new(&options, None, Arc::new(Metrics::new().unwrap())).await.unwrap()
+    }
+
+    /// An UPDATE of `zone` with `prerequisites` and `updates`, as it is received.
+    fn request(zone: &str, prerequisites: Vec<Record>, updates: Vec<Record>) -> MessageRequest {
+        let mut message = Message::new();
+        message.set_op_code(OpCode::Update).set_message_type(MessageType::Query);
+        message.add_query(Query::query(Name::from_str(zone).unwrap(), RecordType::SOA));
+        message.add_answers(prerequisites);
+        message.add_name_servers(updates);
+        MessageRequest::from_bytes(&message.to_vec().unwrap()).unwrap()
+    }
+
+    fn add(name: &str, record_type: &str, value: &str, ttl: u32) -> Record {
+        DnsState::build_record(name.into(), record_type.into(), value.into(), ttl).unwrap()
+    }
+
+    /// A record of `class` without rdata, as prerequisites and deletions give.
+    fn empty(name: &str, record_type: RecordType, class: DNSClass) -> Record {
+        let mut record = Record::with(Name::from_str(name).unwrap(), record_type, 0);
+        record.set_dns_class(class);
+        record
+    }
+
+    async fn lookup(state: &DnsState, name: &str, record_type: RecordType) -> Vec<Record> {
+        state.lookup_records(&LowerName::from_str(name).unwrap(), record_type).await
+    }
+
+    #[tokio::test]
+    async fn applies_updates_whose_prerequisites_hold() {
+        let state = state().await;
+        let www = add("www.example.com.", "A", "192.0.2.1", 300);
+        assert_eq!(apply(&state, &request("example.com.", vec![], vec![www.clone()])).await, ResponseCode::NoError);
+        assert_eq!(lookup(&state, "www.example.com.", RecordType::A).await, vec![www.clone()]);
+
+        // The name is in use, so it can't be required not to be
+        let fresh = request("example.com.", vec![empty("www.example.com.", RecordType::ANY, DNSClass::NONE)], vec![add("www.example.com.", "A", "192.0.2.2", 300)]);
+        assert_eq!(apply(&state, &fresh).await, ResponseCode::YXDomain);
+        let missing = request("example.com.", vec![empty("ftp.example.com.", RecordType::A, DNSClass::ANY)], vec![]);
+        assert_eq!(apply(&state, &missing).await, ResponseCode::NXRRSet);
+
+        // The RRset given as a prerequisite must be the whole RRset
+        let mut exact = add("www.example.com.", "A", "192.0.2.1", 0);
+        exact.set_ttl(0);
+        let replace = request(
+            "example.com.",
+            vec![exact],
+            vec![empty("www.example.com.", RecordType::A, DNSClass::ANY), add("www.example.com.", "A", "192.0.2.3", 300)],
+        );
+        assert_eq!(apply(&state, &replace).await, ResponseCode::NoError);
+        assert_eq!(lookup(&state, "www.example.com.", RecordType::A).await, vec![add("www.example.com.", "A", "192.0.2.3", 300)]);
+
+        let mut value = add("www.example.com.", "A", "192.0.2.3", 0);
+        value.set_dns_class(DNSClass::NONE);
+        assert_eq!(apply(&state, &request("example.com.", vec![], vec![value])).await, ResponseCode::NoError);
+        assert!(lookup(&state, "www.example.com.", RecordType::A).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn holds_added_records_to_what_the_api_accepts() {
+        let state = state().await;
+        let clamped = request("example.com.", vec![], vec![add("short.example.com.", "A", "192.0.2.1", 5)]);
+        assert_eq!(apply(&state, &clamped).await, ResponseCode::NoError);
+        assert_eq!(lookup(&state, "short.example.com.", RecordType::A).await[0].ttl(), 60);
+
+        let alias = add("alias.example.com.", "CNAME", "short.example.com.", 300);
+        assert_eq!(apply(&state, &request("example.com.", vec![], vec![alias])).await, ResponseCode::NoError);
+        let refused = [
+            // Other data can't join a CNAME
+            add("alias.example.com.", "TXT", "\"text\"", 300),
+            // Nor records lie below a delegation, added in the same update
+            add("sub.example.com.", "NS", "ns.example.net.", 300),
+            add("host.sub.example.com.", "A", "192.0.2.2", 300),
+            // Nor SPF terms follow `all`
+            add("mail.example.com.", "TXT", "\"v=spf1 -all ip4:192.0.2.1\"", 300),
+        ];
+        assert_eq!(apply(&state, &request("example.com.", vec![], vec![refused[0].clone()])).await, ResponseCode::Refused);
+        assert_eq!(apply(&state, &request("example.com.", vec![], refused[1..3].to_vec())).await, ResponseCode::Refused);
+        assert!(lookup(&state, "sub.example.com.", RecordType::NS).await.is_empty());
+        assert_eq!(apply(&state, &request("example.com.", vec![], vec![refused[3].clone()])).await, ResponseCode::Refused);
+
+        // Two records are hosted, the quota of four leaves room for two more
+        let hosts: Vec<Record> = (1..=3).map(|i| add(&format!("h{}.example.com.", i), "A", "192.0.2.9", 300)).collect();
+        assert_eq!(apply(&state, &request("example.com.", vec![], hosts.clone())).await, ResponseCode::Refused);
+        assert!(lookup(&state, "h1.example.com.", RecordType::A).await.is_empty());
+        assert_eq!(apply(&state, &request("example.com.", vec![], hosts[..2].to_vec())).await, ResponseCode::NoError);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_updates() {
+        let state = state().await;
+        let outside = request("example.com.", vec![], vec![add("www.example.net.", "A", "192.0.2.1", 300)]);
+        assert_eq!(apply(&state, &outside).await, ResponseCode::NotZone);
+        let unknown = request("example.net.", vec![], vec![add("www.example.net.", "A", "192.0.2.1", 300)]);
+        assert_eq!(apply(&state, &unknown).await, ResponseCode::NotAuth);
+        let mut timed = empty("www.example.com.", RecordType::A, DNSClass::ANY);
+        timed.set_ttl(300);
+        assert_eq!(apply(&state, &request("example.com.", vec![], vec![timed])).await, ResponseCode::FormErr);
+        let meta = request("example.com.", vec![], vec![empty("www.example.com.", RecordType::AXFR, DNSClass::ANY)]);
+        assert_eq!(apply(&state, &meta).await, ResponseCode::FormErr);
+    }
+}
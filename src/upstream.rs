@@ -0,0 +1,690 @@
+//! Upstream resolvers of forwarded queries.
+//!
+//! Upstreams are given as `ip` or `ip:port` for plain DNS over UDP with TCP fallback,
+//! `tls://host[:port][#name]` for DNS over TLS (RFC 7858, port 853 by default) or
+//! `https://host[:port]/dns-query` for DNS over HTTPS (RFC 8484, port 443), so that
+//! forwarded queries leave the network encrypted. A host given by name is resolved
+//! through the system resolver when the config is read, and is the name the upstream's
+//! certificate must be valid for unless `#name` gives another, as in
+//! `tls://1.1.1.1#cloudflare-dns.com`.
+//!
+//! Certificates are verified against the Mozilla root store, or against the CA
+//! certificates of `tls_ca_file` instead. With `tls_pins` set, the certificate chain must
+//! also hold a public key whose SHA-256 digest is pinned, in the base64 `pin-sha256` form
+//! of RFC 7469. Queries whose Client Subnet option is passed on are only sent to the
+//! plain upstreams, and resolved without the option when there are none.
+//!
+//! The upstreams of a route are queried directly, in order, or with `selection =
+//! "fastest"` fastest first: every upstream's response time and share of failed queries
+//! are smoothed over its recent queries, and upstreams are ranked by the time they can
+//! be expected to take, counting a failure as a timeout, with those not yet measured
+//! ranked last. Every `probe_secs`, each upstream other than the fastest is also sent a
+//! copy of a query alongside it, so that its ranking follows the upstream getting
+//! faster again; the copy's answer is only measured, and the query is still answered by
+//! the fastest. Each attempt at a query has `timeout_ms` to be answered, and is retried
+//! `retries` times before the next upstream is asked;
+//! `[dns.forward.overrides."<upstream>"]` gives single upstreams a timeout and retries
+//! of their own. With `hedge_ms` set, a query an upstream hasn't answered within that
+//! many milliseconds is also sent to the next one, and the first answer wins. Answers
+//! with SERVFAIL or REFUSED are passed over for those of the next upstreams, and only
+//! returned when none answers otherwise. An upstream that fails `max_failures` queries
+//! in a row is down: it is logged and skipped for `down_secs`, tried only once the
+//! others have failed, and up again as soon as it answers.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hickory_proto::op::{Message, Query, ResponseCode};
+use hickory_proto::rr::{Name, Record};
+use hickory_proto::rustls::tls_server;
+use hickory_proto::xfer::{DnsHandle, DnsRequest, DnsRequestOptions, FirstAnswer};
+use hickory_server::resolver::config::{NameServerConfig, Protocol, ResolverOpts, TlsClientConfig};
+use hickory_server::resolver::name_server::{ConnectionProvider, GenericConnection, TokioConnectionProvider};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::task::JoinHandle;
+
+use crate::forward;
+use crate::transfer;
+
+/// How queries reach an upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// UDP, retried over TCP when truncated
+    Plain,
+    Tls,
+    Https,
+}
+
+/// An upstream resolver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    pub transport: Transport,
+    /// Name the certificate of an encrypted upstream must be valid for
+    pub tls_name: Option<String>,
+}
+
+impl FromStr for Upstream {
+    type Err = anyhow::Error;
+
+    fn from_str(upstream: &str) -> anyhow::Result<Self> {
+        let (transport, rest, default_port) = match upstream.split_once("://") {
+            None => {
+                return Ok(Upstream {
+                    addr: transfer::parse_server_addr(upstream)?,
+                    transport: Transport::Plain,
+                    tls_name: None,
+                })
+            }
+            Some(("tls", rest)) => (Transport::Tls, rest, 853),
+            Some(("https", rest)) => (Transport::Https, rest, 443),
+            Some((scheme, _)) => anyhow::bail!("unsupported scheme {} of upstream {}, expected tls or https", scheme, upstream),
+        };
+        let (rest, tls_name) = match rest.split_once('#') {
+            Some((rest, name)) => (rest, Some(name)),
+            None => (rest, None),
+        };
+        let (authority, path) = match rest.split_once('/') {
+            Some((authority, path)) => (authority, path),
+            None => (rest, ""),
+        };
+        match (transport, path) {
+            (Transport::Tls, "") | (Transport::Https, "" | "dns-query") => {}
+            (Transport::Tls, _) => anyhow::bail!("DNS over TLS upstream {} can't have a path", upstream),
+            _ => anyhow::bail!("DNS over HTTPS upstream {} must be queried at /dns-query", upstream),
+        }
+
+        let (host, port) = if let Ok(addr) = authority.parse::<SocketAddr>() {
+            (addr.ip().to_string(), addr.port())
+        } else if let Ok(ip) = authority.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            (ip.to_string(), default_port)
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host.to_string(), port.parse().map_err(|_| anyhow::anyhow!("invalid port of upstream {}", upstream))?),
+                None => (authority.to_string(), default_port),
+            }
+        };
+        if host.is_empty() {
+            anyhow::bail!("upstream {} has no host", upstream);
+        }
+        let addr = match host.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, port),
+            Err(_) => (host.as_str(), port)
+                .to_socket_addrs()
+                .map_err(|e| anyhow::anyhow!("can't resolve upstream {}: {}", upstream, e))?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("upstream {} has no address", upstream))?,
+        };
+        let tls_name = tls_name.map(str::to_string).unwrap_or(host);
+        ServerName::try_from(tls_name.as_str()).map_err(|_| anyhow::anyhow!("invalid TLS name {} of upstream {}", tls_name, upstream))?;
+        Ok(Upstream {
+            addr,
+            transport,
+            tls_name: Some(tls_name),
+        })
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    /// Formats the upstream as it is configured, with its address resolved and its TLS
+    /// name given when that isn't its address.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.transport {
+            Transport::Plain => write!(f, "{}", self.addr)?,
+            Transport::Tls => write!(f, "tls://{}", self.addr)?,
+            Transport::Https => write!(f, "https://{}/dns-query", self.addr)?,
+        }
+        match &self.tls_name {
+            Some(name) if *name != self.addr.ip().to_string() => write!(f, "#{}", name),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How long an upstream has to answer each attempt at a query, and how often it is
+/// retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tuning {
+    pub timeout: Duration,
+    /// Attempts made after the first
+    pub retries: u32,
+}
+
+/// When upstreams are considered down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthOptions {
+    /// Queries in a row an upstream fails before it is down
+    pub max_failures: u32,
+    /// How long an upstream that is down is skipped
+    pub down_for: Duration,
+}
+
+/// How the upstreams of a route are ordered for each query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Selection {
+    /// As they are listed
+    Ordered,
+    /// The fastest first, by their smoothed response times and failure rates, with each
+    /// of the others sent a copy of a query every `probe_every` to measure it
+    Fastest { probe_every: Duration },
+}
+
+/// Config options of how the upstreams of every route are queried
+#[derive(Debug, Clone)]
+pub struct ExchangeOptions {
+    /// Tuning of the upstreams without an override
+    pub tuning: Tuning,
+    pub overrides: Vec<(Upstream, Tuning)>,
+    /// Delay after which an unanswered query is also sent to the next upstream
+    pub hedge_after: Option<Duration>,
+    pub health: HealthOptions,
+    /// Whether the names asked of plain upstreams are in randomized case
+    pub randomize_case: bool,
+    pub selection: Selection,
+}
+
+impl ExchangeOptions {
+    /// The tuning of `upstream`.
+    fn tuning(&self, upstream: &Upstream) -> Tuning {
+        self.overrides
+            .iter()
+            .find(|(overridden, _)| overridden == upstream)
+            .map_or(self.tuning, |(_, tuning)| *tuning)
+    }
+}
+
+/// Weight of the latest query in the smoothed response times and failure rates, as
+/// the smoothed round-trip time of TCP (RFC 6298).
+const SMOOTHING: f64 = 0.125;
+
+/// Health of an upstream.
+#[derive(Default)]
+struct Health {
+    /// Queries failed since the last one answered
+    failures: u32,
+    /// When an upstream that is down is tried again
+    down_until: Option<Instant>,
+    /// Smoothed response time; none until the upstream first answers
+    srtt: Option<Duration>,
+    /// Smoothed share of the queries that failed, from 0 to 1
+    failure_rate: f64,
+    answered: u64,
+    failed: u64,
+    /// When the upstream was last sent a query
+    last_sent: Option<Instant>,
+}
+
+impl Health {
+    /// The time a query can be expected to take, counting a failure as `timeout`.
+    fn expected(&self, timeout: Duration) -> Duration {
+        self.srtt.unwrap_or_default() + timeout.mul_f64(self.failure_rate)
+    }
+}
+
+/// Response times and failures of an upstream, as reported through the control API.
+#[derive(Debug, Clone)]
+pub struct UpstreamStats {
+    /// The upstream in the form it is configured in
+    pub upstream: String,
+    /// Smoothed response time; none until the upstream first answers
+    pub srtt: Option<Duration>,
+    /// Smoothed share of the queries that failed, from 0 to 1
+    pub failure_rate: f64,
+    /// Queries answered and failed since the server started
+    pub answered: u64,
+    pub failed: u64,
+    pub down: bool,
+}
+
+/// Health of every upstream, shared by the routes querying them and kept as they are
+/// rebuilt.
+#[derive(Default)]
+pub struct UpstreamHealth {
+    upstreams: Mutex<HashMap<(SocketAddr, Transport), Health>>,
+}
+
+impl UpstreamHealth {
+    /// Whether `upstream` is down and should only be tried once the others have failed.
+    fn is_down(&self, upstream: &Upstream) -> bool {
+        let upstreams = self.upstreams.lock().unwrap();
+        let health = upstreams.get(&(upstream.addr, upstream.transport));
+        health.and_then(|health| health.down_until).is_some_and(|until| until > Instant::now())
+    }
+
+    /// Notes that `upstream` was sent a query.
+    fn sent(&self, upstream: &Upstream) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        upstreams.entry((upstream.addr, upstream.transport)).or_default().last_sent = Some(Instant::now());
+    }
+
+    /// Notes that `upstream` answered a query after `rtt`, which brings it up again.
+    fn answered(&self, upstream: &Upstream, rtt: Duration) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let health = upstreams.entry((upstream.addr, upstream.transport)).or_default();
+        if health.down_until.take().is_some() {
+            eprintln!("Upstream {} is up again", upstream.addr);
+        }
+        health.failures = 0;
+        health.srtt = Some(health.srtt.map_or(rtt, |srtt| srtt.mul_f64(1.0 - SMOOTHING) + rtt.mul_f64(SMOOTHING)));
+        health.failure_rate *= 1.0 - SMOOTHING;
+        health.answered += 1;
+    }
+
+    /// Notes that `upstream` failed to answer a query, taking it down after
+    /// `options.max_failures` in a row, or again if it is being tried after being down.
+    fn failed(&self, upstream: &Upstream, options: &HealthOptions) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let health = upstreams.entry((upstream.addr, upstream.transport)).or_default();
+        health.failures += 1;
+        health.failure_rate = health.failure_rate * (1.0 - SMOOTHING) + SMOOTHING;
+        health.failed += 1;
+        if health.failures < options.max_failures {
+            return;
+        }
+        let now = Instant::now();
+        if health.down_until.is_none_or(|until| until <= now) {
+            if health.down_until.is_none() {
+                eprintln!(
+                    "Upstream {} is down after {} failed queries, skipping it for {}s",
+                    upstream.addr,
+                    health.failures,
+                    options.down_for.as_secs()
+                );
+            }
+            health.down_until = Some(now + options.down_for);
+        }
+    }
+
+    /// Orders `upstreams` by the time a query can be expected to take them, those not yet
+    /// measured last, in the order they are listed.
+    fn rank(&self, upstreams: &mut [(Upstream, Tuning)]) {
+        let health = self.upstreams.lock().unwrap();
+        upstreams.sort_by_cached_key(|(upstream, tuning)| {
+            match health
+                .get(&(upstream.addr, upstream.transport))
+                .filter(|health| health.answered + health.failed > 0)
+            {
+                Some(health) => (false, health.expected(tuning.timeout)),
+                None => (true, Duration::ZERO),
+            }
+        });
+    }
+
+    /// The first of the ranked `upstreams` after the fastest that is up and not sent a
+    /// query for `probe_every`, to be measured with a copy of a query. It is claimed right
+    /// away, so that concurrent queries don't all probe it.
+    fn probe(&self, upstreams: &[(Upstream, Tuning)], probe_every: Duration) -> Option<(Upstream, Tuning)> {
+        let mut health = self.upstreams.lock().unwrap();
+        let now = Instant::now();
+        let (upstream, tuning) = upstreams.iter().skip(1).find(|(upstream, _)| {
+            health.get(&(upstream.addr, upstream.transport)).is_none_or(|health| {
+                health.down_until.is_none_or(|until| until <= now)
+                    && health.last_sent.is_none_or(|sent| now.duration_since(sent) >= probe_every)
+            })
+        })?;
+        health.entry((upstream.addr, upstream.transport)).or_default().last_sent = Some(now);
+        Some((upstream.clone(), *tuning))
+    }
+
+    /// The response times and failures of `upstreams`, those never queried left out.
+    pub fn stats(&self, upstreams: &[Upstream]) -> Vec<UpstreamStats> {
+        let health = self.upstreams.lock().unwrap();
+        let now = Instant::now();
+        let mut seen = HashSet::new();
+        upstreams
+            .iter()
+            .filter(|upstream| seen.insert((upstream.addr, upstream.transport)))
+            .filter_map(|upstream| {
+                let health = health.get(&(upstream.addr, upstream.transport))?;
+                Some(UpstreamStats {
+                    upstream: upstream.to_string(),
+                    srtt: health.srtt,
+                    failure_rate: health.failure_rate,
+                    answered: health.answered,
+                    failed: health.failed,
+                    down: health.down_until.is_some_and(|until| until > now),
+                })
+            })
+            .collect()
+    }
+}
+
+/// The upstreams of a route, with the tuning of each and the connections to those that
+/// are encrypted.
+pub struct Upstreams {
+    upstreams: Vec<(Upstream, Tuning)>,
+    hedge_after: Option<Duration>,
+    health: Arc<UpstreamHealth>,
+    health_options: HealthOptions,
+    randomize_case: bool,
+    selection: Selection,
+    tls: Arc<ClientConfig>,
+    /// Connects to the encrypted upstreams, running the tasks of their connections
+    provider: TokioConnectionProvider,
+    /// Open connections to the encrypted upstreams, dropped on errors
+    connections: Mutex<HashMap<SocketAddr, GenericConnection>>,
+}
+
+impl Upstreams {
+    pub fn new(upstreams: &[Upstream], options: &ExchangeOptions, health: Arc<UpstreamHealth>, tls: Arc<ClientConfig>) -> Self {
+        Upstreams {
+            upstreams: upstreams.iter().map(|upstream| (upstream.clone(), options.tuning(upstream))).collect(),
+            hedge_after: options.hedge_after,
+            health,
+            health_options: options.health,
+            randomize_case: options.randomize_case,
+            selection: options.selection,
+            tls,
+            provider: TokioConnectionProvider::default(),
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The upstreams, in the order they are listed.
+    pub fn upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        self.upstreams.iter().map(|(upstream, _)| upstream)
+    }
+
+    /// Whether any of the upstreams is plain.
+    pub fn has_plain(&self) -> bool {
+        self.upstreams.iter().any(|(upstream, _)| upstream.transport == Transport::Plain)
+    }
+
+    /// Sends `query` to the upstreams in the order of the selection, or only to the plain ones if
+    /// `plain_only`, hedging it if configured, and returns the first answer that isn't
+    /// SERVFAIL or REFUSED, or else the last one.
+    pub async fn query(self: &Arc<Self>, query: &Message, plain_only: bool) -> std::io::Result<Message> {
+        let candidates = self.candidates(plain_only);
+        if let Selection::Fastest { probe_every } = self.selection {
+            if let Some(upstream) = self.health.probe(&candidates, probe_every) {
+                // Only measures the upstream, whose answer is noted in its health and dropped
+                drop(self.spawn_attempt(upstream, query));
+            }
+        }
+        let mut next = candidates.into_iter().peekable();
+        let mut pending = FuturesUnordered::new();
+        let mut error = std::io::Error::other("no upstream answered");
+        let mut failed = None;
+        match next.next() {
+            Some(upstream) => pending.push(self.spawn_attempt(upstream, query)),
+            None => return Err(error),
+        }
+        loop {
+            let hedge_after = self.hedge_after.filter(|_| next.peek().is_some());
+            let hedge = async {
+                match hedge_after {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                Some(result) = pending.next() => {
+                    match result.unwrap_or_else(|e| Err(std::io::Error::other(e))) {
+                        Ok(response) if !matches!(response.response_code(), ResponseCode::ServFail | ResponseCode::Refused) => return Ok(response),
+                        Ok(response) => failed = Some(response),
+                        Err(e) => error = e,
+                    }
+                    if pending.is_empty() {
+                        match next.next() {
+                            Some(upstream) => pending.push(self.spawn_attempt(upstream, query)),
+                            None => return failed.ok_or(error),
+                        }
+                    }
+                }
+                _ = hedge => {
+                    if let Some(upstream) = next.next() {
+                        pending.push(self.spawn_attempt(upstream, query));
+                    }
+                }
+            }
+        }
+    }
+
+    /// The upstreams a query is sent to, in order: those up first, ranked if selecting
+    /// the fastest, then those down.
+    fn candidates(&self, plain_only: bool) -> Vec<(Upstream, Tuning)> {
+        let (mut up, down): (Vec<_>, Vec<_>) = self
+            .upstreams
+            .iter()
+            .filter(|(upstream, _)| !plain_only || upstream.transport == Transport::Plain)
+            .cloned()
+            .partition(|(upstream, _)| !self.health.is_down(upstream));
+        if let Selection::Fastest { .. } = self.selection {
+            self.health.rank(&mut up);
+        }
+        up.extend(down);
+        up
+    }
+
+    /// Starts an attempt at `query` on `upstream`. It runs to its end even once another
+    /// upstream has answered, so that the failures of slow upstreams are noted too.
+    fn spawn_attempt(self: &Arc<Self>, upstream: (Upstream, Tuning), query: &Message) -> JoinHandle<std::io::Result<Message>> {
+        let upstreams = self.clone();
+        let query = query.clone();
+        tokio::spawn(async move { upstreams.attempt(&upstream, &query).await })
+    }
+
+    /// Sends `query` to `upstream`, retrying it as its tuning allows, and notes whether
+    /// the upstream answered.
+    async fn attempt(&self, (upstream, tuning): &(Upstream, Tuning), query: &Message) -> std::io::Result<Message> {
+        let mut error = std::io::Error::other("no attempt made");
+        self.health.sent(upstream);
+        for _ in 0..=tuning.retries {
+            let started = Instant::now();
+            match tokio::time::timeout(tuning.timeout, self.exchange(upstream, query)).await {
+                Ok(Ok(response)) => {
+                    crate::debug!("Upstream {} answered {}: {}", upstream.addr, describe(query), response.response_code());
+                    self.health.answered(upstream, started.elapsed());
+                    return Ok(response);
+                }
+                Ok(Err(e)) => error = e,
+                Err(_) => error = std::io::Error::new(std::io::ErrorKind::TimedOut, format!("{} timed out", upstream.addr)),
+            }
+            crate::debug!("Upstream {} failed to answer {}: {}", upstream.addr, describe(query), error);
+        }
+        self.health.failed(upstream, &self.health_options);
+        Err(error)
+    }
+
+    /// Sends `query` to `upstream`, over a connection kept open if it is encrypted.
+    async fn exchange(&self, upstream: &Upstream, query: &Message) -> std::io::Result<Message> {
+        if upstream.transport == Transport::Plain {
+            if !self.randomize_case {
+                return forward::exchange(upstream.addr, query).await;
+            }
+            let mut response = forward::exchange(upstream.addr, &randomize_case(query)).await?;
+            restore_case(&mut response, query);
+            return Ok(response);
+        }
+        let connection = self.connection(upstream).await?;
+        let mut options = DnsRequestOptions::default();
+        options.use_edns = true;
+        match connection.send(DnsRequest::new(query.clone(), options)).first_answer().await {
+            Ok(response) => Ok(response.into_message()),
+            Err(e) => {
+                self.connections.lock().unwrap().remove(&upstream.addr);
+                Err(std::io::Error::other(e))
+            }
+        }
+    }
+
+    /// The open connection to the encrypted `upstream`, connecting if there is none.
+    async fn connection(&self, upstream: &Upstream) -> std::io::Result<GenericConnection> {
+        if let Some(connection) = self.connections.lock().unwrap().get(&upstream.addr) {
+            return Ok(connection.clone());
+        }
+        let protocol = match upstream.transport {
+            Transport::Https => Protocol::Https,
+            _ => Protocol::Tls,
+        };
+        let mut config = NameServerConfig::new(upstream.addr, protocol);
+        config.tls_dns_name = upstream.tls_name.clone();
+        config.tls_config = Some(TlsClientConfig(self.tls.clone()));
+        let connection = self
+            .provider
+            .new_connection(&config, &ResolverOpts::default())
+            .await
+            .map_err(std::io::Error::other)?;
+        self.connections.lock().unwrap().insert(upstream.addr, connection.clone());
+        Ok(connection)
+    }
+}
+
+/// `query` with its names in random case, each letter upper or lower case at random.
+fn randomize_case(query: &Message) -> Message {
+    let mut randomized = query.clone();
+    for question in randomized.queries_mut() {
+        let labels = question.name().iter().map(|label| {
+            label
+                .iter()
+                .map(|byte| if rand::random() { byte.to_ascii_uppercase() } else { byte.to_ascii_lowercase() })
+                .collect::<Vec<u8>>()
+        });
+        if let Ok(mut name) = Name::from_labels(labels) {
+            name.set_fqdn(question.name().is_fqdn());
+            question.set_name(name);
+        }
+    }
+    randomized
+}
+
+/// Puts the names of `query` back in the questions of its `response`, and in the
+/// records named as they were asked, so that the case the upstream was asked in goes
+/// no further.
+fn restore_case(response: &mut Message, query: &Message) {
+    let asked: Vec<&Name> = query.queries().iter().map(Query::name).collect();
+    for (question, name) in response.queries_mut().iter_mut().zip(&asked) {
+        question.set_name((*name).clone());
+    }
+    let restore = |records: &mut Vec<Record>| {
+        for record in records {
+            // Names compare regardless of case
+            if let Some(name) = asked.iter().find(|name| **name == record.name()) {
+                record.set_name((*name).clone());
+            }
+        }
+    };
+    restore(response.answers_mut());
+    restore(response.name_servers_mut());
+    restore(response.additionals_mut());
+}
+
+/// The name and type `query` asks for, for logging.
+fn describe(query: &Message) -> String {
+    match query.queries().first() {
+        Some(query) => format!("{} {}", query.name(), query.query_type()),
+        None => "an empty query".to_string(),
+    }
+}
+
+/// Builds the TLS config of the encrypted upstreams: certificates are verified against
+/// the CA certificates of `ca_file`, or the Mozilla roots without one, and must have a
+/// public key of `pins` in their chain if any are given.
+pub fn client_config(ca_file: Option<&Path>, pins: &[String]) -> anyhow::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_file {
+        Some(ca_file) => {
+            for cert in tls_server::read_cert(ca_file)? {
+                roots
+                    .add(&cert)
+                    .map_err(|e| anyhow::anyhow!("invalid CA certificate in {}: {}", ca_file.display(), e))?;
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+        })),
+    }
+    let pins = pins
+        .iter()
+        .map(|pin| match STANDARD.decode(pin) {
+            Ok(digest) if digest.len() == 32 => Ok(digest),
+            _ => Err(anyhow::anyhow!("invalid dns.forward.tls_pins entry {}, expected a base64 SHA-256 digest", pin)),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let builder = ClientConfig::builder().with_safe_defaults();
+    let config = if pins.is_empty() {
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        let verifier = PinnedVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            pins,
+        };
+        builder.with_custom_certificate_verifier(Arc::new(verifier)).with_no_client_auth()
+    };
+    Ok(Arc::new(config))
+}
+
+/// Verifies certificates like `WebPkiVerifier`, then requires a pinned public key in the
+/// chain.
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    /// SHA-256 digests of the DER SubjectPublicKeyInfo of the pinned keys
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        let pinned = std::iter::once(end_entity).chain(intermediates).any(|cert| {
+            spki(&cert.0).is_some_and(|spki| {
+                let digest = ring::digest::digest(&ring::digest::SHA256, spki);
+                self.pins.iter().any(|pin| pin.as_slice() == digest.as_ref())
+            })
+        });
+        if !pinned {
+            return Err(rustls::Error::General("no key of the upstream's certificate chain is pinned".into()));
+        }
+        Ok(verified)
+    }
+}
+
+/// Splits the DER element at the start of `der` into the length of its header, the
+/// whole element and what follows it.
+fn element(der: &[u8]) -> Option<(usize, &[u8], &[u8])> {
+    let (_tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..=0x7f => (usize::from(first), rest),
+        0x81..=0x84 => {
+            let count = usize::from(first & 0x7f);
+            let bytes = rest.get(..count)?;
+            (bytes.iter().fold(0, |len, &byte| len << 8 | usize::from(byte)), &rest[count..])
+        }
+        _ => return None,
+    };
+    let header = der.len() - rest.len();
+    let end = header.checked_add(len).filter(|&end| end <= der.len())?;
+    Some((header, &der[..end], &der[end..]))
+}
+
+/// The DER SubjectPublicKeyInfo of the X.509 certificate `cert`.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let (header, certificate, _) = element(cert)?;
+    let (header, tbs, _) = element(&certificate[header..])?;
+    let mut fields = &tbs[header..];
+    // Skipping the version, if given, the serial number, signature algorithm, issuer,
+    // validity and subject
+    if fields.first() == Some(&0xa0) {
+        fields = element(fields)?.2;
+    }
+    for _ in 0..5 {
+        fields = element(fields)?.2;
+    }
+    Some(element(fields)?.1)
+}
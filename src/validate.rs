@@ -0,0 +1,199 @@
+//! Validation of records given through the control and REST APIs.
+//!
+//! Names must be hostnames, optionally with a leading `*` label or `_service` labels,
+//! with labels of at most 63 and names of at most 253 characters once Unicode labels
+//! are punycode-encoded. Values are checked for the record type before being parsed, so
+//! an A record given an IPv6 address, or a CNAME given an address, is rejected with a
+//! message saying so rather than whatever the rdata parser makes of it. CAA, TLSA and
+//! SSHFP values are held to the fields their RFCs define, which the parser would
+//! otherwise accept with any number in them. Failures are `InvalidName` and
+//! `InvalidRdata` errors. SPF, DKIM and DMARC records are checked by `mailauth` once
+//! parsed.
+
+use hickory_proto::rr::{Name, RecordType};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::error::RdnsError;
+
+/// Longest name in presentation format, without its trailing dot.
+const MAX_NAME_LEN: usize = 253;
+/// Longest label of a name.
+const MAX_LABEL_LEN: usize = 63;
+
+/// Checks that `name` is a valid owner name. Unicode names are checked in the punycode
+/// form they are held in.
+pub fn name(name: &str) -> Result<(), RdnsError> {
+    if !name.is_ascii() {
+        let ascii = Name::from_utf8(name)
+            .map_err(|e| RdnsError::InvalidName(format!("invalid international name {}: {}", name, e)))?
+            .to_ascii();
+        return hostname(&ascii, true, "name").map_err(RdnsError::InvalidName);
+    }
+    hostname(name, true, "name").map_err(RdnsError::InvalidName)
+}
+
+/// Checks `name` as a hostname; `what` names it in error messages.
+pub(crate) fn hostname(name: &str, wildcard: bool, what: &str) -> Result<(), String> {
+    let trimmed = name.strip_suffix('.').unwrap_or(name);
+    if trimmed.is_empty() {
+        return Err(format!("{} must not be empty", what));
+    }
+    if trimmed.len() > MAX_NAME_LEN {
+        return Err(format!("{} {} is longer than {} characters", what, name, MAX_NAME_LEN));
+    }
+    for (i, label) in trimmed.split('.').enumerate() {
+        if label.is_empty() {
+            return Err(format!("{} {} has an empty label", what, name));
+        }
+        if label.len() > MAX_LABEL_LEN {
+            return Err(format!("label {} of {} {} is longer than {} characters", label, what, name, MAX_LABEL_LEN));
+        }
+        if wildcard && i == 0 && label == "*" {
+            continue;
+        }
+        let valid = label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            && !label.starts_with('-')
+            && !label.ends_with('-');
+        if !valid {
+            return Err(format!(
+                "label {} of {} {} may only hold letters, digits, inner hyphens and underscores",
+                label, what, name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `value` is plausible rdata for `record_type`: an address of the right
+/// family for A and AAAA records, a hostname for the types that point at one, and
+/// defined fields for CAA, TLSA and SSHFP records.
+pub fn value(record_type: RecordType, value: &str) -> Result<(), RdnsError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(RdnsError::InvalidRdata(format!("{} record value must not be empty", record_type)));
+    }
+    match record_type {
+        RecordType::A if value.parse::<Ipv4Addr>().is_err() => {
+            let hint = if value.parse::<Ipv6Addr>().is_ok() { ", use an AAAA record for IPv6" } else { "" };
+            Err(RdnsError::InvalidRdata(format!("A record value {} is not an IPv4 address{}", value, hint)))
+        }
+        RecordType::AAAA if value.parse::<Ipv6Addr>().is_err() => {
+            let hint = if value.parse::<Ipv4Addr>().is_ok() { ", use an A record for IPv4" } else { "" };
+            Err(RdnsError::InvalidRdata(format!("AAAA record value {} is not an IPv6 address{}", value, hint)))
+        }
+        RecordType::CNAME | RecordType::NS | RecordType::PTR => {
+            if value.parse::<Ipv4Addr>().is_ok() || value.parse::<Ipv6Addr>().is_ok() {
+                return Err(RdnsError::InvalidRdata(format!("{} record value {} must be a name, not an address", record_type, value)));
+            }
+            hostname(value, false, &format!("{} target", record_type)).map_err(RdnsError::InvalidRdata)
+        }
+        RecordType::CAA => caa(value).map_err(RdnsError::InvalidRdata),
+        RecordType::TLSA => tlsa(value).map_err(RdnsError::InvalidRdata),
+        RecordType::SSHFP => sshfp(value).map_err(RdnsError::InvalidRdata),
+        _ => Ok(()),
+    }
+}
+
+/// Checks a CAA value, `flags tag "value"` (RFC 8659).
+fn caa(value: &str) -> Result<(), String> {
+    let mut fields = value.splitn(3, char::is_whitespace);
+    let (flags, tag, tag_value) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(flags), Some(tag), Some(tag_value)) => (flags, tag, tag_value.trim()),
+        _ => return Err(format!("CAA record value {} must be `flags tag \"value\"`", value)),
+    };
+    // Only the issuer critical flag is defined, the other bits are reserved
+    if !matches!(flags.parse::<u8>(), Ok(0 | 128)) {
+        return Err(format!("CAA flags {} must be 0, or 128 for issuer critical", flags));
+    }
+    if tag.is_empty() || tag.len() > 15 || !tag.bytes().all(|b| b.is_ascii_alphanumeric()) {
+        return Err(format!("CAA tag {} must be 1 to 15 letters and digits", tag));
+    }
+    let tag_value = match tag_value.strip_prefix('"') {
+        Some(quoted) => quoted
+            .strip_suffix('"')
+            .ok_or_else(|| format!("CAA value {} has an unclosed quote", tag_value))?,
+        None => tag_value,
+    };
+    match tag.to_ascii_lowercase().as_str() {
+        "issue" | "issuewild" => {
+            let mut parts = tag_value.split(';');
+            let issuer = parts.next().unwrap_or_default().trim();
+            // An empty issuer forbids issuance altogether
+            if !issuer.is_empty() {
+                hostname(issuer, false, "CAA issuer")?;
+            }
+            for parameter in parts.map(str::trim).filter(|parameter| !parameter.is_empty()) {
+                let (key, parameter_value) = parameter.split_once('=').unwrap_or((parameter, ""));
+                if key.is_empty() || !key.bytes().all(|b| b.is_ascii_alphanumeric()) || parameter_value.contains(char::is_whitespace) {
+                    return Err(format!("CAA issuer parameter {} must be key=value", parameter));
+                }
+            }
+            Ok(())
+        }
+        "iodef" => {
+            if ["mailto:", "http://", "https://"].iter().any(|scheme| tag_value.starts_with(scheme)) {
+                Ok(())
+            } else {
+                Err(format!("CAA iodef {} must be a mailto:, http:// or https:// URL", tag_value))
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Checks a TLSA value, `usage selector matching data` (RFC 6698).
+fn tlsa(value: &str) -> Result<(), String> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let [usage, selector, matching, data @ ..] = fields.as_slice() else {
+        return Err(format!("TLSA record value {} must be `usage selector matching data`", value));
+    };
+    if !matches!(usage.parse::<u8>(), Ok(0..=3)) {
+        return Err(format!(
+            "TLSA certificate usage {} must be 0 to 3 (PKIX-TA, PKIX-EE, DANE-TA, DANE-EE)",
+            usage
+        ));
+    }
+    if !matches!(selector.parse::<u8>(), Ok(0..=1)) {
+        return Err(format!("TLSA selector {} must be 0 (full certificate) or 1 (public key)", selector));
+    }
+    let digest_len = match matching.parse::<u8>() {
+        Ok(0) => None,
+        Ok(1) => Some(32),
+        Ok(2) => Some(64),
+        _ => return Err(format!("TLSA matching type {} must be 0 (exact), 1 (SHA-256) or 2 (SHA-512)", matching)),
+    };
+    // The association data may be split over several fields
+    hex_data("TLSA association data", &data.concat(), digest_len)
+}
+
+/// Checks an SSHFP value, `algorithm type fingerprint` (RFC 4255).
+fn sshfp(value: &str) -> Result<(), String> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let [algorithm, fingerprint_type, fingerprint] = fields.as_slice() else {
+        return Err(format!("SSHFP record value {} must be `algorithm type fingerprint`", value));
+    };
+    if !matches!(algorithm.parse::<u8>(), Ok(1..=4 | 6)) {
+        return Err(format!(
+            "SSHFP algorithm {} must be 1 (RSA), 2 (DSA), 3 (ECDSA), 4 (Ed25519) or 6 (Ed448)",
+            algorithm
+        ));
+    }
+    let digest_len = match fingerprint_type.parse::<u8>() {
+        Ok(1) => 20,
+        Ok(2) => 32,
+        _ => return Err(format!("SSHFP fingerprint type {} must be 1 (SHA-1) or 2 (SHA-256)", fingerprint_type)),
+    };
+    hex_data("SSHFP fingerprint", fingerprint, Some(digest_len))
+}
+
+/// Checks that `data` is hex, of `len` bytes when given; `what` names it in error
+/// messages.
+fn hex_data(what: &str, data: &str, len: Option<usize>) -> Result<(), String> {
+    if data.is_empty() || !data.len().is_multiple_of(2) || !data.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("{} {} must be an even number of hex digits", what, data));
+    }
+    match len {
+        Some(len) if data.len() != len * 2 => Err(format!("{} {} must be {} bytes ({} hex digits) for its digest", what, data, len, len * 2)),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,653 @@
+//! DNSSEC validation of forwarded answers.
+//!
+//! With `[dns.forward.dnssec]` configured, forwarded queries are sent to the upstreams
+//! with the DO and CD bits set, so that they return the signatures along with the records
+//! and leave validation to rdns, which checks each answer against a chain of trust
+//! (RFC 4035): from a trust anchor, the root zone's key signing keys by default, through
+//! the DS and DNSKEY records of every zone down to the RRSIGs of the answer, and the
+//! signed NSEC or NSEC3 records proving that a name or type doesn't exist. Answers are
+//! returned without their signatures, with the AD bit to clients that set DO or AD once
+//! the chain holds. Zones the parent proves to have no DS records are unsigned, and
+//! answered without the AD bit. Answers whose signatures are missing, expired or don't
+//! verify are bogus, logged and answered with SERVFAIL. Names under a domain of
+//! `negative_trust_anchors` (RFC 7646) aren't validated, for zones whose signatures are
+//! known to be broken. The keys of each zone, and whether it is signed, are cached for
+//! an hour, and bogus zones for a minute.
+
+use data_encoding::BASE32HEX_NOPAD;
+use futures_util::future::BoxFuture;
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, DS, NSEC3, RRSIG};
+use hickory_proto::rr::dnssec::Verifier;
+use hickory_proto::rr::{DNSClass, LowerName, Name, RData, Record, RecordType};
+use hickory_proto::serialize::txt::RDataParser;
+use hickory_server::authority::{LookupError, MessageResponse};
+use hickory_server::server::{Request, ResponseHandler, ResponseInfo};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tonic::async_trait;
+
+use crate::cache::{self, Answer};
+use crate::ecs::Subnet;
+use crate::forward::MAX_PAYLOAD;
+use crate::settings::ValidationSettings;
+use crate::upstream::Upstreams;
+
+/// How long the status of a zone is cached.
+const ZONE_TTL: Duration = Duration::from_secs(3600);
+/// How long a bogus zone is cached, short as it may be fixed any time.
+const BOGUS_TTL: Duration = Duration::from_secs(60);
+/// Zones cached before the cache is emptied.
+const MAX_ZONES: usize = 10_000;
+/// CNAMEs followed for a single answer.
+const MAX_CNAMES: usize = 8;
+
+/// Config options for DNSSEC validation
+#[derive(Debug, Clone)]
+pub struct ValidationOptions {
+    /// DS records of the trust anchors, with the zone of each
+    pub trust_anchors: Vec<(Name, DS)>,
+    pub negative_trust_anchors: Vec<LowerName>,
+}
+
+impl TryFrom<ValidationSettings> for ValidationOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ValidationSettings) -> anyhow::Result<Self> {
+        let trust_anchors = cfg
+            .trust_anchors
+            .iter()
+            .map(|anchor| parse_anchor(anchor))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if trust_anchors.is_empty() {
+            anyhow::bail!("dns.forward.dnssec.trust_anchors can't be empty");
+        }
+        let negative_trust_anchors = cfg
+            .negative_trust_anchors
+            .iter()
+            .map(|domain| {
+                let mut name = Name::from_utf8(domain).map_err(|e| anyhow::anyhow!("invalid negative trust anchor {}: {}", domain, e))?;
+                name.set_fqdn(true);
+                Ok(LowerName::new(&name))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(ValidationOptions {
+            trust_anchors,
+            negative_trust_anchors,
+        })
+    }
+}
+
+/// Parses a trust anchor given as a DS record in zone file format, with an optional TTL
+/// and class.
+fn parse_anchor(anchor: &str) -> anyhow::Result<(Name, DS)> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "invalid dns.forward.dnssec.trust_anchors entry {}, expected `<zone> DS <key tag> <algorithm> <digest type> <digest>`",
+            anchor
+        )
+    };
+    let mut tokens = anchor.split_whitespace();
+    let mut zone = tokens.next().and_then(|zone| Name::from_utf8(zone).ok()).ok_or_else(invalid)?;
+    zone.set_fqdn(true);
+    let mut tokens = tokens.skip_while(|token| !token.eq_ignore_ascii_case("DS"));
+    tokens.next().ok_or_else(invalid)?;
+    let rdata = tokens.collect::<Vec<_>>().join(" ");
+    match RData::try_from_str(RecordType::DS, &rdata) {
+        Ok(RData::DNSSEC(DNSSECRData::DS(ds))) => Ok((zone, ds)),
+        _ => Err(invalid()),
+    }
+}
+
+/// The outcome of validating an answer, ordered from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verdict {
+    /// Every record is signed along a chain of trust
+    Secure,
+    /// The records are in a zone proven to be unsigned, or under a negative trust anchor
+    Insecure,
+    /// The signatures are missing or don't verify
+    Bogus,
+}
+
+tokio::task_local! {
+    /// Worst verdict on the lookups made for the query being answered.
+    static VERDICT: Cell<Option<Verdict>>;
+}
+
+/// Runs `handling`, a query being answered through the catalog, noting the verdict on
+/// the forwarded lookups it makes for `ValidatingResponseHandler`.
+pub async fn validating<F: Future>(handling: F) -> F::Output {
+    VERDICT.scope(Cell::new(None), handling).await
+}
+
+/// Notes `verdict` on a lookup made for the query being answered.
+pub fn note(verdict: Verdict) {
+    let _ = VERDICT.try_with(|worst| worst.set(worst.get().max(Some(verdict))));
+}
+
+/// Response handler that sets the AD bit on validated answers, for clients that ask for
+/// it with DO or AD, and answers bogus ones with SERVFAIL.
+#[derive(Clone)]
+pub struct ValidatingResponseHandler<R> {
+    inner: R,
+    authentic_data: bool,
+}
+
+impl<R> ValidatingResponseHandler<R> {
+    pub fn new(inner: R, request: &Request) -> Self {
+        let authentic_data = request.header().authentic_data() || request.edns().is_some_and(|edns| edns.dnssec_ok());
+        Self { inner, authentic_data }
+    }
+}
+
+#[async_trait]
+impl<R: ResponseHandler> ResponseHandler for ValidatingResponseHandler<R> {
+    async fn send_response<'a>(
+        &mut self,
+        mut response: MessageResponse<
+            '_,
+            'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+            impl Iterator<Item = &'a Record> + Send + 'a,
+        >,
+    ) -> std::io::Result<ResponseInfo> {
+        match VERDICT.try_with(Cell::get).ok().flatten() {
+            Some(Verdict::Bogus) => {
+                response.header_mut().set_response_code(ResponseCode::ServFail);
+            }
+            Some(Verdict::Secure) if self.authentic_data => {
+                response.header_mut().set_authentic_data(true);
+            }
+            _ => {}
+        }
+        self.inner.send_response(response).await
+    }
+}
+
+/// Whether a zone's records are validated.
+#[derive(Clone)]
+enum ZoneStatus {
+    /// Signed, by these keys the chain of trust vouches for
+    Secure(Arc<[DNSKEY]>),
+    Insecure,
+    Bogus,
+}
+
+/// What the NSEC or NSEC3 records of a negative answer prove.
+enum Proof {
+    /// The name exists, with only these types
+    NoData(Vec<RecordType>),
+    /// The name doesn't exist
+    NoName,
+    /// The name falls in a span of unsigned delegations (RFC 5155 section 6)
+    OptOut,
+}
+
+/// Queries the upstreams of a route with the DO and CD bits set, and validates their
+/// answers.
+pub struct Validator {
+    options: Arc<ValidationOptions>,
+    upstreams: Arc<Upstreams>,
+    /// Status of the zones looked up, until it expires
+    zones: Mutex<HashMap<LowerName, (ZoneStatus, Instant)>>,
+}
+
+impl Validator {
+    pub fn new(options: Arc<ValidationOptions>, upstreams: Arc<Upstreams>) -> Self {
+        Validator {
+            options,
+            upstreams,
+            zones: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolves `name` and `rtype` through the upstreams, passing `subnet` on to the
+    /// plain ones if given, and validates the answer. The verdict is noted for the query
+    /// being answered; bogus answers fail with SERVFAIL.
+    pub async fn lookup(&self, name: &LowerName, rtype: RecordType, subnet: Option<&Subnet>) -> Result<(Answer, Verdict), LookupError> {
+        let name = Name::from(name);
+        let mut answers = Vec::new();
+        let mut verdict = Verdict::Secure;
+        let mut target = name.clone();
+        let mut response_code = ResponseCode::NoError;
+        let mut negative_ttl = None;
+        // Upstreams that only answer authoritatively leave CNAMEs to other zones unfollowed
+        for _ in 0..MAX_CNAMES {
+            let response = self.query(&target, rtype, subnet).await?;
+            verdict = match self.is_negative(&target) || !self.has_anchor(&target) {
+                true => verdict.max(Verdict::Insecure),
+                false => verdict.max(self.check_response(&target, rtype, &response).await?),
+            };
+            answers.extend(
+                response
+                    .answers()
+                    .iter()
+                    .filter(|record| rtype == RecordType::RRSIG || record.record_type() != RecordType::RRSIG)
+                    .cloned(),
+            );
+            response_code = response.response_code();
+            negative_ttl = cache::negative_ttl(&response);
+            match unfollowed(&target, rtype, &response) {
+                Some(next) => target = next,
+                None => break,
+            }
+        }
+        note(verdict);
+        if verdict == Verdict::Bogus {
+            eprintln!("Bogus answer from upstream to {} {}", name, rtype);
+            return Err(LookupError::from(ResponseCode::ServFail));
+        }
+        let answer = Answer::new(Query::query(name, rtype), response_code, answers, negative_ttl)?;
+        Ok((answer, verdict))
+    }
+
+    /// Whether `name` is under a negative trust anchor.
+    fn is_negative(&self, name: &Name) -> bool {
+        let name = LowerName::new(name);
+        self.options.negative_trust_anchors.iter().any(|domain| domain.zone_of(&name))
+    }
+
+    /// Whether `name` is under a trust anchor, and records of its zone can be validated.
+    fn has_anchor(&self, name: &Name) -> bool {
+        self.options.trust_anchors.iter().any(|(zone, _)| zone.zone_of(name))
+    }
+
+    /// Validates the answer to a query for `name` and `rtype`: every RRset of the answer
+    /// section, and the proof of denial if the name, or the target of its CNAMEs, has no
+    /// records of the type.
+    async fn check_response(&self, name: &Name, rtype: RecordType, response: &Message) -> Result<Verdict, LookupError> {
+        let nx = match response.response_code() {
+            ResponseCode::NoError => false,
+            ResponseCode::NXDomain => true,
+            // Errors carry nothing to validate
+            _ => return Ok(Verdict::Insecure),
+        };
+        let answers = response.answers();
+        let mut verdict = Verdict::Secure;
+        for (owner, rrtype) in rrsets(answers) {
+            verdict = verdict.max(self.check_rrset(&owner, rrtype, answers).await?);
+        }
+
+        if unfollowed(name, rtype, response).is_some() {
+            return Ok(verdict);
+        }
+        let target = cname_target(name, rtype, answers);
+        let answered = answers.iter().any(|record| *record.name() == target && record.record_type() == rtype);
+        if nx || (!answered && rtype != RecordType::ANY) {
+            let denial = match self.check_denial(&target, rtype, nx, response.name_servers()).await? {
+                (Verdict::Secure, Some(Proof::OptOut)) => Verdict::Insecure,
+                (verdict, _) => verdict,
+            };
+            verdict = verdict.max(denial);
+        }
+        Ok(verdict)
+    }
+
+    /// Validates the RRset of `owner` and `rtype` among `records` against the RRSIGs
+    /// among them.
+    async fn check_rrset(&self, owner: &Name, rtype: RecordType, records: &[Record]) -> Result<Verdict, LookupError> {
+        let Some(signer) = signatures(owner, rtype, records).next().map(|sig| sig.signer_name().clone()) else {
+            // DS records are held by the parent zone
+            return match rtype {
+                RecordType::DS => self.unsigned(&owner.base_name()).await,
+                _ => self.unsigned(owner).await,
+            };
+        };
+        // A zone signs its own records, and the parent zone the DS records of a child
+        if !signer.zone_of(owner) || (rtype == RecordType::DS && signer == *owner) {
+            return Ok(Verdict::Bogus);
+        }
+        Ok(match self.zone_status(&signer).await? {
+            ZoneStatus::Secure(keys) if signed_by(owner, rtype, records, &signer, &keys) => Verdict::Secure,
+            ZoneStatus::Insecure => Verdict::Insecure,
+            _ => Verdict::Bogus,
+        })
+    }
+
+    /// Validates the proof among `authority` that `name` has no records of `rtype`, or
+    /// doesn't exist at all when `nx`, returning the proof if it holds.
+    async fn check_denial(&self, name: &Name, rtype: RecordType, nx: bool, authority: &[Record]) -> Result<(Verdict, Option<Proof>), LookupError> {
+        // The zone the denial comes from, named by its SOA or the signer of its NSEC records
+        let soa = authority.iter().find(|record| record.record_type() == RecordType::SOA && record.name().zone_of(name));
+        let zone = soa.map(|record| record.name().clone()).or_else(|| {
+            authority.iter().find_map(|record| match record.data() {
+                Some(RData::DNSSEC(DNSSECRData::RRSIG(sig)))
+                    if matches!(sig.type_covered(), RecordType::NSEC | RecordType::NSEC3) && sig.signer_name().zone_of(name) =>
+                {
+                    Some(sig.signer_name().clone())
+                }
+                _ => None,
+            })
+        });
+        let Some(zone) = zone else {
+            let verdict = match rtype {
+                RecordType::DS => self.unsigned(&name.base_name()).await?,
+                _ => self.unsigned(name).await?,
+            };
+            return Ok((verdict, None));
+        };
+        if rtype == RecordType::DS && zone == *name {
+            return Ok((Verdict::Bogus, None));
+        }
+        let keys = match self.zone_status(&zone).await? {
+            ZoneStatus::Secure(keys) => keys,
+            ZoneStatus::Insecure => return Ok((Verdict::Insecure, None)),
+            ZoneStatus::Bogus => return Ok((Verdict::Bogus, None)),
+        };
+        if soa.is_some() && !signed_by(&zone, RecordType::SOA, authority, &zone, &keys) {
+            return Ok((Verdict::Bogus, None));
+        }
+        // Only NSEC and NSEC3 records signed by the zone itself make up the proof
+        let proofs: Vec<&Record> = authority
+            .iter()
+            .filter(|record| matches!(record.record_type(), RecordType::NSEC | RecordType::NSEC3))
+            .filter(|record| signed_by(record.name(), record.record_type(), authority, &zone, &keys))
+            .collect();
+        Ok(match prove(name, rtype, nx, &zone, &proofs) {
+            Some(proof) => (Verdict::Secure, Some(proof)),
+            None => (Verdict::Bogus, None),
+        })
+    }
+
+    /// The verdict on unsigned records at `name`: insecure if the zone holding it is
+    /// proven to be unsigned, bogus otherwise.
+    async fn unsigned(&self, name: &Name) -> Result<Verdict, LookupError> {
+        if self.is_negative(name) || !self.has_anchor(name) {
+            return Ok(Verdict::Insecure);
+        }
+        let response = self.query(name, RecordType::SOA, None).await?;
+        let zone = response
+            .answers()
+            .iter()
+            .chain(response.name_servers())
+            .find(|record| record.record_type() == RecordType::SOA && record.name().zone_of(name))
+            .map(|record| record.name().clone());
+        let Some(zone) = zone else {
+            return Ok(Verdict::Bogus);
+        };
+        Ok(match self.zone_status(&zone).await? {
+            ZoneStatus::Insecure => Verdict::Insecure,
+            _ => Verdict::Bogus,
+        })
+    }
+
+    /// The status of the zone at `zone`, from the cache when it has one.
+    fn zone_status<'a>(&'a self, zone: &'a Name) -> BoxFuture<'a, Result<ZoneStatus, LookupError>> {
+        Box::pin(async move {
+            let key = LowerName::new(zone);
+            if let Some((status, until)) = self.zones.lock().unwrap().get(&key) {
+                if *until > Instant::now() {
+                    return Ok(status.clone());
+                }
+            }
+            let status = self.find_zone_status(zone).await?;
+            let ttl = match status {
+                ZoneStatus::Bogus => BOGUS_TTL,
+                _ => ZONE_TTL,
+            };
+            let mut zones = self.zones.lock().unwrap();
+            if zones.len() >= MAX_ZONES {
+                zones.clear();
+            }
+            zones.insert(key, (status.clone(), Instant::now() + ttl));
+            Ok(status)
+        })
+    }
+
+    /// Follows the chain of trust to the zone at `zone`: its keys are those of a trust
+    /// anchor, or vouched for by DS records the parent zone signed. A parent that proves
+    /// the zone is delegated without DS records makes it insecure.
+    async fn find_zone_status(&self, zone: &Name) -> Result<ZoneStatus, LookupError> {
+        if self.is_negative(zone) {
+            return Ok(ZoneStatus::Insecure);
+        }
+        let anchors: Vec<&DS> = self.options.trust_anchors.iter().filter(|(anchor, _)| anchor == zone).map(|(_, ds)| ds).collect();
+        if !anchors.is_empty() {
+            return self.keys(zone, &anchors).await;
+        }
+        if !self.has_anchor(zone) {
+            return Ok(ZoneStatus::Insecure);
+        }
+
+        let response = self.query(zone, RecordType::DS, None).await?;
+        let answers = response.answers();
+        let ds: Vec<&DS> = answers
+            .iter()
+            .filter(|record| record.name() == zone)
+            .filter_map(|record| match record.data() {
+                Some(RData::DNSSEC(DNSSECRData::DS(ds))) => Some(ds),
+                _ => None,
+            })
+            .collect();
+        match response.response_code() {
+            ResponseCode::NoError if !ds.is_empty() => match self.check_rrset(zone, RecordType::DS, answers).await? {
+                Verdict::Secure => self.keys(zone, &ds).await,
+                Verdict::Insecure => Ok(ZoneStatus::Insecure),
+                Verdict::Bogus => Ok(ZoneStatus::Bogus),
+            },
+            ResponseCode::NoError | ResponseCode::NXDomain => {
+                let nx = response.response_code() == ResponseCode::NXDomain;
+                Ok(match self.check_denial(zone, RecordType::DS, nx, response.name_servers()).await? {
+                    (Verdict::Secure, Some(Proof::OptOut)) => ZoneStatus::Insecure,
+                    (Verdict::Secure, Some(Proof::NoData(types))) if types.contains(&RecordType::NS) => ZoneStatus::Insecure,
+                    (Verdict::Insecure, _) => ZoneStatus::Insecure,
+                    _ => ZoneStatus::Bogus,
+                })
+            }
+            code => Err(LookupError::from(code)),
+        }
+    }
+
+    /// Fetches the keys of the zone at `zone`, which are secure if one of them is vouched
+    /// for by `ds` and signs them all.
+    async fn keys(&self, zone: &Name, ds: &[&DS]) -> Result<ZoneStatus, LookupError> {
+        let response = self.query(zone, RecordType::DNSKEY, None).await?;
+        if !matches!(response.response_code(), ResponseCode::NoError | ResponseCode::NXDomain) {
+            return Err(LookupError::from(response.response_code()));
+        }
+        let answers = response.answers();
+        let keys: Vec<DNSKEY> = answers
+            .iter()
+            .filter(|record| record.name() == zone)
+            .filter_map(|record| match record.data() {
+                Some(RData::DNSSEC(DNSSECRData::DNSKEY(key))) if key.zone_key() && !key.revoke() => Some(key.clone()),
+                _ => None,
+            })
+            .collect();
+        let entry: Vec<DNSKEY> = keys
+            .iter()
+            .filter(|key| ds.iter().any(|ds| ds.covers(zone, key).unwrap_or(false)))
+            .cloned()
+            .collect();
+        if entry.is_empty() || !signed_by(zone, RecordType::DNSKEY, answers, zone, &entry) {
+            return Ok(ZoneStatus::Bogus);
+        }
+        Ok(ZoneStatus::Secure(keys.into()))
+    }
+
+    /// Sends a query for `name` and `rtype` to the upstreams, asking for the
+    /// signatures and unvalidated answers. With `subnet`, it is passed on in a Client
+    /// Subnet option and the query only sent to the plain upstreams, if there are any.
+    async fn query(&self, name: &Name, rtype: RecordType, subnet: Option<&Subnet>) -> Result<Message, LookupError> {
+        let mut query = Message::new();
+        query
+            .set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .set_checking_disabled(true)
+            .add_query(Query::query(name.clone(), rtype));
+        let mut edns = Edns::new();
+        edns.set_max_payload(MAX_PAYLOAD);
+        edns.set_dnssec_ok(true);
+        if let Some(subnet) = subnet {
+            edns.options_mut().insert(subnet.option(0));
+        }
+        query.set_edns(edns);
+
+        let plain_only = subnet.is_some() && self.upstreams.has_plain();
+        self.upstreams.query(&query, plain_only).await.map_err(LookupError::from)
+    }
+}
+
+/// The name the CNAMEs among `answers` lead `name` to, for queries of `rtype`.
+fn cname_target(name: &Name, rtype: RecordType, answers: &[Record]) -> Name {
+    let mut target = name.clone();
+    if rtype == RecordType::CNAME {
+        return target;
+    }
+    for _ in 0..answers.len() {
+        let next = answers.iter().find_map(|record| match record.data() {
+            Some(RData::CNAME(cname)) if *record.name() == target => Some(cname.0.clone()),
+            _ => None,
+        });
+        match next {
+            Some(next) if next != target => target = next,
+            _ => break,
+        }
+    }
+    target
+}
+
+/// The target of the CNAMEs answering a query for `name` and `rtype` if the response
+/// leaves it to be queried: it has neither records nor a denial for it.
+fn unfollowed(name: &Name, rtype: RecordType, response: &Message) -> Option<Name> {
+    let target = cname_target(name, rtype, response.answers());
+    let answered = response.answers().iter().any(|record| *record.name() == target && record.record_type() == rtype);
+    (response.response_code() == ResponseCode::NoError && target != *name && !answered && response.name_servers().is_empty()).then_some(target)
+}
+
+/// The owners and types of the RRsets among `records`, signatures aside.
+fn rrsets(records: &[Record]) -> Vec<(Name, RecordType)> {
+    let mut rrsets: Vec<(Name, RecordType)> = Vec::new();
+    for record in records.iter().filter(|record| record.record_type() != RecordType::RRSIG) {
+        let rrset = (record.name().clone(), record.record_type());
+        if !rrsets.contains(&rrset) {
+            rrsets.push(rrset);
+        }
+    }
+    rrsets
+}
+
+/// The RRSIGs among `records` covering the RRset of `owner` and `rtype`.
+fn signatures<'a>(owner: &'a Name, rtype: RecordType, records: &'a [Record]) -> impl Iterator<Item = &'a RRSIG> + 'a {
+    records.iter().filter(move |record| record.name() == owner).filter_map(move |record| match record.data() {
+        Some(RData::DNSSEC(DNSSECRData::RRSIG(sig))) if sig.type_covered() == rtype => Some(sig),
+        _ => None,
+    })
+}
+
+/// Whether an RRSIG by `signer` among `records`, currently valid, verifies the RRset of
+/// `owner` and `rtype` with one of `keys`.
+fn signed_by(owner: &Name, rtype: RecordType, records: &[Record], signer: &Name, keys: &[DNSKEY]) -> bool {
+    let rrset: Vec<Record> = records
+        .iter()
+        .filter(|record| record.name() == owner && record.record_type() == rtype)
+        .cloned()
+        .collect();
+    if rrset.is_empty() {
+        return false;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as u32);
+    signatures(owner, rtype, records)
+        .filter(|sig| sig.signer_name() == signer)
+        // Inception and expiration are compared in serial number arithmetic
+        .filter(|sig| !is_before(now, sig.sig_inception()) && !is_before(sig.sig_expiration(), now))
+        .any(|sig| {
+            keys.iter().any(|key| {
+                key.algorithm() == sig.algorithm()
+                    && key.calculate_key_tag().is_ok_and(|tag| tag == sig.key_tag())
+                    && key.verify_rrsig(owner, DNSClass::IN, sig, &rrset).is_ok()
+            })
+        })
+}
+
+/// Whether timestamp `a` comes before `b` in RFC 1982 serial arithmetic.
+fn is_before(a: u32, b: u32) -> bool {
+    a != b && b.wrapping_sub(a) < 1 << 31
+}
+
+/// What the NSEC or NSEC3 records `proofs` of `zone` prove about `name` and `rtype`, if
+/// they prove their absence; the absence of a name, with `nx`, or of the type otherwise.
+/// Whether a wildcard could have answered instead isn't checked.
+fn prove(name: &Name, rtype: RecordType, nx: bool, zone: &Name, proofs: &[&Record]) -> Option<Proof> {
+    let lacks = |types: &[RecordType]| !types.contains(&rtype) && !types.contains(&RecordType::CNAME);
+    let nsecs: Vec<(&Name, &Name, &[RecordType])> = proofs
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::DNSSEC(DNSSECRData::NSEC(nsec))) => Some((record.name(), nsec.next_domain_name(), nsec.type_bit_maps())),
+            _ => None,
+        })
+        .collect();
+    if !nsecs.is_empty() {
+        if let Some((_, _, types)) = nsecs.iter().find(|(owner, _, _)| *owner == name) {
+            return (!nx && lacks(types)).then(|| Proof::NoData(types.to_vec()));
+        }
+        // The last NSEC of a zone leads back to its apex
+        let (_, next, _) = nsecs.iter().find(|(owner, next, _)| *owner < name && (name < *next || next <= owner))?;
+        return match nx {
+            true => Some(Proof::NoName),
+            // An empty non-terminal, with names below it but no records
+            false => (*next != name && name.zone_of(next)).then(|| Proof::NoData(Vec::new())),
+        };
+    }
+
+    let nsec3s: Vec<(Vec<u8>, &NSEC3)> = proofs
+        .iter()
+        .filter(|record| record.name().base_name() == *zone)
+        .filter_map(|record| match record.data() {
+            Some(RData::DNSSEC(DNSSECRData::NSEC3(nsec3))) => {
+                let label = record.name().iter().next()?.to_ascii_uppercase();
+                Some((BASE32HEX_NOPAD.decode(&label).ok()?, nsec3))
+            }
+            _ => None,
+        })
+        .collect();
+    let (_, params) = nsec3s.first()?;
+    let hash = |name: &Name| {
+        params
+            .hash_algorithm()
+            .hash(params.salt(), name, params.iterations())
+            .ok()
+            .map(|digest| digest.as_ref().to_vec())
+    };
+    let matching = |name: &Name| hash(name).and_then(|hashed| nsec3s.iter().find(|(owner, _)| *owner == hashed).map(|(_, nsec3)| *nsec3));
+    let covering = |name: &Name| {
+        let hashed = hash(name)?;
+        nsec3s
+            .iter()
+            .find(|(owner, nsec3)| {
+                let next = nsec3.next_hashed_owner_name();
+                *owner < hashed && (hashed.as_slice() < next || next <= owner.as_slice())
+            })
+            .map(|(_, nsec3)| *nsec3)
+    };
+
+    if let Some(nsec3) = matching(name) {
+        return (!nx && lacks(nsec3.type_bit_maps())).then(|| Proof::NoData(nsec3.type_bit_maps().to_vec()));
+    }
+    // The closest encloser proof (RFC 5155 section 7.2.1): the closest existing ancestor
+    // of the name matches an NSEC3, and the name one label below it is covered by one
+    let mut next_closer = name.clone();
+    let mut encloser = name.base_name();
+    while matching(&encloser).is_none() {
+        if encloser.num_labels() <= zone.num_labels() {
+            return None;
+        }
+        next_closer = encloser.clone();
+        encloser = encloser.base_name();
+    }
+    let nsec3 = covering(&next_closer)?;
+    match (nsec3.opt_out(), nx) {
+        (true, _) => Some(Proof::OptOut),
+        (false, true) => Some(Proof::NoName),
+        // Only opt-out explains a missing exact match of a name that exists
+        (false, false) => None,
+    }
+}
@@ -0,0 +1,350 @@
+//! Queries of the server's own DNS listeners.
+//!
+//! `VerifyRecord`, behind `rdnsctl dig`, sends a real query to this server's DNS listener
+//! over UDP, TCP, DNS over TLS or DNS over HTTPS and returns the response a client gets,
+//! so that automation can check that a record it just added resolves end to end: through
+//! the listener, ACLs, rate limits and the response pipeline, not only in the stored
+//! records. The probes of `probe` send their queries the same way.
+//!
+//! Queries go to the first of `dns.listen_addrs`, or to the `[dns.tls]` listener for DoT
+//! and the `[dns.https]` one for DoH, on the loopback address when the listener is bound
+//! to an unspecified one, so they are answered as those of a local client, e.g. from its
+//! view. The certificates of the TLS and HTTPS listeners aren't verified, as the query
+//! goes to the server itself, whose certificate is for its public name rather than
+//! loopback. DoH queries are POSTed over HTTP/1.1. UDP responses are returned as they come,
+//! truncated or not. When `[dns.proxy_protocol]` trusts the loopback address, TCP and
+//! TLS connections start with an `UNKNOWN` PROXY header, as the balancers' own health
+//! checks do.
+
+use hickory_proto::op::{Edns, Message, MessageType, OpCode, Query};
+use hickory_proto::rr::{Name, RData, RecordType};
+use hyper::{header, Body, StatusCode};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ServerName};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::TlsConnector;
+
+use crate::dns::{DnsListeners, DnsOptions};
+use crate::error::RdnsError;
+use crate::forward::MAX_PAYLOAD;
+use crate::proxy::ProxyProtocolOptions;
+
+/// How long a query may take, connecting included.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Header opening connections to listeners that expect the PROXY protocol from loopback.
+const PROXY_HEADER: &[u8] = b"PROXY UNKNOWN\r\n";
+
+/// Media type of DoH queries and answers.
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// How a query is sent to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+    /// DNS over TLS (RFC 7858)
+    Tls,
+    /// DNS over HTTPS (RFC 8484)
+    Https,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Protocol::Udp => "UDP",
+            Protocol::Tcp => "TCP",
+            Protocol::Tls => "TLS",
+            Protocol::Https => "HTTPS",
+        })
+    }
+}
+
+/// The server's answer to a query.
+pub struct Answer {
+    pub response: Message,
+    /// Time from sending the query to receiving the response
+    pub rtt: Duration,
+}
+
+impl Answer {
+    /// Whether the answer section holds a `record_type` record with the data `expected`,
+    /// or any `record_type` record without. Records a CNAME chain leads to count too.
+    pub fn holds(&self, record_type: RecordType, expected: Option<&RData>) -> bool {
+        self.response
+            .answers()
+            .iter()
+            .filter(|record| record.record_type() == record_type)
+            .any(|record| expected.is_none_or(|expected| record.data() == Some(expected)))
+    }
+}
+
+/// The DNS listeners of the server, as queries of it reach them.
+#[derive(Clone)]
+pub struct Listeners {
+    dns: SocketAddr,
+    tls: Option<SocketAddr>,
+    /// Address and URL path of the DoH listener
+    https: Option<(SocketAddr, String)>,
+    /// Whether TCP and TLS connections from loopback must start with a PROXY header
+    proxy_header: bool,
+}
+
+impl Listeners {
+    /// Listeners answering on `dns`, `tls` and `https`, which are reached on loopback when
+    /// unspecified.
+    fn new(
+        dns: SocketAddr,
+        tls: Option<SocketAddr>,
+        https: Option<(SocketAddr, String)>,
+        proxy_protocol: Option<&ProxyProtocolOptions>,
+    ) -> Self {
+        let dns = reachable(dns);
+        Listeners {
+            dns,
+            tls: tls.map(reachable),
+            https: https.map(|(addr, path)| (reachable(addr), path)),
+            proxy_header: proxy_protocol.is_some_and(|proxy| proxy.trusted.contains(dns.ip())),
+        }
+    }
+}
+
+impl TryFrom<&DnsOptions> for Listeners {
+    type Error = anyhow::Error;
+
+    fn try_from(options: &DnsOptions) -> anyhow::Result<Self> {
+        let dns = options
+            .listen_addrs
+            .first()
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("at least one DNS listen address is required"))?;
+        let tls = match &options.tls {
+            Some(tls) => Some(
+                tls.listen_addr
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("dns.tls.listen_addr {} has no address", tls.listen_addr))?,
+            ),
+            None => None,
+        };
+        Ok(Listeners::new(dns, tls, https_listener(options)?, options.proxy_protocol.as_ref()))
+    }
+}
+
+/// The listeners as bound, with the ports picked for those given as port 0.
+impl TryFrom<&DnsListeners> for Listeners {
+    type Error = anyhow::Error;
+
+    fn try_from(listeners: &DnsListeners) -> anyhow::Result<Self> {
+        let dns = *listeners
+            .local_addrs()
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("at least one DNS listen address is required"))?;
+        let options = listeners.options();
+        Ok(Listeners::new(dns, listeners.tls_addr(), https_listener(options)?, options.proxy_protocol.as_ref()))
+    }
+}
+
+/// The address and path of the DoH listener of `options`, if there is one.
+fn https_listener(options: &DnsOptions) -> anyhow::Result<Option<(SocketAddr, String)>> {
+    let Some(https) = &options.https else {
+        return Ok(None);
+    };
+    let addr = https
+        .listen_addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("dns.https.listen_addr {} has no address", https.listen_addr))?;
+    Ok(Some((addr, https.path.clone())))
+}
+
+/// `addr`, with loopback in place of an unspecified address.
+fn reachable(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+        _ => addr,
+    }
+}
+
+impl Listeners {
+    /// The protocols the server can be queried over: UDP and TCP, and DoT and DoH when
+    /// their listeners are configured.
+    pub fn protocols(&self) -> Vec<Protocol> {
+        let mut protocols = vec![Protocol::Udp, Protocol::Tcp];
+        if self.tls.is_some() {
+            protocols.push(Protocol::Tls);
+        }
+        if self.https.is_some() {
+            protocols.push(Protocol::Https);
+        }
+        protocols
+    }
+
+    /// Queries the server for the `record_type` records of `name` over `protocol`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `NotEnabled` for DoT or DoH without their listener, or an error if the
+    /// query isn't answered within the timeout.
+    pub async fn query(&self, name: &Name, record_type: RecordType, protocol: Protocol) -> Result<Answer, RdnsError> {
+        self.query_within(name, record_type, protocol, TIMEOUT).await
+    }
+
+    /// Queries the server as `query` does, failing unless answered within `timeout`.
+    ///
+    /// # Errors
+    ///
+    /// As for `query`.
+    pub async fn query_within(&self, name: &Name, record_type: RecordType, protocol: Protocol, timeout: Duration) -> Result<Answer, RdnsError> {
+        let addr = match protocol {
+            Protocol::Udp | Protocol::Tcp => self.dns,
+            Protocol::Tls => self
+                .tls
+                .ok_or_else(|| RdnsError::NotEnabled("no DNS over TLS listener is configured, see [dns.tls]".into()))?,
+            Protocol::Https => self
+                .https
+                .as_ref()
+                .map(|(addr, _)| *addr)
+                .ok_or_else(|| RdnsError::NotEnabled("no DNS over HTTPS listener is configured, see [dns.https]".into()))?,
+        };
+        let mut query = Message::new();
+        query
+            .set_id(rand::random())
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name.clone(), record_type));
+        let mut edns = Edns::new();
+        edns.set_max_payload(MAX_PAYLOAD);
+        query.set_edns(edns);
+
+        let started = Instant::now();
+        let exchange = async {
+            match protocol {
+                Protocol::Udp => exchange_udp(addr, &query).await,
+                Protocol::Tcp => {
+                    let mut stream = self.connect(addr).await?;
+                    exchange_stream(&mut stream, &query).await
+                }
+                Protocol::Tls => {
+                    let stream = self.connect(addr).await?;
+                    let connector = TlsConnector::from(tls_config());
+                    let mut stream = connector.connect(ServerName::IpAddress(addr.ip()), stream).await?;
+                    exchange_stream(&mut stream, &query).await
+                }
+                Protocol::Https => {
+                    let stream = TcpStream::connect(addr).await?;
+                    let connector = TlsConnector::from(tls_config());
+                    let stream = connector.connect(ServerName::IpAddress(addr.ip()), stream).await?;
+                    let path = self.https.as_ref().map_or("/", |(_, path)| path.as_str());
+                    exchange_https(stream, addr, path, &query).await
+                }
+            }
+        };
+        let response = match tokio::time::timeout(timeout, exchange).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => return Err(RdnsError::Other(anyhow::anyhow!("query to {} over {} failed: {}", addr, protocol, e))),
+            Err(_) => return Err(RdnsError::Other(anyhow::anyhow!("query to {} over {} timed out", addr, protocol))),
+        };
+        Ok(Answer {
+            response,
+            rtt: started.elapsed(),
+        })
+    }
+
+    /// Opens a TCP connection to `addr`, sending the PROXY header if it expects one.
+    async fn connect(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect(addr).await?;
+        if self.proxy_header {
+            stream.write_all(PROXY_HEADER).await?;
+        }
+        Ok(stream)
+    }
+}
+
+/// Sends `query` to `addr` in a single datagram and waits for the answer to it.
+async fn exchange_udp(addr: SocketAddr, query: &Message) -> io::Result<Message> {
+    let bytes = query.to_vec().map_err(io::Error::other)?;
+    let local: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    socket.send(&bytes).await?;
+    let mut buffer = vec![0; usize::from(MAX_PAYLOAD)];
+    loop {
+        let len = socket.recv(&mut buffer).await?;
+        match Message::from_vec(&buffer[..len]) {
+            Ok(response) if response.id() == query.id() => return Ok(response),
+            _ => continue,
+        }
+    }
+}
+
+/// Sends `query` over a connection, framed by its length, and reads the answer.
+async fn exchange_stream<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, query: &Message) -> io::Result<Message> {
+    let bytes = query.to_vec().map_err(io::Error::other)?;
+    let len = u16::try_from(bytes.len()).map_err(io::Error::other)?;
+    stream.write_all(&len.to_be_bytes()).await?;
+    stream.write_all(&bytes).await?;
+    stream.flush().await?;
+    let len = stream.read_u16().await?;
+    let mut buffer = vec![0; usize::from(len)];
+    stream.read_exact(&mut buffer).await?;
+    Message::from_vec(&buffer).map_err(io::Error::other)
+}
+
+/// POSTs `query` to `path` over a connection to the DoH listener at `addr`, and reads the
+/// answer from the response body.
+async fn exchange_https<S>(stream: S, addr: SocketAddr, path: &str, query: &Message) -> io::Result<Message>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await.map_err(io::Error::other)?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    let request = hyper::Request::post(path)
+        .header(header::HOST, addr.to_string())
+        .header(header::CONTENT_TYPE, DNS_MESSAGE)
+        .header(header::ACCEPT, DNS_MESSAGE)
+        .body(Body::from(query.to_vec().map_err(io::Error::other)?))
+        .map_err(io::Error::other)?;
+    let response = sender.send_request(request).await.map_err(io::Error::other)?;
+    if response.status() != StatusCode::OK {
+        return Err(io::Error::other(format!("answered with HTTP status {}", response.status())));
+    }
+    let body = hyper::body::to_bytes(response.into_body()).await.map_err(io::Error::other)?;
+    Message::from_vec(&body).map_err(io::Error::other)
+}
+
+/// TLS config of queries to the server's own TLS listener, which accepts its certificate
+/// whatever it is.
+fn tls_config() -> Arc<ClientConfig> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(OwnCertificate))
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+/// Accepts any certificate, see the module docs.
+struct OwnCertificate;
+
+impl ServerCertVerifier for OwnCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
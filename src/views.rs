@@ -0,0 +1,167 @@
+//! Split-horizon views.
+//!
+//! A view is a named set of client networks with its own overlay of records. A query
+//! from a client in a view's networks is answered from the overlay when it holds records
+//! of the queried name and type, and from the shared zones otherwise, so a view only
+//! needs the records that differ, e.g. internal addresses for LAN clients. Views are
+//! matched in the order they are configured and the first match wins; clients in no
+//! view are answered from the shared zones only. A view may also set the address family
+//! policy of its clients, see `family`.
+
+use hickory_proto::rr::{LowerName, Record, RrKey};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+use crate::family::AddressFamily;
+use crate::settings::ViewSettings;
+
+/// Client networks, given as CIDR blocks or single addresses.
+#[derive(Clone, Debug, Default)]
+pub struct ClientMatch(Vec<IpNet>);
+
+impl ClientMatch {
+    /// Parses `networks`, accepting plain addresses as single-host networks.
+    pub fn parse(networks: &[String]) -> anyhow::Result<Self> {
+        let networks = networks
+            .iter()
+            .map(|network| match network.parse::<IpAddr>() {
+                Ok(address) => Ok(IpNet::from(address)),
+                Err(_) => network
+                    .parse::<IpNet>()
+                    .map_err(|e| anyhow::anyhow!("invalid client network {}: {}", network, e)),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self(networks))
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients of dual-stack sockets arrive as mapped IPv6 addresses
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(address, IpAddr::V4),
+            v4 => v4,
+        };
+        self.0.iter().any(|network| network.contains(&address))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn networks(&self) -> Vec<String> {
+        self.0.iter().map(|network| network.to_string()).collect()
+    }
+}
+
+/// Config options for a view
+pub struct ViewOptions {
+    pub name: String,
+    /// Clients answered from the view.
+    pub clients: ClientMatch,
+    /// Address family policy of the view's clients, overriding `dns.address_family`.
+    pub address_family: Option<AddressFamily>,
+}
+
+impl TryFrom<ViewSettings> for ViewOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(cfg: ViewSettings) -> anyhow::Result<Self> {
+        if cfg.match_clients.is_empty() {
+            anyhow::bail!("view {} matches no clients", cfg.name);
+        }
+        Ok(ViewOptions {
+            clients: ClientMatch::parse(&cfg.match_clients)?,
+            address_family: cfg.address_family.as_deref().map(str::parse).transpose()?,
+            name: cfg.name,
+        })
+    }
+}
+
+/// A view and its record overlay.
+pub struct View {
+    pub name: String,
+    pub clients: ClientMatch,
+    pub address_family: Option<AddressFamily>,
+    /// Records by the name and type they answer for.
+    records: RwLock<HashMap<RrKey, Vec<Record>>>,
+}
+
+impl View {
+    /// The overlay's records of `key`, if it has any.
+    pub fn lookup(&self, key: &RrKey) -> Option<Vec<Record>> {
+        self.records.read().unwrap().get(key).cloned()
+    }
+
+    /// Adds `record` to the overlay, replacing a record of the same value.
+    pub fn upsert(&self, record: Record) {
+        let key = RrKey::new(LowerName::new(record.name()), record.record_type());
+        let mut records = self.records.write().unwrap();
+        let rrset = records.entry(key).or_default();
+        match rrset.iter().position(|existing| existing.data() == record.data()) {
+            Some(index) => rrset[index] = record,
+            None => rrset.push(record),
+        }
+    }
+
+    /// Removes the overlay's records of `key`, returning them.
+    pub fn remove(&self, key: &RrKey) -> Vec<Record> {
+        self.records.write().unwrap().remove(key).unwrap_or_default()
+    }
+
+    /// Removes the overlay's records in the zone `origin`.
+    pub fn remove_zone(&self, origin: &LowerName) {
+        self.records.write().unwrap().retain(|key, _| !origin.zone_of(&key.name));
+    }
+
+    /// Removes every record of the overlay.
+    pub fn clear(&self) {
+        self.records.write().unwrap().clear();
+    }
+
+    /// Every record of the overlay, sorted by name and type.
+    pub fn records(&self) -> Vec<Record> {
+        let records = self.records.read().unwrap();
+        let mut keys: Vec<&RrKey> = records.keys().collect();
+        keys.sort();
+        keys.into_iter().flat_map(|key| records[key].iter().cloned()).collect()
+    }
+}
+
+/// The configured views, in matching order.
+#[derive(Default)]
+pub struct Views(Vec<View>);
+
+impl Views {
+    pub fn new(options: &[ViewOptions]) -> anyhow::Result<Self> {
+        let mut views: Vec<View> = Vec::with_capacity(options.len());
+        for view in options {
+            if views.iter().any(|existing| existing.name == view.name) {
+                anyhow::bail!("view {} is defined more than once", view.name);
+            }
+            views.push(View {
+                name: view.name.clone(),
+                clients: view.clients.clone(),
+                address_family: view.address_family,
+                records: RwLock::new(HashMap::new()),
+            });
+        }
+        Ok(Self(views))
+    }
+
+    /// The first view matching the client at `address`.
+    pub fn for_client(&self, address: IpAddr) -> Option<&View> {
+        self.0.iter().find(|view| view.clients.contains(address))
+    }
+
+    pub fn get(&self, name: &str) -> anyhow::Result<&View> {
+        self.0
+            .iter()
+            .find(|view| view.name == name)
+            .ok_or_else(|| anyhow::anyhow!("no view named {}", name))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &View> {
+        self.0.iter()
+    }
+}
@@ -0,0 +1,277 @@
+//! external-dns webhook provider.
+//!
+//! Serves the webhook provider API that external-dns speaks with `--provider=webhook`, so
+//! that the hostnames of Kubernetes Ingresses and Services are published straight into the
+//! hosted zones:
+//!
+//! - `GET /` negotiates the API version and returns the domain filter, the configured
+//!   domains or else every hosted zone
+//! - `GET /records` lists the managed record sets as endpoints
+//! - `POST /adjustendpoints` returns the endpoints external-dns plans to apply, with their
+//!   names normalized the way `GET /records` reports them
+//! - `POST /records` applies a plan of created, updated and deleted endpoints as one
+//!   changeset, so either all of it lands or none of it does
+//! - `GET /healthz` answers once the provider is up
+//!
+//! external-dns runs the webhook as a sidecar and doesn't authenticate, so the provider
+//! listens on localhost by default. Its changes are recorded in the audit log as
+//! `ApplyChanges`. Ownership is tracked by external-dns itself in TXT records, which are
+//! managed like any other record; labels and provider-specific properties are ignored.
+//! Request bodies are read with the REST gateway's [`rest::read_json`], so the same 1 MiB
+//! cap applies, answering 413 beyond it.
+
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::audit::{self, Actor, AuditLog, Change};
+use crate::dns::{ChangeOperation, DnsState, RecordEntry};
+use crate::rest;
+use crate::settings::WebhookSettings;
+use crate::shutdown::Shutdown;
+
+/// Media type of every request and response, with the API version.
+const MEDIA_TYPE: &str = "application/external.dns.webhook+json;version=1";
+/// Record types external-dns manages.
+const MANAGED_TYPES: [&str; 6] = ["A", "AAAA", "CNAME", "TXT", "SRV", "MX"];
+
+/// Config options for the webhook provider
+pub struct WebhookOptions {
+    pub listen_addr: String,
+    /// Domains external-dns may manage; every hosted zone when empty.
+    pub domain_filter: Vec<String>,
+}
+
+impl From<WebhookSettings> for WebhookOptions {
+    fn from(cfg: WebhookSettings) -> Self {
+        WebhookOptions {
+            listen_addr: cfg.listen_addr,
+            domain_filter: cfg.domain_filter,
+        }
+    }
+}
+
+/// The domains external-dns may manage, as it expects them in the negotiation.
+#[derive(Serialize)]
+struct DomainFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+/// A record set as external-dns sees it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Endpoint {
+    dns_name: String,
+    #[serde(default)]
+    targets: Vec<String>,
+    record_type: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    set_identifier: String,
+    #[serde(default, rename = "recordTTL", skip_serializing_if = "Option::is_none")]
+    record_ttl: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    labels: Option<BTreeMap<String, String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provider_specific: Option<Vec<serde_json::Value>>,
+}
+
+impl Endpoint {
+    /// The endpoint's name as it is reported, without the trailing dot.
+    fn normalized_name(&self) -> String {
+        self.dns_name.trim_end_matches('.').to_ascii_lowercase()
+    }
+}
+
+/// A plan of changes; external-dns sends `null` for the empty lists.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Changes {
+    #[serde(default)]
+    create: Option<Vec<Endpoint>>,
+    #[serde(default)]
+    update_old: Option<Vec<Endpoint>>,
+    #[serde(default)]
+    update_new: Option<Vec<Endpoint>>,
+    #[serde(default)]
+    delete: Option<Vec<Endpoint>>,
+}
+
+/// Builds a response in the webhook media type.
+fn json<T: Serialize>(code: StatusCode, body: &T) -> hyper::Response<Body> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    let mut response = hyper::Response::new(Body::from(body));
+    *response.status_mut() = code;
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, header::HeaderValue::from_static(MEDIA_TYPE));
+    response
+}
+
+/// Builds a plain-text response, as external-dns expects for errors.
+fn text(code: StatusCode, message: impl Into<String>) -> hyper::Response<Body> {
+    let mut response = hyper::Response::new(Body::from(message.into()));
+    *response.status_mut() = code;
+    response
+}
+
+/// The managed records of `state`, grouped into one endpoint per name and type.
+async fn endpoints(state: &DnsState) -> Vec<Endpoint> {
+    let mut sets: BTreeMap<(String, String), Endpoint> = BTreeMap::new();
+    for record in state.get_all_records().await {
+        if !MANAGED_TYPES.contains(&record.record_type.as_str()) {
+            continue;
+        }
+        let name = record.name.trim_end_matches('.').to_string();
+        let endpoint = sets.entry((name.clone(), record.record_type.clone())).or_insert_with(|| Endpoint {
+            dns_name: name,
+            targets: Vec::new(),
+            record_type: record.record_type,
+            set_identifier: String::new(),
+            record_ttl: Some(record.ttl),
+            labels: None,
+            provider_specific: None,
+        });
+        endpoint.targets.push(record.value);
+    }
+    sets.into_values().collect()
+}
+
+/// The changeset that applies `changes`: the record sets of the deleted and updated
+/// endpoints are removed first, then those of the created and updated ones added.
+fn operations(changes: Changes) -> Vec<ChangeOperation> {
+    let removed = changes.delete.into_iter().chain(changes.update_old).flatten();
+    let added = changes.create.into_iter().chain(changes.update_new).flatten();
+    let mut operations: Vec<ChangeOperation> = removed
+        .map(|endpoint| ChangeOperation::Delete {
+            name: endpoint.normalized_name(),
+            record_type: endpoint.record_type,
+        })
+        .collect();
+    for endpoint in added {
+        let name = endpoint.normalized_name();
+        for target in endpoint.targets {
+            operations.push(ChangeOperation::Add(RecordEntry {
+                name: name.clone(),
+                record_type: endpoint.record_type.clone(),
+                value: target,
+                ttl: endpoint.record_ttl.unwrap_or_default(),
+                ..Default::default()
+            }));
+        }
+    }
+    operations
+}
+
+/// Answers a single webhook request.
+async fn serve(
+    state: Arc<RwLock<DnsState>>,
+    audit: Option<Arc<AuditLog>>,
+    domain_filter: Arc<Vec<String>>,
+    peer: SocketAddr,
+    request: hyper::Request<Body>,
+) -> Result<hyper::Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let response = match (method, request.uri().path()) {
+        (Method::GET, "/") => {
+            let include = if domain_filter.is_empty() {
+                let zones = state.read().await.list_zones(None).await;
+                zones.into_iter().map(|(origin, ..)| origin.trim_end_matches('.').to_string()).collect()
+            } else {
+                domain_filter.to_vec()
+            };
+            let mut response = json(StatusCode::OK, &DomainFilter { include, exclude: Vec::new() });
+            response
+                .headers_mut()
+                .insert(header::VARY, header::HeaderValue::from_static("Content-Type"));
+            response
+        }
+        (Method::GET, "/healthz") => text(StatusCode::OK, "ok"),
+        (Method::GET, "/records") => json(StatusCode::OK, &endpoints(&*state.read().await).await),
+        (Method::POST, "/adjustendpoints") => match rest::read_json::<Vec<Endpoint>>(request).await {
+            Ok(mut endpoints) => {
+                for endpoint in &mut endpoints {
+                    endpoint.dns_name = endpoint.normalized_name();
+                }
+                json(StatusCode::OK, &endpoints)
+            }
+            Err(e) => text(e.status(), format!("invalid request body: {}", e)),
+        },
+        (Method::POST, "/records") => match rest::read_json::<Changes>(request).await {
+            Ok(changes) => apply(&state, audit.as_deref(), peer, changes).await,
+            Err(e) => text(e.status(), format!("invalid request body: {}", e)),
+        },
+        _ => text(StatusCode::NOT_FOUND, "no such endpoint"),
+    };
+    Ok(response)
+}
+
+/// Applies a plan of changes, answering 204 No Content once it has landed.
+async fn apply(state: &RwLock<DnsState>, audit: Option<&AuditLog>, peer: SocketAddr, changes: Changes) -> hyper::Response<Body> {
+    let operations = operations(changes);
+    let state = state.read().await;
+    let mut audited = Vec::new();
+    for operation in &operations {
+        let (name, record_type) = match operation {
+            ChangeOperation::Add(entry) => (&entry.name, &entry.record_type),
+            ChangeOperation::Delete { name, record_type } => (name, record_type),
+        };
+        if audited.iter().any(|(audited_name, audited_type, _)| audited_name == name && audited_type == record_type) {
+            continue;
+        }
+        let actor = Actor {
+            token: None,
+            peer: Some(peer.to_string()),
+        };
+        let change = Change::records(audit, &state, actor, "ApplyChanges", name, record_type).await;
+        audited.push((name.clone(), record_type.clone(), change));
+    }
+
+    let result = state.apply_changeset(operations.clone()).await;
+    for (_, _, change) in audited {
+        audit::finish(change, &state, &result).await;
+    }
+    match result {
+        Ok(_) => text(StatusCode::NO_CONTENT, ""),
+        Err(e) => text(rest::status_code(&e), e.to_string()),
+    }
+}
+
+/// Starts the webhook provider, serving the records in `state` to external-dns until
+/// `shutdown` is requested, and recording its changes in `audit` when it is set.
+///
+/// # Errors
+///
+/// Returns an error if the listen address is invalid or the server fails.
+pub async fn run_webhook_server(
+    state: Arc<RwLock<DnsState>>,
+    audit: Option<Arc<AuditLog>>,
+    options: WebhookOptions,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let addr: SocketAddr = options.listen_addr.parse()?;
+    let domain_filter = Arc::new(options.domain_filter);
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let state = state.clone();
+        let audit = audit.clone();
+        let domain_filter = domain_filter.clone();
+        let peer = conn.remote_addr();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                serve(state.clone(), audit.clone(), domain_filter.clone(), peer, request)
+            }))
+        }
+    });
+    println!("external-dns webhook listening on {}", addr);
+    hyper::Server::try_bind(&addr)?
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.requested())
+        .await?;
+    Ok(())
+}
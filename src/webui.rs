@@ -0,0 +1,31 @@
+//! Web dashboard served by the REST gateway.
+//!
+//! A single self-contained page at `/ui`, enabled with `ui = true` under `[rest]`. It
+//! lists the zones and their records, shows the most queried names when query
+//! statistics are enabled, and adds, edits and deletes records. The page itself is
+//! public, but holds no data: it asks for an API token and makes every call through
+//! the REST endpoints with it, so callers see and change only what their token's role
+//! and tenant allow, and their changes land in the audit log like any other.
+
+use hyper::{header, Body, StatusCode};
+
+/// The dashboard, with its styles and script inlined.
+const PAGE: &str = include_str!("webui.html");
+
+/// Sources the page may load from; everything it needs is inline, and it only calls
+/// the gateway serving it.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; script-src 'unsafe-inline'; style-src 'unsafe-inline'; \
+     connect-src 'self'; frame-ancestors 'none'; form-action 'none'; base-uri 'none'";
+
+/// Builds the response serving the dashboard.
+pub(crate) fn page() -> hyper::Response<Body> {
+    hyper::Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(header::CACHE_CONTROL, "no-store")
+        .header(header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY)
+        .header(header::X_FRAME_OPTIONS, "DENY")
+        .header(header::REFERRER_POLICY, "no-referrer")
+        .body(Body::from(PAGE))
+        .unwrap_or_default()
+}
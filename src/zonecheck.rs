@@ -0,0 +1,210 @@
+//! Consistency checks of a zone's records.
+//!
+//! `CheckZone` looks a zone over for the problems that break the resolution of its names
+//! or its transfer to secondaries, without changing it, e.g. before exporting it or
+//! pointing secondaries at it. Each finding names the check that found it:
+//!
+//! - `missing-soa`: the apex has no SOA record, which transfers and negative caching need
+//! - `missing-ns`: the apex has no NS records
+//! - `cname-conflict`: a CNAME shares its name with other data (RFC 1034 section 3.6.2),
+//!   or with another CNAME, as records imported from zone files may
+//! - `dangling-cname`: a CNAME points at a name of the zone that has no records
+//! - `missing-glue`: an NS record names a nameserver inside the zone without A or AAAA
+//!   records, which resolvers can't reach it without
+//! - `target-is-cname`: an NS, MX or SRV record names a CNAME rather than a host (RFC 2181
+//!   section 10.3)
+//! - `occluded`: a record lies at or under a delegation or another hosted zone, which
+//!   hides it from queries, other than the NS and DS records of a delegation and the glue
+//!   of nameservers
+//!
+//! Dangling CNAMEs, targets that are CNAMEs and occluded records are warnings, as
+//! resolvers still answer around them; the rest are errors. Names under other zones hosted here or delegated to
+//! child zones are left to those.
+
+use hickory_proto::rr::{LowerName, RData, Record, RecordSet, RecordType, RrKey};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// How bad a finding is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Breaks resolution or transfers.
+    Error,
+    /// Works, but likely not as intended.
+    Warning,
+}
+
+/// A problem found in a zone.
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Check that found it, e.g. `missing-glue`
+    pub check: &'static str,
+    /// Name the problem is at
+    pub name: String,
+    pub message: String,
+}
+
+/// The records of the zone at `origin`, and the names under it that are left to other
+/// zones.
+struct Zone<'a> {
+    origin: &'a LowerName,
+    records: &'a BTreeMap<RrKey, Arc<RecordSet>>,
+    /// Other hosted zones and delegated child zones under the origin
+    cuts: Vec<LowerName>,
+}
+
+impl Zone<'_> {
+    fn set(&self, name: &LowerName, record_type: RecordType) -> Option<&RecordSet> {
+        self.records.get(&RrKey::new(name.clone(), record_type)).map(|set| &**set)
+    }
+
+    fn values<'a>(&'a self, name: &LowerName, record_type: RecordType) -> impl Iterator<Item = &'a Record> + 'a {
+        self.set(name, record_type).into_iter().flat_map(RecordSet::records_without_rrsigs)
+    }
+
+    /// Whether the zone itself answers for `name`: it is at or under the origin and not
+    /// at or under a cut.
+    fn answers_for(&self, name: &LowerName) -> bool {
+        self.origin.zone_of(name) && !self.cuts.iter().any(|cut| cut.zone_of(name))
+    }
+
+    /// Whether `name` has records, or is covered by a wildcard.
+    fn exists(&self, name: &LowerName) -> bool {
+        self.records.keys().any(|key| {
+            &key.name == name || (key.name.is_wildcard() && key.name.base_name().zone_of(name) && key.name.base_name() != *name)
+        })
+    }
+}
+
+/// Whether records of `record_type` may share a name with a CNAME.
+fn coexists_with_cname(record_type: RecordType) -> bool {
+    matches!(record_type, RecordType::CNAME | RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3)
+}
+
+/// Looks over the `records` of the zone at `origin`, leaving the names at or under
+/// `hosted`, the other hosted zones, to those. Findings are sorted by name, errors first.
+pub fn check(origin: &LowerName, records: &BTreeMap<RrKey, Arc<RecordSet>>, hosted: &[LowerName]) -> Vec<Finding> {
+    let delegations = records
+        .keys()
+        .filter(|key| key.record_type == RecordType::NS && &key.name != origin)
+        .map(|key| key.name.clone());
+    let cuts = hosted
+        .iter()
+        .filter(|zone| *zone != origin && origin.zone_of(zone))
+        .cloned()
+        .chain(delegations)
+        .collect();
+    let zone = Zone { origin, records, cuts };
+    let mut findings = Vec::new();
+    let mut finding = |severity, check, name: &LowerName, message: String| {
+        findings.push(Finding {
+            severity,
+            check,
+            name: name.to_string(),
+            message,
+        })
+    };
+
+    if zone.set(origin, RecordType::SOA).is_none() {
+        finding(Severity::Error, "missing-soa", origin, format!("zone {} has no SOA record", origin));
+    }
+    if zone.set(origin, RecordType::NS).is_none() {
+        finding(Severity::Error, "missing-ns", origin, format!("zone {} has no NS records", origin));
+    }
+
+    let names: BTreeSet<&LowerName> = records.keys().map(|key| &key.name).collect();
+    for name in names {
+        let Some(cnames) = zone.set(name, RecordType::CNAME) else {
+            continue;
+        };
+        if let Some(other) = records.keys().find(|key| &key.name == name && !coexists_with_cname(key.record_type)) {
+            finding(
+                Severity::Error,
+                "cname-conflict",
+                name,
+                format!("{} has a CNAME and {} records, which can't share a name", name, other.record_type),
+            );
+        }
+        if cnames.records_without_rrsigs().count() > 1 {
+            finding(Severity::Error, "cname-conflict", name, format!("{} has more than one CNAME", name));
+        }
+        for cname in cnames.records_without_rrsigs() {
+            let Some(RData::CNAME(target)) = cname.data() else {
+                continue;
+            };
+            let target = LowerName::new(target);
+            if zone.answers_for(&target) && !zone.exists(&target) {
+                finding(
+                    Severity::Warning,
+                    "dangling-cname",
+                    name,
+                    format!("{} is a CNAME for {}, which has no records", name, target),
+                );
+            }
+        }
+    }
+
+    for (key, set) in records {
+        for record in set.records_without_rrsigs() {
+            let target = match record.data() {
+                Some(RData::NS(ns)) => &ns.0,
+                Some(RData::MX(mx)) => mx.exchange(),
+                Some(RData::SRV(srv)) => srv.target(),
+                _ => continue,
+            };
+            let target = LowerName::new(target);
+            if zone.set(&target, RecordType::CNAME).is_some() && zone.answers_for(&target) {
+                finding(
+                    Severity::Warning,
+                    "target-is-cname",
+                    &key.name,
+                    format!("{} record of {} names {}, which is a CNAME", key.record_type, key.name, target),
+                );
+                continue;
+            }
+            // Glue lies in the zone, or under the delegation it is needed for
+            let needs_glue = zone.answers_for(&target) || key.name.zone_of(&target);
+            if key.record_type == RecordType::NS && origin.zone_of(&target) && needs_glue {
+                let addresses = zone.values(&target, RecordType::A).chain(zone.values(&target, RecordType::AAAA)).count();
+                if addresses == 0 {
+                    finding(
+                        Severity::Error,
+                        "missing-glue",
+                        &key.name,
+                        format!("nameserver {} of {} is in the zone but has no A or AAAA records", target, key.name),
+                    );
+                }
+            }
+        }
+    }
+
+    let nameservers: BTreeSet<LowerName> = records
+        .iter()
+        .filter(|(key, _)| key.record_type == RecordType::NS && &key.name != origin)
+        .flat_map(|(_, set)| set.records_without_rrsigs())
+        .filter_map(|record| match record.data() {
+            Some(RData::NS(ns)) => Some(LowerName::new(&ns.0)),
+            _ => None,
+        })
+        .collect();
+    for key in records.keys() {
+        let Some(cut) = zone.cuts.iter().find(|cut| cut.zone_of(&key.name)) else {
+            continue;
+        };
+        let glue = matches!(key.record_type, RecordType::A | RecordType::AAAA) && nameservers.contains(&key.name);
+        let at_cut = &key.name == cut
+            && matches!(key.record_type, RecordType::NS | RecordType::DS | RecordType::RRSIG | RecordType::NSEC | RecordType::NSEC3);
+        if !glue && !at_cut {
+            finding(
+                Severity::Warning,
+                "occluded",
+                &key.name,
+                format!("{} record of {} lies at or under {}, which hides it", key.record_type, key.name, cut),
+            );
+        }
+    }
+
+    findings.sort_by(|a, b| (a.severity, &a.name, a.check).cmp(&(b.severity, &b.name, b.check)));
+    findings
+}
@@ -0,0 +1,137 @@
+//! Directory of zone files.
+//!
+//! With `[dns.zone_dir]` configured, every file in the directory is a BIND-format zone
+//! file for the zone it is named after, without a `.zone` or `.db` extension or a `db.`
+//! prefix: `example.com.zone`, `db.example.com` and `example.com` all hold
+//! `example.com.`. The files are loaded at startup, and the directory is checked every
+//! `poll_secs` seconds: a file that changed is loaded again, a new one creates its zone
+//! and a removed one deletes it. A file that fails to parse is reported and leaves the
+//! version loaded before in place until it is fixed. Hidden files and editor backups
+//! are ignored.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::RwLock;
+
+use crate::dns::DnsState;
+use crate::settings::ZoneDirSettings;
+use crate::zonefile::ZoneFile;
+
+/// Config options for the zone file directory
+#[derive(Clone)]
+pub struct ZoneDirOptions {
+    pub path: PathBuf,
+    pub poll_interval: Duration,
+}
+
+impl From<ZoneDirSettings> for ZoneDirOptions {
+    fn from(cfg: ZoneDirSettings) -> Self {
+        ZoneDirOptions {
+            path: PathBuf::from(cfg.path),
+            poll_interval: Duration::from_secs(cfg.poll_secs.max(1)),
+        }
+    }
+}
+
+/// The origin of the zone a file holds, from its name; none for files that aren't
+/// zone files.
+fn origin_of(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    if name.starts_with('.') || name.ends_with('~') || name.ends_with(".swp") || name.ends_with(".tmp") {
+        return None;
+    }
+    let name = name.strip_suffix(".zone").or_else(|| name.strip_suffix(".db")).unwrap_or(name);
+    let name = name.strip_prefix("db.").unwrap_or(name);
+    (!name.is_empty()).then(|| format!("{}.", name.trim_end_matches('.')))
+}
+
+/// The zone files in the directory, with when each was last modified.
+async fn scan(dir: &Path) -> anyhow::Result<HashMap<PathBuf, SystemTime>> {
+    let mut files = HashMap::new();
+    let mut entries = tokio::fs::read_dir(dir)
+        .await
+        .map_err(|e| anyhow::anyhow!("can't read {}: {}", dir.display(), e))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() && origin_of(&entry.path()).is_some() {
+            files.insert(entry.path(), metadata.modified()?);
+        }
+    }
+    Ok(files)
+}
+
+/// The zone files loaded from the directory.
+pub struct ZoneDir {
+    options: ZoneDirOptions,
+    /// Modification time of each file as of when it was last loaded.
+    loaded: HashMap<PathBuf, SystemTime>,
+}
+
+impl ZoneDir {
+    pub fn new(options: ZoneDirOptions) -> Self {
+        Self {
+            options,
+            loaded: HashMap::new(),
+        }
+    }
+
+    /// Loads the files that changed since they were last loaded and deletes the zones of
+    /// the files that were removed.
+    pub async fn sync(&mut self, state: &mut DnsState) {
+        let files = match scan(&self.options.path).await {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Failed to scan zone directory: {}", e);
+                return;
+            }
+        };
+        for (path, modified) in &files {
+            if self.loaded.get(path) == Some(modified) {
+                continue;
+            }
+            let zone_file = ZoneFile {
+                origin: origin_of(path),
+                path: path.clone(),
+            };
+            match state.import_zone_file(&zone_file).await {
+                Ok(count) => println!("Loaded {} records from {}", count, path.display()),
+                Err(e) => eprintln!("Failed to load {}, keeping the zone as it was: {}", path.display(), e),
+            }
+            // A broken file is tried again once it changes
+            self.loaded.insert(path.clone(), *modified);
+        }
+        let removed: Vec<PathBuf> = self.loaded.keys().filter(|path| !files.contains_key(*path)).cloned().collect();
+        for path in removed {
+            self.loaded.remove(&path);
+            let Some(origin) = origin_of(&path) else {
+                continue;
+            };
+            match state.delete_zone(&origin).await {
+                Ok(()) => println!("Deleted zone {} as {} was removed", origin, path.display()),
+                Err(e) => eprintln!("Failed to delete zone {} of removed {}: {}", origin, path.display(), e),
+            }
+        }
+    }
+
+    /// Whether a file was added, changed or removed since the last sync.
+    async fn changed(&self) -> bool {
+        match scan(&self.options.path).await {
+            Ok(files) => files != self.loaded,
+            Err(_) => false,
+        }
+    }
+
+    /// Reloads the zones whenever their files change. Runs until the process exits.
+    pub async fn run(mut self, state: Arc<RwLock<DnsState>>) {
+        let mut interval = tokio::time::interval(self.options.poll_interval);
+        loop {
+            interval.tick().await;
+            // Scanning without the lock keeps queries flowing while nothing changed
+            if self.changed().await {
+                self.sync(&mut *state.write().await).await;
+            }
+        }
+    }
+}
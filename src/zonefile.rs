@@ -0,0 +1,91 @@
+//! BIND zone file support.
+//!
+//! Parses standard master-file text into record sets that can be loaded into an
+//! authority, and formats records back out in the same syntax so zones can be
+//! migrated between rdns and BIND/NSD.
+
+use hickory_proto::rr::rdata::caa::Value;
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use hickory_proto::serialize::txt::Parser;
+use std::path::PathBuf;
+
+use crate::settings::ZoneFileSettings;
+use crate::svcb;
+
+/// A zone file loaded into the authority at startup.
+pub struct ZoneFile {
+    /// Origin of the zone; taken from the file's `$ORIGIN` when unset.
+    pub origin: Option<String>,
+    pub path: PathBuf,
+}
+
+impl From<ZoneFileSettings> for ZoneFile {
+    fn from(cfg: ZoneFileSettings) -> Self {
+        ZoneFile {
+            origin: cfg.origin,
+            path: PathBuf::from(cfg.path),
+        }
+    }
+}
+
+/// Parses zone file `content`, returning the zone origin and every record it defines.
+///
+/// `path` is used to resolve relative `$INCLUDE` directives.
+pub fn parse(content: &str, origin: Option<Name>, path: Option<PathBuf>) -> anyhow::Result<(Name, Vec<Record>)> {
+    let (origin, record_sets) = Parser::new(content, path, origin).parse()?;
+    let records = record_sets
+        .into_values()
+        .flat_map(|set| set.records_without_rrsigs().cloned().collect::<Vec<_>>())
+        .collect();
+    Ok((origin, records))
+}
+
+/// Formats `records` as a zone file for `origin`, with the SOA record first.
+pub fn format(origin: &Name, records: &[Record]) -> String {
+    let mut out = format!("$ORIGIN {}\n", origin.to_ascii());
+    let (soa, rest): (Vec<_>, Vec<_>) = records
+        .iter()
+        .partition(|record| record.record_type() == RecordType::SOA);
+    for record in soa.into_iter().chain(rest) {
+        let Some(rdata) = record.data() else {
+            continue;
+        };
+        out.push_str(&format!(
+            "{} {} {} {} {}\n",
+            record.name().to_ascii(),
+            record.ttl(),
+            record.dns_class(),
+            record.record_type(),
+            format_rdata(rdata)
+        ));
+    }
+    out
+}
+
+/// Formats rdata in master-file syntax, such that it can be parsed back with
+/// `RData::try_from_str`.
+///
+/// This differs from the `Display` implementation only for TXT records, whose
+/// character-strings are quoted so that embedded whitespace survives a round trip, CAA
+/// records of tags other than issue, issuewild and iodef, whose values are escaped
+/// likewise, and SVCB and HTTPS records, which are written the way `svcb::parse` reads
+/// them.
+pub fn format_rdata(rdata: &RData) -> String {
+    match rdata {
+        RData::SVCB(svcb) => svcb::format(svcb),
+        RData::HTTPS(https) => svcb::format(&https.0),
+        RData::TXT(txt) => txt.iter().map(|data| quote(data)).collect::<Vec<_>>().join(" "),
+        // `Display` fails on values that aren't UTF-8
+        RData::CAA(caa) => match caa.value() {
+            Value::Unknown(data) => format!("{} {} {}", if caa.issuer_critical() { 128 } else { 0 }, caa.tag(), quote(data)),
+            _ => caa.to_string(),
+        },
+        other => other.to_string(),
+    }
+}
+
+/// Quotes a character-string, escaping its backslashes and quotes.
+fn quote(data: &[u8]) -> String {
+    let text = String::from_utf8_lossy(data);
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,74 @@
+//! The control API and the DNS server together: records added over gRPC are answered
+//! over UDP.
+
+use hickory_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use hickory_proto::rr::{Name, RData, RecordType};
+use hickory_proto::serialize::binary::{BinDecodable, BinEncodable};
+use rdns::control::dns_control_client::DnsControlClient;
+use rdns::control::AddRecordRequest;
+use rdns::testing::TestServer;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Queries `addr` over UDP for the `record_type` records of `name`.
+async fn query(addr: SocketAddr, name: &str, record_type: RecordType) -> Message {
+    let mut message = Message::new();
+    message
+        .set_id(7)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .add_query(Query::query(Name::from_str(name).unwrap(), record_type));
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.send_to(&message.to_bytes().unwrap(), addr).await.unwrap();
+    let mut buffer = [0u8; 4096];
+    let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buffer))
+        .await
+        .expect("no answer within 5s")
+        .unwrap();
+    Message::from_bytes(&buffer[..len]).unwrap()
+}
+
+/// The addresses of the A records answering a query.
+fn addresses(response: &Message) -> Vec<String> {
+    response
+        .answers()
+        .iter()
+        .filter_map(|record| match record.data() {
+            Some(RData::A(address)) => Some(address.to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn records_added_over_grpc_are_answered_over_udp() {
+    let server = TestServer::start(&["example.com."]).await.unwrap();
+
+    let response = query(server.dns_addr, "www.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NXDomain);
+
+    let mut client = DnsControlClient::connect(server.grpc_endpoint()).await.unwrap();
+    let added = client
+        .add_record(AddRecordRequest {
+            name: "www.example.com.".into(),
+            record_type: "A".into(),
+            value: "192.0.2.10".into(),
+            ttl: 300,
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(added.success, "{}", added.message);
+
+    let response = query(server.dns_addr, "www.example.com.", RecordType::A).await;
+    assert_eq!(response.response_code(), ResponseCode::NoError);
+    assert!(response.authoritative());
+    assert_eq!(addresses(&response), ["192.0.2.10"]);
+    assert_eq!(response.answers()[0].ttl(), 300);
+
+    drop(client);
+    server.shutdown().await.unwrap();
+}
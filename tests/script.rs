@@ -0,0 +1,96 @@
+//! Scripting hooks: what the hooks are given, and how their results answer queries.
+
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::rdata::A;
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use rdns::script::{Reply, Script, ScriptQuery};
+use rdns::settings::ScriptSettings;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+const SCRIPT: &str = r#"
+fn query(q) {
+    if q.name.ends_with(".corp.example.com.") && !q.client.starts_with("10.") {
+        return "refuse";
+    }
+    if q.name == "wpad.example.com." && q.protocol == "udp" {
+        return [#{ type: "A", value: "10.0.0.80", ttl: 60 }];
+    }
+    if q.name == "loop.example.com." {
+        loop {}
+    }
+}
+
+fn answer(q, records) {
+    if q.code == "NXDOMAIN" {
+        return [#{ type: "A", value: "192.0.2.99" }];
+    }
+    records.filter(|record| record.value != "192.0.2.1")
+}
+"#;
+
+fn load(source: &str, name: &str) -> anyhow::Result<Script> {
+    let path = std::env::temp_dir().join(format!("rdns-{}-{}.rhai", name, std::process::id()));
+    std::fs::write(&path, source).unwrap();
+    let script = Script::try_from(ScriptSettings {
+        path: path.display().to_string(),
+        ttl: 300,
+        max_operations: 10_000,
+    });
+    std::fs::remove_file(&path).unwrap();
+    script
+}
+
+fn query(name: &str, client: [u8; 4]) -> ScriptQuery {
+    ScriptQuery {
+        name: Name::from_str(name).unwrap(),
+        record_type: RecordType::A,
+        client: IpAddr::from(client),
+        protocol: "udp".into(),
+    }
+}
+
+fn a(name: &str, address: Ipv4Addr, ttl: u32) -> Record {
+    Record::from_rdata(Name::from_str(name).unwrap(), ttl, RData::A(A(address)))
+}
+
+#[test]
+fn query_hook_blocks_answers_or_passes_queries_on() {
+    let script = load(SCRIPT, "query").unwrap();
+    assert_eq!(
+        script.query(&query("db.corp.example.com.", [192, 0, 2, 7])),
+        Some(Reply::Respond(ResponseCode::Refused))
+    );
+    assert_eq!(script.query(&query("db.corp.example.com.", [10, 1, 2, 3])), None);
+    // Names are given lower-cased, and records default to the name as queried
+    assert_eq!(
+        script.query(&query("WPAD.example.com.", [10, 1, 2, 3])),
+        Some(Reply::Answer(vec![a("WPAD.example.com.", Ipv4Addr::new(10, 0, 0, 80), 60)]))
+    );
+    // Runaway hooks are stopped and pass the query on
+    assert_eq!(script.query(&query("loop.example.com.", [10, 1, 2, 3])), None);
+}
+
+#[test]
+fn answer_hook_rewrites_answers() {
+    let script = load(SCRIPT, "answer").unwrap();
+    let www = query("www.example.com.", [192, 0, 2, 7]);
+    let answers = [
+        a("www.example.com.", Ipv4Addr::new(192, 0, 2, 1), 300),
+        a("www.example.com.", Ipv4Addr::new(192, 0, 2, 2), 300),
+    ];
+    assert_eq!(
+        script.answer(&www, ResponseCode::NoError, &answers),
+        Some(Reply::Answer(vec![answers[1].clone()]))
+    );
+    assert_eq!(
+        script.answer(&www, ResponseCode::NXDomain, &[]),
+        Some(Reply::Answer(vec![a("www.example.com.", Ipv4Addr::new(192, 0, 2, 99), 300)]))
+    );
+}
+
+#[test]
+fn scripts_without_hooks_are_rejected() {
+    assert!(load("fn lookup(q) { () }", "nohooks").is_err());
+    assert!(load("fn query(q) { ", "invalid").is_err());
+}